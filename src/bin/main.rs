@@ -19,7 +19,18 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
                 .help("Sets a custom config file")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("noise-keygen")
+                .long("noise-keygen")
+                .help("Prints a new key pair for noise:// channels and listeners"),
+        )
         .get_matches();
+    if matches.is_present("noise-keygen") {
+        let (private_key, public_key) = rsnova::noise_keypair();
+        println!("private_key = \"{}\"", private_key);
+        println!("public_key = \"{}\"", public_key);
+        return Ok(());
+    }
     let confile_name = matches.value_of("config").unwrap();
     let mut confile = match File::open(matches.value_of("config").unwrap()) {
         Ok(f) => f,
@@ -0,0 +1,193 @@
+//! Cover traffic of an rmux channel. While the channel carries no streams
+//! of its own, it fetches the configured urls through itself at random
+//! times and reads bodies of random sizes, so an observer of the link sees
+//! traffic whether the user is browsing or away.
+use super::get_channel_stream;
+use crate::config::{ChannelConfig, DecoyConfig, DEFAULT_MUX};
+use crate::rmux::get_channel_stream_count;
+use crate::transport::{new_client_config, tls_connect_io};
+use crate::utils::make_io_error;
+use rand::Rng;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time;
+use url::Url;
+
+const DEFAULT_IDLE_SECS: u64 = 60;
+const DEFAULT_FLOWS_PER_HOUR: u32 = 30;
+const DEFAULT_MIN_KB: u32 = 16;
+const DEFAULT_MAX_KB: u32 = 1024;
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_FLOWS_IN_FLIGHT: usize = 2;
+
+const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+                          (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
+
+/// Starts the cover flows of `cfg` if it asks for them and is an rmux
+/// channel, the only ones whose streams are counted.
+pub fn start_decoys(cfg: &ChannelConfig) {
+    let decoy = match cfg.decoy.as_ref() {
+        Some(d) if !d.urls.is_empty() => d.clone(),
+        _ => return,
+    };
+    let is_rmux = cfg.mux() == DEFAULT_MUX
+        && !["h2://", "naive://", "h3://", "ssh://"]
+            .iter()
+            .any(|scheme| cfg.url.starts_with(scheme));
+    if !is_rmux {
+        warn!("Decoy flows need an rmux channel, not {}", cfg.name);
+        return;
+    }
+    tokio::spawn(run_decoys(cfg.name.clone(), decoy));
+}
+
+/// Pause before the next flow, exponential so flows come as a Poisson
+/// process at `flows_per_hour`.
+fn next_pause(flows_per_hour: u32) -> Duration {
+    let mean = 3600.0 / f64::from(flows_per_hour.max(1));
+    let u: f64 = rand::thread_rng().gen_range(f64::EPSILON, 1.0);
+    Duration::from_secs_f64(-mean * u.ln())
+}
+
+/// Bytes of a body to read, log-uniform between `min_kb` and `max_kb`.
+fn body_len(min_kb: u32, max_kb: u32) -> usize {
+    let min = min_kb.max(1) as usize * 1024;
+    let max = max_kb.max(min_kb).max(1) as usize * 1024;
+    if max == min {
+        return min;
+    }
+    let (lo, hi) = ((min as f64).ln(), (max as f64).ln());
+    let x: f64 = rand::thread_rng().gen_range(lo, hi);
+    (x.exp().round() as usize).clamp(min, max)
+}
+
+async fn run_decoys(channel: String, cfg: DecoyConfig) {
+    let idle = Duration::from_secs(cfg.idle_secs.unwrap_or(DEFAULT_IDLE_SECS));
+    let flows_per_hour = cfg.flows_per_hour.unwrap_or(DEFAULT_FLOWS_PER_HOUR);
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let mut busy_at = Instant::now();
+    let mut next_flow = Instant::now() + next_pause(flows_per_hour);
+    let mut interval = time::interval(CHECK_INTERVAL);
+    info!("[{}]Decoy flows at {} an hour.", channel, flows_per_hour);
+    loop {
+        interval.tick().await;
+        let flows = in_flight.load(Ordering::SeqCst);
+        // the decoys' own streams do not count
+        if get_channel_stream_count(channel.as_str()) > flows {
+            busy_at = Instant::now();
+            continue;
+        }
+        if busy_at.elapsed() < idle || Instant::now() < next_flow {
+            continue;
+        }
+        next_flow = Instant::now() + next_pause(flows_per_hour);
+        if flows >= MAX_FLOWS_IN_FLIGHT {
+            continue;
+        }
+        let url = {
+            let mut rng = rand::thread_rng();
+            cfg.urls[rng.gen_range(0, cfg.urls.len())].clone()
+        };
+        let len = body_len(
+            cfg.min_kb.unwrap_or(DEFAULT_MIN_KB),
+            cfg.max_kb.unwrap_or(DEFAULT_MAX_KB),
+        );
+        let channel = channel.clone();
+        let in_flight = in_flight.clone();
+        in_flight.fetch_add(1, Ordering::SeqCst);
+        tokio::spawn(async move {
+            match time::timeout(
+                FLOW_TIMEOUT,
+                decoy_flow(channel.as_str(), url.as_str(), len),
+            )
+            .await
+            {
+                Ok(Ok(n)) => debug!("[{}]Decoy flow read {} bytes of {}", channel, n, url),
+                Ok(Err(e)) => debug!("[{}]Decoy flow to {} failed:{}", channel, url, e),
+                Err(_) => debug!("[{}]Decoy flow to {} timed out", channel, url),
+            }
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Fetches `url` through `channel`, reading up to `len` bytes of the
+/// response like a page load cut short.
+async fn decoy_flow(channel: &str, url: &str, len: usize) -> Result<usize, std::io::Error> {
+    let url = match Url::parse(url) {
+        Ok(u) => u,
+        Err(e) => return Err(make_io_error(&e.to_string())),
+    };
+    let host = match url.host_str() {
+        Some(h) => String::from(h),
+        None => return Err(make_io_error("decoy url without host")),
+    };
+    let port = url.port_or_known_default().unwrap_or(80);
+    let path = match url.query() {
+        Some(q) => format!("{}?{}", url.path(), q),
+        None => String::from(url.path()),
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}\r\nAccept: text/html,application/xhtml+xml,\
+         application/xml;q=0.9,*/*;q=0.8\r\nAccept-Language: en-US,en;q=0.9\r\n\
+         Connection: close\r\n\r\n",
+        path, host, USER_AGENT
+    );
+    let mut stream =
+        get_channel_stream(String::from(channel), format!("{}:{}", host, port)).await?;
+    let rc = {
+        let (reader, writer) = stream.split();
+        if url.scheme() == "https" {
+            let tls_cfg = Arc::new(new_client_config(&["http/1.1"]));
+            let tls = tls_connect_io(reader, writer, host.as_str(), tls_cfg).await?;
+            let (reader, writer) = tokio::io::split(tls);
+            fetch(reader, writer, request.as_bytes(), len).await
+        } else {
+            fetch(reader, writer, request.as_bytes(), len).await
+        }
+    };
+    let _ = stream.close();
+    rc
+}
+
+async fn fetch<R, W>(
+    mut reader: R,
+    mut writer: W,
+    request: &[u8],
+    len: usize,
+) -> Result<usize, std::io::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(request).await?;
+    writer.flush().await?;
+    let mut buf = vec![0u8; 16 * 1024];
+    let mut n = 0;
+    while n < len {
+        let want = buf.len().min(len - n);
+        match reader.read(&mut buf[..want]).await? {
+            0 => break,
+            r => n += r,
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decoy_draws() {
+        for _ in 0..1000 {
+            let n = body_len(16, 1024);
+            assert!(n >= 16 * 1024 && n <= 1024 * 1024);
+            assert!(next_pause(3600) < Duration::from_secs(60));
+        }
+        assert_eq!(body_len(64, 8), 64 * 1024);
+    }
+}
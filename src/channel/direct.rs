@@ -1,9 +1,31 @@
 use super::ChannelStream;
+use crate::config::DirectConfig;
+use crate::utils::{
+    happy_connect, proxy_connect, udp_connect, DatagramReader, DatagramWriter, DialOptions,
+    UDP_TARGET_PREFIX,
+};
 
 use std::net::Shutdown;
+use std::sync::Mutex;
 use tokio::io::AsyncRead;
 use tokio::io::AsyncWrite;
 use tokio::net::TcpStream;
+use url::Url;
+
+lazy_static! {
+    static ref DIRECT_PROXY: Mutex<Option<Url>> = Mutex::new(None);
+    static ref DIRECT_DIAL: Mutex<DialOptions> = Mutex::new(DialOptions::default());
+}
+
+pub fn init_direct(cfg: &DirectConfig) {
+    if let Some(p) = &cfg.proxy {
+        match Url::parse(p.as_str()) {
+            Ok(u) => *DIRECT_PROXY.lock().unwrap() = Some(u),
+            Err(e) => error!("invalid direct proxy url:{} with error:{}", p, e),
+        }
+    }
+    *DIRECT_DIAL.lock().unwrap() = cfg.dial_options();
+}
 
 struct DirectChannelStream {
     pub conn: TcpStream,
@@ -28,12 +50,54 @@ impl ChannelStream for DirectChannelStream {
     fn close(&mut self) -> std::io::Result<()> {
         self.conn.shutdown(Shutdown::Both)
     }
+    fn tcp_stream(&mut self) -> Option<&mut TcpStream> {
+        Some(&mut self.conn)
+    }
+}
+
+/// A `udp://` target, datagrams framed over the stream.
+struct UdpChannelStream {
+    reader: DatagramReader,
+    writer: DatagramWriter,
+}
+
+impl ChannelStream for UdpChannelStream {
+    fn split(
+        &mut self,
+    ) -> (
+        Box<dyn AsyncRead + Send + Unpin + '_>,
+        Box<dyn AsyncWrite + Send + Unpin + '_>,
+    ) {
+        (Box::new(&mut self.reader), Box::new(&mut self.writer))
+    }
+    fn close(&mut self) -> std::io::Result<()> {
+        // the socket goes away with the stream
+        Ok(())
+    }
 }
 
 pub async fn get_direct_stream(
     addr: String,
 ) -> Result<Box<dyn ChannelStream + Send>, std::io::Error> {
-    let conn = TcpStream::connect(&addr);
+    let opts = DIRECT_DIAL.lock().unwrap().clone();
+    get_direct_stream_with(addr, &opts).await
+}
+
+/// Dials a direct destination with `opts` instead of the `[direct]` ones.
+pub async fn get_direct_stream_with(
+    addr: String,
+    opts: &DialOptions,
+) -> Result<Box<dyn ChannelStream + Send>, std::io::Error> {
+    if addr.starts_with(UDP_TARGET_PREFIX) {
+        let (reader, writer) = udp_connect(&addr[UDP_TARGET_PREFIX.len()..]).await?;
+        return Ok(Box::new(UdpChannelStream { reader, writer }));
+    }
+    let proxy = DIRECT_PROXY.lock().unwrap().clone();
+    if let Some(proxy) = proxy {
+        let conn = proxy_connect(&proxy, addr.as_str(), opts).await?;
+        return Ok(Box::new(DirectChannelStream::new(conn)));
+    }
+    let conn = happy_connect(addr.as_str(), opts);
     let dur = std::time::Duration::from_secs(3);
     let s = tokio::time::timeout(dur, conn).await?;
 
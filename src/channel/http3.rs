@@ -0,0 +1,160 @@
+use super::ChannelStream;
+use crate::config::ChannelConfig;
+use crate::transport::{
+    drain_uni_streams, h2_auth_token, h3_connect, header_value, open_control_stream, read_headers,
+    write_headers, H3Reader, H3Writer, H2_AUTH_HEADER, H2_TARGET_HEADER, H3_PROTOCOL,
+};
+use crate::utils::make_io_error;
+use quinn::{Connection, NewConnection, RecvStream};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncWrite};
+use url::Url;
+
+struct H3Session {
+    id: u32,
+    conn: Connection,
+}
+
+struct H3Channel {
+    key: String,
+    authority: String,
+    /// empty for plain CONNECT, the request path for extended CONNECT
+    path: String,
+    sessions: Vec<H3Session>,
+    cursor: usize,
+}
+
+lazy_static! {
+    static ref H3_CHANNELS: Mutex<HashMap<String, H3Channel>> = Mutex::new(HashMap::new());
+}
+
+pub fn is_h3_channel(channel: &str) -> bool {
+    H3_CHANNELS.lock().unwrap().contains_key(channel)
+}
+
+pub fn get_h3_session_size(channel: &str) -> usize {
+    match H3_CHANNELS.lock().unwrap().get(channel) {
+        Some(c) => c.sessions.len(),
+        None => 0,
+    }
+}
+
+struct H3ChannelStream {
+    reader: H3Reader<RecvStream>,
+    writer: H3Writer,
+}
+
+impl ChannelStream for H3ChannelStream {
+    fn split(
+        &mut self,
+    ) -> (
+        Box<dyn AsyncRead + Send + Unpin + '_>,
+        Box<dyn AsyncWrite + Send + Unpin + '_>,
+    ) {
+        (Box::new(&mut self.reader), Box::new(&mut self.writer))
+    }
+    fn close(&mut self) -> std::io::Result<()> {
+        self.writer.reset();
+        Ok(())
+    }
+}
+
+/// Keeps one HTTP/3 connection to the remote open; every proxied connection
+/// then becomes a single CONNECT stream on it.
+pub async fn init_h3_client(config: ChannelConfig, session_id: u32) -> Result<(), std::io::Error> {
+    let conn_url = match Url::parse(config.url.as_str()) {
+        Err(e) => {
+            error!("invalid connect url:{} with error:{}", config.url, e);
+            return Err(make_io_error("invalid connect url"));
+        }
+        Ok(u) => u,
+    };
+    let host = match conn_url.host_str() {
+        Some(h) => h,
+        None => return Err(make_io_error("invalid connect url")),
+    };
+    let port = conn_url.port().unwrap_or(443);
+    let addr = config.connect_addr(&conn_url);
+    let domain = config.sni(&conn_url);
+    let authority = match config.host_header() {
+        Some(h) => String::from(h),
+        None => format!("{}:{}", host, port),
+    };
+    let (_endpoint, new_conn) = h3_connect(addr.as_str(), domain, config.zero_rtt()).await?;
+    let NewConnection {
+        connection,
+        uni_streams,
+        ..
+    } = new_conn;
+    let _control = open_control_stream(&connection).await?;
+    info!("[{}]h3 session to {} established", session_id, addr);
+    {
+        let mut channels = H3_CHANNELS.lock().unwrap();
+        let channel = channels
+            .entry(config.name.clone())
+            .or_insert_with(|| H3Channel {
+                key: config.cipher.key.clone(),
+                authority,
+                path: String::from(conn_url.path().trim_start_matches('/')),
+                sessions: Vec::new(),
+                cursor: 0,
+            });
+        channel.sessions.push(H3Session {
+            id: session_id,
+            conn: connection,
+        });
+    }
+    drain_uni_streams(uni_streams).await;
+    if let Some(channel) = H3_CHANNELS.lock().unwrap().get_mut(config.name.as_str()) {
+        channel.sessions.retain(|s| s.id != session_id);
+    }
+    info!("[{}]h3 session to {} closed", session_id, addr);
+    Ok(())
+}
+
+pub async fn get_h3_stream(
+    channel: &str,
+    addr: String,
+) -> Result<Box<dyn ChannelStream + Send>, std::io::Error> {
+    let (conn, key, authority, path) = {
+        let mut channels = H3_CHANNELS.lock().unwrap();
+        let c = match channels.get_mut(channel) {
+            Some(c) if !c.sessions.is_empty() => c,
+            _ => return Err(make_io_error("no channel found.")),
+        };
+        c.cursor = (c.cursor + 1) % c.sessions.len();
+        (
+            c.sessions[c.cursor].conn.clone(),
+            c.key.clone(),
+            c.authority.clone(),
+            c.path.clone(),
+        )
+    };
+    let (mut send, mut recv) = match conn.open_bi().await {
+        Ok(s) => s,
+        Err(e) => return Err(make_io_error(&e.to_string())),
+    };
+    let token = h2_auth_token(key.as_str(), addr.as_str());
+    let path = format!("/{}", path);
+    let mut fields = vec![(":method", "CONNECT")];
+    if path.len() > 1 {
+        fields.push((":protocol", H3_PROTOCOL));
+        fields.push((":scheme", "https"));
+        fields.push((":authority", authority.as_str()));
+        fields.push((":path", path.as_str()));
+    } else {
+        fields.push((":authority", addr.as_str()));
+    }
+    fields.push((H2_TARGET_HEADER, addr.as_str()));
+    fields.push((H2_AUTH_HEADER, token.as_str()));
+    write_headers(&mut send, &fields).await?;
+    let response = read_headers(&mut recv).await?;
+    if header_value(&response, ":status") != Some("200") {
+        return Err(make_io_error("h3 stream rejected by remote"));
+    }
+    Ok(Box::new(H3ChannelStream {
+        reader: H3Reader::new(recv),
+        writer: H3Writer::new(send),
+    }))
+}
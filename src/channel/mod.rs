@@ -1,12 +1,24 @@
+mod decoy;
 mod direct;
+mod http2;
+mod http3;
 mod rmux;
 mod routine;
+mod shadowsocks;
+mod ssh;
+mod tags;
+mod trojan;
+mod vmess;
 //mod ws;
 
 use tokio::io::AsyncRead;
 use tokio::io::AsyncWrite;
+use tokio::net::TcpStream;
 
+pub use self::direct::{get_direct_stream_with, init_direct};
 pub use self::routine::routine_channels;
+pub use self::tags::{init_channel_tags, tagged_channel};
+pub use crate::rmux::StreamPriority;
 
 pub trait ChannelStream {
     fn split(
@@ -16,16 +28,83 @@ pub trait ChannelStream {
         Box<dyn AsyncWrite + Send + Unpin + '_>,
     );
     fn close(&mut self) -> std::io::Result<()>;
+    /// The plain TCP connection behind this stream, if there is one.
+    fn tcp_stream(&mut self) -> Option<&mut TcpStream> {
+        None
+    }
+}
+
+/// Live sessions of a channel, whatever carries it.
+pub fn get_session_size(channel: &str) -> usize {
+    if http2::is_h2_channel(channel) {
+        http2::get_h2_session_size(channel)
+    } else if http3::is_h3_channel(channel) {
+        http3::get_h3_session_size(channel)
+    } else if ssh::is_ssh_channel(channel) {
+        ssh::get_ssh_session_size(channel)
+    } else if shadowsocks::is_ss_channel(channel) {
+        shadowsocks::get_ss_session_size(channel)
+    } else if trojan::is_trojan_channel(channel) {
+        trojan::get_trojan_session_size(channel)
+    } else if vmess::is_vmess_channel(channel) {
+        vmess::get_vmess_session_size(channel)
+    } else if crate::mux::is_mux_channel(channel) {
+        crate::mux::get_mux_session_size(channel)
+    } else {
+        crate::rmux::get_channel_session_size(channel)
+    }
 }
 
 pub async fn get_channel_stream(
     channel: String,
     addr: String,
+) -> Result<Box<dyn ChannelStream + Send>, std::io::Error> {
+    get_channel_stream_with_priority(channel, addr, StreamPriority::Normal).await
+}
+
+/// Like `get_channel_stream`, the frames of streams on rmux sessions are
+/// scheduled by `priority`, other channels ignore it.
+pub async fn get_channel_stream_with_priority(
+    channel: String,
+    addr: String,
+    priority: StreamPriority,
 ) -> Result<Box<dyn ChannelStream + Send>, std::io::Error> {
     //irect::get_direct_stream(addr).await
     if channel == "direct" {
         direct::get_direct_stream(addr).await
+    } else if http2::is_h2_channel(channel.as_str()) {
+        http2::get_h2_stream(channel.as_str(), addr).await
+    } else if http3::is_h3_channel(channel.as_str()) {
+        http3::get_h3_stream(channel.as_str(), addr).await
+    } else if ssh::is_ssh_channel(channel.as_str()) {
+        ssh::get_ssh_stream(channel.as_str(), addr).await
+    } else if shadowsocks::is_ss_channel(channel.as_str()) {
+        shadowsocks::get_ss_stream(channel.as_str(), addr).await
+    } else if trojan::is_trojan_channel(channel.as_str()) {
+        trojan::get_trojan_stream(channel.as_str(), addr).await
+    } else if vmess::is_vmess_channel(channel.as_str()) {
+        vmess::get_vmess_stream(channel.as_str(), addr).await
+    } else if crate::mux::is_mux_channel(channel.as_str()) {
+        crate::mux::get_mux_stream(channel.as_str(), addr).await
     } else {
-        rmux::get_rmux_stream(channel.as_str(), addr).await
+        rmux::get_rmux_stream(channel.as_str(), addr, priority, &[])
+            .await
+            .map(|(stream, _)| stream)
+    }
+}
+
+/// Like `get_channel_stream_with_priority`, rmux sessions which take early
+/// data get `early` in the SYN of the stream, as the returned flag tells;
+/// the caller writes it to the stream otherwise.
+pub async fn get_channel_stream_with_data(
+    channel: String,
+    addr: String,
+    priority: StreamPriority,
+    early: &[u8],
+) -> Result<(Box<dyn ChannelStream + Send>, bool), std::io::Error> {
+    if early.is_empty() || !crate::rmux::is_early_data_channel(channel.as_str()) {
+        let stream = get_channel_stream_with_priority(channel, addr, priority).await?;
+        return Ok((stream, false));
     }
+    rmux::get_rmux_stream(channel.as_str(), addr, priority, early).await
 }
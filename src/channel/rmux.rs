@@ -1,24 +1,121 @@
 use super::ChannelStream;
-use crate::config::{ChannelConfig, DEFAULT_RELAY_BUF_SIZE};
+use crate::config::{ChannelConfig, DEFAULT_MUX, DEFAULT_RELAY_BUF_SIZE};
+use crate::mux::{run_mux_client, MuxProtocol};
+use crate::obfs::{obfs_connect, pad_records};
 
 use crate::rmux::{
-    create_stream, new_auth_event, process_rmux_session, read_rmux_event, write_encrypt_event,
-    AuthRequest, AuthResponse, CryptoContext, MuxContext, DEFAULT_RECV_BUF_SIZE,
+    cipher_suite_id, create_stream_with_data, expand_cipher_suites, negotiate_version,
+    new_auth_event, process_rmux_session, read_rmux_event, session_key, user_session_key,
+    AuthRequest, AuthResponse, AuthTrailer, Compression, CryptoContext, Keepalive, KeyShare,
+    MuxContext, PaddingPolicy, RekeyPolicy, SessionLimits, StreamPriority, StreamWindows,
+    DEFAULT_RECV_BUF_SIZE, FEATURE_EARLY_DATA, FEATURE_HYBRID_KEX, FEATURE_PADDING,
+    SUPPORTED_FEATURES,
+};
+use crate::transport::{
+    channel_client_config, dns_connect, grpc_path, kcp_connect, noise_connect, plugin_addr,
+    quic_connect, tls_connect, GrpcReader, GrpcWriter, H2Reader, H2Writer, NoiseKeys,
+    GRPC_CONTENT_TYPE,
 };
 use crate::utils::{
-    http_proxy_connect, make_io_error, AsyncTcpStream, AsyncTokioIO, WebsocketReader,
+    happy_connect, make_io_error, proxy_connect, tfo_connect, zeroize, WebsocketReader,
     WebsocketWriter,
 };
 //use crate::utils::make_io_error;
-use async_tls::TlsConnector;
 use bytes::BytesMut;
-use futures::StreamExt;
+use futures::{FutureExt, StreamExt};
 use std::error::Error;
 use std::io::ErrorKind;
+use std::time::Duration;
 use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::http::header::{HeaderName, HeaderValue, HOST};
 use url::Url;
 
+/// The bytes each stream buffers from the server, advertised in the handshake.
+fn recv_window(config: &ChannelConfig) -> u32 {
+    config
+        .stream_window
+        .unwrap_or_else(|| StreamWindows::default_window(config.relay_buf_size()))
+}
+
+/// The compression asked of the server in the handshake.
+fn compression(config: &ChannelConfig) -> Compression {
+    let name = config
+        .compression
+        .as_ref()
+        .map(|c| c.as_str())
+        .unwrap_or("none");
+    Compression::from_name(name).unwrap_or_else(|| {
+        error!("[{}]Unsupported compression:{}", config.name, name);
+        Compression::None
+    })
+}
+
+fn padding(config: &ChannelConfig) -> PaddingPolicy {
+    config
+        .padding
+        .as_ref()
+        .map(PaddingPolicy::from)
+        .unwrap_or_default()
+}
+
+/// The features asked of the server in the handshake, early data and the
+/// hybrid key exchange only if configured.
+fn features(config: &ChannelConfig) -> u8 {
+    let mut features = SUPPORTED_FEATURES;
+    if !config.early_data.unwrap_or(false) {
+        features &= !FEATURE_EARLY_DATA;
+    }
+    if !config.hybrid_kex.unwrap_or(false) {
+        features &= !FEATURE_HYBRID_KEX;
+    }
+    features
+}
+
+/// The AEAD suites offered to the server in the handshake, none unless
+/// configured.
+fn cipher_suites(config: &ChannelConfig) -> Vec<&'static str> {
+    config
+        .cipher
+        .suites
+        .as_ref()
+        .map(|names| expand_cipher_suites(&names[..]))
+        .unwrap_or_default()
+}
+
+/// The encrypted first frame of a client session, and the secrets of the
+/// key share it carries if any.
+async fn auth_request_bytes(config: &ChannelConfig) -> (Vec<u8>, Option<KeyShare>) {
+    let auth = AuthRequest {
+        method: String::from(config.cipher.method.as_str()),
+    };
+    let share = if features(config) & FEATURE_HYBRID_KEX != 0 {
+        Some(KeyShare::generate())
+    } else {
+        None
+    };
+    let trailer = AuthTrailer {
+        window: Some(recv_window(config)),
+        compression: compression(config).mask(),
+        padding: padding(config).handshake_padding(),
+        features: features(config),
+        cipher_suites: cipher_suites(config)
+            .iter()
+            .filter_map(|m| cipher_suite_id(m))
+            .collect(),
+        key_id: String::from(config.cipher.key_id.as_deref().unwrap_or("")),
+        key_share: share.as_ref().map(|s| s.public().to_vec()),
+        ..Default::default()
+    };
+    let mut ev = new_auth_event(0, &auth, &trailer);
+    let key = session_key(&config.cipher).await;
+    let mut wctx = CryptoContext::with_key(config.cipher.method.as_str(), &key[..], 0);
+    let mut buf = BytesMut::new();
+    wctx.encrypt(&mut ev, &mut buf);
+    (buf.to_vec(), share)
+}
+
 async fn init_client<'a, R, W>(
     config: ChannelConfig,
     session_id: u32,
@@ -29,17 +126,68 @@ where
     R: AsyncBufRead + Unpin + Sized,
     W: AsyncWrite + Unpin + Sized,
 {
-    let sid = 0 as u32;
-    let auth = AuthRequest {
-        //key: String::from(key),
-        method: String::from(config.cipher.method.as_str()),
-    };
-    let ev = new_auth_event(sid, &auth);
-    let key = String::from(config.cipher.key.as_str());
+    match config.obfs.clone() {
+        Some(obfs) => {
+            let (reader, mut writer) = obfs_connect(&obfs, &mut *ri, &mut *wi).await?;
+            let mut buf_reader = tokio::io::BufReader::with_capacity(DEFAULT_RECV_BUF_SIZE, reader);
+            init_session(config, session_id, &mut buf_reader, &mut writer).await
+        }
+        None => init_session(config, session_id, ri, wi).await,
+    }
+}
+
+/// `init_client` on the halves of a stream, which is shut down after.
+async fn init_stream_client<R, W>(
+    config: ChannelConfig,
+    session_id: u32,
+    read: R,
+    mut write: W,
+) -> Result<(), std::io::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf_reader = tokio::io::BufReader::with_capacity(DEFAULT_RECV_BUF_SIZE, read);
+    let rc = init_client(config, session_id, &mut buf_reader, &mut write).await;
+    let _ = write.shutdown().await;
+    rc
+}
+
+/// Starts a session on a carrier past its obfuscation, if any.
+async fn init_session<'a, R, W>(
+    config: ChannelConfig,
+    session_id: u32,
+    ri: &'a mut R,
+    wi: &'a mut W,
+) -> Result<(), std::io::Error>
+where
+    R: AsyncBufRead + Unpin + Sized,
+    W: AsyncWrite + Unpin + Sized,
+{
+    if let Some(protocol) = MuxProtocol::from_name(config.mux()) {
+        return run_mux_client(&config, session_id, protocol, ri, wi).await;
+    }
+    let (auth, share) = auth_request_bytes(&config).await;
+    wi.write_all(&auth[..]).await?;
+    init_client_after_auth(config, session_id, share, ri, wi).await
+}
+
+/// Continues a client session whose auth frame, with `share` if any, was
+/// already written.
+async fn init_client_after_auth<'a, R, W>(
+    config: ChannelConfig,
+    session_id: u32,
+    share: Option<KeyShare>,
+    ri: &'a mut R,
+    wi: &'a mut W,
+) -> Result<(), std::io::Error>
+where
+    R: AsyncBufRead + Unpin + Sized,
+    W: AsyncWrite + Unpin + Sized,
+{
+    let key = session_key(&config.cipher).await;
     let method = String::from(config.cipher.method.as_str());
-    let mut rctx = CryptoContext::new(method.as_str(), key.as_str(), 0);
-    let mut wctx = CryptoContext::new(method.as_str(), key.as_str(), 0);
-    write_encrypt_event(&mut wctx, wi, ev).await?;
+    let mut rctx = CryptoContext::with_key(method.as_str(), &key[..], 0);
 
     let recv_ev = match read_rmux_event(&mut rctx, ri).await {
         Err(e) => return Err(make_io_error(&e.to_string())),
@@ -48,17 +196,95 @@ where
     let decoded: AuthResponse = bincode::deserialize(&recv_ev.body[..]).unwrap();
     if !decoded.success {
         //let _ = c.shutdown(std::net::Shutdown::Both);
-        return Err(std::io::Error::from(ErrorKind::ConnectionRefused));
+        if decoded.err.is_empty() {
+            return Err(std::io::Error::from(ErrorKind::ConnectionRefused));
+        }
+        error!("[{}]Server refused session: {}", config.name, decoded.err);
+        return Err(std::io::Error::new(
+            ErrorKind::ConnectionRefused,
+            decoded.err,
+        ));
+    }
+    let trailer = AuthTrailer::decode(&decoded, &recv_ev.body[..]);
+    let version = match negotiate_version(trailer.version) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("[{}]{}", config.name, e);
+            return Err(make_io_error(e.as_str()));
+        }
+    };
+    let features = features(&config) & trailer.features;
+    // the server picks one of the offered suites, older ones echo the method
+    if decoded.method != method && !cipher_suites(&config).contains(&decoded.method.as_str()) {
+        error!(
+            "[{}]Server picked cipher {} which was not offered",
+            config.name, decoded.method
+        );
+        return Err(make_io_error("server picked a cipher not offered"));
     }
-    let rctx = CryptoContext::new(method.as_str(), key.as_str(), decoded.rand);
-    let wctx = CryptoContext::new(method.as_str(), key.as_str(), decoded.rand);
-    let ctx = MuxContext::new(
+    // a listener user's key seals what follows the handshake
+    let key = match config.cipher.user_key.as_ref() {
+        Some(k) => user_session_key(&config.cipher, k.as_str()).await,
+        None => key,
+    };
+    let mut key = match share {
+        Some(share) if features & FEATURE_HYBRID_KEX != 0 => {
+            let answer = trailer.key_share.as_deref().unwrap_or(&[]);
+            match share.finish(&key[..], answer) {
+                Ok(k) => k,
+                Err(e) => {
+                    error!("[{}]{}", config.name, e);
+                    return Err(make_io_error(e.as_str()));
+                }
+            }
+        }
+        Some(_) => {
+            info!(
+                "[{}]Server does not take the hybrid key exchange.",
+                config.name
+            );
+            key
+        }
+        None => key,
+    };
+    let rctx = CryptoContext::with_key(decoded.method.as_str(), &key[..], decoded.rand);
+    let wctx = CryptoContext::with_key(decoded.method.as_str(), &key[..], decoded.rand);
+    zeroize(&mut key[..]);
+    let mut ctx = MuxContext::new(
         config.name.as_str(),
         session_id,
         rctx,
         wctx,
         config.max_alive_mins as u64 * 60,
     );
+    ctx.set_path(config.url.as_str());
+    ctx.set_windows(StreamWindows {
+        send: trailer
+            .window
+            .unwrap_or_else(|| StreamWindows::default_window(config.relay_buf_size())),
+        recv: recv_window(&config),
+    });
+    ctx.set_compression(compression(&config).negotiate(trailer.compression));
+    if features & FEATURE_PADDING != 0 {
+        ctx.set_padding(padding(&config));
+    } else {
+        ctx.set_padding(padding(&config).without_frames());
+    }
+    ctx.set_rekey(RekeyPolicy::new(
+        config.rekey_interval_mins,
+        config.rekey_after_mb,
+    ));
+    ctx.set_limits(SessionLimits::new(
+        config.max_session_age_mins,
+        config.reconnect_interval_mins,
+        config.max_mb_per_key,
+    ));
+    ctx.set_protocol(version, features);
+    ctx.set_keepalive(Keepalive {
+        interval: Duration::from_secs(config.ping_interval_secs()),
+        timeout: Duration::from_secs(config.ping_timeout_secs()),
+        max_missed: config.max_missed_pings(),
+    });
     process_rmux_session(
         ctx, // config.name.as_str(),
         // session_id,
@@ -74,6 +300,37 @@ where
     Ok(())
 }
 
+fn ws_request(url: &str, config: &ChannelConfig) -> Result<Request, std::io::Error> {
+    let mut req = match url.into_client_request() {
+        Err(e) => return Err(make_io_error(&e.to_string())),
+        Ok(r) => r,
+    };
+    if let Some(host) = config.host_header() {
+        match HeaderValue::from_str(host) {
+            Ok(v) => {
+                req.headers_mut().insert(HOST, v);
+            }
+            Err(e) => return Err(make_io_error(&e.to_string())),
+        }
+    }
+    if let Some(ws) = &config.ws {
+        if let Some(headers) = &ws.headers {
+            for (k, v) in headers.iter() {
+                let name = match HeaderName::from_bytes(k.as_bytes()) {
+                    Ok(n) => n,
+                    Err(e) => return Err(make_io_error(&e.to_string())),
+                };
+                let value = match HeaderValue::from_str(v.as_str()) {
+                    Ok(v) => v,
+                    Err(e) => return Err(make_io_error(&e.to_string())),
+                };
+                req.headers_mut().insert(name, value);
+            }
+        }
+    }
+    Ok(req)
+}
+
 pub async fn init_rmux_client(
     config: ChannelConfig,
     session_id: u32,
@@ -91,27 +348,73 @@ pub async fn init_rmux_client(
         }
         Ok(u) => u,
     };
-    let addr = if config.sni_proxy.is_some() {
-        let mut v = String::from(config.sni_proxy.as_ref().unwrap());
-        if v.find(':').is_none() {
-            v.push_str(":443");
-        }
-        v
-    } else {
-        format!(
-            "{}:{}",
-            conn_url.host().as_ref().unwrap(),
-            conn_url.port_or_known_default().unwrap()
-        )
-    };
+    let addr = config.connect_addr(&conn_url);
     info!("connect rmux:{} to addr:{}", url, addr);
+    if config.plugin.is_some() && ["quic", "kcp", "dns", "unix"].contains(&conn_url.scheme()) {
+        return Err(make_io_error("plugins only take tcp channels"));
+    }
+    let addr = plugin_addr(&config, addr).await?;
 
-    let domain = if config.sni.is_some() {
-        config.sni.as_ref().unwrap().as_str()
-    } else {
-        conn_url.host_str().unwrap()
-    };
-    let mut conn = match config.proxy.as_ref() {
+    let domain = config.sni(&conn_url);
+    if conn_url.scheme() == "quic" {
+        let mut stream = quic_connect(addr.as_str(), domain, config.zero_rtt()).await?;
+        let mut buf_reader =
+            tokio::io::BufReader::with_capacity(DEFAULT_RECV_BUF_SIZE, &mut stream.recv);
+        let rc = init_client(config, session_id, &mut buf_reader, &mut stream.send).await;
+        stream.conn.close(quinn::VarInt::from_u32(0), b"");
+        return rc;
+    }
+    if conn_url.scheme() == "kcp" {
+        let kcp_cfg = config.kcp.clone().unwrap_or_default();
+        let stream = kcp_connect(addr.as_str(), &kcp_cfg).await?;
+        let (read, mut write) = tokio::io::split(stream);
+        let mut buf_reader = tokio::io::BufReader::with_capacity(DEFAULT_RECV_BUF_SIZE, read);
+        return init_client(config, session_id, &mut buf_reader, &mut write).await;
+    }
+    if conn_url.scheme() == "dns" {
+        // the resolver to query, the remote itself or whatever the network offers
+        let resolver = match conn_url.port() {
+            Some(_) => addr,
+            None => format!("{}:53", conn_url.host_str().unwrap_or("")),
+        };
+        let domain = conn_url.path().trim_matches('/');
+        let stream = dns_connect(resolver.as_str(), domain, config.cipher.key.as_str()).await?;
+        let (read, mut write) = tokio::io::split(stream);
+        let mut buf_reader = tokio::io::BufReader::with_capacity(DEFAULT_RECV_BUF_SIZE, read);
+        return init_client(config, session_id, &mut buf_reader, &mut write).await;
+    }
+    #[cfg(unix)]
+    {
+        if conn_url.scheme() == "unix" {
+            info!("Unix socket connect {}", conn_url.path());
+            let stream = tokio::net::UnixStream::connect(conn_url.path()).await?;
+            let (read, mut write) = tokio::io::split(stream);
+            let mut buf_reader = tokio::io::BufReader::with_capacity(DEFAULT_RECV_BUF_SIZE, read);
+            return init_client(config, session_id, &mut buf_reader, &mut write).await;
+        }
+    }
+    if conn_url.scheme() == "rmux"
+        && config.tcp_fast_open()
+        && config.proxy.is_none()
+        && config.plugin.is_none()
+        && config.mux() == DEFAULT_MUX
+        && config.obfs.is_none()
+    {
+        let raddr = match tokio::net::lookup_host(addr.as_str()).await?.next() {
+            Some(a) => a,
+            None => return Err(make_io_error("no address resolved")),
+        };
+        info!("TCP fast open connect {}", raddr);
+        let (auth, share) = auth_request_bytes(&config).await;
+        let mut conn = tfo_connect(raddr, auth, &config.dial_options()).await?;
+        let (read, mut write) = conn.split();
+        let mut buf_reader = tokio::io::BufReader::with_capacity(DEFAULT_RECV_BUF_SIZE, read);
+        let rc =
+            init_client_after_auth(config, session_id, share, &mut buf_reader, &mut write).await;
+        let _ = conn.shutdown(std::net::Shutdown::Both);
+        return rc;
+    }
+    let mut conn = match config.proxy.as_ref().filter(|_| config.plugin.is_none()) {
         Some(p) => {
             let proxy_url = match Url::parse(p.as_str()) {
                 Err(e) => {
@@ -120,11 +423,12 @@ pub async fn init_rmux_client(
                 }
                 Ok(u) => u,
             };
-            http_proxy_connect(&proxy_url, addr.as_str()).await?
+            proxy_connect(&proxy_url, addr.as_str(), &config.dial_options()).await?
         }
         None => {
             info!("TCP connect {}", addr);
-            let c = TcpStream::connect(&addr);
+            let opts = config.dial_options();
+            let c = happy_connect(addr.as_str(), &opts);
             let dur = std::time::Duration::from_secs(5);
             let s = tokio::time::timeout(dur, c).await?;
             match s {
@@ -137,11 +441,14 @@ pub async fn init_rmux_client(
     };
     match conn_url.scheme() {
         "ws" | "wss" => {
-            if !url.ends_with('/') {
-                url.push_str("/relay")
-            } else {
-                url.push_str("relay")
+            let path = config.ws_path();
+            if url.ends_with('/') {
+                url.pop();
+            }
+            if !path.starts_with('/') {
+                url.push('/');
             }
+            url.push_str(path);
             info!("connect url:{}", url);
         }
         _ => {}
@@ -157,8 +464,37 @@ pub async fn init_rmux_client(
                 return rc;
             }
         }
+        "tls" => {
+            info!("TLS connect {:?}", domain);
+            let tls_cfg = channel_client_config(&config, &["http/1.1"])?;
+            let conn = tls_connect(conn, domain, tls_cfg).await?;
+            let (read, write) = tokio::io::split(conn);
+            let rc = match config.record_bucket() {
+                Some(bucket) => {
+                    let (read, write) = pad_records(bucket, read, write);
+                    init_stream_client(config, session_id, read, write).await
+                }
+                None => init_stream_client(config, session_id, read, write).await,
+            };
+            if rc.is_err() {
+                return rc;
+            }
+        }
+        "noise" => {
+            let keys = match config.noise.as_ref() {
+                Some(cfg) => NoiseKeys::new(cfg, false)?,
+                None => return Err(make_io_error("noise channel requires 'noise' config")),
+            };
+            let (read, write) = tokio::io::split(conn);
+            let (read, write) = noise_connect(&keys, read, write).await?;
+            let rc = init_stream_client(config, session_id, read, write).await;
+            if rc.is_err() {
+                return rc;
+            }
+        }
         "ws" => {
-            let ws = match tokio_tungstenite::client_async(url, conn).await {
+            let req = ws_request(url.as_str(), &config)?;
+            let ws = match tokio_tungstenite::client_async(req, conn).await {
                 Err(e) => return Err(make_io_error(&e.to_string())),
                 Ok((s, _)) => s,
             };
@@ -173,13 +509,11 @@ pub async fn init_rmux_client(
             }
         }
         "wss" => {
-            let connector = TlsConnector::default();
-            let conn = AsyncTcpStream::new(conn);
-            //let host = conn_url.host_str();
             info!("TLS connect {:?}", domain);
-            let tls_stream = connector.connect(domain, conn)?.await?;
-            let conn = AsyncTokioIO::new(tls_stream);
-            let ws = match tokio_tungstenite::client_async(url, conn).await {
+            let tls_cfg = channel_client_config(&config, &["http/1.1"])?;
+            let conn = tls_connect(conn, domain, tls_cfg).await?;
+            let req = ws_request(url.as_str(), &config)?;
+            let ws = match tokio_tungstenite::client_async(req, conn).await {
                 Err(e) => return Err(make_io_error(&e.to_string())),
                 Ok((s, _)) => s,
             };
@@ -193,6 +527,62 @@ pub async fn init_rmux_client(
                 return rc;
             }
         }
+        "grpc" => {
+            let authority = match config.host_header() {
+                Some(h) => String::from(h),
+                None => format!(
+                    "{}:{}",
+                    conn_url.host_str().unwrap(),
+                    conn_url.port().unwrap_or(443)
+                ),
+            };
+            let tls_cfg = channel_client_config(&config, &["h2"])?;
+            let tls = tls_connect(conn, domain, tls_cfg).await?;
+            let (sender, connection) = match h2::client::handshake(tls).await {
+                Ok(v) => v,
+                Err(e) => return Err(make_io_error(&e.to_string())),
+            };
+            tokio::spawn(connection.map(|_| ()));
+            let (response, send) = {
+                let mut sender = match sender.ready().await {
+                    Ok(s) => s,
+                    Err(e) => return Err(make_io_error(&e.to_string())),
+                };
+                let req = match http::Request::builder()
+                    .method("POST")
+                    .uri(format!(
+                        "https://{}{}",
+                        authority,
+                        grpc_path(conn_url.path())
+                    ))
+                    .header("content-type", GRPC_CONTENT_TYPE)
+                    .header("te", "trailers")
+                    .body(())
+                {
+                    Ok(r) => r,
+                    Err(e) => return Err(make_io_error(&e.to_string())),
+                };
+                match sender.send_request(req, false) {
+                    Ok(v) => v,
+                    Err(e) => return Err(make_io_error(&e.to_string())),
+                }
+            };
+            let response = match response.await {
+                Ok(r) => r,
+                Err(e) => return Err(make_io_error(&e.to_string())),
+            };
+            if response.status() != http::StatusCode::OK {
+                return Err(make_io_error("grpc call rejected by remote"));
+            }
+            let reader = GrpcReader::new(H2Reader::new(response.into_body()));
+            let mut writer = GrpcWriter::new(H2Writer::new(send), false);
+            let mut buf_reader = tokio::io::BufReader::with_capacity(DEFAULT_RECV_BUF_SIZE, reader);
+            let rc = init_client(config, session_id, &mut buf_reader, &mut writer).await;
+            let _ = writer.shutdown().await;
+            if rc.is_err() {
+                return rc;
+            }
+        }
         _ => {
             let _ = conn.shutdown(std::net::Shutdown::Both);
             error!("unknown schema:{}", conn_url.scheme());
@@ -205,7 +595,17 @@ pub async fn init_rmux_client(
 pub async fn get_rmux_stream(
     channel: &str,
     addr: String,
-) -> Result<Box<dyn ChannelStream + Send>, std::io::Error> {
-    let stream = create_stream(channel, "tcp", addr.as_str(), DEFAULT_RELAY_BUF_SIZE).await?;
-    Ok(Box::new(stream))
+    priority: StreamPriority,
+    early: &[u8],
+) -> Result<(Box<dyn ChannelStream + Send>, bool), std::io::Error> {
+    let (stream, sent) = create_stream_with_data(
+        channel,
+        "tcp",
+        addr.as_str(),
+        DEFAULT_RELAY_BUF_SIZE,
+        priority,
+        early,
+    )
+    .await?;
+    Ok((Box::new(stream), sent))
 }
@@ -1,30 +1,184 @@
+use super::decoy::start_decoys;
+use super::http2::{get_h2_session_size, init_h2_client};
+use super::http3::{get_h3_session_size, init_h3_client};
 use super::rmux::init_rmux_client;
-use crate::config::ChannelConfig;
-use crate::rmux::{get_channel_session_size, routine_all_sessions};
+use super::shadowsocks::init_ss_channel;
+use super::ssh::{get_ssh_session_size, init_ssh_client};
+use super::trojan::init_trojan_channel;
+use super::vmess::init_vmess_channel;
+use crate::config::{ChannelConfig, DEFAULT_MUX};
+use crate::rmux::{
+    get_channel_session_paths, is_channel_pool_busy, pool_wakeup, routine_all_sessions,
+    set_channel_datagrams, set_channel_pool, set_channel_ports, set_channel_vhosts,
+    shrink_channel_pool,
+};
 use chrono::{Local, Timelike};
 use futures::FutureExt;
 use rand::Rng;
 use std::sync::atomic::{AtomicU32, Ordering};
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::time;
 
+/// Picks the path of a multipath channel with the fewest live sessions.
+fn next_path(cfg: &ChannelConfig, live: &mut Vec<String>) -> String {
+    let mut selected = cfg.url.as_str();
+    let mut min = usize::MAX;
+    for path in cfg.paths() {
+        let n = live.iter().filter(|p| p.as_str() == path).count();
+        if n < min {
+            min = n;
+            selected = path;
+        }
+    }
+    live.push(String::from(selected));
+    String::from(selected)
+}
+
+const STREAM_CHANNEL_SCHEMES: [&str; 3] = ["ss://", "trojan://", "vmess://"];
+
+/// Channels to servers of other protocols open one connection per stream,
+/// there are no sessions to keep for them.
+fn is_stream_channel(cfg: &ChannelConfig) -> bool {
+    STREAM_CHANNEL_SCHEMES
+        .iter()
+        .any(|scheme| cfg.url.starts_with(scheme))
+}
+
+fn init_stream_channel(cfg: &ChannelConfig) -> Result<(), std::io::Error> {
+    if cfg.url.starts_with("ss://") {
+        init_ss_channel(cfg)
+    } else if cfg.url.starts_with("trojan://") {
+        init_trojan_channel(cfg)
+    } else {
+        init_vmess_channel(cfg)
+    }
+}
+
+const WAKEUP_GAP: Duration = Duration::from_secs(2);
+
 pub async fn routine_channels(cfgs: Option<Vec<ChannelConfig>>) {
     let mut interval = time::interval(Duration::from_secs(5));
     let session_id_seed = AtomicU32::new(0);
     let mut ping_time: u64 = 0;
+    let mut ping_interval: Option<u64> = None;
+    if let Some(ccfgs) = &cfgs {
+        for channel_cfg in ccfgs.iter() {
+            if is_stream_channel(channel_cfg) {
+                if let Err(e) = init_stream_channel(channel_cfg) {
+                    error!("Failed to init channel {}; error={}", channel_cfg.name, e);
+                }
+                continue;
+            }
+            set_channel_pool(channel_cfg.name.as_str(), channel_cfg.pool());
+            if channel_cfg.mux() == DEFAULT_MUX {
+                let datagrams = channel_cfg.datagrams.unwrap_or(false);
+                set_channel_datagrams(channel_cfg.name.as_str(), datagrams);
+            }
+            if let Some(vhosts) = &channel_cfg.vhosts {
+                set_channel_vhosts(channel_cfg.name.as_str(), vhosts);
+            }
+            if let Some(ports) = &channel_cfg.reverse {
+                set_channel_ports(channel_cfg.name.as_str(), ports);
+            }
+            start_decoys(channel_cfg);
+            let secs = channel_cfg.ping_interval_secs();
+            ping_interval = Some(ping_interval.map_or(secs, |v| v.min(secs)));
+        }
+    }
+    let ping_interval = ping_interval.unwrap_or(30);
+    let mut last_wakeup = Instant::now();
     loop {
-        interval.tick().await;
+        let woken = tokio::select! {
+            _ = interval.tick() => false,
+            _ = pool_wakeup() => true,
+        };
+        // the sessions dialed on the last wakeup may not be up yet
+        if woken {
+            if last_wakeup.elapsed() < WAKEUP_GAP {
+                continue;
+            }
+            last_wakeup = Instant::now();
+        }
         let now = Local::now();
         if let Some(ccfgs) = &cfgs {
             for channel_cfg in ccfgs.iter() {
                 if !channel_cfg.is_valid_hour(now.hour() as u8) {
                     continue;
                 }
-                let count = get_channel_session_size(channel_cfg.name.as_str());
-                if count < channel_cfg.conns_per_host as usize {
-                    let n = channel_cfg.conns_per_host as usize - count;
+                // connected per stream, nothing to keep alive
+                if is_stream_channel(channel_cfg) {
+                    continue;
+                }
+                let is_h2 =
+                    channel_cfg.url.starts_with("h2://") || channel_cfg.url.starts_with("naive://");
+                let is_h3 = channel_cfg.url.starts_with("h3://");
+                let is_ssh = channel_cfg.url.starts_with("ssh://");
+                let count = if is_h2 {
+                    get_h2_session_size(channel_cfg.name.as_str())
+                } else if is_h3 {
+                    get_h3_session_size(channel_cfg.name.as_str())
+                } else if is_ssh {
+                    get_ssh_session_size(channel_cfg.name.as_str())
+                } else {
+                    super::get_session_size(channel_cfg.name.as_str())
+                };
+                let mut n = channel_cfg.min_sessions().saturating_sub(count);
+                if n == 0
+                    && !is_h2
+                    && !is_h3
+                    && !is_ssh
+                    && count < channel_cfg.max_sessions()
+                    && is_channel_pool_busy(channel_cfg.name.as_str())
+                {
+                    n = 1;
+                }
+                if !is_h2 && !is_h3 && !is_ssh {
+                    shrink_channel_pool(channel_cfg.name.as_str(), channel_cfg.min_sessions());
+                }
+                if n > 0 {
+                    let mut live = get_channel_session_paths(channel_cfg.name.as_str());
                     for _ in 0..n {
-                        let init_cfg = channel_cfg.clone();
+                        let mut init_cfg = channel_cfg.clone();
+                        if is_h2 {
+                            let f = init_h2_client(
+                                init_cfg,
+                                session_id_seed.fetch_add(1, Ordering::SeqCst),
+                            )
+                            .map(|r| {
+                                if let Err(e) = r {
+                                    error!("Failed to init_h2_client; error={}", e);
+                                }
+                            });
+                            tokio::spawn(f);
+                            continue;
+                        }
+                        if is_h3 {
+                            let f = init_h3_client(
+                                init_cfg,
+                                session_id_seed.fetch_add(1, Ordering::SeqCst),
+                            )
+                            .map(|r| {
+                                if let Err(e) = r {
+                                    error!("Failed to init_h3_client; error={}", e);
+                                }
+                            });
+                            tokio::spawn(f);
+                            continue;
+                        }
+                        if is_ssh {
+                            let f = init_ssh_client(
+                                init_cfg,
+                                session_id_seed.fetch_add(1, Ordering::SeqCst),
+                            )
+                            .map(|r| {
+                                if let Err(e) = r {
+                                    error!("Failed to init_ssh_client; error={}", e);
+                                }
+                            });
+                            tokio::spawn(f);
+                            continue;
+                        }
+                        init_cfg.url = next_path(channel_cfg, &mut live);
                         let f = init_rmux_client(
                             init_cfg,
                             session_id_seed.fetch_add(1, Ordering::SeqCst),
@@ -43,9 +197,10 @@ pub async fn routine_channels(cfgs: Option<Vec<ChannelConfig>>) {
             Ok(n) => {
                 let rand_inc: i64 = {
                     let mut rng = rand::thread_rng();
-                    rng.gen_range(-10, 10)
+                    let jitter = (ping_interval / 3) as i64;
+                    rng.gen_range(-jitter, jitter + 1)
                 };
-                if n.as_secs() - ping_time > (30 + rand_inc) as u64 {
+                if n.as_secs() - ping_time > (ping_interval as i64 + rand_inc) as u64 {
                     routine_all_sessions().await;
                     ping_time = n.as_secs();
                 }
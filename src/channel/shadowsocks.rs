@@ -0,0 +1,134 @@
+use super::ChannelStream;
+use crate::config::ChannelConfig;
+use crate::transport::{
+    encode_ss_addr, plugin_addr, ShadowsocksCipher, ShadowsocksReader, ShadowsocksWriter,
+};
+use crate::utils::{
+    happy_connect, make_io_error, proxy_connect, split_owned, OwnedReadHalf, OwnedWriteHalf,
+    UDP_TARGET_PREFIX,
+};
+use bytes::BytesMut;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use url::Url;
+
+struct SsChannel {
+    config: ChannelConfig,
+    addr: String,
+    cipher: ShadowsocksCipher,
+}
+
+lazy_static! {
+    static ref SS_CHANNELS: Mutex<HashMap<String, SsChannel>> = Mutex::new(HashMap::new());
+}
+
+pub fn is_ss_channel(channel: &str) -> bool {
+    SS_CHANNELS.lock().unwrap().contains_key(channel)
+}
+
+/// An ss remote has no sessions to keep, every stream is its own connection,
+/// so a configured channel is always available.
+pub fn get_ss_session_size(channel: &str) -> usize {
+    if is_ss_channel(channel) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Registers an `ss://host:port` channel, an existing shadowsocks server
+/// used as the remote with `cipher.key` as its password.
+pub fn init_ss_channel(config: &ChannelConfig) -> Result<(), std::io::Error> {
+    let conn_url = match Url::parse(config.url.as_str()) {
+        Err(e) => {
+            error!("invalid connect url:{} with error:{}", config.url, e);
+            return Err(make_io_error("invalid connect url"));
+        }
+        Ok(u) => u,
+    };
+    let host = match conn_url.host_str() {
+        Some(h) => h,
+        None => return Err(make_io_error("invalid connect url")),
+    };
+    let addr = match &config.connect_host {
+        Some(_) => config.connect_addr(&conn_url),
+        None => format!("{}:{}", host, conn_url.port().unwrap_or(8388)),
+    };
+    let cipher = ShadowsocksCipher::new(config.cipher.method.as_str(), config.cipher.key.as_str())?;
+    SS_CHANNELS.lock().unwrap().insert(
+        config.name.clone(),
+        SsChannel {
+            config: config.clone(),
+            addr,
+            cipher,
+        },
+    );
+    Ok(())
+}
+
+struct SsChannelStream {
+    reader: ShadowsocksReader<OwnedReadHalf<TcpStream>>,
+    writer: ShadowsocksWriter<OwnedWriteHalf<TcpStream>>,
+}
+
+impl ChannelStream for SsChannelStream {
+    fn split(
+        &mut self,
+    ) -> (
+        Box<dyn AsyncRead + Send + Unpin + '_>,
+        Box<dyn AsyncWrite + Send + Unpin + '_>,
+    ) {
+        (Box::new(&mut self.reader), Box::new(&mut self.writer))
+    }
+    fn close(&mut self) -> std::io::Result<()> {
+        // the connection goes away with the stream
+        Ok(())
+    }
+}
+
+pub async fn get_ss_stream(
+    channel: &str,
+    addr: String,
+) -> Result<Box<dyn ChannelStream + Send>, std::io::Error> {
+    if addr.starts_with(UDP_TARGET_PREFIX) {
+        return Err(make_io_error(
+            "udp targets are not relayed over shadowsocks",
+        ));
+    }
+    let (config, server, cipher) = match SS_CHANNELS.lock().unwrap().get(channel) {
+        Some(c) => (c.config.clone(), c.addr.clone(), c.cipher.clone()),
+        None => return Err(make_io_error("no channel found.")),
+    };
+    let server = plugin_addr(&config, server).await?;
+    let conn = match config.proxy.as_ref().filter(|_| config.plugin.is_none()) {
+        Some(p) => {
+            let proxy_url = match Url::parse(p.as_str()) {
+                Err(e) => {
+                    error!("invalid proxy url:{} with error:{}", p, e);
+                    return Err(make_io_error("invalid proxy url"));
+                }
+                Ok(u) => u,
+            };
+            proxy_connect(&proxy_url, server.as_str(), &config.dial_options()).await?
+        }
+        None => {
+            let opts = config.dial_options();
+            let c = happy_connect(server.as_str(), &opts);
+            let dur = std::time::Duration::from_secs(5);
+            tokio::time::timeout(dur, c).await??
+        }
+    };
+    let (r, w) = split_owned(conn);
+    let mut writer = ShadowsocksWriter::new(w, &cipher)?;
+    // the address goes out right away, servers which speak first would
+    // otherwise never hear from us
+    let mut header = BytesMut::new();
+    encode_ss_addr(addr.as_str(), &mut header)?;
+    writer.write_all(&header[..]).await?;
+    Ok(Box::new(SsChannelStream {
+        reader: ShadowsocksReader::new(r, &cipher),
+        writer,
+    }))
+}
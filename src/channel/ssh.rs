@@ -0,0 +1,205 @@
+use super::ChannelStream;
+use crate::config::ChannelConfig;
+use crate::utils::make_io_error;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use url::Url;
+
+const MASTER_READY_TIMEOUT: Duration = Duration::from_secs(15);
+
+struct SshSession {
+    id: u32,
+    control_path: PathBuf,
+}
+
+struct SshChannel {
+    command: String,
+    // port, options and destination shared by the master and every stream
+    args: Vec<String>,
+    sessions: Vec<SshSession>,
+    cursor: usize,
+}
+
+lazy_static! {
+    static ref SSH_CHANNELS: Mutex<HashMap<String, SshChannel>> = Mutex::new(HashMap::new());
+}
+
+pub fn is_ssh_channel(channel: &str) -> bool {
+    SSH_CHANNELS.lock().unwrap().contains_key(channel)
+}
+
+pub fn get_ssh_session_size(channel: &str) -> usize {
+    match SSH_CHANNELS.lock().unwrap().get(channel) {
+        Some(c) => c.sessions.len(),
+        None => 0,
+    }
+}
+
+fn ssh_args(config: &ChannelConfig, conn_url: &Url) -> Result<Vec<String>, std::io::Error> {
+    let host = match conn_url.host_str() {
+        Some(h) => h,
+        None => return Err(make_io_error("invalid connect url")),
+    };
+    let mut args = vec![
+        String::from("-p"),
+        conn_url.port().unwrap_or(22).to_string(),
+        // never wait for a password or passphrase prompt nobody can answer
+        String::from("-o"),
+        String::from("BatchMode=yes"),
+    ];
+    if let Some(ssh) = &config.ssh {
+        if let Some(identity) = &ssh.identity_file {
+            args.push(String::from("-i"));
+            args.push(identity.clone());
+        }
+        for opt in ssh.options.iter().flatten() {
+            args.push(String::from("-o"));
+            args.push(opt.clone());
+        }
+    }
+    if conn_url.username().is_empty() {
+        args.push(String::from(host));
+    } else {
+        args.push(format!("{}@{}", conn_url.username(), host));
+    }
+    Ok(args)
+}
+
+struct SshChannelStream {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+impl ChannelStream for SshChannelStream {
+    fn split(
+        &mut self,
+    ) -> (
+        Box<dyn AsyncRead + Send + Unpin + '_>,
+        Box<dyn AsyncWrite + Send + Unpin + '_>,
+    ) {
+        (Box::new(&mut self.stdout), Box::new(&mut self.stdin))
+    }
+    fn close(&mut self) -> std::io::Result<()> {
+        self.child.kill()
+    }
+}
+
+/// Runs one OpenSSH ControlMaster connection as a session of the channel,
+/// until it exits.
+pub async fn init_ssh_client(config: ChannelConfig, session_id: u32) -> Result<(), std::io::Error> {
+    let conn_url = match Url::parse(config.url.as_str()) {
+        Err(e) => {
+            error!("invalid connect url:{} with error:{}", config.url, e);
+            return Err(make_io_error("invalid connect url"));
+        }
+        Ok(u) => u,
+    };
+    let args = ssh_args(&config, &conn_url)?;
+    let command = String::from(config.ssh.clone().unwrap_or_default().command());
+    let control_path = std::env::temp_dir().join(format!(
+        "rsnova-ssh-{}-{}-{}.sock",
+        config.name,
+        std::process::id(),
+        session_id
+    ));
+    let _ = std::fs::remove_file(&control_path);
+    let mut master = Command::new(command.as_str())
+        .arg("-M")
+        .arg("-N")
+        .arg("-S")
+        .arg(&control_path)
+        .arg("-o")
+        .arg("ControlPersist=no")
+        .arg("-o")
+        .arg(format!(
+            "ServerAliveInterval={}",
+            config.ping_interval_secs()
+        ))
+        .args(&args)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    // the control socket shows up once the connection is authenticated
+    let start = Instant::now();
+    while !control_path.exists() {
+        if start.elapsed() > MASTER_READY_TIMEOUT {
+            return Err(make_io_error("ssh master connection timeout"));
+        }
+        if let Ok(status) = tokio::time::timeout(Duration::from_millis(200), &mut master).await {
+            error!("ssh master exited with {:?}", status);
+            return Err(make_io_error("ssh master connection failed"));
+        }
+    }
+    info!("[{}]ssh session to {} established", session_id, config.url);
+    {
+        let mut channels = SSH_CHANNELS.lock().unwrap();
+        let channel = channels
+            .entry(config.name.clone())
+            .or_insert_with(|| SshChannel {
+                command: command.clone(),
+                args: args.clone(),
+                sessions: Vec::new(),
+                cursor: 0,
+            });
+        channel.sessions.push(SshSession {
+            id: session_id,
+            control_path: control_path.clone(),
+        });
+    }
+    let rc = master.await;
+    if let Some(channel) = SSH_CHANNELS.lock().unwrap().get_mut(config.name.as_str()) {
+        channel.sessions.retain(|s| s.id != session_id);
+    }
+    let _ = std::fs::remove_file(&control_path);
+    info!("[{}]ssh session to {} closed", session_id, config.url);
+    rc.map(|_| ())
+}
+
+/// Opens a direct-tcpip channel to `addr` over the master connection, the
+/// `ssh -W` process then relays it over its stdio.
+pub async fn get_ssh_stream(
+    channel: &str,
+    addr: String,
+) -> Result<Box<dyn ChannelStream + Send>, std::io::Error> {
+    let (command, args, control_path) = {
+        let mut channels = SSH_CHANNELS.lock().unwrap();
+        let c = match channels.get_mut(channel) {
+            Some(c) if !c.sessions.is_empty() => c,
+            _ => return Err(make_io_error("no channel found.")),
+        };
+        c.cursor = (c.cursor + 1) % c.sessions.len();
+        (
+            c.command.clone(),
+            c.args.clone(),
+            c.sessions[c.cursor].control_path.clone(),
+        )
+    };
+    let mut child = Command::new(command.as_str())
+        .arg("-S")
+        .arg(&control_path)
+        .arg("-o")
+        .arg("ControlMaster=no")
+        .arg("-W")
+        .arg(addr.as_str())
+        .args(&args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdin = child.stdin.take();
+    let stdout = child.stdout.take();
+    match (stdin, stdout) {
+        (Some(stdin), Some(stdout)) => Ok(Box::new(SshChannelStream {
+            child,
+            stdin,
+            stdout,
+        })),
+        _ => Err(make_io_error("ssh stdio unavailable")),
+    }
+}
@@ -0,0 +1,70 @@
+//! Tags of the channels, so a pac rule can name a group of remotes like
+//! "proxy:tokyo" instead of one channel. The channels of a tag are kept in
+//! config order, the first one with a session takes the tag's streams and
+//! the later ones are its fallbacks.
+use super::get_session_size;
+use crate::config::ChannelConfig;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static! {
+    static ref CHANNEL_TAGS: Mutex<HashMap<String, Vec<String>>> = Mutex::new(HashMap::new());
+}
+
+/// The channels named or tagged with each tag, in order, from the names
+/// and tags of the channels.
+fn tag_channels<'a, I>(channels: I) -> HashMap<String, Vec<String>>
+where
+    I: Iterator<Item = (&'a String, &'a [String])>,
+{
+    let mut tags: HashMap<String, Vec<String>> = HashMap::new();
+    for (name, channel_tags) in channels {
+        for tag in std::iter::once(name).chain(channel_tags) {
+            let members = tags.entry(tag.clone()).or_default();
+            if !members.contains(name) {
+                members.push(name.clone());
+            }
+        }
+    }
+    tags
+}
+
+pub fn init_channel_tags(channels: &[ChannelConfig]) {
+    let tags = tag_channels(
+        channels
+            .iter()
+            .map(|c| (&c.name, c.tags.as_deref().unwrap_or(&[]))),
+    );
+    *CHANNEL_TAGS.lock().unwrap() = tags;
+}
+
+/// The first channel named or tagged `tag` which has a session.
+pub fn tagged_channel(tag: &str) -> Option<String> {
+    let members = CHANNEL_TAGS.lock().unwrap().get(tag).cloned()?;
+    members
+        .into_iter()
+        .find(|name| get_session_size(name.as_str()) > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_channels() {
+        let channels = [
+            (
+                String::from("tokyo-1"),
+                vec![String::from("tokyo"), String::from("asia")],
+            ),
+            (String::from("tokyo-2"), vec![String::from("tokyo")]),
+            (String::from("frankfurt"), vec![]),
+        ];
+        let tags = tag_channels(channels.iter().map(|(n, t)| (n, t.as_slice())));
+        assert_eq!(tags["tokyo"], vec!["tokyo-1", "tokyo-2"]);
+        assert_eq!(tags["asia"], vec!["tokyo-1"]);
+        assert_eq!(tags["tokyo-2"], vec!["tokyo-2"]);
+        assert_eq!(tags["frankfurt"], vec!["frankfurt"]);
+        assert!(!tags.contains_key("europe"));
+    }
+}
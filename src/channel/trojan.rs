@@ -0,0 +1,123 @@
+use super::ChannelStream;
+use crate::config::ChannelConfig;
+use crate::transport::{
+    channel_client_config, encode_trojan_request, plugin_addr, tls_connect, trojan_hash,
+    TlsClientStream, TROJAN_CMD_CONNECT,
+};
+use crate::utils::{happy_connect, make_io_error, proxy_connect, UDP_TARGET_PREFIX};
+use bytes::BytesMut;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use url::Url;
+
+struct TrojanChannel {
+    config: ChannelConfig,
+    url: Url,
+    hash: String,
+}
+
+lazy_static! {
+    static ref TROJAN_CHANNELS: Mutex<HashMap<String, TrojanChannel>> = Mutex::new(HashMap::new());
+}
+
+pub fn is_trojan_channel(channel: &str) -> bool {
+    TROJAN_CHANNELS.lock().unwrap().contains_key(channel)
+}
+
+/// Like ss remotes, every stream is its own TLS connection.
+pub fn get_trojan_session_size(channel: &str) -> usize {
+    if is_trojan_channel(channel) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Registers a `trojan://host:port` channel, `cipher.key` is the password.
+pub fn init_trojan_channel(config: &ChannelConfig) -> Result<(), std::io::Error> {
+    let url = match Url::parse(config.url.as_str()) {
+        Err(e) => {
+            error!("invalid connect url:{} with error:{}", config.url, e);
+            return Err(make_io_error("invalid connect url"));
+        }
+        Ok(u) => u,
+    };
+    if url.host_str().is_none() {
+        return Err(make_io_error("invalid connect url"));
+    }
+    TROJAN_CHANNELS.lock().unwrap().insert(
+        config.name.clone(),
+        TrojanChannel {
+            config: config.clone(),
+            url,
+            hash: trojan_hash(config.cipher.key.as_str()),
+        },
+    );
+    Ok(())
+}
+
+struct TrojanChannelStream {
+    reader: ReadHalf<TlsClientStream>,
+    writer: WriteHalf<TlsClientStream>,
+}
+
+impl ChannelStream for TrojanChannelStream {
+    fn split(
+        &mut self,
+    ) -> (
+        Box<dyn AsyncRead + Send + Unpin + '_>,
+        Box<dyn AsyncWrite + Send + Unpin + '_>,
+    ) {
+        (Box::new(&mut self.reader), Box::new(&mut self.writer))
+    }
+    fn close(&mut self) -> std::io::Result<()> {
+        // the connection goes away with the stream
+        Ok(())
+    }
+}
+
+pub async fn get_trojan_stream(
+    channel: &str,
+    addr: String,
+) -> Result<Box<dyn ChannelStream + Send>, std::io::Error> {
+    if addr.starts_with(UDP_TARGET_PREFIX) {
+        return Err(make_io_error("udp targets are not relayed over trojan"));
+    }
+    let (config, url, hash) = match TROJAN_CHANNELS.lock().unwrap().get(channel) {
+        Some(c) => (c.config.clone(), c.url.clone(), c.hash.clone()),
+        None => return Err(make_io_error("no channel found.")),
+    };
+    let server = config.connect_addr(&url);
+    let server = plugin_addr(&config, server).await?;
+    let conn = match config.proxy.as_ref().filter(|_| config.plugin.is_none()) {
+        Some(p) => {
+            let proxy_url = match Url::parse(p.as_str()) {
+                Err(e) => {
+                    error!("invalid proxy url:{} with error:{}", p, e);
+                    return Err(make_io_error("invalid proxy url"));
+                }
+                Ok(u) => u,
+            };
+            proxy_connect(&proxy_url, server.as_str(), &config.dial_options()).await?
+        }
+        None => {
+            let opts = config.dial_options();
+            let c = happy_connect(server.as_str(), &opts);
+            let dur = std::time::Duration::from_secs(5);
+            tokio::time::timeout(dur, c).await??
+        }
+    };
+    let tls_cfg = channel_client_config(&config, &["http/1.1"])?;
+    let tls = tls_connect(conn, config.sni(&url), tls_cfg).await?;
+    let (reader, mut writer) = tokio::io::split(tls);
+    let mut header = BytesMut::new();
+    encode_trojan_request(
+        hash.as_str(),
+        TROJAN_CMD_CONNECT,
+        addr.as_str(),
+        &mut header,
+    )?;
+    writer.write_all(&header[..]).await?;
+    Ok(Box::new(TrojanChannelStream { reader, writer }))
+}
@@ -0,0 +1,145 @@
+use super::ChannelStream;
+use crate::config::ChannelConfig;
+use crate::transport::{
+    channel_client_config, parse_uuid, plugin_addr, tls_connect, vmess_request, VmessReader,
+    VmessWriter,
+};
+use crate::utils::{happy_connect, make_io_error, proxy_connect, UDP_TARGET_PREFIX};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use url::Url;
+
+struct VmessChannel {
+    config: ChannelConfig,
+    url: Url,
+    uuid: [u8; 16],
+}
+
+lazy_static! {
+    static ref VMESS_CHANNELS: Mutex<HashMap<String, VmessChannel>> = Mutex::new(HashMap::new());
+}
+
+pub fn is_vmess_channel(channel: &str) -> bool {
+    VMESS_CHANNELS.lock().unwrap().contains_key(channel)
+}
+
+/// Like ss remotes, every stream is its own connection.
+pub fn get_vmess_session_size(channel: &str) -> usize {
+    if is_vmess_channel(channel) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Registers a `vmess://host:port` channel for a v2ray server: `cipher.key`
+/// is the user id and `cipher.method` the body security.
+pub fn init_vmess_channel(config: &ChannelConfig) -> Result<(), std::io::Error> {
+    let url = match Url::parse(config.url.as_str()) {
+        Err(e) => {
+            error!("invalid connect url:{} with error:{}", config.url, e);
+            return Err(make_io_error("invalid connect url"));
+        }
+        Ok(u) => u,
+    };
+    if url.host_str().is_none() {
+        return Err(make_io_error("invalid connect url"));
+    }
+    let uuid = parse_uuid(config.cipher.key.as_str())?;
+    // fail on an unknown security now rather than on every stream
+    vmess_request(&uuid, config.cipher.method.as_str(), "0.0.0.0:0")?;
+    VMESS_CHANNELS.lock().unwrap().insert(
+        config.name.clone(),
+        VmessChannel {
+            config: config.clone(),
+            url,
+            uuid,
+        },
+    );
+    Ok(())
+}
+
+struct VmessChannelStream {
+    reader: Box<dyn AsyncRead + Send + Unpin>,
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+}
+
+impl ChannelStream for VmessChannelStream {
+    fn split(
+        &mut self,
+    ) -> (
+        Box<dyn AsyncRead + Send + Unpin + '_>,
+        Box<dyn AsyncWrite + Send + Unpin + '_>,
+    ) {
+        (Box::new(&mut self.reader), Box::new(&mut self.writer))
+    }
+    fn close(&mut self) -> std::io::Result<()> {
+        // the connection goes away with the stream
+        Ok(())
+    }
+}
+
+/// Opens a VMess AEAD connection to `addr`, over TLS when the channel has
+/// `tls` options.
+pub async fn get_vmess_stream(
+    channel: &str,
+    addr: String,
+) -> Result<Box<dyn ChannelStream + Send>, std::io::Error> {
+    if addr.starts_with(UDP_TARGET_PREFIX) {
+        return Err(make_io_error("udp targets are not relayed over vmess"));
+    }
+    let (config, url, uuid) = match VMESS_CHANNELS.lock().unwrap().get(channel) {
+        Some(c) => (c.config.clone(), c.url.clone(), c.uuid),
+        None => return Err(make_io_error("no channel found.")),
+    };
+    let server = match &config.connect_host {
+        Some(_) => config.connect_addr(&url),
+        None => format!(
+            "{}:{}",
+            url.host_str().unwrap_or(""),
+            url.port().unwrap_or(443)
+        ),
+    };
+    let server = plugin_addr(&config, server).await?;
+    let conn = match config.proxy.as_ref().filter(|_| config.plugin.is_none()) {
+        Some(p) => {
+            let proxy_url = match Url::parse(p.as_str()) {
+                Err(e) => {
+                    error!("invalid proxy url:{} with error:{}", p, e);
+                    return Err(make_io_error("invalid proxy url"));
+                }
+                Ok(u) => u,
+            };
+            proxy_connect(&proxy_url, server.as_str(), &config.dial_options()).await?
+        }
+        None => {
+            let opts = config.dial_options();
+            let c = happy_connect(server.as_str(), &opts);
+            let dur = std::time::Duration::from_secs(5);
+            tokio::time::timeout(dur, c).await??
+        }
+    };
+    let client = vmess_request(&uuid, config.cipher.method.as_str(), addr.as_str())?;
+    let (reader, mut writer): (
+        Box<dyn AsyncRead + Send + Unpin>,
+        Box<dyn AsyncWrite + Send + Unpin>,
+    ) = if config.tls.is_some() {
+        let tls_cfg = channel_client_config(&config, &["http/1.1"])?;
+        let tls = tls_connect(conn, config.sni(&url), tls_cfg).await?;
+        let (r, w) = tokio::io::split(tls);
+        (
+            Box::new(VmessReader::new(r, client.decoder)),
+            Box::new(VmessWriter::new(w, client.encoder, client.header)),
+        )
+    } else {
+        let (r, w) = tokio::io::split(conn);
+        (
+            Box::new(VmessReader::new(r, client.decoder)),
+            Box::new(VmessWriter::new(w, client.encoder, client.header)),
+        )
+    };
+    // send the request header now, the target may be the first to speak
+    writer.flush().await?;
+    Ok(Box::new(VmessChannelStream { reader, writer }))
+}
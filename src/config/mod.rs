@@ -1,10 +1,42 @@
+mod route;
+mod rule_list;
+mod secret;
+
+use self::route::{split_target, DomainSet, IpRuleSet, PortRange};
+use self::rule_list::rule_list_match;
+pub use self::rule_list::{dump_rule_list_stats, set_rule_list, RuleList};
+use self::secret::{resolve_optional_secret, resolve_secret};
+use crate::utils::{DialOptions, TokenBucket, TrafficShaper};
 use regex::Regex;
+use ring::constant_time::verify_slices_are_equal;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
 
 // lazy_static! {
 //     static ref GLOBAL_CONFIG: Mutex<Config> = Mutex::new(Config::new());
 // }
 pub const DEFAULT_RELAY_BUF_SIZE: usize = 4 * 1024;
+const PROXY_PREFIX: &str = "proxy:";
+
+fn dial_options(bind_address: &Option<String>, bind_interface: &Option<String>) -> DialOptions {
+    let bind_address = match bind_address {
+        Some(a) => match a.parse() {
+            Ok(ip) => Some(ip),
+            Err(e) => {
+                error!("invalid bind_address:{} with error:{}", a, e);
+                None
+            }
+        },
+        None => None,
+    };
+    DialOptions {
+        bind_address,
+        bind_interface: bind_interface.clone(),
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LogConfig {
@@ -13,47 +45,532 @@ pub struct LogConfig {
     pub logdir: String,
 }
 
+/// A routing rule. The rules are tried in order, the first one whose
+/// conditions all hold for a target routes it: `channel` is "direct",
+/// "block" to refuse the connection, the name of a channel to relay it
+/// over, or "proxy:" and a channel name or tag to relay it over the first
+/// such channel with a session; skipped while there is none.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PACConfig {
+    /// regex of the `host:port` target, any by default
+    #[serde(default)]
     pub host: String,
+    /// domain matchers the target host has to match one of, like
+    /// "example.com", "full:example.com", "keyword:cdn" or "regex:...",
+    /// see `DomainSet`
+    pub domains: Option<Vec<String>>,
+    /// ip ranges like "10.0.0.0/8", "geoip:private" or countries like
+    /// "geoip:cn" the target has to be in; targets given by name are in
+    /// none
+    pub cidr: Option<Vec<String>>,
+    /// ports or ranges like "8000-9000" the target port has to be in
+    pub ports: Option<Vec<String>>,
+    /// name of a rule list the target host has to be in, see `rule_list`
+    pub list: Option<String>,
     pub channel: String,
+    /// source ip / device for `direct` connections of this rule
+    pub bind_address: Option<String>,
+    pub bind_interface: Option<String>,
+    /// "interactive", "normal" or "bulk", how the frames of the rule's
+    /// streams are scheduled on rmux sessions
+    pub priority: Option<String>,
     #[serde(skip)]
     pub re: Option<Regex>,
+    #[serde(skip)]
+    pub domain_set: DomainSet,
+    #[serde(skip)]
+    pub nets: IpRuleSet,
+    #[serde(skip)]
+    pub port_ranges: Vec<PortRange>,
 }
 
 impl PACConfig {
     pub fn init(&mut self) {
         if self.re.is_none() {
             self.re = Some(Regex::new(self.host.as_str()).unwrap());
+            for domain in self.domains.iter().flatten() {
+                self.domain_set.add(domain.as_str()).unwrap();
+            }
+            for net in self.cidr.iter().flatten() {
+                self.nets.add(net.as_str()).unwrap();
+            }
+            for ports in self.ports.iter().flatten() {
+                self.port_ranges
+                    .push(PortRange::parse(ports.as_str()).unwrap());
+            }
         }
     }
     pub fn is_match(&self, addr: &str) -> bool {
-        self.re.as_ref().unwrap().is_match(addr)
+        if !self.re.as_ref().unwrap().is_match(addr) {
+            return false;
+        }
+        let (host, port) = split_target(addr);
+        if self.domains.is_some() && !self.domain_set.is_match(host) {
+            return false;
+        }
+        if self.cidr.is_some() {
+            match host.parse() {
+                Ok(ip) if self.nets.contains(&ip) => {}
+                _ => return false,
+            }
+        }
+        if let Some(list) = self.list.as_ref() {
+            if !rule_list_match(list.as_str(), host) {
+                return false;
+            }
+        }
+        if self.ports.is_some() {
+            match port {
+                Some(p) if self.port_ranges.iter().any(|r| r.contains(p)) => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+    pub fn is_block(&self) -> bool {
+        self.channel == "block"
+    }
+    /// The name or tag of a "proxy:" channel.
+    pub fn proxy_tag(&self) -> Option<&str> {
+        self.channel.strip_prefix(PROXY_PREFIX)
+    }
+    pub fn dial_options(&self) -> DialOptions {
+        dial_options(&self.bind_address, &self.bind_interface)
+    }
+}
+
+/// Upstream resolver of a `dnsfwd://` listener for the query names matching
+/// the `domain` regex. The queries go to `server` over the tunnel as DNS over
+/// TCP, the channel is picked by the pac rules for `server`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResolverConfig {
+    pub domain: String,
+    pub server: String,
+    #[serde(skip)]
+    pub re: Option<Regex>,
+}
+
+impl ResolverConfig {
+    pub fn init(&mut self) {
+        if self.re.is_none() {
+            self.re = Some(Regex::new(self.domain.as_str()).unwrap());
+        }
+    }
+    pub fn is_match(&self, name: &str) -> bool {
+        self.re.as_ref().unwrap().is_match(name)
+    }
+    /// `server` with the DNS port when it has none
+    pub fn server_addr(&self) -> String {
+        if self.server.parse::<std::net::IpAddr>().is_ok() {
+            if self.server.contains(':') {
+                return format!("[{}]:53", self.server);
+            }
+            return format!("{}:53", self.server);
+        }
+        if self.server.contains(':') {
+            return self.server.clone();
+        }
+        format!("{}:53", self.server)
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CipherConfig {
+    /// inline, or a `keyring:` or `file:` reference, see `key_file`
+    #[serde(default)]
     pub key: String,
+    /// file holding the key instead of `key`, the same for `user_key`
+    pub key_file: Option<String>,
     pub method: String,
+    /// rmux only, the AEAD suites a session may use instead of `method`,
+    /// which still seals the handshake: a client offers them in this
+    /// order, "auto" standing for all of them in the order the CPU runs
+    /// fastest, and a listener takes the first one it lists too; all of
+    /// them by default on a listener, none on a client
+    pub suites: Option<Vec<String>>,
+    /// rmux only, derives the session key from `key` by argon2id instead
+    /// of using its bytes as they are; the peer needs the same settings
+    pub kdf: Option<KdfConfig>,
+    /// rmux clients only, the id of a user of the listener and the user's
+    /// key, which seals the session after the handshake
+    pub key_id: Option<String>,
+    pub user_key: Option<String>,
+    pub user_key_file: Option<String>,
+}
+
+impl CipherConfig {
+    fn resolve_secrets(&mut self) -> std::io::Result<()> {
+        resolve_secret(&mut self.key, self.key_file.as_deref())?;
+        resolve_optional_secret(&mut self.user_key, self.user_key_file.as_deref())
+    }
+}
+
+/// Argon2id settings of an rmux key, a low entropy password then costs a
+/// guesser `memory_kib` KiB and `passes` passes per guess.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KdfConfig {
+    /// random string of at least 8 bytes, one per deployment
+    pub salt: String,
+    /// 19456 KiB and 2 passes by default
+    pub memory_kib: Option<u32>,
+    pub passes: Option<u32>,
+}
+
+pub const DEFAULT_WS_PATH: &str = "/relay";
+pub const DEFAULT_PAC_PATH: &str = "/proxy.pac";
+pub const DEFAULT_DOH_PATH: &str = "/dns-query";
+pub const DEFAULT_MUX: &str = "rmux";
+pub const DEFAULT_ACME_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// HTTP upgrade settings for `ws://`/`wss://` channels and listeners.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebsocketConfig {
+    pub path: Option<String>,
+    pub host: Option<String>,
+    pub headers: Option<HashMap<String, String>>,
+    /// listeners only, HTML file served to every request which is not an
+    /// upgrade on `path`
+    pub decoy: Option<String>,
+}
+
+impl WebsocketConfig {
+    pub fn path(&self) -> &str {
+        match &self.path {
+            Some(p) => p.as_str(),
+            None => DEFAULT_WS_PATH,
+        }
+    }
+}
+
+/// Client side rustls options. The SNI itself is the channel's `sni`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TlsConfig {
+    pub alpn: Option<Vec<String>>,
+    /// extra PEM root certificates to trust
+    pub ca_file: Option<String>,
+    /// trust only `ca_file`, not the bundled web roots
+    pub custom_roots_only: Option<bool>,
+    /// keep session tickets across reconnects, on by default
+    pub session_resumption: Option<bool>,
+    /// PEM CA certificates a TLS listener requires its clients to present a
+    /// certificate of
+    pub client_ca: Option<String>,
+    /// base64 SHA-256 digests of certificates or of their public keys
+    /// (SubjectPublicKeyInfo); a pinned leaf is accepted without CA
+    /// validation, for self-signed remotes, a pinned CA or intermediate has
+    /// to anchor a chain that verifies for the server name
+    pub pin_sha256: Option<Vec<String>>,
+    /// PEM certificate chain and key the client presents to listeners with
+    /// a `client_ca`
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    /// pad the records of `tls://` sessions to multiples of this many bytes,
+    /// rounded up to a power of two; the peer has to pad the same
+    pub record_bucket: Option<u32>,
+}
+
+/// Tuning knobs for `kcp://` channels and listeners, defaults follow the
+/// usual kcp "fast" profile.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct KcpConfig {
+    pub mtu: Option<usize>,
+    pub snd_wnd: Option<u16>,
+    pub rcv_wnd: Option<u16>,
+    pub nodelay: Option<bool>,
+    pub interval: Option<i32>,
+    /// fast resend after this many out of order acks, 0 disables it
+    pub resend: Option<i32>,
+    pub no_congestion: Option<bool>,
+}
+
+impl KcpConfig {
+    pub fn mtu(&self) -> usize {
+        self.mtu.unwrap_or(1350)
+    }
+    pub fn snd_wnd(&self) -> u16 {
+        self.snd_wnd.unwrap_or(128)
+    }
+    pub fn rcv_wnd(&self) -> u16 {
+        self.rcv_wnd.unwrap_or(512)
+    }
+    pub fn nodelay(&self) -> bool {
+        self.nodelay.unwrap_or(true)
+    }
+    pub fn interval(&self) -> i32 {
+        self.interval.unwrap_or(20)
+    }
+    pub fn resend(&self) -> i32 {
+        self.resend.unwrap_or(2)
+    }
+    pub fn no_congestion(&self) -> bool {
+        self.no_congestion.unwrap_or(true)
+    }
+}
+
+/// Options of `ssh://user@host:port` channels. They run the OpenSSH client,
+/// which keeps one ControlMaster connection per session and opens one
+/// direct-tcpip channel per stream over it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SshConfig {
+    /// the ssh binary, "ssh" by default
+    pub command: Option<String>,
+    pub identity_file: Option<String>,
+    /// extra `-o` options, like "StrictHostKeyChecking=accept-new"
+    pub options: Option<Vec<String>>,
+}
+
+impl SshConfig {
+    pub fn command(&self) -> &str {
+        match &self.command {
+            Some(c) => c.as_str(),
+            None => "ssh",
+        }
+    }
+}
+
+/// Padding of an rmux session's frames, so their sizes and timing say less
+/// about the traffic they carry. The peer has to know padding frames.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PaddingConfig {
+    /// random bytes of up to this many appended to the auth frame
+    pub handshake_max: Option<u16>,
+    /// frames smaller than pad_below bytes, 1024 by default, are padded to
+    /// a multiple of bucket bytes
+    pub bucket: Option<u32>,
+    pub pad_below: Option<u32>,
+    /// a dummy frame of up to dummy_max_len bytes, 256 by default, after
+    /// random pauses of up to dummy_interval_ms
+    pub dummy_interval_ms: Option<u32>,
+    pub dummy_max_len: Option<u32>,
+}
+
+/// Cover traffic of an rmux channel: while it carries no streams for
+/// `idle_secs`, 60 by default, it fetches one of `urls` at random about
+/// `flows_per_hour` times an hour, 30 by default, reading bodies of
+/// between `min_kb` and `max_kb`, 16 and 1024 by default, spread
+/// log-uniformly like those of browsing.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DecoyConfig {
+    /// "http://" or "https://" urls
+    pub urls: Vec<String>,
+    pub idle_secs: Option<u64>,
+    pub flows_per_hour: Option<u32>,
+    pub min_kb: Option<u32>,
+    pub max_kb: Option<u32>,
+}
+
+/// Obfuscation of the bytes between the transport and the rmux session,
+/// for carriers without TLS. Both sides need the same.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ObfsConfig {
+    /// "obfs4" or "http"
+    pub mode: String,
+    /// shared secret of "obfs4", or the file holding it
+    pub key: Option<String>,
+    pub key_file: Option<String>,
+    /// host and path of the requests of "http", "/" by default; a listener
+    /// drops connections whose first request is not for them
+    pub host: Option<String>,
+    pub path: Option<String>,
+    /// pauses of up to this many ms between writes of "http", none by
+    /// default
+    pub pace_ms: Option<u32>,
+}
+
+impl ObfsConfig {
+    fn resolve_secrets(&mut self) -> std::io::Result<()> {
+        resolve_optional_secret(&mut self.key, self.key_file.as_deref())
+    }
+}
+
+/// Static keys of "noise://" channels and listeners, base64 X25519 keys
+/// as `rsnova --noise-keygen` prints them.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NoiseConfig {
+    /// "IK" by default, or "XK", which sends the client's key only after
+    /// the server proved its own; both sides need the same
+    pub pattern: Option<String>,
+    #[serde(default)]
+    pub private_key: String,
+    /// file holding the private key instead of `private_key`
+    pub private_key_file: Option<String>,
+    /// public key of the server a channel connects to
+    pub server_key: Option<String>,
+    /// public keys of the clients a listener takes
+    pub client_keys: Option<Vec<String>>,
+}
+
+impl NoiseConfig {
+    fn resolve_secrets(&mut self) -> std::io::Result<()> {
+        resolve_secret(&mut self.private_key, self.private_key_file.as_deref())
+    }
+}
+
+/// A SIP003 plugin, an external obfuscator rsnova runs next to a channel
+/// or listener and passes the traffic through over loopback.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PluginConfig {
+    /// the plugin binary, like "obfs-local" or "v2ray-plugin"
+    pub command: String,
+    /// the plugin's options, passed in SS_PLUGIN_OPTIONS
+    pub opts: Option<String>,
+    pub args: Option<Vec<String>>,
+}
+
+/// Session pool of a channel. The pool keeps at least `min_sessions` (falls
+/// back to `conns_per_host`) and grows up to `max_sessions` while every
+/// session is carrying `max_streams_per_session` streams.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PoolConfig {
+    pub min_sessions: Option<u32>,
+    pub max_sessions: Option<u32>,
+    /// streams a session carries at once, new ones wait for room or a new
+    /// session when all are full; 0 means unlimited
+    pub max_streams_per_session: Option<u32>,
+    /// streams a session opens in its life before it is rolled over to a
+    /// fresh one, 0 means unlimited
+    pub max_session_streams: Option<u32>,
+    /// streams allowed over all sessions of the channel, 0 means unlimited
+    pub max_concurrent_streams: Option<u32>,
+    /// evict a session which has not answered a ping for this long
+    pub health_timeout_secs: Option<u32>,
+    /// "round_robin", or "latency" to prefer the sessions with the lowest
+    /// smoothed rtt, loss and load; round robin still passes over sessions
+    /// which lose pings while others are healthy
+    pub scheduler: Option<String>,
+    /// frames waiting to be written above which a session counts as full
+    /// and the pool grows toward max_sessions, 32 by default, 0 disables
+    pub scale_queue_depth: Option<u32>,
+    /// sessions beyond min_sessions which carried no stream for this long
+    /// are closed, 300 by default
+    pub scale_idle_secs: Option<u32>,
+}
+
+impl PoolConfig {
+    pub fn max_streams_per_session(&self) -> u32 {
+        self.max_streams_per_session.unwrap_or(0)
+    }
+    pub fn max_session_streams(&self) -> u32 {
+        self.max_session_streams.unwrap_or(0)
+    }
+    pub fn max_concurrent_streams(&self) -> u32 {
+        self.max_concurrent_streams.unwrap_or(0)
+    }
+    pub fn health_timeout_secs(&self) -> u32 {
+        self.health_timeout_secs.unwrap_or(90)
+    }
+    pub fn scale_queue_depth(&self) -> u64 {
+        self.scale_queue_depth.unwrap_or(32) as u64
+    }
+    pub fn scale_idle_secs(&self) -> u32 {
+        self.scale_idle_secs.unwrap_or(300)
+    }
+    pub fn is_latency_scheduler(&self) -> bool {
+        self.scheduler.as_ref().map(|s| s.as_str()) == Some("latency")
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChannelConfig {
     pub name: String,
+    /// groups of remotes the channel is in, like its region, which pac
+    /// rules pick with "proxy:<tag>"
+    pub tags: Option<Vec<String>>,
     pub url: String,
+    /// more urls of the same remote, maybe over other transports or links;
+    /// the channel's sessions are spread over `url` and these
+    pub urls: Option<Vec<String>>,
     pub cipher: CipherConfig,
     pub ping_interval_sec: u32,
+    /// seconds an rmux session's ping may wait for its pong, 5 by default
+    pub ping_timeout_sec: Option<u32>,
+    /// pings lost in a row after which an rmux session is dropped and
+    /// redialed, 3 by default
+    pub max_missed_pings: Option<u32>,
     pub conns_per_host: u32,
     pub max_alive_mins: u32,
     pub proxy: Option<String>,
     pub work_time_frame: Option<[u8; 2]>,
     pub sni: Option<String>,
     pub sni_proxy: Option<String>,
+    /// host[:port] actually dialed; `sni` and `host_header` may differ from it
+    pub connect_host: Option<String>,
+    pub host_header: Option<String>,
     pub relay_buf_size: Option<usize>,
+    /// 0-RTT for quic and resumed TLS 1.3 sessions; early data can be
+    /// replayed by an attacker, so it is off by default
+    pub zero_rtt: Option<bool>,
+    pub ws: Option<WebsocketConfig>,
+    pub kcp: Option<KcpConfig>,
+    pub ssh: Option<SshConfig>,
+    pub tls: Option<TlsConfig>,
+    pub pool: Option<PoolConfig>,
+    /// send the auth frame of `rmux://` sessions in the SYN, linux only
+    pub tcp_fast_open: Option<bool>,
+    /// source ip of the connections to the server
+    pub bind_address: Option<String>,
+    /// SO_BINDTODEVICE for the connections to the server, linux only
+    pub bind_interface: Option<String>,
+    /// virtual hosts served through the remote's `vhost://` listener, each
+    /// mapped to the local `host:port` its requests are relayed to
+    pub vhosts: Option<HashMap<String, String>>,
+    /// ports the remote listens on for this channel, relayed back to local targets
+    pub reverse: Option<Vec<ReverseConfig>>,
+    /// bytes each rmux stream buffers from the server, advertised to it in
+    /// the handshake; 4 times relay_buf_size by default
+    pub stream_window: Option<u32>,
+    /// relay UDP flows and DNS queries as rmux datagrams instead of a
+    /// stream each, and the UDP associations of SOCKS5 clients and the tun
+    /// device over one socket of the server each, on sessions whose server
+    /// takes them
+    pub datagrams: Option<bool>,
+    /// compress rmux streams with "lz4" or "zstd" if the server takes it,
+    /// for metered or slow links; "none" by default
+    pub compression: Option<String>,
+    pub padding: Option<PaddingConfig>,
+    /// cover flows through the channel while it is idle
+    pub decoy: Option<DecoyConfig>,
+    /// send the first bytes of a connection in the SYN of its rmux stream
+    /// if the server takes them, saving the server a wait per stream
+    pub early_data: Option<bool>,
+    /// rotate the key of what an rmux session sends after this many
+    /// minutes or megabytes, whichever comes first, if the server takes
+    /// rekey frames; never by default
+    pub rekey_interval_mins: Option<u64>,
+    pub rekey_after_mb: Option<u64>,
+    /// limits of an rmux session whatever the server's: no key seals more
+    /// than `max_mb_per_key` either way (rekeying before if the server
+    /// takes rekey frames, closing the session if not), past
+    /// `reconnect_interval_mins` the session is replaced, past
+    /// `max_session_age_mins` it is closed with the streams it still has;
+    /// none by default
+    pub max_session_age_mins: Option<u64>,
+    pub reconnect_interval_mins: Option<u64>,
+    pub max_mb_per_key: Option<u64>,
+    /// mix an X25519 and ML-KEM-768 exchange into the session key if the
+    /// server takes it, so recorded sessions hold up against quantum
+    /// computers later; off by default
+    pub hybrid_kex: Option<bool>,
+    /// stream multiplexer of the sessions, "rmux" by default, "yamux",
+    /// or "smux" and "smux2" for smux v1 and v2 servers like kcptun's
+    pub mux: Option<String>,
+    /// obfuscation of the sessions under the transport, the listener's
+    /// has to match
+    pub obfs: Option<ObfsConfig>,
+    /// keys of a "noise://" channel
+    pub noise: Option<NoiseConfig>,
+    /// plugin the channel's TCP connections go through, it dials the
+    /// remote itself, so `proxy` is unused then
+    pub plugin: Option<PluginConfig>,
 }
 
 impl ChannelConfig {
+    pub fn record_bucket(&self) -> Option<usize> {
+        self.tls
+            .as_ref()
+            .and_then(|t| t.record_bucket)
+            .map(|b| b as usize)
+    }
     pub fn is_valid_hour(&self, h: u8) -> bool {
         if let Some(frame) = self.work_time_frame {
             return frame[0] <= h && frame[1] > h;
@@ -66,6 +583,362 @@ impl ChannelConfig {
             None => DEFAULT_RELAY_BUF_SIZE,
         }
     }
+    pub fn zero_rtt(&self) -> bool {
+        self.zero_rtt.unwrap_or(false)
+    }
+    pub fn tcp_fast_open(&self) -> bool {
+        self.tcp_fast_open.unwrap_or(false)
+    }
+    pub fn mux(&self) -> &str {
+        self.mux.as_ref().map(|m| m.as_str()).unwrap_or(DEFAULT_MUX)
+    }
+    pub fn dial_options(&self) -> DialOptions {
+        dial_options(&self.bind_address, &self.bind_interface)
+    }
+    pub fn paths(&self) -> Vec<&str> {
+        let mut paths = vec![self.url.as_str()];
+        if let Some(urls) = &self.urls {
+            paths.extend(urls.iter().map(|u| u.as_str()));
+        }
+        paths
+    }
+    /// Pool settings, multipath channels schedule by latency unless told otherwise.
+    pub fn pool(&self) -> PoolConfig {
+        let mut pool = self.pool.clone().unwrap_or_default();
+        if pool.scheduler.is_none() && self.paths().len() > 1 {
+            pool.scheduler = Some(String::from("latency"));
+        }
+        pool
+    }
+    pub fn ping_interval_secs(&self) -> u64 {
+        match self.ping_interval_sec {
+            0 => 30,
+            v => v as u64,
+        }
+    }
+    pub fn ping_timeout_secs(&self) -> u64 {
+        self.ping_timeout_sec.unwrap_or(5) as u64
+    }
+    pub fn max_missed_pings(&self) -> u32 {
+        self.max_missed_pings.unwrap_or(3).max(1)
+    }
+    pub fn min_sessions(&self) -> usize {
+        match self.pool.as_ref().and_then(|p| p.min_sessions) {
+            Some(v) => v as usize,
+            None => self.conns_per_host as usize,
+        }
+    }
+    pub fn max_sessions(&self) -> usize {
+        let min = self.min_sessions();
+        match self.pool.as_ref().and_then(|p| p.max_sessions) {
+            Some(v) if v as usize > min => v as usize,
+            _ => min,
+        }
+    }
+    pub fn ws_path(&self) -> &str {
+        match &self.ws {
+            Some(ws) => ws.path(),
+            None => DEFAULT_WS_PATH,
+        }
+    }
+    /// Address of the underlying connection: `connect_host`, or the older
+    /// `sni_proxy`, and otherwise the url's own host and port.
+    pub fn connect_addr(&self, url: &Url) -> String {
+        let port = url.port_or_known_default().unwrap_or(443);
+        let (front, port) = match &self.connect_host {
+            Some(h) => (Some(h), port),
+            None => (self.sni_proxy.as_ref(), 443),
+        };
+        match front {
+            Some(h) => {
+                let mut v = String::from(h.as_str());
+                if v.rfind(']').unwrap_or(0) >= v.rfind(':').unwrap_or(0) {
+                    v.push_str(format!(":{}", port).as_str());
+                }
+                v
+            }
+            None => format!("{}:{}", url.host_str().unwrap_or(""), port),
+        }
+    }
+    /// TLS server name, defaults to the url host.
+    pub fn sni<'a>(&'a self, url: &'a Url) -> &'a str {
+        match &self.sni {
+            Some(s) => s.as_str(),
+            None => url.host_str().unwrap_or(""),
+        }
+    }
+    /// Inner HTTP Host (or h2 authority) override.
+    pub fn host_header(&self) -> Option<&str> {
+        if let Some(h) = &self.host_header {
+            return Some(h.as_str());
+        }
+        match &self.ws {
+            Some(ws) => ws.host.as_ref().map(|h| h.as_str()),
+            None => None,
+        }
+    }
+}
+
+/// Bandwidth caps in bytes/sec; `upload`/`download` are shared by every
+/// connection of the listener, the `conn_*` limits apply per connection.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RateLimitConfig {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+    pub conn_upload: Option<u64>,
+    pub conn_download: Option<u64>,
+    #[serde(skip)]
+    upload_bucket: Option<Arc<TokenBucket>>,
+    #[serde(skip)]
+    download_bucket: Option<Arc<TokenBucket>>,
+}
+
+impl RateLimitConfig {
+    pub fn init(&mut self) {
+        if self.upload_bucket.is_none() {
+            self.upload_bucket = self
+                .upload
+                .filter(|v| *v > 0)
+                .map(|v| TokenBucket::new(v, v));
+        }
+        if self.download_bucket.is_none() {
+            self.download_bucket = self
+                .download
+                .filter(|v| *v > 0)
+                .map(|v| TokenBucket::new(v, v));
+        }
+    }
+    pub fn upload_buckets(&self) -> Vec<Arc<TokenBucket>> {
+        let mut buckets = Vec::new();
+        if let Some(b) = &self.upload_bucket {
+            buckets.push(b.clone());
+        }
+        if let Some(v) = self.conn_upload.filter(|v| *v > 0) {
+            buckets.push(TokenBucket::new(v, v));
+        }
+        buckets
+    }
+    pub fn download_buckets(&self) -> Vec<Arc<TokenBucket>> {
+        let mut buckets = Vec::new();
+        if let Some(b) = &self.download_bucket {
+            buckets.push(b.clone());
+        }
+        if let Some(v) = self.conn_download.filter(|v| *v > 0) {
+            buckets.push(TokenBucket::new(v, v));
+        }
+        buckets
+    }
+}
+
+/// Bandwidth profile for a listener: sustained `rate` bytes/sec, `burst`
+/// bytes allowed above it, and up to `jitter_ms` random delay per throttled write.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShapingConfig {
+    pub rate: u64,
+    pub burst: Option<u64>,
+    pub jitter_ms: Option<u64>,
+    #[serde(skip)]
+    shaper: Option<Arc<TrafficShaper>>,
+}
+
+impl ShapingConfig {
+    pub fn init(&mut self) {
+        if self.shaper.is_none() && self.rate > 0 {
+            self.shaper = Some(TrafficShaper::new(
+                self.rate,
+                self.burst.unwrap_or(self.rate),
+                Duration::from_millis(self.jitter_ms.unwrap_or(0)),
+            ));
+        }
+    }
+}
+
+/// Credentials accepted by the local proxy listeners.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserConfig {
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+    /// file holding the password instead of `password`
+    pub password_file: Option<String>,
+}
+
+/// A user of an rmux listener. Clients presenting `id` in the handshake
+/// seal their session with `key`, and its streams are held to the rules
+/// below; the session is accounted to the user. On a TLS listener with
+/// `tls.client_ca` the client's certificate picks the user instead.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyConfig {
+    pub id: String,
+    /// may be left out for users which only log in with a certificate
+    #[serde(default)]
+    pub key: String,
+    /// file holding the key instead of `key`
+    pub key_file: Option<String>,
+    /// subject CNs or DNS and email SANs of the client certificates of the
+    /// user, `id` by default
+    pub cert_names: Option<Vec<String>>,
+    /// regexes of the targets the user may reach, matched like pac rules;
+    /// all of them by default
+    pub hosts: Option<Vec<String>>,
+    /// bandwidth of all the user's streams together and of each one
+    pub rate_limit: Option<RateLimitConfig>,
+    #[serde(skip)]
+    host_res: Vec<Regex>,
+}
+
+impl KeyConfig {
+    pub fn init(&mut self) {
+        if self.host_res.is_empty() {
+            self.host_res = self
+                .hosts
+                .iter()
+                .flatten()
+                .map(|h| Regex::new(h.as_str()).unwrap())
+                .collect();
+        }
+        if let Some(limit) = self.rate_limit.as_mut() {
+            limit.init();
+        }
+    }
+    pub fn is_allowed(&self, target: &str) -> bool {
+        self.hosts.is_none() || self.host_res.iter().any(|re| re.is_match(target))
+    }
+    pub fn has_cert_name(&self, name: &str) -> bool {
+        match &self.cert_names {
+            Some(names) => names.iter().any(|n| n == name),
+            None => self.id == name,
+        }
+    }
+    pub fn upload_buckets(&self) -> Vec<Arc<TokenBucket>> {
+        match &self.rate_limit {
+            Some(r) => r.upload_buckets(),
+            None => Vec::new(),
+        }
+    }
+    pub fn download_buckets(&self) -> Vec<Arc<TokenBucket>> {
+        match &self.rate_limit {
+            Some(r) => r.download_buckets(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Interface options of a `tun://` listener.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TunConfig {
+    /// fixed interface name, `utunN` on macOS
+    pub name: Option<String>,
+    pub mtu: Option<u32>,
+}
+
+impl TunConfig {
+    pub fn mtu(&self) -> u32 {
+        self.mtu.unwrap_or(1500)
+    }
+}
+
+/// Opt-in interception of HTTPS proxied through the HTTP proxy listener, only
+/// for clients that trust `ca_cert`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MitmConfig {
+    /// PEM CA cert and PKCS8 key the generated leaf certs are signed with
+    pub ca_cert: String,
+    pub ca_key: String,
+    /// host regexes to intercept, every host when unset
+    pub hosts: Option<Vec<String>>,
+    /// request headers to set on decrypted requests, an empty value removes one
+    pub headers: Option<HashMap<String, String>>,
+    /// rules matched against `host/path` of the first decrypted request, ahead
+    /// of the listener's `pac`
+    pub pac: Option<Vec<PACConfig>>,
+    #[serde(skip)]
+    host_res: Vec<Regex>,
+}
+
+impl MitmConfig {
+    pub fn init(&mut self) {
+        if self.host_res.is_empty() {
+            self.host_res = self
+                .hosts
+                .iter()
+                .flatten()
+                .map(|h| Regex::new(h.as_str()).unwrap())
+                .collect();
+        }
+        for pac in self.pac.iter_mut().flatten() {
+            pac.init();
+        }
+    }
+    pub fn is_match(&self, host: &str) -> bool {
+        self.hosts.is_none() || self.host_res.iter().any(|re| re.is_match(host))
+    }
+}
+
+/// Options of a `trojan://` listener.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TrojanConfig {
+    pub passwords: Vec<String>,
+    /// host:port of the decoy website which gets every connection that is
+    /// not a valid trojan request, so probes only ever see that site
+    pub fallback: Option<String>,
+}
+
+/// A port of the remote whose connections are relayed to a local target.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReverseConfig {
+    /// ip:port the remote listens on, it has to be in the remote's `reverse_ports`
+    pub remote: String,
+    /// host:port the client relays the connections to
+    pub local: String,
+}
+
+/// Fixed target of a `forward://` or `udpfwd://` listener.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ForwardConfig {
+    /// host:port every accepted connection is relayed to
+    pub target: String,
+    /// channel to relay through, the pac rules decide when unset
+    pub channel: Option<String>,
+    /// `udpfwd://` only, a client's flow ends after this many idle seconds
+    pub timeout_secs: Option<u64>,
+}
+
+impl ForwardConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs.unwrap_or(60))
+    }
+}
+
+/// Certificates of a listener obtained and renewed over ACME, answering
+/// HTTP-01 challenges, instead of its `cert` and `key`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AcmeConfig {
+    /// names of the certificate, each has to resolve to this host
+    pub domains: Vec<String>,
+    /// contact for expiry notices of the CA
+    pub email: Option<String>,
+    /// directory url of the CA, Let's Encrypt by default
+    pub directory: Option<String>,
+    /// where the account key and the current certificate are kept
+    pub cache_dir: String,
+    /// address the challenges are answered on, "0.0.0.0:80" by default
+    pub http_listen: Option<String>,
+}
+
+impl AcmeConfig {
+    pub fn directory(&self) -> &str {
+        match &self.directory {
+            Some(d) => d.as_str(),
+            None => DEFAULT_ACME_DIRECTORY,
+        }
+    }
+    pub fn http_listen(&self) -> &str {
+        match &self.http_listen {
+            Some(l) => l.as_str(),
+            None => "0.0.0.0:80",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -75,15 +948,160 @@ pub struct TunnelConfig {
     pub pac: Vec<PACConfig>,
     pub tunnel_server: Option<String>,
     pub relay_buf_size: Option<usize>,
+    pub idle_timeout_secs: Option<u64>,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub shaping: Option<ShapingConfig>,
+    /// PEM cert chain and private key, used by TLS based listeners like `quic://`.
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    /// obtain the certificate of `tls://`, `trojan://`, `socks5s://`, `h2://`
+    /// and `grpc://` listeners from an ACME CA rather than `cert` and `key`
+    pub acme: Option<AcmeConfig>,
+    pub ws: Option<WebsocketConfig>,
+    pub kcp: Option<KcpConfig>,
+    pub tls: Option<TlsConfig>,
+    /// TCP Fast Open queue length of the listener, linux only
+    pub tcp_fast_open: Option<i32>,
+    /// when set, SOCKS5 and HTTP proxy clients have to log in as one of these users
+    pub users: Option<Vec<UserConfig>>,
+    pub mitm: Option<MitmConfig>,
+    pub tun: Option<TunConfig>,
+    pub trojan: Option<TrojanConfig>,
+    /// path the local listener serves its generated PAC file on, "/proxy.pac" by default
+    pub pac_path: Option<String>,
+    /// resolvers of a `dnsfwd://` listener or of the local listener's DoH
+    /// path, the first match wins
+    pub resolvers: Option<Vec<ResolverConfig>>,
+    /// path the local listener answers DNS-over-HTTPS queries on when it has
+    /// resolvers, "/dns-query" by default
+    pub doh_path: Option<String>,
+    /// route connections of transparent listeners by the TLS SNI or HTTP Host
+    /// they start with instead of the bare ip, on by default
+    pub sniff: Option<bool>,
+    pub forward: Option<ForwardConfig>,
+    /// ports clients of an rmux listener may open with their `reverse`
+    /// mappings, none by default
+    pub reverse_ports: Option<Vec<u16>>,
+    /// bytes each rmux stream buffers from the client, advertised to it in
+    /// the handshake; 4 times relay_buf_size by default
+    pub stream_window: Option<u32>,
+    /// accept the compression rmux clients ask for, true by default
+    pub compression: Option<bool>,
+    pub padding: Option<PaddingConfig>,
+    /// accept data in the SYN of rmux streams, true by default
+    pub early_data: Option<bool>,
+    /// seconds the stamp of an rmux auth frame may be off the listener's
+    /// clock, each is taken once within them; 120 by default, 0 turns the
+    /// replay check off
+    pub replay_window_secs: Option<u64>,
+    /// take auth frames of clients predating stamps, which can be replayed;
+    /// false by default
+    pub unstamped_auth: Option<bool>,
+    /// rotate the key of what an rmux session sends, see the channel's
+    pub rekey_interval_mins: Option<u64>,
+    pub rekey_after_mb: Option<u64>,
+    /// limits of the sessions of an rmux listener, see the channel's; past
+    /// `reconnect_interval_mins` the client is asked to open another
+    pub max_session_age_mins: Option<u64>,
+    pub reconnect_interval_mins: Option<u64>,
+    pub max_mb_per_key: Option<u64>,
+    /// take the hybrid key exchange rmux clients offer, true by default
+    pub hybrid_kex: Option<bool>,
+    /// stream multiplexer of an rmux listener's sessions, "rmux" by default
+    pub mux: Option<String>,
+    /// obfuscation an rmux listener expects of its clients
+    pub obfs: Option<ObfsConfig>,
+    /// keys of a "noise://" listener
+    pub noise: Option<NoiseConfig>,
+    /// plugin listening on `listen` in place of the listener, which then
+    /// takes the plugin's connections on a loopback port
+    pub plugin: Option<PluginConfig>,
+    /// users of an rmux listener, clients then have to present the id of
+    /// one; `cipher.key` still seals their handshakes
+    pub keys: Option<Vec<KeyConfig>>,
 }
 
 impl TunnelConfig {
+    pub fn record_bucket(&self) -> Option<usize> {
+        self.tls
+            .as_ref()
+            .and_then(|t| t.record_bucket)
+            .map(|b| b as usize)
+    }
     pub fn relay_buf_size(&self) -> usize {
         match self.relay_buf_size {
             Some(v) => v,
             None => DEFAULT_RELAY_BUF_SIZE,
         }
     }
+    pub fn upload_buckets(&self) -> Vec<Arc<TokenBucket>> {
+        match &self.rate_limit {
+            Some(r) => r.upload_buckets(),
+            None => Vec::new(),
+        }
+    }
+    pub fn download_buckets(&self) -> Vec<Arc<TokenBucket>> {
+        match &self.rate_limit {
+            Some(r) => r.download_buckets(),
+            None => Vec::new(),
+        }
+    }
+    pub fn sniff(&self) -> bool {
+        self.sniff.unwrap_or(true)
+    }
+    pub fn mux(&self) -> &str {
+        self.mux.as_ref().map(|m| m.as_str()).unwrap_or(DEFAULT_MUX)
+    }
+    pub fn pac_path(&self) -> &str {
+        match &self.pac_path {
+            Some(p) => p.as_str(),
+            None => DEFAULT_PAC_PATH,
+        }
+    }
+    pub fn doh_path(&self) -> &str {
+        match &self.doh_path {
+            Some(p) => p.as_str(),
+            None => DEFAULT_DOH_PATH,
+        }
+    }
+    pub fn ws_path(&self) -> &str {
+        match &self.ws {
+            Some(ws) => ws.path(),
+            None => DEFAULT_WS_PATH,
+        }
+    }
+    pub fn shaper(&self) -> Option<Arc<TrafficShaper>> {
+        match &self.shaping {
+            Some(s) => s.shaper.clone(),
+            None => None,
+        }
+    }
+    /// The user of an rmux listener presenting `id`.
+    pub fn find_key(&self, id: &str) -> Option<&KeyConfig> {
+        self.keys.iter().flatten().find(|k| k.id == id)
+    }
+    /// The user of an rmux listener whose client certificate has `names`.
+    pub fn find_cert_user(&self, names: &[String]) -> Option<&KeyConfig> {
+        self.keys
+            .iter()
+            .flatten()
+            .find(|k| names.iter().any(|n| k.has_cert_name(n.as_str())))
+    }
+    pub fn requires_auth(&self) -> bool {
+        self.users.as_ref().map_or(false, |u| !u.is_empty())
+    }
+    pub fn check_user(&self, username: &[u8], password: &[u8]) -> bool {
+        let users = match &self.users {
+            Some(u) => u,
+            None => return false,
+        };
+        // compare every entry in constant time, so a match does not leak by timing
+        users.iter().fold(false, |found, u| {
+            let user_ok = verify_slices_are_equal(u.username.as_bytes(), username).is_ok();
+            let pass_ok = verify_slices_are_equal(u.password.as_bytes(), password).is_ok();
+            found | (user_ok & pass_ok)
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -91,6 +1109,51 @@ pub struct DebugConfig {
     pub listen: String,
 }
 
+/// Options of the builtin `direct` channel.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DirectConfig {
+    /// upstream `http://` or `socks5://` proxy for direct destinations
+    pub proxy: Option<String>,
+    pub bind_address: Option<String>,
+    pub bind_interface: Option<String>,
+}
+
+impl DirectConfig {
+    pub fn dial_options(&self) -> DialOptions {
+        dial_options(&self.bind_address, &self.bind_interface)
+    }
+}
+
+/// The MaxMind DB (`.mmdb`) file of the `geoip:` conditions of pac rules,
+/// like GeoLite2-Country.mmdb.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GeoIpConfig {
+    pub path: String,
+    /// hours after which a changed file is read again, 24 by default
+    pub reload_hours: Option<u64>,
+}
+
+/// A gfwlist, adblock filter list or hosts file fetched from `url`, which
+/// pac rules refer to by `name`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RuleListConfig {
+    pub name: String,
+    pub url: String,
+    /// file the last fetched list is kept in and read at start, one in the
+    /// temp dir by default
+    pub cache: Option<String>,
+    /// hours between fetches, 24 by default
+    pub update_hours: Option<u64>,
+    /// channel the list is fetched over, "direct" by default
+    pub channel: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BufferPoolConfig {
+    pub chunk_size: usize,
+    pub max_chunks: usize,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     pub log: LogConfig,
@@ -98,4 +1161,72 @@ pub struct Config {
     // pub server: Vec<ServerConfig>,
     pub channel: Option<Vec<ChannelConfig>>,
     pub debug: Option<DebugConfig>,
+    pub buffer_pool: Option<BufferPoolConfig>,
+    pub direct: Option<DirectConfig>,
+    pub geoip: Option<GeoIpConfig>,
+    pub rule_list: Option<Vec<RuleListConfig>>,
+    /// on ctrl-c tell the peers of all rmux sessions to open no new
+    /// streams, and exit once the open ones finish or this many seconds
+    /// passed; exits right away if unset
+    pub drain_timeout_secs: Option<u64>,
+}
+
+impl Config {
+    /// Refuses settings the listeners and channels could not run with.
+    pub fn check(&self) -> std::io::Result<()> {
+        let windows = self
+            .channel
+            .iter()
+            .flatten()
+            .map(|c| (c.name.as_str(), c.stream_window))
+            .chain(
+                self.tunnel
+                    .iter()
+                    .map(|t| (t.listen.as_str(), t.stream_window)),
+            );
+        for (name, window) in windows {
+            // stream windows are counted in i32
+            if window.unwrap_or(0) > i32::MAX as u32 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("stream_window of {} exceeds {}", name, i32::MAX),
+                ));
+            }
+        }
+        Ok(())
+    }
+    /// Reads the secrets the config refers to instead of holding them, see
+    /// `secret`.
+    pub fn resolve_secrets(&mut self) -> std::io::Result<()> {
+        for c in self.channel.iter_mut().flatten() {
+            c.cipher.resolve_secrets()?;
+            if let Some(obfs) = c.obfs.as_mut() {
+                obfs.resolve_secrets()?;
+            }
+            if let Some(noise) = c.noise.as_mut() {
+                noise.resolve_secrets()?;
+            }
+        }
+        for t in self.tunnel.iter_mut() {
+            if let Some(cipher) = t.cipher.as_mut() {
+                cipher.resolve_secrets()?;
+            }
+            if let Some(obfs) = t.obfs.as_mut() {
+                obfs.resolve_secrets()?;
+            }
+            if let Some(noise) = t.noise.as_mut() {
+                noise.resolve_secrets()?;
+            }
+            for u in t.users.iter_mut().flatten() {
+                resolve_secret(&mut u.password, u.password_file.as_deref())?;
+            }
+            for k in t.keys.iter_mut().flatten() {
+                resolve_secret(&mut k.key, k.key_file.as_deref())?;
+            }
+            for p in t.trojan.iter_mut().flat_map(|t| t.passwords.iter_mut()) {
+                resolve_secret(p, None)?;
+            }
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,282 @@
+//! The domain, ip and port conditions of pac rules.
+use crate::utils::{geoip_country, DomainTrie, IpTrie};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+const PRIVATE_NETS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+];
+const GEOIP_PREFIX: &str = "geoip:";
+
+lazy_static! {
+    /// compiled regexes of the `regex:` matchers, shared by the rules
+    /// repeating a pattern
+    static ref REGEX_CACHE: Mutex<HashMap<String, Arc<Regex>>> = Mutex::new(HashMap::new());
+}
+
+fn cached_regex(pattern: &str) -> Result<Arc<Regex>, String> {
+    let mut cache = REGEX_CACHE.lock().unwrap();
+    if let Some(re) = cache.get(pattern) {
+        return Ok(re.clone());
+    }
+    let re = Regex::new(pattern).map_err(|e| format!("invalid regex {}:{}", pattern, e))?;
+    let re = Arc::new(re);
+    cache.insert(String::from(pattern), re.clone());
+    Ok(re)
+}
+
+/// The domain matchers of a rule: "example.com" or "domain:example.com"
+/// for the domain and its subdomains, "full:" for the domain alone,
+/// "keyword:" for hosts containing the word and "regex:" for hosts matching
+/// the regex. Hosts are lowercased before matching.
+#[derive(Debug, Clone, Default)]
+pub struct DomainSet {
+    suffixes: DomainTrie,
+    full: HashSet<String>,
+    keywords: Vec<String>,
+    regexes: Vec<Arc<Regex>>,
+}
+
+impl DomainSet {
+    pub fn add(&mut self, matcher: &str) -> Result<(), String> {
+        let (kind, value) = match matcher.find(':') {
+            Some(i) => (&matcher[..i], &matcher[i + 1..]),
+            None => ("domain", matcher),
+        };
+        if value.is_empty() {
+            return Err(format!("empty domain matcher {}", matcher));
+        }
+        match kind {
+            "domain" => {
+                self.suffixes.insert(value.to_ascii_lowercase().as_str());
+            }
+            "full" => {
+                self.full.insert(value.to_ascii_lowercase());
+            }
+            "keyword" => self.keywords.push(value.to_ascii_lowercase()),
+            "regex" => self.regexes.push(cached_regex(value)?),
+            _ => return Err(format!("unknown domain matcher {}", matcher)),
+        }
+        Ok(())
+    }
+
+    pub fn is_match(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        self.suffixes.contains(host.as_str())
+            || self.full.contains(&host)
+            || self.keywords.iter().any(|k| host.contains(k.as_str()))
+            || self.regexes.iter().any(|r| r.is_match(host.as_str()))
+    }
+}
+
+/// An ip range like "10.0.0.0/8" or "2001:db8::/32", a bare ip is a range
+/// of one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (ip, prefix) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let addr: IpAddr = ip.parse().map_err(|_| format!("invalid ip range {}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => match p.parse::<u8>() {
+                Ok(p) if p <= max => p,
+                _ => return Err(format!("invalid prefix length in {}", s)),
+            },
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+}
+
+/// An ip condition of a rule: a range, "geoip:private" for the private
+/// and local ranges, or "geoip:" and a country code, looked up in the
+/// GeoIP database.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IpRule {
+    Net(IpNet),
+    Private,
+    Country(String),
+}
+
+impl IpRule {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.strip_prefix(GEOIP_PREFIX) {
+            Some(code) if code.eq_ignore_ascii_case("private") => Ok(IpRule::Private),
+            Some(code) if code.len() == 2 => Ok(IpRule::Country(code.to_uppercase())),
+            Some(_) => Err(format!("invalid country code in {}", s)),
+            None => IpNet::parse(s).map(IpRule::Net),
+        }
+    }
+}
+
+/// The ip conditions of a rule. The ranges, those of "geoip:private"
+/// included, go into a prefix trie, so lists of thousands of ranges cost
+/// no more per lookup than a few; the countries take one GeoIP lookup.
+#[derive(Debug, Clone, Default)]
+pub struct IpRuleSet {
+    nets: IpTrie<()>,
+    countries: HashSet<String>,
+}
+
+impl IpRuleSet {
+    pub fn add(&mut self, rule: &str) -> Result<(), String> {
+        match IpRule::parse(rule)? {
+            IpRule::Net(net) => {
+                self.nets.insert(net.addr, net.prefix, ());
+            }
+            IpRule::Private => {
+                for net in PRIVATE_NETS {
+                    let net = IpNet::parse(net)?;
+                    self.nets.insert(net.addr, net.prefix, ());
+                }
+            }
+            IpRule::Country(code) => {
+                self.countries.insert(code);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        if self.nets.contains(ip) {
+            return true;
+        }
+        !self.countries.is_empty()
+            && geoip_country(*ip).is_some_and(|code| self.countries.contains(&code))
+    }
+}
+
+/// A port like "443" or an inclusive range like "8000-9000".
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PortRange(u16, u16);
+
+impl PortRange {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid port range {}", s);
+        let (lo, hi) = match s.find('-') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => (s, s),
+        };
+        let lo = lo.trim().parse::<u16>().map_err(|_| invalid())?;
+        let hi = hi.trim().parse::<u16>().map_err(|_| invalid())?;
+        if lo > hi {
+            return Err(invalid());
+        }
+        Ok(PortRange(lo, hi))
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        self.0 <= port && port <= self.1
+    }
+}
+
+/// The host and port of a target, `host:port` or `[v6]:port`, maybe after
+/// a scheme like that of UDP targets.
+pub fn split_target(target: &str) -> (&str, Option<u16>) {
+    let target = match target.find("://") {
+        Some(i) => &target[i + 3..],
+        None => target,
+    };
+    let (host, port) = match target.rfind(':') {
+        Some(i) if target.rfind(']').is_none_or(|j| j < i) => {
+            (&target[..i], target[i + 1..].parse().ok())
+        }
+        _ => (target, None),
+    };
+    (host.trim_start_matches('[').trim_end_matches(']'), port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_domain_set() {
+        let mut set = DomainSet::default();
+        for m in &[
+            "example.com",
+            "full:exact.example.org",
+            "keyword:tracker",
+            r"regex:^bucket-\d+\.s3\.amazonaws\.com$",
+        ] {
+            set.add(m).unwrap();
+        }
+        assert!(set.is_match("www.Example.com"));
+        assert!(set.is_match("exact.example.org"));
+        assert!(!set.is_match("a.exact.example.org"));
+        assert!(set.is_match("ads-tracker.net"));
+        assert!(set.is_match("bucket-42.s3.amazonaws.com"));
+        assert!(!set.is_match("bucket-x.s3.amazonaws.com"));
+        assert!(!set.is_match("example.net"));
+        assert!(set.add("suffix:example.com").is_err());
+        assert!(set.add("regex:(").is_err());
+        assert!(set.add("keyword:").is_err());
+
+        let a = cached_regex("^a+$").unwrap();
+        let b = cached_regex("^a+$").unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+    }
+
+    #[test]
+    fn test_route_conditions() {
+        let mut nets = IpRuleSet::default();
+        nets.add("10.0.0.0/8").unwrap();
+        nets.add("2001:db8::/32").unwrap();
+        assert!(nets.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(!nets.contains(&"11.0.0.1".parse().unwrap()));
+        assert!(!nets.contains(&"::1".parse().unwrap()));
+        assert!(nets.contains(&"2001:db8::1".parse().unwrap()));
+        let mut any = IpRuleSet::default();
+        any.add("0.0.0.0/0").unwrap();
+        assert!(any.contains(&"8.8.8.8".parse().unwrap()));
+        assert!(IpNet::parse("10.0.0.0/33").is_err());
+        assert!(IpNet::parse("example.com").is_err());
+        let mut private = IpRuleSet::default();
+        private.add("geoip:private").unwrap();
+        assert!(private.contains(&"192.168.1.1".parse().unwrap()));
+        assert!(private.contains(&"fd00::1".parse().unwrap()));
+        assert!(!private.contains(&"8.8.8.8".parse().unwrap()));
+        assert_eq!(
+            IpRule::parse("geoip:cn"),
+            Ok(IpRule::Country(String::from("CN")))
+        );
+        assert!(IpRule::parse("geoip:china").is_err());
+
+        let ports = PortRange::parse("8000-9000").unwrap();
+        assert!(ports.contains(8080) && !ports.contains(443));
+        assert!(PortRange::parse("443").unwrap().contains(443));
+        assert!(PortRange::parse("9000-8000").is_err());
+
+        assert_eq!(split_target("example.com:443"), ("example.com", Some(443)));
+        assert_eq!(split_target("[2001:db8::1]:53"), ("2001:db8::1", Some(53)));
+        assert_eq!(split_target("udp://8.8.8.8:53"), ("8.8.8.8", Some(53)));
+        assert_eq!(split_target("example.com"), ("example.com", None));
+    }
+}
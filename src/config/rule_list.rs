@@ -0,0 +1,314 @@
+//! Domain lists pac rules refer to by name, like gfwlist or adblock
+//! filters. A list is taken in the AutoProxy / Adblock Plus syntax, base64
+//! encoded like gfwlist or not, or as a hosts file or one domain or ip
+//! range per line.
+//! Only the host of a target is known, so url rules match by their host
+//! and cosmetic filters are ignored.
+use super::route::IpNet;
+use crate::utils::{DomainTrie, IpTrie};
+use regex::Regex;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+lazy_static! {
+    static ref RULE_LISTS: RwLock<HashMap<String, Arc<RuleList>>> = RwLock::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Copy)]
+enum MatchKind {
+    Net,
+    Domain,
+    Keyword,
+    Regex,
+}
+
+#[derive(Default)]
+struct Patterns {
+    /// hosts matching these or a subdomain of them
+    domains: DomainTrie,
+    /// hosts containing these
+    keywords: Vec<String>,
+    regexes: Vec<Regex>,
+    /// ip hosts in these ranges
+    nets: IpTrie<()>,
+}
+
+impl Patterns {
+    fn len(&self) -> usize {
+        self.domains.len() + self.keywords.len() + self.regexes.len() + self.nets.len()
+    }
+
+    /// The kind of the first pattern `host` matches, ranges and domains
+    /// tried first as they are the cheapest.
+    fn find(&self, host: &str) -> Option<MatchKind> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            if self.nets.contains(&ip) {
+                return Some(MatchKind::Net);
+            }
+        }
+        if self.domains.contains(host) {
+            return Some(MatchKind::Domain);
+        }
+        if self.keywords.iter().any(|k| host.contains(k.as_str())) {
+            return Some(MatchKind::Keyword);
+        }
+        // regexes of filter lists are written for urls
+        let url = format!("https://{}/", host);
+        if self
+            .regexes
+            .iter()
+            .any(|r| r.is_match(host) || r.is_match(url.as_str()))
+        {
+            return Some(MatchKind::Regex);
+        }
+        None
+    }
+}
+
+/// Counters of the lookups in a list, to tell which rules the time goes to.
+#[derive(Default)]
+struct MatchStats {
+    lookups: AtomicU64,
+    net_hits: AtomicU64,
+    domain_hits: AtomicU64,
+    keyword_hits: AtomicU64,
+    regex_hits: AtomicU64,
+    /// hosts matched but taken out by an exception
+    excepted: AtomicU64,
+    nanos: AtomicU64,
+}
+
+/// The compiled rules of a list, the hosts it names except those of its
+/// `@@` exceptions.
+#[derive(Default)]
+pub struct RuleList {
+    include: Patterns,
+    exclude: Patterns,
+    stats: MatchStats,
+}
+
+/// A wildcard pattern of a host, `*` standing for anything.
+fn wildcard(pattern: &str) -> Option<Regex> {
+    let re: Vec<String> = pattern.split('*').map(regex::escape).collect();
+    Regex::new(re.join(".*").as_str()).ok()
+}
+
+/// The host of a url or of a url without scheme.
+fn url_host(url: &str) -> &str {
+    let rest = match url.find("://") {
+        Some(i) => &url[i + 3..],
+        None => url,
+    };
+    let end = rest.find(['/', ':', '^', '|', '?']);
+    &rest[..end.unwrap_or(rest.len())]
+}
+
+impl RuleList {
+    pub fn parse(text: &str) -> Self {
+        let compact: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+        let decoded = base64::decode(compact.as_str())
+            .ok()
+            .and_then(|d| String::from_utf8(d).ok());
+        let text = decoded.as_deref().unwrap_or(text);
+        // bare words are keywords in filter lists and domains elsewhere
+        let filters = decoded.is_some()
+            || text
+                .lines()
+                .any(|l| l.starts_with("[AutoProxy") || l.starts_with("[Adblock"));
+        let mut list = RuleList::default();
+        for line in text.lines() {
+            list.add_line(line.trim(), filters);
+        }
+        list
+    }
+
+    fn add_line(&mut self, line: &str, filters: bool) {
+        if line.is_empty()
+            || line.starts_with('!')
+            || line.starts_with('[')
+            || line.starts_with('#')
+            || line.contains("##")
+            || line.contains("#@#")
+        {
+            return;
+        }
+        let (patterns, line) = match line.strip_prefix("@@") {
+            Some(rest) => (&mut self.exclude, rest),
+            None => (&mut self.include, line),
+        };
+        if line.len() > 2 && line.starts_with('/') && line.ends_with('/') {
+            if let Ok(re) = Regex::new(&line[1..line.len() - 1]) {
+                patterns.regexes.push(re);
+            }
+            return;
+        }
+        let line = match line.find('$') {
+            Some(i) => &line[..i],
+            None => line,
+        };
+        if let Ok(net) = IpNet::parse(line) {
+            patterns.nets.insert(net.addr(), net.prefix(), ());
+            return;
+        }
+        let mut words = line.split_whitespace();
+        if let (Some(first), Some(host)) = (words.next(), words.next()) {
+            // a hosts file line
+            if first.parse::<IpAddr>().is_ok() && host != "localhost" {
+                patterns.domains.insert(host.to_ascii_lowercase().as_str());
+            }
+            return;
+        }
+        let (host, domain) = if let Some(rest) = line.strip_prefix("||") {
+            (url_host(rest), true)
+        } else if let Some(rest) = line.strip_prefix('|') {
+            (url_host(rest), true)
+        } else {
+            (url_host(line), !filters)
+        };
+        let host = host.trim_start_matches('.').to_ascii_lowercase();
+        if host.is_empty() {
+            return;
+        }
+        if host.contains('*') {
+            if let Some(re) = wildcard(host.as_str()) {
+                patterns.regexes.push(re);
+            }
+        } else if domain {
+            patterns.domains.insert(host.as_str());
+        } else {
+            patterns.keywords.push(host);
+        }
+    }
+
+    /// Rules in the list, exceptions included.
+    pub fn len(&self) -> usize {
+        self.include.len() + self.exclude.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_match(&self, host: &str) -> bool {
+        let start = Instant::now();
+        let host = host.to_ascii_lowercase();
+        let kind = self.include.find(host.as_str());
+        let excepted = kind.is_some() && self.exclude.find(host.as_str()).is_some();
+        let stats = &self.stats;
+        stats.lookups.fetch_add(1, Ordering::Relaxed);
+        stats
+            .nanos
+            .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        let hits = match kind {
+            Some(MatchKind::Net) => &stats.net_hits,
+            Some(MatchKind::Domain) => &stats.domain_hits,
+            Some(MatchKind::Keyword) => &stats.keyword_hits,
+            Some(MatchKind::Regex) => &stats.regex_hits,
+            None => return false,
+        };
+        hits.fetch_add(1, Ordering::Relaxed);
+        if excepted {
+            stats.excepted.fetch_add(1, Ordering::Relaxed);
+        }
+        !excepted
+    }
+
+    /// One line of the sizes of the list and the counters of its lookups.
+    pub fn stats(&self) -> String {
+        let (nodes, labels) = self.include.domains.size();
+        let stats = &self.stats;
+        let lookups = stats.lookups.load(Ordering::Relaxed);
+        let avg_nanos = stats.nanos.load(Ordering::Relaxed) / lookups.max(1);
+        format!(
+            "{} ip ranges, {} domains in {} nodes/{} labels, {} keywords, {} regexes, \
+             {} exceptions; {} lookups, hits ip:{} domain:{} keyword:{} regex:{}, \
+             {} excepted, avg {}ns",
+            self.include.nets.len(),
+            self.include.domains.len(),
+            nodes,
+            labels,
+            self.include.keywords.len(),
+            self.include.regexes.len(),
+            self.exclude.len(),
+            lookups,
+            stats.net_hits.load(Ordering::Relaxed),
+            stats.domain_hits.load(Ordering::Relaxed),
+            stats.keyword_hits.load(Ordering::Relaxed),
+            stats.regex_hits.load(Ordering::Relaxed),
+            stats.excepted.load(Ordering::Relaxed),
+            avg_nanos
+        )
+    }
+}
+
+/// Makes `list` the rules of the list called `name`.
+pub fn set_rule_list(name: &str, list: RuleList) {
+    RULE_LISTS
+        .write()
+        .unwrap()
+        .insert(String::from(name), Arc::new(list));
+}
+
+/// Whether `host` is in the list called `name`, never while the list is
+/// not loaded.
+pub fn rule_list_match(name: &str, host: &str) -> bool {
+    let list = match RULE_LISTS.read().unwrap().get(name) {
+        Some(l) => l.clone(),
+        None => return false,
+    };
+    list.is_match(host)
+}
+
+/// The stats of every loaded list, a line each.
+pub fn dump_rule_list_stats() -> String {
+    let lists = RULE_LISTS.read().unwrap();
+    let mut names: Vec<&String> = lists.keys().collect();
+    names.sort();
+    let mut out = String::new();
+    for name in names {
+        let _ = writeln!(out, "{}: {}", name, lists[name].stats());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule_list() {
+        let gfwlist = "[AutoProxy 0.2.9]\n! comment\n||google.com\n.twitter.com\n\
+                       |http://85.17.73.31/\n/^https?:\\/\\/www\\.blocked\\.[a-z]+\\//\n@@||cn.google.com\n\
+                       *.wikipedia.org\n";
+        let list = RuleList::parse(base64::encode(gfwlist).as_str());
+        assert!(list.is_match("www.google.com"));
+        assert!(!list.is_match("cn.google.com"));
+        assert!(list.is_match("mobile.twitter.com"));
+        assert!(list.is_match("85.17.73.31"));
+        assert!(list.is_match("www.blocked.net"));
+        assert!(list.is_match("en.wikipedia.org"));
+        assert!(!list.is_match("example.com"));
+        let stats = list.stats();
+        assert!(stats.contains("7 lookups, hits ip:0 domain:3 keyword:1 regex:2, 1 excepted"));
+
+        let adblock = "[Adblock Plus 2.0]\n||ads.example.com^$third-party\nexample.org##.banner\n";
+        let list = RuleList::parse(adblock);
+        assert!(list.is_match("x.ads.example.com"));
+        assert!(!list.is_match("example.org"));
+
+        let hosts = "# hosts\n0.0.0.0 tracker.example.net\n127.0.0.1 localhost\nplain.example\n\
+                     1.0.1.0/24\n240e::/20\n";
+        let list = RuleList::parse(hosts);
+        assert_eq!(list.len(), 4);
+        assert!(list.is_match("1.0.1.200"));
+        assert!(list.is_match("240e:3b1::1"));
+        assert!(!list.is_match("1.0.2.1"));
+        assert!(list.is_match("Tracker.Example.Net"));
+        assert!(list.is_match("a.plain.example"));
+        assert!(!list.is_match("localhost"));
+    }
+}
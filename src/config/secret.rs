@@ -0,0 +1,127 @@
+//! Secrets kept out of the config file. A secret may be given as
+//! `keyring:<service>/<account>`, looked up in the OS keyring (the macOS
+//! keychain, or the Secret Service through `secret-tool` elsewhere), or as
+//! `file:<path>`; fields with a `*_file` sibling read that file instead of
+//! an inline value. Either way the secret loses surrounding whitespace.
+use crate::utils::make_io_error;
+use std::io;
+use std::process::Command;
+
+const KEYRING_PREFIX: &str = "keyring:";
+const FILE_PREFIX: &str = "file:";
+
+/// Replaces `value` by the secret it refers to, or by the contents of
+/// `file` if set.
+pub fn resolve_secret(value: &mut String, file: Option<&str>) -> io::Result<()> {
+    let secret = if let Some(path) = file {
+        read_secret_file(path)?
+    } else if let Some(path) = value.strip_prefix(FILE_PREFIX) {
+        read_secret_file(path)?
+    } else if let Some(reference) = value.strip_prefix(KEYRING_PREFIX) {
+        keyring_lookup(reference)?
+    } else {
+        return Ok(());
+    };
+    *value = secret;
+    Ok(())
+}
+
+/// `resolve_secret` of an optional `value`, set from `file` if need be.
+pub fn resolve_optional_secret(value: &mut Option<String>, file: Option<&str>) -> io::Result<()> {
+    if file.is_some() && value.is_none() {
+        *value = Some(String::new());
+    }
+    match value.as_mut() {
+        Some(v) => resolve_secret(v, file),
+        None => Ok(()),
+    }
+}
+
+fn trimmed(raw: Vec<u8>) -> io::Result<String> {
+    match std::str::from_utf8(&raw[..]) {
+        Ok(s) => Ok(String::from(s.trim())),
+        Err(_) => Err(make_io_error("secret is not UTF-8")),
+    }
+}
+
+fn read_secret_file(path: &str) -> io::Result<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            warn!(
+                "Secret file {} is readable by others, mode {:o}",
+                path,
+                mode & 0o777
+            );
+        }
+    }
+    match std::fs::read(path) {
+        Ok(raw) => trimmed(raw),
+        Err(e) => Err(make_io_error(&format!("secret file {}: {}", path, e))),
+    }
+}
+
+fn keyring_lookup(reference: &str) -> io::Result<String> {
+    let (service, account) = match reference.find('/') {
+        Some(i) => (&reference[..i], &reference[i + 1..]),
+        None => {
+            return Err(make_io_error(&format!(
+                "keyring reference {} is not service/account",
+                reference
+            )))
+        }
+    };
+    let output = if cfg!(target_os = "macos") {
+        Command::new("security")
+            .args(["find-generic-password", "-s", service, "-a", account, "-w"])
+            .output()
+    } else if cfg!(windows) {
+        return Err(make_io_error(
+            "keyring secrets are not supported on windows",
+        ));
+    } else {
+        Command::new("secret-tool")
+            .args(["lookup", "service", service, "account", account])
+            .output()
+    };
+    match output {
+        Ok(out) if out.status.success() && !out.stdout.is_empty() => trimmed(out.stdout),
+        Ok(_) => Err(make_io_error(&format!(
+            "no keyring secret for {}",
+            reference
+        ))),
+        Err(e) => Err(make_io_error(&format!("keyring lookup: {}", e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_secret() {
+        let path = std::env::temp_dir().join(format!("rsnova-secret-{}", std::process::id()));
+        std::fs::write(&path, b"  s3cret\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut inline = String::from("plain");
+        resolve_secret(&mut inline, None).unwrap();
+        assert_eq!(inline, "plain");
+        let mut by_prefix = format!("file:{}", path);
+        resolve_secret(&mut by_prefix, None).unwrap();
+        assert_eq!(by_prefix, "s3cret");
+        let mut by_field = String::new();
+        resolve_secret(&mut by_field, Some(path)).unwrap();
+        assert_eq!(by_field, "s3cret");
+        let mut optional = None;
+        resolve_optional_secret(&mut optional, Some(path)).unwrap();
+        assert_eq!(optional.as_deref(), Some("s3cret"));
+        let mut missing = String::from("file:/nonexistent/rsnova-secret");
+        assert!(resolve_secret(&mut missing, None).is_err());
+        let mut bad = String::from("keyring:no-account");
+        assert!(resolve_secret(&mut bad, None).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,4 +1,6 @@
-use super::rmux::dump_session_state;
+use super::config::dump_rule_list_stats;
+use super::rmux::{drain_sessions, dump_session_state, session_stats};
+use super::utils::dump_stream_metrics;
 
 pub fn handle_debug_server(debug_server: tiny_http::Server) {
     for request in debug_server.incoming_requests() {
@@ -12,6 +14,23 @@ pub fn handle_debug_server(debug_server: tiny_http::Server) {
         if request.url() == "/stat" {
             let s = tiny_http::Response::from_string(dump_session_state());
             let _ = request.respond(s);
+        } else if request.url() == "/sessions" {
+            let mut info = String::new();
+            for stats in session_stats() {
+                info.push_str(stats.to_string().as_str());
+            }
+            let s = tiny_http::Response::from_string(info);
+            let _ = request.respond(s);
+        } else if request.url() == "/drain" {
+            let n = drain_sessions();
+            let s = tiny_http::Response::from_string(format!("Draining, {} open streams\n", n));
+            let _ = request.respond(s);
+        } else if request.url() == "/streams" {
+            let s = tiny_http::Response::from_string(dump_stream_metrics());
+            let _ = request.respond(s);
+        } else if request.url() == "/rules" {
+            let s = tiny_http::Response::from_string(dump_rule_list_stats());
+            let _ = request.respond(s);
         } else {
             let response = tiny_http::Response::from_string("Not support");
             let _ = request.respond(response);
@@ -12,19 +12,24 @@ extern crate lazy_static;
 extern crate futures;
 
 pub use self::config::Config;
+pub use self::transport::noise_keypair;
 
 mod channel;
 pub mod config;
 mod debug;
+mod mux;
+mod obfs;
 mod rmux;
+mod transport;
 mod tunnel;
 mod utils;
 
 use futures::FutureExt;
 use std::error::Error;
 use std::thread;
+use std::time::{Duration, Instant};
 
-pub async fn start_rsnova(cfg: config::Config) -> Result<(), Box<dyn Error>> {
+pub async fn start_rsnova(mut cfg: config::Config) -> Result<(), Box<dyn Error>> {
     let mut logger = flexi_logger::Logger::with_str(cfg.log.level.as_str());
     if !cfg.log.logdir.is_empty() {
         logger = logger
@@ -34,13 +39,34 @@ pub async fn start_rsnova(cfg: config::Config) -> Result<(), Box<dyn Error>> {
                 flexi_logger::Naming::Numbers,
                 flexi_logger::Cleanup::KeepLogFiles(10),
             )
-            .directory(cfg.log.logdir)
+            .directory(cfg.log.logdir.clone())
             .format(flexi_logger::colored_opt_format);
     }
     if cfg.log.logtostderr {
         logger = logger.duplicate_to_stderr(flexi_logger::Duplicate::Info);
     }
     logger.start().unwrap();
+    cfg.resolve_secrets()?;
+    cfg.check()?;
+
+    if let Some(pool_cfg) = &cfg.buffer_pool {
+        utils::init_buffer_pool(pool_cfg.chunk_size, pool_cfg.max_chunks);
+    }
+    if let Some(direct_cfg) = &cfg.direct {
+        channel::init_direct(direct_cfg);
+    }
+    if let Some(geoip_cfg) = &cfg.geoip {
+        let reload = geoip_cfg
+            .reload_hours
+            .map(|h| Duration::from_secs(h * 3600));
+        utils::init_geoip(geoip_cfg.path.as_str(), reload);
+    }
+    if let Some(channels) = &cfg.channel {
+        channel::init_channel_tags(channels);
+    }
+    if let Some(lists) = &cfg.rule_list {
+        tunnel::start_rule_lists(lists);
+    }
 
     if cfg.debug.is_some() {
         let debug_cfg = cfg.debug.unwrap();
@@ -50,6 +76,10 @@ pub async fn start_rsnova(cfg: config::Config) -> Result<(), Box<dyn Error>> {
         });
     }
 
+    if let Some(timeout) = cfg.drain_timeout_secs {
+        tokio::spawn(drain_on_ctrl_c(Duration::from_secs(timeout)));
+    }
+
     for c in cfg.tunnel {
         info!("Start rsnova client at {} ", c.listen);
         let handle = tunnel::start_tunnel_server(c).map(|r| {
@@ -64,3 +94,20 @@ pub async fn start_rsnova(cfg: config::Config) -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+/// Lets the streams of all sessions finish before exiting, so the peers
+/// can move to another server in a rolling restart.
+async fn drain_on_ctrl_c(timeout: Duration) {
+    if tokio::signal::ctrl_c().await.is_err() {
+        return;
+    }
+    let deadline = Instant::now() + timeout;
+    let mut open_streams = rmux::drain_sessions();
+    info!("Draining {} streams before exit.", open_streams);
+    while open_streams > 0 && Instant::now() < deadline {
+        tokio::time::delay_for(Duration::from_secs(1)).await;
+        open_streams = rmux::drain_sessions();
+    }
+    info!("Exit with {} streams left.", open_streams);
+    std::process::exit(0);
+}
@@ -0,0 +1,79 @@
+//! Stream multiplexers of other tools, spoken by channels and rmux listeners
+//! whose `mux` is not "rmux". Their streams start with the SOCKS5 style
+//! target address, unless the listener has a fixed `forward` target.
+mod session;
+mod smux;
+mod yamux;
+
+pub use self::session::{
+    get_mux_session_size, get_mux_stream, is_mux_channel, run_mux_client, run_mux_server, MuxStream,
+};
+
+use bytes::{Bytes, BytesMut};
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MuxProtocol {
+    Yamux,
+    /// smux of the given version, 1 or 2
+    Smux(u8),
+}
+
+impl MuxProtocol {
+    /// The multiplexer named by a `mux` setting, `None` for rmux.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "yamux" => Some(MuxProtocol::Yamux),
+            "smux" => Some(MuxProtocol::Smux(1)),
+            "smux2" => Some(MuxProtocol::Smux(2)),
+            _ => None,
+        }
+    }
+    /// Bytes a new stream may send before the peer grants more, `None`
+    /// without flow control.
+    fn initial_window(self) -> Option<u32> {
+        match self {
+            MuxProtocol::Yamux => Some(yamux::INITIAL_WINDOW),
+            MuxProtocol::Smux(2) => Some(smux::INITIAL_WINDOW),
+            MuxProtocol::Smux(_) => None,
+        }
+    }
+    /// Grants the peer the `consumed` bytes just read, out of `total` read
+    /// from the stream so far.
+    fn window_update(self, sid: u32, consumed: u32, total: u32) -> Frame {
+        match self {
+            MuxProtocol::Smux(_) => Frame::Consumed(sid, total, smux::INITIAL_WINDOW),
+            _ => Frame::Window(sid, consumed),
+        }
+    }
+    fn encode(self, frame: &Frame, out: &mut BytesMut) {
+        match self {
+            MuxProtocol::Yamux => yamux::encode(frame, out),
+            MuxProtocol::Smux(version) => smux::encode(version, frame, out),
+        }
+    }
+    /// Moves the frames of the first complete header of `buf` to `frames`,
+    /// false if more bytes are needed.
+    fn decode(self, buf: &mut BytesMut, frames: &mut Vec<Frame>) -> io::Result<bool> {
+        match self {
+            MuxProtocol::Yamux => yamux::decode(buf, frames),
+            MuxProtocol::Smux(version) => smux::decode(version, buf, frames),
+        }
+    }
+}
+
+/// What every wire format boils down to, one header may carry several.
+#[derive(Debug, Clone, PartialEq)]
+enum Frame {
+    Open(u32),
+    Ack(u32),
+    Data(u32, Bytes),
+    Fin(u32),
+    Reset(u32),
+    /// a credit of more bytes
+    Window(u32, u32),
+    /// bytes read by the peer since the stream opened and its window
+    Consumed(u32, u32, u32),
+    Ping(u32, bool),
+    GoAway,
+}
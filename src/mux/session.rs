@@ -0,0 +1,539 @@
+use super::{Frame, MuxProtocol};
+use crate::channel::ChannelStream;
+use crate::config::ChannelConfig;
+use crate::transport::encode_ss_addr;
+use crate::utils::make_io_error;
+
+use bytes::{Buf, Bytes, BytesMut};
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+const MAX_DATA_FRAME: usize = 32 * 1024;
+const MAX_WRITE_BATCH: usize = 64 * 1024;
+const READ_BUF_SIZE: usize = 64 * 1024;
+// smux peers drop sessions they heard nothing from for 30 seconds
+const SERVER_PING_INTERVAL: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref CHANNEL_MUX_SESSIONS: Mutex<HashMap<String, Vec<Arc<MuxSession>>>> =
+        Mutex::new(HashMap::new());
+}
+
+struct SendWindow {
+    // None without flow control
+    avail: Option<u32>,
+    // bytes written since the stream opened, wrapping like the peer's count
+    sent: u32,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+impl SendWindow {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct StreamSlot {
+    // dropped once the peer is done writing
+    data_tx: Option<mpsc::UnboundedSender<Bytes>>,
+    window: Arc<Mutex<SendWindow>>,
+}
+
+impl StreamSlot {
+    fn close(&mut self) {
+        self.data_tx = None;
+        let mut window = self.window.lock().unwrap();
+        window.closed = true;
+        window.wake();
+    }
+}
+
+struct SessionState {
+    streams: HashMap<u32, StreamSlot>,
+    next_id: u32,
+    // no new streams after a go away or once the session ended
+    closed: bool,
+}
+
+struct MuxSession {
+    id: u32,
+    protocol: MuxProtocol,
+    frame_tx: mpsc::UnboundedSender<Frame>,
+    state: Mutex<SessionState>,
+}
+
+impl MuxSession {
+    fn new(
+        id: u32,
+        protocol: MuxProtocol,
+        is_client: bool,
+    ) -> (Arc<Self>, mpsc::UnboundedReceiver<Frame>) {
+        let (frame_tx, frame_rx) = mpsc::unbounded_channel();
+        let session = MuxSession {
+            id,
+            protocol,
+            frame_tx,
+            state: Mutex::new(SessionState {
+                streams: HashMap::new(),
+                // clients open odd streams, servers even ones
+                next_id: if is_client { 1 } else { 2 },
+                closed: false,
+            }),
+        };
+        (Arc::new(session), frame_rx)
+    }
+
+    fn send(&self, frame: Frame) -> io::Result<()> {
+        self.frame_tx
+            .send(frame)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn stream_count(&self) -> usize {
+        self.state.lock().unwrap().streams.len()
+    }
+
+    fn add_stream(self: &Arc<Self>, state: &mut SessionState, sid: u32) -> MuxStream {
+        let (data_tx, data_rx) = mpsc::unbounded_channel();
+        let window = Arc::new(Mutex::new(SendWindow {
+            avail: self.protocol.initial_window(),
+            sent: 0,
+            closed: false,
+            waker: None,
+        }));
+        state.streams.insert(
+            sid,
+            StreamSlot {
+                data_tx: Some(data_tx),
+                window: window.clone(),
+            },
+        );
+        MuxStream {
+            reader: MuxReader {
+                sid,
+                session: self.clone(),
+                rx: data_rx,
+                pending: Bytes::new(),
+                consumed: 0,
+                total: 0,
+                eof: false,
+            },
+            writer: MuxWriter {
+                sid,
+                session: self.clone(),
+                window,
+                fin_sent: false,
+            },
+        }
+    }
+
+    fn open_stream(self: &Arc<Self>) -> io::Result<MuxStream> {
+        let stream = {
+            let mut state = self.state.lock().unwrap();
+            if state.closed {
+                return Err(make_io_error("mux session closed"));
+            }
+            let sid = state.next_id;
+            state.next_id += 2;
+            self.add_stream(&mut state, sid)
+        };
+        self.send(Frame::Open(stream.writer.sid))?;
+        Ok(stream)
+    }
+
+    fn handle_frame(
+        self: &Arc<Self>,
+        frame: Frame,
+        accept_tx: Option<&mpsc::UnboundedSender<MuxStream>>,
+    ) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match frame {
+            Frame::Open(sid) => {
+                let accept_tx = match accept_tx {
+                    Some(tx) if !state.closed && !state.streams.contains_key(&sid) => tx,
+                    _ => {
+                        drop(state);
+                        return self.send(Frame::Reset(sid));
+                    }
+                };
+                let stream = self.add_stream(&mut state, sid);
+                drop(state);
+                self.send(Frame::Ack(sid))?;
+                // a refused stream resets itself on drop
+                let _ = accept_tx.send(stream);
+            }
+            Frame::Data(sid, data) => {
+                if let Some(tx) = state.streams.get(&sid).and_then(|s| s.data_tx.as_ref()) {
+                    let _ = tx.send(data);
+                }
+            }
+            Frame::Fin(sid) => {
+                if let Some(slot) = state.streams.get_mut(&sid) {
+                    slot.data_tx = None;
+                }
+            }
+            Frame::Reset(sid) => {
+                if let Some(slot) = state.streams.get_mut(&sid) {
+                    slot.close();
+                }
+            }
+            Frame::Window(sid, delta) => {
+                if let Some(slot) = state.streams.get(&sid) {
+                    let mut window = slot.window.lock().unwrap();
+                    if let Some(avail) = window.avail.as_mut() {
+                        *avail = avail.saturating_add(delta);
+                    }
+                    window.wake();
+                }
+            }
+            Frame::Consumed(sid, consumed, peer_window) => {
+                if let Some(slot) = state.streams.get(&sid) {
+                    let mut window = slot.window.lock().unwrap();
+                    let inflight = window.sent.wrapping_sub(consumed);
+                    if window.avail.is_some() {
+                        window.avail = Some(peer_window.saturating_sub(inflight));
+                    }
+                    window.wake();
+                }
+            }
+            Frame::Ping(opaque, false) => {
+                drop(state);
+                self.send(Frame::Ping(opaque, true))?;
+            }
+            Frame::Ping(_, true) | Frame::Ack(_) => {}
+            Frame::GoAway => state.closed = true,
+        }
+        Ok(())
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        for slot in state.streams.values_mut() {
+            slot.close();
+        }
+    }
+}
+
+pub struct MuxReader {
+    sid: u32,
+    session: Arc<MuxSession>,
+    rx: mpsc::UnboundedReceiver<Bytes>,
+    pending: Bytes,
+    // read since the last window update
+    consumed: u32,
+    total: u32,
+    eof: bool,
+}
+
+impl AsyncRead for MuxReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let me = &mut *self;
+        while me.pending.is_empty() {
+            match ready!(me.rx.poll_recv(cx)) {
+                Some(data) => me.pending = data,
+                None => {
+                    me.eof = true;
+                    return Poll::Ready(Ok(0));
+                }
+            }
+        }
+        let n = std::cmp::min(buf.len(), me.pending.len());
+        buf[..n].copy_from_slice(&me.pending[..n]);
+        me.pending.advance(n);
+        if let Some(window) = me.session.protocol.initial_window() {
+            me.consumed += n as u32;
+            me.total = me.total.wrapping_add(n as u32);
+            if me.consumed >= window / 2 {
+                let update = me
+                    .session
+                    .protocol
+                    .window_update(me.sid, me.consumed, me.total);
+                let _ = me.session.send(update);
+                me.consumed = 0;
+            }
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+pub struct MuxWriter {
+    sid: u32,
+    session: Arc<MuxSession>,
+    window: Arc<Mutex<SendWindow>>,
+    fin_sent: bool,
+}
+
+impl MuxWriter {
+    fn send_fin(&mut self) -> io::Result<()> {
+        if self.fin_sent {
+            return Ok(());
+        }
+        self.fin_sent = true;
+        self.session.send(Frame::Fin(self.sid))
+    }
+}
+
+impl AsyncWrite for MuxWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = {
+            let mut guard = self.window.lock().unwrap();
+            let window = &mut *guard;
+            if window.closed || self.fin_sent {
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+            }
+            if window.avail == Some(0) {
+                window.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            let n = std::cmp::min(buf.len(), MAX_DATA_FRAME);
+            let n = match window.avail.as_mut() {
+                Some(avail) => {
+                    let n = std::cmp::min(n, *avail as usize);
+                    *avail -= n as u32;
+                    n
+                }
+                None => n,
+            };
+            window.sent = window.sent.wrapping_add(n as u32);
+            n
+        };
+        self.session
+            .send(Frame::Data(self.sid, Bytes::copy_from_slice(&buf[..n])))?;
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.send_fin())
+    }
+}
+
+/// A stream of a yamux style session, reset when dropped before both
+/// sides finished it.
+pub struct MuxStream {
+    reader: MuxReader,
+    writer: MuxWriter,
+}
+
+impl MuxStream {
+    pub fn split_mut(&mut self) -> (&mut MuxReader, &mut MuxWriter) {
+        (&mut self.reader, &mut self.writer)
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let sid = self.writer.sid;
+        let session = &self.writer.session;
+        if !self.writer.fin_sent || !self.reader.eof {
+            let _ = session.send(Frame::Reset(sid));
+        }
+        session.state.lock().unwrap().streams.remove(&sid);
+    }
+}
+
+impl ChannelStream for MuxStream {
+    fn split(
+        &mut self,
+    ) -> (
+        Box<dyn AsyncRead + Send + Unpin + '_>,
+        Box<dyn AsyncWrite + Send + Unpin + '_>,
+    ) {
+        (Box::new(&mut self.reader), Box::new(&mut self.writer))
+    }
+    fn close(&mut self) -> io::Result<()> {
+        self.writer.send_fin()
+    }
+}
+
+/// Runs a session until its transport fails or closes, frames are written
+/// in batches by one task while another reads and dispatches them.
+async fn run_session<R, W>(
+    session: &Arc<MuxSession>,
+    mut frame_rx: mpsc::UnboundedReceiver<Frame>,
+    ri: &mut R,
+    wi: &mut W,
+    accept_tx: Option<mpsc::UnboundedSender<MuxStream>>,
+    ping_interval: Duration,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let protocol = session.protocol;
+    let read_loop = async {
+        let mut buf = BytesMut::with_capacity(READ_BUF_SIZE);
+        let mut frames = Vec::new();
+        loop {
+            while protocol.decode(&mut buf, &mut frames)? {}
+            for frame in frames.drain(..) {
+                session.handle_frame(frame, accept_tx.as_ref())?;
+            }
+            buf.reserve(READ_BUF_SIZE);
+            if ri.read_buf(&mut buf).await? == 0 {
+                return Ok::<(), io::Error>(());
+            }
+        }
+    };
+    let write_loop = async {
+        let mut out = BytesMut::new();
+        while let Some(frame) = frame_rx.recv().await {
+            protocol.encode(&frame, &mut out);
+            while out.len() < MAX_WRITE_BATCH {
+                match frame_rx.try_recv() {
+                    Ok(frame) => protocol.encode(&frame, &mut out),
+                    Err(_) => break,
+                }
+            }
+            wi.write_all(&out[..]).await?;
+            wi.flush().await?;
+            out.clear();
+        }
+        Ok::<(), io::Error>(())
+    };
+    let keepalive = async {
+        let mut ticker = tokio::time::interval(ping_interval);
+        let mut opaque: u32 = 0;
+        loop {
+            ticker.tick().await;
+            opaque = opaque.wrapping_add(1);
+            if session.send(Frame::Ping(opaque, false)).is_err() {
+                return Ok::<(), io::Error>(());
+            }
+        }
+    };
+    let r = tokio::select! {
+        r = read_loop => r,
+        r = write_loop => r,
+        r = keepalive => r,
+    };
+    session.close();
+    r
+}
+
+pub fn is_mux_channel(channel: &str) -> bool {
+    CHANNEL_MUX_SESSIONS.lock().unwrap().contains_key(channel)
+}
+
+pub fn get_mux_session_size(channel: &str) -> usize {
+    match CHANNEL_MUX_SESSIONS.lock().unwrap().get(channel) {
+        Some(sessions) => sessions.len(),
+        None => 0,
+    }
+}
+
+/// Opens a stream to `addr` on the least busy session of the channel.
+pub async fn get_mux_stream(
+    channel: &str,
+    addr: String,
+) -> Result<Box<dyn ChannelStream + Send>, std::io::Error> {
+    let session = CHANNEL_MUX_SESSIONS
+        .lock()
+        .unwrap()
+        .get(channel)
+        .and_then(|sessions| sessions.iter().min_by_key(|s| s.stream_count()).cloned());
+    let session = match session {
+        Some(s) => s,
+        None => return Err(make_io_error("no mux session")),
+    };
+    let mut stream = session.open_stream()?;
+    let mut header = BytesMut::new();
+    encode_ss_addr(addr.as_str(), &mut header)?;
+    stream.writer.write_all(&header[..]).await?;
+    Ok(Box::new(stream))
+}
+
+/// Serves the client end of a channel's session over a connected transport.
+pub async fn run_mux_client<R, W>(
+    config: &ChannelConfig,
+    session_id: u32,
+    protocol: MuxProtocol,
+    ri: &mut R,
+    wi: &mut W,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (session, frame_rx) = MuxSession::new(session_id, protocol, true);
+    CHANNEL_MUX_SESSIONS
+        .lock()
+        .unwrap()
+        .entry(config.name.clone())
+        .or_insert_with(Vec::new)
+        .push(session.clone());
+    info!(
+        "[{}]{} session of channel {} started",
+        session.id,
+        config.mux(),
+        config.name
+    );
+    let ping_interval = Duration::from_secs(config.ping_interval_secs());
+    let r = run_session(&session, frame_rx, ri, wi, None, ping_interval).await;
+    if let Some(sessions) = CHANNEL_MUX_SESSIONS
+        .lock()
+        .unwrap()
+        .get_mut(config.name.as_str())
+    {
+        sessions.retain(|s| !Arc::ptr_eq(s, &session));
+    }
+    r
+}
+
+/// Serves the server end of a session, handing every stream the peer opens
+/// to `on_stream`.
+pub async fn run_mux_server<R, W, F>(
+    session_id: u32,
+    protocol: MuxProtocol,
+    ri: &mut R,
+    wi: &mut W,
+    mut on_stream: F,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: FnMut(MuxStream),
+{
+    let (accept_tx, mut accept_rx) = mpsc::unbounded_channel();
+    let (session, frame_rx) = MuxSession::new(session_id, protocol, false);
+    let run = run_session(
+        &session,
+        frame_rx,
+        ri,
+        wi,
+        Some(accept_tx),
+        SERVER_PING_INTERVAL,
+    );
+    let accept = async {
+        while let Some(stream) = accept_rx.recv().await {
+            on_stream(stream);
+        }
+        Ok::<(), io::Error>(())
+    };
+    tokio::select! {
+        r = run => r,
+        r = accept => r,
+    }
+}
@@ -0,0 +1,119 @@
+use super::Frame;
+use crate::utils::make_invalid_data_error;
+
+use bytes::{Buf, BufMut, BytesMut};
+use std::io;
+
+const HEADER_LEN: usize = 8;
+const UPDATE_LEN: usize = 8;
+
+const CMD_SYN: u8 = 0;
+const CMD_FIN: u8 = 1;
+const CMD_PSH: u8 = 2;
+const CMD_NOP: u8 = 3;
+// version 2 only
+const CMD_UPD: u8 = 4;
+
+/// The window smux v2 peers assume before their first update.
+pub(super) const INITIAL_WINDOW: u32 = 256 * 1024;
+
+fn put_header(out: &mut BytesMut, version: u8, cmd: u8, sid: u32, len: usize) {
+    out.reserve(HEADER_LEN + len);
+    out.put_u8(version);
+    out.put_u8(cmd);
+    out.put_u16_le(len as u16);
+    out.put_u32_le(sid);
+}
+
+/// Writes a frame the way xtaci/smux does. smux has no acks, resets or go
+/// aways, a reset is a FIN and a ping a NOP nobody answers.
+pub(super) fn encode(version: u8, frame: &Frame, out: &mut BytesMut) {
+    match frame {
+        Frame::Open(sid) => put_header(out, version, CMD_SYN, *sid, 0),
+        Frame::Data(sid, data) => {
+            put_header(out, version, CMD_PSH, *sid, data.len());
+            out.extend_from_slice(&data[..]);
+        }
+        Frame::Fin(sid) | Frame::Reset(sid) => put_header(out, version, CMD_FIN, *sid, 0),
+        Frame::Ping(..) => put_header(out, version, CMD_NOP, 0, 0),
+        Frame::Consumed(sid, consumed, window) if version >= 2 => {
+            put_header(out, version, CMD_UPD, *sid, UPDATE_LEN);
+            out.put_u32_le(*consumed);
+            out.put_u32_le(*window);
+        }
+        Frame::Ack(_) | Frame::Window(..) | Frame::Consumed(..) | Frame::GoAway => {}
+    }
+}
+
+pub(super) fn decode(version: u8, buf: &mut BytesMut, frames: &mut Vec<Frame>) -> io::Result<bool> {
+    if buf.len() < HEADER_LEN {
+        return Ok(false);
+    }
+    if buf[0] != version {
+        return Err(make_invalid_data_error("invalid smux version"));
+    }
+    let cmd = buf[1];
+    let len = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+    let sid = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+    if buf.len() < HEADER_LEN + len {
+        return Ok(false);
+    }
+    buf.advance(HEADER_LEN);
+    let body = buf.split_to(len).freeze();
+    match cmd {
+        CMD_SYN => frames.push(Frame::Open(sid)),
+        CMD_FIN => frames.push(Frame::Fin(sid)),
+        CMD_PSH => {
+            if !body.is_empty() {
+                frames.push(Frame::Data(sid, body));
+            }
+        }
+        // an ack, so it is not answered
+        CMD_NOP => frames.push(Frame::Ping(0, true)),
+        CMD_UPD if version >= 2 && body.len() >= UPDATE_LEN => {
+            let consumed = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
+            let window = u32::from_le_bytes([body[4], body[5], body[6], body[7]]);
+            frames.push(Frame::Consumed(sid, consumed, window));
+        }
+        _ => return Err(make_invalid_data_error("invalid smux command")),
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_smux_codec() {
+        let mut buf = BytesMut::new();
+        encode(2, &Frame::Open(1), &mut buf);
+        assert_eq!(&buf[..], &[2, 0, 0, 0, 1, 0, 0, 0]);
+        encode(2, &Frame::Data(1, Bytes::from_static(b"hi")), &mut buf);
+        encode(2, &Frame::Consumed(1, 10, 4096), &mut buf);
+        encode(2, &Frame::Ack(1), &mut buf);
+        encode(2, &Frame::Reset(1), &mut buf);
+        let mut frames = Vec::new();
+        while decode(2, &mut buf, &mut frames).unwrap() {}
+        assert!(buf.is_empty());
+        assert_eq!(
+            frames,
+            vec![
+                Frame::Open(1),
+                Frame::Data(1, Bytes::from_static(b"hi")),
+                Frame::Consumed(1, 10, 4096),
+                Frame::Fin(1),
+            ]
+        );
+
+        // v1 has no window updates
+        encode(1, &Frame::Consumed(1, 10, 4096), &mut buf);
+        assert!(buf.is_empty());
+        let mut upd = BytesMut::from(&[1u8, CMD_UPD, 8, 0, 1, 0, 0, 0][..]);
+        upd.extend_from_slice(&[0u8; 8]);
+        assert!(decode(1, &mut upd, &mut frames).is_err());
+        let mut other = BytesMut::from(&[2u8, CMD_NOP, 0, 0, 0, 0, 0, 0][..]);
+        assert!(decode(1, &mut other, &mut frames).is_err());
+    }
+}
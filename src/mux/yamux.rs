@@ -0,0 +1,130 @@
+use super::Frame;
+use crate::utils::make_invalid_data_error;
+
+use bytes::{Buf, BufMut, BytesMut};
+use std::io;
+
+const VERSION: u8 = 0;
+const HEADER_LEN: usize = 12;
+
+const TYPE_DATA: u8 = 0;
+const TYPE_WINDOW_UPDATE: u8 = 1;
+const TYPE_PING: u8 = 2;
+const TYPE_GO_AWAY: u8 = 3;
+
+const FLAG_SYN: u16 = 1;
+const FLAG_ACK: u16 = 2;
+const FLAG_FIN: u16 = 4;
+const FLAG_RST: u16 = 8;
+
+pub(super) const INITIAL_WINDOW: u32 = 256 * 1024;
+
+fn put_header(out: &mut BytesMut, ty: u8, flags: u16, sid: u32, len: u32) {
+    out.reserve(HEADER_LEN);
+    out.put_u8(VERSION);
+    out.put_u8(ty);
+    out.put_u16(flags);
+    out.put_u32(sid);
+    out.put_u32(len);
+}
+
+/// Writes a frame the way hashicorp/yamux does, stream state changes ride
+/// on empty window updates.
+pub(super) fn encode(frame: &Frame, out: &mut BytesMut) {
+    match frame {
+        Frame::Open(sid) => put_header(out, TYPE_WINDOW_UPDATE, FLAG_SYN, *sid, 0),
+        Frame::Ack(sid) => put_header(out, TYPE_WINDOW_UPDATE, FLAG_ACK, *sid, 0),
+        Frame::Data(sid, data) => {
+            put_header(out, TYPE_DATA, 0, *sid, data.len() as u32);
+            out.extend_from_slice(&data[..]);
+        }
+        Frame::Fin(sid) => put_header(out, TYPE_WINDOW_UPDATE, FLAG_FIN, *sid, 0),
+        Frame::Reset(sid) => put_header(out, TYPE_WINDOW_UPDATE, FLAG_RST, *sid, 0),
+        Frame::Window(sid, delta) => put_header(out, TYPE_WINDOW_UPDATE, 0, *sid, *delta),
+        Frame::Ping(opaque, ack) => {
+            let flags = if *ack { FLAG_ACK } else { FLAG_SYN };
+            put_header(out, TYPE_PING, flags, 0, *opaque)
+        }
+        Frame::GoAway => put_header(out, TYPE_GO_AWAY, 0, 0, 0),
+        // smux v2 only
+        Frame::Consumed(..) => {}
+    }
+}
+
+pub(super) fn decode(buf: &mut BytesMut, frames: &mut Vec<Frame>) -> io::Result<bool> {
+    if buf.len() < HEADER_LEN {
+        return Ok(false);
+    }
+    if buf[0] != VERSION {
+        return Err(make_invalid_data_error("invalid yamux version"));
+    }
+    let ty = buf[1];
+    let flags = u16::from_be_bytes([buf[2], buf[3]]);
+    let sid = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+    let len = u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]);
+    let body_len = if ty == TYPE_DATA { len as usize } else { 0 };
+    if buf.len() < HEADER_LEN + body_len {
+        return Ok(false);
+    }
+    buf.advance(HEADER_LEN);
+    let body = buf.split_to(body_len).freeze();
+    match ty {
+        TYPE_DATA | TYPE_WINDOW_UPDATE => {
+            if flags & FLAG_SYN != 0 {
+                frames.push(Frame::Open(sid));
+            }
+            if flags & FLAG_ACK != 0 {
+                frames.push(Frame::Ack(sid));
+            }
+            if ty == TYPE_DATA && !body.is_empty() {
+                frames.push(Frame::Data(sid, body));
+            } else if ty == TYPE_WINDOW_UPDATE && len > 0 {
+                frames.push(Frame::Window(sid, len));
+            }
+            if flags & FLAG_FIN != 0 {
+                frames.push(Frame::Fin(sid));
+            }
+            if flags & FLAG_RST != 0 {
+                frames.push(Frame::Reset(sid));
+            }
+        }
+        TYPE_PING => frames.push(Frame::Ping(len, flags & FLAG_ACK != 0)),
+        TYPE_GO_AWAY => frames.push(Frame::GoAway),
+        _ => return Err(make_invalid_data_error("invalid yamux frame type")),
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_yamux_codec() {
+        let mut buf = BytesMut::new();
+        encode(&Frame::Open(3), &mut buf);
+        assert_eq!(&buf[..], &[0, 1, 0, 1, 0, 0, 0, 3, 0, 0, 0, 0]);
+        encode(&Frame::Data(3, Bytes::from_static(b"hello")), &mut buf);
+        encode(&Frame::Ping(7, false), &mut buf);
+        // a syn carried by a data frame, with a fin
+        buf.extend_from_slice(&[0, 0, 0, 5, 0, 0, 0, 2, 0, 0, 0, 1, b'x']);
+        let mut frames = Vec::new();
+        while decode(&mut buf, &mut frames).unwrap() {}
+        assert!(buf.is_empty());
+        assert_eq!(
+            frames,
+            vec![
+                Frame::Open(3),
+                Frame::Data(3, Bytes::from_static(b"hello")),
+                Frame::Ping(7, false),
+                Frame::Open(2),
+                Frame::Data(2, Bytes::from_static(b"x")),
+                Frame::Fin(2),
+            ]
+        );
+        let mut partial = BytesMut::from(&[0u8, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 4, 1][..]);
+        assert!(!decode(&mut partial, &mut frames).unwrap());
+        assert_eq!(partial.len(), 13);
+    }
+}
@@ -0,0 +1,101 @@
+//! Length bucketing of TLS records. Every write becomes frames of a 2 byte
+//! length, the data and zeros up to a multiple of the bucket, so records,
+//! which carry one write each, are only as telling as the bucket. The
+//! bucket is a power of two, so records split at the 16KB maximum stay in
+//! buckets too.
+use super::{ObfsDecoder, ObfsEncoder};
+use crate::utils::make_io_error;
+use bytes::{Buf, BytesMut};
+use std::io;
+
+const MIN_BUCKET: usize = 16;
+const MAX_RECORD_LEN: usize = 16 * 1024;
+
+/// `bucket` rounded up to a power of two within the record size.
+pub fn normalize_bucket(bucket: usize) -> usize {
+    let bucket = bucket.max(MIN_BUCKET).next_power_of_two();
+    bucket.min(MAX_RECORD_LEN)
+}
+
+fn padded_len(len: usize, bucket: usize) -> usize {
+    (2 + len + bucket - 1) / bucket * bucket
+}
+
+pub struct BucketEncoder {
+    bucket: usize,
+}
+
+impl BucketEncoder {
+    pub fn new(bucket: usize) -> Self {
+        Self { bucket }
+    }
+}
+
+impl ObfsEncoder for BucketEncoder {
+    fn encode(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        for chunk in data.chunks(MAX_RECORD_LEN - 2) {
+            let start = out.len();
+            out.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            out.extend_from_slice(chunk);
+            out.resize(start + padded_len(chunk.len(), self.bucket), 0);
+        }
+    }
+}
+
+pub struct BucketDecoder {
+    bucket: usize,
+}
+
+impl BucketDecoder {
+    pub fn new(bucket: usize) -> Self {
+        Self { bucket }
+    }
+}
+
+impl ObfsDecoder for BucketDecoder {
+    fn decode(&mut self, input: &mut BytesMut, out: &mut BytesMut) -> io::Result<()> {
+        while input.len() >= 2 {
+            let len = u16::from_be_bytes([input[0], input[1]]) as usize;
+            if len > MAX_RECORD_LEN - 2 {
+                return Err(make_io_error("invalid padded record"));
+            }
+            let frame_len = padded_len(len, self.bucket);
+            if input.len() < frame_len {
+                break;
+            }
+            out.extend_from_slice(&input[2..2 + len]);
+            input.advance(frame_len);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        assert_eq!(normalize_bucket(0), 16);
+        assert_eq!(normalize_bucket(100), 128);
+        assert_eq!(normalize_bucket(1 << 20), MAX_RECORD_LEN);
+
+        let mut encoder = BucketEncoder::new(128);
+        let mut decoder = BucketDecoder::new(128);
+        let mut wire = Vec::new();
+        encoder.encode(b"hello", &mut wire);
+        assert_eq!(wire.len(), 128);
+        let data = vec![1u8; 20000];
+        encoder.encode(&data[..], &mut wire);
+        assert_eq!(wire.len() % 128, 0);
+
+        let mut input = BytesMut::from(&wire[..200]);
+        let mut plain = BytesMut::new();
+        decoder.decode(&mut input, &mut plain).unwrap();
+        assert_eq!(&plain[..], b"hello");
+        input.extend_from_slice(&wire[200..]);
+        decoder.decode(&mut input, &mut plain).unwrap();
+        assert_eq!(plain.len(), 5 + data.len());
+        assert!(input.is_empty());
+    }
+}
@@ -0,0 +1,374 @@
+//! HTTP mimicry for networks which let only plain HTTP through. The client
+//! uploads with chunked POST requests and the server answers each with a
+//! chunked 200 response, the session's bytes are the chunks. Bodies end
+//! after a random length and the next request follows on the connection,
+//! the server starts its next response once that request arrived. Nothing
+//! is hidden from a reader of the bodies, the session seals them itself.
+use super::{ObfsDecoder, ObfsEncoder, Obfuscator};
+use crate::config::ObfsConfig;
+use crate::utils::make_io_error;
+use bytes::{Buf, BytesMut};
+use rand::Rng;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const MAX_HEAD_LEN: usize = 8 * 1024;
+const MAX_CHUNK_LEN: usize = 1024 * 1024;
+// a body ends somewhere in this range of bytes
+const MIN_BODY_LEN: usize = 64 * 1024;
+const MAX_BODY_LEN: usize = 1024 * 1024;
+const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+                          (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
+
+fn body_len() -> usize {
+    rand::thread_rng().gen_range(MIN_BODY_LEN, MAX_BODY_LEN + 1)
+}
+
+fn header<'a>(headers: &'a [httparse::Header<'_>], name: &str) -> Option<&'a [u8]> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .map(|h| h.value)
+}
+
+fn is_chunked(headers: &[httparse::Header<'_>]) -> bool {
+    match header(headers, "Transfer-Encoding") {
+        Some(v) => String::from_utf8_lossy(v).eq_ignore_ascii_case("chunked"),
+        None => false,
+    }
+}
+
+fn request_head(host: &str, path: &str) -> Vec<u8> {
+    format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}\r\nAccept: */*\r\n\
+         Content-Type: application/octet-stream\r\nTransfer-Encoding: chunked\r\n\
+         Connection: keep-alive\r\n\r\n",
+        path, host, USER_AGENT
+    )
+    .into_bytes()
+}
+
+fn response_head() -> Vec<u8> {
+    let date = chrono::Utc::now().format("%a, %d %b %Y %H:%M:%S GMT");
+    format!(
+        "HTTP/1.1 200 OK\r\nServer: nginx\r\nDate: {}\r\n\
+         Content-Type: application/octet-stream\r\nTransfer-Encoding: chunked\r\n\
+         Cache-Control: no-store\r\nConnection: keep-alive\r\n\r\n",
+        date
+    )
+    .into_bytes()
+}
+
+/// The length of the request head at the start of `input`, `None` while it
+/// is incomplete. Only chunked POSTs of `path` on `host` are taken.
+fn parse_request(input: &[u8], host: &str, path: &str) -> io::Result<Option<usize>> {
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut req = httparse::Request::new(&mut headers);
+    let n = match req.parse(input) {
+        Ok(httparse::Status::Complete(n)) => n,
+        Ok(httparse::Status::Partial) if input.len() < MAX_HEAD_LEN => return Ok(None),
+        _ => return Err(make_io_error("invalid http obfs request")),
+    };
+    let valid = req.method == Some("POST")
+        && req.path == Some(path)
+        && header(req.headers, "Host").map_or(false, |h| h.eq_ignore_ascii_case(host.as_bytes()))
+        && is_chunked(req.headers);
+    if !valid {
+        return Err(make_io_error("unexpected http obfs request"));
+    }
+    Ok(Some(n))
+}
+
+/// The length of the response head at the start of `input`, `None` while
+/// it is incomplete.
+fn parse_response(input: &[u8]) -> io::Result<Option<usize>> {
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut res = httparse::Response::new(&mut headers);
+    let n = match res.parse(input) {
+        Ok(httparse::Status::Complete(n)) => n,
+        Ok(httparse::Status::Partial) if input.len() < MAX_HEAD_LEN => return Ok(None),
+        _ => return Err(make_io_error("invalid http obfs response")),
+    };
+    if res.code != Some(200) || !is_chunked(res.headers) {
+        return Err(make_io_error("unexpected http obfs response"));
+    }
+    Ok(Some(n))
+}
+
+pub struct HttpMimic {
+    host: String,
+    path: String,
+    pace: Option<Duration>,
+    is_client: bool,
+}
+
+impl HttpMimic {
+    pub fn new(cfg: &ObfsConfig) -> io::Result<Self> {
+        let host = match &cfg.host {
+            Some(h) => h.clone(),
+            None => return Err(make_io_error("http obfs requires a host")),
+        };
+        Ok(Self {
+            host,
+            path: cfg.path.clone().unwrap_or_else(|| String::from("/")),
+            pace: cfg.pace_ms.map(|ms| Duration::from_millis(ms as u64)),
+            is_client: false,
+        })
+    }
+}
+
+impl Obfuscator for HttpMimic {
+    fn client_hello(&mut self) -> Vec<u8> {
+        self.is_client = true;
+        request_head(self.host.as_str(), self.path.as_str())
+    }
+
+    fn client_finish(&mut self, input: &[u8]) -> io::Result<Option<usize>> {
+        parse_response(input)
+    }
+
+    fn server_accept(&mut self, input: &[u8]) -> io::Result<Option<(usize, Vec<u8>)>> {
+        match parse_request(input, self.host.as_str(), self.path.as_str())? {
+            Some(n) => Ok(Some((n, response_head()))),
+            None => Ok(None),
+        }
+    }
+
+    fn into_codecs(self: Box<Self>) -> (Box<dyn ObfsEncoder>, Box<dyn ObfsDecoder>) {
+        // requests the server read and did not answer yet
+        let unanswered = Arc::new(AtomicUsize::new(0));
+        let encoder = ChunkedEncoder {
+            head: if self.is_client {
+                Head::Request(request_head(self.host.as_str(), self.path.as_str()))
+            } else {
+                Head::Response(unanswered.clone())
+            },
+            left: body_len(),
+            pace: self.pace,
+        };
+        let decoder = ChunkedDecoder {
+            head: if self.is_client {
+                None
+            } else {
+                Some((self.host, self.path, unanswered))
+            },
+            state: DecodeState::ChunkSize,
+        };
+        (Box::new(encoder), Box::new(decoder))
+    }
+}
+
+/// What starts the next body of a side.
+enum Head {
+    Request(Vec<u8>),
+    Response(Arc<AtomicUsize>),
+}
+
+struct ChunkedEncoder {
+    head: Head,
+    left: usize,
+    pace: Option<Duration>,
+}
+
+impl ChunkedEncoder {
+    fn next_head(&mut self) -> Option<Vec<u8>> {
+        match &self.head {
+            Head::Request(head) => Some(head.clone()),
+            Head::Response(unanswered) => {
+                let answered = unanswered
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                    .is_ok();
+                if answered {
+                    Some(response_head())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+impl ObfsEncoder for ChunkedEncoder {
+    fn encode(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        for chunk in data.chunks(MAX_CHUNK_LEN) {
+            out.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+            out.extend_from_slice(chunk);
+            out.extend_from_slice(b"\r\n");
+            self.left = self.left.saturating_sub(chunk.len());
+        }
+        if self.left > 0 {
+            return;
+        }
+        // a response only ends when there is a request for the next one
+        if let Some(head) = self.next_head() {
+            out.extend_from_slice(b"0\r\n\r\n");
+            out.extend_from_slice(&head[..]);
+            self.left = body_len();
+        }
+    }
+
+    fn pause(&mut self) -> Option<Duration> {
+        self.pace.map(|max| {
+            let ms = rand::thread_rng().gen_range(0, max.as_millis() as u64 + 1);
+            Duration::from_millis(ms)
+        })
+    }
+}
+
+enum DecodeState {
+    Head,
+    ChunkSize,
+    ChunkData(usize),
+    ChunkEnd,
+    Trailer,
+}
+
+struct ChunkedDecoder {
+    /// host and path requests have to be for and the count of unanswered
+    /// ones, on the server
+    head: Option<(String, String, Arc<AtomicUsize>)>,
+    state: DecodeState,
+}
+
+impl ChunkedDecoder {
+    /// Takes the head of the next message, false while it is incomplete.
+    fn take_head(&mut self, input: &mut BytesMut) -> io::Result<bool> {
+        let n = match &self.head {
+            Some((host, path, _)) => parse_request(&input[..], host.as_str(), path.as_str())?,
+            None => parse_response(&input[..])?,
+        };
+        match n {
+            Some(n) => {
+                input.advance(n);
+                if let Some((_, _, unanswered)) = &self.head {
+                    unanswered.fetch_add(1, Ordering::SeqCst);
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl ObfsDecoder for ChunkedDecoder {
+    fn decode(&mut self, input: &mut BytesMut, out: &mut BytesMut) -> io::Result<()> {
+        loop {
+            match self.state {
+                DecodeState::Head => {
+                    if !self.take_head(input)? {
+                        return Ok(());
+                    }
+                    self.state = DecodeState::ChunkSize;
+                }
+                DecodeState::ChunkSize => {
+                    let line_end = match input.windows(2).position(|w| w == b"\r\n") {
+                        Some(p) => p,
+                        None if input.len() > 16 => {
+                            return Err(make_io_error("invalid http obfs chunk"));
+                        }
+                        None => return Ok(()),
+                    };
+                    let line = String::from_utf8_lossy(&input[..line_end]);
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    let len = match usize::from_str_radix(size, 16) {
+                        Ok(n) if n <= MAX_CHUNK_LEN => n,
+                        _ => return Err(make_io_error("invalid http obfs chunk")),
+                    };
+                    input.advance(line_end + 2);
+                    self.state = if len == 0 {
+                        DecodeState::Trailer
+                    } else {
+                        DecodeState::ChunkData(len)
+                    };
+                }
+                DecodeState::ChunkData(len) => {
+                    if input.is_empty() {
+                        return Ok(());
+                    }
+                    let n = std::cmp::min(len, input.len());
+                    out.extend_from_slice(&input[..n]);
+                    input.advance(n);
+                    self.state = if n == len {
+                        DecodeState::ChunkEnd
+                    } else {
+                        DecodeState::ChunkData(len - n)
+                    };
+                }
+                DecodeState::ChunkEnd | DecodeState::Trailer => {
+                    if input.len() < 2 {
+                        return Ok(());
+                    }
+                    if &input[..2] != b"\r\n" {
+                        return Err(make_io_error("invalid http obfs chunk"));
+                    }
+                    input.advance(2);
+                    self.state = match self.state {
+                        DecodeState::Trailer => DecodeState::Head,
+                        _ => DecodeState::ChunkSize,
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ObfsConfig {
+        ObfsConfig {
+            mode: String::from("http"),
+            host: Some(String::from("example.com")),
+            path: Some(String::from("/upload")),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_http_mimic() {
+        let mut client = Box::new(HttpMimic::new(&config()).unwrap());
+        let mut server = Box::new(HttpMimic::new(&config()).unwrap());
+        let hello = client.client_hello();
+        assert!(hello.starts_with(b"POST /upload HTTP/1.1\r\nHost: example.com\r\n"));
+        assert!(server.server_accept(&hello[..20]).unwrap().is_none());
+        let (n, reply) = server.server_accept(&hello[..]).unwrap().unwrap();
+        assert_eq!(n, hello.len());
+        assert!(reply.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert_eq!(client.client_finish(&reply[..]).unwrap(), Some(reply.len()));
+        let other = request_head("example.com", "/other");
+        assert!(server.server_accept(&other[..]).is_err());
+
+        let (mut client_enc, _) = client.into_codecs();
+        let (mut server_enc, mut server_dec) = server.into_codecs();
+        let mut wire = Vec::new();
+        client_enc.encode(b"hello", &mut wire);
+        assert_eq!(&wire[..], b"5\r\nhello\r\n");
+        // a full body ends the request and starts the next one
+        let data = vec![7u8; MAX_BODY_LEN];
+        client_enc.encode(&data[..], &mut wire);
+        assert!(wire.ends_with(&hello[..]));
+        client_enc.encode(b"world", &mut wire);
+
+        let mut plain = BytesMut::new();
+        let mut input = BytesMut::new();
+        for b in wire.chunks(1000) {
+            input.extend_from_slice(b);
+            server_dec.decode(&mut input, &mut plain).unwrap();
+        }
+        assert!(input.is_empty());
+        assert_eq!(plain.len(), 10 + MAX_BODY_LEN);
+        assert_eq!(&plain[..5], b"hello");
+        assert_eq!(&plain[plain.len() - 5..], b"world");
+
+        // the response ends after a full body as the next request arrived
+        let mut wire = Vec::new();
+        server_enc.encode(&data[..], &mut wire);
+        assert!(wire.windows(17).any(|w| w == b"HTTP/1.1 200 OK\r\n"));
+        let mut wire = Vec::new();
+        server_enc.encode(&data[..], &mut wire);
+        assert!(!wire.windows(17).any(|w| w == b"HTTP/1.1 200 OK\r\n"));
+    }
+}
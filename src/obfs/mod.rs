@@ -0,0 +1,278 @@
+//! Obfuscation between a carrier and the session it carries, so the bytes
+//! on the wire do not give the tunnel away even without TLS. A scheme runs
+//! one round trip of handshake, the client's hello and the server's reply,
+//! and then encodes what each side writes. It only disguises: the session
+//! still seals its frames itself.
+mod bucket;
+mod http;
+mod obfs4;
+
+use crate::config::ObfsConfig;
+use crate::utils::make_io_error;
+use bytes::{Buf, BytesMut};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{delay_for, Delay};
+
+// the largest handshake message a scheme may wait for
+const MAX_HANDSHAKE_LEN: usize = 16 * 1024;
+const MAX_WRITE: usize = 16 * 1024;
+
+/// One handshake of an obfuscation scheme, on either side.
+pub trait Obfuscator: Send {
+    /// The client's hello.
+    fn client_hello(&mut self) -> Vec<u8>;
+    /// Takes the server's reply from the start of `input`, returning how
+    /// many bytes it had; `None` while the reply is incomplete.
+    fn client_finish(&mut self, input: &[u8]) -> io::Result<Option<usize>>;
+    /// Takes a client's hello from the start of `input`, returning how many
+    /// bytes it had and the reply; `None` while the hello is incomplete.
+    fn server_accept(&mut self, input: &[u8]) -> io::Result<Option<(usize, Vec<u8>)>>;
+    /// Codecs of what this side writes and reads after the handshake.
+    fn into_codecs(self: Box<Self>) -> (Box<dyn ObfsEncoder>, Box<dyn ObfsDecoder>);
+}
+
+pub trait ObfsEncoder: Send {
+    /// Appends the wire bytes of `data` to `out`.
+    fn encode(&mut self, data: &[u8], out: &mut Vec<u8>);
+    /// How long the next write waits after this one.
+    fn pause(&mut self) -> Option<Duration> {
+        None
+    }
+}
+
+pub trait ObfsDecoder: Send {
+    /// Moves what the complete units at the start of `input` carry to `out`.
+    fn decode(&mut self, input: &mut BytesMut, out: &mut BytesMut) -> io::Result<()>;
+}
+
+/// The scheme named by the config's `mode`.
+pub fn new_obfuscator(cfg: &ObfsConfig) -> io::Result<Box<dyn Obfuscator>> {
+    match cfg.mode.as_str() {
+        "obfs4" => match &cfg.key {
+            Some(key) => Ok(Box::new(obfs4::Obfs4::new(key.as_str()))),
+            None => Err(make_io_error("obfs4 requires a key")),
+        },
+        "http" => Ok(Box::new(http::HttpMimic::new(cfg)?)),
+        m => Err(make_io_error(&format!("unknown obfs mode:{}", m))),
+    }
+}
+
+/// Runs the client handshake over `reader` and `writer`, which are wrapped
+/// in the codecs after it.
+pub async fn obfs_connect<R, W>(
+    cfg: &ObfsConfig,
+    mut reader: R,
+    mut writer: W,
+) -> io::Result<(ObfsReader<R>, ObfsWriter<W>)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut obfs = new_obfuscator(cfg)?;
+    writer.write_all(&obfs.client_hello()[..]).await?;
+    writer.flush().await?;
+    let mut input = BytesMut::new();
+    loop {
+        read_handshake(&mut reader, &mut input).await?;
+        if let Some(n) = obfs.client_finish(&input[..])? {
+            input.advance(n);
+            break;
+        }
+    }
+    let (encoder, decoder) = obfs.into_codecs();
+    Ok((
+        ObfsReader::new(reader, decoder, input),
+        ObfsWriter::new(writer, encoder),
+    ))
+}
+
+/// The server side of `obfs_connect`.
+pub async fn obfs_accept<R, W>(
+    cfg: &ObfsConfig,
+    mut reader: R,
+    mut writer: W,
+) -> io::Result<(ObfsReader<R>, ObfsWriter<W>)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut obfs = new_obfuscator(cfg)?;
+    let mut input = BytesMut::new();
+    loop {
+        read_handshake(&mut reader, &mut input).await?;
+        if let Some((n, reply)) = obfs.server_accept(&input[..])? {
+            input.advance(n);
+            writer.write_all(&reply[..]).await?;
+            writer.flush().await?;
+            break;
+        }
+    }
+    let (encoder, decoder) = obfs.into_codecs();
+    Ok((
+        ObfsReader::new(reader, decoder, input),
+        ObfsWriter::new(writer, encoder),
+    ))
+}
+
+/// Pads what goes over a TLS stream to multiples of `bucket` bytes, the
+/// peer has to pad with the same.
+pub fn pad_records<R, W>(bucket: usize, reader: R, writer: W) -> (ObfsReader<R>, ObfsWriter<W>) {
+    let bucket = bucket::normalize_bucket(bucket);
+    (
+        ObfsReader::new(
+            reader,
+            Box::new(bucket::BucketDecoder::new(bucket)),
+            BytesMut::new(),
+        ),
+        ObfsWriter::new(writer, Box::new(bucket::BucketEncoder::new(bucket))),
+    )
+}
+
+async fn read_handshake<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    input: &mut BytesMut,
+) -> io::Result<()> {
+    if input.len() >= MAX_HANDSHAKE_LEN {
+        return Err(make_io_error("obfs handshake too long"));
+    }
+    let mut buf = [0u8; 4096];
+    let n = reader.read(&mut buf[..]).await?;
+    if n == 0 {
+        return Err(make_io_error("obfs handshake closed early"));
+    }
+    input.extend_from_slice(&buf[..n]);
+    Ok(())
+}
+
+/// Decodes what the peer's `ObfsWriter` sent.
+pub struct ObfsReader<R> {
+    inner: R,
+    decoder: Box<dyn ObfsDecoder>,
+    raw: BytesMut,
+    plain: BytesMut,
+    undecoded: bool,
+    eof: bool,
+}
+
+impl<R> ObfsReader<R> {
+    /// `raw` are the bytes read past the handshake.
+    pub fn new(inner: R, decoder: Box<dyn ObfsDecoder>, raw: BytesMut) -> Self {
+        Self {
+            inner,
+            decoder,
+            undecoded: !raw.is_empty(),
+            raw,
+            plain: BytesMut::new(),
+            eof: false,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ObfsReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let me = &mut *self;
+        loop {
+            if !me.plain.is_empty() {
+                let n = std::cmp::min(buf.len(), me.plain.len());
+                buf[..n].copy_from_slice(&me.plain[..n]);
+                me.plain.advance(n);
+                return Poll::Ready(Ok(n));
+            }
+            if me.undecoded {
+                me.undecoded = false;
+                me.decoder.decode(&mut me.raw, &mut me.plain)?;
+                continue;
+            }
+            if me.eof {
+                return Poll::Ready(Ok(0));
+            }
+            let mut chunk = [0u8; 8192];
+            let n = ready!(Pin::new(&mut me.inner).poll_read(cx, &mut chunk[..]))?;
+            if n == 0 {
+                me.eof = true;
+                continue;
+            }
+            me.raw.extend_from_slice(&chunk[..n]);
+            me.undecoded = true;
+        }
+    }
+}
+
+/// Encodes every write before it goes to the carrier.
+pub struct ObfsWriter<W> {
+    inner: W,
+    encoder: Box<dyn ObfsEncoder>,
+    pending: Vec<u8>,
+    pos: usize,
+    delay: Option<Delay>,
+}
+
+impl<W> ObfsWriter<W> {
+    pub fn new(inner: W, encoder: Box<dyn ObfsEncoder>) -> Self {
+        Self {
+            inner,
+            encoder,
+            pending: Vec::new(),
+            pos: 0,
+            delay: None,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> ObfsWriter<W> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pos < self.pending.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero)));
+            }
+            self.pos += n;
+        }
+        self.pending.clear();
+        self.pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ObfsWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let me = &mut *self;
+        ready!(me.poll_pending(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if let Some(d) = &mut me.delay {
+            ready!(Pin::new(d).poll(cx));
+            me.delay = None;
+        }
+        let n = std::cmp::min(buf.len(), MAX_WRITE);
+        me.encoder.encode(&buf[..n], &mut me.pending);
+        me.delay = me.encoder.pause().map(delay_for);
+        // the data is encoded, whatever is left goes out with the next call
+        if let Poll::Ready(Err(e)) = me.poll_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,385 @@
+//! An obfs4 style scheme. Both handshake messages are a random seed, random
+//! padding, a mark derived from the seed which ends the padding and a MAC,
+//! so they are random bytes of random length to an observer. The frames
+//! after it are sealed with keys of both seeds, carry padding of lengths
+//! drawn from a per session distribution and have their lengths masked.
+//! Unlike obfs4 the secret is shared and there is no ntor key exchange;
+//! the session inside keeps its own keys anyway.
+use super::{ObfsDecoder, ObfsEncoder, Obfuscator};
+use crate::rmux::ReplayFilter;
+use crate::utils::make_io_error;
+use bytes::{Buf, BytesMut};
+use rand::Rng;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::constant_time::verify_slices_are_equal;
+use ring::hmac;
+use std::io;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SEED_LEN: usize = 32;
+const MARK_LEN: usize = 16;
+const MAC_LEN: usize = 16;
+const MAX_HANDSHAKE_PAD: usize = 4096;
+const TAG_LEN: usize = 16;
+const MAX_FRAME_PAYLOAD: usize = 1400;
+const MAX_FRAME_PAD: usize = 255;
+const MAX_FRAME_LEN: usize = 2 + MAX_FRAME_PAYLOAD + MAX_FRAME_PAD + TAG_LEN;
+// a client hello is valid for the hour it was made in and the ones around it
+const HOUR_SECS: u64 = 3600;
+
+lazy_static! {
+    // apart from the auth stamps, whose window is much shorter
+    static ref SEEN_HELLOS: Mutex<ReplayFilter> = Mutex::new(ReplayFilter::default());
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn epoch_hour() -> u64 {
+    unix_secs() / HOUR_SECS
+}
+
+fn frame_nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[NONCE_LEN - 8..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+fn length_mask(key: &hmac::Key, counter: u64) -> u16 {
+    let tag = hmac::sign(key, &counter.to_be_bytes());
+    u16::from_be_bytes([tag.as_ref()[0], tag.as_ref()[1]])
+}
+
+pub struct Obfs4 {
+    secret: hmac::Key,
+    is_client: bool,
+    client_seed: [u8; SEED_LEN],
+    server_seed: [u8; SEED_LEN],
+}
+
+impl Obfs4 {
+    pub fn new(key: &str) -> Self {
+        Self {
+            secret: hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes()),
+            is_client: false,
+            client_seed: [0u8; SEED_LEN],
+            server_seed: [0u8; SEED_LEN],
+        }
+    }
+
+    fn mac(&self, parts: &[&[u8]]) -> hmac::Tag {
+        let mut ctx = hmac::Context::with_key(&self.secret);
+        for p in parts.iter() {
+            ctx.update(p);
+        }
+        ctx.sign()
+    }
+
+    fn mark(&self, seed: &[u8]) -> Vec<u8> {
+        self.mac(&[b"mark", seed]).as_ref()[..MARK_LEN].to_vec()
+    }
+
+    /// The seed, random padding, the seed's mark and the MAC of them and
+    /// of `bound`.
+    fn hello(&self, seed: &[u8], bound: &[u8]) -> Vec<u8> {
+        let mut rng = rand::thread_rng();
+        let pad_len = rng.gen_range(0, MAX_HANDSHAKE_PAD + 1);
+        let mut msg = seed.to_vec();
+        msg.resize(SEED_LEN + pad_len, 0);
+        rng.fill(&mut msg[SEED_LEN..]);
+        msg.extend_from_slice(&self.mark(seed)[..]);
+        let mac = self.mac(&[&msg[..], bound]);
+        msg.extend_from_slice(&mac.as_ref()[..MAC_LEN]);
+        msg
+    }
+
+    /// The length of the hello at the start of `input` and where its MAC
+    /// starts, `None` while it is incomplete.
+    fn find_hello(&self, input: &[u8]) -> io::Result<Option<(usize, usize)>> {
+        if input.len() < SEED_LEN + MARK_LEN {
+            return Ok(None);
+        }
+        let mark = self.mark(&input[..SEED_LEN]);
+        let limit = std::cmp::min(input.len(), SEED_LEN + MAX_HANDSHAKE_PAD + MARK_LEN);
+        let pos = input[SEED_LEN..limit]
+            .windows(MARK_LEN)
+            .position(|w| w == &mark[..]);
+        let mac_start = match pos {
+            Some(p) => SEED_LEN + p + MARK_LEN,
+            None if limit == SEED_LEN + MAX_HANDSHAKE_PAD + MARK_LEN => {
+                return Err(make_io_error("invalid obfs4 handshake"));
+            }
+            None => return Ok(None),
+        };
+        if input.len() < mac_start + MAC_LEN {
+            return Ok(None);
+        }
+        Ok(Some((mac_start + MAC_LEN, mac_start)))
+    }
+
+    fn mac_matches(&self, msg: &[u8], bound: &[u8], mac: &[u8]) -> bool {
+        let expected = self.mac(&[msg, bound]);
+        verify_slices_are_equal(&expected.as_ref()[..MAC_LEN], mac).is_ok()
+    }
+
+    /// The sealing key and the length mask key of what `sender` writes.
+    fn frame_keys(&self, sender: &[u8]) -> (LessSafeKey, hmac::Key) {
+        let seeds = [&self.client_seed[..], &self.server_seed[..], sender];
+        let key = self.mac(&[seeds[0], seeds[1], seeds[2], b"key"]);
+        let len_key = self.mac(&[seeds[0], seeds[1], seeds[2], b"len"]);
+        // an HMAC-SHA256 tag is exactly a ChaCha20-Poly1305 key
+        let key = UnboundKey::new(&CHACHA20_POLY1305, key.as_ref()).unwrap();
+        (
+            LessSafeKey::new(key),
+            hmac::Key::new(hmac::HMAC_SHA256, len_key.as_ref()),
+        )
+    }
+}
+
+impl Obfuscator for Obfs4 {
+    fn client_hello(&mut self) -> Vec<u8> {
+        self.is_client = true;
+        rand::thread_rng().fill(&mut self.client_seed[..]);
+        self.hello(&self.client_seed[..], &epoch_hour().to_be_bytes())
+    }
+
+    fn client_finish(&mut self, input: &[u8]) -> io::Result<Option<usize>> {
+        let (len, mac_start) = match self.find_hello(input)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let mac = &input[mac_start..len];
+        if !self.mac_matches(&input[..mac_start], &self.client_seed[..], mac) {
+            return Err(make_io_error("invalid obfs4 handshake reply"));
+        }
+        self.server_seed.copy_from_slice(&input[..SEED_LEN]);
+        Ok(Some(len))
+    }
+
+    fn server_accept(&mut self, input: &[u8]) -> io::Result<Option<(usize, Vec<u8>)>> {
+        let (len, mac_start) = match self.find_hello(input)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let mac = &input[mac_start..len];
+        let now = epoch_hour();
+        let hour = (now.saturating_sub(1)..=now + 1)
+            .find(|h| self.mac_matches(&input[..mac_start], &h.to_be_bytes(), mac));
+        let hour = match hour {
+            Some(h) => h,
+            None => return Err(make_io_error("invalid obfs4 handshake")),
+        };
+        // probes replaying a hello get no reply either
+        let mut stamp = [0u8; MAC_LEN];
+        stamp.copy_from_slice(mac);
+        let fresh =
+            SEEN_HELLOS
+                .lock()
+                .unwrap()
+                .check(unix_secs(), 2 * HOUR_SECS, hour * HOUR_SECS, stamp);
+        if !fresh {
+            return Err(make_io_error("replayed obfs4 handshake"));
+        }
+        self.client_seed.copy_from_slice(&input[..SEED_LEN]);
+        rand::thread_rng().fill(&mut self.server_seed[..]);
+        let reply = self.hello(&self.server_seed[..], &self.client_seed[..]);
+        Ok(Some((len, reply)))
+    }
+
+    fn into_codecs(self: Box<Self>) -> (Box<dyn ObfsEncoder>, Box<dyn ObfsDecoder>) {
+        let (ours, theirs): (&[u8], &[u8]) = if self.is_client {
+            (b"client", b"server")
+        } else {
+            (b"server", b"client")
+        };
+        let (key, len_key) = self.frame_keys(ours);
+        let encoder = FrameEncoder {
+            key,
+            len_key,
+            counter: 0,
+            pads: PadDistribution::new(),
+        };
+        let (key, len_key) = self.frame_keys(theirs);
+        let decoder = FrameDecoder {
+            key,
+            len_key,
+            counter: 0,
+            next_len: None,
+        };
+        (Box::new(encoder), Box::new(decoder))
+    }
+}
+
+/// Padding lengths of a session: a few random lengths with random weights,
+/// so sessions differ from each other while each one stays consistent.
+struct PadDistribution {
+    lens: Vec<(usize, u32)>,
+    total: u32,
+}
+
+impl PadDistribution {
+    fn new() -> Self {
+        let mut rng = rand::thread_rng();
+        let n = rng.gen_range(1, 9);
+        let lens: Vec<(usize, u32)> = (0..n)
+            .map(|_| (rng.gen_range(0, MAX_FRAME_PAD + 1), rng.gen_range(1, 101)))
+            .collect();
+        let total = lens.iter().map(|(_, w)| w).sum();
+        Self { lens, total }
+    }
+
+    fn sample(&self) -> usize {
+        let mut r = rand::thread_rng().gen_range(0, self.total);
+        for (len, weight) in self.lens.iter() {
+            if r < *weight {
+                return *len;
+            }
+            r -= weight;
+        }
+        0
+    }
+}
+
+/// Frames of a masked 2 byte length and the sealed payload length, payload
+/// and padding.
+struct FrameEncoder {
+    key: LessSafeKey,
+    len_key: hmac::Key,
+    counter: u64,
+    pads: PadDistribution,
+}
+
+impl ObfsEncoder for FrameEncoder {
+    fn encode(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        for chunk in data.chunks(MAX_FRAME_PAYLOAD) {
+            let pad = self.pads.sample();
+            let mut frame = Vec::with_capacity(2 + chunk.len() + pad + TAG_LEN);
+            frame.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            frame.extend_from_slice(chunk);
+            frame.resize(2 + chunk.len() + pad, 0);
+            self.key
+                .seal_in_place_append_tag(frame_nonce(self.counter), Aad::empty(), &mut frame)
+                .unwrap();
+            let len = frame.len() as u16 ^ length_mask(&self.len_key, self.counter);
+            out.extend_from_slice(&len.to_be_bytes());
+            out.extend_from_slice(&frame[..]);
+            self.counter += 1;
+        }
+    }
+}
+
+struct FrameDecoder {
+    key: LessSafeKey,
+    len_key: hmac::Key,
+    counter: u64,
+    next_len: Option<usize>,
+}
+
+impl ObfsDecoder for FrameDecoder {
+    fn decode(&mut self, input: &mut BytesMut, out: &mut BytesMut) -> io::Result<()> {
+        loop {
+            let len = match self.next_len {
+                Some(len) => len,
+                None => {
+                    if input.len() < 2 {
+                        return Ok(());
+                    }
+                    let masked = u16::from_be_bytes([input[0], input[1]]);
+                    let len = (masked ^ length_mask(&self.len_key, self.counter)) as usize;
+                    if len < 2 + TAG_LEN || len > MAX_FRAME_LEN {
+                        return Err(make_io_error("invalid obfs4 frame length"));
+                    }
+                    input.advance(2);
+                    self.next_len = Some(len);
+                    len
+                }
+            };
+            if input.len() < len {
+                return Ok(());
+            }
+            let mut frame = input.split_to(len);
+            let plain = match self.key.open_in_place(
+                frame_nonce(self.counter),
+                Aad::empty(),
+                &mut frame[..],
+            ) {
+                Ok(p) => p,
+                Err(_) => return Err(make_io_error("invalid obfs4 frame")),
+            };
+            let n = u16::from_be_bytes([plain[0], plain[1]]) as usize;
+            if n > plain.len() - 2 {
+                return Err(make_io_error("invalid obfs4 frame payload"));
+            }
+            out.extend_from_slice(&plain[2..2 + n]);
+            self.counter += 1;
+            self.next_len = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_obfs4() {
+        let mut client = Box::new(Obfs4::new("secret"));
+        let mut server = Box::new(Obfs4::new("secret"));
+        let mut hello = client.client_hello();
+        assert!(hello.len() >= SEED_LEN + MARK_LEN + MAC_LEN);
+        // incomplete, then with the first frame right behind it
+        assert!(server
+            .server_accept(&hello[..hello.len() - 1])
+            .unwrap()
+            .is_none());
+        hello.extend_from_slice(b"next");
+        let (n, mut reply) = server.server_accept(&hello[..]).unwrap().unwrap();
+        assert_eq!(n, hello.len() - 4);
+        // the same hello again is a replay
+        let mut again = Box::new(Obfs4::new("secret"));
+        assert!(again.server_accept(&hello[..]).is_err());
+        let mut other = Box::new(Obfs4::new("other"));
+        other.client_hello();
+        // without the key the mark is just more padding
+        assert!(other.client_finish(&reply[..]).unwrap().is_none());
+
+        reply.extend_from_slice(b"x");
+        assert_eq!(
+            client.client_finish(&reply[..]).unwrap(),
+            Some(reply.len() - 1)
+        );
+        let (mut client_enc, mut client_dec) = client.into_codecs();
+        let (mut server_enc, mut server_dec) = server.into_codecs();
+
+        let data: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        let mut wire = Vec::new();
+        client_enc.encode(&data[..], &mut wire);
+        client_enc.encode(b"tail", &mut wire);
+        assert!(wire.len() > data.len() + 4);
+        let mut input = BytesMut::from(&wire[..wire.len() - 3]);
+        let mut plain = BytesMut::new();
+        server_dec.decode(&mut input, &mut plain).unwrap();
+        assert_eq!(&plain[..], &data[..]);
+        input.extend_from_slice(&wire[wire.len() - 3..]);
+        server_dec.decode(&mut input, &mut plain).unwrap();
+        assert_eq!(&plain[data.len()..], b"tail");
+        assert!(input.is_empty());
+
+        let mut wire = Vec::new();
+        server_enc.encode(b"pong", &mut wire);
+        let mut plain = BytesMut::new();
+        client_dec
+            .decode(&mut BytesMut::from(&wire[..]), &mut plain)
+            .unwrap();
+        assert_eq!(&plain[..], b"pong");
+        // frames do not decode in the other direction
+        assert!(server_dec
+            .decode(&mut BytesMut::from(&wire[..]), &mut BytesMut::new())
+            .is_err());
+    }
+}
@@ -0,0 +1,380 @@
+//! Compression of stream data, agreed in the handshake: each side appends
+//! a mask of the algorithms it takes after its stream window, the client
+//! what it wants to use, the listener what it accepted of that. A session
+//! which agreed on one may send `FLAG_COMPRESSED_DATA` events, whose body is
+//! the algorithm, the length of the data and the compressed data.
+//!
+//! LZ4 blocks and zstd frames are implemented in tree, in their reference
+//! formats.
+use super::event::{new_data_event, Event, FLAG_COMPRESSED_DATA};
+use super::zstd::{zstd_compress, zstd_decompress};
+use crate::utils::make_io_error;
+
+use std::io;
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+// frames which do not shrink in a row before a stream stops trying
+const MAX_MISSES: u32 = 8;
+// below this the header costs more than compression saves
+const MIN_COMPRESS_LEN: usize = 64;
+// the largest body an event header can describe
+const MAX_RAW_LEN: usize = (1 << 24) - 1;
+
+/// Compression algorithm of a session's streams.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None = 0,
+    Lz4 = 1,
+    Zstd = 2,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+impl Compression {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Compression::None),
+            "lz4" => Some(Compression::Lz4),
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+    /// The bit of the algorithm in the mask advertised in the handshake.
+    pub fn mask(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+    /// This algorithm if the peer's `mask` has it too, `None` otherwise.
+    pub fn negotiate(self, mask: u8) -> Self {
+        if mask & self.mask() != 0 {
+            self
+        } else {
+            Compression::None
+        }
+    }
+    /// What a listener accepts of a client's `mask`, zstd before LZ4.
+    pub fn accept(mask: u8) -> Self {
+        match Compression::Zstd.negotiate(mask) {
+            Compression::None => Compression::Lz4.negotiate(mask),
+            c => c,
+        }
+    }
+}
+
+// a TLS record or the magic of a compressed format, which won't shrink
+fn looks_compressed(data: &[u8]) -> bool {
+    const MAGICS: [&[u8]; 9] = [
+        b"\x1f\x8b",
+        b"\x28\xb5\x2f\xfd",
+        b"\x04\x22\x4d\x18",
+        b"PK\x03\x04",
+        b"\x89PNG",
+        b"\xff\xd8\xff",
+        b"\xfd7zXZ\x00",
+        b"BZh",
+        b"GIF8",
+    ];
+    if data.len() >= 3 && (0x14..=0x17).contains(&data[0]) && data[1] == 3 {
+        return true;
+    }
+    MAGICS.iter().any(|m| data.starts_with(m))
+}
+
+/// Decides per stream whether its frames are compressed: from the first
+/// write unless that one looks compressed already, until `MAX_MISSES`
+/// frames in a row did not shrink, like once a CONNECT turned into TLS.
+pub(super) struct StreamCompressor {
+    compression: Compression,
+    // 0 undecided, 1 compressing, 2 off
+    state: AtomicU8,
+    misses: AtomicU32,
+    pub compressed_frames: AtomicU32,
+}
+
+const UNDECIDED: u8 = 0;
+const COMPRESSING: u8 = 1;
+const OFF: u8 = 2;
+
+impl StreamCompressor {
+    pub fn new(compression: Compression) -> Self {
+        let state = if compression == Compression::None {
+            OFF
+        } else {
+            UNDECIDED
+        };
+        Self {
+            compression,
+            state: AtomicU8::new(state),
+            misses: AtomicU32::new(0),
+            compressed_frames: AtomicU32::new(0),
+        }
+    }
+
+    /// The event carrying `data` written to stream `sid`.
+    pub fn data_event(&self, sid: u32, data: &[u8]) -> Event {
+        let mut state = self.state.load(Ordering::SeqCst);
+        if state == UNDECIDED {
+            state = if looks_compressed(data) {
+                OFF
+            } else {
+                COMPRESSING
+            };
+            self.state.store(state, Ordering::SeqCst);
+        }
+        if state != COMPRESSING || data.len() < MIN_COMPRESS_LEN {
+            return new_data_event(sid, data, false);
+        }
+        match compress_body(self.compression, data) {
+            Some(body) => {
+                self.misses.store(0, Ordering::SeqCst);
+                self.compressed_frames.fetch_add(1, Ordering::SeqCst);
+                let mut ev = new_data_event(sid, &body[..], false);
+                ev.header.set_flag(FLAG_COMPRESSED_DATA);
+                ev
+            }
+            None => {
+                if self.misses.fetch_add(1, Ordering::SeqCst) + 1 >= MAX_MISSES {
+                    self.state.store(OFF, Ordering::SeqCst);
+                }
+                new_data_event(sid, data, false)
+            }
+        }
+    }
+}
+
+// the body of a compressed event, `None` unless it saves an eighth
+fn compress_body(compression: Compression, data: &[u8]) -> Option<Vec<u8>> {
+    if compression == Compression::None || data.len() > MAX_RAW_LEN {
+        return None;
+    }
+    let mut body = Vec::with_capacity(data.len());
+    body.push(compression as u8);
+    body.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    match compression {
+        Compression::Zstd => zstd_compress(data, &mut body),
+        _ => lz4_compress(data, &mut body),
+    }
+    if body.len() > data.len() - data.len() / 8 {
+        return None;
+    }
+    Some(body)
+}
+
+/// The data of a `FLAG_COMPRESSED_DATA` body.
+pub(super) fn decompress_body(body: &[u8]) -> io::Result<Vec<u8>> {
+    if body.len() < 5 {
+        return Err(make_io_error("invalid compressed data"));
+    }
+    let mut n = [0u8; 4];
+    n.copy_from_slice(&body[1..5]);
+    let raw_len = u32::from_be_bytes(n) as usize;
+    if raw_len > MAX_RAW_LEN {
+        return Err(make_io_error("compressed data too large"));
+    }
+    let data = match body[0] {
+        1 => lz4_decompress(&body[5..], raw_len),
+        2 => zstd_decompress(&body[5..], raw_len).filter(|data| data.len() == raw_len),
+        _ => return Err(make_io_error("invalid compressed data")),
+    };
+    data.ok_or_else(|| make_io_error("corrupted compressed data"))
+}
+
+const MIN_MATCH: usize = 4;
+// a block ends with literals, the last match starts before MF_LIMIT
+const LAST_LITERALS: usize = 5;
+const MF_LIMIT: usize = 12;
+const HASH_LOG: u32 = 12;
+const MAX_OFFSET: usize = 65535;
+
+fn read_u32(src: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([src[i], src[i + 1], src[i + 2], src[i + 3]])
+}
+
+fn hash(v: u32) -> usize {
+    (v.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+fn push_len(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 255 {
+        out.push(255);
+        n -= 255;
+    }
+    out.push(n as u8);
+}
+
+fn push_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let lit_len = literals.len();
+    let match_len = matched.map(|(_, len)| len - MIN_MATCH).unwrap_or(0);
+    let token = (std::cmp::min(lit_len, 15) << 4) | std::cmp::min(match_len, 15);
+    out.push(token as u8);
+    if lit_len >= 15 {
+        push_len(out, lit_len - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_len >= 15 {
+            push_len(out, match_len - 15);
+        }
+    }
+}
+
+/// Appends `src` as an LZ4 block to `out`, greedy matching of 4 byte hashes.
+fn lz4_compress(src: &[u8], out: &mut Vec<u8>) {
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut i = 0;
+    if src.len() > MF_LIMIT {
+        let match_end = src.len() - LAST_LITERALS;
+        while i < src.len() - MF_LIMIT {
+            let v = read_u32(src, i);
+            let h = hash(v);
+            // positions are stored plus one, zero is an empty slot
+            let candidate = table[h];
+            table[h] = i + 1;
+            if candidate > 0 && i - (candidate - 1) <= MAX_OFFSET {
+                let c = candidate - 1;
+                if read_u32(src, c) == v {
+                    let mut len = MIN_MATCH;
+                    while i + len < match_end && src[c + len] == src[i + len] {
+                        len += 1;
+                    }
+                    push_sequence(out, &src[anchor..i], Some((i - c, len)));
+                    i += len;
+                    anchor = i;
+                    continue;
+                }
+            }
+            i += 1;
+        }
+    }
+    push_sequence(out, &src[anchor..], None);
+}
+
+fn read_len(src: &[u8], i: &mut usize) -> Option<usize> {
+    let mut n = 0;
+    loop {
+        let b = *src.get(*i)?;
+        *i += 1;
+        n += b as usize;
+        if b != 255 {
+            return Some(n);
+        }
+    }
+}
+
+/// Decodes an LZ4 block which has to yield exactly `raw_len` bytes.
+fn lz4_decompress(src: &[u8], raw_len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(raw_len);
+    let mut i = 0;
+    loop {
+        let token = *src.get(i)?;
+        i += 1;
+        let mut lit_len = (token >> 4) as usize;
+        if lit_len == 15 {
+            lit_len += read_len(src, &mut i)?;
+        }
+        if out.len() + lit_len > raw_len {
+            return None;
+        }
+        out.extend_from_slice(src.get(i..i + lit_len)?);
+        i += lit_len;
+        if i == src.len() {
+            break;
+        }
+        let offset = u16::from_le_bytes([*src.get(i)?, *src.get(i + 1)?]) as usize;
+        i += 2;
+        if offset == 0 || offset > out.len() {
+            return None;
+        }
+        let mut match_len = (token & 15) as usize;
+        if match_len == 15 {
+            match_len += read_len(src, &mut i)?;
+        }
+        match_len += MIN_MATCH;
+        if out.len() + match_len > raw_len {
+            return None;
+        }
+        // byte by byte, a match may overlap what it copies
+        let start = out.len() - offset;
+        for k in 0..match_len {
+            let b = out[start + k];
+            out.push(b);
+        }
+    }
+    if out.len() != raw_len {
+        return None;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lz4_roundtrip() {
+        let text = b"GET / HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n".repeat(20);
+        let mut noise = Vec::new();
+        let mut x: u32 = 7;
+        for _ in 0..1000 {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            noise.push((x >> 16) as u8);
+        }
+        for src in [&text[..], &noise[..], b"", b"abc", &[0u8; 300][..]].iter() {
+            let mut block = Vec::new();
+            lz4_compress(src, &mut block);
+            assert_eq!(lz4_decompress(&block[..], src.len()).unwrap(), src.to_vec());
+        }
+        let mut block = Vec::new();
+        lz4_compress(&text[..], &mut block);
+        assert!(block.len() < text.len() / 4);
+        assert!(lz4_decompress(&block[..], text.len() - 1).is_none());
+        assert!(lz4_decompress(&block[..block.len() - 3], text.len()).is_none());
+        // a match reaching back before the start
+        assert!(lz4_decompress(&[0x10, b'a', 2, 0], 5).is_none());
+    }
+
+    #[test]
+    fn test_stream_compressor() {
+        assert_eq!(Compression::Lz4.negotiate(3), Compression::Lz4);
+        assert_eq!(Compression::Lz4.negotiate(0), Compression::None);
+        assert_eq!(Compression::accept(3), Compression::Zstd);
+        assert_eq!(Compression::accept(1), Compression::Lz4);
+        assert_eq!(Compression::accept(4), Compression::None);
+        let data = b"0123456789abcdef".repeat(16);
+        let c = StreamCompressor::new(Compression::Lz4);
+        let ev = c.data_event(3, &data[..]);
+        assert_eq!(ev.header.flags(), FLAG_COMPRESSED_DATA);
+        assert_eq!(decompress_body(&ev.body[..]).unwrap(), data);
+        let zstd = StreamCompressor::new(Compression::Zstd);
+        let ev = zstd.data_event(3, &data[..]);
+        assert_eq!(ev.header.flags(), FLAG_COMPRESSED_DATA);
+        assert_eq!(ev.body[0], Compression::Zstd as u8);
+        assert_eq!(decompress_body(&ev.body[..]).unwrap(), data);
+        // a stream starting with a TLS record is left alone
+        let tls = StreamCompressor::new(Compression::Lz4);
+        let mut hello = vec![0x16, 3, 1];
+        hello.extend_from_slice(&data[..]);
+        assert_ne!(
+            tls.data_event(3, &hello[..]).header.flags(),
+            FLAG_COMPRESSED_DATA
+        );
+        assert_ne!(
+            tls.data_event(3, &data[..]).header.flags(),
+            FLAG_COMPRESSED_DATA
+        );
+        let off = StreamCompressor::new(Compression::None);
+        assert_ne!(
+            off.data_event(3, &data[..]).header.flags(),
+            FLAG_COMPRESSED_DATA
+        );
+    }
+}
@@ -1,13 +1,146 @@
 use bytes::{Buf, BufMut, BytesMut};
 //use tokio::io::read_exact;
 use super::event::*;
-use crate::utils::make_io_error;
+use crate::utils::zeroize;
 use ring::aead::*;
+use ring::hkdf;
+use std::time::{Duration, Instant};
 use tokio::prelude::*;
 
 pub const METHOD_AES128_GCM: &str = "aes128gcm";
+pub const METHOD_AES256_GCM: &str = "aes256gcm";
 pub const METHOD_CHACHA20_POLY1305: &str = "chacha20poly1305";
 pub const METHOD_NONE: &str = "none";
+/// In a list of cipher suites, the AEAD ones in the order this CPU runs
+/// them fastest.
+pub const CIPHER_SUITES_AUTO: &str = "auto";
+
+// the AEAD methods a session may negotiate, by the id a client offers them
+// with after its auth stamp
+const CIPHER_SUITES: [(u8, &str); 3] = [
+    (1, METHOD_AES128_GCM),
+    (2, METHOD_CHACHA20_POLY1305),
+    (3, METHOD_AES256_GCM),
+];
+
+/// Whether the CPU has AES instructions, AES-GCM then outruns
+/// ChaCha20-Poly1305.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn has_aes_hardware() -> bool {
+    is_x86_feature_detected!("aes") && is_x86_feature_detected!("pclmulqdq")
+}
+
+#[cfg(target_arch = "aarch64")]
+fn has_aes_hardware() -> bool {
+    std::arch::is_aarch64_feature_detected!("aes")
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn has_aes_hardware() -> bool {
+    false
+}
+
+/// The AEAD suites, AES-GCM first on hardware with AES instructions and
+/// ChaCha20-Poly1305 first elsewhere.
+pub fn preferred_cipher_suites() -> Vec<&'static str> {
+    if has_aes_hardware() {
+        vec![
+            METHOD_AES256_GCM,
+            METHOD_AES128_GCM,
+            METHOD_CHACHA20_POLY1305,
+        ]
+    } else {
+        vec![
+            METHOD_CHACHA20_POLY1305,
+            METHOD_AES256_GCM,
+            METHOD_AES128_GCM,
+        ]
+    }
+}
+
+/// The id of an AEAD suite in the list a client offers.
+pub fn cipher_suite_id(method: &str) -> Option<u8> {
+    CIPHER_SUITES
+        .iter()
+        .find(|(_, m)| *m == method)
+        .map(|(id, _)| *id)
+}
+
+/// The method of a suite id a client offered.
+pub fn cipher_suite_method(id: u8) -> Option<&'static str> {
+    CIPHER_SUITES
+        .iter()
+        .find(|(i, _)| *i == id)
+        .map(|(_, m)| *m)
+}
+
+/// Whether `CryptoContext::new` takes `method`.
+pub fn is_supported_method(method: &str) -> bool {
+    method == METHOD_NONE || cipher_suite_id(method).is_some()
+}
+
+/// The suites of a configured list, with `CIPHER_SUITES_AUTO` expanded;
+/// unknown names are left out.
+pub fn expand_cipher_suites(names: &[String]) -> Vec<&'static str> {
+    let mut suites = Vec::new();
+    for name in names.iter() {
+        let expanded = if name.as_str() == CIPHER_SUITES_AUTO {
+            preferred_cipher_suites()
+        } else {
+            match CIPHER_SUITES.iter().find(|(_, m)| *m == name.as_str()) {
+                Some((_, m)) => vec![*m],
+                None => {
+                    error!("Unsupported cipher suite:{}", name);
+                    Vec::new()
+                }
+            }
+        };
+        for m in expanded {
+            if !suites.contains(&m) {
+                suites.push(m);
+            }
+        }
+    }
+    suites
+}
+
+const REKEY_INFO: &[u8] = b"rmux rekey";
+
+/// When a session rotates the key of the frames it sends, after an interval
+/// or a volume of sealed bytes, whichever comes first; never by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RekeyPolicy {
+    pub interval: Option<Duration>,
+    pub bytes: Option<u64>,
+}
+
+impl RekeyPolicy {
+    pub fn new(interval_mins: Option<u64>, after_mb: Option<u64>) -> Self {
+        Self {
+            interval: interval_mins
+                .filter(|m| *m > 0)
+                .map(|m| Duration::from_secs(m * 60)),
+            bytes: after_mb.filter(|mb| *mb > 0).map(|mb| mb * 1024 * 1024),
+        }
+    }
+
+    /// The policy rotating keys before they sealed `key_bytes`.
+    pub fn within(self, key_bytes: Option<u64>) -> Self {
+        let bytes = match (self.bytes, key_bytes) {
+            (Some(b), Some(limit)) => Some(b.min(limit)),
+            (b, limit) => b.or(limit),
+        };
+        Self { bytes, ..self }
+    }
+}
+
+struct KeyLen(usize);
+
+impl hkdf::KeyType for KeyLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
 
 struct CryptoNonceSequence {
     nonce: u64,
@@ -30,10 +163,19 @@ impl NonceSequence for CryptoNonceSequence {
 }
 
 pub struct CryptoContext {
-    pub key: String,
     pub nonce: u64,
+    // the key the cipher and the header obfuscation use, the session key
+    // until the first rekey
+    key_bytes: Vec<u8>,
+    algorithm: Option<&'static Algorithm>,
     sealing_key: Option<SealingKey<CryptoNonceSequence>>,
     opening_key: Option<OpeningKey<CryptoNonceSequence>>,
+    rekey: RekeyPolicy,
+    rekeyed_at: Instant,
+    sealed_bytes: u64,
+    // no key seals or opens frames past this many bytes
+    key_limit: Option<u64>,
+    opened_bytes: u64,
 }
 
 type DecryptError = (u32, &'static str);
@@ -48,47 +190,116 @@ fn make_key<K: BoundKey<CryptoNonceSequence>>(
     K::new(key, nonce_sequence)
 }
 
+impl Drop for CryptoContext {
+    fn drop(&mut self) {
+        zeroize(&mut self.key_bytes[..]);
+    }
+}
+
 impl CryptoContext {
     pub fn new(method: &str, k: &str, nonce: u64) -> Self {
-        let mut key = String::from(k);
-        while key.len() < 32 {
-            key.push('F');
-        }
-        let aes_key = key.clone();
-        match method {
-            METHOD_CHACHA20_POLY1305 => CryptoContext {
-                nonce,
-                sealing_key: Some(make_key(
-                    &CHACHA20_POLY1305,
-                    &aes_key.as_bytes()[0..32],
-                    nonce,
-                )),
-                opening_key: Some(make_key(
-                    &CHACHA20_POLY1305,
-                    &aes_key.as_bytes()[0..32],
-                    nonce,
-                )),
-                key,
-            },
-            METHOD_NONE => CryptoContext {
-                key,
-                nonce,
-                sealing_key: None,
-                opening_key: None,
-            },
-            METHOD_AES128_GCM => CryptoContext {
-                key,
-                nonce,
-                sealing_key: Some(make_key(&AES_128_GCM, &aes_key.as_bytes()[0..16], nonce)),
-                opening_key: Some(make_key(&AES_128_GCM, &aes_key.as_bytes()[0..16], nonce)),
-            },
+        let mut key = Vec::from(k.as_bytes());
+        key.resize(std::cmp::max(key.len(), 32), b'F');
+        Self::with_key(method, &key[..], nonce)
+    }
+
+    /// A context sealing with the first 32 bytes of `key`, see
+    /// `session_key`.
+    pub fn with_key(method: &str, key: &[u8], nonce: u64) -> Self {
+        let algorithm: Option<&'static Algorithm> = match method {
+            METHOD_CHACHA20_POLY1305 => Some(&CHACHA20_POLY1305),
+            METHOD_NONE => None,
+            METHOD_AES128_GCM => Some(&AES_128_GCM),
+            METHOD_AES256_GCM => Some(&AES_256_GCM),
             _ => panic!("not supported crypto method."),
+        };
+        let mut ctx = CryptoContext {
+            key_bytes: Vec::from(&key[0..32]),
+            nonce,
+            algorithm,
+            sealing_key: None,
+            opening_key: None,
+            rekey: RekeyPolicy::default(),
+            rekeyed_at: Instant::now(),
+            sealed_bytes: 0,
+            key_limit: None,
+            opened_bytes: 0,
+        };
+        ctx.make_keys();
+        ctx
+    }
+
+    fn make_keys(&mut self) {
+        if let Some(algorithm) = self.algorithm {
+            let key = &self.key_bytes[0..algorithm.key_len()];
+            self.sealing_key = Some(make_key(algorithm, key, self.nonce));
+            self.opening_key = Some(make_key(algorithm, key, self.nonce));
         }
     }
 
+    /// Rotates the keys of the sending side by `policy`, see `rekey_due`.
+    pub fn set_rekey(&mut self, policy: RekeyPolicy) {
+        self.rekey = policy;
+    }
+
+    /// Holds every key to `bytes` of frames, see `key_exhausted` and
+    /// `count_opened`.
+    pub fn set_key_limit(&mut self, bytes: Option<u64>) {
+        self.key_limit = bytes;
+    }
+
+    /// Whether the current key sealed as many bytes as it may, no frame
+    /// is sent with it after.
+    pub(super) fn key_exhausted(&self) -> bool {
+        self.algorithm.is_some() && self.key_limit.map_or(false, |n| self.sealed_bytes >= n)
+    }
+
+    /// Counts a frame of `len` bytes the peer sealed, false if the current
+    /// key had opened as many bytes as it may before it.
+    pub(super) fn count_opened(&mut self, len: usize) -> bool {
+        if self.algorithm.is_none() {
+            return true;
+        }
+        let within = self.key_limit.map_or(true, |n| self.opened_bytes < n);
+        self.opened_bytes += len as u64;
+        within
+    }
+
+    /// Whether the frames sealed since the last rekey reached the policy's
+    /// interval or volume.
+    pub(super) fn rekey_due(&self) -> bool {
+        if self.algorithm.is_none() {
+            return false;
+        }
+        let by_time = self
+            .rekey
+            .interval
+            .map_or(false, |d| self.rekeyed_at.elapsed() >= d);
+        let by_bytes = self.rekey.bytes.map_or(false, |n| self.sealed_bytes >= n);
+        by_time || by_bytes
+    }
+
+    /// Derives the next key from the current one and the `salt` of a
+    /// `FLAG_REKEY` frame, both sides do so right after that frame.
+    pub(super) fn rekey(&mut self, salt: &[u8]) {
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(&self.key_bytes[..]);
+        let mut next = vec![0u8; self.key_bytes.len()];
+        let info = [REKEY_INFO];
+        if let Ok(okm) = prk.expand(&info, KeyLen(next.len())) {
+            if okm.fill(&mut next[..]).is_ok() {
+                zeroize(&mut self.key_bytes[..]);
+                self.key_bytes = next;
+            }
+        }
+        self.make_keys();
+        self.rekeyed_at = Instant::now();
+        self.sealed_bytes = 0;
+        self.opened_bytes = 0;
+    }
+
     fn skip32_decrypt_key(&self) -> [u8; 10] {
         let mut sk: [u8; 10] = Default::default();
-        sk[0..10].copy_from_slice(&self.key.as_bytes()[0..10]);
+        sk[0..10].copy_from_slice(&self.key_bytes[0..10]);
         let dk = self.nonce.to_le_bytes();
         for i in 2..10 {
             sk[i] |= dk[i - 2];
@@ -98,16 +309,24 @@ impl CryptoContext {
     fn skip32_encrypt_key(&self) -> [u8; 10] {
         //let mut key = [0; 32];
         let mut sk: [u8; 10] = Default::default();
-        sk[0..10].copy_from_slice(&self.key.as_bytes()[0..10]);
+        sk[0..10].copy_from_slice(&self.key_bytes[0..10]);
         let dk = self.nonce.to_le_bytes();
         for i in 2..10 {
             sk[i] |= dk[i - 2];
         }
         sk
     }
-    pub fn encrypt(&mut self, ev: &mut Event, out: &mut BytesMut) {
+    /// Bytes the cipher adds to a non empty body.
+    pub fn tag_len(&self) -> usize {
+        match self.sealing_key {
+            Some(_) => MAX_TAG_LEN,
+            None => 0,
+        }
+    }
+    /// Appends the sealed frame of `ev` to `out`, a `BytesMut` gathering
+    /// frames or a `Vec` of its own.
+    pub fn encrypt<B: BufMut>(&mut self, ev: &mut Event, out: &mut B) {
         if self.sealing_key.is_none() {
-            out.reserve(EVENT_HEADER_LEN + ev.body.len());
             out.put_u32_le(ev.header.flag_len);
             out.put_u32_le(ev.header.stream_id);
             if !ev.body.is_empty() {
@@ -117,7 +336,6 @@ impl CryptoContext {
             let sk = self.skip32_encrypt_key();
             let e1 = skip32::encode(&sk, ev.header.flag_len);
             let e2 = skip32::encode(&sk, ev.header.stream_id);
-            out.reserve(EVENT_HEADER_LEN);
             out.put_u32_le(e1);
             out.put_u32_le(e2);
             if !ev.body.is_empty() {
@@ -129,13 +347,14 @@ impl CryptoContext {
                 {
                     Ok(_) => {}
                     Err(e) => {
-                        error!("encrypt error:{} {}", e, out.len());
+                        error!("encrypt error:{} {}", e, ev.body.len());
                     }
                 }
             }
             out.put_slice(&ev.body[..]);
             //warn!("[{}]send bytes {}", self.nonce, out.len());
             self.nonce += 1;
+            self.sealed_bytes += ev.body.len() as u64;
         }
         //self.nonce += 1;
     }
@@ -545,4 +764,30 @@ mod tests {
         assert_eq!(buf.len(), 0);
         assert_eq!(str::from_utf8(&r.body[..]).unwrap(), s);
     }
+
+    #[test]
+    fn test_key_limit() {
+        let policy = RekeyPolicy::new(None, Some(64)).within(Some(1024));
+        assert_eq!(policy.bytes, Some(1024));
+        assert_eq!(RekeyPolicy::default().within(None).bytes, None);
+
+        let key = [7u8; 32];
+        let mut wctx = CryptoContext::with_key(METHOD_CHACHA20_POLY1305, &key[..], 1);
+        let mut rctx = CryptoContext::with_key(METHOD_CHACHA20_POLY1305, &key[..], 1);
+        wctx.set_key_limit(Some(100));
+        rctx.set_key_limit(Some(100));
+        let mut buf = BytesMut::new();
+        wctx.encrypt(&mut new_data_event(1, &[0u8; 60], false), &mut buf);
+        assert!(!wctx.key_exhausted());
+        wctx.encrypt(&mut new_data_event(1, &[0u8; 60], false), &mut buf);
+        assert!(wctx.key_exhausted());
+        assert!(rctx.count_opened(60));
+        assert!(rctx.count_opened(60));
+        assert!(!rctx.count_opened(1));
+
+        wctx.rekey(b"salt");
+        rctx.rekey(b"salt");
+        assert!(!wctx.key_exhausted());
+        assert!(rctx.count_opened(60));
+    }
 }
@@ -0,0 +1,162 @@
+//! Unreliable datagrams carried by `FLAG_DATAGRAM` events. The stream id of
+//! such an event names a flow of the session, independent of its streams,
+//! and the body is the flow's `host:port` target, prefixed with its length,
+//! followed by the datagram. A datagram which finds the queue full is
+//! dropped, like on a congested link.
+use super::event::{Event, FLAG_DATAGRAM};
+use crate::utils::{make_io_error, UDP_FLOW_IDLE};
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+const MAX_DATAGRAM: usize = 65535;
+
+/// The flows of one session by id, each with the sender of the datagrams
+/// which arrive for it.
+pub(super) type DatagramFlows = Mutex<HashMap<u32, mpsc::UnboundedSender<Vec<u8>>>>;
+
+pub(super) fn new_datagram_event(flow_id: u32, addr: &str, data: &[u8]) -> Event {
+    let addr = &addr.as_bytes()[..std::cmp::min(addr.len(), 255)];
+    let mut body = Vec::with_capacity(1 + addr.len() + data.len());
+    body.push(addr.len() as u8);
+    body.extend_from_slice(addr);
+    body.extend_from_slice(data);
+    let mut ev = super::event::new_data_event(flow_id, &body[..], false);
+    ev.header.set_flag(FLAG_DATAGRAM);
+    ev
+}
+
+/// The target and the datagram of a `FLAG_DATAGRAM` body.
+pub(super) fn parse_datagram(body: &[u8]) -> Option<(&str, &[u8])> {
+    let n = *body.first()? as usize;
+    if body.len() < 1 + n {
+        return None;
+    }
+    let addr = std::str::from_utf8(&body[1..1 + n]).ok()?;
+    Some((addr, &body[1 + n..]))
+}
+
+/// A client's flow of datagrams to one target over a session.
+pub struct DatagramFlow {
+    id: u32,
+    addr: String,
+    tx: mpsc::Sender<Event>,
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    flows: Arc<DatagramFlows>,
+}
+
+impl DatagramFlow {
+    pub(super) fn new(
+        id: u32,
+        addr: &str,
+        tx: mpsc::Sender<Event>,
+        flows: Arc<DatagramFlows>,
+    ) -> Self {
+        let (reply_tx, rx) = mpsc::unbounded_channel();
+        flows.lock().unwrap().insert(id, reply_tx);
+        Self {
+            id,
+            addr: String::from(addr),
+            tx,
+            rx,
+            flows,
+        }
+    }
+    /// Queues `data` for the target; an error once the session is gone.
+    pub fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        match self
+            .tx
+            .try_send(new_datagram_event(self.id, self.addr.as_str(), data))
+        {
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(make_io_error("session closed")),
+            _ => Ok(()),
+        }
+    }
+    /// The next datagram from the target, `None` once the session is gone.
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.rx.recv().await
+    }
+}
+
+impl Drop for DatagramFlow {
+    fn drop(&mut self) {
+        self.flows.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Relays the datagrams of a flow a client opened to its target, the
+/// replies go back on `tx` until the target stayed quiet for
+/// `UDP_FLOW_IDLE`.
+pub(super) async fn serve_datagram_flow(
+    flow_id: u32,
+    addr: String,
+    mut rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    mut tx: mpsc::Sender<Event>,
+    flows: Arc<DatagramFlows>,
+) {
+    if let Err(e) = relay_datagrams(flow_id, addr.as_str(), &mut rx, &mut tx).await {
+        error!("[{}]Datagram flow to {} failed; error={}", flow_id, addr, e);
+    }
+    flows.lock().unwrap().remove(&flow_id);
+}
+
+async fn relay_datagrams(
+    flow_id: u32,
+    addr: &str,
+    rx: &mut mpsc::UnboundedReceiver<Vec<u8>>,
+    tx: &mut mpsc::Sender<Event>,
+) -> io::Result<()> {
+    let remote = match tokio::net::lookup_host(addr).await?.next() {
+        Some(a) => a,
+        None => return Err(make_io_error("no address resolved")),
+    };
+    let local = if remote.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(remote).await?;
+    let (mut recv_half, mut send_half) = socket.split();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        tokio::select! {
+            data = rx.recv() => match data {
+                Some(data) => {
+                    send_half.send(&data[..]).await?;
+                }
+                None => return Ok(()),
+            },
+            r = tokio::time::timeout(UDP_FLOW_IDLE, recv_half.recv(&mut buf)) => match r {
+                Ok(Ok(n)) => {
+                    if tx.send(new_datagram_event(flow_id, addr, &buf[..n])).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Ok(()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_datagram_body() {
+        let ev = new_datagram_event(7, "1.1.1.1:53", b"query");
+        assert_eq!(ev.header.flags(), FLAG_DATAGRAM);
+        assert_eq!(ev.header.stream_id, 7);
+        assert_eq!(ev.header.len() as usize, ev.body.len());
+        let (addr, data) = parse_datagram(&ev.body[..]).unwrap();
+        assert_eq!(addr, "1.1.1.1:53");
+        assert_eq!(data, b"query");
+        assert!(parse_datagram(&[9, b'a']).is_none());
+        assert!(parse_datagram(&[]).is_none());
+    }
+}
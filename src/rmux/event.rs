@@ -1,4 +1,6 @@
 //use tokio::codec::{Decoder, Encoder};
+use rand::Rng;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub const FLAG_SYN: u8 = 1;
 pub const FLAG_FIN: u8 = 2;
@@ -9,9 +11,58 @@ pub const FLAG_AUTH: u8 = 6;
 pub const FLAG_SHUTDOWN: u8 = 7;
 pub const FLAG_PONG: u8 = 8;
 pub const FLAG_ROUTINE: u8 = 9;
+pub const FLAG_HALF_CLOSE: u8 = 10;
+// the sender takes no new streams, the open ones may finish
+pub const FLAG_GO_AWAY: u8 = 11;
+// an unreliable datagram of a flow, see `datagram`
+pub const FLAG_DATAGRAM: u8 = 12;
+// stream data compressed with the algorithm agreed on, see `compress`
+pub const FLAG_COMPRESSED_DATA: u8 = 13;
+// random bytes the receiver drops, see `padding`
+pub const FLAG_PADDING: u8 = 14;
+// the salt of the sender's next key, see `CryptoContext::rekey`
+pub const FLAG_REKEY: u8 = 15;
+// an operation of a UDP association, see `udp_relay`
+pub const FLAG_UDP_RELAY: u8 = 16;
 
 pub const EVENT_HEADER_LEN: usize = 8;
 
+/// Version of the frames this build speaks, advertised after the auth
+/// padding; peers which advertise none speak 1.
+pub const PROTOCOL_VERSION: u8 = 2;
+/// The oldest version a session is still run with.
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+// features advertised after the auth padding, see `AuthTrailer`; a side
+// only uses what both advertise
+// the SYN of a stream may carry its first data after the priority byte
+pub const FEATURE_EARLY_DATA: u8 = 1;
+// `FLAG_DATAGRAM` frames
+pub const FEATURE_DATAGRAMS: u8 = 2;
+// `FLAG_PADDING` frames, the auth padding is skipped by every peer
+pub const FEATURE_PADDING: u8 = 4;
+// `FLAG_REKEY` frames
+pub const FEATURE_REKEY: u8 = 8;
+// `FLAG_UDP_RELAY` frames
+pub const FEATURE_UDP_RELAY: u8 = 16;
+// key shares after the key ids of the auth frames, see `kex`
+pub const FEATURE_HYBRID_KEX: u8 = 32;
+// `FLAG_HALF_CLOSE` frames, without them shutting down the writer of a
+// stream closes it
+pub const FEATURE_HALF_CLOSE: u8 = 64;
+pub const SUPPORTED_FEATURES: u8 = FEATURE_EARLY_DATA
+    | FEATURE_DATAGRAMS
+    | FEATURE_PADDING
+    | FEATURE_REKEY
+    | FEATURE_UDP_RELAY
+    | FEATURE_HYBRID_KEX
+    | FEATURE_HALF_CLOSE;
+
+const REKEY_SALT_LEN: usize = 32;
+
+/// Random bytes of the stamp closing an auth body, see `replay`.
+pub const AUTH_NONCE_LEN: usize = 16;
+
 pub fn get_event_type_str(flags: u8) -> &'static str {
     match flags {
         FLAG_SYN => "FLAG_SYN",
@@ -22,6 +73,13 @@ pub fn get_event_type_str(flags: u8) -> &'static str {
         FLAG_AUTH => "FLAG_AUTH",
         FLAG_SHUTDOWN => "FLAG_SHUTDOWN",
         FLAG_PONG => "FLAG_PONG",
+        FLAG_HALF_CLOSE => "FLAG_HALF_CLOSE",
+        FLAG_GO_AWAY => "FLAG_GO_AWAY",
+        FLAG_DATAGRAM => "FLAG_DATAGRAM",
+        FLAG_COMPRESSED_DATA => "FLAG_COMPRESSED_DATA",
+        FLAG_PADDING => "FLAG_PADDING",
+        FLAG_REKEY => "FLAG_REKEY",
+        FLAG_UDP_RELAY => "FLAG_UDP_RELAY",
         _ => "INVALID",
     }
 }
@@ -83,13 +141,145 @@ pub fn new_empty_event(remote: bool) -> Event {
     }
 }
 
-pub fn new_auth_event<T: serde::Serialize>(sid: u32, msg: &T) -> Event {
-    let data = bincode::serialize(msg).unwrap();
+/// What an auth request or response carries after its message, in this
+/// order: the window each stream buffers from the peer, the mask of the
+/// compressions it takes, `padding` random bytes after their length, the
+/// mask of the `FEATURE_*` it supports, its protocol version, a stamp of
+/// the unix time and `AUTH_NONCE_LEN` random bytes, the ids of the cipher
+/// suites a client offers, the listener user it presents and the key share
+/// of a hybrid key exchange. Peers predating a field stop before it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthTrailer {
+    /// at most `i32::MAX` once decoded, which stream windows count in
+    pub window: Option<u32>,
+    pub compression: u8,
+    pub padding: usize,
+    pub features: u8,
+    /// 1 from peers which advertise none
+    pub version: u8,
+    /// a fresh one is written unless set
+    pub stamp: Option<(u64, [u8; AUTH_NONCE_LEN])>,
+    pub cipher_suites: Vec<u8>,
+    pub key_id: String,
+    pub key_share: Option<Vec<u8>>,
+}
+
+impl Default for AuthTrailer {
+    fn default() -> Self {
+        Self {
+            window: None,
+            compression: 0,
+            padding: 0,
+            features: 0,
+            version: PROTOCOL_VERSION,
+            stamp: None,
+            cipher_suites: Vec::new(),
+            key_id: String::new(),
+            key_share: None,
+        }
+    }
+}
+
+impl AuthTrailer {
+    /// The trailer after the auth message `msg` decoded from `body`, with
+    /// what older peers mean by the fields they do not send.
+    pub fn decode<T: serde::Serialize>(msg: &T, body: &[u8]) -> Self {
+        let mut trailer = Self {
+            version: 1,
+            ..Default::default()
+        };
+        if let Ok(n) = bincode::serialized_size(msg) {
+            trailer.decode_fields(&body[std::cmp::min(n as usize, body.len())..]);
+        }
+        trailer
+    }
+
+    // fills in the fields `buf` has, up to the first one missing
+    fn decode_fields(&mut self, buf: &[u8]) -> Option<()> {
+        let window = buf.get(0..4)?;
+        let window = u32::from_be_bytes([window[0], window[1], window[2], window[3]]);
+        self.window = Some(std::cmp::min(window, i32::MAX as u32));
+        self.compression = *buf.get(4)?;
+        self.padding = u16::from_be_bytes([*buf.get(5)?, *buf.get(6)?]) as usize;
+        let buf = buf.get(7 + self.padding..)?;
+        self.features = *buf.first()?;
+        self.version = *buf.get(1)?;
+        let stamp = buf.get(2..2 + 8 + AUTH_NONCE_LEN)?;
+        let mut secs = [0u8; 8];
+        secs.copy_from_slice(&stamp[..8]);
+        let mut nonce = [0u8; AUTH_NONCE_LEN];
+        nonce.copy_from_slice(&stamp[8..]);
+        self.stamp = Some((u64::from_be_bytes(secs), nonce));
+        let buf = &buf[2 + 8 + AUTH_NONCE_LEN..];
+        let count = *buf.first()? as usize;
+        self.cipher_suites = buf.get(1..1 + count)?.to_vec();
+        let buf = &buf[1 + count..];
+        let len = *buf.first()? as usize;
+        let id = buf.get(1..1 + len)?;
+        self.key_id = std::str::from_utf8(id)
+            .map(String::from)
+            .unwrap_or_default();
+        let buf = &buf[1 + len..];
+        let len = u16::from_be_bytes([*buf.first()?, *buf.get(1)?]) as usize;
+        self.key_share = Some(buf.get(2..2 + len)?.to_vec());
+        Some(())
+    }
+
+    fn encode(&self, data: &mut Vec<u8>) {
+        let padding = std::cmp::min(self.padding, u16::MAX as usize);
+        data.extend_from_slice(&self.window.unwrap_or(0).to_be_bytes());
+        data.push(self.compression);
+        data.extend_from_slice(&(padding as u16).to_be_bytes());
+        let start = data.len();
+        data.resize(start + padding, 0);
+        rand::thread_rng().fill(&mut data[start..]);
+        data.push(self.features);
+        data.push(self.version);
+        let (secs, nonce) = self.stamp.unwrap_or_else(|| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let mut nonce = [0u8; AUTH_NONCE_LEN];
+            rand::thread_rng().fill(&mut nonce[..]);
+            (now, nonce)
+        });
+        data.extend_from_slice(&secs.to_be_bytes());
+        data.extend_from_slice(&nonce[..]);
+        let suites = &self.cipher_suites[..std::cmp::min(self.cipher_suites.len(), 255)];
+        data.push(suites.len() as u8);
+        data.extend_from_slice(suites);
+        let key_id = &self.key_id.as_bytes()[..std::cmp::min(self.key_id.len(), 255)];
+        data.push(key_id.len() as u8);
+        data.extend_from_slice(key_id);
+        if let Some(share) = self.key_share.as_ref() {
+            data.extend_from_slice(&(share.len() as u16).to_be_bytes());
+            data.extend_from_slice(share);
+        }
+    }
+}
+
+/// An auth request or response: `msg` followed by `trailer`.
+pub fn new_auth_event<T: serde::Serialize>(sid: u32, msg: &T, trailer: &AuthTrailer) -> Event {
+    let mut data = bincode::serialize(msg).unwrap();
+    trailer.encode(&mut data);
     let mut ev = new_data_event(sid, &data[..], false);
     ev.header.set_flag(FLAG_AUTH);
     ev
 }
 
+/// The version of a session with a peer speaking `peer`, the older of both,
+/// or why there is none.
+pub fn negotiate_version(peer: u8) -> Result<u8, String> {
+    if peer < MIN_PROTOCOL_VERSION {
+        return Err(format!(
+            "peer speaks rmux version {}, {} is the oldest supported",
+            peer, MIN_PROTOCOL_VERSION
+        ));
+    }
+    Ok(std::cmp::min(peer, PROTOCOL_VERSION))
+}
+
 pub fn new_syn_event<T: serde::Serialize>(sid: u32, msg: &T) -> Event {
     let data = bincode::serialize(msg).unwrap();
     let mut ev = new_data_event(sid, &data[..], false);
@@ -108,6 +298,28 @@ pub fn new_fin_event(sid: u32, remote: bool) -> Event {
     }
 }
 
+pub fn new_half_close_event(sid: u32, remote: bool) -> Event {
+    Event {
+        header: Header {
+            flag_len: get_flag_len(0, FLAG_HALF_CLOSE),
+            stream_id: sid,
+        },
+        body: Vec::new(),
+        remote,
+    }
+}
+
+pub fn new_go_away_event(remote: bool) -> Event {
+    Event {
+        header: Header {
+            flag_len: get_flag_len(0, FLAG_GO_AWAY),
+            stream_id: 0,
+        },
+        body: Vec::new(),
+        remote,
+    }
+}
+
 pub fn new_shutdown_event(sid: u32, remote: bool) -> Event {
     Event {
         header: Header {
@@ -161,6 +373,15 @@ pub fn new_data_event(sid: u32, buf: &[u8], remote: bool) -> Event {
         remote,
     }
 }
+/// A rekey frame with a random salt, sealed with the key it replaces.
+pub fn new_rekey_event() -> Event {
+    let mut salt = [0u8; REKEY_SALT_LEN];
+    rand::thread_rng().fill(&mut salt[..]);
+    let mut ev = new_data_event(0, &salt[..], false);
+    ev.header.set_flag(FLAG_REKEY);
+    ev
+}
+
 pub fn new_window_update_event(sid: u32, len: u32, remote: bool) -> Event {
     Event {
         header: Header {
@@ -171,3 +392,87 @@ pub fn new_window_update_event(sid: u32, len: u32, remote: bool) -> Event {
         remote,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::message::AuthRequest;
+    use super::*;
+
+    #[test]
+    fn test_auth_body() {
+        let auth = AuthRequest {
+            method: String::from("chacha20poly1305"),
+        };
+        let trailer = AuthTrailer {
+            window: Some(65536),
+            compression: 1,
+            padding: 37,
+            features: FEATURE_EARLY_DATA,
+            cipher_suites: vec![3, 2],
+            key_id: String::from("alice"),
+            ..Default::default()
+        };
+        let ev = new_auth_event(0, &auth, &trailer);
+        assert_eq!(ev.header.flags(), FLAG_AUTH);
+        let decoded = AuthTrailer::decode(&auth, &ev.body[..]);
+        let (secs, nonce) = decoded.stamp.unwrap();
+        assert!(secs > 0);
+        assert_eq!(
+            decoded,
+            AuthTrailer {
+                stamp: decoded.stamp,
+                ..trailer.clone()
+            }
+        );
+        let again = AuthTrailer::decode(&auth, &new_auth_event(0, &auth, &trailer).body[..]);
+        assert_ne!(again.stamp, decoded.stamp);
+        let wide = AuthTrailer {
+            window: Some(u32::MAX),
+            ..Default::default()
+        };
+        let wide = new_auth_event(0, &auth, &wide);
+        assert_eq!(
+            AuthTrailer::decode(&auth, &wide.body[..]).window,
+            Some(i32::MAX as u32)
+        );
+        let shared = AuthTrailer {
+            key_share: Some(vec![9; 40]),
+            ..trailer.clone()
+        };
+        let shared = new_auth_event(0, &auth, &shared);
+        assert_eq!(shared.header.len() as usize, shared.body.len());
+        let decoded = AuthTrailer::decode(&auth, &shared.body[..]);
+        assert_eq!(decoded.key_share, Some(vec![9; 40]));
+        assert_eq!(decoded.key_id, "alice");
+        let truncated = &shared.body[..shared.body.len() - 1];
+        assert_eq!(AuthTrailer::decode(&auth, truncated).key_share, None);
+        // peers predating key ids, suites, stamps, versions, features, or
+        // windows, send less
+        let decode = |n: usize| AuthTrailer::decode(&auth, &ev.body[..n]);
+        let n = bincode::serialized_size(&auth).unwrap() as usize;
+        let offered = ev.body.len() - 6;
+        let stamped = offered - 3;
+        let unstamped = stamped - 8 - AUTH_NONCE_LEN;
+        assert_eq!(decode(offered).key_id, "");
+        assert_eq!(decode(offered).cipher_suites, vec![3, 2]);
+        assert_eq!(decode(stamped).key_id, "");
+        assert!(decode(stamped).cipher_suites.is_empty());
+        assert!(decode(stamped + 2).cipher_suites.is_empty());
+        assert_eq!(decode(stamped).stamp, Some((secs, nonce)));
+        assert_eq!(decode(stamped - 1).stamp, None);
+        assert_eq!(decode(unstamped - 1).version, 1);
+        assert_eq!(decode(unstamped - 1).features, FEATURE_EARLY_DATA);
+        assert_eq!(decode(unstamped - 2).features, 0);
+        assert_eq!(decode(n + 4).features, 0);
+        assert_eq!(decode(n + 4).window, Some(65536));
+        assert_eq!(decode(n), AuthTrailer::decode(&auth, b""));
+        assert_eq!(decode(n).window, None);
+        assert_eq!(decode(n).version, 1);
+        assert_eq!(negotiate_version(1), Ok(1));
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION + 1),
+            Ok(PROTOCOL_VERSION)
+        );
+        assert!(negotiate_version(0).is_err());
+    }
+}
@@ -0,0 +1,484 @@
+//! The master key of rmux sessions, the configured key as it is or derived
+//! from it by argon2id (RFC 9106) with a per deployment salt, so a captured
+//! handshake does not make a weak password cheap to guess. Blake2b, which
+//! argon2 hashes with, lives here too.
+use crate::config::{CipherConfig, KdfConfig};
+
+use ring::digest;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// OWASP's minimum for argon2id: 19 MiB, 2 passes, one lane
+const DEFAULT_MEMORY_KIB: u32 = 19 * 1024;
+const DEFAULT_PASSES: u32 = 2;
+const MIN_SALT_LEN: usize = 8;
+const KEY_LEN: usize = 32;
+
+lazy_static! {
+    // derivations are slow by design, each key, salt and cost is done once;
+    // by a hash of them, not to keep the configured keys around
+    static ref DERIVED_KEYS: Mutex<HashMap<Vec<u8>, Vec<u8>>> = Mutex::new(HashMap::new());
+}
+
+/// The 32 bytes a `CryptoContext` seals sessions with: the configured key
+/// padded with 'F' like older versions did, or its argon2id hash if `kdf`
+/// is configured; both peers have to agree on the salt and the costs.
+pub async fn session_key(cipher: &CipherConfig) -> Vec<u8> {
+    user_session_key(cipher, cipher.key.as_str()).await
+}
+
+/// The session key of a listener user's `key`, derived by the `kdf` of
+/// `cipher` like `session_key`.
+pub async fn user_session_key(cipher: &CipherConfig, key: &str) -> Vec<u8> {
+    match cipher.kdf.as_ref() {
+        Some(kdf) => derive_key(key, kdf).await,
+        None => {
+            let mut key = Vec::from(key.as_bytes());
+            key.resize(std::cmp::max(key.len(), KEY_LEN), b'F');
+            key.truncate(KEY_LEN);
+            key
+        }
+    }
+}
+
+/// The cache id of a derivation.
+fn derivation_id(key: &str, salt: &str, memory: u32, passes: u32) -> Vec<u8> {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    for field in [key.as_bytes(), salt.as_bytes()].iter() {
+        ctx.update(&(field.len() as u32).to_le_bytes());
+        ctx.update(field);
+    }
+    ctx.update(&memory.to_le_bytes());
+    ctx.update(&passes.to_le_bytes());
+    ctx.finish().as_ref().to_vec()
+}
+
+async fn derive_key(key: &str, kdf: &KdfConfig) -> Vec<u8> {
+    let memory = kdf.memory_kib.unwrap_or(DEFAULT_MEMORY_KIB);
+    let passes = kdf.passes.unwrap_or(DEFAULT_PASSES).max(1);
+    if kdf.salt.len() < MIN_SALT_LEN {
+        warn!(
+            "Kdf salt shorter than {} bytes, pick a longer random one.",
+            MIN_SALT_LEN
+        );
+    }
+    let id = derivation_id(key, kdf.salt.as_str(), memory, passes);
+    if let Some(k) = DERIVED_KEYS.lock().unwrap().get(&id) {
+        return k.clone();
+    }
+    // seconds of hashing with the default costs, off the runtime threads
+    let (key, salt) = (String::from(key), kdf.salt.clone());
+    let out = tokio::task::spawn_blocking(move || {
+        let mut out = vec![0u8; KEY_LEN];
+        argon2id(
+            key.as_bytes(),
+            salt.as_bytes(),
+            &[],
+            &[],
+            passes,
+            memory,
+            1,
+            &mut out[..],
+        );
+        out
+    })
+    .await
+    .expect("argon2id panicked");
+    DERIVED_KEYS.lock().unwrap().insert(id, out.clone());
+    out
+}
+
+const BLAKE2B_IV: [u64; 8] = [
+    0x6a09_e667_f3bc_c908,
+    0xbb67_ae85_84ca_a73b,
+    0x3c6e_f372_fe94_f82b,
+    0xa54f_f53a_5f1d_36f1,
+    0x510e_527f_ade6_82d1,
+    0x9b05_688c_2b3e_6c1f,
+    0x1f83_d9ab_fb41_bd6b,
+    0x5be0_cd19_137e_2179,
+];
+
+const BLAKE2B_SIGMA: [[usize; 16]; 12] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+];
+
+/// Unkeyed Blake2b with a digest of up to 64 bytes.
+struct Blake2b {
+    h: [u64; 8],
+    t: u128,
+    buf: [u8; 128],
+    buf_len: usize,
+    out_len: usize,
+}
+
+impl Blake2b {
+    fn new(out_len: usize) -> Self {
+        let mut h = BLAKE2B_IV;
+        h[0] ^= 0x0101_0000 ^ out_len as u64;
+        Self {
+            h,
+            t: 0,
+            buf: [0; 128],
+            buf_len: 0,
+            out_len,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // the last block is compressed by finalize, with its own flag
+            if self.buf_len == 128 {
+                self.t += 128;
+                let block = self.buf;
+                self.compress(&block, false);
+                self.buf_len = 0;
+            }
+            let n = std::cmp::min(128 - self.buf_len, data.len());
+            self.buf[self.buf_len..self.buf_len + n].copy_from_slice(&data[..n]);
+            self.buf_len += n;
+            data = &data[n..];
+        }
+    }
+
+    fn finalize(mut self, out: &mut [u8]) {
+        self.t += self.buf_len as u128;
+        for b in self.buf[self.buf_len..].iter_mut() {
+            *b = 0;
+        }
+        let block = self.buf;
+        self.compress(&block, true);
+        let mut digest = [0u8; 64];
+        for (i, w) in self.h.iter().enumerate() {
+            digest[i * 8..i * 8 + 8].copy_from_slice(&w.to_le_bytes());
+        }
+        out.copy_from_slice(&digest[..self.out_len]);
+    }
+
+    fn compress(&mut self, block: &[u8; 128], last: bool) {
+        let mut m = [0u64; 16];
+        for (i, w) in m.iter_mut().enumerate() {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&block[i * 8..i * 8 + 8]);
+            *w = u64::from_le_bytes(b);
+        }
+        let mut v = [0u64; 16];
+        v[..8].copy_from_slice(&self.h);
+        v[8..].copy_from_slice(&BLAKE2B_IV);
+        v[12] ^= self.t as u64;
+        v[13] ^= (self.t >> 64) as u64;
+        if last {
+            v[14] = !v[14];
+        }
+        for s in BLAKE2B_SIGMA.iter() {
+            blake2b_g(&mut v, 0, 4, 8, 12, m[s[0]], m[s[1]]);
+            blake2b_g(&mut v, 1, 5, 9, 13, m[s[2]], m[s[3]]);
+            blake2b_g(&mut v, 2, 6, 10, 14, m[s[4]], m[s[5]]);
+            blake2b_g(&mut v, 3, 7, 11, 15, m[s[6]], m[s[7]]);
+            blake2b_g(&mut v, 0, 5, 10, 15, m[s[8]], m[s[9]]);
+            blake2b_g(&mut v, 1, 6, 11, 12, m[s[10]], m[s[11]]);
+            blake2b_g(&mut v, 2, 7, 8, 13, m[s[12]], m[s[13]]);
+            blake2b_g(&mut v, 3, 4, 9, 14, m[s[14]], m[s[15]]);
+        }
+        for i in 0..8 {
+            self.h[i] ^= v[i] ^ v[i + 8];
+        }
+    }
+}
+
+fn blake2b_g(v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize, x: u64, y: u64) {
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+/// H' of argon2, Blake2b stretched to a digest of any length.
+fn blake2b_long(out: &mut [u8], input: &[&[u8]]) {
+    let mut h = Blake2b::new(std::cmp::min(out.len(), 64));
+    h.update(&(out.len() as u32).to_le_bytes());
+    for part in input.iter() {
+        h.update(part);
+    }
+    if out.len() <= 64 {
+        h.finalize(out);
+        return;
+    }
+    let mut v = [0u8; 64];
+    h.finalize(&mut v);
+    out[..32].copy_from_slice(&v[..32]);
+    let mut pos = 32;
+    while out.len() - pos > 64 {
+        let mut h = Blake2b::new(64);
+        h.update(&v);
+        h.finalize(&mut v);
+        out[pos..pos + 32].copy_from_slice(&v[..32]);
+        pos += 32;
+    }
+    let mut h = Blake2b::new(out.len() - pos);
+    h.update(&v);
+    h.finalize(&mut out[pos..]);
+}
+
+const BLOCK_WORDS: usize = 128;
+const SYNC_POINTS: u32 = 4;
+const ARGON2_VERSION: u32 = 0x13;
+const ARGON2ID_TYPE: u32 = 2;
+
+type Block = [u64; BLOCK_WORDS];
+
+fn fblamka(x: u64, y: u64) -> u64 {
+    let m = 0xffff_ffffu64;
+    x.wrapping_add(y)
+        .wrapping_add(2u64.wrapping_mul((x & m).wrapping_mul(y & m)))
+}
+
+fn permute(v: &mut Block, idx: [usize; 16]) {
+    let mut gb = |a: usize, b: usize, c: usize, d: usize| {
+        let (a, b, c, d) = (idx[a], idx[b], idx[c], idx[d]);
+        v[a] = fblamka(v[a], v[b]);
+        v[d] = (v[d] ^ v[a]).rotate_right(32);
+        v[c] = fblamka(v[c], v[d]);
+        v[b] = (v[b] ^ v[c]).rotate_right(24);
+        v[a] = fblamka(v[a], v[b]);
+        v[d] = (v[d] ^ v[a]).rotate_right(16);
+        v[c] = fblamka(v[c], v[d]);
+        v[b] = (v[b] ^ v[c]).rotate_right(63);
+    };
+    gb(0, 4, 8, 12);
+    gb(1, 5, 9, 13);
+    gb(2, 6, 10, 14);
+    gb(3, 7, 11, 15);
+    gb(0, 5, 10, 15);
+    gb(1, 6, 11, 12);
+    gb(2, 7, 8, 13);
+    gb(3, 4, 9, 14);
+}
+
+/// The compression G of argon2 over `prev` and `reference`, into `next`
+/// or xored onto it for the passes after the first.
+fn fill_block(prev: &Block, reference: &Block, next: &mut Block, xor: bool) {
+    let mut r = [0u64; BLOCK_WORDS];
+    for i in 0..BLOCK_WORDS {
+        r[i] = prev[i] ^ reference[i];
+    }
+    let mut tmp = r;
+    if xor {
+        for i in 0..BLOCK_WORDS {
+            tmp[i] ^= next[i];
+        }
+    }
+    for i in 0..8 {
+        let mut idx = [0usize; 16];
+        for (j, x) in idx.iter_mut().enumerate() {
+            *x = 16 * i + j;
+        }
+        permute(&mut r, idx);
+    }
+    for i in 0..8 {
+        let mut idx = [0usize; 16];
+        for (j, x) in idx.iter_mut().enumerate() {
+            *x = 2 * i + 16 * (j / 2) + j % 2;
+        }
+        permute(&mut r, idx);
+    }
+    for i in 0..BLOCK_WORDS {
+        next[i] = tmp[i] ^ r[i];
+    }
+}
+
+fn block_from_bytes(bytes: &[u8]) -> Block {
+    let mut block = [0u64; BLOCK_WORDS];
+    for (i, w) in block.iter_mut().enumerate() {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(&bytes[i * 8..i * 8 + 8]);
+        *w = u64::from_le_bytes(b);
+    }
+    block
+}
+
+/// Argon2id of RFC 9106 with `lanes` lanes, computed one after the other,
+/// over `memory_kib` blocks of 1 KiB.
+#[allow(clippy::too_many_arguments)]
+fn argon2id(
+    password: &[u8],
+    salt: &[u8],
+    secret: &[u8],
+    ad: &[u8],
+    passes: u32,
+    memory_kib: u32,
+    lanes: u32,
+    out: &mut [u8],
+) {
+    let memory_kib = std::cmp::max(memory_kib, 2 * SYNC_POINTS * lanes);
+    let mut h0 = [0u8; 72];
+    {
+        let params = [
+            lanes,
+            out.len() as u32,
+            memory_kib,
+            passes,
+            ARGON2_VERSION,
+            ARGON2ID_TYPE,
+        ];
+        let mut h = Blake2b::new(64);
+        for p in params.iter() {
+            h.update(&p.to_le_bytes());
+        }
+        for field in [password, salt, secret, ad].iter() {
+            h.update(&(field.len() as u32).to_le_bytes());
+            h.update(field);
+        }
+        h.finalize(&mut h0[..64]);
+    }
+
+    let segment_len = (memory_kib / (SYNC_POINTS * lanes)) as usize;
+    let lane_len = segment_len * SYNC_POINTS as usize;
+    let block_count = lane_len * lanes as usize;
+    let mut memory: Vec<Block> = vec![[0u64; BLOCK_WORDS]; block_count];
+    let mut bytes = [0u8; 1024];
+    for lane in 0..lanes as usize {
+        for i in 0..2 {
+            h0[64..68].copy_from_slice(&(i as u32).to_le_bytes());
+            h0[68..72].copy_from_slice(&(lane as u32).to_le_bytes());
+            blake2b_long(&mut bytes, &[&h0[..]]);
+            memory[lane * lane_len + i] = block_from_bytes(&bytes);
+        }
+    }
+
+    let zero = [0u64; BLOCK_WORDS];
+    for pass in 0..passes {
+        for slice in 0..SYNC_POINTS {
+            for lane in 0..lanes {
+                let independent = pass == 0 && slice < SYNC_POINTS / 2;
+                let mut input = [0u64; BLOCK_WORDS];
+                let mut addresses = [0u64; BLOCK_WORDS];
+                input[0] = pass as u64;
+                input[1] = lane as u64;
+                input[2] = slice as u64;
+                input[3] = block_count as u64;
+                input[4] = passes as u64;
+                input[5] = ARGON2ID_TYPE as u64;
+                let next_addresses = |input: &mut Block, addresses: &mut Block| {
+                    input[6] += 1;
+                    let mut tmp = [0u64; BLOCK_WORDS];
+                    fill_block(&zero, input, &mut tmp, false);
+                    fill_block(&zero, &tmp, addresses, false);
+                };
+                let mut start = 0;
+                if pass == 0 && slice == 0 {
+                    start = 2;
+                    if independent {
+                        next_addresses(&mut input, &mut addresses);
+                    }
+                }
+                let lane_start = lane as usize * lane_len;
+                for i in start..segment_len {
+                    let col = slice as usize * segment_len + i;
+                    let prev_col = if col == 0 { lane_len - 1 } else { col - 1 };
+                    let pseudo_rand = if independent {
+                        if i % BLOCK_WORDS == 0 {
+                            next_addresses(&mut input, &mut addresses);
+                        }
+                        addresses[i % BLOCK_WORDS]
+                    } else {
+                        memory[lane_start + prev_col][0]
+                    };
+                    let ref_lane = if pass == 0 && slice == 0 {
+                        lane as usize
+                    } else {
+                        ((pseudo_rand >> 32) % lanes as u64) as usize
+                    };
+                    let same_lane = ref_lane == lane as usize;
+                    let area = if pass == 0 {
+                        if same_lane {
+                            slice as usize * segment_len + i - 1
+                        } else if i == 0 {
+                            slice as usize * segment_len - 1
+                        } else {
+                            slice as usize * segment_len
+                        }
+                    } else if same_lane {
+                        lane_len - segment_len + i - 1
+                    } else if i == 0 {
+                        lane_len - segment_len - 1
+                    } else {
+                        lane_len - segment_len
+                    } as u64;
+                    let x = pseudo_rand & 0xffff_ffff;
+                    let x = (x * x) >> 32;
+                    let relative = area - 1 - ((area * x) >> 32);
+                    let start_col = if pass == 0 || slice == SYNC_POINTS - 1 {
+                        0
+                    } else {
+                        (slice as usize + 1) * segment_len
+                    };
+                    let ref_col = (start_col + relative as usize) % lane_len;
+                    let prev = memory[lane_start + prev_col];
+                    let reference = memory[ref_lane * lane_len + ref_col];
+                    fill_block(&prev, &reference, &mut memory[lane_start + col], pass > 0);
+                }
+            }
+        }
+    }
+
+    let mut last = memory[lane_len - 1];
+    for lane in 1..lanes as usize {
+        let block = &memory[lane * lane_len + lane_len - 1];
+        for i in 0..BLOCK_WORDS {
+            last[i] ^= block[i];
+        }
+    }
+    for (i, w) in last.iter().enumerate() {
+        bytes[i * 8..i * 8 + 8].copy_from_slice(&w.to_le_bytes());
+    }
+    blake2b_long(out, &[&bytes[..]]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_blake2b() {
+        let mut out = [0u8; 64];
+        let mut h = Blake2b::new(64);
+        h.update(b"abc");
+        h.finalize(&mut out);
+        assert_eq!(
+            hex(&out),
+            "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1\
+             7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923"
+        );
+    }
+
+    #[test]
+    fn test_argon2id() {
+        // RFC 9106 section 5.3
+        let mut out = [0u8; 32];
+        argon2id(&[1; 32], &[2; 16], &[3; 8], &[4; 12], 3, 32, 4, &mut out);
+        assert_eq!(
+            hex(&out),
+            "0d640df58d78766c08c037a34a8b53c9d01ef0452d75b65eb52520e96b01e659"
+        );
+    }
+}
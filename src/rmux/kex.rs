@@ -0,0 +1,125 @@
+//! Hybrid key exchange of X25519 and ML-KEM-768. A client opting in sends
+//! an ephemeral X25519 key and ML-KEM encapsulation key after its auth
+//! frame's key id, a server taking it answers with its own X25519 key and
+//! the ML-KEM ciphertext, and both mix the two shared secrets into the
+//! session key. Recorded sessions then stay sealed unless both X25519 and
+//! ML-KEM, as well as the configured key, are broken.
+use super::mlkem::{self, DecapsKey, CIPHERTEXT_LEN, ENCAPS_KEY_LEN};
+use crate::utils::zeroize;
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::hmac;
+use ring::rand::SystemRandom;
+
+const X25519_LEN: usize = 32;
+pub const CLIENT_SHARE_LEN: usize = X25519_LEN + ENCAPS_KEY_LEN;
+pub const SERVER_SHARE_LEN: usize = X25519_LEN + CIPHERTEXT_LEN;
+
+/// The ephemeral secrets of a client's key share.
+pub struct KeyShare {
+    x25519: EphemeralPrivateKey,
+    kem: DecapsKey,
+    public: Vec<u8>,
+}
+
+impl KeyShare {
+    pub fn generate() -> Self {
+        let x25519 = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new()).unwrap();
+        let kem = DecapsKey::generate();
+        let mut public = x25519.compute_public_key().unwrap().as_ref().to_vec();
+        public.extend_from_slice(kem.encaps_key());
+        Self {
+            x25519,
+            kem,
+            public,
+        }
+    }
+
+    /// What goes into the auth frame.
+    pub fn public(&self) -> &[u8] {
+        &self.public[..]
+    }
+
+    /// The session key mixing `key` with the secrets shared through the
+    /// server's `share`.
+    pub fn finish(self, key: &[u8], share: &[u8]) -> Result<Vec<u8>, String> {
+        if share.len() != SERVER_SHARE_LEN {
+            return Err(String::from("invalid server key share"));
+        }
+        let mut kem_secret = self.kem.decaps(&share[X25519_LEN..])?;
+        let peer = UnparsedPublicKey::new(&X25519, &share[..X25519_LEN]);
+        let mut x25519_secret = agreement::agree_ephemeral(
+            self.x25519,
+            &peer,
+            String::from("invalid server X25519 key"),
+            |k| Ok(k.to_vec()),
+        )?;
+        let key = mix_key(
+            key,
+            &kem_secret[..],
+            &x25519_secret[..],
+            &self.public[..],
+            share,
+        );
+        zeroize(&mut kem_secret[..]);
+        zeroize(&mut x25519_secret[..]);
+        Ok(key)
+    }
+}
+
+/// The server's answer to a client's `share` and the session key mixing
+/// `key` with the secrets shared through them.
+pub fn accept_key_share(key: &[u8], share: &[u8]) -> Result<(Vec<u8>, Vec<u8>), String> {
+    if share.len() != CLIENT_SHARE_LEN {
+        return Err(String::from("invalid client key share"));
+    }
+    let (mut kem_secret, ciphertext) = mlkem::encaps(&share[X25519_LEN..])?;
+    let rng = SystemRandom::new();
+    let x25519 = EphemeralPrivateKey::generate(&X25519, &rng).unwrap();
+    let mut answer = x25519.compute_public_key().unwrap().as_ref().to_vec();
+    answer.extend_from_slice(&ciphertext[..]);
+    let peer = UnparsedPublicKey::new(&X25519, &share[..X25519_LEN]);
+    let mut x25519_secret = agreement::agree_ephemeral(
+        x25519,
+        &peer,
+        String::from("invalid client X25519 key"),
+        |k| Ok(k.to_vec()),
+    )?;
+    let key = mix_key(key, &kem_secret[..], &x25519_secret[..], share, &answer[..]);
+    zeroize(&mut kem_secret[..]);
+    zeroize(&mut x25519_secret[..]);
+    Ok((answer, key))
+}
+
+// both secrets and both shares under the configured key, so neither half
+// alone nor a swapped share gives the session key
+fn mix_key(key: &[u8], kem: &[u8], x25519: &[u8], client: &[u8], server: &[u8]) -> Vec<u8> {
+    let mut ctx = hmac::Context::with_key(&hmac::Key::new(hmac::HMAC_SHA256, key));
+    for part in [&b"rmux hybrid kex"[..], kem, x25519, client, server].iter() {
+        ctx.update(part);
+    }
+    ctx.sign().as_ref().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_share() {
+        let key = [7u8; 32];
+        let share = KeyShare::generate();
+        assert_eq!(share.public().len(), CLIENT_SHARE_LEN);
+        let (answer, server_key) = accept_key_share(&key[..], share.public()).unwrap();
+        assert_eq!(answer.len(), SERVER_SHARE_LEN);
+        assert_eq!(server_key.len(), 32);
+        assert_ne!(&server_key[..], &key[..]);
+        assert!(accept_key_share(&key[..], &share.public()[1..]).is_err());
+
+        let other = KeyShare::generate();
+        assert_ne!(
+            other.finish(&key[..], &answer[..]).unwrap(),
+            server_key.clone()
+        );
+        assert_eq!(share.finish(&key[..], &answer[..]).unwrap(), server_key);
+    }
+}
@@ -0,0 +1,583 @@
+//! ML-KEM-768 (FIPS 203, the standardized Kyber) and the SHA-3 functions
+//! it is built on, for the post-quantum half of the hybrid key exchange.
+use crate::utils::zeroize;
+use ring::constant_time::verify_slices_are_equal;
+use ring::rand::{SecureRandom, SystemRandom};
+
+const N: usize = 256;
+const Q: u32 = 3329;
+const K: usize = 3;
+const ETA1: usize = 2;
+const ETA2: usize = 2;
+const DU: u32 = 10;
+const DV: u32 = 4;
+// 128^-1 mod q, scales the inverse NTT
+const N_INV: u32 = 3303;
+
+pub const ENCAPS_KEY_LEN: usize = 384 * K + 32;
+pub const CIPHERTEXT_LEN: usize = 32 * (DU as usize * K + DV as usize);
+const PKE_DECAPS_KEY_LEN: usize = 384 * K;
+const DECAPS_KEY_LEN: usize = 768 * K + 96;
+
+const RC: [u64; 24] = [
+    0x0000_0000_0000_0001,
+    0x0000_0000_0000_8082,
+    0x8000_0000_0000_808a,
+    0x8000_0000_8000_8000,
+    0x0000_0000_0000_808b,
+    0x0000_0000_8000_0001,
+    0x8000_0000_8000_8081,
+    0x8000_0000_0000_8009,
+    0x0000_0000_0000_008a,
+    0x0000_0000_0000_0088,
+    0x0000_0000_8000_8009,
+    0x0000_0000_8000_000a,
+    0x0000_0000_8000_808b,
+    0x8000_0000_0000_008b,
+    0x8000_0000_0000_8089,
+    0x8000_0000_0000_8003,
+    0x8000_0000_0000_8002,
+    0x8000_0000_0000_0080,
+    0x0000_0000_0000_800a,
+    0x8000_0000_8000_000a,
+    0x8000_0000_8000_8081,
+    0x8000_0000_0000_8080,
+    0x0000_0000_8000_0001,
+    0x8000_0000_8000_8008,
+];
+const ROTC: [u32; 24] = [
+    1, 3, 6, 10, 15, 21, 28, 36, 45, 55, 2, 14, 27, 41, 56, 8, 25, 43, 62, 18, 39, 61, 20, 44,
+];
+const PILN: [usize; 24] = [
+    10, 7, 11, 17, 18, 3, 5, 16, 8, 21, 24, 4, 15, 23, 19, 13, 12, 2, 20, 14, 22, 9, 6, 1,
+];
+
+fn keccak_f(st: &mut [u64; 25]) {
+    for rc in RC.iter() {
+        let mut bc = [0u64; 5];
+        for i in 0..5 {
+            bc[i] = st[i] ^ st[i + 5] ^ st[i + 10] ^ st[i + 15] ^ st[i + 20];
+        }
+        for i in 0..5 {
+            let t = bc[(i + 4) % 5] ^ bc[(i + 1) % 5].rotate_left(1);
+            for j in (0..25).step_by(5) {
+                st[j + i] ^= t;
+            }
+        }
+        let mut t = st[1];
+        for i in 0..24 {
+            let j = PILN[i];
+            let next = st[j];
+            st[j] = t.rotate_left(ROTC[i]);
+            t = next;
+        }
+        for j in (0..25).step_by(5) {
+            let mut row = [0u64; 5];
+            row.copy_from_slice(&st[j..j + 5]);
+            for i in 0..5 {
+                st[j + i] = row[i] ^ (!row[(i + 1) % 5] & row[(i + 2) % 5]);
+            }
+        }
+        st[0] ^= rc;
+    }
+}
+
+/// A Keccak sponge, SHA3 or SHAKE by its rate and padding.
+struct Keccak {
+    st: [u64; 25],
+    rate: usize,
+    pad: u8,
+    pos: usize,
+    squeezing: bool,
+}
+
+impl Keccak {
+    fn new(rate: usize, pad: u8) -> Self {
+        Self {
+            st: [0u64; 25],
+            rate,
+            pad,
+            pos: 0,
+            squeezing: false,
+        }
+    }
+    fn shake128() -> Self {
+        Self::new(168, 0x1f)
+    }
+    fn shake256() -> Self {
+        Self::new(136, 0x1f)
+    }
+    fn xor_byte(&mut self, i: usize, b: u8) {
+        self.st[i / 8] ^= u64::from(b) << (8 * (i % 8));
+    }
+    fn absorb(&mut self, data: &[u8]) -> &mut Self {
+        for b in data.iter() {
+            self.xor_byte(self.pos, *b);
+            self.pos += 1;
+            if self.pos == self.rate {
+                keccak_f(&mut self.st);
+                self.pos = 0;
+            }
+        }
+        self
+    }
+    fn squeeze(&mut self, out: &mut [u8]) {
+        if !self.squeezing {
+            self.xor_byte(self.pos, self.pad);
+            self.xor_byte(self.rate - 1, 0x80);
+            keccak_f(&mut self.st);
+            self.pos = 0;
+            self.squeezing = true;
+        }
+        for o in out.iter_mut() {
+            if self.pos == self.rate {
+                keccak_f(&mut self.st);
+                self.pos = 0;
+            }
+            *o = (self.st[self.pos / 8] >> (8 * (self.pos % 8))) as u8;
+            self.pos += 1;
+        }
+    }
+}
+
+fn sha3_256(parts: &[&[u8]]) -> [u8; 32] {
+    let mut h = Keccak::new(136, 0x06);
+    for p in parts.iter() {
+        h.absorb(p);
+    }
+    let mut out = [0u8; 32];
+    h.squeeze(&mut out);
+    out
+}
+
+fn sha3_512(parts: &[&[u8]]) -> [u8; 64] {
+    let mut h = Keccak::new(72, 0x06);
+    for p in parts.iter() {
+        h.absorb(p);
+    }
+    let mut out = [0u8; 64];
+    h.squeeze(&mut out);
+    out
+}
+
+type Poly = [u32; N];
+
+fn bitrev7(i: usize) -> usize {
+    (i as u8).reverse_bits() as usize >> 1
+}
+
+fn pow_mod(base: u32, exp: usize) -> u32 {
+    (0..exp).fold(1, |acc, _| acc * base % Q)
+}
+
+lazy_static! {
+    // 17^BitRev7(i), and 17^(2 BitRev7(i) + 1) of the base case products
+    static ref ZETAS: Vec<u32> = (0..128).map(|i| pow_mod(17, bitrev7(i))).collect();
+    static ref GAMMAS: Vec<u32> = (0..128).map(|i| pow_mod(17, 2 * bitrev7(i) + 1)).collect();
+}
+
+fn ntt(f: &mut Poly) {
+    let mut k = 1;
+    let mut len = 128;
+    while len >= 2 {
+        for start in (0..N).step_by(2 * len) {
+            let zeta = ZETAS[k];
+            k += 1;
+            for j in start..start + len {
+                let t = zeta * f[j + len] % Q;
+                f[j + len] = (f[j] + Q - t) % Q;
+                f[j] = (f[j] + t) % Q;
+            }
+        }
+        len /= 2;
+    }
+}
+
+fn inv_ntt(f: &mut Poly) {
+    let mut k = 127;
+    let mut len = 2;
+    while len <= 128 {
+        for start in (0..N).step_by(2 * len) {
+            let zeta = ZETAS[k];
+            k -= 1;
+            for j in start..start + len {
+                let t = f[j];
+                f[j] = (t + f[j + len]) % Q;
+                f[j + len] = zeta * ((f[j + len] + Q - t) % Q) % Q;
+            }
+        }
+        len *= 2;
+    }
+    for c in f.iter_mut() {
+        *c = *c * N_INV % Q;
+    }
+}
+
+/// Adds the product of `a` and `b`, both in the NTT domain, to `acc`.
+fn mul_acc(acc: &mut Poly, a: &Poly, b: &Poly) {
+    for i in 0..128 {
+        let (a0, a1, b0, b1) = (a[2 * i], a[2 * i + 1], b[2 * i], b[2 * i + 1]);
+        let c0 = (a0 * b0 + a1 * b1 % Q * GAMMAS[i]) % Q;
+        let c1 = (a0 * b1 + a1 * b0) % Q;
+        acc[2 * i] = (acc[2 * i] + c0) % Q;
+        acc[2 * i + 1] = (acc[2 * i + 1] + c1) % Q;
+    }
+}
+
+fn add(a: &mut Poly, b: &Poly) {
+    for (x, y) in a.iter_mut().zip(b.iter()) {
+        *x = (*x + y) % Q;
+    }
+}
+
+fn byte_encode(f: &Poly, d: u32, out: &mut Vec<u8>) {
+    let mut acc = 0u64;
+    let mut bits = 0;
+    for c in f.iter() {
+        acc |= u64::from(*c) << bits;
+        bits += d;
+        while bits >= 8 {
+            out.push(acc as u8);
+            acc >>= 8;
+            bits -= 8;
+        }
+    }
+}
+
+fn byte_decode(bytes: &[u8], d: u32) -> Poly {
+    let mut f = [0u32; N];
+    let mask = (1u64 << d) - 1;
+    let mut acc = 0u64;
+    let mut bits = 0;
+    let mut input = bytes.iter();
+    for c in f.iter_mut() {
+        while bits < d {
+            acc |= u64::from(*input.next().unwrap_or(&0)) << bits;
+            bits += 8;
+        }
+        *c = (acc & mask) as u32;
+        acc >>= d;
+        bits -= d;
+    }
+    f
+}
+
+fn compress(f: &mut Poly, d: u32) {
+    for c in f.iter_mut() {
+        *c = ((((*c as u64) << d) + u64::from(Q / 2)) / u64::from(Q)) as u32 & ((1 << d) - 1);
+    }
+}
+
+fn decompress(f: &mut Poly, d: u32) {
+    for c in f.iter_mut() {
+        *c = ((u64::from(*c) * u64::from(Q) + (1 << (d - 1))) >> d) as u32;
+    }
+}
+
+/// SampleNTT, the matrix entry of `rho` at (`i`, `j`).
+fn sample_ntt(rho: &[u8], i: usize, j: usize) -> Poly {
+    let mut xof = Keccak::shake128();
+    xof.absorb(rho).absorb(&[j as u8, i as u8]);
+    let mut f = [0u32; N];
+    let mut n = 0;
+    let mut c = [0u8; 3];
+    while n < N {
+        xof.squeeze(&mut c);
+        let d1 = u32::from(c[0]) | (u32::from(c[1] & 0x0f) << 8);
+        let d2 = u32::from(c[1] >> 4) | (u32::from(c[2]) << 4);
+        if d1 < Q {
+            f[n] = d1;
+            n += 1;
+        }
+        if d2 < Q && n < N {
+            f[n] = d2;
+            n += 1;
+        }
+    }
+    f
+}
+
+/// SamplePolyCBD of PRF(`seed`, `nonce`).
+fn sample_cbd(seed: &[u8], nonce: u8, eta: usize) -> Poly {
+    let mut bytes = vec![0u8; 64 * eta];
+    Keccak::shake256()
+        .absorb(seed)
+        .absorb(&[nonce])
+        .squeeze(&mut bytes[..]);
+    let bit = |i: usize| u32::from((bytes[i / 8] >> (i % 8)) & 1);
+    let mut f = [0u32; N];
+    for (i, c) in f.iter_mut().enumerate() {
+        let x: u32 = (0..eta).map(|j| bit(2 * i * eta + j)).sum();
+        let y: u32 = (0..eta).map(|j| bit(2 * i * eta + eta + j)).sum();
+        *c = (x + Q - y) % Q;
+    }
+    f
+}
+
+fn pke_keygen(d: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let g = sha3_512(&[d, &[K as u8]]);
+    let (rho, sigma) = g.split_at(32);
+    let mut s = [[0u32; N]; K];
+    let mut e = [[0u32; N]; K];
+    for (nonce, p) in s.iter_mut().chain(e.iter_mut()).enumerate() {
+        *p = sample_cbd(sigma, nonce as u8, ETA1);
+        ntt(p);
+    }
+    let mut ek = Vec::with_capacity(ENCAPS_KEY_LEN);
+    for (i, e) in e.iter().enumerate() {
+        let mut t = *e;
+        for (j, s) in s.iter().enumerate() {
+            mul_acc(&mut t, &sample_ntt(rho, i, j), s);
+        }
+        byte_encode(&t, 12, &mut ek);
+    }
+    ek.extend_from_slice(rho);
+    let mut dk = Vec::with_capacity(PKE_DECAPS_KEY_LEN);
+    for p in s.iter() {
+        byte_encode(p, 12, &mut dk);
+    }
+    (ek, dk)
+}
+
+fn pke_encrypt(ek: &[u8], m: &[u8], r: &[u8]) -> Vec<u8> {
+    let rho = &ek[384 * K..];
+    let mut t = [[0u32; N]; K];
+    for (i, p) in t.iter_mut().enumerate() {
+        *p = byte_decode(&ek[384 * i..384 * (i + 1)], 12);
+    }
+    let mut y = [[0u32; N]; K];
+    for (i, p) in y.iter_mut().enumerate() {
+        *p = sample_cbd(r, i as u8, ETA1);
+        ntt(p);
+    }
+    let mut c = Vec::with_capacity(CIPHERTEXT_LEN);
+    for i in 0..K {
+        let mut u = [0u32; N];
+        for (j, y) in y.iter().enumerate() {
+            mul_acc(&mut u, &sample_ntt(rho, j, i), y);
+        }
+        inv_ntt(&mut u);
+        add(&mut u, &sample_cbd(r, (K + i) as u8, ETA2));
+        compress(&mut u, DU);
+        byte_encode(&u, DU, &mut c);
+    }
+    let mut v = [0u32; N];
+    for (t, y) in t.iter().zip(y.iter()) {
+        mul_acc(&mut v, t, y);
+    }
+    inv_ntt(&mut v);
+    add(&mut v, &sample_cbd(r, (2 * K) as u8, ETA2));
+    let mut mu = byte_decode(m, 1);
+    decompress(&mut mu, 1);
+    add(&mut v, &mu);
+    compress(&mut v, DV);
+    byte_encode(&v, DV, &mut c);
+    c
+}
+
+fn pke_decrypt(dk: &[u8], c: &[u8]) -> Vec<u8> {
+    let du_len = 32 * DU as usize;
+    let mut w = byte_decode(&c[du_len * K..], DV);
+    decompress(&mut w, DV);
+    let mut su = [0u32; N];
+    for i in 0..K {
+        let mut u = byte_decode(&c[du_len * i..du_len * (i + 1)], DU);
+        decompress(&mut u, DU);
+        ntt(&mut u);
+        mul_acc(&mut su, &byte_decode(&dk[384 * i..384 * (i + 1)], 12), &u);
+    }
+    inv_ntt(&mut su);
+    for (x, y) in w.iter_mut().zip(su.iter()) {
+        *x = (*x + Q - y) % Q;
+    }
+    compress(&mut w, 1);
+    let mut m = Vec::with_capacity(32);
+    byte_encode(&w, 1, &mut m);
+    m
+}
+
+fn random_bytes() -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new().fill(&mut bytes).unwrap();
+    bytes
+}
+
+/// A decapsulation key, with the encapsulation key to send the peer.
+pub struct DecapsKey {
+    dk: Vec<u8>,
+}
+
+impl Drop for DecapsKey {
+    fn drop(&mut self) {
+        zeroize(&mut self.dk[..]);
+    }
+}
+
+impl DecapsKey {
+    pub fn generate() -> Self {
+        Self::from_seed(&random_bytes(), &random_bytes())
+    }
+
+    fn from_seed(d: &[u8], z: &[u8]) -> Self {
+        let (ek, mut dk) = pke_keygen(d);
+        dk.extend_from_slice(&ek[..]);
+        dk.extend_from_slice(&sha3_256(&[&ek[..]]));
+        dk.extend_from_slice(z);
+        Self { dk }
+    }
+
+    pub fn encaps_key(&self) -> &[u8] {
+        &self.dk[PKE_DECAPS_KEY_LEN..PKE_DECAPS_KEY_LEN + ENCAPS_KEY_LEN]
+    }
+
+    /// The shared secret of ciphertext `c`; one unrelated to the peer's if
+    /// `c` was tampered with.
+    pub fn decaps(&self, c: &[u8]) -> Result<[u8; 32], String> {
+        if c.len() != CIPHERTEXT_LEN {
+            return Err(String::from("invalid ML-KEM ciphertext"));
+        }
+        let ek = self.encaps_key();
+        let h = &self.dk[DECAPS_KEY_LEN - 64..DECAPS_KEY_LEN - 32];
+        let z = &self.dk[DECAPS_KEY_LEN - 32..];
+        let m = pke_decrypt(&self.dk[..PKE_DECAPS_KEY_LEN], c);
+        let g = sha3_512(&[&m[..], h]);
+        let mut rejected = [0u8; 32];
+        Keccak::shake256()
+            .absorb(z)
+            .absorb(c)
+            .squeeze(&mut rejected);
+        let valid = verify_slices_are_equal(&pke_encrypt(ek, &m[..], &g[32..])[..], c).is_ok();
+        // a branch free pick of the key
+        let mask = 0u8.wrapping_sub(valid as u8);
+        let mut key = [0u8; 32];
+        for i in 0..32 {
+            key[i] = (g[i] & mask) | (rejected[i] & !mask);
+        }
+        Ok(key)
+    }
+}
+
+/// A shared secret and the ciphertext carrying it to the owner of `ek`.
+pub fn encaps(ek: &[u8]) -> Result<([u8; 32], Vec<u8>), String> {
+    if ek.len() != ENCAPS_KEY_LEN {
+        return Err(String::from("invalid ML-KEM encapsulation key"));
+    }
+    // the coefficients have to be reduced, FIPS 203's modulus check
+    for i in 0..K {
+        let chunk = &ek[384 * i..384 * (i + 1)];
+        let t = byte_decode(chunk, 12);
+        if t.iter().any(|c| *c >= Q) {
+            return Err(String::from("invalid ML-KEM encapsulation key"));
+        }
+    }
+    Ok(encaps_with(ek, &random_bytes()))
+}
+
+/// `encaps` with the message `m` picked by the caller, for the known answer
+/// tests.
+fn encaps_with(ek: &[u8], m: &[u8]) -> ([u8; 32], Vec<u8>) {
+    let g = sha3_512(&[m, &sha3_256(&[ek])[..]]);
+    let c = pke_encrypt(ek, m, &g[32..]);
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&g[..32]);
+    (key, c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_sha3() {
+        assert_eq!(
+            hex(&sha3_256(&[&b""[..]])),
+            "a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a"
+        );
+        assert_eq!(
+            hex(&sha3_256(&[&b"ab"[..], &b"c"[..]])),
+            "3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532"
+        );
+        assert_eq!(
+            hex(&sha3_512(&[&b"abc"[..]])),
+            "b751850b1a57168a5693cd924b6b096e08f621827444f70d884f5d0240d2712e\
+             10e116e9192af3c91a7ec57647e3934057340b4cf408d5a56592f8274eec53f0"
+        );
+        let mut out = [0u8; 32];
+        Keccak::shake128().absorb(b"").squeeze(&mut out);
+        assert_eq!(
+            hex(&out),
+            "7f9c2ba4e88f827d616045507605853ed73b8093f6efbc88eb1a6eacfa66ef26"
+        );
+        // past the rate of 168 bytes
+        let long = [0xa3u8; 200];
+        let mut one = [0u8; 300];
+        Keccak::shake256().absorb(&long[..]).squeeze(&mut one);
+        let mut two = [0u8; 300];
+        let mut xof = Keccak::shake256();
+        xof.absorb(&long[..100]).absorb(&long[100..]);
+        xof.squeeze(&mut two[..150]);
+        xof.squeeze(&mut two[150..]);
+        assert_eq!(&one[..], &two[..]);
+    }
+
+    #[test]
+    fn test_ntt() {
+        let mut f = [0u32; N];
+        for (i, c) in f.iter_mut().enumerate() {
+            *c = (i as u32 * 13) % Q;
+        }
+        let orig = f;
+        ntt(&mut f);
+        inv_ntt(&mut f);
+        assert_eq!(&f[..], &orig[..]);
+    }
+
+    #[test]
+    fn test_mlkem_known_answers() {
+        // the answers of OpenSSL 3.5's ML-KEM-768 for the same seed,
+        // message and ciphertexts
+        let d: Vec<u8> = (0..32).collect();
+        let z: Vec<u8> = (32..64).collect();
+        let dk = DecapsKey::from_seed(&d[..], &z[..]);
+        assert_eq!(
+            hex(&sha3_256(&[dk.encaps_key()])),
+            "a24e16d8f8f9383a95b77050f4d9fd2f5733eec1d63ef3c23ebf9918173669a7"
+        );
+        let (key, mut c) = encaps_with(dk.encaps_key(), &[0x42; 32]);
+        assert_eq!(
+            hex(&key),
+            "b83e7f23b33f909715c7a50b0d4b1f6684d53e1f4b9056f803b29f058ccb5566"
+        );
+        assert_eq!(
+            hex(&sha3_256(&[&c[..]])),
+            "e9a0824664dba3f8f3c86ecb43a0c889030947ff01d276d04d46c204b62fc221"
+        );
+        assert_eq!(dk.decaps(&c[..]).unwrap(), key);
+        c[0] ^= 1;
+        assert_eq!(
+            hex(&dk.decaps(&c[..]).unwrap()),
+            "3816af13752429d4e8b800fd2c691b3254d09ed953cf287c99453d3d8057b41e"
+        );
+    }
+
+    #[test]
+    fn test_mlkem() {
+        let dk = DecapsKey::generate();
+        assert_eq!(dk.encaps_key().len(), ENCAPS_KEY_LEN);
+        let (key, mut c) = encaps(dk.encaps_key()).unwrap();
+        assert_eq!(c.len(), CIPHERTEXT_LEN);
+        assert_eq!(dk.decaps(&c[..]).unwrap(), key);
+        // implicit rejection
+        c[0] ^= 1;
+        assert_ne!(dk.decaps(&c[..]).unwrap(), key);
+        assert!(dk.decaps(&c[1..]).is_err());
+        let mut ek = dk.encaps_key().to_vec();
+        ek[0] = 0xff;
+        ek[1] |= 0x0f;
+        assert!(encaps(&ek[..]).is_err());
+    }
+}
@@ -1,15 +1,50 @@
+mod compress;
 mod crypto;
+mod datagram;
 mod event;
+mod kdf;
+mod kex;
 mod message;
+mod mlkem;
+mod padding;
+mod priority;
+mod replay;
+mod reverse;
 mod session;
+mod stats;
 mod stream;
+mod udp_relay;
+mod zstd;
 
-pub use self::crypto::{read_rmux_event, write_encrypt_event, CryptoContext};
-pub use self::event::{new_auth_event, Event, FLAG_AUTH};
+pub use self::compress::Compression;
+pub use self::crypto::{
+    cipher_suite_id, cipher_suite_method, expand_cipher_suites, is_supported_method,
+    preferred_cipher_suites, read_rmux_event, write_encrypt_event, CryptoContext, RekeyPolicy,
+};
+pub use self::datagram::DatagramFlow;
+pub use self::event::{
+    negotiate_version, new_auth_event, AuthTrailer, Event, FEATURE_EARLY_DATA, FEATURE_HYBRID_KEX,
+    FEATURE_PADDING, FLAG_AUTH, SUPPORTED_FEATURES,
+};
+pub use self::kdf::{session_key, user_session_key};
+pub use self::kex::{accept_key_share, KeyShare};
 pub use self::message::{AuthRequest, AuthResponse};
+pub use self::padding::PaddingPolicy;
+pub use self::priority::StreamPriority;
+pub use self::replay::{check_auth_stamp, ReplayFilter, DEFAULT_REPLAY_WINDOW_SECS};
+pub use self::reverse::{
+    allow_reverse_ports, forget_bound_service, get_bound_session, set_channel_ports,
+    set_channel_vhosts, PROTO_PORT, PROTO_VHOST,
+};
 pub use self::session::{
-    create_stream, dump_session_state, get_channel_session_size, handle_rmux_session,
-    process_rmux_session, routine_all_sessions, MuxContext,
+    create_session_stream, create_stream, create_stream_with_data, drain_sessions,
+    dump_session_state, get_channel_session_paths, get_channel_session_size,
+    get_channel_stream_count, handle_rmux_session, is_channel_pool_busy, is_early_data_channel,
+    open_datagram_flow, open_udp_association, pool_wakeup, process_rmux_session,
+    routine_all_sessions, session_stats, set_channel_datagrams, set_channel_pool,
+    shrink_channel_pool, Keepalive, MuxContext, SessionLimits,
 };
+pub use self::stream::StreamWindows;
+pub use self::udp_relay::{UdpAssociation, UDP_RELAY_QUEUE};
 
 pub const DEFAULT_RECV_BUF_SIZE: usize = 64 * 1024;
@@ -0,0 +1,121 @@
+use super::event::{new_data_event, Event, EVENT_HEADER_LEN, FLAG_PADDING};
+use crate::config::PaddingConfig;
+
+use rand::Rng;
+use std::time::Duration;
+
+const DEFAULT_PAD_BELOW: u32 = 1024;
+const DEFAULT_DUMMY_MAX_LEN: u32 = 256;
+
+/// How a session pads what it sends, nothing by default: random bytes in
+/// the auth frame, padding frames topping small frames up to a multiple of
+/// `bucket`, and dummy frames at random intervals.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PaddingPolicy {
+    pub handshake_max: u16,
+    pub bucket: u32,
+    pub pad_below: u32,
+    pub dummy_interval: Option<Duration>,
+    pub dummy_max_len: u32,
+}
+
+impl From<&PaddingConfig> for PaddingPolicy {
+    fn from(cfg: &PaddingConfig) -> Self {
+        Self {
+            handshake_max: cfg.handshake_max.unwrap_or(0),
+            bucket: cfg.bucket.unwrap_or(0),
+            pad_below: cfg.pad_below.unwrap_or(DEFAULT_PAD_BELOW),
+            dummy_interval: cfg
+                .dummy_interval_ms
+                .filter(|ms| *ms > 0)
+                .map(|ms| Duration::from_millis(ms as u64)),
+            dummy_max_len: cfg.dummy_max_len.unwrap_or(DEFAULT_DUMMY_MAX_LEN),
+        }
+    }
+}
+
+impl PaddingPolicy {
+    /// The policy for a peer which does not know `FLAG_PADDING`, only the
+    /// auth frame is padded.
+    pub fn without_frames(self) -> Self {
+        Self {
+            bucket: 0,
+            dummy_interval: None,
+            ..self
+        }
+    }
+
+    /// Random bytes to append to the auth frame.
+    pub fn handshake_padding(&self) -> usize {
+        if self.handshake_max == 0 {
+            return 0;
+        }
+        rand::thread_rng().gen_range(0, self.handshake_max as usize + 1)
+    }
+
+    /// The body length of the padding frame which tops a frame of `len`
+    /// bytes on the wire up to a multiple of the bucket, given the `tag`
+    /// bytes the cipher adds to a non empty body.
+    pub(super) fn bucket_padding(&self, len: usize, tag: usize) -> Option<usize> {
+        let bucket = self.bucket as usize;
+        if bucket == 0 || len >= self.pad_below as usize || len % bucket == 0 {
+            return None;
+        }
+        let mut gap = bucket - len % bucket;
+        loop {
+            if gap == EVENT_HEADER_LEN {
+                return Some(0);
+            }
+            if gap > EVENT_HEADER_LEN + tag {
+                return Some(gap - EVENT_HEADER_LEN - tag);
+            }
+            gap += bucket;
+        }
+    }
+
+    /// The pause before the next dummy frame and its length.
+    pub(super) fn next_dummy(&self) -> Option<(Duration, usize)> {
+        let interval = self.dummy_interval?;
+        let mut rng = rand::thread_rng();
+        let pause = rng.gen_range(0, interval.as_millis() as u64 + 1);
+        let len = rng.gen_range(0, self.dummy_max_len as usize + 1);
+        Some((Duration::from_millis(pause), len))
+    }
+}
+
+/// A frame of `len` random bytes the peer drops.
+pub(super) fn new_padding_event(len: usize) -> Event {
+    let mut body = vec![0u8; len];
+    rand::thread_rng().fill(&mut body[..]);
+    let mut ev = new_data_event(0, &body[..], false);
+    ev.header.set_flag(FLAG_PADDING);
+    ev
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_padding() {
+        let policy = PaddingPolicy {
+            bucket: 128,
+            pad_below: 1024,
+            ..Default::default()
+        };
+        for tag in [0, 16].iter() {
+            for len in 1..1024 {
+                match policy.bucket_padding(len, *tag) {
+                    None => assert_eq!(len % 128, 0),
+                    Some(0) => assert_eq!((len + EVENT_HEADER_LEN) % 128, 0),
+                    Some(n) => assert_eq!((len + EVENT_HEADER_LEN + n + tag) % 128, 0),
+                }
+            }
+        }
+        assert_eq!(policy.bucket_padding(1024, 16), None);
+        assert_eq!(PaddingPolicy::default().bucket_padding(10, 16), None);
+        let ev = new_padding_event(5);
+        assert_eq!(ev.header.flags(), FLAG_PADDING);
+        assert_eq!(ev.header.len(), 5);
+    }
+}
@@ -0,0 +1,375 @@
+use super::event::{Event, FLAG_COMPRESSED_DATA, FLAG_DATA, FLAG_FIN, FLAG_HALF_CLOSE, FLAG_SYN};
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use tokio::sync::mpsc;
+
+const PRIORITY_QUEUE_SIZE: usize = 16;
+/// Bytes a stream may have queued for the session's writer before its
+/// writes wait, a single frame always gets through.
+pub(super) const STREAM_QUEUE_BUDGET: usize = 64 * 1024;
+// bytes a stream may send per turn of the round robin over its class
+const QUANTUM: usize = 16 * 1024;
+
+/// Scheduling class of the frames a stream sends, picked by the routing
+/// rule which opened it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamPriority {
+    Interactive = 0,
+    Normal = 1,
+    Bulk = 2,
+}
+
+impl Default for StreamPriority {
+    fn default() -> Self {
+        StreamPriority::Normal
+    }
+}
+
+impl StreamPriority {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "interactive" => Some(StreamPriority::Interactive),
+            "normal" => Some(StreamPriority::Normal),
+            "bulk" => Some(StreamPriority::Bulk),
+            _ => None,
+        }
+    }
+    pub(super) fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(StreamPriority::Interactive),
+            1 => Some(StreamPriority::Normal),
+            2 => Some(StreamPriority::Bulk),
+            _ => None,
+        }
+    }
+    // frames a class may send per round while the others wait
+    fn weight(self) -> u32 {
+        match self {
+            StreamPriority::Interactive => 8,
+            StreamPriority::Normal => 4,
+            StreamPriority::Bulk => 1,
+        }
+    }
+}
+
+const PRIORITIES: [StreamPriority; 3] = [
+    StreamPriority::Interactive,
+    StreamPriority::Normal,
+    StreamPriority::Bulk,
+];
+
+/// The data bytes a stream has queued for the session's writer, taken by
+/// its writes and given back as the writer sends them.
+#[derive(Default)]
+pub(super) struct QueueBudget {
+    queued: AtomicUsize,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl QueueBudget {
+    /// Ready while the stream has room to queue a frame, otherwise its
+    /// writer is woken once the session's writer took some of the queue.
+    pub fn poll_room(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.queued() < STREAM_QUEUE_BUDGET {
+            return Poll::Ready(());
+        }
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
+        // the queue may have drained before the waker was stored
+        if self.queued() < STREAM_QUEUE_BUDGET {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+    pub fn take(&self, n: usize) {
+        self.queued.fetch_add(n, Ordering::SeqCst);
+    }
+    pub fn give_back(&self, n: usize) {
+        self.queued.fetch_sub(n, Ordering::SeqCst);
+        self.wake();
+    }
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+    /// Wakes a writer waiting for room, like when its stream closed.
+    pub fn wake(&self) {
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+}
+
+type QueueBudgets = Arc<Mutex<HashMap<u32, Arc<QueueBudget>>>>;
+
+/// The ends streams queue their frames on, one per class.
+#[derive(Clone)]
+pub(super) struct PrioritySenders {
+    txs: Vec<mpsc::Sender<Event>>,
+    budgets: QueueBudgets,
+}
+
+impl PrioritySenders {
+    pub fn get(&self, priority: StreamPriority) -> mpsc::Sender<Event> {
+        self.txs[priority as usize].clone()
+    }
+    /// The budget of stream `sid`, the receiver gives back what it sends.
+    pub fn budget(&self, sid: u32) -> Arc<QueueBudget> {
+        self.budgets
+            .lock()
+            .unwrap()
+            .entry(sid)
+            .or_insert_with(|| Arc::new(QueueBudget::default()))
+            .clone()
+    }
+}
+
+// the frames whose order within their stream matters, the rest of a class
+// goes ahead of them in the order it came
+fn is_stream_frame(ev: &Event) -> bool {
+    match ev.header.flags() {
+        FLAG_SYN | FLAG_DATA | FLAG_COMPRESSED_DATA | FLAG_HALF_CLOSE | FLAG_FIN => true,
+        _ => false,
+    }
+}
+
+fn is_data_frame(ev: &Event) -> bool {
+    let flags = ev.header.flags();
+    flags == FLAG_DATA || flags == FLAG_COMPRESSED_DATA
+}
+
+struct StreamQueue {
+    frames: VecDeque<Event>,
+    deficit: usize,
+}
+
+/// The frames of one class: control frames first, then those of its
+/// streams by deficit round robin, so each stream with frames queued sends
+/// about `QUANTUM` bytes per turn whatever the size of its frames.
+#[derive(Default)]
+struct ClassQueue {
+    control: VecDeque<Event>,
+    // the streams with frames queued, the one served first
+    active: VecDeque<u32>,
+    streams: HashMap<u32, StreamQueue>,
+}
+
+impl ClassQueue {
+    fn push(&mut self, ev: Event) {
+        if !is_stream_frame(&ev) {
+            self.control.push_back(ev);
+            return;
+        }
+        let sid = ev.header.stream_id;
+        let active = &mut self.active;
+        self.streams
+            .entry(sid)
+            .or_insert_with(|| {
+                active.push_back(sid);
+                StreamQueue {
+                    frames: VecDeque::new(),
+                    deficit: QUANTUM,
+                }
+            })
+            .frames
+            .push_back(ev);
+    }
+
+    fn pop(&mut self) -> Option<Event> {
+        if let Some(ev) = self.control.pop_front() {
+            return Some(ev);
+        }
+        loop {
+            let sid = *self.active.front()?;
+            let queue = self.streams.get_mut(&sid)?;
+            let cost = queue.frames.front().map_or(0, |ev| ev.body.len());
+            if cost > queue.deficit {
+                // its turn is over, the next one has a quantum more
+                queue.deficit += QUANTUM;
+                self.active.rotate_left(1);
+                continue;
+            }
+            queue.deficit -= cost;
+            let ev = queue.frames.pop_front();
+            if queue.frames.is_empty() {
+                self.streams.remove(&sid);
+                self.active.pop_front();
+            }
+            return ev;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.control.is_empty() && self.active.is_empty()
+    }
+}
+
+/// Weighted round robin over the classes: every round each class sends up
+/// to its weight in frames, an idle class leaves its share to the others.
+/// The class queues are drained as frames come, the budgets of the streams
+/// bound what they hold.
+pub(super) struct PriorityReceiver {
+    rxs: Vec<mpsc::Receiver<Event>>,
+    queues: Vec<ClassQueue>,
+    budgets: QueueBudgets,
+    credits: [u32; 3],
+}
+
+pub(super) fn priority_channel() -> (PrioritySenders, PriorityReceiver) {
+    let (txs, rxs) = PRIORITIES
+        .iter()
+        .map(|_| mpsc::channel(PRIORITY_QUEUE_SIZE))
+        .unzip();
+    let budgets = QueueBudgets::default();
+    let receiver = PriorityReceiver {
+        rxs,
+        queues: PRIORITIES.iter().map(|_| ClassQueue::default()).collect(),
+        budgets: budgets.clone(),
+        credits: [0; 3],
+    };
+    (PrioritySenders { txs, budgets }, receiver)
+}
+
+impl PriorityReceiver {
+    fn fill(&mut self) {
+        for (rx, queue) in self.rxs.iter_mut().zip(self.queues.iter_mut()) {
+            while let Ok(ev) = rx.try_recv() {
+                queue.push(ev);
+            }
+        }
+    }
+
+    // the stream's writer may queue what the session's writer took
+    fn on_sent(&self, ev: &Event) {
+        if !is_data_frame(ev) && ev.header.flags() != FLAG_FIN {
+            return;
+        }
+        let mut budgets = self.budgets.lock().unwrap();
+        if ev.header.flags() == FLAG_FIN {
+            if let Some(budget) = budgets.remove(&ev.header.stream_id) {
+                budget.wake();
+            }
+        } else if let Some(budget) = budgets.get(&ev.header.stream_id) {
+            budget.give_back(ev.body.len());
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<Event> {
+        self.fill();
+        for _ in 0..2 {
+            for p in PRIORITIES.iter() {
+                let i = *p as usize;
+                if self.credits[i] == 0 {
+                    continue;
+                }
+                if let Some(ev) = self.queues[i].pop() {
+                    self.credits[i] -= 1;
+                    self.on_sent(&ev);
+                    return Some(ev);
+                }
+            }
+            // the classes with credit left are idle, start a new round
+            for p in PRIORITIES.iter() {
+                self.credits[*p as usize] = p.weight();
+            }
+        }
+        None
+    }
+
+    /// Waits for the next frame of any class, `None` once all are closed.
+    pub async fn recv(&mut self) -> Option<Event> {
+        if let Some(ev) = self.try_recv() {
+            return Some(ev);
+        }
+        debug_assert!(self.queues.iter().all(|q| q.is_empty()));
+        let (interactive, rest) = self.rxs.split_at_mut(1);
+        let (normal, bulk) = rest.split_at_mut(1);
+        let ev = tokio::select! {
+            Some(ev) = interactive[0].recv() => ev,
+            Some(ev) = normal[0].recv() => ev,
+            Some(ev) = bulk[0].recv() => ev,
+            else => return None,
+        };
+        self.on_sent(&ev);
+        Some(ev)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::event::{new_data_event, new_fin_event, new_ping_event, FLAG_PING};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_weighted_order() {
+        let (senders, mut receiver) = priority_channel();
+        let mut bulk = senders.get(StreamPriority::Bulk);
+        let mut interactive = senders.get(StreamPriority::Interactive);
+        for _ in 0..PRIORITY_QUEUE_SIZE {
+            bulk.send(new_data_event(2, b"b", false)).await.unwrap();
+            interactive
+                .send(new_data_event(1, b"i", false))
+                .await
+                .unwrap();
+        }
+        let mut order = Vec::new();
+        while let Some(ev) = receiver.try_recv() {
+            order.push(ev.header.stream_id);
+        }
+        assert_eq!(order.len(), 2 * PRIORITY_QUEUE_SIZE);
+        // 8 interactive frames for each bulk one, bulk is never starved
+        assert_eq!(&order[..9], &[1, 1, 1, 1, 1, 1, 1, 1, 2]);
+        assert_eq!(&order[9..18], &[1, 1, 1, 1, 1, 1, 1, 1, 2]);
+        assert!(order[18..].iter().all(|id| *id == 2));
+        assert_eq!(
+            StreamPriority::from_name("bulk"),
+            Some(StreamPriority::Bulk)
+        );
+        assert_eq!(StreamPriority::from_u8(7), None);
+    }
+
+    #[test]
+    fn test_deficit_round_robin() {
+        let mut queue = ClassQueue::default();
+        // a stream of large frames and one of small frames, then a ping
+        for _ in 0..4 {
+            queue.push(new_data_event(1, &[0u8; QUANTUM][..], false));
+        }
+        for _ in 0..8 {
+            queue.push(new_data_event(3, &[0u8; QUANTUM / 4][..], false));
+        }
+        queue.push(new_fin_event(3, false));
+        queue.push(new_ping_event(0, false));
+        let mut order = Vec::new();
+        while let Some(ev) = queue.pop() {
+            order.push((ev.header.stream_id, ev.header.flags()));
+        }
+        assert_eq!(order[0], (0, FLAG_PING));
+        let ids: Vec<u32> = order[1..].iter().map(|(id, _)| *id).collect();
+        // a quantum each per turn, the FIN stays after its stream's data
+        assert_eq!(&ids[..6], &[1, 3, 3, 3, 3, 1]);
+        assert_eq!(order[11], (3, FLAG_FIN));
+        assert_eq!(&ids[11..], &[1, 1]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_queue_budget() {
+        let (senders, mut receiver) = priority_channel();
+        let budget = senders.budget(5);
+        budget.take(STREAM_QUEUE_BUDGET);
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert_eq!(budget.poll_room(&mut cx), Poll::Pending);
+        let mut tx = senders.get(StreamPriority::Normal);
+        tx.try_send(new_data_event(5, &[0u8; 1024][..], false))
+            .unwrap();
+        assert!(receiver.try_recv().is_some());
+        assert_eq!(budget.queued(), STREAM_QUEUE_BUDGET - 1024);
+        assert_eq!(budget.poll_room(&mut cx), Poll::Ready(()));
+        tx.try_send(new_fin_event(5, false)).unwrap();
+        assert!(receiver.try_recv().is_some());
+        assert!(senders.budgets.lock().unwrap().is_empty());
+    }
+}
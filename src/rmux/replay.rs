@@ -0,0 +1,78 @@
+//! Refusal of replayed handshakes. A client stamps its auth frame with the
+//! time and random bytes, inside the encryption; the remote takes a stamp
+//! only once and only near its own clock, so a probe replaying a captured
+//! auth frame gets no answer, like for any other garbage.
+use super::event::AUTH_NONCE_LEN;
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_REPLAY_WINDOW_SECS: u64 = 120;
+// beyond this many stamps in the window the oldest are forgotten early
+const MAX_STAMPS: usize = 1 << 16;
+
+type Nonce = [u8; AUTH_NONCE_LEN];
+
+lazy_static! {
+    static ref SEEN_STAMPS: Mutex<ReplayFilter> = Mutex::new(ReplayFilter::default());
+}
+
+/// The stamps taken within the window, in the order they arrived.
+#[derive(Default)]
+pub struct ReplayFilter {
+    order: VecDeque<(u64, Nonce)>,
+    seen: HashSet<Nonce>,
+}
+
+impl ReplayFilter {
+    pub fn check(&mut self, now: u64, window: u64, secs: u64, nonce: Nonce) -> bool {
+        if secs.saturating_add(window) < now || secs > now.saturating_add(window) {
+            return false;
+        }
+        // stamps older than the window fail the time check anyway
+        while let Some((t, n)) = self.order.front() {
+            if t.saturating_add(window) >= now && self.order.len() < MAX_STAMPS {
+                break;
+            }
+            self.seen.remove(n);
+            self.order.pop_front();
+        }
+        if !self.seen.insert(nonce) {
+            return false;
+        }
+        self.order.push_back((secs, nonce));
+        true
+    }
+}
+
+/// Whether an auth frame stamped at unix time `secs` with `nonce` is within
+/// `window` seconds of now and was not seen before, then it is remembered.
+pub fn check_auth_stamp(window: u64, secs: u64, nonce: &Nonce) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    SEEN_STAMPS.lock().unwrap().check(now, window, secs, *nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_filter() {
+        let mut filter = ReplayFilter::default();
+        let now = 1_000_000;
+        assert!(filter.check(now, 120, now - 5, [1; AUTH_NONCE_LEN]));
+        assert!(!filter.check(now, 120, now - 5, [1; AUTH_NONCE_LEN]));
+        assert!(filter.check(now, 120, now + 60, [2; AUTH_NONCE_LEN]));
+        // stale, or too far ahead of the clock
+        assert!(!filter.check(now, 120, now - 121, [3; AUTH_NONCE_LEN]));
+        assert!(!filter.check(now, 120, now + 121, [4; AUTH_NONCE_LEN]));
+        // forgotten once out of the window, where the time check refuses it
+        assert!(filter.check(now + 200, 120, now + 190, [5; AUTH_NONCE_LEN]));
+        assert!(!filter.seen.contains(&[1; AUTH_NONCE_LEN]));
+        assert!(!filter.check(now + 200, 120, now - 5, [1; AUTH_NONCE_LEN]));
+    }
+}
@@ -0,0 +1,153 @@
+//! Streams the remote opens towards a client, for services the client
+//! exposes through its sessions.
+//!
+//! A client binds each service it exposes, a virtual host or a port of the
+//! remote, by opening a bind stream on every new session. The remote relays
+//! requests for the service with a stream back on one of those sessions,
+//! which the client connects to the local target of the service.
+use crate::config::ReverseConfig;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+pub const PROTO_VHOST_BIND: &str = "vhost_bind";
+pub const PROTO_VHOST: &str = "vhost";
+pub const PROTO_PORT_BIND: &str = "port_bind";
+pub const PROTO_PORT: &str = "port";
+
+type ServiceKey = (&'static str, String);
+
+lazy_static! {
+    // client side, the local targets of the services each channel exposes
+    static ref CHANNEL_SERVICES: Mutex<HashMap<String, HashMap<ServiceKey, String>>> =
+        Mutex::new(HashMap::new());
+    // remote side, the sessions which bound each service
+    static ref BOUND_SESSIONS: Mutex<HashMap<ServiceKey, Vec<u32>>> = Mutex::new(HashMap::new());
+    static ref REVERSE_PORTS: Mutex<HashSet<u16>> = Mutex::new(HashSet::new());
+}
+
+fn service_key(proto: &'static str, name: &str) -> ServiceKey {
+    if proto == PROTO_VHOST {
+        return (proto, name.trim_end_matches('.').to_lowercase());
+    }
+    (proto, String::from(name))
+}
+
+/// The stream proto of the services a bind stream of `bind_proto` binds.
+pub(super) fn bound_proto(bind_proto: &str) -> Option<&'static str> {
+    match bind_proto {
+        PROTO_VHOST_BIND => Some(PROTO_VHOST),
+        PROTO_PORT_BIND => Some(PROTO_PORT),
+        _ => None,
+    }
+}
+
+fn set_channel_services<'a, I>(channel: &str, proto: &'static str, services: I)
+where
+    I: Iterator<Item = (&'a String, &'a String)>,
+{
+    let mut channels = CHANNEL_SERVICES.lock().unwrap();
+    let exposed = channels
+        .entry(String::from(channel))
+        .or_insert_with(HashMap::new);
+    exposed.retain(|(p, _), _| *p != proto);
+    for (name, target) in services {
+        exposed.insert(service_key(proto, name.as_str()), target.clone());
+    }
+}
+
+/// Sets the virtual hosts `channel` exposes, each mapped to a local
+/// `host:port`.
+pub fn set_channel_vhosts(channel: &str, vhosts: &HashMap<String, String>) {
+    set_channel_services(channel, PROTO_VHOST, vhosts.iter());
+}
+
+/// Sets the ports of the remote `channel` listens on for local targets.
+pub fn set_channel_ports(channel: &str, ports: &[ReverseConfig]) {
+    set_channel_services(
+        channel,
+        PROTO_PORT,
+        ports.iter().map(|p| (&p.remote, &p.local)),
+    );
+}
+
+/// Bind protos and names of the services `channel` exposes.
+pub(super) fn channel_binds(channel: &str) -> Vec<(&'static str, String)> {
+    match CHANNEL_SERVICES.lock().unwrap().get(channel) {
+        Some(exposed) => exposed
+            .keys()
+            .map(|(proto, name)| {
+                let bind = if *proto == PROTO_VHOST {
+                    PROTO_VHOST_BIND
+                } else {
+                    PROTO_PORT_BIND
+                };
+                (bind, name.clone())
+            })
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+pub(super) fn exposed_target(channel: &str, proto: &str, name: &str) -> Option<String> {
+    let proto = match proto {
+        PROTO_VHOST => PROTO_VHOST,
+        PROTO_PORT => PROTO_PORT,
+        _ => return None,
+    };
+    CHANNEL_SERVICES
+        .lock()
+        .unwrap()
+        .get(channel)
+        .and_then(|exposed| exposed.get(&service_key(proto, name)).cloned())
+}
+
+/// Returns true if nothing had bound the service before.
+pub(super) fn bind_session(proto: &'static str, name: &str, session_id: u32) -> bool {
+    let mut sessions = BOUND_SESSIONS.lock().unwrap();
+    let key = service_key(proto, name);
+    let first = !sessions.contains_key(&key);
+    let ids = sessions.entry(key).or_insert_with(Vec::new);
+    if !ids.contains(&session_id) {
+        ids.push(session_id);
+    }
+    first
+}
+
+/// Drops the session from its services, the services themselves stay known
+/// so a port listener is reused once a client binds it again.
+pub(super) fn unbind_session(session_id: u32) {
+    let mut sessions = BOUND_SESSIONS.lock().unwrap();
+    for ids in sessions.values_mut() {
+        ids.retain(|id| *id != session_id);
+    }
+}
+
+/// Forgets a service entirely, like a port whose listener failed.
+pub fn forget_bound_service(proto: &'static str, name: &str) {
+    BOUND_SESSIONS
+        .lock()
+        .unwrap()
+        .remove(&service_key(proto, name));
+}
+
+/// A remote session bound to the service, the most recent one first.
+pub fn get_bound_session(proto: &'static str, name: &str) -> Option<u32> {
+    BOUND_SESSIONS
+        .lock()
+        .unwrap()
+        .get(&service_key(proto, name))
+        .and_then(|ids| ids.last().cloned())
+}
+
+/// Ports clients may bind on this remote, none by default.
+pub fn allow_reverse_ports(ports: &[u16]) {
+    REVERSE_PORTS.lock().unwrap().extend(ports.iter().cloned());
+}
+
+pub(super) fn is_reverse_port_allowed(addr: &str) -> bool {
+    match addr.parse::<SocketAddr>() {
+        Ok(a) => REVERSE_PORTS.lock().unwrap().contains(&a.port()),
+        Err(_) => false,
+    }
+}
@@ -1,37 +1,65 @@
-use super::crypto::{read_rmux_event, CryptoContext};
+use super::compress::{decompress_body, Compression};
+use super::crypto::{read_rmux_event, CryptoContext, RekeyPolicy};
+use super::datagram::{parse_datagram, serve_datagram_flow, DatagramFlow, DatagramFlows};
 use super::event::{
-    get_event_type_str, new_ping_event, new_pong_event, new_routine_event, new_shutdown_event,
-    new_syn_event, new_window_update_event, Event, FLAG_DATA, FLAG_FIN, FLAG_PING, FLAG_PONG,
-    FLAG_ROUTINE, FLAG_SHUTDOWN, FLAG_SYN, FLAG_WIN_UPDATE,
+    get_event_type_str, new_fin_event, new_go_away_event, new_ping_event, new_pong_event,
+    new_rekey_event, new_routine_event, new_shutdown_event, new_syn_event, new_window_update_event,
+    Event, EVENT_HEADER_LEN, FEATURE_DATAGRAMS, FEATURE_EARLY_DATA, FEATURE_REKEY,
+    FEATURE_UDP_RELAY, FLAG_COMPRESSED_DATA, FLAG_DATA, FLAG_DATAGRAM, FLAG_FIN, FLAG_GO_AWAY,
+    FLAG_HALF_CLOSE, FLAG_PADDING, FLAG_PING, FLAG_PONG, FLAG_REKEY, FLAG_ROUTINE, FLAG_SHUTDOWN,
+    FLAG_SYN, FLAG_UDP_RELAY, FLAG_WIN_UPDATE, MIN_PROTOCOL_VERSION,
 };
 use super::message::ConnectRequest;
-use super::stream::MuxStream;
+use super::padding::{new_padding_event, PaddingPolicy};
+use super::priority::{priority_channel, PriorityReceiver, PrioritySenders, StreamPriority};
+use super::reverse::{
+    bind_session, bound_proto, channel_binds, exposed_target, is_reverse_port_allowed,
+    unbind_session, PROTO_PORT,
+};
+use super::stats::{SessionStats, StreamStats};
+use super::stream::{MuxStream, MuxStreamState, StreamWindows};
+use super::udp_relay::{handle_udp_relay_event, UdpAssociation, UdpAssociations};
 use super::DEFAULT_RECV_BUF_SIZE;
 use crate::channel::get_channel_stream;
 use crate::channel::ChannelStream;
-use crate::tunnel::relay;
-use crate::utils::{clear_channel, make_io_error, VBuf};
-use bytes::BytesMut;
-use futures::future::join3;
+use crate::config::{KeyConfig, PoolConfig};
+use crate::tunnel::{relay, start_reverse_listener};
+use crate::utils::{
+    clear_channel, make_io_error, register_stream_metrics, MeteredStream, RateLimitedReader,
+    RateLimitedWriter, ShapedWriter, TrafficShaper, VBuf,
+};
+use futures::future::{join3, pending};
 use futures::FutureExt;
 use rand::Rng;
 use std::collections::HashMap;
 use std::error::Error;
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc;
 use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::{mpsc, Notify};
+use tokio::time::delay_for;
 
-use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 
 lazy_static! {
     static ref CHANNEL_SESSIONS: Mutex<ChannelSessionManager> =
         Mutex::new(ChannelSessionManager::new());
+    static ref POOL_WAKEUP: Notify = Notify::new();
 }
 
+/// How long a new stream waits for room in a full pool.
+const POOL_FULL_WAIT: Duration = Duration::from_secs(5);
+const POOL_FULL_RETRY: Duration = Duration::from_millis(100);
+/// Health below which the round robin only picks a session when no other
+/// one is left: pings lost in a row, or the loss rate in thousandths.
+const DEGRADED_MISSED_PINGS: u32 = 2;
+const DEGRADED_LOSS_PERMILLE: u32 = 300;
+// queued frames which weigh a session's latency score like a stream more
+const SCORE_QUEUE_UNIT: u64 = 16;
+
 struct ChannelSessionManager {
     channels: HashMap<String, ChannelMuxSession>,
     retired: Vec<MuxSession>,
@@ -49,6 +77,20 @@ impl ChannelSessionManager {
 struct ChannelMuxSession {
     sessions: Vec<Option<MuxSession>>,
     cursor: AtomicU32,
+    pool: PoolConfig,
+    /// the server relays datagram flows
+    datagrams: bool,
+}
+
+impl ChannelMuxSession {
+    fn new(pool: PoolConfig) -> Self {
+        Self {
+            sessions: Vec::new(),
+            cursor: AtomicU32::new(0),
+            pool,
+            datagrams: false,
+        }
+    }
 }
 
 pub struct MuxSessionState {
@@ -56,14 +98,52 @@ pub struct MuxSessionState {
     last_pong_recv_time: AtomicU32,
     pub born_time: Instant,
     retired: AtomicBool,
+    /// a GOAWAY was sent, streams the peer opens are refused
+    going_away: AtomicBool,
     io_active_unix_secs: AtomicU32,
     closed: AtomicBool,
     process_event_state: AtomicU32,
     process_send_state: AtomicU32,
     process_recv_state: AtomicU32,
+    active_streams: AtomicU32,
+    /// url the session was dialed with, one of the channel's paths
+    path: String,
+    /// protocol version agreed in the handshake
+    version: u8,
+    settings: SessionSettings,
+    ping_pending: AtomicBool,
+    ping_send_ms: AtomicU64,
+    rtt_ms: AtomicU32,
+    /// smoothed rtt and its mean deviation over the pongs, like TCP's
+    srtt_ms: AtomicU32,
+    rtt_var_ms: AtomicU32,
+    /// moving average of the pings lost, in thousandths
+    loss_permille: AtomicU32,
+    missed_pings: AtomicU32,
+    /// pongs received, the keepalive tells answered pings by it
+    pongs: AtomicU32,
+    send_frames: AtomicU64,
+    /// frames the writer is done with, the rest of `send_frames` is queued
+    written_frames: AtomicU64,
+    /// last time the pool routine saw the session carrying streams
+    busy_unix_secs: AtomicU32,
+    recv_frames: AtomicU64,
+    send_bytes: AtomicU64,
+    recv_bytes: AtomicU64,
+    /// the open streams, kept for `session_stats`
+    streams: Mutex<HashMap<u32, Arc<MuxStreamState>>>,
+    datagrams: Arc<DatagramFlows>,
+    udp_associations: Arc<UdpAssociations>,
+    /// the listener user the session is accounted to
+    user: Option<Arc<KeyConfig>>,
 }
 
 impl MuxSessionState {
+    /// Whether the session's user may reach `target`, any session without
+    /// one may.
+    fn allows(&self, target: &str) -> bool {
+        self.user.as_ref().map_or(true, |u| u.is_allowed(target))
+    }
     fn ping_pong_gap(&self) -> i64 {
         let t1 = self.last_ping_send_time.load(Ordering::SeqCst);
         let t2 = self.last_pong_recv_time.load(Ordering::SeqCst);
@@ -78,6 +158,132 @@ impl MuxSessionState {
     fn is_closed(&self) -> bool {
         self.closed.load(Ordering::SeqCst)
     }
+    fn has_feature(&self, feature: u8) -> bool {
+        self.settings.has_feature(feature)
+    }
+    /// True if no pong arrived for `timeout_secs`, only meaningful for
+    /// sessions which send pings.
+    fn is_unhealthy(&self, now_unix_secs: u32, timeout_secs: u32) -> bool {
+        let pong = self.last_pong_recv_time.load(Ordering::SeqCst);
+        now_unix_secs.saturating_sub(pong) > timeout_secs
+    }
+    fn on_ping_sent(&self) {
+        if self.ping_pending.swap(true, Ordering::SeqCst) {
+            self.missed_pings.fetch_add(1, Ordering::SeqCst);
+            self.update_loss(true);
+        }
+        self.ping_send_ms.store(
+            self.born_time.elapsed().as_millis() as u64,
+            Ordering::SeqCst,
+        );
+    }
+    fn on_pong_recv(&self) {
+        self.pongs.fetch_add(1, Ordering::SeqCst);
+        if self.ping_pending.swap(false, Ordering::SeqCst) {
+            let now_ms = self.born_time.elapsed().as_millis() as u64;
+            let rtt = now_ms.saturating_sub(self.ping_send_ms.load(Ordering::SeqCst));
+            self.rtt_ms.store(rtt as u32, Ordering::SeqCst);
+            self.update_rtt(rtt as u32);
+            self.update_loss(false);
+            let missed = self.missed_pings.load(Ordering::SeqCst);
+            self.missed_pings
+                .store(missed.saturating_sub(1), Ordering::SeqCst);
+        }
+    }
+    fn update_rtt(&self, rtt: u32) {
+        let srtt = self.srtt_ms.load(Ordering::SeqCst);
+        if srtt == 0 {
+            self.srtt_ms.store(std::cmp::max(rtt, 1), Ordering::SeqCst);
+            self.rtt_var_ms.store(rtt / 2, Ordering::SeqCst);
+            return;
+        }
+        let var = self.rtt_var_ms.load(Ordering::SeqCst);
+        let delta = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+        self.rtt_var_ms
+            .store((var * 3 + delta) / 4, Ordering::SeqCst);
+        self.srtt_ms
+            .store(std::cmp::max((srtt * 7 + rtt) / 8, 1), Ordering::SeqCst);
+    }
+    fn update_loss(&self, lost: bool) {
+        let loss = self.loss_permille.load(Ordering::SeqCst);
+        let sample = if lost { 1000 } else { 0 };
+        self.loss_permille
+            .store((loss * 7 + sample) / 8, Ordering::SeqCst);
+    }
+    /// Lost pings or a loss rate which make the session a last resort.
+    fn is_degraded(&self) -> bool {
+        self.missed_pings.load(Ordering::SeqCst) >= DEGRADED_MISSED_PINGS
+            || self.loss_permille.load(Ordering::SeqCst) >= DEGRADED_LOSS_PERMILLE
+    }
+    /// Lower is better: the rtt a new stream is likely to see, the smoothed
+    /// one plus its deviation, weighted by the carried streams and the send
+    /// backlog, grown by the loss rate and doubled for every ping lost
+    /// since the last pong.
+    fn path_score(&self, load: u32) -> u64 {
+        let missed = std::cmp::min(self.missed_pings.load(Ordering::SeqCst), 8);
+        let srtt = self.srtt_ms.load(Ordering::SeqCst) as u64;
+        let var = self.rtt_var_ms.load(Ordering::SeqCst) as u64;
+        let rtt = srtt + 4 * var + 1;
+        let loss = self.loss_permille.load(Ordering::SeqCst) as u64;
+        let backlog = 1 + self.queue_depth() / SCORE_QUEUE_UNIT;
+        (rtt * (load as u64 + 1) * backlog * (1000 + 9 * loss) / 1000) << missed
+    }
+    fn on_frame_sent(&self, ev: &Event) {
+        self.send_frames.fetch_add(1, Ordering::SeqCst);
+        self.send_bytes
+            .fetch_add(ev.body.len() as u64, Ordering::SeqCst);
+    }
+    fn queue_depth(&self) -> u64 {
+        let written = self.written_frames.load(Ordering::SeqCst);
+        self.send_frames
+            .load(Ordering::SeqCst)
+            .saturating_sub(written)
+    }
+    fn on_frame_recv(&self, ev: &Event) {
+        self.recv_frames.fetch_add(1, Ordering::SeqCst);
+        self.recv_bytes
+            .fetch_add(ev.body.len() as u64, Ordering::SeqCst);
+    }
+    fn track_stream(&self, stream: &MuxStream) {
+        self.streams
+            .lock()
+            .unwrap()
+            .insert(stream.id(), stream.state.clone());
+    }
+    fn untrack_stream(&self, sid: u32) {
+        self.streams.lock().unwrap().remove(&sid);
+    }
+    fn snapshot(&self, channel: &str, session_id: u32) -> SessionStats {
+        let streams: Vec<StreamStats> = self
+            .streams
+            .lock()
+            .unwrap()
+            .values()
+            .map(|s| s.snapshot())
+            .collect();
+        SessionStats {
+            channel: String::from(channel),
+            session_id,
+            path: self.path.clone(),
+            user: self.user.as_ref().map(|u| u.id.clone()).unwrap_or_default(),
+            version: self.version,
+            features: self.settings.features,
+            age: self.born_time.elapsed(),
+            retired: self.is_retired(),
+            closed: self.is_closed(),
+            rtt_ms: self.rtt_ms.load(Ordering::SeqCst),
+            srtt_ms: self.srtt_ms.load(Ordering::SeqCst),
+            rtt_var_ms: self.rtt_var_ms.load(Ordering::SeqCst),
+            loss_permille: self.loss_permille.load(Ordering::SeqCst),
+            missed_pings: self.missed_pings.load(Ordering::SeqCst),
+            open_streams: streams.len(),
+            send_frames: self.send_frames.load(Ordering::SeqCst),
+            recv_frames: self.recv_frames.load(Ordering::SeqCst),
+            send_bytes: self.send_bytes.load(Ordering::SeqCst),
+            recv_bytes: self.recv_bytes.load(Ordering::SeqCst),
+            streams,
+        }
+    }
     fn get_io_idle_secs(&self, now_unix_secs: u32) -> u32 {
         let secs = self.io_active_unix_secs.load(Ordering::SeqCst);
         if secs == 0 {
@@ -90,20 +296,37 @@ impl MuxSessionState {
 pub struct MuxSession {
     id: u32,
     event_tx: mpsc::Sender<Event>,
+    priority_txs: PrioritySenders,
     pendding_streams: Vec<MuxStream>,
     stream_id_seed: AtomicU32,
     state: Arc<MuxSessionState>,
     max_alive_secs: u64,
+    /// pinged by its own keepalive rather than the routine
+    keepalive: bool,
+    /// streams opened on it by the pool so far
+    opened_streams: u32,
+    /// ids of the datagram flows and the udp associations opened on it
+    datagram_id_seed: AtomicU32,
+}
+
+impl MuxSession {
+    fn load(&self) -> u32 {
+        self.state.active_streams.load(Ordering::SeqCst) + self.pendding_streams.len() as u32
+    }
+    /// Streams whose SYN the session never got to, lost like its open ones.
+    fn abort_pendding_streams(&mut self) {
+        for mut stream in self.pendding_streams.drain(..) {
+            stream.state.session_lost.store(true, Ordering::SeqCst);
+            let _ = stream.close();
+        }
+    }
 }
 
 fn store_mux_session(channel: &str, session: MuxSession) {
     let cmap = &mut CHANNEL_SESSIONS.lock().unwrap().channels;
     //info!("{}0 store cmap size:{}", channel, cmap.len());
     if cmap.get_mut(channel).is_none() {
-        let csession = ChannelMuxSession {
-            sessions: Vec::new(),
-            cursor: AtomicU32::new(0),
-        };
+        let csession = ChannelMuxSession::new(PoolConfig::default());
         cmap.insert(String::from(channel), csession);
     }
     if let Some(csession) = cmap.get_mut(channel) {
@@ -124,7 +347,9 @@ fn erase_mux_session(channel: &str, sid: u32) {
         for s in csession.sessions.iter_mut() {
             if let Some(ss) = s {
                 if ss.id == sid {
-                    let _ = s.take();
+                    if let Some(mut session) = s.take() {
+                        session.abort_pendding_streams();
+                    }
                     return;
                 }
             }
@@ -132,12 +357,31 @@ fn erase_mux_session(channel: &str, sid: u32) {
     }
     for i in 0..holder.retired.len() {
         if holder.retired[i].id == sid {
-            holder.retired.remove(i);
+            holder.retired.remove(i).abort_pendding_streams();
             return;
         }
     }
 }
 
+/// Moves a session out of the pool: no new streams are opened on it and it
+/// closes once its streams are done.
+fn retire_mux_session(channel: &str, sid: u32) {
+    let mut holder = CHANNEL_SESSIONS.lock().unwrap();
+    let mut retired = None;
+    if let Some(csession) = holder.channels.get_mut(channel) {
+        for s in csession.sessions.iter_mut() {
+            if s.as_ref().map(|ss| ss.id) == Some(sid) {
+                retired = s.take();
+                break;
+            }
+        }
+    }
+    if let Some(session) = retired {
+        session.state.retired.store(true, Ordering::SeqCst);
+        holder.retired.push(session);
+    }
+}
+
 fn hanle_pendding_mux_streams(channel: &str, sid: u32, streams: &mut HashMap<u32, MuxStream>) {
     let cmap = &mut CHANNEL_SESSIONS.lock().unwrap().channels;
     if let Some(csession) = cmap.get_mut(channel) {
@@ -146,6 +390,7 @@ fn hanle_pendding_mux_streams(channel: &str, sid: u32, streams: &mut HashMap<u32
                 if ss.id == sid {
                     loop {
                         if let Some(s) = ss.pendding_streams.pop() {
+                            ss.state.track_stream(&s);
                             streams.insert(s.id(), s);
                         } else {
                             return;
@@ -157,6 +402,221 @@ fn hanle_pendding_mux_streams(channel: &str, sid: u32, streams: &mut HashMap<u32
     }
 }
 
+/// Sets the pool limits used for the sessions of `channel`.
+pub fn set_channel_pool(channel: &str, pool: PoolConfig) {
+    let cmap = &mut CHANNEL_SESSIONS.lock().unwrap().channels;
+    match cmap.get_mut(channel) {
+        Some(csession) => csession.pool = pool,
+        None => {
+            cmap.insert(String::from(channel), ChannelMuxSession::new(pool));
+        }
+    }
+}
+
+/// Closes the sessions of `channel` beyond `min_sessions` which carried no
+/// stream for `scale_idle_secs`, once the pool grew for a burst.
+pub fn shrink_channel_pool(channel: &str, min_sessions: usize) {
+    let now_unix_secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32;
+    let mut holder = CHANNEL_SESSIONS.lock().unwrap();
+    let mut idle = Vec::new();
+    if let Some(csession) = holder.channels.get_mut(channel) {
+        let idle_secs = csession.pool.scale_idle_secs();
+        let mut count = csession.sessions.iter().flatten().count();
+        for session in csession.sessions.iter_mut() {
+            if let Some(s) = session {
+                if s.load() > 0 {
+                    s.state
+                        .busy_unix_secs
+                        .store(now_unix_secs, Ordering::SeqCst);
+                    continue;
+                }
+                let busy = s.state.busy_unix_secs.load(Ordering::SeqCst);
+                if count > min_sessions && now_unix_secs.saturating_sub(busy) >= idle_secs {
+                    info!("[{}][{}]Close idle session of the pool.", channel, s.id);
+                    s.state.retired.store(true, Ordering::SeqCst);
+                    idle.push(session.take().unwrap());
+                    count -= 1;
+                }
+            }
+        }
+    }
+    for s in idle.iter() {
+        // closes the session since it is retired without streams
+        let _ = s.event_tx.clone().try_send(new_routine_event(0));
+    }
+    holder.retired.append(&mut idle);
+}
+
+/// Resolves when a pool ran out of room or rolled a session over, the
+/// routine dials sessions then rather than on its next tick.
+pub async fn pool_wakeup() {
+    POOL_WAKEUP.notified().await
+}
+
+/// Lets the pool of `channel` open datagram flows on the sessions whose
+/// server advertised `FEATURE_DATAGRAMS`.
+pub fn set_channel_datagrams(channel: &str, enabled: bool) {
+    let cmap = &mut CHANNEL_SESSIONS.lock().unwrap().channels;
+    cmap.entry(String::from(channel))
+        .or_insert_with(|| ChannelMuxSession::new(PoolConfig::default()))
+        .datagrams = enabled;
+}
+
+/// Opens a flow of datagrams to `addr` on a session of `channel`, `None`
+/// if the channel has no live session or does not carry datagrams.
+pub fn open_datagram_flow(channel: &str, addr: &str) -> Option<DatagramFlow> {
+    let cmap = &mut CHANNEL_SESSIONS.lock().unwrap().channels;
+    let csession = cmap.get_mut(channel)?;
+    if !csession.datagrams {
+        return None;
+    }
+    for _ in 0..csession.sessions.len() {
+        let idx = csession.cursor.fetch_add(1, Ordering::SeqCst) as usize % csession.sessions.len();
+        if let Some(session) = &csession.sessions[idx] {
+            if session.state.is_closed() || !session.state.has_feature(FEATURE_DATAGRAMS) {
+                continue;
+            }
+            let id = session.datagram_id_seed.fetch_add(1, Ordering::SeqCst);
+            return Some(DatagramFlow::new(
+                id,
+                addr,
+                session.priority_txs.get(StreamPriority::Interactive),
+                session.state.datagrams.clone(),
+            ));
+        }
+    }
+    None
+}
+
+/// Opens a UDP association on a session of `channel` whose server takes
+/// `FLAG_UDP_RELAY`, the datagrams coming back go to `reply_tx` along with
+/// their source; `None` if there is none or the channel does not carry
+/// datagrams.
+pub fn open_udp_association(
+    channel: &str,
+    reply_tx: mpsc::Sender<(String, Vec<u8>)>,
+) -> Option<UdpAssociation> {
+    let cmap = &mut CHANNEL_SESSIONS.lock().unwrap().channels;
+    let csession = cmap.get_mut(channel)?;
+    if !csession.datagrams {
+        return None;
+    }
+    for _ in 0..csession.sessions.len() {
+        let idx = csession.cursor.fetch_add(1, Ordering::SeqCst) as usize % csession.sessions.len();
+        if let Some(session) = &csession.sessions[idx] {
+            if session.state.is_closed() || !session.state.has_feature(FEATURE_UDP_RELAY) {
+                continue;
+            }
+            let id = session.datagram_id_seed.fetch_add(1, Ordering::SeqCst);
+            return UdpAssociation::open(
+                id,
+                session.priority_txs.get(StreamPriority::Interactive),
+                session.state.udp_associations.clone(),
+                reply_tx,
+            );
+        }
+    }
+    None
+}
+
+/// Hands a datagram to its flow, a server starts the flows clients open.
+fn handle_datagram_event(
+    channel: &str,
+    session_state: &Arc<MuxSessionState>,
+    priority_txs: &PrioritySenders,
+    ev: Event,
+) {
+    let flow_id = ev.header.stream_id;
+    let (addr, data) = match parse_datagram(&ev.body[..]) {
+        Some(v) => v,
+        None => {
+            warn!("[{}][{}]Invalid datagram.", channel, flow_id);
+            return;
+        }
+    };
+    let mut flows = session_state.datagrams.lock().unwrap();
+    if let Some(tx) = flows.get(&flow_id) {
+        if tx.send(data.to_vec()).is_ok() {
+            return;
+        }
+        flows.remove(&flow_id);
+    }
+    // clients only take the replies of their own flows
+    if !channel.is_empty() {
+        return;
+    }
+    if !session_state.allows(addr) {
+        warn!("[{}]Refused datagram flow to {}.", flow_id, addr);
+        return;
+    }
+    let (tx, rx) = mpsc::unbounded_channel();
+    let _ = tx.send(data.to_vec());
+    flows.insert(flow_id, tx);
+    tokio::spawn(serve_datagram_flow(
+        flow_id,
+        String::from(addr),
+        rx,
+        priority_txs.get(StreamPriority::Interactive),
+        session_state.datagrams.clone(),
+    ));
+}
+
+/// Urls of the live sessions of `channel`, one entry per session.
+pub fn get_channel_session_paths(channel: &str) -> Vec<String> {
+    let cmap = &mut CHANNEL_SESSIONS.lock().unwrap().channels;
+    match cmap.get_mut(channel) {
+        Some(csession) => csession
+            .sessions
+            .iter()
+            .flatten()
+            .map(|s| s.state.path.clone())
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Returns true if the channel has sessions and every one of them is
+/// carrying `max_streams_per_session` streams or has more than
+/// `scale_queue_depth` frames waiting to be written.
+pub fn is_channel_pool_busy(channel: &str) -> bool {
+    let cmap = &mut CHANNEL_SESSIONS.lock().unwrap().channels;
+    if let Some(csession) = cmap.get_mut(channel) {
+        let limit = csession.pool.max_streams_per_session();
+        let depth = csession.pool.scale_queue_depth();
+        if limit == 0 && depth == 0 {
+            return false;
+        }
+        let mut count = 0;
+        for s in csession.sessions.iter().flatten() {
+            let full_streams = limit > 0 && s.load() >= limit;
+            let full_queue = depth > 0 && s.state.queue_depth() > depth;
+            if !full_streams && !full_queue {
+                return false;
+            }
+            count += 1;
+        }
+        return count > 0;
+    }
+    false
+}
+
+/// Streams open or waiting to open on the live sessions of `channel`.
+pub fn get_channel_stream_count(channel: &str) -> usize {
+    let cmap = &mut CHANNEL_SESSIONS.lock().unwrap().channels;
+    match cmap.get_mut(channel) {
+        Some(csession) => csession
+            .sessions
+            .iter()
+            .flatten()
+            .map(|s| s.load() as usize)
+            .sum(),
+        None => 0,
+    }
+}
+
 pub fn get_channel_session_size(channel: &str) -> usize {
     let cmap = &mut CHANNEL_SESSIONS.lock().unwrap().channels;
     let mut len: usize = 0;
@@ -185,23 +645,31 @@ impl RoutineAction {
 }
 
 pub async fn routine_all_sessions() {
+    let now_unix_secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32;
     let mut actions = Vec::new();
     {
         let mut holder = CHANNEL_SESSIONS.lock().unwrap();
         let cmap = &mut holder.channels;
         let mut retired = Vec::new();
         for (channel, csession) in cmap.iter_mut() {
+            let health_timeout = csession.pool.health_timeout_secs();
             for session in csession.sessions.iter_mut() {
                 if let Some(s) = session {
-                    if s.state.ping_pong_gap() < -60 {
+                    if !channel.is_empty() && s.state.is_unhealthy(now_unix_secs, health_timeout) {
                         error!("[{}]Session heartbeat timeout.", s.id);
                         let shutdown = new_shutdown_event(0, false);
                         actions.push(RoutineAction::new(shutdown, s.event_tx.clone()));
+                        // the event loop may be stuck on a dead connection,
+                        // so do not rely on the shutdown event alone
                         s.state.retired.store(true, Ordering::SeqCst);
+                        s.state.closed.store(true, Ordering::SeqCst);
                         retired.push(session.take().unwrap());
                         continue;
                     } else {
-                        if !channel.is_empty() {
+                        if !channel.is_empty() && !s.keepalive {
                             let ping = new_ping_event(0, false);
                             actions.push(RoutineAction::new(ping, s.event_tx.clone()));
                         }
@@ -241,64 +709,382 @@ pub async fn routine_all_sessions() {
     }
 }
 
+fn select_by_latency(sessions: &[Option<MuxSession>], limit: u32) -> Option<usize> {
+    let mut selected: Option<(usize, bool, u64)> = None;
+    for (idx, s) in sessions.iter().enumerate() {
+        if let Some(session) = s {
+            if session.state.is_closed() {
+                continue;
+            }
+            let load = session.load();
+            let has_room = limit == 0 || load < limit;
+            let score = session.state.path_score(load);
+            let better = match selected {
+                Some((_, room, best)) => (has_room && !room) || (has_room == room && score < best),
+                None => true,
+            };
+            if better {
+                selected = Some((idx, has_room, score));
+            }
+        }
+    }
+    selected.map(|(idx, _, _)| idx)
+}
+
+/// Whether a session of `channel` takes the first data of a stream in its
+/// SYN, see `create_stream_with_data`.
+pub fn is_early_data_channel(channel: &str) -> bool {
+    let cmap = &CHANNEL_SESSIONS.lock().unwrap().channels;
+    cmap.get(channel).map_or(false, |csession| {
+        csession
+            .sessions
+            .iter()
+            .flatten()
+            .any(|s| s.state.has_feature(FEATURE_EARLY_DATA) && !s.state.is_closed())
+    })
+}
+
 pub async fn create_stream(
     channel: &str,
     proto: &str,
     addr: &str,
     relay_buf_size: usize,
+    priority: StreamPriority,
 ) -> Result<MuxStream, std::io::Error> {
-    let (stream, ev, ev_sender) = {
-        let mut stream: Option<MuxStream> = None;
-        let mut ev: Option<Event> = None;
-        let mut ev_sender: Option<mpsc::Sender<Event>> = None;
+    create_stream_with_data(channel, proto, addr, relay_buf_size, priority, &[])
+        .await
+        .map(|(stream, _)| stream)
+}
+
+/// Like `create_stream`, `early` goes in the SYN if the session picked
+/// takes early data, which the returned flag tells; the caller writes it
+/// to the stream otherwise.
+pub async fn create_stream_with_data(
+    channel: &str,
+    proto: &str,
+    addr: &str,
+    relay_buf_size: usize,
+    priority: StreamPriority,
+    early: &[u8],
+) -> Result<(MuxStream, bool), std::io::Error> {
+    let deadline = Instant::now() + POOL_FULL_WAIT;
+    let mut waiting = false;
+    loop {
+        match open_pool_stream(channel, proto, addr, relay_buf_size, priority, early)? {
+            PoolStream::Opened(stream, ev, mut ev_sender, sent) => {
+                if ev_sender.send(ev).await.is_ok() {
+                    return Ok((stream, sent));
+                }
+                return Err(make_io_error("no channel found."));
+            }
+            // the routine dials another session, or streams finish
+            PoolStream::Full if Instant::now() < deadline => {
+                if !waiting {
+                    waiting = true;
+                    POOL_WAKEUP.notify();
+                }
+                delay_for(POOL_FULL_RETRY).await;
+            }
+            PoolStream::Full => {
+                return Err(make_io_error("all sessions of channel are full."));
+            }
+            PoolStream::None => return Err(make_io_error("no channel found.")),
+        }
+    }
+}
+
+enum PoolStream {
+    /// the stream, its SYN, and whether the SYN carries the early data
+    Opened(MuxStream, Event, mpsc::Sender<Event>, bool),
+    /// every session carries `max_streams_per_session` streams
+    Full,
+    None,
+}
+
+fn open_pool_stream(
+    channel: &str,
+    proto: &str,
+    addr: &str,
+    relay_buf_size: usize,
+    priority: StreamPriority,
+    early: &[u8],
+) -> Result<PoolStream, std::io::Error> {
+    let mut holder = CHANNEL_SESSIONS.lock().unwrap();
+    let mut rollover = None;
+    let mut opened = PoolStream::None;
+    if let Some(csession) = holder.channels.get_mut(channel) {
+        let max_streams = csession.pool.max_concurrent_streams();
+        if max_streams > 0 {
+            let total: u32 = csession.sessions.iter().flatten().map(|s| s.load()).sum();
+            if total >= max_streams {
+                return Err(make_io_error("too many concurrent streams on channel."));
+            }
+        }
+        // prefer a healthy session below the per session limit and without
+        // a send backlog, otherwise the least loaded one below the limit
+        let limit = csession.pool.max_streams_per_session();
+        let depth = csession.pool.scale_queue_depth();
+        let mut selected: Option<usize> = None;
+        let mut live = false;
+        let by_latency = csession.pool.is_latency_scheduler();
+        if by_latency {
+            selected = select_by_latency(&csession.sessions, limit);
+            live = selected.is_some();
+        }
+        for _ in 0..csession.sessions.len() {
+            if by_latency {
+                break;
+            }
+            let mut idx = csession.cursor.fetch_add(1, Ordering::SeqCst);
+            idx %= csession.sessions.len() as u32;
+            let idx = idx as usize;
+            if let Some(session) = &csession.sessions[idx] {
+                if session.state.is_closed() {
+                    continue;
+                }
+                live = true;
+                if limit > 0 && session.load() >= limit {
+                    continue;
+                }
+                let backlogged = depth > 0 && session.state.queue_depth() > depth;
+                if !backlogged && !session.state.is_degraded() {
+                    selected = Some(idx);
+                    break;
+                }
+                let better = match selected {
+                    Some(i) => session.load() < csession.sessions[i].as_ref().unwrap().load(),
+                    None => true,
+                };
+                if better {
+                    selected = Some(idx);
+                }
+            }
+        }
+        if let Some(idx) = selected {
+            if limit > 0 && csession.sessions[idx].as_ref().unwrap().load() >= limit {
+                selected = None;
+            }
+        }
+        match selected {
+            Some(idx) => {
+                let session = csession.sessions[idx].as_mut().unwrap();
+                let (pendding_stream, cev, sent) = new_pendding_stream(
+                    channel,
+                    session,
+                    proto,
+                    addr,
+                    relay_buf_size,
+                    priority,
+                    early,
+                );
+                let ev_sender = session.event_tx.clone();
+                session.opened_streams += 1;
+                let lifetime = csession.pool.max_session_streams();
+                if lifetime > 0 && session.opened_streams >= lifetime {
+                    // done once its streams are, the routine dials a fresh one
+                    info!(
+                        "[{}][{}]Roll over session after {} streams.",
+                        channel, session.id, session.opened_streams
+                    );
+                    session.state.retired.store(true, Ordering::SeqCst);
+                    rollover = csession.sessions[idx].take();
+                }
+                opened = PoolStream::Opened(pendding_stream, cev, ev_sender, sent);
+            }
+            None if live => opened = PoolStream::Full,
+            None => {}
+        }
+    }
+    if let Some(session) = rollover {
+        holder.retired.push(session);
+        POOL_WAKEUP.notify();
+    }
+    Ok(opened)
+}
 
+fn new_pendding_stream(
+    channel: &str,
+    session: &mut MuxSession,
+    proto: &str,
+    addr: &str,
+    relay_buf_size: usize,
+    priority: StreamPriority,
+    early: &[u8],
+) -> (MuxStream, Event, bool) {
+    let creq = ConnectRequest {
+        proto: String::from(proto),
+        addr: String::from(addr),
+    };
+    let mut cev = new_syn_event(session.stream_id_seed.fetch_add(2, Ordering::SeqCst), &creq);
+    let settings = SessionSettings {
+        relay_buf_size,
+        ..session.state.settings
+    };
+    let early = if settings.has_feature(FEATURE_EARLY_DATA)
+        && early.len() <= settings.windows.send as usize
+    {
+        early
+    } else {
+        &[]
+    };
+    if priority != StreamPriority::Normal || !early.is_empty() {
+        // after the request, where peers without priorities stop reading
+        cev.body.push(priority as u8);
+        cev.body.extend_from_slice(early);
+        cev.header.set_len(cev.body.len() as u32);
+    }
+    let pendding_stream = MuxStream::new(
+        channel,
+        session.id,
+        cev.header.stream_id,
+        session.priority_txs.get(priority),
+        creq,
+        &settings,
+        session.priority_txs.budget(cev.header.stream_id),
+    );
+    if !early.is_empty() {
+        pendding_stream.on_early_data(early.len());
+    }
+    session.pendding_streams.push(pendding_stream.clone());
+    (pendding_stream, cev, !early.is_empty())
+}
+
+/// Like `create_stream`, but on the session `session_id` of `channel`
+/// rather than the one the pool would pick. The remote opens its streams
+/// to a client this way, on a session of that client.
+pub async fn create_session_stream(
+    channel: &str,
+    session_id: u32,
+    proto: &str,
+    addr: &str,
+    relay_buf_size: usize,
+) -> Result<MuxStream, std::io::Error> {
+    let opened = {
         let cmap = &mut CHANNEL_SESSIONS.lock().unwrap().channels;
-        //let mut cmap: HashMap<String, ChannelMuxSession> = HashMap::new();
-        if let Some(csession) = cmap.get_mut(channel) {
-            for _ in 0..csession.sessions.len() {
-                let mut idx = csession.cursor.fetch_add(1, Ordering::SeqCst);
-                idx %= csession.sessions.len() as u32;
-                if let Some(session) = &mut csession.sessions.as_mut_slice()[idx as usize] {
-                    let creq = ConnectRequest {
-                        proto: String::from(proto),
-                        addr: String::from(addr),
-                    };
-                    let cev =
-                        new_syn_event(session.stream_id_seed.fetch_add(2, Ordering::SeqCst), &creq);
-                    let pendding_stream = MuxStream::new(
+        cmap.get_mut(channel).and_then(|csession| {
+            csession
+                .sessions
+                .iter_mut()
+                .flatten()
+                .find(|s| s.id == session_id && !s.state.is_closed())
+                .map(|session| {
+                    let (stream, ev, _) = new_pendding_stream(
                         channel,
-                        session.id,
-                        cev.header.stream_id,
-                        session.event_tx.clone(),
-                        creq,
+                        session,
+                        proto,
+                        addr,
                         relay_buf_size,
+                        StreamPriority::Normal,
+                        &[],
                     );
-                    session.pendding_streams.push(pendding_stream.clone());
-                    stream = Some(pendding_stream);
-                    ev = Some(cev);
-                    ev_sender = Some(session.event_tx.clone());
-                    break;
-                }
+                    (stream, ev, session.event_tx.clone())
+                })
+        })
+    };
+    if let Some((stream, ev, mut ev_sender)) = opened {
+        if ev_sender.send(ev).await.is_ok() {
+            return Ok(stream);
+        }
+    }
+    Err(make_io_error("no session found."))
+}
+
+/// Binds the services `channel` exposes to the new session `session_id`,
+/// the bind streams are closed right after their SYN.
+async fn bind_session_services(channel: String, session_id: u32, relay_buf_size: usize) {
+    for (proto, name) in channel_binds(channel.as_str()) {
+        match create_session_stream(
+            channel.as_str(),
+            session_id,
+            proto,
+            name.as_str(),
+            relay_buf_size,
+        )
+        .await
+        {
+            Ok(mut stream) => {
+                let _ = stream.close();
+            }
+            Err(e) => {
+                error!(
+                    "[{}][{}]Failed to bind {} {}: {}",
+                    channel, session_id, proto, name, e
+                );
             }
         }
-        (stream, ev, ev_sender)
-    };
-    if stream.is_some() && ev_sender.unwrap().send(ev.unwrap()).await.is_ok() {
-        return Ok(stream.unwrap());
     }
-    Err(make_io_error("no channel found."))
 }
 
-async fn handle_rmux_stream(mut stream: MuxStream) -> Result<(), Box<dyn Error>> {
+/// Remote side of a bind stream, the first bind of an allowed port starts
+/// its listener.
+fn bind_service(proto: &'static str, name: &str, session_id: u32, relay_buf_size: usize) {
+    if proto == PROTO_PORT && !is_reverse_port_allowed(name) {
+        error!("[{}]Refused to bind port {}", session_id, name);
+        return;
+    }
+    if bind_session(proto, name, session_id) && proto == PROTO_PORT {
+        let addr = String::from(name);
+        let handle = start_reverse_listener(addr.clone(), relay_buf_size).map(move |r| {
+            if let Err(e) = r {
+                error!("Failed to listen on reverse port {}; error={}", addr, e);
+            }
+        });
+        tokio::spawn(handle);
+    }
+}
+
+async fn handle_rmux_stream(
+    mut stream: MuxStream,
+    user: Option<Arc<KeyConfig>>,
+) -> Result<(), Box<dyn Error>> {
     let stream_id = stream.state.stream_id;
     let relay_buf_size = stream.relay_buf_size();
-    let target = String::from(stream.target.addr.as_str());
+    // a client only connects the remote to the targets it exposes
+    let target = if stream.state.channel.is_empty() {
+        Some(String::from(stream.target.addr.as_str()))
+    } else {
+        exposed_target(
+            stream.state.channel.as_str(),
+            stream.target.proto.as_str(),
+            stream.target.addr.as_str(),
+        )
+    };
+    let target = match target {
+        Some(t) => t,
+        None => {
+            let _ = stream.close();
+            return Err(Box::new(make_io_error("refused stream from remote")));
+        }
+    };
+    if !user
+        .as_ref()
+        .map_or(true, |u| u.is_allowed(target.as_str()))
+    {
+        let _ = stream.close();
+        return Err(Box::new(make_io_error("target not allowed for the user")));
+    }
+    // server side sessions have no channel name, they are accounted to
+    // their user if any
+    let group = match user.as_ref() {
+        Some(u) => format!("rmux:{}", u.id),
+        None if stream.state.channel.is_empty() => String::from("rmux"),
+        None => stream.state.channel.clone(),
+    };
+    let (upload, download) = user
+        .as_ref()
+        .map(|u| (u.upload_buckets(), u.download_buckets()))
+        .unwrap_or_default();
+    let metrics = register_stream_metrics(group.as_str(), target.as_str());
     let result = get_channel_stream(String::from("direct"), target).await;
     match result {
         Ok(mut remote) => {
             {
                 let (mut ri, mut wi) = stream.split();
-                let (mut ro, mut wo) = remote.split();
+                let (ro, wo) = remote.split();
+                let mut ro =
+                    RateLimitedReader::new(MeteredStream::target(ro, metrics.metrics()), download);
+                let mut wo =
+                    RateLimitedWriter::new(MeteredStream::target(wo, metrics.metrics()), upload);
                 relay(
                     stream_id,
                     &mut ri,
@@ -324,9 +1110,10 @@ fn handle_syn(
     channel: &str,
     session_id: u32,
     ev: Event,
-    evtx: mpsc::Sender<Event>,
-    relay_buf_size: usize,
-) -> Option<MuxStream> {
+    priority_txs: &PrioritySenders,
+    session_state: &MuxSessionState,
+) -> Option<(MuxStream, Vec<u8>)> {
+    let settings = &session_state.settings;
     let connect_req: ConnectRequest = match bincode::deserialize(&ev.body[..]) {
         Ok(m) => m,
         Err(err) => {
@@ -339,19 +1126,48 @@ fn handle_syn(
             return None;
         }
     };
+    let tail = bincode::serialized_size(&connect_req)
+        .ok()
+        .and_then(|n| ev.body.get(n as usize..))
+        .unwrap_or(&[]);
+    let priority = tail
+        .first()
+        .and_then(|v| StreamPriority::from_u8(*v))
+        .unwrap_or_default();
+    // data the client sent along, see `new_pendding_stream`
+    let early = tail.get(1..).unwrap_or(&[]).to_vec();
     let sid = ev.header.stream_id;
     info!(
-        "[{}]Handle conn request:{} {}",
-        sid, connect_req.proto, connect_req.addr
+        "[{}]Handle conn request:{} {} {:?}",
+        sid, connect_req.proto, connect_req.addr, priority
     );
-    let stream = MuxStream::new(channel, session_id, sid, evtx, connect_req, relay_buf_size);
-    let handle = handle_rmux_stream(stream.clone()).map(move |r| {
+    if channel.is_empty() {
+        if let Some(proto) = bound_proto(connect_req.proto.as_str()) {
+            bind_service(
+                proto,
+                connect_req.addr.as_str(),
+                session_id,
+                settings.relay_buf_size,
+            );
+            return None;
+        }
+    }
+    let stream = MuxStream::new(
+        channel,
+        session_id,
+        sid,
+        priority_txs.get(priority),
+        connect_req,
+        settings,
+        priority_txs.budget(sid),
+    );
+    let handle = handle_rmux_stream(stream.clone(), session_state.user.clone()).map(move |r| {
         if let Err(e) = r {
             error!("[{}]Failed to handle rmux stream; error={}", sid, e);
         }
     });
     tokio::spawn(handle);
-    Some(stream)
+    Some((stream, early))
 }
 
 fn get_streams_stat_info(streams: &mut HashMap<u32, MuxStream>) -> String {
@@ -385,6 +1201,32 @@ fn log_session_state(sid: u32, now_unix_secs: u32, session_state: &Arc<MuxSessio
     stat_info.push_str(format!("IOIdleSecs:{}\n", idle_secs).as_str());
     stat_info.push_str(format!("Retired:{}\n", session_state.is_retired()).as_str());
     stat_info.push_str(format!("Closed:{}\n", session_state.is_closed()).as_str());
+    stat_info.push_str(format!("Path:{}\n", session_state.path).as_str());
+    stat_info.push_str(
+        format!(
+            "Version:{} Features:{:#x}\n",
+            session_state.version, session_state.settings.features
+        )
+        .as_str(),
+    );
+    stat_info.push_str(
+        format!(
+            "RttMs:{} SrttMs:{} RttVarMs:{} LossPermille:{} MissedPings:{}\n",
+            session_state.rtt_ms.load(Ordering::SeqCst),
+            session_state.srtt_ms.load(Ordering::SeqCst),
+            session_state.rtt_var_ms.load(Ordering::SeqCst),
+            session_state.loss_permille.load(Ordering::SeqCst),
+            session_state.missed_pings.load(Ordering::SeqCst)
+        )
+        .as_str(),
+    );
+    stat_info.push_str(
+        format!(
+            "ActiveStreams:{}\n",
+            session_state.active_streams.load(Ordering::SeqCst)
+        )
+        .as_str(),
+    );
     stat_info.push_str(
         format!(
             "ProcEventState:{}\n",
@@ -410,6 +1252,58 @@ fn log_session_state(sid: u32, now_unix_secs: u32, session_state: &Arc<MuxSessio
     stat_info
 }
 
+/// Announces to the peers of all sessions that they take no new streams,
+/// the open streams go on until done. Returns the count of open streams.
+pub fn drain_sessions() -> usize {
+    let mut actions = Vec::new();
+    let mut open_streams = 0;
+    {
+        let mut holder = CHANNEL_SESSIONS.lock().unwrap();
+        let mut draining = Vec::new();
+        for csession in holder.channels.values_mut() {
+            for session in csession.sessions.iter_mut() {
+                if let Some(s) = session.take() {
+                    s.state.retired.store(true, Ordering::SeqCst);
+                    draining.push(s);
+                }
+            }
+        }
+        for s in draining.iter() {
+            actions.push(RoutineAction::new(
+                new_go_away_event(false),
+                s.event_tx.clone(),
+            ));
+        }
+        holder.retired.append(&mut draining);
+        for s in holder.retired.iter() {
+            if !s.state.is_closed() {
+                open_streams += s.load() as usize;
+            }
+        }
+    }
+    for action in actions.iter_mut() {
+        let ev = action.ev.take().unwrap();
+        let _ = action.sender.try_send(ev);
+    }
+    open_streams
+}
+
+/// Snapshot of the counters of every session, retired ones included, for
+/// the metrics and admin endpoints.
+pub fn session_stats() -> Vec<SessionStats> {
+    let holder = CHANNEL_SESSIONS.lock().unwrap();
+    let mut stats = Vec::new();
+    for (channel, csession) in holder.channels.iter() {
+        for s in csession.sessions.iter().flatten() {
+            stats.push(s.state.snapshot(channel.as_str(), s.id));
+        }
+    }
+    for s in holder.retired.iter() {
+        stats.push(s.state.snapshot("", s.id));
+    }
+    stats
+}
+
 pub fn dump_session_state() -> String {
     let now_unix_secs = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -454,6 +1348,7 @@ fn handle_ping_event(
     is_remote: bool,
 ) {
     if !is_remote {
+        session_state.on_ping_sent();
         let now_unix_secs = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
@@ -501,6 +1396,7 @@ fn handle_fin_event(
     if let Some(mut stream) = streams.remove(&sid) {
         let _ = stream.close();
     }
+    session_state.untrack_stream(sid);
     if session_state.is_retired() && streams.is_empty() {
         session_state.closed.store(true, Ordering::SeqCst);
         return true;
@@ -511,12 +1407,34 @@ fn handle_fin_event(
 async fn send_local_event(
     mut ev: Event,
     wctx: &mut CryptoContext,
-    send_tx: &mut mpsc::Sender<Vec<u8>>,
+    padding: &PaddingPolicy,
+    send_tx: &mut mpsc::Sender<Vec<Vec<u8>>>,
 ) -> bool {
-    let mut buf = BytesMut::with_capacity(ev.body.len() + 64);
-    wctx.encrypt(&mut ev, &mut buf);
-    let evbuf = buf.to_vec();
-    let send_rc = send_tx.send(evbuf).await;
+    let mut frame = Vec::with_capacity(EVENT_HEADER_LEN + ev.body.len() + wctx.tag_len());
+    wctx.encrypt(&mut ev, &mut frame);
+    // written together, the frame and its padding look like one bucket
+    let bucket = padding.bucket_padding(frame.len(), wctx.tag_len());
+    let mut frames = vec![frame];
+    if let Some(n) = bucket {
+        let mut frame = Vec::with_capacity(EVENT_HEADER_LEN + n + wctx.tag_len());
+        wctx.encrypt(&mut new_padding_event(n), &mut frame);
+        frames.push(frame);
+    }
+    if wctx.rekey_due() {
+        let mut rekey = new_rekey_event();
+        let salt = rekey.body.clone();
+        let mut frame = Vec::new();
+        wctx.encrypt(&mut rekey, &mut frame);
+        frames.push(frame);
+        wctx.rekey(&salt[..]);
+    }
+    if wctx.key_exhausted() {
+        // the frame still goes out, no other after it
+        error!("Key limit reached without rekey, close session.");
+        let _ = send_tx.send(frames).await;
+        return false;
+    }
+    let send_rc = send_tx.send(frames).await;
     send_rc.is_ok()
 }
 
@@ -527,7 +1445,8 @@ async fn handle_local_event<'a>(
     session_state: &Arc<MuxSessionState>,
     ev: Event,
     wctx: &mut CryptoContext,
-    send_tx: &mut mpsc::Sender<Vec<u8>>,
+    padding: &PaddingPolicy,
+    send_tx: &mut mpsc::Sender<Vec<Vec<u8>>>,
 ) -> bool {
     if FLAG_SHUTDOWN == ev.header.flags() {
         return false;
@@ -543,7 +1462,13 @@ async fn handle_local_event<'a>(
     if FLAG_ROUTINE == ev.header.flags() {
         return !handle_routine_event(tunnel_id, streams, &session_state);
     }
-    send_local_event(ev, wctx, send_tx).await
+    let go_away = FLAG_GO_AWAY == ev.header.flags();
+    if go_away {
+        info!("[{}][{}]Draining session.", channel, tunnel_id);
+        session_state.going_away.store(true, Ordering::SeqCst);
+    }
+    session_state.on_frame_sent(&ev);
+    send_local_event(ev, wctx, padding, send_tx).await && !(go_away && streams.is_empty())
 }
 
 async fn process_event<'a>(
@@ -552,14 +1477,25 @@ async fn process_event<'a>(
     mut wctx: CryptoContext,
     session_state: Arc<MuxSessionState>,
     mut event_rx: mpsc::Receiver<Event>,
-    event_tx: mpsc::Sender<Event>,
-    mut send_tx: mpsc::Sender<Vec<u8>>,
-    relay_buf_size: usize,
+    mut priority_rx: PriorityReceiver,
+    priority_txs: PrioritySenders,
+    mut send_tx: mpsc::Sender<Vec<Vec<u8>>>,
 ) {
+    let padding = session_state.settings.padding;
     let mut streams = HashMap::new();
     while !session_state.closed.load(Ordering::SeqCst) {
         session_state.process_event_state.store(0, Ordering::SeqCst);
-        let rev = event_rx.recv().await;
+        session_state
+            .active_streams
+            .store(streams.len() as u32, Ordering::SeqCst);
+        // control and remote events first, the streams' frames by class
+        let rev = match event_rx.try_recv() {
+            Ok(ev) => Some(ev),
+            Err(_) => tokio::select! {
+                ev = event_rx.recv() => ev,
+                Some(ev) = priority_rx.recv() => Some(ev),
+            },
+        };
         if let Some(ev) = rev {
             if FLAG_PING == ev.header.flags() {
                 handle_ping_event(tunnel_id, &mut streams, &session_state, ev.remote);
@@ -573,6 +1509,7 @@ async fn process_event<'a>(
                     &session_state,
                     ev,
                     &mut wctx,
+                    &padding,
                     &mut send_tx,
                 )
                 .await
@@ -584,10 +1521,21 @@ async fn process_event<'a>(
                 break;
             }
             match ev.header.flags() {
+                FLAG_SYN if session_state.going_away.load(Ordering::SeqCst) => {
+                    let fin = new_fin_event(ev.header.stream_id, false);
+                    session_state.on_frame_sent(&fin);
+                    if !send_local_event(fin, &mut wctx, &padding, &mut send_tx).await {
+                        break;
+                    }
+                }
                 FLAG_SYN => {
-                    if let Some(stream) =
-                        handle_syn(channel, tunnel_id, ev, event_tx.clone(), relay_buf_size)
+                    if let Some((mut stream, early)) =
+                        handle_syn(channel, tunnel_id, ev, &priority_txs, &session_state)
                     {
+                        session_state.track_stream(&stream);
+                        if !early.is_empty() {
+                            stream.offer_data(early).await;
+                        }
                         streams.entry(stream.state.stream_id).or_insert(stream);
                     } else {
                     }
@@ -609,18 +1557,38 @@ async fn process_event<'a>(
                         );
                     }
                 }
+                FLAG_COMPRESSED_DATA => {
+                    if let Some(stream) = streams.get_mut(&ev.header.stream_id) {
+                        match decompress_body(&ev.body[..]) {
+                            Ok(data) if !data.is_empty() => {
+                                session_state.process_event_state.store(1, Ordering::SeqCst);
+                                stream.offer_data(data).await;
+                                session_state.process_event_state.store(2, Ordering::SeqCst);
+                            }
+                            _ => {
+                                error!(
+                                    "[{}][{}]Invalid compressed data, close stream.",
+                                    channel, ev.header.stream_id
+                                );
+                                let _ = stream.close();
+                            }
+                        }
+                    } else {
+                        warn!(
+                            "[{}][{}]No stream found for data event.",
+                            channel, ev.header.stream_id
+                        );
+                    }
+                }
                 FLAG_PING => {
-                    if !send_local_event(
-                        new_pong_event(ev.header.stream_id, false),
-                        &mut wctx,
-                        &mut send_tx,
-                    )
-                    .await
-                    {
+                    let pong = new_pong_event(ev.header.stream_id, false);
+                    session_state.on_frame_sent(&pong);
+                    if !send_local_event(pong, &mut wctx, &padding, &mut send_tx).await {
                         break;
                     }
                 }
                 FLAG_PONG => {
+                    session_state.on_pong_recv();
                     session_state.last_pong_recv_time.store(
                         SystemTime::now()
                             .duration_since(UNIX_EPOCH)
@@ -634,9 +1602,38 @@ async fn process_event<'a>(
                         stream.update_send_window(ev.header.len());
                     }
                 }
-                _ => {
-                    error!("invalid flags:{}", ev.header.flags());
-                    //None
+                FLAG_HALF_CLOSE => {
+                    if let Some(stream) = streams.get_mut(&ev.header.stream_id) {
+                        stream.offer_eof();
+                    }
+                }
+                FLAG_DATAGRAM => {
+                    handle_datagram_event(channel, &session_state, &priority_txs, ev);
+                }
+                FLAG_UDP_RELAY => {
+                    handle_udp_relay_event(
+                        channel,
+                        &session_state.udp_associations,
+                        session_state.user.as_deref(),
+                        priority_txs.get(StreamPriority::Interactive),
+                        ev,
+                    );
+                }
+                FLAG_GO_AWAY => {
+                    info!("[{}][{}]Peer is draining the session.", channel, tunnel_id);
+                    retire_mux_session(channel, tunnel_id);
+                    if streams.is_empty() {
+                        break;
+                    }
+                }
+                flags => {
+                    // a frame type the session never agreed on, nothing
+                    // after it can be trusted to parse
+                    error!(
+                        "[{}][{}]Unknown frame type:{} from peer speaking version {}, close session.",
+                        channel, tunnel_id, flags, session_state.version
+                    );
+                    break;
                 }
             }
         } else {
@@ -648,20 +1645,90 @@ async fn process_event<'a>(
     error!("[{}][{}]handle_event done", channel, tunnel_id);
     session_state.closed.store(true, Ordering::SeqCst);
     for (_, stream) in streams.iter_mut() {
+        stream.state.session_lost.store(true, Ordering::SeqCst);
         let _ = stream.close();
     }
+    session_state.streams.lock().unwrap().clear();
+    // ends the flows the session serves
+    session_state.datagrams.lock().unwrap().clear();
+    session_state.udp_associations.lock().unwrap().clear();
     clear_channel(&mut event_rx);
 
     let _ = send_tx.send(Vec::new()).await;
     session_state.process_event_state.store(6, Ordering::SeqCst);
 }
 
+/// Ping schedule of a client session: a ping every `interval`, lost if
+/// its pong takes longer than `timeout`; `max_missed` lost in a row mark
+/// the session dead.
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub max_missed: u32,
+}
+
+/// Limits a session is held to whatever the peer's: past `reconnect_after`
+/// it takes no new streams and the peer is asked to open another, past
+/// `max_age` it is closed, and no key seals or opens more than `key_bytes`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SessionLimits {
+    pub max_age: Option<Duration>,
+    pub reconnect_after: Option<Duration>,
+    pub key_bytes: Option<u64>,
+}
+
+impl SessionLimits {
+    pub fn new(
+        max_age_mins: Option<u64>,
+        reconnect_mins: Option<u64>,
+        max_mb_per_key: Option<u64>,
+    ) -> Self {
+        let mins = |m: Option<u64>| m.filter(|m| *m > 0).map(|m| Duration::from_secs(m * 60));
+        Self {
+            max_age: mins(max_age_mins),
+            reconnect_after: mins(reconnect_mins),
+            key_bytes: max_mb_per_key
+                .filter(|mb| *mb > 0)
+                .map(|mb| mb * 1024 * 1024),
+        }
+    }
+}
+
+/// What a session agreed on with its peer in the handshake, which all of
+/// its streams and frames follow.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct SessionSettings {
+    pub relay_buf_size: usize,
+    pub windows: StreamWindows,
+    pub compression: Compression,
+    pub padding: PaddingPolicy,
+    /// `FEATURE_*` agreed in the handshake
+    pub features: u8,
+}
+
+impl SessionSettings {
+    pub fn has_feature(&self, feature: u8) -> bool {
+        self.features & feature != 0
+    }
+}
+
 pub struct MuxContext<'a> {
     channel: &'a str,
     tunnel_id: u32,
     rctx: CryptoContext,
     wctx: CryptoContext,
     max_alive_secs: u64,
+    path: String,
+    windows: Option<StreamWindows>,
+    compression: Compression,
+    padding: PaddingPolicy,
+    rekey: RekeyPolicy,
+    limits: SessionLimits,
+    version: u8,
+    features: u8,
+    keepalive: Option<Keepalive>,
+    user: Option<Arc<KeyConfig>>,
 }
 impl<'a> MuxContext<'a> {
     pub fn new(
@@ -677,8 +1744,55 @@ impl<'a> MuxContext<'a> {
             rctx,
             wctx,
             max_alive_secs,
+            path: String::new(),
+            windows: None,
+            compression: Compression::None,
+            padding: PaddingPolicy::default(),
+            rekey: RekeyPolicy::default(),
+            limits: SessionLimits::default(),
+            version: MIN_PROTOCOL_VERSION,
+            features: 0,
+            keepalive: None,
+            user: None,
         }
     }
+    pub fn set_path(&mut self, path: &str) {
+        self.path = String::from(path);
+    }
+    /// Stream windows agreed in the handshake, derived from the relay
+    /// buffer size when unset.
+    pub fn set_windows(&mut self, windows: StreamWindows) {
+        self.windows = Some(windows);
+    }
+    /// Compression agreed in the handshake, none when unset.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+    pub fn set_padding(&mut self, padding: PaddingPolicy) {
+        self.padding = padding;
+    }
+    /// When to rotate the key of what the session sends, applied if the
+    /// peer agreed on `FEATURE_REKEY`.
+    pub fn set_rekey(&mut self, rekey: RekeyPolicy) {
+        self.rekey = rekey;
+    }
+    pub fn set_limits(&mut self, limits: SessionLimits) {
+        self.limits = limits;
+    }
+    /// Protocol version and `FEATURE_*` agreed in the handshake, the oldest
+    /// version without any feature when unset.
+    pub fn set_protocol(&mut self, version: u8, features: u8) {
+        self.version = version;
+        self.features = features;
+    }
+    pub fn set_keepalive(&mut self, keepalive: Keepalive) {
+        self.keepalive = Some(keepalive);
+    }
+    /// The listener user who presented its key id in the handshake, the
+    /// session's streams are held to its rules.
+    pub fn set_user(&mut self, user: KeyConfig) {
+        self.user = Some(Arc::new(user));
+    }
 }
 
 pub async fn process_rmux_session<'a, R, W>(
@@ -694,24 +1808,74 @@ where
     let channel = ctx.channel;
     let tunnel_id = ctx.tunnel_id;
     let mut rctx = ctx.rctx;
-    let wctx = ctx.wctx;
+    let mut wctx = ctx.wctx;
+    let limits = ctx.limits;
+    if ctx.features & FEATURE_REKEY != 0 {
+        wctx.set_rekey(ctx.rekey.within(limits.key_bytes));
+    }
+    rctx.set_key_limit(limits.key_bytes);
+    wctx.set_key_limit(limits.key_bytes);
     let max_alive_secs = ctx.max_alive_secs;
+    let keepalive = ctx.keepalive;
+    let padding = ctx.padding;
+    let settings = SessionSettings {
+        relay_buf_size,
+        windows: ctx.windows.unwrap_or_else(|| {
+            let window = StreamWindows::default_window(relay_buf_size);
+            StreamWindows {
+                send: window,
+                recv: window,
+            }
+        }),
+        compression: ctx.compression,
+        padding,
+        features: ctx.features,
+    };
     let (mut event_tx, event_rx) = mpsc::channel::<Event>(16);
     let (send_tx, mut send_rx) = mpsc::channel(16);
+    let (priority_txs, priority_rx) = priority_channel();
 
     //let is_server = channel.is_empty();
 
     let seed = if channel.is_empty() { 2 } else { 1 };
+    let now_unix_secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32;
     let session_state = MuxSessionState {
         last_ping_send_time: AtomicU32::new(0),
-        last_pong_recv_time: AtomicU32::new(0),
+        // counts as answered at birth so the health check has a base
+        last_pong_recv_time: AtomicU32::new(now_unix_secs),
         born_time: Instant::now(),
         retired: AtomicBool::new(false),
+        going_away: AtomicBool::new(false),
         io_active_unix_secs: AtomicU32::new(0),
         closed: AtomicBool::new(false),
         process_event_state: AtomicU32::new(0),
         process_send_state: AtomicU32::new(0),
         process_recv_state: AtomicU32::new(0),
+        active_streams: AtomicU32::new(0),
+        path: ctx.path,
+        version: ctx.version,
+        settings,
+        ping_pending: AtomicBool::new(false),
+        ping_send_ms: AtomicU64::new(0),
+        rtt_ms: AtomicU32::new(0),
+        srtt_ms: AtomicU32::new(0),
+        rtt_var_ms: AtomicU32::new(0),
+        loss_permille: AtomicU32::new(0),
+        missed_pings: AtomicU32::new(0),
+        pongs: AtomicU32::new(0),
+        send_frames: AtomicU64::new(0),
+        written_frames: AtomicU64::new(0),
+        busy_unix_secs: AtomicU32::new(now_unix_secs),
+        recv_frames: AtomicU64::new(0),
+        send_bytes: AtomicU64::new(0),
+        recv_bytes: AtomicU64::new(0),
+        streams: Mutex::new(HashMap::new()),
+        datagrams: Arc::new(Mutex::new(HashMap::new())),
+        udp_associations: Arc::new(Mutex::new(HashMap::new())),
+        user: ctx.user,
     };
     let session_state = Arc::new(session_state);
     //let send_session_state = session_state.clone();
@@ -719,19 +1883,38 @@ where
     let mux_session = MuxSession {
         id: tunnel_id,
         event_tx: event_tx.clone(),
+        priority_txs: priority_txs.clone(),
         pendding_streams: Vec::new(),
         stream_id_seed: AtomicU32::new(seed),
         state: session_state.clone(),
         max_alive_secs,
+        keepalive: keepalive.is_some(),
+        opened_streams: 0,
+        datagram_id_seed: AtomicU32::new(1),
         //streams: HashMap::new(),
     };
     info!(
-        "[{}][{}]Start tunnel session with crypto {} {}",
-        channel, tunnel_id, rctx.nonce, rctx.key
+        "[{}][{}]Start tunnel session version {} features {:#x} with crypto {}",
+        channel, tunnel_id, session_state.version, session_state.settings.features, rctx.nonce
     );
     store_mux_session(channel, mux_session);
+    if !channel.is_empty() {
+        tokio::spawn(bind_session_services(
+            String::from(channel),
+            tunnel_id,
+            relay_buf_size,
+        ));
+    }
 
     let (mut close_tx, mut close_rx) = mpsc::channel::<()>(1);
+    let (mut dead_tx, mut dead_rx) = mpsc::channel::<()>(1);
+    let mut expired_tx = dead_tx.clone();
+    let limits_state = session_state.clone();
+    let mut limits_tx = event_tx.clone();
+    let keepalive_state = session_state.clone();
+    let mut keepalive_tx = event_tx.clone();
+    let padding_state = session_state.clone();
+    let mut padding_tx = event_tx.clone();
     //let mut drop = close_rx.fuse();
 
     let mut handle_recv_event_tx = event_tx.clone();
@@ -752,7 +1935,25 @@ where
                                 Ordering::SeqCst,
                             );
                             ev.remote = true;
-                            if FLAG_DATA != ev.header.flags() {
+                            recv_session_state.on_frame_recv(&ev);
+                            if FLAG_REKEY != ev.header.flags() && !rctx.count_opened(ev.body.len()) {
+                                error!(
+                                    "[{}][{}]Peer sealed more than the key limit, close session.",
+                                    channel, tunnel_id
+                                );
+                                break;
+                            }
+                            if FLAG_PADDING == ev.header.flags() {
+                                continue;
+                            }
+                            if FLAG_REKEY == ev.header.flags() {
+                                // the frames after it are sealed with the next key
+                                rctx.rekey(&ev.body[..]);
+                                info!("[{}][{}]Peer rotated its key.", channel, tunnel_id);
+                                continue;
+                            }
+                            let flags = ev.header.flags();
+                            if FLAG_DATA != flags && FLAG_COMPRESSED_DATA != flags {
                                 info!(
                                     "[{}][{}][{}]remote recv event type:{}, len:{}",
                                     channel,
@@ -805,83 +2006,100 @@ where
         wctx,
         session_state.clone(),
         event_rx,
-        event_tx.clone(),
+        priority_rx,
+        priority_txs,
         send_tx.clone(),
-        relay_buf_size,
     );
 
     let handle_send = async {
         let mut vbuf = VBuf::new();
-        while !handle_send_session_state.closed.load(Ordering::SeqCst) {
-            // if let Some(data) = send_rx.recv().await {
-            //     if data.is_empty() {
-            //         break;
-            //     }
-            //     if let Err(e) = wi.write_all(&data[..]).await {
-            //         error!("Failed to write data with err:{}", e);
-            //         break;
-            //     }
-            //     send_session_state.io_active_unix_secs.store(
-            //         SystemTime::now()
-            //             .duration_since(UNIX_EPOCH)
-            //             .unwrap()
-            //             .as_secs() as u32,
-            //         Ordering::SeqCst,
-            //     );
-            // } else {
-            //     break;
-            // }
-
-            if vbuf.vlen() == 0 {
-                if let Some(data) = send_rx.recv().await {
-                    if data.is_empty() {
+        let send_loop = async {
+            while !handle_send_session_state.closed.load(Ordering::SeqCst) {
+                // if let Some(data) = send_rx.recv().await {
+                //     if data.is_empty() {
+                //         break;
+                //     }
+                //     if let Err(e) = wi.write_all(&data[..]).await {
+                //         error!("Failed to write data with err:{}", e);
+                //         break;
+                //     }
+                //     send_session_state.io_active_unix_secs.store(
+                //         SystemTime::now()
+                //             .duration_since(UNIX_EPOCH)
+                //             .unwrap()
+                //             .as_secs() as u32,
+                //         Ordering::SeqCst,
+                //     );
+                // } else {
+                //     break;
+                // }
+
+                // the frames of an event go into one vectored write, as
+                // separate slices
+                if vbuf.vlen() == 0 {
+                    if let Some(frames) = send_rx.recv().await {
+                        if frames.is_empty() {
+                            break;
+                        }
+                        frames.into_iter().for_each(|f| {
+                            vbuf.push(f);
+                        });
+                    } else {
                         break;
                     }
-                    vbuf.push(data);
-                } else {
-                    break;
                 }
-            }
-            let mut exit = false;
-            while vbuf.vlen() < 60 {
-                match send_rx.try_recv() {
-                    Ok(data) => {
-                        if data.is_empty() {
+                let mut exit = false;
+                while vbuf.vlen() < 60 {
+                    match send_rx.try_recv() {
+                        Ok(frames) => {
+                            if frames.is_empty() {
+                                exit = true;
+                                break;
+                            } else {
+                                frames.into_iter().for_each(|f| {
+                                    vbuf.push(f);
+                                });
+                            }
+                        }
+                        Err(TryRecvError::Closed) => {
                             exit = true;
                             break;
-                        } else {
-                            vbuf.push(data);
                         }
-                    }
-                    Err(TryRecvError::Closed) => {
-                        exit = true;
-                        break;
-                    }
-                    Err(TryRecvError::Empty) => {
-                        break;
+                        Err(TryRecvError::Empty) => {
+                            break;
+                        }
                     }
                 }
-            }
-            if exit {
-                break;
-            }
-            session_state.io_active_unix_secs.store(
-                SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs() as u32,
-                Ordering::SeqCst,
-            );
-            match wi.write_buf(&mut vbuf).await {
-                Ok(n) => {
-                    if 0 == n {
+                if exit {
+                    break;
+                }
+                session_state.io_active_unix_secs.store(
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs() as u32,
+                    Ordering::SeqCst,
+                );
+                let queued = vbuf.vlen();
+                match wi.write_buf(&mut vbuf).await {
+                    Ok(n) => {
+                        if 0 == n {
+                            break;
+                        }
+                        session_state
+                            .written_frames
+                            .fetch_add((queued - vbuf.vlen()) as u64, Ordering::SeqCst);
+                    }
+                    Err(_) => {
                         break;
                     }
                 }
-                Err(_) => {
-                    break;
-                }
             }
+        };
+        // a dead connection may block the write forever
+        tokio::select! {
+            _ = send_loop => {},
+            Some(_) = dead_rx.recv() => {},
         }
         handle_send_session_state
             .process_send_state
@@ -902,25 +2120,110 @@ where
             .store(2, Ordering::SeqCst);
     };
 
-    join3(handle_recv, handle_event, handle_send).await;
+    let handle_keepalive = async move {
+        if let Some(ka) = keepalive {
+            let mut lost = 0;
+            while !keepalive_state.is_closed() {
+                let pongs = keepalive_state.pongs.load(Ordering::SeqCst);
+                // a full queue means a stuck session, the ping counts as lost
+                let _ = keepalive_tx.try_send(new_ping_event(0, false));
+                delay_for(ka.timeout).await;
+                if keepalive_state.pongs.load(Ordering::SeqCst) != pongs {
+                    lost = 0;
+                } else {
+                    lost += 1;
+                    warn!(
+                        "[{}][{}]Ping timeout, {} in a row",
+                        channel, tunnel_id, lost
+                    );
+                    if lost >= ka.max_missed {
+                        error!(
+                            "[{}][{}]Session dead after {} lost pings.",
+                            channel, tunnel_id, lost
+                        );
+                        // no new streams, the routine dials a replacement
+                        keepalive_state.retired.store(true, Ordering::SeqCst);
+                        erase_mux_session(channel, tunnel_id);
+                        let _ = dead_tx.send(()).await;
+                        break;
+                    }
+                }
+                delay_for(ka.interval.checked_sub(ka.timeout).unwrap_or_default()).await;
+            }
+        }
+        // done when the session is
+        pending::<()>().await
+    };
+
+    let handle_dummy = async move {
+        while let Some((pause, len)) = padding.next_dummy() {
+            delay_for(pause).await;
+            if padding_state.is_closed() {
+                break;
+            }
+            let _ = padding_tx.try_send(new_padding_event(len));
+        }
+        pending::<()>().await
+    };
+
+    let handle_limits = async move {
+        let born = Instant::now();
+        let reconnect = limits
+            .reconnect_after
+            .filter(|d| limits.max_age.map_or(true, |age| *d < age));
+        if let Some(after) = reconnect {
+            delay_for(after).await;
+            if !limits_state.is_closed() {
+                info!(
+                    "[{}][{}]Session reached its reconnect interval, draining it.",
+                    channel, tunnel_id
+                );
+                // the client dials a replacement, for the GOAWAY if on the
+                // server's side
+                retire_mux_session(channel, tunnel_id);
+                let _ = limits_tx.send(new_go_away_event(false)).await;
+            }
+        }
+        if let Some(age) = limits.max_age {
+            delay_for(age.checked_sub(born.elapsed()).unwrap_or_default()).await;
+            if !limits_state.is_closed() {
+                error!(
+                    "[{}][{}]Session reached its max age, close it.",
+                    channel, tunnel_id
+                );
+                limits_state.retired.store(true, Ordering::SeqCst);
+                erase_mux_session(channel, tunnel_id);
+                let _ = expired_tx.send(()).await;
+            }
+        }
+        pending::<()>().await
+    };
+
+    tokio::select! {
+        _ = join3(handle_recv, handle_event, handle_send) => {},
+        _ = handle_keepalive => {},
+        _ = handle_dummy => {},
+        _ = handle_limits => {},
+    }
     erase_mux_session(channel, tunnel_id);
+    if channel.is_empty() {
+        unbind_session(tunnel_id);
+    }
     info!("[{}][{}]Close tunnel session", channel, tunnel_id);
     Ok(())
 }
 
+/// Runs the session `ctx` describes over a plain TCP connection.
 pub async fn handle_rmux_session(
-    channel: &str,
-    tunnel_id: u32,
+    ctx: MuxContext<'_>,
     mut inbound: TcpStream,
-    rctx: CryptoContext,
-    wctx: CryptoContext,
-    max_alive_secs: u64,
     relay_buf_size: usize,
+    shaper: Option<Arc<TrafficShaper>>,
     //cfg: &TunnelConfig,
 ) -> Result<(), std::io::Error> {
-    let (ri, mut wi) = inbound.split();
+    let (ri, wi) = inbound.split();
+    let mut wi = ShapedWriter::new(wi, shaper);
     let mut buf_reader = tokio::io::BufReader::with_capacity(DEFAULT_RECV_BUF_SIZE, ri);
-    let ctx = MuxContext::new(channel, tunnel_id, rctx, wctx, max_alive_secs);
     process_rmux_session(
         ctx, // channel,
         // tunnel_id,
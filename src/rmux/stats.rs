@@ -0,0 +1,120 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Counters of one stream at the time of the snapshot.
+#[derive(Debug, Clone)]
+pub struct StreamStats {
+    pub stream_id: u32,
+    pub target: String,
+    pub age: Duration,
+    pub send_bytes: u64,
+    pub recv_bytes: u64,
+    pub send_frames: u64,
+    pub recv_frames: u64,
+    /// credit left to send before the peer has to grant more
+    pub send_window: i64,
+    /// writes which had to wait for the peer to grant more credit
+    pub window_stalls: u64,
+    /// frames sent compressed, of `send_frames`
+    pub compressed_frames: u64,
+    /// data bytes waiting in the session's queue
+    pub queued_bytes: u64,
+    pub closed: bool,
+}
+
+/// Counters of one session and its open streams at the time of the
+/// snapshot, see `session_stats`.
+#[derive(Debug, Clone)]
+pub struct SessionStats {
+    /// empty for sessions accepted by a listener and retired ones
+    pub channel: String,
+    pub session_id: u32,
+    pub path: String,
+    /// id of the listener user the session is accounted to, if any
+    pub user: String,
+    /// protocol version and `FEATURE_*` agreed in the handshake
+    pub version: u8,
+    pub features: u8,
+    pub age: Duration,
+    pub retired: bool,
+    pub closed: bool,
+    /// the last rtt sample, and the smoothed rtt and its deviation
+    pub rtt_ms: u32,
+    pub srtt_ms: u32,
+    pub rtt_var_ms: u32,
+    /// moving average of the pings lost, in thousandths
+    pub loss_permille: u32,
+    pub missed_pings: u32,
+    pub open_streams: usize,
+    pub send_frames: u64,
+    pub recv_frames: u64,
+    pub send_bytes: u64,
+    pub recv_bytes: u64,
+    pub streams: Vec<StreamStats>,
+}
+
+impl SessionStats {
+    /// Stalls of the open streams; a session with many is limited by its
+    /// peer's windows rather than by its link.
+    pub fn window_stalls(&self) -> u64 {
+        self.streams.iter().map(|s| s.window_stalls).sum()
+    }
+}
+
+impl fmt::Display for StreamStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:target:{}, age:{:?}, send_bytes:{}, recv_bytes:{}, send_frames:{}, recv_frames:{}, send_window:{}, window_stalls:{}, compressed_frames:{}, queued_bytes:{}, closed:{}",
+            self.stream_id,
+            self.target,
+            self.age,
+            self.send_bytes,
+            self.recv_bytes,
+            self.send_frames,
+            self.recv_frames,
+            self.send_window,
+            self.window_stalls,
+            self.compressed_frames,
+            self.queued_bytes,
+            self.closed
+        )
+    }
+}
+
+impl fmt::Display for SessionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "[{}][{}]path:{}, user:{}, version:{}, features:{:#x}, age:{:?}, retired:{}, closed:{}, rtt_ms:{}, srtt_ms:{}, rtt_var_ms:{}, loss_permille:{}, missed_pings:{}",
+            self.channel,
+            self.session_id,
+            self.path,
+            self.user,
+            self.version,
+            self.features,
+            self.age,
+            self.retired,
+            self.closed,
+            self.rtt_ms,
+            self.srtt_ms,
+            self.rtt_var_ms,
+            self.loss_permille,
+            self.missed_pings
+        )?;
+        writeln!(
+            f,
+            "  streams:{}, send_frames:{}, recv_frames:{}, send_bytes:{}, recv_bytes:{}, window_stalls:{}",
+            self.open_streams,
+            self.send_frames,
+            self.recv_frames,
+            self.send_bytes,
+            self.recv_bytes,
+            self.window_stalls()
+        )?;
+        for s in self.streams.iter() {
+            writeln!(f, "  {}", s)?;
+        }
+        Ok(())
+    }
+}
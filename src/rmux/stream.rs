@@ -1,5 +1,11 @@
-use super::event::{new_data_event, new_fin_event, new_window_update_event, Event};
+use super::compress::StreamCompressor;
+use super::event::{
+    new_fin_event, new_half_close_event, new_window_update_event, Event, FEATURE_HALF_CLOSE,
+};
 use super::message::ConnectRequest;
+use super::priority::QueueBudget;
+use super::session::SessionSettings;
+use super::stats::StreamStats;
 
 use bytes::BytesMut;
 use std::pin::Pin;
@@ -22,6 +28,22 @@ const RELAY_BUF_FACTOR: usize = 4;
 // static WRITER_COUNT: AtomicU32 = AtomicU32::new(0);
 // static STREAM_COUNT: AtomicU32 = AtomicU32::new(0);
 
+/// Flow control credit of a session's streams: the bytes a stream may have
+/// in flight to the peer, as the peer advertised, and the bytes it buffers
+/// from the peer, as advertised to it.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamWindows {
+    pub send: u32,
+    pub recv: u32,
+}
+
+impl StreamWindows {
+    /// What a peer which advertises no window is assumed to buffer.
+    pub fn default_window(relay_buf_size: usize) -> u32 {
+        (relay_buf_size * RELAY_BUF_FACTOR) as u32
+    }
+}
+
 pub struct MuxStreamState {
     pub channel: String,
     pub session_id: u32,
@@ -29,10 +51,26 @@ pub struct MuxStreamState {
     pub send_buf_window: AtomicI32,
     pub recv_buf_size: AtomicI32,
     pub closed: AtomicBool,
+    // peer sent FLAG_HALF_CLOSE, reads return EOF
+    pub read_shutdown: AtomicBool,
+    // local writer was shut down and FLAG_HALF_CLOSE sent
+    pub write_shutdown: AtomicBool,
+    // the peer agreed on FEATURE_HALF_CLOSE
+    half_close: AtomicBool,
+    // closed because its session ended, reads fail with ConnectionAborted
+    pub session_lost: AtomicBool,
     pub total_recv_bytes: AtomicU32,
     pub total_send_bytes: AtomicU32,
+    pub send_frames: AtomicU32,
+    pub recv_frames: AtomicU32,
+    pub window_stalls: AtomicU32,
     pub born_time: Instant,
+    target: String,
     relay_buf_size: usize,
+    recv_window: i32,
+    compressor: StreamCompressor,
+    /// data queued for the session's writer, see `STREAM_QUEUE_BUDGET`
+    queue: Arc<QueueBudget>,
 }
 
 struct SharedIOState {
@@ -57,6 +95,34 @@ impl SharedIOState {
 impl MuxStreamState {
     fn close(&self) {
         self.closed.store(true, Ordering::SeqCst);
+        self.queue.wake();
+    }
+    /// EOF for a stream the peer closed, ConnectionAborted for one whose
+    /// session was lost, so a relay can tell a stream it may reopen.
+    fn closed_read(&self) -> std::io::Result<usize> {
+        if self.session_lost.load(Ordering::SeqCst) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionAborted,
+                "session lost",
+            ));
+        }
+        Ok(0)
+    }
+    pub fn snapshot(&self) -> StreamStats {
+        StreamStats {
+            stream_id: self.stream_id,
+            target: self.target.clone(),
+            age: self.born_time.elapsed(),
+            send_bytes: self.total_send_bytes.load(Ordering::SeqCst) as u64,
+            recv_bytes: self.total_recv_bytes.load(Ordering::SeqCst) as u64,
+            send_frames: self.send_frames.load(Ordering::SeqCst) as u64,
+            recv_frames: self.recv_frames.load(Ordering::SeqCst) as u64,
+            send_window: self.send_buf_window.load(Ordering::SeqCst) as i64,
+            window_stalls: self.window_stalls.load(Ordering::SeqCst) as u64,
+            compressed_frames: self.compressor.compressed_frames.load(Ordering::SeqCst) as u64,
+            queued_bytes: self.queue.queued() as u64,
+            closed: self.closed.load(Ordering::SeqCst),
+        }
     }
 }
 
@@ -80,7 +146,7 @@ fn inc_recv_buf_window(
         .total_recv_bytes
         .fetch_add(inc as u32, Ordering::SeqCst);
     let current_recv_buf_size = state.recv_buf_size.load(Ordering::SeqCst);
-    let mut min_report_window: i32 = (state.relay_buf_size * RELAY_BUF_FACTOR) as i32;
+    let mut min_report_window: i32 = state.recv_window;
     if min_report_window > MIN_REPORT_RECV_SIZE {
         min_report_window = MIN_REPORT_RECV_SIZE;
     }
@@ -133,8 +199,14 @@ impl AsyncRead for MuxStreamReader {
         } = &mut *self;
         if state.closed.load(Ordering::SeqCst) {
             clear_unbounded_channel(rx);
+            if state.session_lost.load(Ordering::SeqCst) {
+                return Poll::Ready(state.closed_read());
+            }
             return Poll::Ready(Err(make_io_error("closed")));
         }
+        if state.read_shutdown.load(Ordering::SeqCst) && recv_buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
         let n = if !recv_buf.is_empty() {
             fill_read_buf(recv_buf, buf)
         } else {
@@ -156,12 +228,18 @@ impl AsyncRead for MuxStreamReader {
             Poll::Ready(Some(b)) => {
                 let mut copy_n = b.len();
                 if 0 == copy_n {
+                    if state.read_shutdown.load(Ordering::SeqCst)
+                        && !state.closed.load(Ordering::SeqCst)
+                    {
+                        // half close, the write direction stays usable
+                        return Poll::Ready(Ok(0));
+                    }
                     //close
                     //error!("[{}]####2 Close", state.stream_id);
                     state.close();
                     //rx.close();
                     clear_unbounded_channel(rx);
-                    return Poll::Ready(Ok(0));
+                    return Poll::Ready(state.closed_read());
                 }
                 if copy_n > buf.len() {
                     copy_n = buf.len();
@@ -184,7 +262,7 @@ impl AsyncRead for MuxStreamReader {
                 //error!("[{}]####3 Close", state.stream_id);
                 state.close();
                 clear_unbounded_channel(rx);
-                Poll::Ready(Ok(0))
+                Poll::Ready(state.closed_read())
             }
             Poll::Pending => Poll::Pending,
         }
@@ -221,11 +299,24 @@ impl AsyncWrite for MuxStreamWriter {
             io_state.lock().unwrap().try_close();
             return Poll::Ready(Err(make_io_error("closed")));
         }
+        if state.write_shutdown.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(make_io_error("write shutdown")));
+        }
         if state.send_buf_window.load(Ordering::SeqCst) < 0 {
-            io_state.lock().unwrap().waker = Some(cx.waker().clone());
+            let mut io = io_state.lock().unwrap();
+            // count a stall once, not every poll while it lasts
+            if io.waker.is_none() {
+                state.window_stalls.fetch_add(1, Ordering::SeqCst);
+            }
+            io.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        // the copy feeding it waits too, rather than the queue growing
+        if state.queue.poll_room(cx).is_pending() {
             return Poll::Pending;
         }
-        let ev = new_data_event(state.stream_id, buf, false);
+        let ev = state.compressor.data_event(state.stream_id, buf);
+        let queued = ev.body.len();
 
         // let future = tx.send(ev);
         // pin_mut!(future);
@@ -250,8 +341,11 @@ impl AsyncWrite for MuxStreamWriter {
             }
             Poll::Ready(Ok(())) => {}
         }
+        // taken before the session's writer may give it back
+        state.queue.take(queued);
         match tx.try_send(ev) {
             Err(e) => {
+                state.queue.give_back(queued);
                 io_state.lock().unwrap().try_close();
                 Poll::Ready(Err(make_io_error(&e.to_string())))
             }
@@ -262,6 +356,7 @@ impl AsyncWrite for MuxStreamWriter {
                 state
                     .total_send_bytes
                     .fetch_add(buf.len() as u32, Ordering::SeqCst);
+                state.send_frames.fetch_add(1, Ordering::SeqCst);
                 Poll::Ready(Ok(buf.len()))
             }
         }
@@ -270,12 +365,42 @@ impl AsyncWrite for MuxStreamWriter {
         Poll::Ready(Ok(()))
     }
     fn poll_shutdown(
-        self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
     ) -> Poll<Result<(), std::io::Error>> {
-        self.state.closed.store(true, Ordering::SeqCst);
-        self.io_state.lock().unwrap().try_close();
-        Poll::Ready(Ok(()))
+        let Self {
+            tx,
+            state,
+            io_state,
+        } = &mut *self;
+        if state.closed.load(Ordering::SeqCst) || state.write_shutdown.load(Ordering::SeqCst) {
+            return Poll::Ready(Ok(()));
+        }
+        // a peer without half-close only knows streams closed as a whole
+        if !state.half_close.load(Ordering::SeqCst) {
+            state.close();
+            io_state.lock().unwrap().try_close();
+            return Poll::Ready(Ok(()));
+        }
+        // only the write direction is closed, the peer still may send data
+        match tx.poll_ready(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(e)) => {
+                io_state.lock().unwrap().try_close();
+                return Poll::Ready(Err(make_io_error(&e.to_string())));
+            }
+            Poll::Ready(Ok(())) => {}
+        }
+        match tx.try_send(new_half_close_event(state.stream_id, false)) {
+            Err(e) => {
+                io_state.lock().unwrap().try_close();
+                Poll::Ready(Err(make_io_error(&e.to_string())))
+            }
+            Ok(()) => {
+                state.write_shutdown.store(true, Ordering::SeqCst);
+                Poll::Ready(Ok(()))
+            }
+        }
     }
 }
 
@@ -295,25 +420,40 @@ impl Drop for MuxStream {
 }
 
 impl MuxStream {
-    pub fn new(
+    pub(super) fn new(
         name: &str,
         id0: u32,
         id1: u32,
         evtx: mpsc::Sender<Event>,
         target: ConnectRequest,
-        relay_buf_size: usize,
+        settings: &SessionSettings,
+        queue: Arc<QueueBudget>,
     ) -> Self {
+        let windows = settings.windows;
         let state = MuxStreamState {
             channel: String::from(name),
             session_id: id0,
             stream_id: id1,
-            send_buf_window: AtomicI32::new((relay_buf_size * RELAY_BUF_FACTOR) as i32),
+            send_buf_window: AtomicI32::new(windows.send as i32),
             recv_buf_size: AtomicI32::new(0),
             closed: AtomicBool::new(false),
+            read_shutdown: AtomicBool::new(false),
+            write_shutdown: AtomicBool::new(false),
+            // shutting down the writer sends `FLAG_HALF_CLOSE` once the
+            // peer agreed on it
+            half_close: AtomicBool::new(settings.has_feature(FEATURE_HALF_CLOSE)),
+            session_lost: AtomicBool::new(false),
             total_recv_bytes: AtomicU32::new(0),
             total_send_bytes: AtomicU32::new(0),
+            send_frames: AtomicU32::new(0),
+            recv_frames: AtomicU32::new(0),
+            window_stalls: AtomicU32::new(0),
             born_time: Instant::now(),
-            relay_buf_size,
+            target: target.addr.clone(),
+            relay_buf_size: settings.relay_buf_size,
+            recv_window: windows.recv as i32,
+            compressor: StreamCompressor::new(settings.compression),
+            queue,
         };
         let (dtx, drx) = mpsc::unbounded_channel();
         let io_state = SharedIOState {
@@ -347,6 +487,16 @@ impl MuxStream {
             self.data_tx = Some(tx);
         }
     }
+    /// Counts `n` bytes sent in the SYN like a write, see `create_stream_with_data`.
+    pub(super) fn on_early_data(&self, n: usize) {
+        self.state
+            .send_buf_window
+            .fetch_sub(n as i32, Ordering::SeqCst);
+        self.state
+            .total_send_bytes
+            .fetch_add(n as u32, Ordering::SeqCst);
+        self.state.send_frames.fetch_add(1, Ordering::SeqCst);
+    }
     pub fn update_send_window(&self, inc: u32) {
         self.state
             .send_buf_window
@@ -369,6 +519,7 @@ impl MuxStream {
         }
         //error!("[{}]off data len:{}.", self.state.stream_id, data.len());
         assert!(!data.is_empty());
+        self.state.recv_frames.fetch_add(1, Ordering::SeqCst);
         if let Some(tx) = &mut self.data_tx {
             //let _ = tx.send(data).await;
             let _ = tx.send(data);
@@ -376,6 +527,17 @@ impl MuxStream {
             //error!("[{}]Non recv rx for data.", self.state.stream_id);
         }
     }
+    /// Peer closed its write direction; readers see EOF once drained.
+    pub fn offer_eof(&mut self) {
+        self.check_data_tx();
+        if self.state.closed.load(Ordering::SeqCst) {
+            return;
+        }
+        self.state.read_shutdown.store(true, Ordering::SeqCst);
+        if let Some(tx) = &mut self.data_tx {
+            let _ = tx.send(Vec::new());
+        }
+    }
     pub fn clone(&self) -> Self {
         let mut v = Self {
             target: self.target.clone(),
@@ -0,0 +1,311 @@
+//! UDP associations relayed by `FLAG_UDP_RELAY` events, for local sockets
+//! which talk to any number of targets, like a SOCKS5 UDP ASSOCIATE or a
+//! source port of the tun device. The stream id of such an event names an
+//! association of the session and the body starts with the operation:
+//! `UDP_OPEN` asks the remote for a socket of its own, `UDP_DATA` carries a
+//! datagram after its target or source, prefixed with its length like a
+//! `FLAG_DATAGRAM` body, and `UDP_EXPIRE` ends the association, sent by the
+//! remote when the socket stayed quiet for `UDP_FLOW_IDLE` or it refuses an
+//! open, by the client once the local socket is gone.
+//!
+//! The remote keeps its associations in a NAT table per session: one
+//! unconnected socket per association, so a reply from any address finds
+//! its way back, like behind a full cone NAT.
+use super::datagram::parse_datagram;
+use super::event::{new_data_event, Event, FLAG_UDP_RELAY};
+use crate::config::KeyConfig;
+use crate::utils::{make_io_error, UDP_FLOW_IDLE};
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+pub const UDP_OPEN: u8 = 1;
+pub const UDP_DATA: u8 = 2;
+pub const UDP_EXPIRE: u8 = 3;
+
+const MAX_DATAGRAM: usize = 65535;
+// associations a remote serves per session, it refuses opens beyond
+const MAX_ASSOCIATIONS: usize = 1024;
+// targets whose address an association remembers
+const MAX_RESOLVED: usize = 256;
+/// Datagrams waiting for an association, the later ones are dropped like
+/// by a full socket buffer.
+pub const UDP_RELAY_QUEUE: usize = 256;
+
+/// The associations of one session by id, each with the sender of the
+/// datagrams which arrive for it along with their target or source.
+pub(super) type UdpAssociations = Mutex<HashMap<u32, mpsc::Sender<(String, Vec<u8>)>>>;
+
+fn new_udp_relay_event(id: u32, op: u8, addr: &str, data: &[u8]) -> Event {
+    let mut body = Vec::with_capacity(2 + addr.len() + data.len());
+    body.push(op);
+    if op == UDP_DATA {
+        let addr = &addr.as_bytes()[..std::cmp::min(addr.len(), 255)];
+        body.push(addr.len() as u8);
+        body.extend_from_slice(addr);
+        body.extend_from_slice(data);
+    }
+    let mut ev = new_data_event(id, &body[..], false);
+    ev.header.set_flag(FLAG_UDP_RELAY);
+    ev
+}
+
+/// The operation of a `FLAG_UDP_RELAY` body, with the address and the
+/// datagram of a `UDP_DATA` one.
+fn parse_udp_relay(body: &[u8]) -> Option<(u8, &str, &[u8])> {
+    match *body.first()? {
+        UDP_DATA => {
+            let (addr, data) = parse_datagram(&body[1..])?;
+            Some((UDP_DATA, addr, data))
+        }
+        op @ UDP_OPEN | op @ UDP_EXPIRE => Some((op, "", &[])),
+        _ => None,
+    }
+}
+
+/// A client's association over a session, the datagrams coming back are
+/// handed to the sender it was opened with.
+pub struct UdpAssociation {
+    id: u32,
+    tx: mpsc::Sender<Event>,
+    associations: Arc<UdpAssociations>,
+}
+
+impl UdpAssociation {
+    pub(super) fn open(
+        id: u32,
+        mut tx: mpsc::Sender<Event>,
+        associations: Arc<UdpAssociations>,
+        reply_tx: mpsc::Sender<(String, Vec<u8>)>,
+    ) -> Option<Self> {
+        if tx
+            .try_send(new_udp_relay_event(id, UDP_OPEN, "", &[]))
+            .is_err()
+        {
+            return None;
+        }
+        associations.lock().unwrap().insert(id, reply_tx);
+        Some(Self {
+            id,
+            tx,
+            associations,
+        })
+    }
+    /// Queues `data` for `addr`, a `host:port`; an error once the remote
+    /// expired the association or the session is gone.
+    pub fn send_to(&mut self, addr: &str, data: &[u8]) -> io::Result<()> {
+        if self.is_expired() {
+            return Err(make_io_error("udp association expired"));
+        }
+        match self
+            .tx
+            .try_send(new_udp_relay_event(self.id, UDP_DATA, addr, data))
+        {
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(make_io_error("session closed")),
+            _ => Ok(()),
+        }
+    }
+    pub fn is_expired(&self) -> bool {
+        !self.associations.lock().unwrap().contains_key(&self.id)
+    }
+}
+
+impl Drop for UdpAssociation {
+    fn drop(&mut self) {
+        if self.associations.lock().unwrap().remove(&self.id).is_some() {
+            let _ = self
+                .tx
+                .try_send(new_udp_relay_event(self.id, UDP_EXPIRE, "", &[]));
+        }
+    }
+}
+
+/// Handles a `FLAG_UDP_RELAY` event, a server starts the associations
+/// clients open and expires those it does not know; it drops datagrams
+/// to targets the session's `user` may not reach.
+pub(super) fn handle_udp_relay_event(
+    channel: &str,
+    associations: &Arc<UdpAssociations>,
+    user: Option<&KeyConfig>,
+    mut tx: mpsc::Sender<Event>,
+    ev: Event,
+) {
+    let id = ev.header.stream_id;
+    let (op, addr, data) = match parse_udp_relay(&ev.body[..]) {
+        Some(v) => v,
+        None => {
+            warn!("[{}][{}]Invalid udp relay frame.", channel, id);
+            return;
+        }
+    };
+    let serving = channel.is_empty();
+    let mut table = associations.lock().unwrap();
+    match op {
+        UDP_OPEN if serving => {
+            if table.contains_key(&id) {
+                return;
+            }
+            if table.len() >= MAX_ASSOCIATIONS {
+                warn!("[{}]Too many udp associations, refuse {}.", channel, id);
+                let _ = tx.try_send(new_udp_relay_event(id, UDP_EXPIRE, "", &[]));
+                return;
+            }
+            let (assoc_tx, rx) = mpsc::channel(UDP_RELAY_QUEUE);
+            table.insert(id, assoc_tx);
+            tokio::spawn(serve_udp_association(id, rx, tx, associations.clone()));
+        }
+        UDP_DATA => {
+            if serving && !user.map_or(true, |u| u.is_allowed(addr)) {
+                debug!("[{}]Dropped udp datagram to {}.", id, addr);
+                return;
+            }
+            if let Some(assoc_tx) = table.get_mut(&id) {
+                match assoc_tx.try_send((String::from(addr), data.to_vec())) {
+                    Ok(()) => return,
+                    Err(mpsc::error::TrySendError::Full(_)) => {
+                        debug!("[{}]Udp association queue full, dropped datagram.", id);
+                        return;
+                    }
+                    Err(mpsc::error::TrySendError::Closed(_)) => {
+                        table.remove(&id);
+                    }
+                }
+            }
+            // one side lost the association, the other one lets go too
+            let _ = tx.try_send(new_udp_relay_event(id, UDP_EXPIRE, "", &[]));
+        }
+        UDP_EXPIRE => {
+            table.remove(&id);
+        }
+        _ => {}
+    }
+}
+
+/// Relays the datagrams of an association a client opened, from its own
+/// socket, until the socket stayed quiet for `UDP_FLOW_IDLE`, the client
+/// expired it or the session is gone.
+async fn serve_udp_association(
+    id: u32,
+    mut rx: mpsc::Receiver<(String, Vec<u8>)>,
+    mut tx: mpsc::Sender<Event>,
+    associations: Arc<UdpAssociations>,
+) {
+    if let Err(e) = relay_udp_association(id, &mut rx, &mut tx).await {
+        error!("[{}]Udp association failed; error={}", id, e);
+    }
+    // tell the client, unless the association ended with it
+    if associations.lock().unwrap().remove(&id).is_some() {
+        let _ = tx.send(new_udp_relay_event(id, UDP_EXPIRE, "", &[])).await;
+    }
+}
+
+async fn relay_udp_association(
+    id: u32,
+    rx: &mut mpsc::Receiver<(String, Vec<u8>)>,
+    tx: &mut mpsc::Sender<Event>,
+) -> io::Result<()> {
+    // a dual stack socket if the host has IPv6, v4 targets are mapped
+    let socket = match UdpSocket::bind("[::]:0").await {
+        Ok(s) => s,
+        Err(_) => UdpSocket::bind("0.0.0.0:0").await?,
+    };
+    let v6 = socket.local_addr()?.is_ipv6();
+    let (mut recv_half, mut send_half) = socket.split();
+    let mut resolved: HashMap<String, SocketAddr> = HashMap::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Some((addr, data)) => {
+                    match resolve_target(&mut resolved, addr.as_str(), v6).await {
+                        Some(target) => {
+                            send_half.send_to(&data[..], &target).await?;
+                        }
+                        None => debug!("[{}]Udp target {} not resolved.", id, addr),
+                    }
+                }
+                None => return Ok(()),
+            },
+            r = tokio::time::timeout(UDP_FLOW_IDLE, recv_half.recv_from(&mut buf)) => match r {
+                Ok(Ok((n, from))) => {
+                    let from = unmapped(from).to_string();
+                    if tx.send(new_udp_relay_event(id, UDP_DATA, from.as_str(), &buf[..n])).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Ok(()),
+            },
+        }
+    }
+}
+
+async fn resolve_target(
+    resolved: &mut HashMap<String, SocketAddr>,
+    addr: &str,
+    v6: bool,
+) -> Option<SocketAddr> {
+    if let Some(a) = resolved.get(addr) {
+        return Some(*a);
+    }
+    let a = tokio::net::lookup_host(addr)
+        .await
+        .ok()?
+        .find(|a| v6 || a.is_ipv4())?;
+    let a = match a.ip() {
+        IpAddr::V4(ip) if v6 => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), a.port()),
+        _ => a,
+    };
+    if resolved.len() >= MAX_RESOLVED {
+        resolved.clear();
+    }
+    resolved.insert(String::from(addr), a);
+    Some(a)
+}
+
+// the v4 address a dual stack socket sees as a mapped one
+fn unmapped(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(a) = addr {
+        if let Some(ip) = a.ip().to_ipv4_mapped() {
+            return SocketAddr::new(IpAddr::V4(ip), a.port());
+        }
+    }
+    addr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_udp_relay_body() {
+        let ev = new_udp_relay_event(9, UDP_DATA, "8.8.8.8:53", b"query");
+        assert_eq!(ev.header.flags(), FLAG_UDP_RELAY);
+        assert_eq!(ev.header.stream_id, 9);
+        assert_eq!(
+            parse_udp_relay(&ev.body[..]),
+            Some((UDP_DATA, "8.8.8.8:53", &b"query"[..]))
+        );
+        let ev = new_udp_relay_event(9, UDP_EXPIRE, "8.8.8.8:53", b"query");
+        assert_eq!(ev.body, vec![UDP_EXPIRE]);
+        assert_eq!(
+            parse_udp_relay(&ev.body[..]),
+            Some((UDP_EXPIRE, "", &[][..]))
+        );
+        assert!(parse_udp_relay(&[UDP_DATA, 9, b'a']).is_none());
+        assert!(parse_udp_relay(&[7]).is_none());
+        assert!(parse_udp_relay(&[]).is_none());
+    }
+
+    #[test]
+    fn test_unmapped() {
+        let mapped: SocketAddr = "[::ffff:1.2.3.4]:53".parse().unwrap();
+        assert_eq!(unmapped(mapped), "1.2.3.4:53".parse().unwrap());
+        let v6: SocketAddr = "[2001:db8::1]:53".parse().unwrap();
+        assert_eq!(unmapped(v6), v6);
+    }
+}
@@ -0,0 +1,1273 @@
+//! Zstandard frames (RFC 8878) for `Compression::Zstd`, in tree like the
+//! LZ4 blocks. The decoder takes any frame which needs no dictionary. The
+//! encoder writes a subset: greedy matches coded with the predefined
+//! sequence tables, literals Huffman coded when their weights fit the
+//! direct representation and raw otherwise.
+
+const MAGIC: u32 = 0xFD2F_B528;
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+const MAX_BLOCK_SIZE: usize = 128 * 1024;
+const MAX_HUFFMAN_BITS: u32 = 11;
+const MIN_MATCH: usize = 4;
+const HASH_LOG: u32 = 16;
+
+// literals length codes: baseline and extra bits
+const LL_BASE: [u32; 36] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 18, 20, 22, 24, 28, 32, 40, 48, 64,
+    128, 256, 512, 1024, 2048, 4096, 8192, 16384, 32768, 65536,
+];
+const LL_BITS: [u8; 36] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 3, 3, 4, 6, 7, 8, 9, 10, 11,
+    12, 13, 14, 15, 16,
+];
+// match length codes: baseline and extra bits
+const ML_BASE: [u32; 53] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27,
+    28, 29, 30, 31, 32, 33, 34, 35, 37, 39, 41, 43, 47, 51, 59, 67, 83, 99, 131, 259, 515, 1027,
+    2051, 4099, 8195, 16387, 32771, 65539,
+];
+const ML_BITS: [u8; 53] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    1, 1, 1, 1, 2, 2, 3, 3, 4, 4, 5, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+];
+// the predefined distributions of the sequence codes
+const LL_DEFAULT: [i16; 36] = [
+    4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1,
+    -1, -1, -1, -1,
+];
+const ML_DEFAULT: [i16; 53] = [
+    1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1,
+];
+const OF_DEFAULT: [i16; 29] = [
+    1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1,
+];
+
+fn highbit(v: u32) -> u32 {
+    31 - v.leading_zeros()
+}
+
+// a little endian integer of up to 8 bytes
+fn read_le(src: &[u8], pos: usize, len: usize) -> Option<u64> {
+    let bytes = src.get(pos..pos + len)?;
+    Some(
+        bytes
+            .iter()
+            .rev()
+            .fold(0u64, |v, b| (v << 8) | u64::from(*b)),
+    )
+}
+
+/// XXH64 with seed 0, the low half of which is a frame's checksum.
+fn xxh64(data: &[u8]) -> u64 {
+    const P1: u64 = 11_400_714_785_074_694_791;
+    const P2: u64 = 14_029_467_366_897_019_727;
+    const P3: u64 = 1_609_587_929_392_839_161;
+    const P4: u64 = 9_650_029_242_287_828_579;
+    const P5: u64 = 2_870_177_450_012_600_261;
+    fn round(acc: u64, lane: u64) -> u64 {
+        acc.wrapping_add(lane.wrapping_mul(P2))
+            .rotate_left(31)
+            .wrapping_mul(P1)
+    }
+    fn merge(acc: u64, v: u64) -> u64 {
+        (acc ^ round(0, v)).wrapping_mul(P1).wrapping_add(P4)
+    }
+    let lane = |i: usize| read_le(data, i, 8).unwrap_or(0);
+    let mut i = 0;
+    let mut h = if data.len() >= 32 {
+        let mut v = [P1.wrapping_add(P2), P2, 0, 0u64.wrapping_sub(P1)];
+        while i + 32 <= data.len() {
+            for (k, acc) in v.iter_mut().enumerate() {
+                *acc = round(*acc, lane(i + 8 * k));
+            }
+            i += 32;
+        }
+        let h = v[0]
+            .rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18));
+        v.iter().fold(h, |h, acc| merge(h, *acc))
+    } else {
+        P5
+    };
+    h = h.wrapping_add(data.len() as u64);
+    while i + 8 <= data.len() {
+        h = (h ^ round(0, lane(i)))
+            .rotate_left(27)
+            .wrapping_mul(P1)
+            .wrapping_add(P4);
+        i += 8;
+    }
+    if i + 4 <= data.len() {
+        let v = read_le(data, i, 4).unwrap_or(0);
+        h = (h ^ v.wrapping_mul(P1))
+            .rotate_left(23)
+            .wrapping_mul(P2)
+            .wrapping_add(P3);
+        i += 4;
+    }
+    for b in &data[i..] {
+        h = (h ^ u64::from(*b).wrapping_mul(P5))
+            .rotate_left(11)
+            .wrapping_mul(P1);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(P2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(P3);
+    h ^ (h >> 32)
+}
+
+// reads least significant bits first, as FSE table descriptions are laid out
+struct ForwardBits<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ForwardBits<'a> {
+    fn peek(&self, n: u32) -> u32 {
+        let mut v: u64 = 0;
+        for i in 0..4 {
+            if let Some(b) = self.data.get(self.pos / 8 + i) {
+                v |= u64::from(*b) << (8 * i);
+            }
+        }
+        ((v >> (self.pos % 8)) & ((1 << n) - 1)) as u32
+    }
+
+    fn read(&mut self, n: u32) -> u32 {
+        let v = self.peek(n);
+        self.pos += n as usize;
+        v
+    }
+}
+
+// reads from the end of a bitstream towards its start, past the padding
+// and the marker bit of its last byte; reading past the start gives zeros
+struct BackwardBits<'a> {
+    data: &'a [u8],
+    bits: isize,
+}
+
+impl<'a> BackwardBits<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let last = *data.last()?;
+        if last == 0 {
+            return None;
+        }
+        let bits = data.len() * 8 - last.leading_zeros() as usize - 1;
+        Some(Self {
+            data,
+            bits: bits as isize,
+        })
+    }
+
+    // up to 56 bits, the first of them the most significant
+    fn peek(&self, n: u32) -> u64 {
+        if n == 0 || self.bits <= 0 {
+            return 0;
+        }
+        let start = self.bits - n as isize;
+        if start < 0 {
+            let left = self.bits as u32;
+            return self.peek_at(0, left) << (n - left);
+        }
+        self.peek_at(start as usize, n)
+    }
+
+    fn peek_at(&self, start: usize, n: u32) -> u64 {
+        let mut v: u64 = 0;
+        for i in 0..8 {
+            if let Some(b) = self.data.get(start / 8 + i) {
+                v |= u64::from(*b) << (8 * i);
+            }
+        }
+        (v >> (start % 8)) & ((1 << n) - 1)
+    }
+
+    fn read(&mut self, n: u32) -> u64 {
+        let v = self.peek(n);
+        self.bits -= n as isize;
+        v
+    }
+
+    fn overflowed(&self) -> bool {
+        self.bits < 0
+    }
+
+    fn finished(&self) -> bool {
+        self.bits == 0
+    }
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            out: Vec::new(),
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    fn write(&mut self, v: u64, n: u32) {
+        if n == 0 {
+            return;
+        }
+        self.acc |= (v & ((1 << n) - 1)) << self.bits;
+        self.bits += n;
+        while self.bits >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    // the marker bit a backward reader starts from
+    fn finish(mut self) -> Vec<u8> {
+        self.write(1, 1);
+        if self.bits > 0 {
+            self.out.push(self.acc as u8);
+        }
+        self.out
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct FseEntry {
+    symbol: u8,
+    bits: u8,
+    base: u16,
+}
+
+struct FseTable {
+    log: u32,
+    entries: Vec<FseEntry>,
+}
+
+impl FseTable {
+    fn rle(symbol: u8) -> Self {
+        Self {
+            log: 0,
+            entries: vec![FseEntry {
+                symbol,
+                bits: 0,
+                base: 0,
+            }],
+        }
+    }
+
+    /// Builds the decoding table of a normalized distribution, whose
+    /// counts of -1 stand for a probability below one.
+    fn new(norm: &[i16], log: u32) -> Option<Self> {
+        let size = 1usize << log;
+        let total: usize = norm.iter().map(|n| n.unsigned_abs() as usize).sum();
+        if total != size || norm.len() > 256 {
+            return None;
+        }
+        let mut entries = vec![FseEntry::default(); size];
+        let mut next = vec![0u32; norm.len()];
+        let mut high = size;
+        for (s, n) in norm.iter().enumerate() {
+            if *n == -1 {
+                high -= 1;
+                entries[high].symbol = s as u8;
+                next[s] = 1;
+            } else {
+                next[s] = (*n).max(0) as u32;
+            }
+        }
+        for_each_spread(norm, log, high, |pos, s| entries[pos].symbol = s);
+        for e in entries.iter_mut() {
+            let x = next[e.symbol as usize];
+            next[e.symbol as usize] += 1;
+            let bits = log - highbit(x);
+            e.bits = bits as u8;
+            e.base = ((x << bits) as usize - size) as u16;
+        }
+        Some(Self { log, entries })
+    }
+
+    fn symbol(&self, state: usize) -> u8 {
+        self.entries[state].symbol
+    }
+
+    fn update(&self, state: usize, bits: &mut BackwardBits) -> usize {
+        let e = self.entries[state];
+        e.base as usize + bits.read(u32::from(e.bits)) as usize
+    }
+}
+
+// the positions of the symbols with a count of one or more, spread over
+// the table below the `high` cells taken by the counts of -1
+fn for_each_spread<F: FnMut(usize, u8)>(norm: &[i16], log: u32, high: usize, mut f: F) {
+    let size = 1usize << log;
+    let step = (size >> 1) + (size >> 3) + 3;
+    let mut pos = 0;
+    for (s, n) in norm.iter().enumerate() {
+        for _ in 0..(*n).max(0) {
+            f(pos, s as u8);
+            pos = (pos + step) & (size - 1);
+            while pos >= high {
+                pos = (pos + step) & (size - 1);
+            }
+        }
+    }
+}
+
+/// Reads an FSE table description, returning the table and its length.
+fn read_fse_table(data: &[u8], max_symbol: usize, max_log: u32) -> Option<(FseTable, usize)> {
+    let mut bits = ForwardBits { data, pos: 0 };
+    let log = bits.read(4) + 5;
+    if log > max_log {
+        return None;
+    }
+    let mut norm: Vec<i16> = Vec::new();
+    let mut remaining: i32 = (1 << log) + 1;
+    let mut threshold: i32 = 1 << log;
+    let mut nb_bits = log + 1;
+    let mut previous_zero = false;
+    while remaining > 1 && norm.len() <= max_symbol {
+        if previous_zero {
+            loop {
+                let repeat = bits.read(2);
+                norm.resize(norm.len() + repeat as usize, 0);
+                if repeat != 3 {
+                    break;
+                }
+            }
+            if norm.len() > max_symbol {
+                return None;
+            }
+        }
+        let max = (2 * threshold - 1) - remaining;
+        let v = bits.peek(nb_bits) as i32;
+        let mut count = if (v & (threshold - 1)) < max {
+            bits.pos += nb_bits as usize - 1;
+            v & (threshold - 1)
+        } else {
+            bits.pos += nb_bits as usize;
+            let v = v & (2 * threshold - 1);
+            if v >= threshold {
+                v - max
+            } else {
+                v
+            }
+        };
+        count -= 1;
+        remaining -= count.abs();
+        if remaining < 1 {
+            return None;
+        }
+        norm.push(count as i16);
+        previous_zero = count == 0;
+        while remaining < threshold {
+            nb_bits -= 1;
+            threshold >>= 1;
+        }
+    }
+    if remaining != 1 || bits.pos > data.len() * 8 {
+        return None;
+    }
+    Some((FseTable::new(&norm, log)?, bits.pos.div_ceil(8)))
+}
+
+struct HuffmanTable {
+    max_bits: u32,
+    // symbol and code length, indexed by the next `max_bits` bits
+    entries: Vec<(u8, u8)>,
+}
+
+impl HuffmanTable {
+    /// Builds the table from the weights of all symbols but the last one.
+    fn new(mut weights: Vec<u8>) -> Option<Self> {
+        if weights.is_empty() || weights.len() > 255 {
+            return None;
+        }
+        let mut total: u32 = 0;
+        for w in weights.iter() {
+            if u32::from(*w) > MAX_HUFFMAN_BITS {
+                return None;
+            }
+            if *w > 0 {
+                total += 1 << (w - 1);
+            }
+        }
+        if total == 0 {
+            return None;
+        }
+        let max_bits = highbit(total) + 1;
+        let left = (1 << max_bits) - total;
+        if max_bits > MAX_HUFFMAN_BITS || !left.is_power_of_two() {
+            return None;
+        }
+        weights.push(highbit(left) as u8 + 1);
+        let mut entries = vec![(0u8, 0u8); 1 << max_bits];
+        let mut pos = 0;
+        for w in 1..=max_bits as u8 {
+            for (s, _) in weights.iter().enumerate().filter(|(_, x)| **x == w) {
+                let n = 1 << (w - 1);
+                for e in entries[pos..pos + n].iter_mut() {
+                    *e = (s as u8, max_bits as u8 + 1 - w);
+                }
+                pos += n;
+            }
+        }
+        Some(Self { max_bits, entries })
+    }
+
+    /// Reads a Huffman tree description, returning the table and its length.
+    fn read(data: &[u8]) -> Option<(Self, usize)> {
+        let header = *data.first()? as usize;
+        let mut weights = Vec::new();
+        let len = if header >= 128 {
+            let n = header - 127;
+            let packed = data.get(1..1 + n.div_ceil(2))?;
+            for i in 0..n {
+                let b = packed[i / 2];
+                weights.push(if i % 2 == 0 { b >> 4 } else { b & 15 });
+            }
+            1 + packed.len()
+        } else {
+            // two interleaved FSE states over one bitstream
+            let src = data.get(1..1 + header)?;
+            let (table, n) = read_fse_table(src, 255, 6)?;
+            let mut bits = BackwardBits::new(src.get(n..)?)?;
+            let mut states = [bits.read(table.log) as usize, bits.read(table.log) as usize];
+            let mut k = 0;
+            loop {
+                weights.push(table.symbol(states[k]));
+                states[k] = table.update(states[k], &mut bits);
+                if bits.overflowed() {
+                    weights.push(table.symbol(states[1 - k]));
+                    break;
+                }
+                if weights.len() > 255 {
+                    return None;
+                }
+                k = 1 - k;
+            }
+            1 + header
+        };
+        Some((Self::new(weights)?, len))
+    }
+
+    fn decode_stream(&self, src: &[u8], n: usize, out: &mut Vec<u8>) -> Option<()> {
+        let mut bits = BackwardBits::new(src)?;
+        for _ in 0..n {
+            let (symbol, len) = self.entries[bits.peek(self.max_bits) as usize];
+            bits.read(u32::from(len));
+            out.push(symbol);
+        }
+        if !bits.finished() {
+            return None;
+        }
+        Some(())
+    }
+}
+
+// what a frame's blocks carry over to the next one
+struct FrameState {
+    huffman: Option<HuffmanTable>,
+    ll: Option<FseTable>,
+    of: Option<FseTable>,
+    ml: Option<FseTable>,
+    reps: [usize; 3],
+}
+
+impl FrameState {
+    fn new() -> Self {
+        Self {
+            huffman: None,
+            ll: None,
+            of: None,
+            ml: None,
+            reps: [1, 4, 8],
+        }
+    }
+}
+
+// the offset of an offset value, 1 to 3 being the repeated ones
+fn repeat_offset(reps: &mut [usize; 3], value: u64, literals_len: usize) -> usize {
+    let old = *reps;
+    if value > 3 {
+        let offset = (value - 3) as usize;
+        *reps = [offset, old[0], old[1]];
+        return offset;
+    }
+    let index = value as usize - 1 + usize::from(literals_len == 0);
+    let offset = match index {
+        0 => return old[0],
+        1 => old[1],
+        2 => old[2],
+        _ => std::cmp::max(old[0] - 1, 1),
+    };
+    *reps = if index == 1 {
+        [offset, old[0], old[2]]
+    } else {
+        [offset, old[0], old[1]]
+    };
+    offset
+}
+
+fn read_literals(src: &[u8], huffman: &mut Option<HuffmanTable>) -> Option<(Vec<u8>, usize)> {
+    let b0 = *src.first()? as usize;
+    let format = (b0 >> 2) & 3;
+    if b0 & 3 < 2 {
+        let (size, header) = match format {
+            0 | 2 => (b0 >> 3, 1),
+            1 => ((b0 >> 4) + (read_le(src, 1, 1)? << 4) as usize, 2),
+            _ => ((b0 >> 4) + (read_le(src, 1, 2)? << 4) as usize, 3),
+        };
+        if size > MAX_BLOCK_SIZE {
+            return None;
+        }
+        if b0 & 3 == 0 {
+            let literals = src.get(header..header + size)?.to_vec();
+            return Some((literals, header + size));
+        }
+        return Some((vec![*src.get(header)?; size], header + 1));
+    }
+    let (regenerated, compressed, header) = match format {
+        0 | 1 => {
+            let v = read_le(src, 0, 3)? as usize;
+            ((v >> 4) & 0x3FF, (v >> 14) & 0x3FF, 3)
+        }
+        2 => {
+            let v = read_le(src, 0, 4)? as usize;
+            ((v >> 4) & 0x3FFF, (v >> 18) & 0x3FFF, 4)
+        }
+        _ => {
+            let v = read_le(src, 0, 5)? as usize;
+            ((v >> 4) & 0x3FFFF, (v >> 22) & 0x3FFFF, 5)
+        }
+    };
+    if regenerated > MAX_BLOCK_SIZE {
+        return None;
+    }
+    let mut data = src.get(header..header + compressed)?;
+    // a treeless block reuses the previous table
+    if b0 & 3 == 2 {
+        let (table, n) = HuffmanTable::read(data)?;
+        *huffman = Some(table);
+        data = &data[n..];
+    }
+    let table = huffman.as_ref()?;
+    let mut literals = Vec::with_capacity(regenerated);
+    if format == 0 {
+        table.decode_stream(data, regenerated, &mut literals)?;
+    } else {
+        let quarter = regenerated.div_ceil(4);
+        let last = regenerated.checked_sub(3 * quarter)?;
+        let mut start = 6;
+        for i in 0..4 {
+            let end = if i < 3 {
+                start + read_le(data, 2 * i, 2)? as usize
+            } else {
+                data.len()
+            };
+            let n = if i < 3 { quarter } else { last };
+            table.decode_stream(data.get(start..end)?, n, &mut literals)?;
+            start = end;
+        }
+    }
+    Some((literals, header + compressed))
+}
+
+// the limits of a sequence code and its predefined distribution
+struct CodeKind {
+    max_symbol: usize,
+    max_log: u32,
+    default: &'static [i16],
+    default_log: u32,
+}
+
+const LL_KIND: CodeKind = CodeKind {
+    max_symbol: 35,
+    max_log: 9,
+    default: &LL_DEFAULT,
+    default_log: 6,
+};
+// offset codes up to 31 are valid, the predefined ones stop at 28
+const OF_KIND: CodeKind = CodeKind {
+    max_symbol: 31,
+    max_log: 8,
+    default: &OF_DEFAULT,
+    default_log: 5,
+};
+const ML_KIND: CodeKind = CodeKind {
+    max_symbol: 52,
+    max_log: 9,
+    default: &ML_DEFAULT,
+    default_log: 6,
+};
+
+fn read_sequence_table(
+    mode: u8,
+    src: &[u8],
+    pos: &mut usize,
+    table: &mut Option<FseTable>,
+    kind: &CodeKind,
+) -> Option<()> {
+    match mode {
+        0 => *table = Some(FseTable::new(kind.default, kind.default_log)?),
+        1 => {
+            let symbol = *src.get(*pos)?;
+            if symbol as usize > kind.max_symbol {
+                return None;
+            }
+            *pos += 1;
+            *table = Some(FseTable::rle(symbol));
+        }
+        2 => {
+            let (t, n) = read_fse_table(src.get(*pos..)?, kind.max_symbol, kind.max_log)?;
+            *pos += n;
+            *table = Some(t);
+        }
+        _ => {
+            table.as_ref()?;
+        }
+    }
+    Some(())
+}
+
+fn decode_block(
+    src: &[u8],
+    state: &mut FrameState,
+    out: &mut Vec<u8>,
+    frame_start: usize,
+    limit: usize,
+) -> Option<()> {
+    let block_start = out.len();
+    let (literals, mut pos) = read_literals(src, &mut state.huffman)?;
+    let b0 = *src.get(pos)? as usize;
+    let count = if b0 < 128 {
+        pos += 1;
+        b0
+    } else if b0 < 255 {
+        pos += 2;
+        ((b0 - 128) << 8) + *src.get(pos - 1)? as usize
+    } else {
+        pos += 3;
+        read_le(src, pos - 2, 2)? as usize + 0x7F00
+    };
+    if count > 0 {
+        let modes = *src.get(pos)?;
+        pos += 1;
+        if modes & 3 != 0 {
+            return None;
+        }
+        read_sequence_table(modes >> 6, src, &mut pos, &mut state.ll, &LL_KIND)?;
+        read_sequence_table((modes >> 4) & 3, src, &mut pos, &mut state.of, &OF_KIND)?;
+        read_sequence_table((modes >> 2) & 3, src, &mut pos, &mut state.ml, &ML_KIND)?;
+    } else if pos != src.len() {
+        return None;
+    }
+    let mut used = 0;
+    if count > 0 {
+        let (ll, of, ml) = (state.ll.as_ref()?, state.of.as_ref()?, state.ml.as_ref()?);
+        let mut bits = BackwardBits::new(src.get(pos..)?)?;
+        let mut ll_state = bits.read(ll.log) as usize;
+        let mut of_state = bits.read(of.log) as usize;
+        let mut ml_state = bits.read(ml.log) as usize;
+        let mut reps = state.reps;
+        for i in 0..count {
+            let ll_code = ll.symbol(ll_state) as usize;
+            let of_code = u32::from(of.symbol(of_state));
+            let ml_code = ml.symbol(ml_state) as usize;
+            if ll_code >= LL_BASE.len() || ml_code >= ML_BASE.len() || of_code > 31 {
+                return None;
+            }
+            let value = (1u64 << of_code) + bits.read(of_code);
+            let match_len = ML_BASE[ml_code] as usize + bits.read(ML_BITS[ml_code].into()) as usize;
+            let literals_len =
+                LL_BASE[ll_code] as usize + bits.read(LL_BITS[ll_code].into()) as usize;
+            let offset = repeat_offset(&mut reps, value, literals_len);
+            if i + 1 < count {
+                ll_state = ll.update(ll_state, &mut bits);
+                ml_state = ml.update(ml_state, &mut bits);
+                of_state = of.update(of_state, &mut bits);
+            }
+            if out.len() + literals_len + match_len > limit {
+                return None;
+            }
+            out.extend_from_slice(literals.get(used..used + literals_len)?);
+            used += literals_len;
+            if offset > out.len() - frame_start {
+                return None;
+            }
+            // byte by byte, a match may overlap what it copies
+            let start = out.len() - offset;
+            for k in 0..match_len {
+                let b = out[start + k];
+                out.push(b);
+            }
+        }
+        if !bits.finished() {
+            return None;
+        }
+        state.reps = reps;
+    }
+    if out.len() + literals.len() - used > limit {
+        return None;
+    }
+    out.extend_from_slice(&literals[used..]);
+    if out.len() - block_start > MAX_BLOCK_SIZE {
+        return None;
+    }
+    Some(())
+}
+
+// decodes the frame after its magic, returning the length it took
+fn decode_frame(src: &[u8], out: &mut Vec<u8>, limit: usize) -> Option<usize> {
+    let descriptor = *src.first()?;
+    let single_segment = descriptor & 0x20 != 0;
+    if descriptor & 0x08 != 0 {
+        return None;
+    }
+    let mut pos = if single_segment { 1 } else { 2 };
+    let dict_len = [0, 1, 2, 4][(descriptor & 3) as usize];
+    if read_le(src, pos, dict_len)? != 0 {
+        return None;
+    }
+    pos += dict_len;
+    let content_size = match descriptor >> 6 {
+        0 if !single_segment => None,
+        0 => Some(read_le(src, pos, 1)?),
+        1 => Some(read_le(src, pos, 2)? + 256),
+        2 => Some(read_le(src, pos, 4)?),
+        _ => Some(read_le(src, pos, 8)?),
+    };
+    pos += [usize::from(single_segment), 2, 4, 8][(descriptor >> 6) as usize];
+    if content_size.unwrap_or(0) > limit as u64 {
+        return None;
+    }
+    let start = out.len();
+    let mut state = FrameState::new();
+    loop {
+        let header = read_le(src, pos, 3)? as usize;
+        pos += 3;
+        let size = header >> 3;
+        if size > MAX_BLOCK_SIZE {
+            return None;
+        }
+        match (header >> 1) & 3 {
+            0 => {
+                if out.len() + size > limit {
+                    return None;
+                }
+                out.extend_from_slice(src.get(pos..pos + size)?);
+                pos += size;
+            }
+            1 => {
+                if out.len() + size > limit {
+                    return None;
+                }
+                out.resize(out.len() + size, *src.get(pos)?);
+                pos += 1;
+            }
+            2 => {
+                decode_block(src.get(pos..pos + size)?, &mut state, out, start, limit)?;
+                pos += size;
+            }
+            _ => return None,
+        }
+        if header & 1 == 1 {
+            break;
+        }
+    }
+    if let Some(n) = content_size {
+        if n != (out.len() - start) as u64 {
+            return None;
+        }
+    }
+    if descriptor & 0x04 != 0 {
+        let checksum = read_le(src, pos, 4)?;
+        if checksum != xxh64(&out[start..]) & 0xFFFF_FFFF {
+            return None;
+        }
+        pos += 4;
+    }
+    Some(pos)
+}
+
+/// Decodes the frames of `src`, `None` if they are corrupted or yield more
+/// than `limit` bytes.
+pub(super) fn zstd_decompress(src: &[u8], limit: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < src.len() {
+        let magic = read_le(src, pos, 4)? as u32;
+        pos += 4;
+        if magic & 0xFFFF_FFF0 == SKIPPABLE_MAGIC {
+            pos += 4 + read_le(src, pos, 4)? as usize;
+            if pos > src.len() {
+                return None;
+            }
+            continue;
+        }
+        if magic != MAGIC {
+            return None;
+        }
+        pos += decode_frame(&src[pos..], &mut out, limit)?;
+    }
+    Some(out)
+}
+
+struct FseEncoder {
+    log: u32,
+    states: Vec<u16>,
+    // per symbol: where its states start, less its count, and the bits
+    // to write from a state, in the shifted form of the reference encoder
+    symbols: Vec<(i32, u32)>,
+}
+
+impl FseEncoder {
+    fn new(norm: &[i16], log: u32) -> Self {
+        let size = 1usize << log;
+        let mut cumul = vec![0usize; norm.len() + 1];
+        let mut symbol_at = vec![0u8; size];
+        let mut high = size;
+        for (s, n) in norm.iter().enumerate() {
+            cumul[s + 1] = cumul[s] + n.unsigned_abs() as usize;
+            if *n == -1 {
+                high -= 1;
+                symbol_at[high] = s as u8;
+            }
+        }
+        for_each_spread(norm, log, high, |pos, s| symbol_at[pos] = s);
+        let mut states = vec![0u16; size];
+        for (u, s) in symbol_at.iter().enumerate() {
+            states[cumul[*s as usize]] = (size + u) as u16;
+            cumul[*s as usize] += 1;
+        }
+        let mut total: i32 = 0;
+        let symbols = norm
+            .iter()
+            .map(|n| match n {
+                0 => (0, ((log + 1) << 16) - size as u32),
+                -1 | 1 => {
+                    total += 1;
+                    (total - 2, (log << 16) - size as u32)
+                }
+                _ => {
+                    let n = i32::from(*n);
+                    let max_bits_out = log - highbit(n as u32 - 1);
+                    let min_state_plus = (n as u32) << max_bits_out;
+                    total += n;
+                    (total - 2 * n, (max_bits_out << 16) - min_state_plus)
+                }
+            })
+            .collect();
+        Self {
+            log,
+            states,
+            symbols,
+        }
+    }
+
+    fn state(&self, symbol: u8, value: u32, nb_bits: u32) -> u32 {
+        let (find, _) = self.symbols[symbol as usize];
+        u32::from(self.states[((value >> nb_bits) as i32 + find) as usize])
+    }
+
+    fn init(&self, symbol: u8) -> u32 {
+        let (_, delta) = self.symbols[symbol as usize];
+        let nb_bits = (delta + (1 << 15)) >> 16;
+        self.state(symbol, (nb_bits << 16) - delta, nb_bits)
+    }
+
+    fn encode(&self, w: &mut BitWriter, state: &mut u32, symbol: u8) {
+        let (_, delta) = self.symbols[symbol as usize];
+        let nb_bits = (*state + delta) >> 16;
+        w.write(u64::from(*state), nb_bits);
+        *state = self.state(symbol, *state, nb_bits);
+    }
+
+    fn flush(&self, w: &mut BitWriter, state: u32) {
+        w.write(u64::from(state), self.log);
+    }
+}
+
+// a sequence: literals before it, offset and match length
+struct Sequence {
+    literals_len: usize,
+    offset: usize,
+    match_len: usize,
+}
+
+// a code of `base` and the extra bits on top of the baseline
+fn length_code(v: usize, base: &[u32], bits: &[u8]) -> (u8, u64, u32) {
+    let code = base.iter().rposition(|b| *b as usize <= v).unwrap_or(0);
+    (
+        code as u8,
+        (v - base[code] as usize) as u64,
+        u32::from(bits[code]),
+    )
+}
+
+fn write_sequences(seqs: &[Sequence], out: &mut Vec<u8>) {
+    let n = seqs.len();
+    if n < 128 {
+        out.push(n as u8);
+    } else if n < 0x7F00 {
+        out.push((n >> 8) as u8 + 128);
+        out.push(n as u8);
+    } else {
+        out.push(255);
+        out.extend_from_slice(&((n - 0x7F00) as u16).to_le_bytes());
+    }
+    if n == 0 {
+        return;
+    }
+    // predefined tables for all three codes
+    out.push(0);
+    let ll = FseEncoder::new(LL_KIND.default, LL_KIND.default_log);
+    let of = FseEncoder::new(OF_KIND.default, OF_KIND.default_log);
+    let ml = FseEncoder::new(ML_KIND.default, ML_KIND.default_log);
+    let codes: Vec<_> = seqs
+        .iter()
+        .map(|s| {
+            let value = s.offset as u64 + 3;
+            let of_bits = highbit(value as u32);
+            (
+                length_code(s.literals_len, &LL_BASE, &LL_BITS),
+                (of_bits as u8, value - (1 << of_bits), of_bits),
+                length_code(s.match_len, &ML_BASE, &ML_BITS),
+            )
+        })
+        .collect();
+    let mut w = BitWriter::new();
+    // backwards, the decoder starts with the first sequence
+    let (l, o, m) = codes[n - 1];
+    let mut ml_state = ml.init(m.0);
+    let mut of_state = of.init(o.0);
+    let mut ll_state = ll.init(l.0);
+    w.write(l.1, l.2);
+    w.write(m.1, m.2);
+    w.write(o.1, o.2);
+    for (l, o, m) in codes[..n - 1].iter().rev() {
+        of.encode(&mut w, &mut of_state, o.0);
+        ml.encode(&mut w, &mut ml_state, m.0);
+        ll.encode(&mut w, &mut ll_state, l.0);
+        w.write(l.1, l.2);
+        w.write(m.1, m.2);
+        w.write(o.1, o.2);
+    }
+    ml.flush(&mut w, ml_state);
+    of.flush(&mut w, of_state);
+    ll.flush(&mut w, ll_state);
+    out.extend_from_slice(&w.finish());
+}
+
+// code lengths of at most MAX_HUFFMAN_BITS for the counts, halving them
+// until the tree is shallow enough
+fn huffman_lengths(counts: &[u32]) -> Vec<u32> {
+    let mut counts = counts.to_vec();
+    loop {
+        // (weight, node); leaves are 0..n, the rest are merged nodes
+        let mut parent = vec![usize::MAX; counts.len()];
+        let mut nodes: Vec<(u64, usize)> = counts
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| **c > 0)
+            .map(|(s, c)| (u64::from(*c), s))
+            .collect();
+        while nodes.len() > 1 {
+            nodes.sort_by(|a, b| b.cmp(a));
+            let (a, x) = nodes.pop().unwrap_or_default();
+            let (b, y) = nodes.pop().unwrap_or_default();
+            parent.push(usize::MAX);
+            let id = parent.len() - 1;
+            parent[x] = id;
+            parent[y] = id;
+            nodes.push((a + b, id));
+        }
+        let mut lengths = vec![0u32; counts.len()];
+        for (s, len) in lengths.iter_mut().enumerate() {
+            let mut p = s;
+            while counts[s] > 0 && parent[p] != usize::MAX {
+                *len += 1;
+                p = parent[p];
+            }
+        }
+        if lengths.iter().all(|l| *l <= MAX_HUFFMAN_BITS) {
+            return lengths;
+        }
+        for c in counts.iter_mut().filter(|c| **c > 0) {
+            *c = (*c >> 1) | 1;
+        }
+    }
+}
+
+// literals Huffman coded, `None` if they can't be or that doesn't pay off
+fn huffman_literals(literals: &[u8]) -> Option<Vec<u8>> {
+    let mut counts = [0u32; 256];
+    for b in literals {
+        counts[*b as usize] += 1;
+    }
+    let max_symbol = counts.iter().rposition(|c| *c > 0)?;
+    // the direct representation holds the weights of up to 128 symbols
+    // before the last one
+    if literals.len() < 32 || max_symbol > 128 || counts.iter().filter(|c| **c > 0).count() < 2 {
+        return None;
+    }
+    let lengths = huffman_lengths(&counts[..=max_symbol]);
+    let max_bits = *lengths.iter().max()?;
+    let weights: Vec<u8> = lengths
+        .iter()
+        .map(|l| if *l > 0 { (max_bits + 1 - l) as u8 } else { 0 })
+        .collect();
+    // codes in the order the decoding table is filled
+    let mut codes = vec![0u64; weights.len()];
+    let mut pos = 0u64;
+    for w in 1..=max_bits as u8 {
+        for (s, _) in weights.iter().enumerate().filter(|(_, x)| **x == w) {
+            codes[s] = pos >> (w - 1);
+            pos += 1 << (w - 1);
+        }
+    }
+    let mut data = vec![127 + max_symbol as u8];
+    for pair in weights[..max_symbol].chunks(2) {
+        data.push((pair[0] << 4) | pair.get(1).copied().unwrap_or(0));
+    }
+    let stream = |part: &[u8]| {
+        let mut w = BitWriter::new();
+        for b in part.iter().rev() {
+            w.write(codes[*b as usize], lengths[*b as usize]);
+        }
+        w.finish()
+    };
+    let regenerated = literals.len();
+    let four_streams = regenerated > 1023;
+    if four_streams {
+        let quarter = regenerated.div_ceil(4);
+        let streams: Vec<_> = literals.chunks(quarter).map(stream).collect();
+        if streams.len() != 4 {
+            return None;
+        }
+        for s in streams[..3].iter() {
+            data.extend_from_slice(&(s.len() as u16).to_le_bytes());
+        }
+        for s in streams.iter() {
+            data.extend_from_slice(s);
+        }
+    } else {
+        data.extend_from_slice(&stream(literals));
+    }
+    let compressed = data.len();
+    let mut out = Vec::with_capacity(5 + compressed);
+    if !four_streams {
+        // a single stream has 10 bits for either size
+        if compressed > 1023 {
+            return None;
+        }
+        let v = 2 | (regenerated << 4) | (compressed << 14);
+        out.extend_from_slice(&v.to_le_bytes()[..3]);
+    } else if regenerated <= 1023 && compressed <= 1023 {
+        let v = 2 | (1 << 2) | (regenerated << 4) | (compressed << 14);
+        out.extend_from_slice(&v.to_le_bytes()[..3]);
+    } else if regenerated <= 0x3FFF && compressed <= 0x3FFF {
+        let v = 2 | (2 << 2) | (regenerated << 4) | (compressed << 18);
+        out.extend_from_slice(&v.to_le_bytes()[..4]);
+    } else if compressed <= 0x3FFFF {
+        let v = 2 | (3 << 2) | (regenerated as u64) << 4 | (compressed as u64) << 22;
+        out.extend_from_slice(&v.to_le_bytes()[..5]);
+    } else {
+        return None;
+    }
+    out.extend_from_slice(&data);
+    if out.len() >= regenerated {
+        return None;
+    }
+    Some(out)
+}
+
+fn write_literals(literals: &[u8], out: &mut Vec<u8>) {
+    if let Some(coded) = huffman_literals(literals) {
+        out.extend_from_slice(&coded);
+        return;
+    }
+    let n = literals.len();
+    if n < 32 {
+        out.push((n << 3) as u8);
+    } else if n < 4096 {
+        out.push((n << 4) as u8 | 0x04);
+        out.push((n >> 4) as u8);
+    } else {
+        out.push((n << 4) as u8 | 0x0C);
+        out.push((n >> 4) as u8);
+        out.push((n >> 12) as u8);
+    }
+    out.extend_from_slice(literals);
+}
+
+fn read_u32(src: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([src[i], src[i + 1], src[i + 2], src[i + 3]])
+}
+
+fn hash(v: u32) -> usize {
+    (v.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+// the content of a compressed block for `src[start..end]`, whose matches
+// may reach back to the start of the frame
+fn compress_block(src: &[u8], start: usize, end: usize, table: &mut [u32]) -> Vec<u8> {
+    let mut seqs = Vec::new();
+    let mut literals = Vec::new();
+    let mut anchor = start;
+    let mut i = start;
+    while i + MIN_MATCH <= end {
+        let v = read_u32(src, i);
+        let h = hash(v);
+        // positions are stored plus one, zero is an empty slot
+        let candidate = table[h] as usize;
+        table[h] = i as u32 + 1;
+        if candidate > 0 && read_u32(src, candidate - 1) == v {
+            let c = candidate - 1;
+            let mut len = MIN_MATCH;
+            while i + len < end && src[c + len] == src[i + len] {
+                len += 1;
+            }
+            literals.extend_from_slice(&src[anchor..i]);
+            seqs.push(Sequence {
+                literals_len: i - anchor,
+                offset: i - c,
+                match_len: len,
+            });
+            i += len;
+            anchor = i;
+            continue;
+        }
+        i += 1;
+    }
+    literals.extend_from_slice(&src[anchor..end]);
+    let mut out = Vec::new();
+    write_literals(&literals, &mut out);
+    write_sequences(&seqs, &mut out);
+    out
+}
+
+/// Appends `src` as a single segment zstd frame to `out`.
+pub(super) fn zstd_compress(src: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&MAGIC.to_le_bytes());
+    let n = src.len() as u64;
+    if n < 256 {
+        out.extend_from_slice(&[0x20, n as u8]);
+    } else if n < 65536 + 256 {
+        out.push(0x60);
+        out.extend_from_slice(&((n - 256) as u16).to_le_bytes());
+    } else if n <= u64::from(u32::MAX) {
+        out.push(0xA0);
+        out.extend_from_slice(&(n as u32).to_le_bytes());
+    } else {
+        out.push(0xE0);
+        out.extend_from_slice(&n.to_le_bytes());
+    }
+    if src.is_empty() {
+        // a last raw block of nothing
+        out.extend_from_slice(&[1, 0, 0]);
+        return;
+    }
+    let mut table = vec![0u32; 1 << HASH_LOG];
+    let mut start = 0;
+    while start < src.len() {
+        let end = std::cmp::min(start + MAX_BLOCK_SIZE, src.len());
+        let last = usize::from(end == src.len());
+        let block = compress_block(src, start, end, &mut table);
+        let (kind, body) = if block.len() < end - start {
+            (2, &block[..])
+        } else {
+            (0, &src[start..end])
+        };
+        let header = (body.len() << 3) | (kind << 1) | last;
+        out.extend_from_slice(&header.to_le_bytes()[..3]);
+        out.extend_from_slice(body);
+        start = end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zstd_roundtrip() {
+        let text = b"GET / HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n".repeat(20);
+        let mut noise = Vec::new();
+        let mut x: u32 = 7;
+        for _ in 0..300_000 {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            noise.push((x >> 16) as u8);
+        }
+        // past one block, with matches reaching back across blocks
+        let mut long = noise[..100_000].to_vec();
+        long.extend_from_slice(&text[..]);
+        long.extend_from_slice(&noise[..100_000]);
+        let lines: String = (0..5000)
+            .map(|i| format!("{} {}\n", i, i * i % 97))
+            .collect();
+        for src in [
+            &text[..],
+            &noise[..],
+            &long[..],
+            lines.as_bytes(),
+            b"",
+            b"abc",
+            &[0u8; 300][..],
+        ]
+        .iter()
+        {
+            let mut frame = Vec::new();
+            zstd_compress(src, &mut frame);
+            assert_eq!(
+                zstd_decompress(&frame[..], src.len()).unwrap(),
+                src.to_vec()
+            );
+        }
+        let mut frame = Vec::new();
+        zstd_compress(&text[..], &mut frame);
+        assert!(frame.len() < text.len() / 4);
+        assert!(zstd_decompress(&frame[..], text.len() - 1).is_none());
+        assert!(zstd_decompress(&frame[..frame.len() - 3], text.len()).is_none());
+    }
+
+    #[test]
+    fn test_zstd_reference_frame() {
+        // `zstd -19 --check` of the lines below
+        const FRAME: &[u8] = &[
+            0x28, 0xb5, 0x2f, 0xfd, 0x64, 0x55, 0x00, 0x55, 0x05, 0x00, 0x56, 0x95, 0x29, 0x09,
+            0xa0, 0xed, 0xf0, 0xdb, 0x0d, 0x90, 0x0a, 0x90, 0x85, 0x25, 0x00, 0x26, 0x00, 0x26,
+            0x00, 0x8b, 0xd5, 0xe2, 0x98, 0x56, 0x2c, 0xa5, 0x58, 0xae, 0x13, 0x1f, 0x55, 0x62,
+            0x92, 0x46, 0x3c, 0x85, 0x38, 0xb0, 0x39, 0x59, 0xf1, 0xa4, 0xde, 0x36, 0xb5, 0x4b,
+            0x5a, 0x5b, 0x94, 0xce, 0xce, 0x54, 0x39, 0x1a, 0x43, 0x21, 0x8d, 0xf9, 0xa4, 0x30,
+            0x97, 0x35, 0x65, 0x2c, 0xca, 0x50, 0x3d, 0x99, 0xb2, 0x26, 0x0f, 0xb5, 0xe4, 0xc8,
+            0x92, 0x4c, 0xd4, 0x91, 0x2d, 0x2b, 0xf2, 0x54, 0x43, 0x96, 0x28, 0xc8, 0x70, 0xcd,
+            0x78, 0xa6, 0x18, 0x57, 0xf6, 0x22, 0x17, 0xb6, 0xe8, 0xc9, 0x12, 0x6d, 0x1d, 0x1a,
+            0xa3, 0x42, 0xcb, 0x34, 0x68, 0xa4, 0x02, 0x6d, 0xd6, 0x9c, 0x0d, 0x8b, 0x73, 0x44,
+            0x6f, 0xae, 0xa9, 0xcd, 0x61, 0xad, 0x39, 0xb1, 0x34, 0x8b, 0x74, 0x66, 0xaa, 0xca,
+            0x8c, 0x18, 0x1b, 0x36, 0x6b, 0xb3, 0x62, 0x8d, 0xd4, 0xab, 0x65, 0x6a, 0x35, 0x46,
+            0xab, 0xb6, 0x52, 0x3d, 0xd9, 0xa9, 0x0b, 0x2b, 0x75, 0x5e, 0xa3, 0xae, 0x2b, 0xd4,
+            0x73, 0x4d, 0x7a, 0xae, 0x48, 0xd7, 0xf5, 0xe8, 0xbc, 0x1a, 0x05, 0x00, 0x51, 0x13,
+            0xb2, 0x63,
+        ];
+        let lines: String = (0..60).map(|i| format!("{} {}\n", i, i * i % 97)).collect();
+        assert_eq!(zstd_decompress(FRAME, 4096).unwrap(), lines.as_bytes());
+        let mut corrupted = FRAME.to_vec();
+        let n = corrupted.len();
+        corrupted[n - 1] ^= 1;
+        assert!(zstd_decompress(&corrupted[..], 4096).is_none());
+    }
+
+    #[test]
+    fn test_xxh64() {
+        assert_eq!(xxh64(b""), 0xEF46_DB37_51D8_E999);
+    }
+}
@@ -0,0 +1,747 @@
+//! Certificates of TLS listeners from an ACME (RFC 8555) CA. The account
+//! key and the certificate are kept in `cache_dir`, a certificate is
+//! requested at start when there is none or it is about to expire, and a
+//! background task renews it later on. Challenges are answered over
+//! HTTP-01 by a small responder on `http_listen`; the new certificate is
+//! handed to new handshakes right away, no reload needed.
+use super::http_client::{read_response, HttpResponse};
+use super::tls::{cert_not_after, new_client_config, tls_connect};
+use crate::config::AcmeConfig;
+use crate::utils::make_io_error;
+use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType};
+use ring::digest::{digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::{ResolvesServerCert, SignatureScheme};
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::delay_for;
+use url::Url;
+
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+const RENEW_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+// Let's Encrypt certificates last 90 days
+const RENEW_BEFORE_SECS: i64 = 30 * 24 * 3600;
+const POLL_INTERVAL: Duration = Duration::from_secs(3);
+const POLL_ATTEMPTS: usize = 40;
+
+lazy_static! {
+    /// key authorizations of the pending challenges by token
+    static ref CHALLENGES: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+    /// one resolver per cache_dir, shared by the listeners using it
+    static ref RESOLVERS: tokio::sync::Mutex<HashMap<String, Arc<AcmeCertResolver>>> =
+        tokio::sync::Mutex::new(HashMap::new());
+}
+
+fn to_io_error(e: rcgen::RcgenError) -> std::io::Error {
+    make_io_error(&e.to_string())
+}
+
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+/// Hands out the current certificate, renewals swap in the next one.
+pub struct AcmeCertResolver {
+    current: RwLock<Option<(CertifiedKey, i64)>>,
+}
+
+impl AcmeCertResolver {
+    fn set(&self, key: CertifiedKey, not_after: i64) {
+        *self.current.write().unwrap() = Some((key, not_after));
+    }
+
+    fn needs_renewal(&self) -> bool {
+        match &*self.current.read().unwrap() {
+            Some((_, not_after)) => *not_after - chrono::Utc::now().timestamp() < RENEW_BEFORE_SECS,
+            None => true,
+        }
+    }
+}
+
+impl ResolvesServerCert for AcmeCertResolver {
+    fn resolve(
+        &self,
+        _server_name: Option<webpki::DNSNameRef>,
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        self.current
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|(k, _)| k.clone())
+    }
+}
+
+/// The resolver of `cfg`'s certificate; the first call per `cache_dir`
+/// loads or requests it and starts the responder and the renewals.
+pub async fn acme_resolver(cfg: &AcmeConfig) -> Result<Arc<AcmeCertResolver>, std::io::Error> {
+    let mut resolvers = RESOLVERS.lock().await;
+    if let Some(r) = resolvers.get(&cfg.cache_dir) {
+        return Ok(r.clone());
+    }
+    if cfg.domains.is_empty() {
+        return Err(make_io_error("acme requires at least one domain"));
+    }
+    std::fs::create_dir_all(cfg.cache_dir.as_str())?;
+    let listener = TcpListener::bind(cfg.http_listen()).await?;
+    tokio::spawn(serve_challenges(listener));
+
+    let resolver = Arc::new(AcmeCertResolver {
+        current: RwLock::new(None),
+    });
+    match load_cached(cfg) {
+        Ok((key, not_after)) => resolver.set(key, not_after),
+        Err(e) => info!("no usable acme certificate in {}: {}", cfg.cache_dir, e),
+    }
+    if resolver.needs_renewal() {
+        let (key, not_after) = provision(cfg).await?;
+        resolver.set(key, not_after);
+    }
+    tokio::spawn(renew(cfg.clone(), resolver.clone()));
+    resolvers.insert(cfg.cache_dir.clone(), resolver.clone());
+    Ok(resolver)
+}
+
+async fn renew(cfg: AcmeConfig, resolver: Arc<AcmeCertResolver>) {
+    loop {
+        delay_for(RENEW_CHECK_INTERVAL).await;
+        if !resolver.needs_renewal() {
+            continue;
+        }
+        match provision(&cfg).await {
+            Ok((key, not_after)) => {
+                info!("renewed acme certificate of {:?}", cfg.domains);
+                resolver.set(key, not_after);
+            }
+            Err(e) => error!(
+                "Failed to renew acme certificate of {:?}: {}",
+                cfg.domains, e
+            ),
+        }
+    }
+}
+
+fn load_cached(cfg: &AcmeConfig) -> Result<(CertifiedKey, i64), std::io::Error> {
+    let dir = Path::new(cfg.cache_dir.as_str());
+    let chain = {
+        let mut reader = BufReader::new(std::fs::File::open(dir.join("cert.pem"))?);
+        certs(&mut reader).unwrap_or_default()
+    };
+    let mut keys = {
+        let mut reader = BufReader::new(std::fs::File::open(dir.join("key.pem"))?);
+        pkcs8_private_keys(&mut reader).unwrap_or_default()
+    };
+    if chain.is_empty() || keys.is_empty() {
+        return Err(make_io_error("invalid cached acme certificate"));
+    }
+    let not_after = match cert_not_after(&chain[0].0[..]) {
+        Some(t) => t,
+        None => return Err(make_io_error("invalid cached acme certificate")),
+    };
+    let key = match any_supported_type(&keys.remove(0)) {
+        Ok(k) => k,
+        Err(_) => return Err(make_io_error("unsupported acme certificate key")),
+    };
+    Ok((CertifiedKey::new(chain, Arc::new(key)), not_after))
+}
+
+/// Orders a certificate, saves it in `cache_dir` and loads it from there.
+async fn provision(cfg: &AcmeConfig) -> Result<(CertifiedKey, i64), std::io::Error> {
+    info!("requesting acme certificate of {:?}", cfg.domains);
+    let mut client = AcmeClient::new(cfg).await?;
+    let (chain, key) = client.order(&cfg.domains[..]).await?;
+    let dir = Path::new(cfg.cache_dir.as_str());
+    std::fs::write(dir.join("key.pem"), key)?;
+    std::fs::write(dir.join("cert.pem"), chain)?;
+    load_cached(cfg)
+}
+
+async fn serve_challenges(mut listener: TcpListener) {
+    while let Ok((conn, _)) = listener.accept().await {
+        tokio::spawn(async move {
+            if let Err(e) = answer_challenge(conn).await {
+                debug!("Failed to answer acme challenge: {}", e);
+            }
+        });
+    }
+}
+
+async fn answer_challenge(mut conn: TcpStream) -> Result<(), std::io::Error> {
+    let mut buf = vec![0u8; 4096];
+    let mut n = 0;
+    let path = loop {
+        let r = conn.read(&mut buf[n..]).await?;
+        if r == 0 {
+            return Ok(());
+        }
+        n += r;
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(&buf[..n]) {
+            Ok(httparse::Status::Complete(_)) => break String::from(req.path.unwrap_or("")),
+            Ok(httparse::Status::Partial) if n < buf.len() => continue,
+            _ => return Ok(()),
+        }
+    };
+    let key_auth = path
+        .strip_prefix(CHALLENGE_PATH)
+        .and_then(|token| CHALLENGES.lock().unwrap().get(token).cloned());
+    let resp = match key_auth {
+        Some(k) => format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            k.len(),
+            k
+        ),
+        None => String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+    };
+    conn.write_all(resp.as_bytes()).await
+}
+
+/// The ES256 key of the ACME account, generated on first use.
+struct AccountKey {
+    pair: EcdsaKeyPair,
+    jwk: String,
+    thumbprint: String,
+}
+
+impl AccountKey {
+    fn load(cache_dir: &str) -> Result<Self, std::io::Error> {
+        let path = Path::new(cache_dir).join("account.key");
+        let pkcs8 = match std::fs::read(&path) {
+            Ok(d) => d,
+            Err(_) => {
+                let doc = EcdsaKeyPair::generate_pkcs8(
+                    &ECDSA_P256_SHA256_FIXED_SIGNING,
+                    &SystemRandom::new(),
+                )
+                .map_err(|_| make_io_error("failed to generate acme account key"))?;
+                std::fs::write(&path, doc.as_ref())?;
+                doc.as_ref().to_vec()
+            }
+        };
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8[..])
+            .map_err(|_| make_io_error("invalid acme account key"))?;
+        // uncompressed point, 0x04 || x || y
+        let public = pair.public_key().as_ref();
+        // members in lexicographic order and no whitespace, as the
+        // thumbprint (RFC 7638) wants them
+        let jwk = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            b64(&public[1..33]),
+            b64(&public[33..65])
+        );
+        let thumbprint = b64(digest(&SHA256, jwk.as_bytes()).as_ref());
+        Ok(Self {
+            pair,
+            jwk,
+            thumbprint,
+        })
+    }
+
+    /// A flattened JWS of `payload`, identified by the account url `kid`
+    /// once there is one and by the public key before.
+    fn sign(
+        &self,
+        url: &str,
+        nonce: &str,
+        kid: Option<&str>,
+        payload: &str,
+    ) -> Result<String, std::io::Error> {
+        let key = match kid {
+            Some(k) => format!(r#""kid":{}"#, json_str(k)),
+            None => format!(r#""jwk":{}"#, self.jwk),
+        };
+        let protected = format!(
+            r#"{{"alg":"ES256",{},"nonce":{},"url":{}}}"#,
+            key,
+            json_str(nonce),
+            json_str(url)
+        );
+        let protected = b64(protected.as_bytes());
+        let payload = b64(payload.as_bytes());
+        let input = format!("{}.{}", protected, payload);
+        let sig = self
+            .pair
+            .sign(&SystemRandom::new(), input.as_bytes())
+            .map_err(|_| make_io_error("failed to sign acme request"))?;
+        Ok(format!(
+            r#"{{"protected":"{}","payload":"{}","signature":"{}"}}"#,
+            protected,
+            payload,
+            b64(sig.as_ref())
+        ))
+    }
+}
+
+struct AcmeClient {
+    key: AccountKey,
+    directory: Json,
+    nonce: Option<String>,
+    kid: Option<String>,
+}
+
+impl AcmeClient {
+    async fn new(cfg: &AcmeConfig) -> Result<Self, std::io::Error> {
+        let key = AccountKey::load(cfg.cache_dir.as_str())?;
+        let directory = https_request("GET", cfg.directory(), None).await?.json()?;
+        let mut client = Self {
+            key,
+            directory,
+            nonce: None,
+            kid: None,
+        };
+        // an existing account is returned as is
+        let contact = match &cfg.email {
+            Some(e) => format!(r#","contact":[{}]"#, json_str(&format!("mailto:{}", e))),
+            None => String::new(),
+        };
+        let payload = format!(r#"{{"termsOfServiceAgreed":true{}}}"#, contact);
+        let url = client.url("newAccount")?;
+        let resp = client.post(url.as_str(), payload.as_str()).await?;
+        client.kid = Some(resp.location()?);
+        Ok(client)
+    }
+
+    fn url(&self, name: &str) -> Result<String, std::io::Error> {
+        match self.directory.get(name).and_then(Json::as_str) {
+            Some(u) => Ok(String::from(u)),
+            None => Err(make_io_error(&format!("acme directory has no {}", name))),
+        }
+    }
+
+    async fn nonce(&mut self) -> Result<String, std::io::Error> {
+        if let Some(n) = self.nonce.take() {
+            return Ok(n);
+        }
+        let url = self.url("newNonce")?;
+        let resp = https_request("HEAD", url.as_str(), None).await?;
+        match resp.header("replay-nonce") {
+            Some(n) => Ok(String::from(n)),
+            None => Err(make_io_error("acme server sent no nonce")),
+        }
+    }
+
+    /// Signed POST of `payload`, an empty one is a POST-as-GET. A request
+    /// whose nonce was refused is sent once more with a fresh one.
+    async fn post(&mut self, url: &str, payload: &str) -> Result<HttpResponse, std::io::Error> {
+        let mut retried = false;
+        loop {
+            let nonce = self.nonce().await?;
+            let body = self
+                .key
+                .sign(url, nonce.as_str(), self.kid.as_deref(), payload)?;
+            let resp = https_request("POST", url, Some(body.as_str())).await?;
+            self.nonce = resp.header("replay-nonce").map(String::from);
+            if resp.status < 300 {
+                return Ok(resp);
+            }
+            let problem = resp.json().ok();
+            let field = |name: &str| {
+                problem
+                    .as_ref()
+                    .and_then(|p| p.get(name))
+                    .and_then(Json::as_str)
+                    .unwrap_or("")
+            };
+            if field("type").ends_with(":badNonce") && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(make_io_error(&format!(
+                "acme request to {} failed with {}: {} {}",
+                url,
+                resp.status,
+                field("type"),
+                field("detail")
+            )));
+        }
+    }
+
+    /// POST-as-GET of `url` until its status is `want`.
+    async fn poll(&mut self, url: &str, want: &str) -> Result<Json, std::io::Error> {
+        for _ in 0..POLL_ATTEMPTS {
+            let v = self.post(url, "").await?.json()?;
+            match v.get("status").and_then(Json::as_str) {
+                Some(s) if s == want => return Ok(v),
+                Some("invalid") => {
+                    return Err(make_io_error(&format!("acme {} became invalid", url)));
+                }
+                _ => delay_for(POLL_INTERVAL).await,
+            }
+        }
+        Err(make_io_error(&format!(
+            "acme {} is still not {}",
+            url, want
+        )))
+    }
+
+    async fn authorize(&mut self, url: &str) -> Result<(), std::io::Error> {
+        let auth = self.post(url, "").await?.json()?;
+        if auth.get("status").and_then(Json::as_str) == Some("valid") {
+            return Ok(());
+        }
+        let challenge = auth
+            .get("challenges")
+            .map_or(&[][..], Json::as_array)
+            .iter()
+            .find(|c| c.get("type").and_then(Json::as_str) == Some("http-01"));
+        let (token, challenge_url) = match challenge.map(|c| (c.get("token"), c.get("url"))) {
+            Some((Some(Json::Str(t)), Some(Json::Str(u)))) => (t.clone(), u.clone()),
+            _ => return Err(make_io_error("acme authorization has no http-01 challenge")),
+        };
+        let key_auth = format!("{}.{}", token, self.key.thumbprint);
+        CHALLENGES.lock().unwrap().insert(token.clone(), key_auth);
+        let result = match self.post(challenge_url.as_str(), "{}").await {
+            Ok(_) => self.poll(url, "valid").await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        CHALLENGES.lock().unwrap().remove(&token);
+        result
+    }
+
+    /// Orders a certificate of `domains`, returning the PEM chain and key.
+    async fn order(&mut self, domains: &[String]) -> Result<(String, String), std::io::Error> {
+        let ids: Vec<String> = domains
+            .iter()
+            .map(|d| format!(r#"{{"type":"dns","value":{}}}"#, json_str(d)))
+            .collect();
+        let url = self.url("newOrder")?;
+        let payload = format!(r#"{{"identifiers":[{}]}}"#, ids.join(","));
+        let resp = self.post(url.as_str(), payload.as_str()).await?;
+        let order_url = resp.location()?;
+        let order = resp.json()?;
+        let auths: Vec<String> = order
+            .get("authorizations")
+            .map_or(&[][..], Json::as_array)
+            .iter()
+            .filter_map(|a| a.as_str().map(String::from))
+            .collect();
+        for auth in auths.iter() {
+            self.authorize(auth.as_str()).await?;
+        }
+        let finalize = match order.get("finalize").and_then(Json::as_str) {
+            Some(u) => String::from(u),
+            None => return Err(make_io_error("acme order has no finalize url")),
+        };
+
+        let mut params = CertificateParams::new(domains.to_vec());
+        let mut dn = DistinguishedName::new();
+        dn.push(DnType::CommonName, domains[0].as_str());
+        params.distinguished_name = dn;
+        let cert = Certificate::from_params(params).map_err(to_io_error)?;
+        let csr = cert.serialize_request_der().map_err(to_io_error)?;
+        let payload = format!(r#"{{"csr":"{}"}}"#, b64(&csr[..]));
+        self.post(finalize.as_str(), payload.as_str()).await?;
+        let order = self.poll(order_url.as_str(), "valid").await?;
+        let cert_url = match order.get("certificate").and_then(Json::as_str) {
+            Some(u) => String::from(u),
+            None => return Err(make_io_error("acme order has no certificate url")),
+        };
+        let chain = self.post(cert_url.as_str(), "").await?.body;
+        match String::from_utf8(chain) {
+            Ok(chain) => Ok((chain, cert.serialize_private_key_pem())),
+            Err(_) => Err(make_io_error("invalid acme certificate chain")),
+        }
+    }
+}
+
+impl HttpResponse {
+    fn location(&self) -> Result<String, std::io::Error> {
+        match self.header("location") {
+            Some(l) => Ok(String::from(l)),
+            None => Err(make_io_error("acme response has no location")),
+        }
+    }
+
+    fn json(&self) -> Result<Json, std::io::Error> {
+        match std::str::from_utf8(&self.body[..])
+            .ok()
+            .and_then(Json::parse)
+        {
+            Some(v) => Ok(v),
+            None => Err(make_io_error("invalid json in acme response")),
+        }
+    }
+}
+
+/// One request per connection, the CA is only talked to every few weeks.
+async fn https_request(
+    method: &str,
+    url: &str,
+    body: Option<&str>,
+) -> Result<HttpResponse, std::io::Error> {
+    let u = Url::parse(url).map_err(|e| make_io_error(&e.to_string()))?;
+    let host = match u.host_str() {
+        Some(h) if u.scheme() == "https" => h,
+        _ => return Err(make_io_error(&format!("invalid acme url:{}", url))),
+    };
+    let port = u.port_or_known_default().unwrap_or(443);
+    let mut path = String::from(u.path());
+    if let Some(q) = u.query() {
+        path.push('?');
+        path.push_str(q);
+    }
+    let conn = TcpStream::connect(format!("{}:{}", host, port)).await?;
+    let config = Arc::new(new_client_config(&["http/1.1"]));
+    let mut stream = tls_connect(conn, host, config).await?;
+    let mut req = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: rsnova\r\nAccept: */*\r\nConnection: close\r\n",
+        method, path, host
+    );
+    if let Some(b) = body {
+        req.push_str("Content-Type: application/jose+json\r\n");
+        req.push_str(format!("Content-Length: {}\r\n", b.len()).as_str());
+    }
+    req.push_str("\r\n");
+    req.push_str(body.unwrap_or(""));
+    stream.write_all(req.as_bytes()).await?;
+    read_response(&mut stream, method == "HEAD").await
+}
+
+fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(format!("\\u{:04x}", c as u32).as_str()),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Just enough JSON for the CA's replies.
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(s: &str) -> Option<Json> {
+        let mut p = JsonParser {
+            s: s.as_bytes(),
+            pos: 0,
+        };
+        let v = p.value()?;
+        p.skip_ws();
+        if p.pos == p.s.len() {
+            Some(v)
+        } else {
+            None
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Obj(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::Str(s) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    fn as_array(&self) -> &[Json] {
+        match self {
+            Json::Arr(a) => &a[..],
+            _ => &[],
+        }
+    }
+}
+
+struct JsonParser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).copied()
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\r') | Some(b'\n') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn literal(&mut self, lit: &str, v: Json) -> Option<Json> {
+        if self.s[self.pos..].starts_with(lit.as_bytes()) {
+            self.pos += lit.len();
+            Some(v)
+        } else {
+            None
+        }
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.skip_ws();
+        match self.peek()? {
+            b'{' => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.skip_ws();
+                if self.peek()? == b'}' {
+                    self.pos += 1;
+                    return Some(Json::Obj(members));
+                }
+                loop {
+                    self.skip_ws();
+                    let key = self.string()?;
+                    self.skip_ws();
+                    if self.peek()? != b':' {
+                        return None;
+                    }
+                    self.pos += 1;
+                    members.push((key, self.value()?));
+                    self.skip_ws();
+                    match self.peek()? {
+                        b',' => self.pos += 1,
+                        b'}' => {
+                            self.pos += 1;
+                            return Some(Json::Obj(members));
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+            b'[' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_ws();
+                if self.peek()? == b']' {
+                    self.pos += 1;
+                    return Some(Json::Arr(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_ws();
+                    match self.peek()? {
+                        b',' => self.pos += 1,
+                        b']' => {
+                            self.pos += 1;
+                            return Some(Json::Arr(items));
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+            b'"' => self.string().map(Json::Str),
+            b't' => self.literal("true", Json::Bool(true)),
+            b'f' => self.literal("false", Json::Bool(false)),
+            b'n' => self.literal("null", Json::Null),
+            _ => {
+                let start = self.pos;
+                while let Some(b) = self.peek() {
+                    if !(b.is_ascii_digit() || b"+-.eE".contains(&b)) {
+                        break;
+                    }
+                    self.pos += 1;
+                }
+                let num = std::str::from_utf8(&self.s[start..self.pos]).ok()?;
+                num.parse().ok().map(Json::Num)
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let hex = std::str::from_utf8(self.s.get(self.pos..self.pos + 4)?).ok()?;
+        self.pos += 4;
+        u32::from_str_radix(hex, 16).ok()
+    }
+
+    fn string(&mut self) -> Option<String> {
+        if self.peek()? != b'"' {
+            return None;
+        }
+        self.pos += 1;
+        let mut out = Vec::new();
+        loop {
+            let b = self.peek()?;
+            self.pos += 1;
+            match b {
+                b'"' => return String::from_utf8(out).ok(),
+                b'\\' => {
+                    let e = self.peek()?;
+                    self.pos += 1;
+                    match e {
+                        b'"' | b'\\' | b'/' => out.push(e),
+                        b'b' => out.push(8),
+                        b'f' => out.push(12),
+                        b'n' => out.push(b'\n'),
+                        b'r' => out.push(b'\r'),
+                        b't' => out.push(b'\t'),
+                        b'u' => {
+                            let mut c = self.hex4()?;
+                            if (0xd800..0xdc00).contains(&c) {
+                                if !self.s[self.pos..].starts_with(b"\\u") {
+                                    return None;
+                                }
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return None;
+                                }
+                                c = 0x10000 + ((c - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            let mut utf8 = [0u8; 4];
+                            let c = std::char::from_u32(c)?;
+                            out.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+                        }
+                        _ => return None,
+                    }
+                }
+                _ => out.push(b),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json() {
+        let s = r#" {"status": "pending", "n": -1.5e2, "ok": true, "none": null,
+            "challenges": [{"type": "http-01", "token": "a\"bé😀"}, []] } "#;
+        let v = Json::parse(s).unwrap();
+        assert_eq!(v.get("status").and_then(Json::as_str), Some("pending"));
+        assert_eq!(v.get("n"), Some(&Json::Num(-150.0)));
+        assert_eq!(v.get("ok"), Some(&Json::Bool(true)));
+        assert_eq!(v.get("none"), Some(&Json::Null));
+        let challenges = v.get("challenges").unwrap().as_array();
+        assert_eq!(challenges.len(), 2);
+        let token = challenges[0].get("token").and_then(Json::as_str).unwrap();
+        assert_eq!(token, "a\"b\u{e9}\u{1f600}");
+        assert_eq!(
+            Json::parse(&json_str(token)),
+            Some(Json::Str(String::from(token)))
+        );
+        assert!(Json::parse(r#"{"a":1"#).is_none());
+        assert!(Json::parse(r#"{"a":1} x"#).is_none());
+    }
+}
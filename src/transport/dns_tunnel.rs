@@ -0,0 +1,805 @@
+//! Experimental transport carrying a byte stream in DNS queries and answers,
+//! for networks where nothing but name resolution gets out.
+//!
+//! The client polls: every query is a TXT lookup of
+//! `<base32 of session|seq|flags|data>.<domain>`, and the remote, acting as
+//! the authoritative server of `domain`, answers with the data queued for
+//! that session. Only one query is in flight at a time, a lost one is simply
+//! sent again and the server replays its last answer for a repeated seq.
+//! The first query of a session carries an HMAC of its id under the tunnel
+//! key, the server opens no session for a query without it.
+use crate::utils::make_io_error;
+use bytes::{Buf, BufMut, BytesMut};
+use ring::constant_time::verify_slices_are_equal;
+use ring::hmac;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::delay_for;
+
+const TYPE_TXT: u16 = 16;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+const RCODE_REFUSED: u16 = 5;
+const EDNS_UDP_SIZE: u16 = 1232;
+const MAX_NAME_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+// session id, seq and flags in front of every upstream payload
+const UP_HEADER_LEN: usize = 9;
+// truncated HMAC of the session id behind the header of its first payload
+const OPEN_TAG_LEN: usize = 8;
+// open sessions a listener takes at once
+const MAX_SESSIONS: usize = 256;
+// upstream: the client is done
+const FLAG_FIN: u8 = 1;
+// downstream: more data is queued, poll again right away
+const FLAG_MORE: u8 = 2;
+// downstream: the session is unknown or closed
+const FLAG_CLOSED: u8 = 4;
+const MAX_PENDING: usize = 64 * 1024;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const QUERY_RETRIES: usize = 5;
+const POLL_STEP: Duration = Duration::from_millis(50);
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const SESSION_IDLE: Duration = Duration::from_secs(90);
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+fn open_tag(key: &hmac::Key, session: u32) -> hmac::Tag {
+    hmac::sign(key, &session.to_be_bytes())
+}
+
+/// Unpadded lower case base32, safe in DNS labels which are case insensitive.
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() * 8).div_ceil(5));
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for b in data {
+        acc = (acc << 8) | u32::from(*b);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((acc >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((acc << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+pub fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let v = match c.to_ascii_lowercase() {
+            c @ b'a'..=b'z' => c - b'a',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        acc = (acc << 5) | u32::from(v);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Most data bytes one query name under `domain` can carry.
+fn max_upstream(domain: &str) -> usize {
+    let avail = MAX_NAME_LEN.saturating_sub(domain.len());
+    // every label is followed by a dot
+    let chars = avail - (avail + MAX_LABEL_LEN) / (MAX_LABEL_LEN + 1);
+    (chars * 5 / 8).saturating_sub(UP_HEADER_LEN)
+}
+
+fn payload_name(payload: &[u8], domain: &str) -> String {
+    let encoded = base32_encode(payload);
+    let mut name = String::with_capacity(MAX_NAME_LEN);
+    for label in encoded.as_bytes().chunks(MAX_LABEL_LEN) {
+        name.push_str(std::str::from_utf8(label).unwrap());
+        name.push('.');
+    }
+    name.push_str(domain);
+    name
+}
+
+fn in_zone(name: &str, domain: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name == domain || name.ends_with(format!(".{}", domain).as_str())
+}
+
+fn name_payload(name: &str, domain: &str) -> Option<Vec<u8>> {
+    if !in_zone(name, domain) || name.len() <= domain.len() + 1 {
+        return None;
+    }
+    let labels = &name[..name.len() - domain.len() - 1];
+    base32_decode(labels.replace('.', "").as_str())
+}
+
+fn be16(msg: &[u8], pos: usize) -> Option<u16> {
+    let b = msg.get(pos..pos + 2)?;
+    Some(u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *msg.get(pos)? as usize;
+        if len & 0xc0 == 0xc0 {
+            if end.is_none() {
+                end = Some(pos + 2);
+            }
+            jumps += 1;
+            if jumps > 16 {
+                return None;
+            }
+            pos = ((len & 0x3f) << 8) | *msg.get(pos + 1)? as usize;
+            continue;
+        }
+        if len == 0 {
+            return Some((name, end.unwrap_or(pos + 1)));
+        }
+        if len > MAX_LABEL_LEN {
+            return None;
+        }
+        let label = msg.get(pos + 1..pos + 1 + len)?;
+        if !name.is_empty() {
+            name.push('.');
+        }
+        name.push_str(std::str::from_utf8(label).ok()?);
+        pos += 1 + len;
+    }
+}
+
+/// Name of the first question of a DNS message and the offset right behind
+/// that question.
+pub fn dns_question(msg: &[u8]) -> Option<(String, usize)> {
+    if be16(msg, 4)? == 0 {
+        return None;
+    }
+    let (name, pos) = read_name(msg, 12)?;
+    if pos + 4 > msg.len() {
+        return None;
+    }
+    Some((name, pos + 4))
+}
+
+fn write_name(name: &str, out: &mut BytesMut) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        out.put_u8(label.len() as u8);
+        out.put_slice(label.as_bytes());
+    }
+    out.put_u8(0);
+}
+
+fn write_opt(out: &mut BytesMut) {
+    out.put_u8(0);
+    out.put_u16(TYPE_OPT);
+    out.put_u16(EDNS_UDP_SIZE);
+    out.put_u32(0);
+    out.put_u16(0);
+}
+
+fn build_query(id: u16, name: &str) -> BytesMut {
+    let mut out = BytesMut::with_capacity(512);
+    out.put_u16(id);
+    // recursion desired, the query usually goes through a resolver
+    out.put_u16(0x0100);
+    out.put_u16(1);
+    out.put_u16(0);
+    out.put_u16(0);
+    out.put_u16(1);
+    write_name(name, &mut out);
+    out.put_u16(TYPE_TXT);
+    out.put_u16(CLASS_IN);
+    write_opt(&mut out);
+    out
+}
+
+struct Query {
+    id: u16,
+    flags: u16,
+    // the question section as received, so 0x20 case randomization survives
+    question: Vec<u8>,
+    name: String,
+    qtype: u16,
+    edns: Option<u16>,
+}
+
+impl Query {
+    /// Room for TXT data in an answer fitting the client's UDP size.
+    fn txt_budget(&self) -> usize {
+        let limit = match self.edns {
+            Some(size) => (size as usize).clamp(512, 4096),
+            None => 512,
+        };
+        let overhead = 12 + self.question.len() + 12 + if self.edns.is_some() { 11 } else { 0 };
+        let room = limit.saturating_sub(overhead);
+        // one length byte per 255 byte string
+        room.saturating_sub(room.div_ceil(255))
+    }
+}
+
+fn parse_query(msg: &[u8]) -> Option<Query> {
+    let id = be16(msg, 0)?;
+    let flags = be16(msg, 2)?;
+    if flags & 0x8000 != 0 || be16(msg, 4)? != 1 {
+        return None;
+    }
+    let records = u32::from(be16(msg, 6)?) + u32::from(be16(msg, 8)?) + u32::from(be16(msg, 10)?);
+    let (name, pos) = read_name(msg, 12)?;
+    let qtype = be16(msg, pos)?;
+    let question = msg.get(12..pos + 4)?.to_vec();
+    let mut pos = pos + 4;
+    let mut edns = None;
+    for _ in 0..records {
+        let (_, p) = read_name(msg, pos)?;
+        let ty = be16(msg, p)?;
+        let rdlen = be16(msg, p + 8)? as usize;
+        if ty == TYPE_OPT {
+            edns = Some(be16(msg, p + 2)?);
+        }
+        pos = p + 10 + rdlen;
+    }
+    Some(Query {
+        id,
+        flags,
+        question,
+        name,
+        qtype,
+        edns,
+    })
+}
+
+fn build_response(q: &Query, rcode: u16, txt: Option<&[u8]>) -> BytesMut {
+    let mut out = BytesMut::with_capacity(512);
+    out.put_u16(q.id);
+    // response, authoritative, echo recursion desired
+    out.put_u16(0x8400 | (q.flags & 0x0100) | rcode);
+    out.put_u16(1);
+    out.put_u16(if txt.is_some() { 1 } else { 0 });
+    out.put_u16(0);
+    out.put_u16(if q.edns.is_some() { 1 } else { 0 });
+    out.put_slice(&q.question[..]);
+    if let Some(data) = txt {
+        let mut rdata = BytesMut::with_capacity(data.len() + data.len() / 255 + 1);
+        for chunk in data.chunks(255) {
+            rdata.put_u8(chunk.len() as u8);
+            rdata.put_slice(chunk);
+        }
+        if data.is_empty() {
+            rdata.put_u8(0);
+        }
+        // pointer to the question name
+        out.put_u16(0xc00c);
+        out.put_u16(TYPE_TXT);
+        out.put_u16(CLASS_IN);
+        // never let resolvers cache an answer
+        out.put_u32(0);
+        out.put_u16(rdata.len() as u16);
+        out.put_slice(&rdata[..]);
+    }
+    if q.edns.is_some() {
+        write_opt(&mut out);
+    }
+    out
+}
+
+/// Concatenated strings of the first TXT answer of a successful response
+/// to query `id`; anything else is None and the query will be retried.
+fn parse_response(msg: &[u8], id: u16) -> Option<Vec<u8>> {
+    let flags = be16(msg, 2)?;
+    if be16(msg, 0)? != id || flags & 0x8000 == 0 || flags & 0xf != 0 {
+        return None;
+    }
+    let mut pos = 12;
+    for _ in 0..be16(msg, 4)? {
+        let (_, p) = read_name(msg, pos)?;
+        pos = p + 4;
+    }
+    for _ in 0..be16(msg, 6)? {
+        let (_, p) = read_name(msg, pos)?;
+        let ty = be16(msg, p)?;
+        let rdlen = be16(msg, p + 8)? as usize;
+        let rdata = msg.get(p + 10..p + 10 + rdlen)?;
+        pos = p + 10 + rdlen;
+        if ty != TYPE_TXT {
+            continue;
+        }
+        let mut data = Vec::with_capacity(rdlen);
+        let mut i = 0;
+        while i < rdata.len() {
+            let len = rdata[i] as usize;
+            data.extend_from_slice(rdata.get(i + 1..i + 1 + len)?);
+            i += 1 + len;
+        }
+        return Some(data);
+    }
+    None
+}
+
+struct DnsPipe {
+    inbound: BytesMut,
+    outbound: BytesMut,
+    last_active: Instant,
+    // set once the local side dropped or shut down its stream
+    released: bool,
+    closed: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl DnsPipe {
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(w) = self.read_waker.take() {
+            w.wake();
+        }
+        if let Some(w) = self.write_waker.take() {
+            w.wake();
+        }
+    }
+    fn push_inbound(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        self.inbound.extend_from_slice(data);
+        if let Some(w) = self.read_waker.take() {
+            w.wake();
+        }
+    }
+    fn take_outbound(&mut self, max: usize) -> BytesMut {
+        let n = std::cmp::min(max, self.outbound.len());
+        let data = self.outbound.split_to(n);
+        if let Some(w) = self.write_waker.take() {
+            w.wake();
+        }
+        data
+    }
+}
+
+type SharedPipe = Arc<Mutex<DnsPipe>>;
+
+fn new_pipe() -> SharedPipe {
+    Arc::new(Mutex::new(DnsPipe {
+        inbound: BytesMut::new(),
+        outbound: BytesMut::new(),
+        last_active: Instant::now(),
+        released: false,
+        closed: false,
+        read_waker: None,
+        write_waker: None,
+    }))
+}
+
+pub struct DnsStream {
+    pipe: SharedPipe,
+}
+
+impl Drop for DnsStream {
+    fn drop(&mut self) {
+        if let Ok(mut p) = self.pipe.lock() {
+            p.released = true;
+        }
+    }
+}
+
+impl AsyncRead for DnsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut p = self.pipe.lock().unwrap();
+        if p.inbound.is_empty() {
+            if p.closed {
+                return Poll::Ready(Ok(0));
+            }
+            p.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = std::cmp::min(buf.len(), p.inbound.len());
+        buf[..n].copy_from_slice(&p.inbound[..n]);
+        p.inbound.advance(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for DnsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut p = self.pipe.lock().unwrap();
+        if p.closed {
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+        }
+        if p.outbound.len() >= MAX_PENDING {
+            p.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = std::cmp::min(buf.len(), MAX_PENDING - p.outbound.len());
+        p.outbound.extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.pipe.lock().unwrap().released = true;
+        Poll::Ready(Ok(()))
+    }
+}
+
+async fn exchange(socket: &mut UdpSocket, name: &str, buf: &mut [u8]) -> Option<Vec<u8>> {
+    for _ in 0..QUERY_RETRIES {
+        let id = rand::random::<u16>();
+        let msg = build_query(id, name);
+        if socket.send(&msg[..]).await.is_err() {
+            return None;
+        }
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match tokio::time::timeout(left, socket.recv(buf)).await {
+                Ok(Ok(n)) => {
+                    // stale answers to earlier attempts are dropped here
+                    if let Some(data) = parse_response(&buf[..n], id) {
+                        return Some(data);
+                    }
+                }
+                Ok(Err(_)) => return None,
+                Err(_) => break,
+            }
+        }
+    }
+    None
+}
+
+async fn client_loop(
+    mut socket: UdpSocket,
+    pipe: SharedPipe,
+    session: u32,
+    domain: String,
+    key: hmac::Key,
+) {
+    let max_up = max_upstream(domain.as_str());
+    let mut buf = vec![0u8; 4096];
+    let mut seq: u32 = 0;
+    let mut idle: u32 = 0;
+    loop {
+        let (data, fin) = {
+            let mut p = pipe.lock().unwrap();
+            if p.closed {
+                break;
+            }
+            let data = if seq == 0 {
+                p.take_outbound(max_up - OPEN_TAG_LEN)
+            } else {
+                p.take_outbound(max_up)
+            };
+            (data, p.released && p.outbound.is_empty())
+        };
+        let mut payload = BytesMut::with_capacity(UP_HEADER_LEN + OPEN_TAG_LEN + data.len());
+        payload.put_u32(session);
+        payload.put_u32(seq);
+        payload.put_u8(if fin { FLAG_FIN } else { 0 });
+        if seq == 0 {
+            payload.put_slice(&open_tag(&key, session).as_ref()[..OPEN_TAG_LEN]);
+        }
+        payload.put_slice(&data[..]);
+        let name = payload_name(&payload[..], domain.as_str());
+        let reply = exchange(&mut socket, name.as_str(), &mut buf).await;
+        let flags = {
+            let mut p = pipe.lock().unwrap();
+            let reply = match reply {
+                Some(r) if !r.is_empty() => r,
+                _ => {
+                    error!("dns tunnel session {} lost", session);
+                    p.close();
+                    break;
+                }
+            };
+            p.push_inbound(&reply[1..]);
+            if fin || reply[0] & FLAG_CLOSED != 0 {
+                p.close();
+                break;
+            }
+            if reply.len() > 1 {
+                reply[0] | FLAG_MORE
+            } else {
+                reply[0]
+            }
+        };
+        seq = seq.wrapping_add(1);
+        if !data.is_empty() || flags & FLAG_MORE != 0 {
+            idle = 0;
+            continue;
+        }
+        // back off while idle, but wake up soon once there is data to send
+        idle = std::cmp::min(idle + 1, 5);
+        let wait = std::cmp::min(POLL_STEP * (1 << idle), MAX_POLL_INTERVAL);
+        let start = Instant::now();
+        while start.elapsed() < wait {
+            delay_for(POLL_STEP).await;
+            let p = pipe.lock().unwrap();
+            if !p.outbound.is_empty() || p.released {
+                break;
+            }
+        }
+    }
+}
+
+/// Opens a tunnel session through the resolver at `addr` (or the remote
+/// itself) to the authoritative server of `domain`, which has to share `key`.
+pub async fn dns_connect(addr: &str, domain: &str, key: &str) -> Result<DnsStream, std::io::Error> {
+    let remote = match tokio::net::lookup_host(addr).await?.next() {
+        Some(a) => a,
+        None => return Err(make_io_error("no address resolved")),
+    };
+    let local = if remote.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(remote).await?;
+    let pipe = new_pipe();
+    let domain = domain.trim_matches('.').to_ascii_lowercase();
+    tokio::spawn(client_loop(
+        socket,
+        pipe.clone(),
+        rand::random::<u32>(),
+        domain,
+        hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes()),
+    ));
+    Ok(DnsStream { pipe })
+}
+
+struct ServerSession {
+    pipe: SharedPipe,
+    next_seq: u32,
+    last_reply: Vec<u8>,
+}
+
+fn serve_payload(
+    sessions: &mut HashMap<u32, ServerSession>,
+    key: &hmac::Key,
+    payload: &[u8],
+    budget: usize,
+) -> (Vec<u8>, Option<DnsStream>) {
+    if payload.len() < UP_HEADER_LEN || budget == 0 {
+        return (vec![FLAG_CLOSED], None);
+    }
+    let mut xbuf: [u8; 4] = Default::default();
+    xbuf.copy_from_slice(&payload[0..4]);
+    let id = u32::from_be_bytes(xbuf);
+    xbuf.copy_from_slice(&payload[4..8]);
+    let seq = u32::from_be_bytes(xbuf);
+    let flags = payload[8];
+    let mut data_start = UP_HEADER_LEN;
+    let mut accepted = None;
+    if !sessions.contains_key(&id) {
+        if seq != 0 || payload.len() < UP_HEADER_LEN + OPEN_TAG_LEN {
+            return (vec![FLAG_CLOSED], None);
+        }
+        data_start += OPEN_TAG_LEN;
+        let tag = open_tag(key, id);
+        if verify_slices_are_equal(
+            &payload[UP_HEADER_LEN..data_start],
+            &tag.as_ref()[..OPEN_TAG_LEN],
+        )
+        .is_err()
+        {
+            return (vec![FLAG_CLOSED], None);
+        }
+        let open = sessions
+            .values()
+            .filter(|s| !s.pipe.lock().unwrap().closed)
+            .count();
+        if open >= MAX_SESSIONS {
+            return (vec![FLAG_CLOSED], None);
+        }
+        let pipe = new_pipe();
+        accepted = Some(DnsStream { pipe: pipe.clone() });
+        sessions.insert(
+            id,
+            ServerSession {
+                pipe,
+                next_seq: 0,
+                last_reply: Vec::new(),
+            },
+        );
+    }
+    let s = sessions.get_mut(&id).unwrap();
+    if seq != s.next_seq {
+        // a retransmit, or an older query a resolver is still retrying
+        return (s.last_reply.clone(), accepted);
+    }
+    let mut p = s.pipe.lock().unwrap();
+    p.last_active = Instant::now();
+    p.push_inbound(&payload[data_start..]);
+    let data = p.take_outbound(budget - 1);
+    let mut reply_flags = 0;
+    if !p.outbound.is_empty() {
+        reply_flags |= FLAG_MORE;
+    }
+    if flags & FLAG_FIN != 0 || p.closed || (p.released && p.outbound.is_empty()) {
+        reply_flags |= FLAG_CLOSED;
+        p.close();
+    }
+    drop(p);
+    let mut reply = Vec::with_capacity(1 + data.len());
+    reply.push(reply_flags);
+    reply.extend_from_slice(&data[..]);
+    s.next_seq = seq.wrapping_add(1);
+    s.last_reply = reply.clone();
+    (reply, accepted)
+}
+
+pub struct DnsListener {
+    incoming: mpsc::UnboundedReceiver<(DnsStream, SocketAddr)>,
+}
+
+impl DnsListener {
+    pub async fn accept(&mut self) -> Option<(DnsStream, SocketAddr)> {
+        self.incoming.recv().await
+    }
+}
+
+/// Answers queries for `domain` on a UDP socket, every tunnel session
+/// opened with `key` becomes one accepted stream, up to `MAX_SESSIONS` at
+/// once. Names outside the zone are refused.
+pub async fn dns_listen(
+    addr: &str,
+    domain: &str,
+    key: &str,
+) -> Result<DnsListener, std::io::Error> {
+    let mut socket = UdpSocket::bind(addr).await?;
+    let domain = domain.trim_matches('.').to_ascii_lowercase();
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes());
+    let (accept_tx, accept_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut sessions: HashMap<u32, ServerSession> = HashMap::new();
+        let mut buf = vec![0u8; 4096];
+        let mut last_sweep = Instant::now();
+        loop {
+            let (n, peer) = match socket.recv_from(&mut buf).await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("dns tunnel listener recv error:{}", e);
+                    break;
+                }
+            };
+            let q = match parse_query(&buf[..n]) {
+                Some(q) => q,
+                None => continue,
+            };
+            let reply = if !in_zone(q.name.as_str(), domain.as_str()) {
+                build_response(&q, RCODE_REFUSED, None)
+            } else {
+                match name_payload(q.name.as_str(), domain.as_str()) {
+                    Some(payload) if q.qtype == TYPE_TXT => {
+                        let (txt, accepted) =
+                            serve_payload(&mut sessions, &key, &payload, q.txt_budget());
+                        if let Some(stream) = accepted {
+                            if accept_tx.send((stream, peer)).is_err() {
+                                break;
+                            }
+                        }
+                        build_response(&q, 0, Some(&txt[..]))
+                    }
+                    _ => build_response(&q, 0, None),
+                }
+            };
+            if let Err(e) = socket.send_to(&reply[..], &peer).await {
+                error!("dns tunnel send to {} error:{}", peer, e);
+            }
+            if last_sweep.elapsed() > Duration::from_secs(10) {
+                last_sweep = Instant::now();
+                sessions.retain(|_, s| {
+                    let mut p = s.pipe.lock().unwrap();
+                    if p.last_active.elapsed() < SESSION_IDLE {
+                        return true;
+                    }
+                    p.close();
+                    false
+                });
+            }
+        }
+    });
+    Ok(DnsListener {
+        incoming: accept_rx,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base32() {
+        let cases: Vec<&[u8]> = vec![b"", b"f", b"fo", b"foobar", &[0xff, 0, 0x80, 1, 2, 3, 4]];
+        for data in cases {
+            let s = base32_encode(data);
+            assert_eq!(base32_decode(s.as_str()).unwrap(), data.to_vec());
+            assert_eq!(
+                base32_decode(s.to_uppercase().as_str()).unwrap(),
+                data.to_vec()
+            );
+        }
+        assert_eq!(base32_encode(b"foobar"), "mzxw6ytboi");
+    }
+
+    #[test]
+    fn test_query_roundtrip() {
+        let domain = "t.example.com";
+        let payload = vec![7u8; max_upstream(domain) + UP_HEADER_LEN];
+        let name = payload_name(&payload, domain);
+        assert!(name.len() <= MAX_NAME_LEN);
+        let msg = build_query(0x1234, name.as_str());
+        let q = parse_query(&msg[..]).unwrap();
+        assert_eq!(q.qtype, TYPE_TXT);
+        assert_eq!(q.edns, Some(EDNS_UDP_SIZE));
+        assert_eq!(dns_question(&msg[..]).unwrap().0, name);
+        assert_eq!(name_payload(q.name.as_str(), domain).unwrap(), payload);
+        let txt = vec![9u8; q.txt_budget()];
+        let res = build_response(&q, 0, Some(&txt[..]));
+        assert!(res.len() <= EDNS_UDP_SIZE as usize);
+        assert_eq!(parse_response(&res[..], 0x1234).unwrap(), txt);
+        assert!(parse_response(&res[..], 0x4321).is_none());
+    }
+
+    #[test]
+    fn test_open_session() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"abcdefg");
+        let other = hmac::Key::new(hmac::HMAC_SHA256, b"gfedcba");
+        let mut sessions = HashMap::new();
+        let open = |id: u32, key: &hmac::Key| {
+            let mut payload = BytesMut::new();
+            payload.put_u32(id);
+            payload.put_u32(0);
+            payload.put_u8(0);
+            payload.put_slice(&open_tag(key, id).as_ref()[..OPEN_TAG_LEN]);
+            payload.put_slice(b"hi");
+            payload
+        };
+        let (reply, accepted) = serve_payload(&mut sessions, &key, &open(1, &other)[..], 512);
+        assert_eq!((reply, accepted.is_none()), (vec![FLAG_CLOSED], true));
+        let (reply, accepted) = serve_payload(&mut sessions, &key, &open(1, &key)[..9], 512);
+        assert_eq!((reply, accepted.is_none()), (vec![FLAG_CLOSED], true));
+
+        let (reply, accepted) = serve_payload(&mut sessions, &key, &open(1, &key)[..], 512);
+        assert_eq!(reply, vec![0]);
+        let stream = accepted.unwrap();
+        assert_eq!(&stream.pipe.lock().unwrap().inbound[..], b"hi");
+        // a retransmit opens nothing new
+        let (_, accepted) = serve_payload(&mut sessions, &key, &open(1, &key)[..], 512);
+        assert!(accepted.is_none());
+
+        for id in 2..=MAX_SESSIONS as u32 {
+            let (_, accepted) = serve_payload(&mut sessions, &key, &open(id, &key)[..], 512);
+            assert!(accepted.is_some());
+        }
+        let id = MAX_SESSIONS as u32 + 1;
+        let (_, accepted) = serve_payload(&mut sessions, &key, &open(id, &key)[..], 512);
+        assert!(accepted.is_none());
+        stream.pipe.lock().unwrap().close();
+        let (_, accepted) = serve_payload(&mut sessions, &key, &open(id, &key)[..], 512);
+        assert!(accepted.is_some());
+    }
+}
@@ -0,0 +1,237 @@
+use super::http2::{H2Reader, H2Writer};
+use crate::utils::{
+    decode_varint, encode_varint, make_invalid_data_error, FrameCodec, LengthPrefix,
+};
+use bytes::{Buf, Bytes, BytesMut};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+pub const DEFAULT_GRPC_SERVICE: &str = "rsnova.Tunnel";
+pub const GRPC_CONTENT_TYPE: &str = "application/grpc";
+
+// bytes of a hunk we send
+const MAX_GRPC_HUNK: usize = 16 * 1024;
+// a longer message from the peer is refused rather than buffered, what
+// other gun implementations send fits well below
+const MAX_GRPC_MESSAGE: usize = 64 * 1024;
+// field 1, length delimited: `message Hunk { bytes data = 1; }`
+const HUNK_DATA_TAG: u64 = (1 << 3) | 2;
+
+/// Path of the single bidi streaming method, `/<service>/Tun`, the same
+/// layout other tools' gRPC ("gun") modes use.
+pub fn grpc_path(service: &str) -> String {
+    let service = service.trim_matches('/');
+    let service = if service.is_empty() {
+        DEFAULT_GRPC_SERVICE
+    } else {
+        service
+    };
+    format!("/{}/Tun", service)
+}
+
+fn parse_hunk(mut msg: &[u8]) -> io::Result<Bytes> {
+    let mut data = BytesMut::new();
+    while !msg.is_empty() {
+        let (tag, n) = match decode_varint(msg)? {
+            Some(v) => v,
+            None => return Err(make_invalid_data_error("truncated grpc message")),
+        };
+        msg = &msg[n..];
+        let skip = match tag & 0x7 {
+            0 => match decode_varint(msg)? {
+                Some((_, n)) => n,
+                None => return Err(make_invalid_data_error("truncated grpc message")),
+            },
+            1 => 8,
+            5 => 4,
+            2 => {
+                let (len, n) = match decode_varint(msg)? {
+                    Some(v) => v,
+                    None => return Err(make_invalid_data_error("truncated grpc message")),
+                };
+                let len = len as usize;
+                if msg.len() < n + len {
+                    return Err(make_invalid_data_error("truncated grpc message"));
+                }
+                if tag == HUNK_DATA_TAG {
+                    data.extend_from_slice(&msg[n..n + len]);
+                }
+                n + len
+            }
+            _ => return Err(make_invalid_data_error("unsupported protobuf wire type")),
+        };
+        if msg.len() < skip {
+            return Err(make_invalid_data_error("truncated grpc message"));
+        }
+        msg = &msg[skip..];
+    }
+    Ok(data.freeze())
+}
+
+// a compressed flag, which we never set nor take, and a u32 length
+fn message_codec() -> FrameCodec {
+    FrameCodec {
+        flags: true,
+        ..FrameCodec::new(LengthPrefix::U32, MAX_GRPC_MESSAGE)
+    }
+}
+
+/// Unwraps length prefixed `Hunk` messages of a gRPC stream into raw bytes.
+pub struct GrpcReader {
+    inner: H2Reader,
+    raw: BytesMut,
+    data: Bytes,
+}
+
+impl GrpcReader {
+    pub fn new(inner: H2Reader) -> Self {
+        Self {
+            inner,
+            raw: BytesMut::new(),
+            data: Bytes::new(),
+        }
+    }
+
+    fn next_message(&mut self) -> io::Result<Option<Bytes>> {
+        match message_codec().decode(&mut self.raw)? {
+            Some(msg) => parse_hunk(&msg[..]).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl AsyncRead for GrpcReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            if !self.data.is_empty() {
+                let n = std::cmp::min(buf.len(), self.data.len());
+                let data = self.data.split_to(n);
+                buf[..n].copy_from_slice(&data[..]);
+                return Poll::Ready(Ok(n));
+            }
+            if let Some(data) = self.next_message()? {
+                self.data = data;
+                continue;
+            }
+            let Self { inner, raw, .. } = &mut *self;
+            raw.reserve(4096);
+            let n = ready!(Pin::new(inner).poll_read_buf(cx, raw))?;
+            if n == 0 {
+                if self.raw.is_empty() {
+                    return Poll::Ready(Ok(0));
+                }
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::UnexpectedEof)));
+            }
+        }
+    }
+}
+
+/// Wraps every write into one `Hunk` message. A write only completes once
+/// its whole message has been handed to h2, so nothing is left buffered.
+pub struct GrpcWriter {
+    inner: H2Writer,
+    pending: BytesMut,
+    pending_len: usize,
+    trailers: bool,
+}
+
+impl GrpcWriter {
+    /// `trailers` should be set on the server side, which has to finish the
+    /// call with a `grpc-status` trailer.
+    pub fn new(inner: H2Writer, trailers: bool) -> Self {
+        Self {
+            inner,
+            pending: BytesMut::new(),
+            pending_len: 0,
+            trailers,
+        }
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[..]))?;
+            self.pending.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for GrpcWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.pending.is_empty() {
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            let n = std::cmp::min(buf.len(), MAX_GRPC_HUNK);
+            let mut hunk = BytesMut::with_capacity(n + 8);
+            encode_varint(HUNK_DATA_TAG, &mut hunk);
+            FrameCodec::new(LengthPrefix::Varint, MAX_GRPC_HUNK).encode(&buf[..n], &mut hunk)?;
+            message_codec().encode(&hunk[..], &mut self.pending)?;
+            self.pending_len = n;
+        }
+        ready!(self.poll_drain(cx))?;
+        Poll::Ready(Ok(self.pending_len))
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_drain(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        if self.trailers {
+            let mut trailers = http::HeaderMap::new();
+            trailers.insert("grpc-status", http::HeaderValue::from_static("0"));
+            return Poll::Ready(self.inner.send_trailers(trailers));
+        }
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BufMut;
+
+    #[test]
+    fn test_parse_hunk() {
+        let mut msg = BytesMut::new();
+        // unknown varint field 2 before the data
+        encode_varint(2 << 3, &mut msg);
+        encode_varint(300, &mut msg);
+        encode_varint(HUNK_DATA_TAG, &mut msg);
+        encode_varint(5, &mut msg);
+        msg.put_slice(b"hello");
+        assert_eq!(&parse_hunk(&msg[..]).unwrap()[..], b"hello");
+        assert!(parse_hunk(&msg[..msg.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_message_codec() {
+        let codec = message_codec();
+        let mut raw = BytesMut::from(&[0u8, 0, 0][..]);
+        assert!(codec.decode(&mut raw).unwrap().is_none());
+        let mut raw = BytesMut::from(&[0u8, 0, 0, 0, 2, 1, 2][..]);
+        assert_eq!(&codec.decode(&mut raw).unwrap().unwrap()[..], &[1, 2]);
+        let mut raw = BytesMut::from(&[1u8, 0, 0, 0, 4][..]);
+        assert!(codec.decode(&mut raw).is_err());
+        let mut raw = BytesMut::from(&[0u8, 0, 1, 0, 1][..]);
+        assert!(codec.decode(&mut raw).is_err());
+        let mut raw = BytesMut::from(&[0u8, 0xff, 0xff, 0xff, 0xff][..]);
+        assert!(codec.decode(&mut raw).is_err());
+    }
+
+    #[test]
+    fn test_grpc_path() {
+        assert_eq!(grpc_path(""), "/rsnova.Tunnel/Tun");
+        assert_eq!(grpc_path("/GunService/"), "/GunService/Tun");
+    }
+}
@@ -0,0 +1,577 @@
+use super::quic::{quic_connection, quic_listen_alpn};
+use crate::utils::make_invalid_data_error;
+use bytes::{Buf, BufMut, BytesMut};
+use futures::StreamExt;
+use quinn::{Endpoint, Incoming, IncomingUniStreams, NewConnection, SendStream, VarInt};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const H3_ALPN: &[&[u8]] = &[b"h3"];
+/// `:protocol` of the extended CONNECT (RFC 9220) requests.
+pub const H3_PROTOCOL: &str = "rsnova";
+
+const FRAME_DATA: u64 = 0x0;
+const FRAME_HEADERS: u64 = 0x1;
+const FRAME_SETTINGS: u64 = 0x4;
+const STREAM_CONTROL: u64 = 0x0;
+const SETTINGS_ENABLE_CONNECT_PROTOCOL: u64 = 0x8;
+const H3_REQUEST_CANCELLED: u32 = 0x10c;
+const MAX_DATA_FRAME: usize = 16 * 1024;
+const MAX_HEADERS_FRAME: u64 = 64 * 1024;
+
+// QPACK static table (RFC 9204, appendix A)
+const QPACK_STATIC: [(&str, &str); 99] = [
+    (":authority", ""),
+    (":path", "/"),
+    ("age", "0"),
+    ("content-disposition", ""),
+    ("content-length", "0"),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("referer", ""),
+    ("set-cookie", ""),
+    (":method", "CONNECT"),
+    (":method", "DELETE"),
+    (":method", "GET"),
+    (":method", "HEAD"),
+    (":method", "OPTIONS"),
+    (":method", "POST"),
+    (":method", "PUT"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "103"),
+    (":status", "200"),
+    (":status", "304"),
+    (":status", "404"),
+    (":status", "503"),
+    ("accept", "*/*"),
+    ("accept", "application/dns-message"),
+    ("accept-encoding", "gzip, deflate, br"),
+    ("accept-ranges", "bytes"),
+    ("access-control-allow-headers", "cache-control"),
+    ("access-control-allow-headers", "content-type"),
+    ("access-control-allow-origin", "*"),
+    ("cache-control", "max-age=0"),
+    ("cache-control", "max-age=2592000"),
+    ("cache-control", "max-age=604800"),
+    ("cache-control", "no-cache"),
+    ("cache-control", "no-store"),
+    ("cache-control", "public, max-age=31536000"),
+    ("content-encoding", "br"),
+    ("content-encoding", "gzip"),
+    ("content-type", "application/dns-message"),
+    ("content-type", "application/javascript"),
+    ("content-type", "application/json"),
+    ("content-type", "application/x-www-form-urlencoded"),
+    ("content-type", "image/gif"),
+    ("content-type", "image/jpeg"),
+    ("content-type", "image/png"),
+    ("content-type", "text/css"),
+    ("content-type", "text/html; charset=utf-8"),
+    ("content-type", "text/plain"),
+    ("content-type", "text/plain;charset=utf-8"),
+    ("range", "bytes=0-"),
+    ("strict-transport-security", "max-age=31536000"),
+    (
+        "strict-transport-security",
+        "max-age=31536000; includesubdomains",
+    ),
+    (
+        "strict-transport-security",
+        "max-age=31536000; includesubdomains; preload",
+    ),
+    ("vary", "accept-encoding"),
+    ("vary", "origin"),
+    ("x-content-type-options", "nosniff"),
+    ("x-xss-protection", "1; mode=block"),
+    (":status", "100"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "302"),
+    (":status", "400"),
+    (":status", "403"),
+    (":status", "421"),
+    (":status", "425"),
+    (":status", "500"),
+    ("accept-language", ""),
+    ("access-control-allow-credentials", "FALSE"),
+    ("access-control-allow-credentials", "TRUE"),
+    ("access-control-allow-headers", "*"),
+    ("access-control-allow-methods", "get"),
+    ("access-control-allow-methods", "get, post, options"),
+    ("access-control-allow-methods", "options"),
+    ("access-control-expose-headers", "content-length"),
+    ("access-control-request-headers", "content-type"),
+    ("access-control-request-method", "get"),
+    ("access-control-request-method", "post"),
+    ("alt-svc", "clear"),
+    ("authorization", ""),
+    (
+        "content-security-policy",
+        "script-src 'none'; object-src 'none'; base-uri 'none'",
+    ),
+    ("early-data", "1"),
+    ("expect-ct", ""),
+    ("forwarded", ""),
+    ("if-range", ""),
+    ("origin", ""),
+    ("purpose", "prefetch"),
+    ("server", ""),
+    ("timing-allow-origin", "*"),
+    ("upgrade-insecure-requests", "1"),
+    ("user-agent", ""),
+    ("x-forwarded-for", ""),
+    ("x-frame-options", "deny"),
+    ("x-frame-options", "sameorigin"),
+];
+
+pub fn encode_quic_varint(v: u64, out: &mut BytesMut) {
+    if v < 1 << 6 {
+        out.put_u8(v as u8);
+    } else if v < 1 << 14 {
+        out.put_u16(0x4000 | v as u16);
+    } else if v < 1 << 30 {
+        out.put_u32(0x8000_0000 | v as u32);
+    } else {
+        out.put_u64(0xc000_0000_0000_0000 | v);
+    }
+}
+
+/// Decodes a QUIC variable length integer (RFC 9000 16), returning the value
+/// and its encoded length, or None if `buf` is too short.
+pub fn decode_quic_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let first = *buf.first()?;
+    let len = 1 << (first >> 6);
+    if buf.len() < len {
+        return None;
+    }
+    let mut v = u64::from(first & 0x3f);
+    for b in &buf[1..len] {
+        v = (v << 8) | u64::from(*b);
+    }
+    Some((v, len))
+}
+
+fn encode_frame(ty: u64, payload: &[u8], out: &mut BytesMut) {
+    out.reserve(payload.len() + 16);
+    encode_quic_varint(ty, out);
+    encode_quic_varint(payload.len() as u64, out);
+    out.put_slice(payload);
+}
+
+// QPACK prefixed integer (RFC 7541 5.1) with `flags` in the bits above it
+fn encode_prefixed_int(flags: u8, prefix: u8, v: u64, out: &mut BytesMut) {
+    let max = (1u64 << prefix) - 1;
+    if v < max {
+        out.put_u8(flags | v as u8);
+        return;
+    }
+    out.put_u8(flags | max as u8);
+    let mut v = v - max;
+    while v >= 0x80 {
+        out.put_u8((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.put_u8(v as u8);
+}
+
+fn decode_prefixed_int(buf: &[u8], prefix: u8) -> io::Result<(u64, usize)> {
+    let max = (1u64 << prefix) - 1;
+    let first = match buf.first() {
+        Some(b) => u64::from(*b) & max,
+        None => return Err(make_invalid_data_error("truncated qpack integer")),
+    };
+    if first < max {
+        return Ok((first, 1));
+    }
+    let mut v = max;
+    for (i, b) in buf[1..].iter().enumerate() {
+        if i >= 8 {
+            break;
+        }
+        v += u64::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            return Ok((v, i + 2));
+        }
+    }
+    Err(make_invalid_data_error("truncated qpack integer"))
+}
+
+fn encode_string(flags: u8, prefix: u8, s: &str, out: &mut BytesMut) {
+    encode_prefixed_int(flags, prefix, s.len() as u64, out);
+    out.put_slice(s.as_bytes());
+}
+
+fn decode_string(buf: &[u8], prefix: u8) -> io::Result<(String, usize)> {
+    let huffman = buf.first().map_or(false, |b| b & (1 << prefix) != 0);
+    if huffman {
+        return Err(make_invalid_data_error(
+            "huffman coded qpack string not supported",
+        ));
+    }
+    let (len, n) = decode_prefixed_int(buf, prefix)?;
+    let end = n + len as usize;
+    if buf.len() < end {
+        return Err(make_invalid_data_error("truncated qpack string"));
+    }
+    match std::str::from_utf8(&buf[n..end]) {
+        Ok(s) => Ok((String::from(s), end)),
+        Err(_) => Err(make_invalid_data_error("invalid qpack string")),
+    }
+}
+
+fn static_entry(index: u64) -> io::Result<(&'static str, &'static str)> {
+    match QPACK_STATIC.get(index as usize) {
+        Some(e) => Ok(*e),
+        None => Err(make_invalid_data_error("invalid qpack static index")),
+    }
+}
+
+/// Encodes a header block against the static table only, so no encoder or
+/// decoder streams are ever needed.
+pub fn encode_headers(fields: &[(&str, &str)], out: &mut BytesMut) {
+    // required insert count and delta base are always zero
+    out.put_u8(0);
+    out.put_u8(0);
+    for (name, value) in fields {
+        if let Some(i) = QPACK_STATIC
+            .iter()
+            .position(|(n, v)| n == name && v == value)
+        {
+            // indexed field line, static table
+            encode_prefixed_int(0xc0, 6, i as u64, out);
+        } else if let Some(i) = QPACK_STATIC.iter().position(|(n, _)| n == name) {
+            // literal with static name reference
+            encode_prefixed_int(0x50, 4, i as u64, out);
+            encode_string(0, 7, value, out);
+        } else {
+            // literal with literal name
+            encode_string(0x20, 3, name, out);
+            encode_string(0, 7, value, out);
+        }
+    }
+}
+
+pub fn decode_headers(mut buf: &[u8]) -> io::Result<Vec<(String, String)>> {
+    let (insert_count, n) = decode_prefixed_int(buf, 8)?;
+    if insert_count != 0 {
+        return Err(make_invalid_data_error("qpack dynamic table not supported"));
+    }
+    buf = &buf[n..];
+    let (_, n) = decode_prefixed_int(buf, 7)?;
+    buf = &buf[n..];
+    let mut fields = Vec::new();
+    while !buf.is_empty() {
+        let b = buf[0];
+        if b & 0x80 != 0 {
+            if b & 0x40 == 0 {
+                return Err(make_invalid_data_error("qpack dynamic table not supported"));
+            }
+            let (i, n) = decode_prefixed_int(buf, 6)?;
+            let (name, value) = static_entry(i)?;
+            fields.push((String::from(name), String::from(value)));
+            buf = &buf[n..];
+        } else if b & 0x40 != 0 {
+            if b & 0x10 == 0 {
+                return Err(make_invalid_data_error("qpack dynamic table not supported"));
+            }
+            let (i, n) = decode_prefixed_int(buf, 4)?;
+            let (name, _) = static_entry(i)?;
+            let (value, m) = decode_string(&buf[n..], 7)?;
+            fields.push((String::from(name), value));
+            buf = &buf[n + m..];
+        } else if b & 0x20 != 0 {
+            let (name, n) = decode_string(buf, 3)?;
+            let (value, m) = decode_string(&buf[n..], 7)?;
+            fields.push((name, value));
+            buf = &buf[n + m..];
+        } else {
+            return Err(make_invalid_data_error("qpack dynamic table not supported"));
+        }
+    }
+    Ok(fields)
+}
+
+pub fn header_value<'a>(fields: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    fields
+        .iter()
+        .find(|(n, _)| n.as_str() == name)
+        .map(|(_, v)| v.as_str())
+}
+
+async fn read_quic_varint<R: AsyncRead + Unpin>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf[..1]).await?;
+    let len = 1 << (buf[0] >> 6);
+    r.read_exact(&mut buf[1..len]).await?;
+    match decode_quic_varint(&buf[..len]) {
+        Some((v, _)) => Ok(v),
+        None => Err(make_invalid_data_error("invalid quic varint")),
+    }
+}
+
+/// Reads frames up to the first HEADERS frame of a request stream and
+/// decodes its fields, leaving the stream at the start of the body.
+pub async fn read_headers<R: AsyncRead + Unpin>(r: &mut R) -> io::Result<Vec<(String, String)>> {
+    loop {
+        let ty = read_quic_varint(r).await?;
+        let len = read_quic_varint(r).await?;
+        if len > MAX_HEADERS_FRAME {
+            return Err(make_invalid_data_error("h3 frame too large"));
+        }
+        let mut payload = vec![0u8; len as usize];
+        r.read_exact(&mut payload[..]).await?;
+        match ty {
+            FRAME_HEADERS => return decode_headers(&payload[..]),
+            FRAME_DATA => return Err(make_invalid_data_error("h3 DATA before HEADERS")),
+            // reserved and unknown frame types must be ignored
+            _ => {}
+        }
+    }
+}
+
+pub async fn write_headers<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    fields: &[(&str, &str)],
+) -> io::Result<()> {
+    let mut block = BytesMut::new();
+    encode_headers(fields, &mut block);
+    let mut frame = BytesMut::new();
+    encode_frame(FRAME_HEADERS, &block[..], &mut frame);
+    w.write_all(&frame[..]).await
+}
+
+/// Opens our control stream with SETTINGS enabling extended CONNECT. The
+/// returned stream has to stay open for the life of the connection.
+pub async fn open_control_stream(conn: &quinn::Connection) -> io::Result<SendStream> {
+    let mut send = match conn.open_uni().await {
+        Ok(s) => s,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+    };
+    let mut settings = BytesMut::new();
+    encode_quic_varint(SETTINGS_ENABLE_CONNECT_PROTOCOL, &mut settings);
+    encode_quic_varint(1, &mut settings);
+    let mut buf = BytesMut::new();
+    encode_quic_varint(STREAM_CONTROL, &mut buf);
+    encode_frame(FRAME_SETTINGS, &settings[..], &mut buf);
+    send.write_all(&buf[..]).await?;
+    Ok(send)
+}
+
+/// Drains the peer's unidirectional streams (control, QPACK encoder and
+/// decoder); none of them carry anything we act upon. Returns once the
+/// connection is gone.
+pub async fn drain_uni_streams(mut uni_streams: IncomingUniStreams) {
+    while let Some(stream) = uni_streams.next().await {
+        let mut recv = match stream {
+            Ok(s) => s,
+            Err(_) => break,
+        };
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            while let Ok(n) = recv.read(&mut buf).await {
+                if n.is_none() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+pub async fn h3_connect(
+    addr: &str,
+    server_name: &str,
+    zero_rtt: bool,
+) -> io::Result<(Endpoint, NewConnection)> {
+    quic_connection(addr, server_name, zero_rtt, H3_ALPN).await
+}
+
+pub async fn h3_listen(
+    addr: &str,
+    cert_path: &str,
+    key_path: &str,
+) -> io::Result<(Endpoint, Incoming)> {
+    quic_listen_alpn(addr, cert_path, key_path, H3_ALPN).await
+}
+
+/// Unwraps the DATA frames of a request stream; other frames are skipped.
+pub struct H3Reader<R> {
+    inner: R,
+    buf: BytesMut,
+    data_left: u64,
+    skip_left: u64,
+    eof: bool,
+}
+
+impl<R> H3Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: BytesMut::new(),
+            data_left: 0,
+            skip_left: 0,
+            eof: false,
+        }
+    }
+
+    fn next_frame(&mut self) -> bool {
+        let (ty, n) = match decode_quic_varint(&self.buf[..]) {
+            Some(v) => v,
+            None => return false,
+        };
+        let (len, m) = match decode_quic_varint(&self.buf[n..]) {
+            Some(v) => v,
+            None => return false,
+        };
+        self.buf.advance(n + m);
+        if ty == FRAME_DATA {
+            self.data_left = len;
+        } else {
+            self.skip_left = len;
+        }
+        true
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for H3Reader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            if !self.buf.is_empty() {
+                if self.skip_left > 0 {
+                    let n = std::cmp::min(self.skip_left, self.buf.len() as u64);
+                    self.buf.advance(n as usize);
+                    self.skip_left -= n;
+                    continue;
+                }
+                if self.data_left > 0 {
+                    let n = std::cmp::min(self.data_left, self.buf.len() as u64) as usize;
+                    let n = std::cmp::min(n, out.len());
+                    out[..n].copy_from_slice(&self.buf[..n]);
+                    self.buf.advance(n);
+                    self.data_left -= n as u64;
+                    return Poll::Ready(Ok(n));
+                }
+                if self.next_frame() {
+                    continue;
+                }
+            }
+            if self.eof {
+                if self.buf.is_empty() && self.data_left == 0 && self.skip_left == 0 {
+                    return Poll::Ready(Ok(0));
+                }
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::UnexpectedEof)));
+            }
+            let Self { inner, buf, .. } = &mut *self;
+            buf.reserve(4096);
+            let n = ready!(Pin::new(inner).poll_read_buf(cx, buf))?;
+            if n == 0 {
+                self.eof = true;
+            }
+        }
+    }
+}
+
+/// Wraps every write into one DATA frame. A write only completes once its
+/// whole frame has been handed to QUIC, so nothing is left buffered.
+pub struct H3Writer {
+    inner: SendStream,
+    pending: BytesMut,
+    pending_len: usize,
+}
+
+impl H3Writer {
+    pub fn new(inner: SendStream) -> Self {
+        Self {
+            inner,
+            pending: BytesMut::new(),
+            pending_len: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        let _ = self.inner.reset(VarInt::from_u32(H3_REQUEST_CANCELLED));
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[..]))?;
+            self.pending.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for H3Writer {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.pending.is_empty() {
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            let n = std::cmp::min(buf.len(), MAX_DATA_FRAME);
+            let mut frame = BytesMut::new();
+            encode_frame(FRAME_DATA, &buf[..n], &mut frame);
+            self.pending = frame;
+            self.pending_len = n;
+        }
+        ready!(self.poll_drain(cx))?;
+        Poll::Ready(Ok(self.pending_len))
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_drain(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quic_varint() {
+        for v in &[0u64, 63, 64, 16383, 16384, (1 << 30) - 1, 1 << 30] {
+            let mut buf = BytesMut::new();
+            encode_quic_varint(*v, &mut buf);
+            assert_eq!(decode_quic_varint(&buf[..]), Some((*v, buf.len())));
+        }
+        assert_eq!(decode_quic_varint(&[0x40]), None);
+    }
+
+    #[test]
+    fn test_headers_roundtrip() {
+        let fields = [
+            (":method", "CONNECT"),
+            (":protocol", H3_PROTOCOL),
+            (":scheme", "https"),
+            (":authority", "example.com:443"),
+            (":path", "/tunnel"),
+            ("x-rsnova-target", "1.2.3.4:80"),
+        ];
+        let mut buf = BytesMut::new();
+        encode_headers(&fields, &mut buf);
+        let decoded = decode_headers(&buf[..]).unwrap();
+        assert_eq!(decoded.len(), fields.len());
+        for ((n, v), (dn, dv)) in fields.iter().zip(decoded.iter()) {
+            assert_eq!(n, dn);
+            assert_eq!(v, dv);
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use crate::rmux::check_auth_stamp;
+use bytes::Bytes;
+use h2::{RecvStream, SendStream};
+use rand::Rng;
+use ring::constant_time::verify_slices_are_equal;
+use ring::hmac;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+pub const H2_TARGET_HEADER: &str = "x-rsnova-target";
+pub const H2_AUTH_HEADER: &str = "x-rsnova-auth";
+
+/// random bytes of a token, what the replay filter remembers
+const H2_NONCE_LEN: usize = 16;
+
+fn to_hex(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len() * 2);
+    for b in data {
+        s.push_str(format!("{:02x}", b).as_str());
+    }
+    s
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn token_mac(key: &str, ts: &str, nonce: &str, target: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes());
+    let mut ctx = hmac::Context::with_key(&key);
+    ctx.update(ts.as_bytes());
+    ctx.update(b".");
+    ctx.update(nonce.as_bytes());
+    ctx.update(b".");
+    ctx.update(target.as_bytes());
+    to_hex(ctx.sign().as_ref())
+}
+
+/// Per request proof of the shared cipher key for `target`: the unix time
+/// and random bytes, with an HMAC-SHA256 over them and the target.
+pub fn h2_auth_token(key: &str, target: &str) -> String {
+    let ts = format!("{:x}", unix_secs());
+    let mut nonce = [0u8; H2_NONCE_LEN];
+    rand::thread_rng().fill(&mut nonce[..]);
+    let nonce = to_hex(&nonce[..]);
+    let mac = token_mac(key, ts.as_str(), nonce.as_str(), target);
+    format!("{}.{}.{}", ts, nonce, mac)
+}
+
+/// Whether `token` proves `key` for `target`. Its stamp has to be within
+/// `window` seconds of now and is taken only once, so a captured request
+/// replayed by a probe gets the same answer as garbage; 0 turns the replay
+/// check off.
+pub fn check_h2_auth_token(key: &str, target: &str, token: &str, window: u64) -> bool {
+    if key.is_empty() {
+        return false;
+    }
+    let mut parts = token.split('.');
+    let (ts, nonce, mac) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(t), Some(n), Some(m), None) => (t, n, m),
+        _ => return false,
+    };
+    let expected = token_mac(key, ts, nonce, target);
+    if verify_slices_are_equal(expected.as_bytes(), mac.as_bytes()).is_err() {
+        return false;
+    }
+    if window == 0 {
+        return true;
+    }
+    let secs = match u64::from_str_radix(ts, 16) {
+        Ok(v) => v,
+        Err(_) => return false,
+    };
+    let mut stamp = [0u8; H2_NONCE_LEN];
+    for (i, b) in stamp.iter_mut().enumerate() {
+        *b = match nonce
+            .get(2 * i..2 * i + 2)
+            .map(|h| u8::from_str_radix(h, 16))
+        {
+            Some(Ok(v)) => v,
+            _ => return false,
+        };
+    }
+    nonce.len() == 2 * H2_NONCE_LEN && check_auth_stamp(window, secs, &stamp)
+}
+
+fn h2_io_error(e: h2::Error) -> io::Error {
+    if e.is_io() {
+        return e.into_io().unwrap();
+    }
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// Reads the DATA frames of an h2 stream. Window capacity is only released
+/// once the bytes are handed to the caller, so a slow consumer stalls the
+/// sender through ordinary h2 flow control.
+pub struct H2Reader {
+    recv: RecvStream,
+    pending: Bytes,
+}
+
+impl H2Reader {
+    pub fn new(recv: RecvStream) -> Self {
+        Self {
+            recv,
+            pending: Bytes::new(),
+        }
+    }
+}
+
+impl AsyncRead for H2Reader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        while self.pending.is_empty() {
+            match ready!(self.recv.poll_data(cx)) {
+                None => return Poll::Ready(Ok(0)),
+                Some(Err(e)) => return Poll::Ready(Err(h2_io_error(e))),
+                Some(Ok(data)) => self.pending = data,
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.pending.len());
+        let data = self.pending.split_to(n);
+        buf[..n].copy_from_slice(&data[..]);
+        if let Err(e) = self.recv.flow_control().release_capacity(n) {
+            return Poll::Ready(Err(h2_io_error(e)));
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// Writes into an h2 stream, never sending more than the peer's window.
+pub struct H2Writer {
+    send: SendStream<Bytes>,
+}
+
+impl H2Writer {
+    pub fn new(send: SendStream<Bytes>) -> Self {
+        Self { send }
+    }
+    /// Ends the stream with trailers instead of an empty DATA frame.
+    pub fn send_trailers(&mut self, trailers: http::HeaderMap) -> io::Result<()> {
+        self.send.send_trailers(trailers).map_err(h2_io_error)
+    }
+    pub fn reset(&mut self) {
+        self.send.send_reset(h2::Reason::CANCEL);
+    }
+}
+
+impl AsyncWrite for H2Writer {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        self.send.reserve_capacity(buf.len());
+        let n = match ready!(self.send.poll_capacity(cx)) {
+            None => return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe))),
+            Some(Err(e)) => return Poll::Ready(Err(h2_io_error(e))),
+            Some(Ok(n)) => std::cmp::min(n, buf.len()),
+        };
+        if let Err(e) = self
+            .send
+            .send_data(Bytes::copy_from_slice(&buf[..n]), false)
+        {
+            return Poll::Ready(Err(h2_io_error(e)));
+        }
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.send.reserve_capacity(0);
+        match self.send.send_data(Bytes::new(), true) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(e) => Poll::Ready(Err(h2_io_error(e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_h2_auth_token() {
+        let token = h2_auth_token("secret", "example.com:443");
+        assert!(!check_h2_auth_token(
+            "other",
+            "example.com:443",
+            &token,
+            120
+        ));
+        assert!(!check_h2_auth_token(
+            "secret",
+            "example.org:443",
+            &token,
+            120
+        ));
+        assert!(check_h2_auth_token(
+            "secret",
+            "example.com:443",
+            &token,
+            120
+        ));
+        // taken once
+        assert!(!check_h2_auth_token(
+            "secret",
+            "example.com:443",
+            &token,
+            120
+        ));
+
+        let token = h2_auth_token("", "example.com:443");
+        assert!(!check_h2_auth_token("", "example.com:443", &token, 0));
+
+        let ts = format!("{:x}", unix_secs() - 1000);
+        let nonce = to_hex(&[7u8; H2_NONCE_LEN]);
+        let mac = token_mac("secret", ts.as_str(), nonce.as_str(), "a:1");
+        let stale = format!("{}.{}.{}", ts, nonce, mac);
+        assert!(!check_h2_auth_token("secret", "a:1", &stale, 120));
+        assert!(check_h2_auth_token("secret", "a:1", &stale, 0));
+        assert!(!check_h2_auth_token("secret", "a:1", "1.2", 120));
+    }
+}
@@ -0,0 +1,126 @@
+//! Responses to the few plain HTTP/1.1 requests rsnova makes itself, like
+//! those of the ACME client and the rule list updates.
+use crate::utils::make_io_error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub struct HttpResponse {
+    pub status: u16,
+    /// lowercased names
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Reads the response to a request sent on `stream`, which has no body if
+/// `head_only`.
+pub async fn read_response<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    head_only: bool,
+) -> Result<HttpResponse, std::io::Error> {
+    let mut buf = Vec::new();
+    let mut chunk = vec![0u8; 8192];
+    let mut resp = loop {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut head = httparse::Response::new(&mut headers);
+        match head.parse(&buf[..]) {
+            Ok(httparse::Status::Complete(n)) => {
+                break HttpResponse {
+                    status: head.code.unwrap_or(0),
+                    headers: head
+                        .headers
+                        .iter()
+                        .map(|h| {
+                            let value = String::from_utf8_lossy(h.value).trim().to_owned();
+                            (h.name.to_ascii_lowercase(), value)
+                        })
+                        .collect(),
+                    body: buf[n..].to_vec(),
+                };
+            }
+            Ok(httparse::Status::Partial) => {}
+            Err(e) => return Err(make_io_error(&e.to_string())),
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(make_io_error("http server closed the connection"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+    if head_only || resp.status == 204 || resp.status == 304 {
+        resp.body.clear();
+        return Ok(resp);
+    }
+    let chunked = resp
+        .header("transfer-encoding")
+        .is_some_and(|v| v.to_ascii_lowercase().contains("chunked"));
+    let length = resp
+        .header("content-length")
+        .and_then(|v| v.parse::<usize>().ok());
+    loop {
+        if chunked {
+            if let Some(body) = dechunk(&resp.body[..]) {
+                resp.body = body;
+                return Ok(resp);
+            }
+        } else if let Some(len) = length {
+            if resp.body.len() >= len {
+                resp.body.truncate(len);
+                return Ok(resp);
+            }
+        }
+        let n = match stream.read(&mut chunk).await {
+            Ok(n) => n,
+            // the body ends with the connection, with or without close_notify
+            Err(_) if !chunked && length.is_none() => 0,
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            if !chunked && length.is_none() {
+                return Ok(resp);
+            }
+            return Err(make_io_error("truncated http response"));
+        }
+        resp.body.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// The body of a chunked encoding, None until its last chunk arrived.
+fn dechunk(data: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut rest = data;
+    loop {
+        let (n, size) = match httparse::parse_chunk_size(rest).ok()? {
+            httparse::Status::Complete((n, size)) => (n, size as usize),
+            httparse::Status::Partial => return None,
+        };
+        rest = &rest[n..];
+        if size == 0 {
+            return Some(body);
+        }
+        if rest.len() < size + 2 {
+            return None;
+        }
+        body.extend_from_slice(&rest[..size]);
+        rest = &rest[size + 2..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dechunk() {
+        let data = b"5\r\nhello\r\n7;x=y\r\n, world\r\n0\r\n\r\n";
+        assert_eq!(dechunk(&data[..]), Some(b"hello, world".to_vec()));
+        assert_eq!(dechunk(&data[..12]), None);
+    }
+}
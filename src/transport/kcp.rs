@@ -0,0 +1,341 @@
+use crate::config::KcpConfig;
+use crate::utils::make_io_error;
+use bytes::Bytes;
+use kcp::Kcp;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::delay_for;
+
+const KCP_OVERHEAD: usize = 24;
+const KCP_MAX_DATAGRAM: usize = 64 * 1024;
+// rmux pings every ~30s, so a silent peer for this long is gone
+const KCP_SESSION_IDLE: Duration = Duration::from_secs(90);
+
+type Datagram = (SocketAddr, Bytes);
+
+struct KcpOutput {
+    peer: SocketAddr,
+    tx: mpsc::UnboundedSender<Datagram>,
+}
+
+impl Write for KcpOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.tx.send((self.peer, Bytes::copy_from_slice(buf))) {
+            Ok(()) => Ok(buf.len()),
+            Err(_) => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct KcpState {
+    kcp: Kcp<KcpOutput>,
+    start: Instant,
+    last_input: Instant,
+    max_send: usize,
+    snd_wnd: usize,
+    // set once the local side dropped its stream
+    released: bool,
+    closed: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl KcpState {
+    fn now_ms(&self) -> u32 {
+        self.start.elapsed().as_millis() as u32
+    }
+    fn wake(&mut self) {
+        if let Some(w) = self.read_waker.take() {
+            w.wake();
+        }
+        if let Some(w) = self.write_waker.take() {
+            w.wake();
+        }
+    }
+    fn close(&mut self) {
+        self.closed = true;
+        self.wake();
+    }
+    fn input(&mut self, data: &[u8]) {
+        if self.kcp.input(data).is_err() {
+            return;
+        }
+        self.last_input = Instant::now();
+        self.wake();
+    }
+}
+
+type SharedState = Arc<Mutex<KcpState>>;
+
+fn new_state(
+    conv: u32,
+    peer: SocketAddr,
+    tx: mpsc::UnboundedSender<Datagram>,
+    cfg: &KcpConfig,
+) -> SharedState {
+    let mut kcp = Kcp::new(conv, KcpOutput { peer, tx });
+    kcp.set_nodelay(
+        cfg.nodelay(),
+        cfg.interval(),
+        cfg.resend(),
+        cfg.no_congestion(),
+    );
+    kcp.set_wndsize(cfg.snd_wnd(), cfg.rcv_wnd());
+    if kcp.set_mtu(cfg.mtu()).is_err() {
+        warn!("invalid kcp mtu:{}", cfg.mtu());
+    }
+    let now = Instant::now();
+    let state = Arc::new(Mutex::new(KcpState {
+        kcp,
+        start: now,
+        last_input: now,
+        // stay well below the fragment limit of a single kcp message
+        max_send: (cfg.mtu() - KCP_OVERHEAD) * 64,
+        snd_wnd: cfg.snd_wnd() as usize,
+        released: false,
+        closed: false,
+        read_waker: None,
+        write_waker: None,
+    }));
+    tokio::spawn(update_loop(state.clone()));
+    state
+}
+
+async fn update_loop(state: SharedState) {
+    loop {
+        let wait = {
+            let mut s = state.lock().unwrap();
+            if s.closed {
+                break;
+            }
+            if s.last_input.elapsed() > KCP_SESSION_IDLE || (s.released && s.kcp.wait_snd() == 0) {
+                s.close();
+                break;
+            }
+            let now = s.now_ms();
+            if s.kcp.update(now).is_err() {
+                s.close();
+                break;
+            }
+            s.kcp.check(now).saturating_sub(now)
+        };
+        delay_for(Duration::from_millis(std::cmp::max(wait, 1) as u64)).await;
+    }
+}
+
+pub struct KcpStream {
+    state: SharedState,
+    pending: Bytes,
+}
+
+impl KcpStream {
+    fn new(state: SharedState) -> Self {
+        Self {
+            state,
+            pending: Bytes::new(),
+        }
+    }
+}
+
+impl Drop for KcpStream {
+    fn drop(&mut self) {
+        if let Ok(mut s) = self.state.lock() {
+            s.released = true;
+        }
+    }
+}
+
+impl AsyncRead for KcpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.pending.is_empty() {
+            let mut s = self.state.lock().unwrap();
+            let size = match s.kcp.peeksize() {
+                Ok(size) => size,
+                Err(_) => {
+                    if s.closed {
+                        return Poll::Ready(Ok(0));
+                    }
+                    s.read_waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            };
+            let mut data = vec![0u8; size];
+            let n = match s.kcp.recv(&mut data) {
+                Ok(n) => n,
+                Err(e) => return Poll::Ready(Err(make_io_error(&e.to_string()))),
+            };
+            data.truncate(n);
+            drop(s);
+            self.pending = Bytes::from(data);
+        }
+        let n = std::cmp::min(buf.len(), self.pending.len());
+        let data = self.pending.split_to(n);
+        buf[..n].copy_from_slice(&data[..]);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for KcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut s = self.state.lock().unwrap();
+        if s.closed {
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+        }
+        if s.kcp.wait_snd() >= s.snd_wnd * 2 {
+            s.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = std::cmp::min(buf.len(), s.max_send);
+        if let Err(e) = s.kcp.send(&buf[..n]) {
+            return Poll::Ready(Err(make_io_error(&e.to_string())));
+        }
+        let _ = s.kcp.flush();
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut s = self.state.lock().unwrap();
+        let _ = s.kcp.flush();
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // kcp has no FIN, the carried protocol is expected to close itself
+        self.poll_flush(cx)
+    }
+}
+
+async fn resolve(addr: &str) -> Result<SocketAddr, std::io::Error> {
+    match tokio::net::lookup_host(addr).await?.next() {
+        Some(a) => Ok(a),
+        None => Err(make_io_error("no address resolved")),
+    }
+}
+
+pub async fn kcp_connect(addr: &str, cfg: &KcpConfig) -> Result<KcpStream, std::io::Error> {
+    let remote = resolve(addr).await?;
+    let local = if remote.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(remote).await?;
+    let (mut recv_half, mut send_half) = socket.split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<Datagram>();
+    let state = new_state(rand::random::<u32>(), remote, tx, cfg);
+    tokio::spawn(async move {
+        while let Some((_, data)) = rx.recv().await {
+            if send_half.send(&data[..]).await.is_err() {
+                break;
+            }
+        }
+    });
+    let input_state = state.clone();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; KCP_MAX_DATAGRAM];
+        loop {
+            let r = tokio::time::timeout(Duration::from_secs(1), recv_half.recv(&mut buf)).await;
+            let done = {
+                let mut s = input_state.lock().unwrap();
+                match r {
+                    _ if s.closed => true,
+                    Ok(Ok(n)) => {
+                        s.input(&buf[..n]);
+                        false
+                    }
+                    Ok(Err(e)) => {
+                        error!("kcp recv error:{}", e);
+                        s.close();
+                        true
+                    }
+                    Err(_) => false,
+                }
+            };
+            if done {
+                break;
+            }
+        }
+    });
+    Ok(KcpStream::new(state))
+}
+
+pub struct KcpListener {
+    incoming: mpsc::UnboundedReceiver<(KcpStream, SocketAddr)>,
+}
+
+impl KcpListener {
+    pub async fn accept(&mut self) -> Option<(KcpStream, SocketAddr)> {
+        self.incoming.recv().await
+    }
+}
+
+/// Listens on one UDP socket, demultiplexing kcp sessions by peer address.
+pub async fn kcp_listen(addr: &str, cfg: KcpConfig) -> Result<KcpListener, std::io::Error> {
+    let socket = UdpSocket::bind(addr).await?;
+    let (mut recv_half, mut send_half) = socket.split();
+    let (out_tx, mut out_rx) = mpsc::unbounded_channel::<Datagram>();
+    let (accept_tx, accept_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some((peer, data)) = out_rx.recv().await {
+            if let Err(e) = send_half.send_to(&data[..], &peer).await {
+                error!("kcp send to {} error:{}", peer, e);
+            }
+        }
+    });
+    tokio::spawn(async move {
+        let mut sessions: HashMap<SocketAddr, SharedState> = HashMap::new();
+        let mut buf = vec![0u8; KCP_MAX_DATAGRAM];
+        loop {
+            let (n, peer) = match recv_half.recv_from(&mut buf).await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("kcp listener recv error:{}", e);
+                    break;
+                }
+            };
+            if n < KCP_OVERHEAD {
+                continue;
+            }
+            let conv = kcp::get_conv(&buf[..n]);
+            let fresh = match sessions.get(&peer) {
+                Some(s) => {
+                    let s = s.lock().unwrap();
+                    s.closed || s.kcp.conv() != conv
+                }
+                None => true,
+            };
+            if fresh {
+                sessions.retain(|_, s| !s.lock().unwrap().closed);
+                let state = new_state(conv, peer, out_tx.clone(), &cfg);
+                sessions.insert(peer, state.clone());
+                if accept_tx.send((KcpStream::new(state), peer)).is_err() {
+                    break;
+                }
+            }
+            if let Some(s) = sessions.get(&peer) {
+                s.lock().unwrap().input(&buf[..n]);
+            }
+        }
+    });
+    Ok(KcpListener {
+        incoming: accept_rx,
+    })
+}
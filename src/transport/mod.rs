@@ -0,0 +1,42 @@
+mod acme;
+mod dns_tunnel;
+mod grpc;
+mod h3;
+mod http2;
+mod http_client;
+mod kcp;
+mod naive;
+mod noise;
+mod plugin;
+mod quic;
+mod shadowsocks;
+mod tls;
+mod trojan;
+mod vmess;
+
+pub use self::dns_tunnel::{dns_connect, dns_listen, dns_question};
+pub use self::grpc::{grpc_path, GrpcReader, GrpcWriter, GRPC_CONTENT_TYPE};
+pub use self::h3::{
+    drain_uni_streams, h3_connect, h3_listen, header_value, open_control_stream, read_headers,
+    write_headers, H3Reader, H3Writer, H3_PROTOCOL,
+};
+pub use self::http2::{
+    check_h2_auth_token, h2_auth_token, H2Reader, H2Writer, H2_AUTH_HEADER, H2_TARGET_HEADER,
+};
+pub use self::http_client::read_response;
+pub use self::kcp::{kcp_connect, kcp_listen};
+pub use self::naive::{naive_padding_value, NaivePadReader, NaivePadWriter, NAIVE_PADDING_HEADER};
+pub use self::noise::{noise_accept, noise_connect, noise_keypair, NoiseKeys};
+pub use self::plugin::{plugin_addr, plugin_listen};
+pub use self::quic::{quic_connect, quic_listen};
+pub use self::shadowsocks::{
+    encode_ss_addr, parse_ss_addr, ShadowsocksCipher, ShadowsocksReader, ShadowsocksWriter,
+};
+pub use self::tls::{
+    channel_client_config, listener_server_config, new_client_config, tls_accept, tls_connect,
+    tls_connect_io, ClientAuthAcceptor, TlsClientStream,
+};
+pub use self::trojan::{
+    encode_trojan_request, parse_trojan_request, trojan_hash, TROJAN_CMD_CONNECT,
+};
+pub use self::vmess::{parse_uuid, vmess_request, VmessReader, VmessWriter};
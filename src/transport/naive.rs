@@ -0,0 +1,188 @@
+use rand::Rng;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+pub const NAIVE_PADDING_HEADER: &str = "padding";
+
+// naiveproxy pads the first reads and writes of each direction
+const FIRST_PADDINGS: usize = 8;
+const MAX_PADDING: usize = 255;
+const MAX_PAYLOAD: usize = 65535;
+// not huffman coded by hpack, so the header keeps its length on the wire
+const PADDING_CHARS: &[u8] = b"!#$()+<>?@[]^`{}";
+
+/// Value of the `padding` header, 16 to 32 random characters.
+pub fn naive_padding_value() -> String {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(16, 33);
+    (0..len)
+        .map(|_| PADDING_CHARS[rng.gen_range(0, PADDING_CHARS.len())] as char)
+        .collect()
+}
+
+/// Strips the padding of the first frames the peer sent: a 2 byte payload
+/// length, a 1 byte padding length, the payload and the zero padding.
+pub struct NaivePadReader<R> {
+    inner: R,
+    frames: usize,
+    header: [u8; 3],
+    header_len: usize,
+    payload_left: usize,
+    padding_left: usize,
+}
+
+impl<R> NaivePadReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            frames: FIRST_PADDINGS,
+            header: [0u8; 3],
+            header_len: 0,
+            payload_left: 0,
+            padding_left: 0,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for NaivePadReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let me = &mut *self;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            if me.payload_left > 0 {
+                let n = std::cmp::min(buf.len(), me.payload_left);
+                let n = ready!(Pin::new(&mut me.inner).poll_read(cx, &mut buf[..n]))?;
+                me.payload_left -= n;
+                return Poll::Ready(Ok(n));
+            }
+            if me.padding_left > 0 {
+                let mut skip = [0u8; MAX_PADDING];
+                let n = me.padding_left;
+                let n = ready!(Pin::new(&mut me.inner).poll_read(cx, &mut skip[..n]))?;
+                if n == 0 {
+                    return Poll::Ready(Ok(0));
+                }
+                me.padding_left -= n;
+                continue;
+            }
+            if me.frames == 0 {
+                return Pin::new(&mut me.inner).poll_read(cx, buf);
+            }
+            let start = me.header_len;
+            let n = ready!(Pin::new(&mut me.inner).poll_read(cx, &mut me.header[start..]))?;
+            if n == 0 {
+                return Poll::Ready(Ok(0));
+            }
+            me.header_len += n;
+            if me.header_len == me.header.len() {
+                me.header_len = 0;
+                me.frames -= 1;
+                me.payload_left = u16::from_be_bytes([me.header[0], me.header[1]]) as usize;
+                me.padding_left = me.header[2] as usize;
+            }
+        }
+    }
+}
+
+/// Pads the first writes the way `NaivePadReader` expects them, later
+/// writes go through as they are.
+pub struct NaivePadWriter<W> {
+    inner: W,
+    frames: usize,
+    pending: Vec<u8>,
+    pos: usize,
+}
+
+impl<W> NaivePadWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            frames: FIRST_PADDINGS,
+            pending: Vec::new(),
+            pos: 0,
+        }
+    }
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+}
+
+impl<W: AsyncWrite + Unpin> NaivePadWriter<W> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pos < self.pending.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero)));
+            }
+            self.pos += n;
+        }
+        self.pending.clear();
+        self.pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for NaivePadWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let me = &mut *self;
+        ready!(me.poll_pending(cx))?;
+        if me.frames == 0 || buf.is_empty() {
+            return Pin::new(&mut me.inner).poll_write(cx, buf);
+        }
+        let n = std::cmp::min(buf.len(), MAX_PAYLOAD);
+        let padding = rand::thread_rng().gen_range(0, MAX_PADDING + 1);
+        me.pending.extend_from_slice(&(n as u16).to_be_bytes());
+        me.pending.push(padding as u8);
+        me.pending.extend_from_slice(&buf[..n]);
+        me.pending.resize(me.pending.len() + padding, 0);
+        me.frames -= 1;
+        // the frame is ours now, whatever is left goes out with the next call
+        if let Poll::Ready(Err(e)) = me.poll_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_padding_roundtrip() {
+        let mut writer = NaivePadWriter::new(Vec::new());
+        for i in 0..10u8 {
+            writer.write_all(&[i; 100]).await.unwrap();
+        }
+        writer.flush().await.unwrap();
+        let wire = writer.inner;
+        assert!(wire.len() >= 1000 + 3 * FIRST_PADDINGS);
+        let mut reader = NaivePadReader::new(&wire[..]);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        let expected: Vec<u8> = (0..10u8).flat_map(|i| vec![i; 100]).collect();
+        assert_eq!(data, expected);
+        assert!(naive_padding_value().len() >= 16);
+    }
+}
@@ -0,0 +1,841 @@
+//! `noise://` connections: a Noise_IK or Noise_XK handshake
+//! (25519, ChaChaPoly, SHA256) instead of TLS. Both sides hold static
+//! X25519 keys, the client knows the server's and the server lists the
+//! clients it takes, so there are no certificates. Every message goes with
+//! a 2 byte length, and after the handshake the connection runs in the
+//! codec streams of `obfs`. X25519 is done here: ring's keys agree once
+//! and can't be loaded, while a Noise ephemeral takes part in two DHs and
+//! the static keys come from the config. The tests check it against ring
+//! and the handshakes against vectors of another Noise implementation.
+use crate::config::NoiseConfig;
+use crate::obfs::{ObfsDecoder, ObfsEncoder, ObfsReader, ObfsWriter};
+use crate::utils::{make_io_error, zeroize, FrameCodec, FrameReader, FrameWriter, LengthPrefix};
+use bytes::{Buf, BytesMut};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hmac};
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const MAX_MESSAGE_LEN: usize = 65535;
+const MAX_PAYLOAD_LEN: usize = 16 * 1024;
+
+type Key = [u8; KEY_LEN];
+
+// field elements of 2^255 - 19 in five 51 bit limbs
+type Fe = [u64; 5];
+const MASK51: u64 = (1 << 51) - 1;
+
+fn fe_from_bytes(b: &Key) -> Fe {
+    let mut w = [0u64; 4];
+    for (i, v) in w.iter_mut().enumerate() {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&b[i * 8..i * 8 + 8]);
+        *v = u64::from_le_bytes(bytes);
+    }
+    [
+        w[0] & MASK51,
+        (w[0] >> 51 | w[1] << 13) & MASK51,
+        (w[1] >> 38 | w[2] << 26) & MASK51,
+        (w[2] >> 25 | w[3] << 39) & MASK51,
+        (w[3] >> 12) & MASK51,
+    ]
+}
+
+fn fe_carry(t: &mut Fe) {
+    for i in 0..4 {
+        t[i + 1] += t[i] >> 51;
+        t[i] &= MASK51;
+    }
+    t[0] += 19 * (t[4] >> 51);
+    t[4] &= MASK51;
+}
+
+fn fe_to_bytes(h: &Fe) -> Key {
+    let mut t = *h;
+    fe_carry(&mut t);
+    fe_carry(&mut t);
+    // t is below 2p now, take p off if t + 19 reaches 2^255
+    let mut q = (t[0] + 19) >> 51;
+    for v in t.iter().skip(1) {
+        q = (v + q) >> 51;
+    }
+    t[0] += 19 * q;
+    for i in 0..4 {
+        t[i + 1] += t[i] >> 51;
+        t[i] &= MASK51;
+    }
+    t[4] &= MASK51;
+    let w = [
+        t[0] | t[1] << 51,
+        t[1] >> 13 | t[2] << 38,
+        t[2] >> 26 | t[3] << 25,
+        t[3] >> 39 | t[4] << 12,
+    ];
+    let mut out = [0u8; KEY_LEN];
+    for (i, v) in w.iter().enumerate() {
+        out[i * 8..i * 8 + 8].copy_from_slice(&v.to_le_bytes());
+    }
+    out
+}
+
+fn fe_add(a: &Fe, b: &Fe) -> Fe {
+    let mut r = [0u64; 5];
+    for i in 0..5 {
+        r[i] = a[i] + b[i];
+    }
+    r
+}
+
+fn fe_sub(a: &Fe, b: &Fe) -> Fe {
+    // plus 2p, so no limb goes below zero
+    let mut r = [
+        a[0] + 0xf_ffff_ffff_ffda - b[0],
+        a[1] + 0xf_ffff_ffff_fffe - b[1],
+        a[2] + 0xf_ffff_ffff_fffe - b[2],
+        a[3] + 0xf_ffff_ffff_fffe - b[3],
+        a[4] + 0xf_ffff_ffff_fffe - b[4],
+    ];
+    fe_carry(&mut r);
+    r
+}
+
+fn fe_mul(a: &Fe, b: &Fe) -> Fe {
+    let m = |x: u64, y: u64| u128::from(x) * u128::from(y);
+    let b19 = [b[0], b[1] * 19, b[2] * 19, b[3] * 19, b[4] * 19];
+    let r = [
+        m(a[0], b[0]) + m(a[1], b19[4]) + m(a[2], b19[3]) + m(a[3], b19[2]) + m(a[4], b19[1]),
+        m(a[0], b[1]) + m(a[1], b[0]) + m(a[2], b19[4]) + m(a[3], b19[3]) + m(a[4], b19[2]),
+        m(a[0], b[2]) + m(a[1], b[1]) + m(a[2], b[0]) + m(a[3], b19[4]) + m(a[4], b19[3]),
+        m(a[0], b[3]) + m(a[1], b[2]) + m(a[2], b[1]) + m(a[3], b[0]) + m(a[4], b19[4]),
+        m(a[0], b[4]) + m(a[1], b[3]) + m(a[2], b[2]) + m(a[3], b[1]) + m(a[4], b[0]),
+    ];
+    let mut out = [0u64; 5];
+    let mut carry = 0u128;
+    for i in 0..5 {
+        let v = r[i] + carry;
+        out[i] = v as u64 & MASK51;
+        carry = v >> 51;
+    }
+    let v = u128::from(out[0]) + carry * 19;
+    out[0] = v as u64 & MASK51;
+    out[1] += (v >> 51) as u64;
+    out
+}
+
+fn fe_invert(a: &Fe) -> Fe {
+    // a^(p - 2), p - 2 = 2^255 - 21
+    let mut r = [1, 0, 0, 0, 0];
+    for bit in (0..255).rev() {
+        r = fe_mul(&r, &r);
+        if bit != 2 && bit != 4 {
+            r = fe_mul(&r, a);
+        }
+    }
+    r
+}
+
+fn fe_cswap(a: &mut Fe, b: &mut Fe, swap: u64) {
+    let mask = 0u64.wrapping_sub(swap);
+    for i in 0..5 {
+        let t = mask & (a[i] ^ b[i]);
+        a[i] ^= t;
+        b[i] ^= t;
+    }
+}
+
+/// X25519 of RFC 7748.
+fn x25519(scalar: &Key, point: &Key) -> Key {
+    let mut k = *scalar;
+    k[0] &= 248;
+    k[31] &= 127;
+    k[31] |= 64;
+    let x1 = fe_from_bytes(point);
+    let (mut x2, mut z2, mut x3, mut z3) = ([1, 0, 0, 0, 0], [0u64; 5], x1, [1, 0, 0, 0, 0]);
+    let a24 = [121_665, 0, 0, 0, 0];
+    let mut swap = 0;
+    for t in (0..255).rev() {
+        let bit = u64::from((k[t / 8] >> (t % 8)) & 1);
+        swap ^= bit;
+        fe_cswap(&mut x2, &mut x3, swap);
+        fe_cswap(&mut z2, &mut z3, swap);
+        swap = bit;
+        let a = fe_add(&x2, &z2);
+        let aa = fe_mul(&a, &a);
+        let b = fe_sub(&x2, &z2);
+        let bb = fe_mul(&b, &b);
+        let e = fe_sub(&aa, &bb);
+        let c = fe_add(&x3, &z3);
+        let d = fe_sub(&x3, &z3);
+        let da = fe_mul(&d, &a);
+        let cb = fe_mul(&c, &b);
+        let sum = fe_add(&da, &cb);
+        x3 = fe_mul(&sum, &sum);
+        let diff = fe_sub(&da, &cb);
+        z3 = fe_mul(&x1, &fe_mul(&diff, &diff));
+        x2 = fe_mul(&aa, &bb);
+        z2 = fe_mul(&e, &fe_add(&aa, &fe_mul(&a24, &e)));
+    }
+    fe_cswap(&mut x2, &mut x3, swap);
+    fe_cswap(&mut z2, &mut z3, swap);
+    fe_to_bytes(&fe_mul(&x2, &fe_invert(&z2)))
+}
+
+fn public_key(private: &Key) -> Key {
+    let mut base = [0u8; KEY_LEN];
+    base[0] = 9;
+    x25519(private, &base)
+}
+
+fn random_key() -> Key {
+    let mut key = [0u8; KEY_LEN];
+    SystemRandom::new().fill(&mut key).unwrap();
+    key
+}
+
+/// A new private key and its public key, base64 encoded.
+pub fn noise_keypair() -> (String, String) {
+    let private = random_key();
+    (
+        base64::encode(&private),
+        base64::encode(&public_key(&private)),
+    )
+}
+
+fn decode_key(key: &str) -> io::Result<Key> {
+    match base64::decode(key.trim()) {
+        Ok(bytes) if bytes.len() == KEY_LEN => {
+            let mut k = [0u8; KEY_LEN];
+            k.copy_from_slice(&bytes[..]);
+            Ok(k)
+        }
+        _ => Err(make_io_error(&format!("invalid noise key:{}", key))),
+    }
+}
+
+fn hkdf(ck: &Key, ikm: &[u8]) -> (Key, Key) {
+    let temp = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, ck), ikm);
+    let temp = hmac::Key::new(hmac::HMAC_SHA256, temp.as_ref());
+    let out1 = hmac::sign(&temp, &[1]);
+    let mut ctx = hmac::Context::with_key(&temp);
+    ctx.update(out1.as_ref());
+    ctx.update(&[2]);
+    let out2 = ctx.sign();
+    let (mut k1, mut k2) = ([0u8; KEY_LEN], [0u8; KEY_LEN]);
+    k1.copy_from_slice(out1.as_ref());
+    k2.copy_from_slice(out2.as_ref());
+    (k1, k2)
+}
+
+/// A Noise CipherState.
+struct CipherState {
+    key: LessSafeKey,
+    n: u64,
+}
+
+impl CipherState {
+    fn new(key: &Key) -> Self {
+        Self {
+            key: LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key).unwrap()),
+            n: 0,
+        }
+    }
+    fn nonce(&mut self) -> Nonce {
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[4..].copy_from_slice(&self.n.to_le_bytes());
+        self.n += 1;
+        Nonce::assume_unique_for_key(nonce)
+    }
+    fn encrypt(&mut self, ad: &[u8], data: &mut Vec<u8>) {
+        let nonce = self.nonce();
+        self.key
+            .seal_in_place_append_tag(nonce, Aad::from(ad), data)
+            .unwrap();
+    }
+    fn decrypt(&mut self, ad: &[u8], data: &mut Vec<u8>) -> io::Result<()> {
+        let nonce = self.nonce();
+        let n = match self.key.open_in_place(nonce, Aad::from(ad), &mut data[..]) {
+            Ok(plain) => plain.len(),
+            Err(_) => return Err(make_io_error("noise message failed to decrypt")),
+        };
+        data.truncate(n);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Token {
+    E,
+    S,
+    Ee,
+    Es,
+    Se,
+    Ss,
+}
+
+const IK: &[&[Token]] = &[
+    &[Token::E, Token::Es, Token::S, Token::Ss],
+    &[Token::E, Token::Ee, Token::Se],
+];
+const XK: &[&[Token]] = &[
+    &[Token::E, Token::Es],
+    &[Token::E, Token::Ee],
+    &[Token::S, Token::Se],
+];
+
+/// A Noise HandshakeState of a pattern whose responder key is known.
+struct Handshake {
+    h: Key,
+    ck: Key,
+    cipher: Option<CipherState>,
+    pattern: &'static [&'static [Token]],
+    initiator: bool,
+    s: Key,
+    e: Key,
+    rs: Option<Key>,
+    re: Key,
+}
+
+impl Drop for Handshake {
+    fn drop(&mut self) {
+        zeroize(&mut self.ck);
+        zeroize(&mut self.e);
+        zeroize(&mut self.s);
+    }
+}
+
+impl Handshake {
+    fn new(keys: &NoiseKeys, initiator: bool) -> Self {
+        let (name, pattern): (&[u8], _) = match keys.xk {
+            true => (b"Noise_XK_25519_ChaChaPoly_SHA256", XK),
+            false => (b"Noise_IK_25519_ChaChaPoly_SHA256", IK),
+        };
+        let mut h = [0u8; KEY_LEN];
+        h[..name.len()].copy_from_slice(name);
+        let mut hs = Self {
+            h,
+            ck: h,
+            cipher: None,
+            pattern,
+            initiator,
+            s: keys.private,
+            e: [0u8; KEY_LEN],
+            rs: keys.server,
+            re: [0u8; KEY_LEN],
+        };
+        // the empty prologue, then the responder's key both sides know
+        hs.mix_hash(&[]);
+        let responder = if initiator {
+            keys.server.unwrap_or_default()
+        } else {
+            keys.public
+        };
+        hs.mix_hash(&responder);
+        hs
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        ctx.update(&self.h);
+        ctx.update(data);
+        self.h.copy_from_slice(ctx.finish().as_ref());
+    }
+
+    fn mix_key(&mut self, ikm: &[u8]) {
+        let (ck, k) = hkdf(&self.ck, ikm);
+        self.ck = ck;
+        self.cipher = Some(CipherState::new(&k));
+    }
+
+    fn encrypt_and_hash(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        let mut data = data.to_vec();
+        let h = self.h;
+        if let Some(cipher) = self.cipher.as_mut() {
+            cipher.encrypt(&h, &mut data);
+        }
+        self.mix_hash(&data);
+        out.extend_from_slice(&data);
+    }
+
+    fn decrypt_and_hash(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut plain = data.to_vec();
+        let h = self.h;
+        if let Some(cipher) = self.cipher.as_mut() {
+            cipher.decrypt(&h, &mut plain)?;
+        }
+        self.mix_hash(data);
+        Ok(plain)
+    }
+
+    fn dh(&mut self, token: Token) -> io::Result<()> {
+        let rs = self.rs.unwrap_or([0u8; KEY_LEN]);
+        let (mine, theirs) = match (token, self.initiator) {
+            (Token::Ee, _) => (self.e, self.re),
+            (Token::Ss, _) => (self.s, rs),
+            (Token::Es, true) | (Token::Se, false) => (self.e, rs),
+            (Token::Es, false) | (Token::Se, true) => (self.s, self.re),
+            _ => return Ok(()),
+        };
+        let mut shared = x25519(&mine, &theirs);
+        // a low order point of the peer gives no secret
+        if shared == [0u8; KEY_LEN] {
+            return Err(make_io_error("invalid noise public key"));
+        }
+        self.mix_key(&shared);
+        zeroize(&mut shared);
+        Ok(())
+    }
+
+    /// Whether this side writes message `i` of the pattern.
+    fn writes(&self, i: usize) -> bool {
+        (i & 1 == 0) == self.initiator
+    }
+
+    fn write_message(&mut self, i: usize) -> io::Result<Vec<u8>> {
+        self.write_message_with(i, random_key())
+    }
+
+    /// `write_message` with the ephemeral key `e`, if the message has one.
+    fn write_message_with(&mut self, i: usize, e: Key) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        for token in self.pattern[i].iter() {
+            match token {
+                Token::E => {
+                    self.e = e;
+                    let e = public_key(&self.e);
+                    out.extend_from_slice(&e);
+                    self.mix_hash(&e);
+                }
+                Token::S => {
+                    let s = public_key(&self.s);
+                    self.encrypt_and_hash(&s, &mut out);
+                }
+                t => self.dh(*t)?,
+            }
+        }
+        // an empty payload
+        self.encrypt_and_hash(&[], &mut out);
+        Ok(out)
+    }
+
+    fn read_message(&mut self, i: usize, mut msg: &[u8]) -> io::Result<()> {
+        for token in self.pattern[i].iter() {
+            match token {
+                Token::E => {
+                    if msg.len() < KEY_LEN {
+                        return Err(make_io_error("short noise message"));
+                    }
+                    self.re.copy_from_slice(&msg[..KEY_LEN]);
+                    let re = self.re;
+                    self.mix_hash(&re);
+                    msg = &msg[KEY_LEN..];
+                }
+                Token::S => {
+                    let len = KEY_LEN + if self.cipher.is_some() { TAG_LEN } else { 0 };
+                    if msg.len() < len {
+                        return Err(make_io_error("short noise message"));
+                    }
+                    let s = self.decrypt_and_hash(&msg[..len])?;
+                    let mut rs = [0u8; KEY_LEN];
+                    rs.copy_from_slice(&s[..]);
+                    self.rs = Some(rs);
+                    msg = &msg[len..];
+                }
+                t => self.dh(*t)?,
+            }
+        }
+        self.decrypt_and_hash(msg)?;
+        Ok(())
+    }
+
+    /// The ciphers of what this side sends and receives.
+    fn split(self) -> (CipherState, CipherState) {
+        let (mut k1, mut k2) = hkdf(&self.ck, &[]);
+        let (c1, c2) = (CipherState::new(&k1), CipherState::new(&k2));
+        zeroize(&mut k1);
+        zeroize(&mut k2);
+        if self.initiator {
+            (c1, c2)
+        } else {
+            (c2, c1)
+        }
+    }
+}
+
+/// The keys of a `noise` config.
+#[derive(Clone)]
+pub struct NoiseKeys {
+    xk: bool,
+    private: Key,
+    public: Key,
+    server: Option<Key>,
+    clients: Vec<Key>,
+}
+
+impl NoiseKeys {
+    /// The keys of a channel, which needs the server's, or of a listener,
+    /// which needs its clients'.
+    pub fn new(cfg: &NoiseConfig, listener: bool) -> io::Result<Self> {
+        let xk = match cfg.pattern.as_deref().unwrap_or("IK") {
+            "IK" | "ik" => false,
+            "XK" | "xk" => true,
+            p => return Err(make_io_error(&format!("unknown noise pattern:{}", p))),
+        };
+        let private = decode_key(cfg.private_key.as_str())?;
+        let server = match (&cfg.server_key, listener) {
+            (Some(k), false) => Some(decode_key(k.as_str())?),
+            (None, false) => return Err(make_io_error("noise channel requires 'server_key'")),
+            _ => None,
+        };
+        let clients = match (&cfg.client_keys, listener) {
+            (Some(keys), true) if !keys.is_empty() => keys
+                .iter()
+                .map(|k| decode_key(k.as_str()))
+                .collect::<io::Result<Vec<Key>>>()?,
+            (_, true) => return Err(make_io_error("noise listener requires 'client_keys'")),
+            _ => Vec::new(),
+        };
+        Ok(Self {
+            xk,
+            public: public_key(&private),
+            private,
+            server,
+            clients,
+        })
+    }
+}
+
+async fn handshake<R, W>(
+    keys: &NoiseKeys,
+    initiator: bool,
+    reader: R,
+    writer: W,
+) -> io::Result<(ObfsReader<R>, ObfsWriter<W>)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let codec = FrameCodec::new(LengthPrefix::U16, MAX_MESSAGE_LEN);
+    let mut reader = FrameReader::new(reader, codec);
+    let mut writer = FrameWriter::new(writer, codec);
+    let mut hs = Handshake::new(keys, initiator);
+    for i in 0..hs.pattern.len() {
+        if hs.writes(i) {
+            let msg = hs.write_message(i)?;
+            writer.write_frame(&msg[..]).await?;
+            continue;
+        }
+        let msg = match reader.read_frame().await? {
+            Some(msg) => msg,
+            None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        };
+        hs.read_message(i, &msg[..])?;
+        // an unknown client gets no answer
+        if let (false, Some(rs)) = (initiator, hs.rs) {
+            if !keys.clients.contains(&rs) {
+                return Err(make_io_error("noise client key not taken"));
+            }
+        }
+    }
+    let (send, recv) = hs.split();
+    // the peer may have sent its first transport message right behind the
+    // last handshake message
+    let (reader, rest) = reader.into_inner();
+    Ok((
+        ObfsReader::new(
+            reader,
+            Box::new(NoiseDecoder {
+                cipher: recv,
+                next_len: None,
+            }),
+            rest,
+        ),
+        ObfsWriter::new(writer.into_inner(), Box::new(NoiseEncoder { cipher: send })),
+    ))
+}
+
+/// The client side of a Noise handshake over `reader` and `writer`.
+pub async fn noise_connect<R, W>(
+    keys: &NoiseKeys,
+    reader: R,
+    writer: W,
+) -> io::Result<(ObfsReader<R>, ObfsWriter<W>)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    handshake(keys, true, reader, writer).await
+}
+
+/// The server side of `noise_connect`, taking only the listed clients.
+pub async fn noise_accept<R, W>(
+    keys: &NoiseKeys,
+    reader: R,
+    writer: W,
+) -> io::Result<(ObfsReader<R>, ObfsWriter<W>)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    handshake(keys, false, reader, writer).await
+}
+
+struct NoiseEncoder {
+    cipher: CipherState,
+}
+
+impl ObfsEncoder for NoiseEncoder {
+    fn encode(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        for chunk in data.chunks(MAX_PAYLOAD_LEN) {
+            let mut msg = chunk.to_vec();
+            self.cipher.encrypt(&[], &mut msg);
+            out.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+            out.extend_from_slice(&msg[..]);
+        }
+    }
+}
+
+struct NoiseDecoder {
+    cipher: CipherState,
+    next_len: Option<usize>,
+}
+
+impl ObfsDecoder for NoiseDecoder {
+    fn decode(&mut self, input: &mut BytesMut, out: &mut BytesMut) -> io::Result<()> {
+        loop {
+            let len = match self.next_len {
+                Some(len) => len,
+                None if input.len() >= 2 => {
+                    let len = u16::from_be_bytes([input[0], input[1]]) as usize;
+                    if !(TAG_LEN..=MAX_MESSAGE_LEN).contains(&len) {
+                        return Err(make_io_error("invalid noise message length"));
+                    }
+                    input.advance(2);
+                    len
+                }
+                None => return Ok(()),
+            };
+            if input.len() < len {
+                self.next_len = Some(len);
+                return Ok(());
+            }
+            self.next_len = None;
+            let mut msg = input.split_to(len).to_vec();
+            self.cipher.decrypt(&[], &mut msg)?;
+            out.extend_from_slice(&msg[..]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::agreement::{agree_ephemeral, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+
+    fn hex_key(s: &str) -> Key {
+        let mut k = [0u8; KEY_LEN];
+        for (i, b) in k.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+        }
+        k
+    }
+
+    #[test]
+    fn test_x25519() {
+        // RFC 7748 5.2 and 6.1
+        assert_eq!(
+            x25519(
+                &hex_key("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4"),
+                &hex_key("e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c")
+            ),
+            hex_key("c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552")
+        );
+        let alice = hex_key("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let bob = hex_key("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+        assert_eq!(
+            public_key(&alice),
+            hex_key("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a")
+        );
+        let shared = hex_key("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+        assert_eq!(x25519(&alice, &public_key(&bob)), shared);
+        assert_eq!(x25519(&bob, &public_key(&alice)), shared);
+    }
+
+    #[test]
+    fn test_x25519_ring() {
+        let rng = SystemRandom::new();
+        for _ in 0..32 {
+            let theirs = EphemeralPrivateKey::generate(&X25519, &rng).unwrap();
+            let mut their_public = [0u8; KEY_LEN];
+            their_public.copy_from_slice(theirs.compute_public_key().unwrap().as_ref());
+            let mine = random_key();
+            let peer = UnparsedPublicKey::new(&X25519, public_key(&mine));
+            let shared = agree_ephemeral(theirs, &peer, (), |s| Ok(s.to_vec())).unwrap();
+            assert_eq!(&shared[..], &x25519(&mine, &their_public)[..]);
+        }
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_noise_vectors() {
+        // from a python Noise over the X25519, ChaChaPoly and SHA256 of
+        // OpenSSL, with the keys of the cacophony vectors
+        let init_static =
+            hex_key("e61ef9919cde45dd5f82166404bd08e38bceb5dfdfded0a34c8df7ed542214d1");
+        let resp_static =
+            hex_key("4a3acbfdb163dec651dfa3194dece676d437029c62a408b4c5ea9114246e4893");
+        let init_ephemeral =
+            hex_key("893e28b9dc6ca8d611ab664754b8ceb7bac5117349a4439a6b0569da977c464a");
+        let resp_ephemeral =
+            hex_key("bbdb4cdbd309f1a1f2e1456967fe288cadd6f712d65dc7b7793d5e63da6b375b");
+        let vectors: &[(bool, &[&str], &str, &str, &str)] = &[
+            (
+                false,
+                &[
+                    "ca35def5ae56cec33dc2036731ab14896bc4c75dbb07a61f879f8e3afa4c7944718da798efbcd91528520204f904b9bd6c7413dccdc214d951e15253e39987f1b2115bd383c41c3c8aabf178a07f112a9f365f38ab33928c48ee73d78f0c59db",
+                    "95ebc60d2b1fa672c1f46a8aa265ef51bfe38e7ccb39ec5be34069f144808843558d05ff8c31a737a39ba0506a0fc812",
+                ],
+                "88228e666d4d4deff97b18cbe6c35e3929f7d013950fec6e7d8d3dba086e75fc",
+                "3349d11af74acb4606dbccc298bc5aac1e40135a",
+                "074c2fade14b125b249015cfb0f0160fe4816106",
+            ),
+            (
+                true,
+                &[
+                    "ca35def5ae56cec33dc2036731ab14896bc4c75dbb07a61f879f8e3afa4c7944e32413c39844ef71783d8cf15ac15c2c",
+                    "95ebc60d2b1fa672c1f46a8aa265ef51bfe38e7ccb39ec5be34069f1448088430b341b8949c0cf34584eca264d8ee3db",
+                    "5d8e67b9c1b8e36f5dc674bc5cd2ce243fb5d1710fa57de0370da7cc979015395db24344bf25fb16d4e06cc1c30c414398a908ea0f30dd0cfe64c1758f6ce1e1",
+                ],
+                "e5eac031db93143ad01e800aebce7b01901431ae4bf35aa255adc6eb9227782b",
+                "7d07c4134ca10bf82d13f4396dd6befc71cd64cb",
+                "010bfd2d0ce21a12f2bc3cc3b35a0d388c222f56",
+            ),
+        ];
+        for (xk, msgs, h, ping, pong) in vectors.iter() {
+            let client = NoiseKeys {
+                xk: *xk,
+                private: init_static,
+                public: public_key(&init_static),
+                server: Some(public_key(&resp_static)),
+                clients: Vec::new(),
+            };
+            let server = NoiseKeys {
+                xk: *xk,
+                private: resp_static,
+                public: public_key(&resp_static),
+                server: None,
+                clients: vec![client.public],
+            };
+            let mut initiator = Handshake::new(&client, true);
+            let mut responder = Handshake::new(&server, false);
+            for (i, msg) in msgs.iter().enumerate() {
+                let (from, to, e) = match initiator.writes(i) {
+                    true => (&mut initiator, &mut responder, init_ephemeral),
+                    false => (&mut responder, &mut initiator, resp_ephemeral),
+                };
+                let wire = from.write_message_with(i, e).unwrap();
+                assert_eq!(wire, hex(msg));
+                to.read_message(i, &wire[..]).unwrap();
+            }
+            assert_eq!(&initiator.h[..], &hex(h)[..]);
+            assert_eq!(&responder.h[..], &hex(h)[..]);
+            let (mut client_send, mut client_recv) = initiator.split();
+            let (mut server_send, mut server_recv) = responder.split();
+            let mut data = b"ping".to_vec();
+            client_send.encrypt(&[], &mut data);
+            assert_eq!(data, hex(ping));
+            server_recv.decrypt(&[], &mut data).unwrap();
+            assert_eq!(&data[..], b"ping");
+            let mut data = b"pong".to_vec();
+            server_send.encrypt(&[], &mut data);
+            assert_eq!(data, hex(pong));
+            client_recv.decrypt(&[], &mut data).unwrap();
+            assert_eq!(&data[..], b"pong");
+        }
+    }
+
+    fn keys(pattern: &str) -> (NoiseKeys, NoiseKeys, NoiseKeys) {
+        let (server_private, server_public) = noise_keypair();
+        let (client_private, client_public) = noise_keypair();
+        let server = NoiseKeys::new(
+            &NoiseConfig {
+                pattern: Some(String::from(pattern)),
+                private_key: server_private,
+                client_keys: Some(vec![client_public]),
+                ..Default::default()
+            },
+            true,
+        )
+        .unwrap();
+        let client = |private_key: String| {
+            NoiseKeys::new(
+                &NoiseConfig {
+                    pattern: Some(String::from(pattern)),
+                    private_key,
+                    server_key: Some(server_public.clone()),
+                    ..Default::default()
+                },
+                false,
+            )
+            .unwrap()
+        };
+        (server, client(client_private), client(noise_keypair().0))
+    }
+
+    fn handshake(server: &NoiseKeys, client: &NoiseKeys) -> io::Result<(Handshake, Handshake)> {
+        let mut initiator = Handshake::new(client, true);
+        let mut responder = Handshake::new(server, false);
+        for i in 0..initiator.pattern.len() {
+            let (from, to) = match initiator.writes(i) {
+                true => (&mut initiator, &mut responder),
+                false => (&mut responder, &mut initiator),
+            };
+            let msg = from.write_message(i)?;
+            to.read_message(i, &msg[..])?;
+        }
+        Ok((initiator, responder))
+    }
+
+    #[test]
+    fn test_noise() {
+        for pattern in ["IK", "XK"].iter() {
+            let (server, client, stranger) = keys(pattern);
+            let (initiator, responder) = handshake(&server, &client).unwrap();
+            assert_eq!(responder.rs, Some(client.public));
+            let (send, _) = initiator.split();
+            let (_, recv) = responder.split();
+            let mut encoder = NoiseEncoder { cipher: send };
+            let mut decoder = NoiseDecoder {
+                cipher: recv,
+                next_len: None,
+            };
+            let data = vec![3u8; 40000];
+            let mut wire = Vec::new();
+            encoder.encode(b"ping", &mut wire);
+            encoder.encode(&data[..], &mut wire);
+            let mut input = BytesMut::from(&wire[..10]);
+            let mut plain = BytesMut::new();
+            decoder.decode(&mut input, &mut plain).unwrap();
+            assert!(plain.is_empty());
+            input.extend_from_slice(&wire[10..]);
+            decoder.decode(&mut input, &mut plain).unwrap();
+            assert_eq!(&plain[..4], b"ping");
+            assert_eq!(plain.len(), 4 + data.len());
+
+            // the stranger gets through the handshake itself, but with a
+            // key the listener does not take
+            let (_, responder) = handshake(&server, &stranger).unwrap();
+            assert!(!server.clients.contains(&responder.rs.unwrap()));
+            let (other, _, _) = keys(pattern);
+            assert!(handshake(&other, &client).is_err());
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! SIP003 plugins. rsnova runs the plugin binary with the addresses of
+//! both its ends in the environment, SS_REMOTE_* the public one and
+//! SS_LOCAL_* the loopback one rsnova talks plain TCP to, and restarts it
+//! whenever it exits. A channel's plugin listens on its loopback port and
+//! dials the remote, a listener's plugin takes the public port and relays
+//! to the listener bound to loopback.
+use crate::config::{ChannelConfig, PluginConfig};
+use crate::utils::make_io_error;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::process::{Child, Command};
+use tokio::time::delay_for;
+
+const PLUGIN_READY_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    /// loopback address of the plugin of each channel and remote
+    static ref CHANNEL_PLUGINS: Mutex<HashMap<String, SocketAddr>> = Mutex::new(HashMap::new());
+}
+
+/// Splits `host:port`, dropping the brackets of an IPv6 host.
+fn split_host_port(addr: &str) -> Result<(&str, &str), std::io::Error> {
+    match addr.rfind(':') {
+        Some(i) if i + 1 < addr.len() => {
+            let host = addr[..i].trim_start_matches('[').trim_end_matches(']');
+            Ok((host, &addr[i + 1..]))
+        }
+        _ => Err(make_io_error(&format!(
+            "no port in plugin address {}",
+            addr
+        ))),
+    }
+}
+
+fn plugin_env(
+    remote: &str,
+    local: SocketAddr,
+    opts: &str,
+) -> Result<Vec<(&'static str, String)>, std::io::Error> {
+    let (host, port) = split_host_port(remote)?;
+    Ok(vec![
+        ("SS_REMOTE_HOST", String::from(host)),
+        ("SS_REMOTE_PORT", String::from(port)),
+        ("SS_LOCAL_HOST", local.ip().to_string()),
+        ("SS_LOCAL_PORT", local.port().to_string()),
+        ("SS_PLUGIN_OPTIONS", String::from(opts)),
+    ])
+}
+
+fn spawn_plugin(
+    cfg: &PluginConfig,
+    remote: &str,
+    local: SocketAddr,
+) -> Result<Child, std::io::Error> {
+    let env = plugin_env(remote, local, cfg.opts.as_deref().unwrap_or(""))?;
+    Command::new(cfg.command.as_str())
+        .args(cfg.args.iter().flatten())
+        .envs(env)
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+}
+
+/// Waits for `child` to exit and starts it again, backing off while it
+/// keeps exiting right away.
+async fn supervise(mut child: Child, cfg: PluginConfig, remote: String, local: SocketAddr) {
+    let mut delay = MIN_RESTART_DELAY;
+    loop {
+        let started = Instant::now();
+        match (&mut child).await {
+            Ok(status) => warn!("Plugin {} exited with {}", cfg.command, status),
+            Err(e) => warn!("Plugin {} failed:{}", cfg.command, e),
+        }
+        if started.elapsed() > MAX_RESTART_DELAY {
+            delay = MIN_RESTART_DELAY;
+        }
+        loop {
+            delay_for(delay).await;
+            delay = (delay * 2).min(MAX_RESTART_DELAY);
+            match spawn_plugin(&cfg, remote.as_str(), local) {
+                Ok(c) => {
+                    info!("Plugin {} restarted", cfg.command);
+                    child = c;
+                    break;
+                }
+                Err(e) => error!("Failed to restart plugin {}:{}", cfg.command, e),
+            }
+        }
+    }
+}
+
+/// A free port on loopback, for the plugin's end rsnova does not listen on.
+fn free_loopback_addr() -> Result<SocketAddr, std::io::Error> {
+    std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()
+}
+
+/// Waits for the plugin to listen on `addr`. It is not connected to, the
+/// plugin would dial the remote for nothing; `addr` is taken once binding
+/// it fails.
+async fn wait_ready(addr: SocketAddr) -> Result<(), std::io::Error> {
+    let start = Instant::now();
+    loop {
+        if std::net::TcpListener::bind(addr).is_err() {
+            return Ok(());
+        }
+        if start.elapsed() > PLUGIN_READY_TIMEOUT {
+            return Err(make_io_error("plugin not listening"));
+        }
+        delay_for(Duration::from_millis(100)).await;
+    }
+}
+
+/// The address a channel's connection to `remote` dials: `remote` itself,
+/// or the loopback end of the channel's plugin, started on first use.
+pub async fn plugin_addr(config: &ChannelConfig, remote: String) -> Result<String, std::io::Error> {
+    let plugin = match config.plugin.as_ref() {
+        Some(p) => p,
+        None => return Ok(remote),
+    };
+    let key = format!("{}/{}", config.name, remote);
+    let local = {
+        let mut plugins = CHANNEL_PLUGINS.lock().unwrap();
+        match plugins.get(&key) {
+            Some(local) => *local,
+            None => {
+                let local = free_loopback_addr()?;
+                let child = spawn_plugin(plugin, remote.as_str(), local)?;
+                info!(
+                    "[{}]Plugin {} for {} on {}",
+                    config.name, plugin.command, remote, local
+                );
+                tokio::spawn(supervise(child, plugin.clone(), remote.clone(), local));
+                plugins.insert(key, local);
+                local
+            }
+        }
+    };
+    wait_ready(local).await?;
+    Ok(local.to_string())
+}
+
+/// Binds the listener of `listen` to loopback and starts the plugin
+/// taking the connections of `listen` for it.
+pub async fn plugin_listen(
+    plugin: &PluginConfig,
+    listen: &str,
+) -> Result<TcpListener, std::io::Error> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let local = listener.local_addr()?;
+    let child = spawn_plugin(plugin, listen, local)?;
+    info!("Plugin {} on {} for {}", plugin.command, listen, local);
+    tokio::spawn(supervise(
+        child,
+        plugin.clone(),
+        String::from(listen),
+        local,
+    ));
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plugin_env() {
+        let local: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let env = plugin_env("[2001:db8::1]:8388", local, "obfs=http").unwrap();
+        assert_eq!(env[0], ("SS_REMOTE_HOST", String::from("2001:db8::1")));
+        assert_eq!(env[1], ("SS_REMOTE_PORT", String::from("8388")));
+        assert_eq!(env[2], ("SS_LOCAL_HOST", String::from("127.0.0.1")));
+        assert_eq!(env[3], ("SS_LOCAL_PORT", String::from("40000")));
+        assert_eq!(env[4], ("SS_PLUGIN_OPTIONS", String::from("obfs=http")));
+        assert!(plugin_env("example.com", local, "").is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use crate::utils::make_io_error;
+use quinn::{
+    CertificateChain, ClientConfig, ClientConfigBuilder, Connection, Endpoint, Incoming,
+    NewConnection, PrivateKey, RecvStream, SendStream, ServerConfigBuilder,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+const QUIC_ALPN: &[&[u8]] = &[b"rsnova"];
+
+lazy_static! {
+    // shared so that session tickets survive reconnects and 0-RTT can be used
+    static ref QUIC_CLIENT_CONFIGS: Mutex<HashMap<&'static [u8], ClientConfig>> =
+        Mutex::new(HashMap::new());
+}
+
+fn client_config(alpn: &'static [&'static [u8]]) -> ClientConfig {
+    let mut cfgs = QUIC_CLIENT_CONFIGS.lock().unwrap();
+    cfgs.entry(alpn[0])
+        .or_insert_with(|| {
+            let mut builder = ClientConfigBuilder::default();
+            builder.protocols(alpn);
+            builder.build()
+        })
+        .clone()
+}
+
+/// One bidirectional QUIC stream used as an rmux carrier. The connection and
+/// endpoint are kept alongside so they live as long as the stream.
+pub struct QuicStream {
+    pub send: SendStream,
+    pub recv: RecvStream,
+    pub conn: Connection,
+    _endpoint: Option<Endpoint>,
+}
+
+async fn resolve(addr: &str) -> Result<SocketAddr, std::io::Error> {
+    match tokio::net::lookup_host(addr).await?.next() {
+        Some(a) => Ok(a),
+        None => Err(make_io_error("no address resolved")),
+    }
+}
+
+/// Dials a QUIC connection speaking `alpn`; the endpoint has to be kept
+/// for as long as the connection is used.
+pub async fn quic_connection(
+    addr: &str,
+    server_name: &str,
+    zero_rtt: bool,
+    alpn: &'static [&'static [u8]],
+) -> Result<(Endpoint, NewConnection), std::io::Error> {
+    let remote = resolve(addr).await?;
+    let local: SocketAddr = if remote.is_ipv6() {
+        "[::]:0".parse().unwrap()
+    } else {
+        "0.0.0.0:0".parse().unwrap()
+    };
+    let mut builder = Endpoint::builder();
+    builder.default_client_config(client_config(alpn));
+    let (endpoint, _) = match builder.bind(&local) {
+        Ok(v) => v,
+        Err(e) => return Err(make_io_error(&e.to_string())),
+    };
+    let connecting = match endpoint.connect(&remote, server_name) {
+        Ok(c) => c,
+        Err(e) => return Err(make_io_error(&e.to_string())),
+    };
+    let connecting = if zero_rtt {
+        match connecting.into_0rtt() {
+            Ok((conn, _)) => {
+                info!("QUIC 0-RTT connect {}", addr);
+                Ok(conn)
+            }
+            Err(c) => Err(c),
+        }
+    } else {
+        Err(connecting)
+    };
+    match connecting {
+        Ok(conn) => Ok((endpoint, conn)),
+        Err(c) => match c.await {
+            Ok(conn) => Ok((endpoint, conn)),
+            Err(e) => Err(make_io_error(&e.to_string())),
+        },
+    }
+}
+
+pub async fn quic_connect(
+    addr: &str,
+    server_name: &str,
+    zero_rtt: bool,
+) -> Result<QuicStream, std::io::Error> {
+    let (endpoint, NewConnection { connection, .. }) =
+        quic_connection(addr, server_name, zero_rtt, QUIC_ALPN).await?;
+    let (send, recv) = match connection.open_bi().await {
+        Ok(s) => s,
+        Err(e) => return Err(make_io_error(&e.to_string())),
+    };
+    Ok(QuicStream {
+        send,
+        recv,
+        conn: connection,
+        _endpoint: Some(endpoint),
+    })
+}
+
+/// Binds a QUIC server endpoint with the PEM encoded cert chain and key.
+pub async fn quic_listen(
+    addr: &str,
+    cert_path: &str,
+    key_path: &str,
+) -> Result<(Endpoint, Incoming), std::io::Error> {
+    quic_listen_alpn(addr, cert_path, key_path, QUIC_ALPN).await
+}
+
+pub async fn quic_listen_alpn(
+    addr: &str,
+    cert_path: &str,
+    key_path: &str,
+    alpn: &[&[u8]],
+) -> Result<(Endpoint, Incoming), std::io::Error> {
+    let local = resolve(addr).await?;
+    let cert = std::fs::read(cert_path)?;
+    let key = std::fs::read(key_path)?;
+    let cert = match CertificateChain::from_pem(&cert) {
+        Ok(c) => c,
+        Err(e) => return Err(make_io_error(&e.to_string())),
+    };
+    let key = match PrivateKey::from_pem(&key) {
+        Ok(k) => k,
+        Err(e) => return Err(make_io_error(&e.to_string())),
+    };
+    let mut server_config = ServerConfigBuilder::default();
+    server_config.protocols(alpn);
+    if let Err(e) = server_config.certificate(cert, key) {
+        return Err(make_io_error(&e.to_string()));
+    }
+    let mut builder = Endpoint::builder();
+    builder.listen(server_config.build());
+    match builder.bind(&local) {
+        Ok(v) => Ok(v),
+        Err(e) => Err(make_io_error(&e.to_string())),
+    }
+}
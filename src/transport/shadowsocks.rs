@@ -0,0 +1,462 @@
+use crate::utils::make_invalid_data_error;
+use bytes::{Buf, BufMut, BytesMut};
+use ring::aead::{Aad, Algorithm, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
+use ring::aead::{AES_128_GCM, AES_256_GCM, CHACHA20_POLY1305};
+use ring::hkdf;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+pub const SS_AES_128_GCM: &str = "aes-128-gcm";
+pub const SS_AES_256_GCM: &str = "aes-256-gcm";
+pub const SS_CHACHA20_POLY1305: &str = "chacha20-ietf-poly1305";
+
+const SS_SUBKEY_INFO: &[u8] = b"ss-subkey";
+const SS_TAG_LEN: usize = 16;
+// payload length of one chunk is kept below 0x3FFF by the spec
+const SS_MAX_PAYLOAD: usize = 0x3FFF;
+
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// OpenSSL's `EVP_BytesToKey` with md5 and no salt, how shadowsocks turns a
+/// password into the master key.
+fn evp_bytes_to_key(password: &[u8], key_len: usize) -> Vec<u8> {
+    let mut key = Vec::with_capacity(key_len + 16);
+    let mut last: Vec<u8> = Vec::new();
+    while key.len() < key_len {
+        let mut data = last.clone();
+        data.extend_from_slice(password);
+        last = md5::compute(&data).0.to_vec();
+        key.extend_from_slice(&last[..]);
+    }
+    key.truncate(key_len);
+    key
+}
+
+/// Method and master key of a shadowsocks AEAD endpoint.
+#[derive(Clone)]
+pub struct ShadowsocksCipher {
+    algorithm: &'static Algorithm,
+    key: Vec<u8>,
+}
+
+impl ShadowsocksCipher {
+    pub fn new(method: &str, password: &str) -> io::Result<Self> {
+        let algorithm = match method {
+            SS_AES_128_GCM => &AES_128_GCM,
+            SS_AES_256_GCM => &AES_256_GCM,
+            SS_CHACHA20_POLY1305 => &CHACHA20_POLY1305,
+            _ => return Err(make_invalid_data_error("unsupported shadowsocks method")),
+        };
+        Ok(Self {
+            algorithm,
+            key: evp_bytes_to_key(password.as_bytes(), algorithm.key_len()),
+        })
+    }
+
+    /// Salt length, the same as the key length for every AEAD method.
+    pub fn salt_len(&self) -> usize {
+        self.key.len()
+    }
+
+    fn session_key(&self, salt: &[u8]) -> io::Result<AeadSession> {
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA1_FOR_LEGACY_USE_ONLY, salt).extract(&self.key);
+        let okm = match prk.expand(&[SS_SUBKEY_INFO], self.algorithm) {
+            Ok(okm) => okm,
+            Err(_) => {
+                return Err(make_invalid_data_error(
+                    "failed to derive shadowsocks subkey",
+                ))
+            }
+        };
+        Ok(AeadSession {
+            key: LessSafeKey::new(UnboundKey::from(okm)),
+            nonce: [0u8; NONCE_LEN],
+        })
+    }
+
+    pub fn encoder(&self) -> io::Result<ShadowsocksEncoder> {
+        let salt: Vec<u8> = (0..self.salt_len()).map(|_| rand::random::<u8>()).collect();
+        Ok(ShadowsocksEncoder {
+            session: self.session_key(&salt[..])?,
+            salt: Some(salt),
+        })
+    }
+
+    pub fn decoder(&self) -> ShadowsocksDecoder {
+        ShadowsocksDecoder {
+            cipher: self.clone(),
+            session: None,
+            payload_len: None,
+        }
+    }
+}
+
+/// Subkey of one direction with its little endian nonce counter.
+struct AeadSession {
+    key: LessSafeKey,
+    nonce: [u8; NONCE_LEN],
+}
+
+impl AeadSession {
+    fn next_nonce(&mut self) -> Nonce {
+        let nonce = Nonce::assume_unique_for_key(self.nonce);
+        for b in self.nonce.iter_mut() {
+            *b = b.wrapping_add(1);
+            if *b != 0 {
+                break;
+            }
+        }
+        nonce
+    }
+    fn seal(&mut self, data: &[u8], out: &mut BytesMut) -> io::Result<()> {
+        let mut sealed = Vec::with_capacity(data.len() + SS_TAG_LEN);
+        sealed.extend_from_slice(data);
+        let nonce = self.next_nonce();
+        if self
+            .key
+            .seal_in_place_append_tag(nonce, Aad::empty(), &mut sealed)
+            .is_err()
+        {
+            return Err(make_invalid_data_error("shadowsocks encrypt failed"));
+        }
+        out.put_slice(&sealed[..]);
+        Ok(())
+    }
+    fn open(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        let nonce = self.next_nonce();
+        match self.key.open_in_place(nonce, Aad::empty(), data) {
+            Ok(plain) => Ok(plain.len()),
+            Err(_) => Err(make_invalid_data_error("shadowsocks decrypt failed")),
+        }
+    }
+}
+
+/// Sender side of a shadowsocks AEAD stream: the salt, then chunks of
+/// `[len][len tag][payload][payload tag]`.
+pub struct ShadowsocksEncoder {
+    session: AeadSession,
+    salt: Option<Vec<u8>>,
+}
+
+impl ShadowsocksEncoder {
+    pub fn encode(&mut self, mut data: &[u8], out: &mut BytesMut) -> io::Result<()> {
+        if let Some(salt) = self.salt.take() {
+            out.put_slice(&salt[..]);
+        }
+        while !data.is_empty() {
+            let n = std::cmp::min(data.len(), SS_MAX_PAYLOAD);
+            out.reserve(2 + n + 2 * SS_TAG_LEN);
+            self.session.seal(&(n as u16).to_be_bytes(), out)?;
+            self.session.seal(&data[..n], out)?;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+}
+
+/// Receiver side of a shadowsocks AEAD stream.
+pub struct ShadowsocksDecoder {
+    cipher: ShadowsocksCipher,
+    session: Option<AeadSession>,
+    payload_len: Option<usize>,
+}
+
+impl ShadowsocksDecoder {
+    /// Decrypts the next chunk off the front of `buf`, `None` if more bytes
+    /// are needed.
+    pub fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        if self.session.is_none() {
+            let salt_len = self.cipher.salt_len();
+            if buf.len() < salt_len {
+                return Ok(None);
+            }
+            let salt = buf.split_to(salt_len);
+            self.session = Some(self.cipher.session_key(&salt[..])?);
+        }
+        let session = self.session.as_mut().unwrap();
+        let payload_len = match self.payload_len {
+            Some(n) => n,
+            None => {
+                if buf.len() < 2 + SS_TAG_LEN {
+                    return Ok(None);
+                }
+                let mut len_buf = buf.split_to(2 + SS_TAG_LEN);
+                session.open(&mut len_buf[..])?;
+                let n = (((len_buf[0] as usize) << 8) | len_buf[1] as usize) & SS_MAX_PAYLOAD;
+                self.payload_len = Some(n);
+                n
+            }
+        };
+        if buf.len() < payload_len + SS_TAG_LEN {
+            return Ok(None);
+        }
+        let mut payload = buf.split_to(payload_len + SS_TAG_LEN);
+        let n = session.open(&mut payload[..])?;
+        payload.truncate(n);
+        self.payload_len = None;
+        Ok(Some(payload))
+    }
+}
+
+/// Decrypts a shadowsocks AEAD stream.
+pub struct ShadowsocksReader<R> {
+    inner: R,
+    decoder: ShadowsocksDecoder,
+    raw: BytesMut,
+    data: BytesMut,
+}
+
+impl<R> ShadowsocksReader<R> {
+    pub fn new(inner: R, cipher: &ShadowsocksCipher) -> Self {
+        Self {
+            inner,
+            decoder: cipher.decoder(),
+            raw: BytesMut::new(),
+            data: BytesMut::new(),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ShadowsocksReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            if !self.data.is_empty() {
+                let n = std::cmp::min(buf.len(), self.data.len());
+                buf[..n].copy_from_slice(&self.data[..n]);
+                self.data.advance(n);
+                return Poll::Ready(Ok(n));
+            }
+            let Self {
+                inner,
+                decoder,
+                raw,
+                data,
+            } = &mut *self;
+            if let Some(chunk) = decoder.decode(raw)? {
+                *data = chunk;
+                continue;
+            }
+            raw.reserve(4096);
+            let n = ready!(Pin::new(inner).poll_read_buf(cx, raw))?;
+            if n == 0 {
+                if self.raw.is_empty() {
+                    return Poll::Ready(Ok(0));
+                }
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::UnexpectedEof)));
+            }
+        }
+    }
+}
+
+/// Encrypts every write into shadowsocks chunks. Like `GrpcWriter`, a write
+/// only completes once its chunks are handed to the inner writer.
+pub struct ShadowsocksWriter<W> {
+    inner: W,
+    encoder: ShadowsocksEncoder,
+    pending: BytesMut,
+    pending_len: usize,
+}
+
+impl<W> ShadowsocksWriter<W> {
+    pub fn new(inner: W, cipher: &ShadowsocksCipher) -> io::Result<Self> {
+        Ok(Self {
+            inner,
+            encoder: cipher.encoder()?,
+            pending: BytesMut::new(),
+            pending_len: 0,
+        })
+    }
+}
+
+impl<W: AsyncWrite + Unpin> ShadowsocksWriter<W> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero)));
+            }
+            self.pending.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ShadowsocksWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.pending.is_empty() {
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            let Self {
+                encoder, pending, ..
+            } = &mut *self;
+            encoder.encode(buf, pending)?;
+            self.pending_len = buf.len();
+        }
+        ready!(self.poll_drain(cx))?;
+        Poll::Ready(Ok(self.pending_len))
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// SOCKS5 style target address leading the first chunk, `host:port` in.
+pub fn encode_ss_addr(target: &str, out: &mut BytesMut) -> io::Result<()> {
+    let pos = match target.rfind(':') {
+        Some(p) => p,
+        None => return Err(make_invalid_data_error("target without port")),
+    };
+    let port: u16 = match target[pos + 1..].parse() {
+        Ok(p) => p,
+        Err(_) => return Err(make_invalid_data_error("invalid target port")),
+    };
+    let host = target[..pos].trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            out.put_u8(ATYP_IPV4);
+            out.put_slice(&ip.octets()[..]);
+        }
+        Ok(IpAddr::V6(ip)) => {
+            out.put_u8(ATYP_IPV6);
+            out.put_slice(&ip.octets()[..]);
+        }
+        Err(_) => {
+            if host.is_empty() || host.len() > 255 {
+                return Err(make_invalid_data_error("invalid target host"));
+            }
+            out.put_u8(ATYP_DOMAIN);
+            out.put_u8(host.len() as u8);
+            out.put_slice(host.as_bytes());
+        }
+    }
+    out.put_u16(port);
+    Ok(())
+}
+
+/// Parses the address written by `encode_ss_addr`, returning `host:port`
+/// and the bytes it took, or `None` if `buf` is still too short.
+pub fn parse_ss_addr(buf: &[u8]) -> io::Result<Option<(String, usize)>> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    let (host, n) = match buf[0] {
+        ATYP_IPV4 => {
+            if buf.len() < 1 + 4 + 2 {
+                return Ok(None);
+            }
+            let ip = Ipv4Addr::new(buf[1], buf[2], buf[3], buf[4]);
+            (ip.to_string(), 1 + 4)
+        }
+        ATYP_IPV6 => {
+            if buf.len() < 1 + 16 + 2 {
+                return Ok(None);
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&buf[1..17]);
+            (format!("[{}]", Ipv6Addr::from(octets)), 1 + 16)
+        }
+        ATYP_DOMAIN => {
+            if buf.len() < 2 || buf.len() < 2 + buf[1] as usize + 2 {
+                return Ok(None);
+            }
+            let len = buf[1] as usize;
+            match std::str::from_utf8(&buf[2..2 + len]) {
+                Ok(h) => (String::from(h), 2 + len),
+                Err(_) => return Err(make_invalid_data_error("invalid target host")),
+            }
+        }
+        _ => return Err(make_invalid_data_error("unknown address type")),
+    };
+    let port = ((buf[n] as u16) << 8) | buf[n + 1] as u16;
+    Ok(Some((format!("{}:{}", host, port), n + 2)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evp_bytes_to_key() {
+        // the first block is md5 of the password itself
+        let key = evp_bytes_to_key(b"foobar", 32);
+        assert_eq!(&key[..16], &md5::compute(b"foobar").0[..]);
+        let mut second = key[..16].to_vec();
+        second.extend_from_slice(b"foobar");
+        assert_eq!(&key[16..], &md5::compute(&second).0[..]);
+    }
+
+    #[test]
+    fn test_chunk_roundtrip() {
+        for method in &[SS_AES_128_GCM, SS_AES_256_GCM, SS_CHACHA20_POLY1305] {
+            let cipher = ShadowsocksCipher::new(method, "secret").unwrap();
+            let mut encoder = cipher.encoder().unwrap();
+            let mut wire = BytesMut::new();
+            let big = vec![7u8; SS_MAX_PAYLOAD + 10];
+            encoder.encode(b"hello", &mut wire).unwrap();
+            encoder.encode(&big[..], &mut wire).unwrap();
+            assert_eq!(
+                wire.len(),
+                cipher.salt_len() + 3 * (2 + 2 * SS_TAG_LEN) + 5 + big.len()
+            );
+
+            let mut decoder = cipher.decoder();
+            let mut partial = wire.split_to(10);
+            assert!(decoder.decode(&mut partial).unwrap().is_none());
+            partial.unsplit(wire);
+            let mut wire = partial;
+            assert_eq!(&decoder.decode(&mut wire).unwrap().unwrap()[..], b"hello");
+            let mut rest = decoder.decode(&mut wire).unwrap().unwrap();
+            rest.unsplit(decoder.decode(&mut wire).unwrap().unwrap());
+            assert_eq!(&rest[..], &big[..]);
+            assert!(wire.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        let cipher = ShadowsocksCipher::new(SS_AES_256_GCM, "secret").unwrap();
+        let mut wire = BytesMut::new();
+        cipher
+            .encoder()
+            .unwrap()
+            .encode(b"hello", &mut wire)
+            .unwrap();
+        let other = ShadowsocksCipher::new(SS_AES_256_GCM, "guess").unwrap();
+        assert!(other.decoder().decode(&mut wire).is_err());
+        assert!(ShadowsocksCipher::new("rc4-md5", "secret").is_err());
+    }
+
+    #[test]
+    fn test_ss_addr() {
+        for target in &["1.2.3.4:80", "[::1]:443", "example.com:8080"] {
+            let mut buf = BytesMut::new();
+            encode_ss_addr(target, &mut buf).unwrap();
+            let len = buf.len();
+            assert!(parse_ss_addr(&buf[..len - 1]).unwrap().is_none());
+            buf.put_slice(b"payload");
+            let (addr, n) = parse_ss_addr(&buf[..]).unwrap().unwrap();
+            assert_eq!(addr.as_str(), *target);
+            assert_eq!(n, len);
+        }
+        assert!(parse_ss_addr(&[9, 0, 0]).is_err());
+    }
+}
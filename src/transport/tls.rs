@@ -0,0 +1,570 @@
+use super::acme::acme_resolver;
+use crate::config::{ChannelConfig, TlsConfig, TunnelConfig};
+use crate::utils::{make_io_error, AsyncFuturesIO, AsyncTcpStream, AsyncTokioIO};
+use async_tls::{TlsAcceptor, TlsConnector};
+use ring::digest::{digest, SHA256};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientCertVerified, ClientCertVerifier, ClientConfig,
+    ClientSessionMemoryCache, DistinguishedNames, NoClientAuth, NoServerSessionStorage, PrivateKey,
+    RootCertStore, ServerCertVerified, ServerCertVerifier, ServerConfig, TLSError,
+};
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use x509_parser::extensions::GeneralName;
+use x509_parser::parse_x509_certificate;
+
+lazy_static! {
+    /// Session tickets per channel, they outlive the connections so that a
+    /// reconnect can resume.
+    static ref SESSION_CACHES: Mutex<HashMap<String, Arc<ClientSessionMemoryCache>>> =
+        Mutex::new(HashMap::new());
+}
+
+fn session_cache(channel: &str) -> Arc<ClientSessionMemoryCache> {
+    SESSION_CACHES
+        .lock()
+        .unwrap()
+        .entry(String::from(channel))
+        .or_insert_with(|| ClientSessionMemoryCache::new(32))
+        .clone()
+}
+
+pub type TlsClientStream = AsyncTokioIO<async_tls::client::TlsStream<AsyncTcpStream>>;
+pub type TlsServerStream = AsyncTokioIO<async_tls::server::TlsStream<AsyncTcpStream>>;
+
+pub fn new_client_config(alpn: &[&str]) -> ClientConfig {
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    config.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+    config
+}
+
+/// Builds a client config from the channel's `tls` section, falling back to
+/// `default_alpn` when no ALPN list is configured.
+pub fn client_config(
+    tls: Option<&TlsConfig>,
+    default_alpn: &[&str],
+) -> Result<ClientConfig, std::io::Error> {
+    let tls = match tls {
+        Some(t) => t,
+        None => return Ok(new_client_config(default_alpn)),
+    };
+    let mut config = ClientConfig::new();
+    if !tls.custom_roots_only.unwrap_or(false) {
+        config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    }
+    if let Some(ca_file) = &tls.ca_file {
+        let mut reader = BufReader::new(std::fs::File::open(ca_file)?);
+        match config.root_store.add_pem_file(&mut reader) {
+            Ok((valid, _)) if valid > 0 => {}
+            _ => return Err(make_io_error("no valid certificate in ca_file")),
+        }
+    }
+    config.alpn_protocols = match &tls.alpn {
+        Some(alpn) => alpn.iter().map(|p| p.as_bytes().to_vec()).collect(),
+        None => default_alpn.iter().map(|p| p.as_bytes().to_vec()).collect(),
+    };
+    if let Some(pins) = &tls.pin_sha256 {
+        let verifier = PinnedCertVerifier::new(&pins[..])?;
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(verifier));
+    }
+    if let (Some(cert), Some(key)) = (&tls.cert, &tls.key) {
+        let (chain, key) = load_cert_and_key(cert.as_str(), key.as_str())?;
+        config.set_single_client_cert(chain, key);
+    }
+    Ok(config)
+}
+
+/// what rustls verifies chains with
+static PIN_SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// Accepts a server by SHA-256 digests of certificates or of their public
+/// keys. A pinned leaf says more than a CA could, so neither its chain, its
+/// validity nor its name are checked. A pinned CA or intermediate only
+/// counts as the trust anchor of a chain webpki verified for the name, as
+/// anyone can append a public intermediate to their own chain.
+struct PinnedCertVerifier {
+    pins: Vec<Vec<u8>>,
+}
+
+impl PinnedCertVerifier {
+    fn new(pins: &[String]) -> Result<Self, std::io::Error> {
+        let mut digests = Vec::new();
+        for pin in pins.iter() {
+            match base64::decode(pin.trim()) {
+                Ok(d) if d.len() == SHA256.output_len => digests.push(d),
+                _ => return Err(make_io_error(&format!("invalid pin_sha256:{}", pin))),
+            }
+        }
+        if digests.is_empty() {
+            return Err(make_io_error("empty pin_sha256"));
+        }
+        Ok(Self { pins: digests })
+    }
+
+    fn is_pinned(&self, data: &[u8]) -> bool {
+        let d = digest(&SHA256, data);
+        self.pins.iter().any(|p| p[..] == *d.as_ref())
+    }
+
+    /// Whether `cert` or its public key is pinned.
+    fn is_pinned_cert(&self, cert: &[u8]) -> bool {
+        self.is_pinned(cert) || cert_spki(cert).map_or(false, |spki| self.is_pinned(spki))
+    }
+}
+
+/// Appends a DER length.
+fn der_push_len(out: &mut Vec<u8>, len: usize) {
+    if len < 0x80 {
+        out.push(len as u8);
+        return;
+    }
+    let bytes = len.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    out.push(0x80 | (bytes.len() - skip) as u8);
+    out.extend_from_slice(&bytes[skip..]);
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let leaf = match presented_certs.first() {
+            Some(c) => c,
+            None => return Err(TLSError::NoCertificatesPresented),
+        };
+        if self.is_pinned_cert(&leaf.0[..]) {
+            return Ok(ServerCertVerified::assertion());
+        }
+        // a pinned certificate further up only counts as the anchor of a
+        // chain webpki verifies for the name, like one of a pinned root
+        let mut anchors = Vec::new();
+        for cert in presented_certs[1..].iter() {
+            if self.is_pinned_cert(&cert.0[..]) {
+                if let Ok(anchor) = webpki::trust_anchor_util::cert_der_as_trust_anchor(&cert.0[..])
+                {
+                    anchors.push(anchor);
+                }
+            }
+        }
+        for root in roots.roots.iter() {
+            let anchor = root.to_trust_anchor();
+            // trust anchors keep the content of the SubjectPublicKeyInfo only
+            let mut spki = vec![0x30];
+            der_push_len(&mut spki, anchor.spki.len());
+            spki.extend_from_slice(anchor.spki);
+            if self.is_pinned(&spki[..]) {
+                anchors.push(anchor);
+            }
+        }
+        if anchors.is_empty() {
+            return Err(TLSError::General(String::from(
+                "server certificate matches no pin_sha256",
+            )));
+        }
+        let chain: Vec<&[u8]> = presented_certs[1..].iter().map(|c| &c.0[..]).collect();
+        let now = webpki::Time::try_from(std::time::SystemTime::now())
+            .map_err(|_| TLSError::FailedToGetCurrentTime)?;
+        let cert = webpki::EndEntityCert::from(&leaf.0[..]).map_err(TLSError::WebPKIError)?;
+        cert.verify_is_valid_tls_server_cert(
+            PIN_SIG_ALGS,
+            &webpki::TLSServerTrustAnchors(&anchors[..]),
+            &chain[..],
+            now,
+        )
+        .map_err(TLSError::WebPKIError)?;
+        cert.verify_is_valid_for_dns_name(dns_name)
+            .map_err(TLSError::WebPKIError)?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Unix time an X.509 certificate expires at.
+pub(super) fn cert_not_after(cert: &[u8]) -> Option<i64> {
+    let (_, cert) = parse_x509_certificate(cert).ok()?;
+    Some(cert.validity().not_after.timestamp())
+}
+
+/// The subject CNs and the DNS and email SANs of an X.509 certificate, what
+/// client certificates are mapped to users by.
+fn cert_names(cert: &[u8]) -> Vec<String> {
+    let mut names = Vec::new();
+    let cert = match parse_x509_certificate(cert) {
+        Ok((_, cert)) => cert,
+        Err(_) => return names,
+    };
+    for cn in cert.subject().iter_common_name() {
+        if let Ok(cn) = cn.as_str() {
+            names.push(String::from(cn));
+        }
+    }
+    if let Some((_, san)) = cert.tbs_certificate.subject_alternative_name() {
+        for name in san.general_names.iter() {
+            match name {
+                GeneralName::RFC822Name(n) | GeneralName::DNSName(n) => {
+                    names.push(String::from(*n))
+                }
+                _ => {}
+            }
+        }
+    }
+    names
+}
+
+/// The DER SubjectPublicKeyInfo of an X.509 certificate, what HPKP style
+/// pins are the digest of.
+fn cert_spki(cert: &[u8]) -> Option<&[u8]> {
+    let (_, cert) = parse_x509_certificate(cert).ok()?;
+    Some(cert.tbs_certificate.subject_pki.raw)
+}
+
+/// Client config for a channel: `client_config` plus the channel's session
+/// cache, and TLS 1.3 early data when `zero_rtt` is on.
+pub fn channel_client_config(
+    config: &ChannelConfig,
+    default_alpn: &[&str],
+) -> Result<Arc<ClientConfig>, std::io::Error> {
+    let mut tls_cfg = client_config(config.tls.as_ref(), default_alpn)?;
+    let resumption = match &config.tls {
+        Some(t) => t.session_resumption.unwrap_or(true),
+        None => true,
+    };
+    if resumption {
+        tls_cfg.session_persistence = session_cache(config.name.as_str());
+        tls_cfg.enable_early_data = config.zero_rtt();
+    } else {
+        tls_cfg.enable_tickets = false;
+    }
+    Ok(Arc::new(tls_cfg))
+}
+
+/// Early data is only sent when resuming a session whose server allowed it,
+/// otherwise the first writes simply wait for the handshake.
+pub async fn tls_connect(
+    conn: TcpStream,
+    domain: &str,
+    config: Arc<ClientConfig>,
+) -> Result<TlsClientStream, std::io::Error> {
+    let early_data = config.enable_early_data;
+    let connector = TlsConnector::from(config).early_data(early_data);
+    let tls_stream = connector
+        .connect(domain, AsyncTcpStream::new(conn))?
+        .await?;
+    Ok(AsyncTokioIO::new(tls_stream))
+}
+
+/// `tls_connect` over the halves of an already open stream, like a channel
+/// stream.
+pub async fn tls_connect_io<R, W>(
+    reader: R,
+    writer: W,
+    domain: &str,
+    config: Arc<ClientConfig>,
+) -> Result<AsyncTokioIO<async_tls::client::TlsStream<AsyncFuturesIO<R, W>>>, std::io::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let connector = TlsConnector::from(config);
+    let tls_stream = connector
+        .connect(domain, AsyncFuturesIO::new(reader, writer))?
+        .await?;
+    Ok(AsyncTokioIO::new(tls_stream))
+}
+
+/// Loads a PEM cert chain and a PKCS8 or RSA private key, with `client_ca`
+/// set clients have to present a certificate issued by one of its PEM
+/// certificates.
+pub fn load_server_config_with_clients(
+    cert_path: &str,
+    key_path: &str,
+    alpn: &[&str],
+    client_ca: Option<&str>,
+) -> Result<ServerConfig, std::io::Error> {
+    let (cert_chain, key) = load_cert_and_key(cert_path, key_path)?;
+    let mut config = new_server_config(alpn, client_ca)?;
+    if let Err(e) = config.set_single_cert(cert_chain, key) {
+        return Err(make_io_error(&e.to_string()));
+    }
+    Ok(config)
+}
+
+/// Loads a PEM cert chain and a PKCS8 or RSA private key.
+fn load_cert_and_key(
+    cert_path: &str,
+    key_path: &str,
+) -> Result<(Vec<Certificate>, PrivateKey), std::io::Error> {
+    let cert_chain = {
+        let mut reader = BufReader::new(std::fs::File::open(cert_path)?);
+        match certs(&mut reader) {
+            Ok(c) => c,
+            Err(_) => return Err(make_io_error("invalid cert file")),
+        }
+    };
+    let mut keys = {
+        let mut reader = BufReader::new(std::fs::File::open(key_path)?);
+        pkcs8_private_keys(&mut reader).unwrap_or_default()
+    };
+    if keys.is_empty() {
+        let mut reader = BufReader::new(std::fs::File::open(key_path)?);
+        keys = rsa_private_keys(&mut reader).unwrap_or_default();
+    }
+    if keys.is_empty() {
+        return Err(make_io_error("no private key found"));
+    }
+    Ok((cert_chain, keys.remove(0)))
+}
+
+fn client_verifier(ca_file: &str) -> Result<Arc<dyn ClientCertVerifier>, std::io::Error> {
+    let mut roots = RootCertStore::empty();
+    let mut reader = BufReader::new(std::fs::File::open(ca_file)?);
+    match roots.add_pem_file(&mut reader) {
+        Ok((valid, _)) if valid > 0 => Ok(AllowAnyAuthenticatedClient::new(roots)),
+        _ => Err(make_io_error("no valid certificate in client_ca")),
+    }
+}
+
+/// A server config without a certificate yet, see
+/// `load_server_config_with_clients` for `client_ca`.
+pub(super) fn new_server_config(
+    alpn: &[&str],
+    client_ca: Option<&str>,
+) -> Result<ServerConfig, std::io::Error> {
+    let mut config = match client_ca {
+        Some(ca_file) => ServerConfig::new(client_verifier(ca_file)?),
+        None => ServerConfig::new(NoClientAuth::new()),
+    };
+    config.set_protocols(
+        &alpn
+            .iter()
+            .map(|p| p.as_bytes().to_vec())
+            .collect::<Vec<_>>(),
+    );
+    Ok(config)
+}
+
+/// Server config of a TLS listener, with certificates from `acme` when it
+/// is set and otherwise from the listener's `cert` and `key`.
+pub async fn listener_server_config(
+    cfg: &TunnelConfig,
+    name: &str,
+    alpn: &[&str],
+    client_ca: Option<&str>,
+) -> Result<ServerConfig, std::io::Error> {
+    if let Some(acme) = &cfg.acme {
+        let mut config = new_server_config(alpn, client_ca)?;
+        config.cert_resolver = acme_resolver(acme).await?;
+        return Ok(config);
+    }
+    match (cfg.cert.as_ref(), cfg.key.as_ref()) {
+        (Some(cert), Some(key)) => {
+            load_server_config_with_clients(cert.as_str(), key.as_str(), alpn, client_ca)
+        }
+        _ => Err(make_io_error(&format!(
+            "{} listener requires 'cert' and 'key' or 'acme'",
+            name
+        ))),
+    }
+}
+
+pub async fn tls_accept(
+    conn: TcpStream,
+    acceptor: &TlsAcceptor,
+) -> Result<TlsServerStream, std::io::Error> {
+    let tls_stream = acceptor.accept(AsyncTcpStream::new(conn)).await?;
+    Ok(AsyncTokioIO::new(tls_stream))
+}
+
+/// Passes client certificates on to `inner`, keeping the leaf of the chain
+/// it verified.
+struct RecordingClientVerifier {
+    inner: Arc<dyn ClientCertVerifier>,
+    leaf: Arc<Mutex<Option<Certificate>>>,
+}
+
+impl ClientCertVerifier for RecordingClientVerifier {
+    fn client_auth_root_subjects(&self) -> DistinguishedNames {
+        self.inner.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[Certificate],
+    ) -> Result<ClientCertVerified, TLSError> {
+        let verified = self.inner.verify_client_cert(presented_certs)?;
+        *self.leaf.lock().unwrap() = presented_certs.first().cloned();
+        Ok(verified)
+    }
+}
+
+/// Accepts TLS clients with a certificate issued by `client_ca` and tells
+/// who they are. async-tls does not expose the session, so every connection
+/// gets a config of its own whose verifier keeps the certificate.
+#[derive(Clone)]
+pub struct ClientAuthAcceptor {
+    config: Arc<ServerConfig>,
+    verifier: Arc<dyn ClientCertVerifier>,
+}
+
+impl ClientAuthAcceptor {
+    pub fn new(config: ServerConfig, client_ca: &str) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            config: Arc::new(config),
+            verifier: client_verifier(client_ca)?,
+        })
+    }
+
+    /// The accepted stream and the names of the client's certificate, see
+    /// `cert_names`.
+    pub async fn accept(
+        &self,
+        conn: TcpStream,
+    ) -> Result<(TlsServerStream, Vec<String>), std::io::Error> {
+        let leaf = Arc::new(Mutex::new(None));
+        let mut config = ServerConfig::new(Arc::new(RecordingClientVerifier {
+            inner: self.verifier.clone(),
+            leaf: leaf.clone(),
+        }));
+        let base = self.config.as_ref();
+        config.ciphersuites = base.ciphersuites.clone();
+        config.ignore_client_order = base.ignore_client_order;
+        config.mtu = base.mtu;
+        config.ticketer = base.ticketer.clone();
+        config.cert_resolver = base.cert_resolver.clone();
+        config.alpn_protocols = base.alpn_protocols.clone();
+        config.versions = base.versions.clone();
+        config.key_log = base.key_log.clone();
+        // a resumed session skips the verifier, so none are kept
+        config.session_storage = Arc::new(NoServerSessionStorage {});
+        let stream = tls_accept(conn, &TlsAcceptor::from(Arc::new(config))).await?;
+        let names = match leaf.lock().unwrap().take() {
+            Some(cert) => cert_names(&cert.0[..]),
+            None => return Err(make_io_error("no client certificate")),
+        };
+        Ok((stream, names))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rcgen_cert(name: &str, is_ca: bool) -> rcgen::Certificate {
+        let mut params = rcgen::CertificateParams::new(vec![String::from(name)]);
+        if is_ca {
+            params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        }
+        rcgen::Certificate::from_params(params).unwrap()
+    }
+
+    fn pin_of(der: &[u8]) -> String {
+        base64::encode(digest(&SHA256, der).as_ref())
+    }
+
+    fn verify(pins: &[&[u8]], chain: &[Vec<u8>], name: &str) -> bool {
+        let pins: Vec<String> = pins.iter().map(|p| pin_of(p)).collect();
+        let verifier = PinnedCertVerifier::new(&pins[..]).unwrap();
+        let chain: Vec<Certificate> = chain.iter().map(|c| Certificate(c.clone())).collect();
+        let name = webpki::DNSNameRef::try_from_ascii_str(name).unwrap();
+        verifier
+            .verify_server_cert(&RootCertStore::empty(), &chain[..], name, &[])
+            .is_ok()
+    }
+
+    #[test]
+    fn test_pinned_chain() {
+        let ca = rcgen_cert("ca.test", true);
+        let ca_der = ca.serialize_der().unwrap();
+        let leaf = rcgen_cert("pin.test", false);
+        let leaf_der = leaf.serialize_der_with_signer(&ca).unwrap();
+        let chain = [leaf_der.clone(), ca_der.clone()];
+        // a pinned leaf is enough whatever the name
+        assert!(verify(&[&leaf_der[..]], &chain[..], "other.test"));
+        // a pinned CA needs the chain up to it to verify for the name
+        assert!(verify(&[&ca_der[..]], &chain[..], "pin.test"));
+        assert!(!verify(&[&ca_der[..]], &chain[..], "other.test"));
+        assert!(verify(
+            &[cert_spki(&ca_der[..]).unwrap()],
+            &chain[..],
+            "pin.test"
+        ));
+
+        // the pinned cert appended to a chain it did not sign
+        let rogue = rcgen_cert("pin.test", false);
+        let rogue_der = rogue.serialize_der().unwrap();
+        let rogue_chain = [rogue_der, ca_der.clone()];
+        assert!(!verify(&[&ca_der[..]], &rogue_chain[..], "pin.test"));
+        assert!(!verify(&[&ca_der[..]], &chain[..1], "pin.test"));
+    }
+
+    // a self-signed P-256 certificate for pin.test
+    const CERT: &str = "MIIBfDCCASGgAwIBAgIUE1o6baZoXVCKVpd24R7NmdNzyNEwCgYIKoZIzj0EAwIwEzERMA8GA1UEAwwIcGluLnRlc3QwHhcNMjYxMDE1MDMzMDAwWhcNMzYxMDEyMDMzMDAwWjATMREwDwYDVQQDDAhwaW4udGVzdDBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABBsjTN9n16w8fTI2ODjriDyxaB1U6XaxSrYgEja2a3M53g3c8/ye8nH6rehDNGli1ClTZ+ZH6WD8rluzterRth+jUzBRMB0GA1UdDgQWBBQSdVwURVz/nVxbnmV9I16Qu/0mqDAfBgNVHSMEGDAWgBQSdVwURVz/nVxbnmV9I16Qu/0mqDAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0kAMEYCIQCcjLOxywozmyYxF48tXKQWOnRcsC/4ZgYrG0ZDCQNsWwIhAOiMWvIy7DK0LV+FxFDwV/Z8LxjxKzONKKk029fpIFXK";
+    const SPKI_PIN: &str = "Q6LxbML0kC026pSMefLqivHGBRVWCHd+3/vXhC2mRE4=";
+    const CLIENT_CERT: &str = "MIIB0TCCAXagAwIBAgIUbcBPCI8LSZ+ODfkt0CAnacIQjkwwCgYIKoZIzj0EAwIwITEPMA0GA1UECgwGcnNub3ZhMQ4wDAYDVQQDDAVhbGljZTAeFw0yNjEwMTUwMzM4MDFaFw0zNjEwMTIwMzM4MDFaMCExDzANBgNVBAoMBnJzbm92YTEOMAwGA1UEAwwFYWxpY2UwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAAQseQSrFWU8NMqwepRL0RWdVrcEtO6dsars8VlH8JDOf3wlXZja37qFnTgcYpuFmqbcpnryFZzL2GpdulqiqzKJo4GLMIGIMB0GA1UdDgQWBBQEMKLTrG4jvHO3qGIdJ3i6YQ60mzAfBgNVHSMEGDAWgBQEMKLTrG4jvHO3qGIdJ3i6YQ60mzAPBgNVHRMBAf8EBTADAQH/MDUGA1UdEQQuMCyCEWFsaWNlLmV4YW1wbGUuY29tgRFhbGljZUBleGFtcGxlLmNvbYcECgAAATAKBggqhkjOPQQDAgNJADBGAiEA0jKmFLft7kbrPx6nAEAdoWizIt3TbvfLpR8eBlxJMMACIQDdROPsV/foCHN4NTCkdj2B6Oi9Dy8vpu0+osGA5GJi0A==";
+    const CERT_PIN: &str = "i/md6hG7baKpcx0zHC5l5JMnbu5pGSK5v8Wv7F4ciM8=";
+
+    #[test]
+    fn test_pins() {
+        let cert = base64::decode(CERT).unwrap();
+        let spki = cert_spki(&cert[..]).unwrap();
+        // SEQUENCE { AlgorithmIdentifier, BIT STRING } of an uncompressed point
+        assert_eq!(spki.len(), 91);
+        assert_eq!(spki[0], 0x30);
+        let by_key = PinnedCertVerifier::new(&[String::from(SPKI_PIN)]).unwrap();
+        assert!(by_key.is_pinned(spki));
+        assert!(!by_key.is_pinned(&cert[..]));
+        let by_cert = PinnedCertVerifier::new(&[String::from(CERT_PIN)]).unwrap();
+        assert!(by_cert.is_pinned(&cert[..]));
+        assert!(PinnedCertVerifier::new(&[String::from("abcd")]).is_err());
+        assert!(PinnedCertVerifier::new(&[]).is_err());
+        assert!(cert_spki(&cert[..40]).is_none());
+    }
+
+    #[test]
+    fn test_cert_names() {
+        // CN=alice with the SANs alice.example.com, alice@example.com and
+        // an ip address
+        let cert = base64::decode(CLIENT_CERT).unwrap();
+        assert_eq!(
+            cert_names(&cert[..]),
+            vec!["alice", "alice.example.com", "alice@example.com"]
+        );
+        let cert = base64::decode(CERT).unwrap();
+        assert_eq!(cert_names(&cert[..]), vec!["pin.test"]);
+        assert!(cert_names(&cert[..100]).is_empty());
+    }
+
+    #[test]
+    fn test_not_after() {
+        let cert = base64::decode(CERT).unwrap();
+        // 2036-10-12T03:30:00Z
+        assert_eq!(cert_not_after(&cert[..]), Some(2107395000));
+        assert_eq!(cert_not_after(&cert[..100]), None);
+    }
+}
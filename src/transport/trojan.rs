@@ -0,0 +1,249 @@
+use super::shadowsocks::{encode_ss_addr, parse_ss_addr};
+use crate::utils::make_invalid_data_error;
+use bytes::{BufMut, BytesMut};
+use std::io;
+
+pub const TROJAN_CMD_CONNECT: u8 = 1;
+
+const TROJAN_HASH_LEN: usize = 56;
+const CRLF: &[u8] = b"\r\n";
+
+const SHA224_INIT: [u32; 8] = [
+    0xc105_9ed8,
+    0x367c_d507,
+    0x3070_dd17,
+    0xf70e_5939,
+    0xffc0_0b31,
+    0x6858_1511,
+    0x64f9_8fa7,
+    0xbefa_4fa4,
+];
+
+const SHA256_K: [u32; 64] = [
+    0x428a_2f98,
+    0x7137_4491,
+    0xb5c0_fbcf,
+    0xe9b5_dba5,
+    0x3956_c25b,
+    0x59f1_11f1,
+    0x923f_82a4,
+    0xab1c_5ed5,
+    0xd807_aa98,
+    0x1283_5b01,
+    0x2431_85be,
+    0x550c_7dc3,
+    0x72be_5d74,
+    0x80de_b1fe,
+    0x9bdc_06a7,
+    0xc19b_f174,
+    0xe49b_69c1,
+    0xefbe_4786,
+    0x0fc1_9dc6,
+    0x240c_a1cc,
+    0x2de9_2c6f,
+    0x4a74_84aa,
+    0x5cb0_a9dc,
+    0x76f9_88da,
+    0x983e_5152,
+    0xa831_c66d,
+    0xb003_27c8,
+    0xbf59_7fc7,
+    0xc6e0_0bf3,
+    0xd5a7_9147,
+    0x06ca_6351,
+    0x1429_2967,
+    0x27b7_0a85,
+    0x2e1b_2138,
+    0x4d2c_6dfc,
+    0x5338_0d13,
+    0x650a_7354,
+    0x766a_0abb,
+    0x81c2_c92e,
+    0x9272_2c85,
+    0xa2bf_e8a1,
+    0xa81a_664b,
+    0xc24b_8b70,
+    0xc76c_51a3,
+    0xd192_e819,
+    0xd699_0624,
+    0xf40e_3585,
+    0x106a_a070,
+    0x19a4_c116,
+    0x1e37_6c08,
+    0x2748_774c,
+    0x34b0_bcb5,
+    0x391c_0cb3,
+    0x4ed8_aa4a,
+    0x5b9c_ca4f,
+    0x682e_6ff3,
+    0x748f_82ee,
+    0x78a5_636f,
+    0x84c8_7814,
+    0x8cc7_0208,
+    0x90be_fffa,
+    0xa450_6ceb,
+    0xbef9_a3f7,
+    0xc671_78f2,
+];
+
+// ring has no SHA-224, which is SHA-256 with other initial values cut to
+// 28 bytes; passwords are hashed once, so a plain implementation will do.
+fn sha224(data: &[u8]) -> [u8; 28] {
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    let mut h = SHA224_INIT;
+    for block in msg.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let mut v = h;
+        for (k, wi) in SHA256_K.iter().zip(w.iter()) {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7]
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(*k)
+                .wrapping_add(*wi);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+            v[7] = v[6];
+            v[6] = v[5];
+            v[5] = v[4];
+            v[4] = v[3].wrapping_add(t1);
+            v[3] = v[2];
+            v[2] = v[1];
+            v[1] = v[0];
+            v[0] = t1.wrapping_add(t2);
+        }
+        for (x, y) in h.iter_mut().zip(v.iter()) {
+            *x = x.wrapping_add(*y);
+        }
+    }
+    let mut out = [0u8; 28];
+    for (i, x) in h.iter().take(7).enumerate() {
+        out[i * 4..i * 4 + 4].copy_from_slice(&x.to_be_bytes());
+    }
+    out
+}
+
+/// Hex SHA-224 of a password, the credential a trojan client sends.
+pub fn trojan_hash(password: &str) -> String {
+    sha224(password.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// `hash CRLF cmd addr port CRLF`, the payload may follow right behind.
+pub fn encode_trojan_request(
+    hash: &str,
+    cmd: u8,
+    target: &str,
+    out: &mut BytesMut,
+) -> io::Result<()> {
+    out.reserve(TROJAN_HASH_LEN + 2 + 1 + 259 + 2);
+    out.put_slice(hash.as_bytes());
+    out.put_slice(CRLF);
+    out.put_u8(cmd);
+    encode_ss_addr(target, out)?;
+    out.put_slice(CRLF);
+    Ok(())
+}
+
+/// A parsed trojan request header.
+pub struct TrojanRequest {
+    pub hash: String,
+    pub cmd: u8,
+    pub target: String,
+    /// bytes the header took
+    pub len: usize,
+}
+
+/// Parses the header written by `encode_trojan_request`, `None` if `buf` is
+/// still too short. Anything malformed is an error, the caller should hand
+/// the connection to its fallback then.
+pub fn parse_trojan_request(buf: &[u8]) -> io::Result<Option<TrojanRequest>> {
+    let head = std::cmp::min(buf.len(), TROJAN_HASH_LEN);
+    if !buf[..head].iter().all(|b| b.is_ascii_hexdigit()) {
+        return Err(make_invalid_data_error("invalid trojan password hash"));
+    }
+    if buf.len() < TROJAN_HASH_LEN + 2 + 1 {
+        return Ok(None);
+    }
+    if &buf[TROJAN_HASH_LEN..TROJAN_HASH_LEN + 2] != CRLF {
+        return Err(make_invalid_data_error("invalid trojan request"));
+    }
+    let cmd = buf[TROJAN_HASH_LEN + 2];
+    let addr_start = TROJAN_HASH_LEN + 3;
+    let (target, n) = match parse_ss_addr(&buf[addr_start..])? {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let end = addr_start + n;
+    if buf.len() < end + 2 {
+        return Ok(None);
+    }
+    if &buf[end..end + 2] != CRLF {
+        return Err(make_invalid_data_error("invalid trojan request"));
+    }
+    Ok(Some(TrojanRequest {
+        hash: String::from_utf8_lossy(&buf[..TROJAN_HASH_LEN]).to_lowercase(),
+        cmd,
+        target,
+        len: end + 2,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trojan_hash() {
+        assert_eq!(
+            trojan_hash(""),
+            "d14a028c2a3a2bc9476102bb288234c415a2b01f828ea62ac5b3e42f"
+        );
+        assert_eq!(
+            trojan_hash("abc"),
+            "23097d223405d8228642a477bda255b32aadbce4bda0b3f7e36c9da7"
+        );
+        // two blocks of padding
+        assert_eq!(
+            trojan_hash("abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "75388b16512776cc5dba5da1fd890150b0c6455cb4f58b1952522525"
+        );
+    }
+
+    #[test]
+    fn test_trojan_request() {
+        let hash = trojan_hash("secret");
+        let mut buf = BytesMut::new();
+        encode_trojan_request(&hash, TROJAN_CMD_CONNECT, "example.com:443", &mut buf).unwrap();
+        let len = buf.len();
+        assert!(parse_trojan_request(&buf[..len - 1]).unwrap().is_none());
+        buf.put_slice(b"GET / HTTP/1.1\r\n");
+        let req = parse_trojan_request(&buf[..]).unwrap().unwrap();
+        assert_eq!(req.hash, hash);
+        assert_eq!(req.cmd, TROJAN_CMD_CONNECT);
+        assert_eq!(req.target, "example.com:443");
+        assert_eq!(req.len, len);
+        // plain HTTP probes are rejected right away
+        assert!(parse_trojan_request(b"GET / HTTP/1.1\r\n").is_err());
+    }
+}
@@ -0,0 +1,678 @@
+use super::shadowsocks::encode_ss_addr;
+use crate::utils::make_invalid_data_error;
+use bytes::{Buf, BufMut, BytesMut};
+use ring::aead::{Aad, Algorithm, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
+use ring::aead::{AES_128_GCM, CHACHA20_POLY1305};
+use ring::digest;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+pub const VMESS_AES_128_GCM: &str = "aes-128-gcm";
+pub const VMESS_CHACHA20_POLY1305: &str = "chacha20-poly1305";
+pub const VMESS_NONE: &str = "none";
+
+const SEC_AES_128_GCM: u8 = 3;
+const SEC_CHACHA20_POLY1305: u8 = 4;
+const SEC_NONE: u8 = 5;
+
+const VERSION: u8 = 1;
+// chunked body with plain length prefixes, no masking or padding
+const OPT_CHUNK_STREAM: u8 = 1;
+const CMD_TCP: u8 = 1;
+
+const ADDR_IPV4: u8 = 1;
+const ADDR_DOMAIN: u8 = 2;
+const ADDR_IPV6: u8 = 3;
+
+const TAG_LEN: usize = 16;
+const MAX_CHUNK_PAYLOAD: usize = 8192;
+
+const CMD_KEY_SALT: &[u8] = b"c48619fe-8f02-49e0-b9e9-edf763e17e21";
+const KDF_SALT: &[u8] = b"VMess AEAD KDF";
+const KDF_AUTH_ID_KEY: &[u8] = b"AES Auth ID Encryption";
+const KDF_HEADER_LEN_KEY: &[u8] = b"VMess Header AEAD Key_Length";
+const KDF_HEADER_LEN_NONCE: &[u8] = b"VMess Header AEAD Nonce_Length";
+const KDF_HEADER_KEY: &[u8] = b"VMess Header AEAD Key";
+const KDF_HEADER_NONCE: &[u8] = b"VMess Header AEAD Nonce";
+const KDF_RESP_LEN_KEY: &[u8] = b"AEAD Resp Header Len Key";
+const KDF_RESP_LEN_NONCE: &[u8] = b"AEAD Resp Header Len IV";
+const KDF_RESP_KEY: &[u8] = b"AEAD Resp Header Key";
+const KDF_RESP_NONCE: &[u8] = b"AEAD Resp Header IV";
+
+const AES_SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+/// Parses a user id like `b831381d-6324-4d53-ad4f-8cda48b30811`.
+pub fn parse_uuid(s: &str) -> io::Result<[u8; 16]> {
+    let hex: Vec<u8> = s.bytes().filter(|b| *b != b'-').collect();
+    if hex.len() != 32 {
+        return Err(make_invalid_data_error("invalid vmess user id"));
+    }
+    let mut id = [0u8; 16];
+    for (i, pair) in hex.chunks(2).enumerate() {
+        let pair = std::str::from_utf8(pair)
+            .map_err(|_| make_invalid_data_error("invalid vmess user id"))?;
+        id[i] = u8::from_str_radix(pair, 16)
+            .map_err(|_| make_invalid_data_error("invalid vmess user id"))?;
+    }
+    Ok(id)
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    out.copy_from_slice(digest::digest(&digest::SHA256, data).as_ref());
+    out
+}
+
+// HMAC where the hash is itself an HMAC keyed by the previous key, down to
+// plain SHA-256; `keys` runs from the innermost key to the outermost one.
+fn kdf_hash(keys: &[&[u8]], data: &[u8]) -> [u8; 32] {
+    let (key, inner) = match keys.split_last() {
+        Some(v) => v,
+        None => return sha256(data),
+    };
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        block[..32].copy_from_slice(&kdf_hash(inner, key)[..]);
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut ipad: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    ipad.extend_from_slice(data);
+    let mut opad: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+    opad.extend_from_slice(&kdf_hash(inner, &ipad[..])[..]);
+    kdf_hash(inner, &opad[..])
+}
+
+/// The VMess AEAD key derivation, nested HMAC-SHA256 over `path`.
+fn kdf(key: &[u8], path: &[&[u8]]) -> [u8; 32] {
+    let mut keys = vec![KDF_SALT];
+    keys.extend_from_slice(path);
+    kdf_hash(&keys[..], key)
+}
+
+fn kdf16(key: &[u8], path: &[&[u8]]) -> [u8; 16] {
+    let mut out = [0u8; 16];
+    out.copy_from_slice(&kdf(key, path)[..16]);
+    out
+}
+
+fn xtime(b: u8) -> u8 {
+    (b << 1) ^ if b & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+// ring offers no raw block cipher, the auth id is the only single AES block
+// VMess needs.
+fn aes128_encrypt_block(key: &[u8; 16], block: &[u8; 16]) -> [u8; 16] {
+    let mut w = [0u8; 176];
+    w[..16].copy_from_slice(key);
+    let mut rcon = 1u8;
+    for i in 4..44 {
+        let mut t = [w[4 * i - 4], w[4 * i - 3], w[4 * i - 2], w[4 * i - 1]];
+        if i % 4 == 0 {
+            t = [
+                AES_SBOX[t[1] as usize] ^ rcon,
+                AES_SBOX[t[2] as usize],
+                AES_SBOX[t[3] as usize],
+                AES_SBOX[t[0] as usize],
+            ];
+            rcon = xtime(rcon);
+        }
+        for j in 0..4 {
+            w[4 * i + j] = w[4 * i - 16 + j] ^ t[j];
+        }
+    }
+    let mut s = *block;
+    for (b, k) in s.iter_mut().zip(w[..16].iter()) {
+        *b ^= k;
+    }
+    for round in 1..11 {
+        let mut t = [0u8; 16];
+        // SubBytes and ShiftRows, the state is column major
+        for c in 0..4 {
+            for r in 0..4 {
+                t[r + 4 * c] = AES_SBOX[s[r + 4 * ((c + r) % 4)] as usize];
+            }
+        }
+        if round < 10 {
+            for c in 0..4 {
+                let a = [t[4 * c], t[4 * c + 1], t[4 * c + 2], t[4 * c + 3]];
+                let x = a[0] ^ a[1] ^ a[2] ^ a[3];
+                for r in 0..4 {
+                    t[4 * c + r] = a[r] ^ x ^ xtime(a[r] ^ a[(r + 1) % 4]);
+                }
+            }
+        }
+        for (b, k) in t.iter_mut().zip(w[16 * round..16 * round + 16].iter()) {
+            *b ^= k;
+        }
+        s = t;
+    }
+    s
+}
+
+fn fnv1a32(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5u32, |h, b| {
+        (h ^ u32::from(*b)).wrapping_mul(0x0100_0193)
+    })
+}
+
+fn create_auth_id(cmd_key: &[u8; 16], time: u64) -> [u8; 16] {
+    let mut id = [0u8; 16];
+    id[..8].copy_from_slice(&time.to_be_bytes());
+    id[8..12].copy_from_slice(&rand::random::<u32>().to_be_bytes());
+    let crc = crc::crc32::checksum_ieee(&id[..12]);
+    id[12..].copy_from_slice(&crc.to_be_bytes());
+    aes128_encrypt_block(&kdf16(cmd_key, &[KDF_AUTH_ID_KEY]), &id)
+}
+
+fn make_nonce(nonce: &[u8]) -> Nonce {
+    let mut n = [0u8; NONCE_LEN];
+    n.copy_from_slice(&nonce[..NONCE_LEN]);
+    Nonce::assume_unique_for_key(n)
+}
+
+fn aead_key(algorithm: &'static Algorithm, key: &[u8]) -> io::Result<LessSafeKey> {
+    match UnboundKey::new(algorithm, key) {
+        Ok(k) => Ok(LessSafeKey::new(k)),
+        Err(_) => Err(make_invalid_data_error("invalid vmess key")),
+    }
+}
+
+fn seal(key: &LessSafeKey, nonce: &[u8], aad: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut sealed = data.to_vec();
+    match key.seal_in_place_append_tag(make_nonce(nonce), Aad::from(aad), &mut sealed) {
+        Ok(_) => Ok(sealed),
+        Err(_) => Err(make_invalid_data_error("vmess encrypt failed")),
+    }
+}
+
+fn open(key: &LessSafeKey, nonce: &[u8], data: &mut [u8]) -> io::Result<usize> {
+    match key.open_in_place(make_nonce(nonce), Aad::empty(), data) {
+        Ok(plain) => Ok(plain.len()),
+        Err(_) => Err(make_invalid_data_error("vmess decrypt failed")),
+    }
+}
+
+/// Body cipher of one direction, chunks are `[len][payload]` with the
+/// length counting the tag too.
+struct BodyCipher {
+    key: Option<LessSafeKey>,
+    iv: [u8; 16],
+    count: u16,
+}
+
+impl BodyCipher {
+    fn new(security: u8, key: &[u8; 16], iv: &[u8; 16]) -> io::Result<Self> {
+        let key = match security {
+            SEC_AES_128_GCM => Some(aead_key(&AES_128_GCM, &key[..])?),
+            SEC_CHACHA20_POLY1305 => {
+                let first = md5::compute(&key[..]).0;
+                let second = md5::compute(&first[..]).0;
+                let mut full = first.to_vec();
+                full.extend_from_slice(&second[..]);
+                Some(aead_key(&CHACHA20_POLY1305, &full[..])?)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            key,
+            iv: *iv,
+            count: 0,
+        })
+    }
+    fn next_nonce(&mut self) -> [u8; NONCE_LEN] {
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[..2].copy_from_slice(&self.count.to_be_bytes());
+        nonce[2..].copy_from_slice(&self.iv[2..NONCE_LEN]);
+        self.count = self.count.wrapping_add(1);
+        nonce
+    }
+    fn overhead(&self) -> usize {
+        if self.key.is_some() {
+            TAG_LEN
+        } else {
+            0
+        }
+    }
+    fn seal_chunk(&mut self, data: &[u8], out: &mut BytesMut) -> io::Result<()> {
+        out.reserve(2 + data.len() + TAG_LEN);
+        out.put_u16((data.len() + self.overhead()) as u16);
+        if self.key.is_none() {
+            out.put_slice(data);
+            return Ok(());
+        }
+        let nonce = self.next_nonce();
+        let sealed = seal(self.key.as_ref().unwrap(), &nonce[..], &[], data)?;
+        out.put_slice(&sealed[..]);
+        Ok(())
+    }
+    /// The next chunk, `Some` of an empty one at the end of the stream.
+    fn open_chunk(&mut self, buf: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let len = ((buf[0] as usize) << 8) | buf[1] as usize;
+        if len < self.overhead() {
+            return Err(make_invalid_data_error("invalid vmess chunk"));
+        }
+        if buf.len() < 2 + len {
+            return Ok(None);
+        }
+        buf.advance(2);
+        let mut chunk = buf.split_to(len);
+        if self.key.is_some() {
+            let nonce = self.next_nonce();
+            let n = open(self.key.as_ref().unwrap(), &nonce[..], &mut chunk[..])?;
+            chunk.truncate(n);
+        }
+        Ok(Some(chunk))
+    }
+}
+
+/// Client side of a VMess AEAD connection: the sealed request header to
+/// send first, and the ciphers of both directions.
+pub struct VmessClient {
+    pub header: BytesMut,
+    pub encoder: VmessEncoder,
+    pub decoder: VmessDecoder,
+}
+
+/// Builds a TCP request to `target` for the user `uuid`, `security` is one
+/// of "aes-128-gcm", "chacha20-poly1305" or "none".
+pub fn vmess_request(uuid: &[u8; 16], security: &str, target: &str) -> io::Result<VmessClient> {
+    let security = match security {
+        VMESS_AES_128_GCM => SEC_AES_128_GCM,
+        VMESS_CHACHA20_POLY1305 => SEC_CHACHA20_POLY1305,
+        VMESS_NONE => SEC_NONE,
+        _ => return Err(make_invalid_data_error("unsupported vmess security")),
+    };
+    let body_key: [u8; 16] = rand::random();
+    let body_iv: [u8; 16] = rand::random();
+    let resp_v = rand::random::<u8>();
+
+    let mut cmd = BytesMut::with_capacity(64);
+    cmd.put_u8(VERSION);
+    cmd.put_slice(&body_iv[..]);
+    cmd.put_slice(&body_key[..]);
+    cmd.put_u8(resp_v);
+    cmd.put_u8(OPT_CHUNK_STREAM);
+    // no padding in the high nibble
+    cmd.put_u8(security);
+    cmd.put_u8(0);
+    cmd.put_u8(CMD_TCP);
+    // same address forms as SOCKS5, with the port first and other type ids
+    let mut addr = BytesMut::new();
+    encode_ss_addr(target, &mut addr)?;
+    let port_pos = addr.len() - 2;
+    cmd.put_slice(&addr[port_pos..]);
+    cmd.put_u8(match addr[0] {
+        1 => ADDR_IPV4,
+        4 => ADDR_IPV6,
+        _ => ADDR_DOMAIN,
+    });
+    cmd.put_slice(&addr[1..port_pos]);
+    let checksum = fnv1a32(&cmd[..]);
+    cmd.put_u32(checksum);
+
+    let mut id_key = uuid.to_vec();
+    id_key.extend_from_slice(CMD_KEY_SALT);
+    let cmd_key = md5::compute(&id_key).0;
+    let time = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    };
+    let auth_id = create_auth_id(&cmd_key, time);
+    let conn_nonce: [u8; 8] = rand::random();
+    let path = |label: &'static [u8]| -> [&[u8]; 3] { [label, &auth_id[..], &conn_nonce[..]] };
+
+    let len_key = aead_key(&AES_128_GCM, &kdf16(&cmd_key, &path(KDF_HEADER_LEN_KEY)))?;
+    let len_nonce = kdf(&cmd_key, &path(KDF_HEADER_LEN_NONCE));
+    let sealed_len = seal(
+        &len_key,
+        &len_nonce[..],
+        &auth_id[..],
+        &(cmd.len() as u16).to_be_bytes(),
+    )?;
+    let header_key = aead_key(&AES_128_GCM, &kdf16(&cmd_key, &path(KDF_HEADER_KEY)))?;
+    let header_nonce = kdf(&cmd_key, &path(KDF_HEADER_NONCE));
+    let sealed_cmd = seal(&header_key, &header_nonce[..], &auth_id[..], &cmd[..])?;
+
+    let mut header = BytesMut::with_capacity(16 + sealed_len.len() + 8 + sealed_cmd.len());
+    header.put_slice(&auth_id[..]);
+    header.put_slice(&sealed_len[..]);
+    header.put_slice(&conn_nonce[..]);
+    header.put_slice(&sealed_cmd[..]);
+
+    let mut resp_key = [0u8; 16];
+    resp_key.copy_from_slice(&sha256(&body_key[..])[..16]);
+    let mut resp_iv = [0u8; 16];
+    resp_iv.copy_from_slice(&sha256(&body_iv[..])[..16]);
+    Ok(VmessClient {
+        header,
+        encoder: VmessEncoder {
+            body: BodyCipher::new(security, &body_key, &body_iv)?,
+        },
+        decoder: VmessDecoder {
+            body: BodyCipher::new(security, &resp_key, &resp_iv)?,
+            resp_key,
+            resp_iv,
+            resp_v,
+            header_len: None,
+            header_done: false,
+        },
+    })
+}
+
+pub struct VmessEncoder {
+    body: BodyCipher,
+}
+
+impl VmessEncoder {
+    pub fn encode(&mut self, data: &[u8], out: &mut BytesMut) -> io::Result<()> {
+        for chunk in data.chunks(MAX_CHUNK_PAYLOAD) {
+            self.body.seal_chunk(chunk, out)?;
+        }
+        Ok(())
+    }
+    /// The empty chunk which ends the request body.
+    pub fn encode_end(&mut self, out: &mut BytesMut) -> io::Result<()> {
+        self.body.seal_chunk(&[], out)
+    }
+}
+
+pub struct VmessDecoder {
+    body: BodyCipher,
+    resp_key: [u8; 16],
+    resp_iv: [u8; 16],
+    resp_v: u8,
+    header_len: Option<usize>,
+    header_done: bool,
+}
+
+impl VmessDecoder {
+    fn decode_header(&mut self, buf: &mut BytesMut) -> io::Result<bool> {
+        let len = match self.header_len {
+            Some(n) => n,
+            None => {
+                if buf.len() < 2 + TAG_LEN {
+                    return Ok(false);
+                }
+                let key = aead_key(&AES_128_GCM, &kdf16(&self.resp_key, &[KDF_RESP_LEN_KEY]))?;
+                let nonce = kdf(&self.resp_iv, &[KDF_RESP_LEN_NONCE]);
+                let mut sealed = buf.split_to(2 + TAG_LEN);
+                open(&key, &nonce[..], &mut sealed[..])?;
+                let n = ((sealed[0] as usize) << 8) | sealed[1] as usize;
+                self.header_len = Some(n);
+                n
+            }
+        };
+        if buf.len() < len + TAG_LEN {
+            return Ok(false);
+        }
+        let key = aead_key(&AES_128_GCM, &kdf16(&self.resp_key, &[KDF_RESP_KEY]))?;
+        let nonce = kdf(&self.resp_iv, &[KDF_RESP_NONCE]);
+        let mut sealed = buf.split_to(len + TAG_LEN);
+        let n = open(&key, &nonce[..], &mut sealed[..])?;
+        if n < 4 || sealed[0] != self.resp_v {
+            return Err(make_invalid_data_error("unexpected vmess response header"));
+        }
+        self.header_done = true;
+        Ok(true)
+    }
+
+    /// Decrypts the next chunk off the front of `buf`, `None` if more bytes
+    /// are needed and an empty chunk once the server ended the response.
+    pub fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        if !self.header_done && !self.decode_header(buf)? {
+            return Ok(None);
+        }
+        self.body.open_chunk(buf)
+    }
+}
+
+/// Decrypts the response of a VMess connection.
+pub struct VmessReader<R> {
+    inner: R,
+    decoder: VmessDecoder,
+    raw: BytesMut,
+    data: BytesMut,
+    eof: bool,
+}
+
+impl<R> VmessReader<R> {
+    pub fn new(inner: R, decoder: VmessDecoder) -> Self {
+        Self {
+            inner,
+            decoder,
+            raw: BytesMut::new(),
+            data: BytesMut::new(),
+            eof: false,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for VmessReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            if !self.data.is_empty() {
+                let n = std::cmp::min(buf.len(), self.data.len());
+                buf[..n].copy_from_slice(&self.data[..n]);
+                self.data.advance(n);
+                return Poll::Ready(Ok(n));
+            }
+            if self.eof {
+                return Poll::Ready(Ok(0));
+            }
+            let Self {
+                inner,
+                decoder,
+                raw,
+                data,
+                eof,
+            } = &mut *self;
+            if let Some(chunk) = decoder.decode(raw)? {
+                *eof = chunk.is_empty();
+                *data = chunk;
+                continue;
+            }
+            raw.reserve(4096);
+            let n = ready!(Pin::new(inner).poll_read_buf(cx, raw))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::UnexpectedEof)));
+            }
+        }
+    }
+}
+
+/// Encrypts the request body of a VMess connection, shutting it down sends
+/// the closing empty chunk first.
+pub struct VmessWriter<W> {
+    inner: W,
+    encoder: VmessEncoder,
+    pending: BytesMut,
+    pending_len: usize,
+    closed: bool,
+}
+
+impl<W> VmessWriter<W> {
+    /// `header` is sent ahead of the first chunk.
+    pub fn new(inner: W, encoder: VmessEncoder, header: BytesMut) -> Self {
+        Self {
+            inner,
+            encoder,
+            pending: header,
+            pending_len: 0,
+            closed: false,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> VmessWriter<W> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero)));
+            }
+            self.pending.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for VmessWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.pending_len == 0 {
+            // the header, if still pending, goes out ahead of this chunk
+            ready!(self.poll_drain(cx))?;
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            let Self {
+                encoder, pending, ..
+            } = &mut *self;
+            encoder.encode(buf, pending)?;
+            self.pending_len = buf.len();
+        }
+        ready!(self.poll_drain(cx))?;
+        let n = self.pending_len;
+        self.pending_len = 0;
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.closed {
+            ready!(self.poll_drain(cx))?;
+            let Self {
+                encoder, pending, ..
+            } = &mut *self;
+            encoder.encode_end(pending)?;
+            self.closed = true;
+        }
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aes128_block() {
+        // FIPS-197 appendix C.1
+        let key: Vec<u8> = (0..16).collect();
+        let mut k = [0u8; 16];
+        k.copy_from_slice(&key[..]);
+        let mut block = [0u8; 16];
+        for (i, b) in block.iter_mut().enumerate() {
+            *b = (i as u8) * 0x11;
+        }
+        let out = aes128_encrypt_block(&k, &block);
+        assert_eq!(
+            out,
+            [
+                0x69, 0xc4, 0xe0, 0xd8, 0x6a, 0x7b, 0x04, 0x30, 0xd8, 0xcd, 0xb7, 0x80, 0x70, 0xb4,
+                0xc5, 0x5a
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fnv1a32() {
+        assert_eq!(fnv1a32(b""), 0x811c_9dc5);
+        assert_eq!(fnv1a32(b"a"), 0xe40c_292c);
+    }
+
+    #[test]
+    fn test_parse_uuid() {
+        let id = parse_uuid("b831381d-6324-4d53-ad4f-8cda48b30811").unwrap();
+        assert_eq!(id[0], 0xb8);
+        assert_eq!(id[15], 0x11);
+        assert!(parse_uuid("b831381d-6324").is_err());
+        assert!(parse_uuid("x831381d-6324-4d53-ad4f-8cda48b30811").is_err());
+    }
+
+    #[test]
+    fn test_kdf_single_level() {
+        // with no path the kdf is plain HMAC-SHA256 keyed by the salt
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, KDF_SALT);
+        let expected = ring::hmac::sign(&key, b"data");
+        assert_eq!(&kdf(b"data", &[])[..], expected.as_ref());
+    }
+
+    #[test]
+    fn test_kdf_nested() {
+        let hex = |v: [u8; 32]| -> String { v.iter().map(|b| format!("{:02x}", b)).collect() };
+        assert_eq!(
+            hex(kdf(&[b'k'; 16], &[KDF_AUTH_ID_KEY])),
+            "2af7a3a08e4e957ca1328719ab5744e53b7a6e59e82f4204496d3d74e2770e2c"
+        );
+        assert_eq!(
+            hex(kdf(&[b'k'; 16], &[b"a", b"b"])),
+            "b453a28c8cf068b9812e1e4c7b3bed12e2ae15b735623be44b6204f3e5b4cb71"
+        );
+    }
+
+    #[test]
+    fn test_body_roundtrip() {
+        for security in &[SEC_AES_128_GCM, SEC_CHACHA20_POLY1305, SEC_NONE] {
+            let key = [1u8; 16];
+            let iv = [2u8; 16];
+            let mut encoder = VmessEncoder {
+                body: BodyCipher::new(*security, &key, &iv).unwrap(),
+            };
+            let mut decoder = BodyCipher::new(*security, &key, &iv).unwrap();
+            let data = vec![9u8; MAX_CHUNK_PAYLOAD + 1];
+            let mut wire = BytesMut::new();
+            encoder.encode(&data[..], &mut wire).unwrap();
+            encoder.encode_end(&mut wire).unwrap();
+            let mut out = decoder.open_chunk(&mut wire).unwrap().unwrap();
+            out.unsplit(decoder.open_chunk(&mut wire).unwrap().unwrap());
+            assert_eq!(&out[..], &data[..]);
+            assert!(decoder.open_chunk(&mut wire).unwrap().unwrap().is_empty());
+            assert!(wire.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_request_header_length() {
+        let uuid = parse_uuid("b831381d-6324-4d53-ad4f-8cda48b30811").unwrap();
+        let client = vmess_request(&uuid, VMESS_AES_128_GCM, "example.com:443").unwrap();
+        // version, iv, key, v, opt, sec, rsv, cmd, port, type, len + domain, fnv
+        let cmd_len = 1 + 16 + 16 + 1 + 1 + 1 + 1 + 1 + 2 + 1 + 12 + 4;
+        assert_eq!(client.header.len(), 16 + 18 + 8 + cmd_len + TAG_LEN);
+        assert!(vmess_request(&uuid, "aes-256-cfb", "example.com:443").is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use super::tls::valid_tls_version;
+
+/// Bytes peeked from a client of a mixed port before it is classified.
+pub(super) const DETECT_PREFIX_LEN: usize = 8;
+
+/// The protocols a mixed port serves, anything else is relayed raw to its
+/// original destination when it has one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Inbound {
+    Socks5,
+    Socks4,
+    Tls,
+    Http2,
+    HttpConnect,
+    Http,
+}
+
+pub(super) struct Detector {
+    pub name: &'static str,
+    pub inbound: Inbound,
+    matches: fn(&[u8]) -> bool,
+}
+
+fn is_socks5(prefix: &[u8]) -> bool {
+    // version and a non zero count of auth methods
+    prefix.len() >= 2 && prefix[0] == 5 && prefix[1] > 0
+}
+
+fn is_socks4(prefix: &[u8]) -> bool {
+    // version and CONNECT or BIND
+    prefix.len() >= 2 && prefix[0] == 4 && (prefix[1] == 1 || prefix[1] == 2)
+}
+
+/// True if the prefix starts with `method` and a space, or with as much of
+/// them as was read, at least three bytes.
+fn is_method(prefix: &[u8], method: &str) -> bool {
+    let expect = format!("{} ", method);
+    let n = prefix.len().min(expect.len());
+    n >= 3 && prefix[..n].eq_ignore_ascii_case(&expect.as_bytes()[..n])
+}
+
+fn is_http2(prefix: &[u8]) -> bool {
+    // the connection preface "PRI * HTTP/2.0"
+    is_method(prefix, "PRI")
+}
+
+fn is_http_connect(prefix: &[u8]) -> bool {
+    is_method(prefix, "CONNECT")
+}
+
+fn is_http(prefix: &[u8]) -> bool {
+    [
+        "GET", "PUT", "POST", "DELETE", "OPTIONS", "TRACE", "PATCH", "HEAD", "UPGRADE",
+    ]
+    .iter()
+    .any(|m| is_method(prefix, m))
+}
+
+/// Matchers of the mixed port, tried in order. A new inbound protocol adds
+/// its variant to `Inbound` and its matcher here.
+static DETECTORS: &[Detector] = &[
+    Detector {
+        name: "SOCKS5 proxy",
+        inbound: Inbound::Socks5,
+        matches: is_socks5,
+    },
+    Detector {
+        name: "SOCKS4 proxy",
+        inbound: Inbound::Socks4,
+        matches: is_socks4,
+    },
+    Detector {
+        name: "SNI proxy",
+        inbound: Inbound::Tls,
+        matches: valid_tls_version,
+    },
+    Detector {
+        name: "HTTP/2 proxy",
+        inbound: Inbound::Http2,
+        matches: is_http2,
+    },
+    Detector {
+        name: "HTTPS proxy",
+        inbound: Inbound::HttpConnect,
+        matches: is_http_connect,
+    },
+    Detector {
+        name: "HTTP proxy",
+        inbound: Inbound::Http,
+        matches: is_http,
+    },
+];
+
+/// Classifies a client by the first bytes it sent.
+pub(super) fn detect_inbound(prefix: &[u8]) -> Option<&'static Detector> {
+    DETECTORS.iter().find(|d| (d.matches)(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect(prefix: &[u8]) -> Option<Inbound> {
+        detect_inbound(prefix).map(|d| d.inbound)
+    }
+
+    #[test]
+    fn test_detect_inbound() {
+        assert_eq!(detect(&[5, 1, 0]), Some(Inbound::Socks5));
+        assert_eq!(detect(&[4, 1, 0, 80, 1, 2, 3, 4]), Some(Inbound::Socks4));
+        assert_eq!(detect(&[0x16, 3, 1, 0, 200]), Some(Inbound::Tls));
+        assert_eq!(detect(b"PRI * HT"), Some(Inbound::Http2));
+        assert_eq!(detect(b"CONNECT "), Some(Inbound::HttpConnect));
+        assert_eq!(detect(b"get / HT"), Some(Inbound::Http));
+        assert_eq!(detect(b"POS"), Some(Inbound::Http));
+        assert_eq!(detect(b"GETX"), None);
+        assert_eq!(detect(b"SSH-2.0-"), None);
+        assert_eq!(detect(&[4, 9]), None);
+    }
+}
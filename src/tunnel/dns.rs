@@ -0,0 +1,34 @@
+use super::rmux::serve_rmux_session;
+use crate::config::TunnelConfig;
+use crate::transport::dns_listen;
+use futures::FutureExt;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+pub async fn start_dns_server(
+    addr: &str,
+    domain: &str,
+    cfg: TunnelConfig,
+) -> Result<(), std::io::Error> {
+    let key = match cfg.cipher.as_ref() {
+        Some(c) => c.key.as_str(),
+        None => "",
+    };
+    let mut listener = dns_listen(addr, domain, key).await?;
+    let tunnel_id_seed = AtomicU32::new(0);
+    while let Some((stream, peer)) = listener.accept().await {
+        let tunnel_id = tunnel_id_seed.fetch_add(1, Ordering::SeqCst);
+        info!("[{}]Accept dns tunnel session via {}", tunnel_id, peer);
+        let cfg = cfg.clone();
+        let handle = async move {
+            let (read, write) = tokio::io::split(stream);
+            serve_rmux_session(tunnel_id, read, write, &cfg).await
+        }
+        .map(move |r| {
+            if let Err(e) = r {
+                error!("[{}]Failed to handle; error={}", tunnel_id, e);
+            }
+        });
+        tokio::spawn(handle);
+    }
+    Ok(())
+}
@@ -0,0 +1,226 @@
+use super::relay::{open_rule_stream, rule_channel, select_rule};
+use crate::config::TunnelConfig;
+use crate::rmux::open_datagram_flow;
+use crate::transport::dns_question;
+use crate::utils::{
+    make_error, register_stream_metrics, FrameCodec, FrameReader, FrameWriter, LengthPrefix,
+};
+
+use futures::future::join;
+use futures::FutureExt;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+
+const MAX_DNS_MESSAGE: usize = 65535;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+// truncation bit of the third header byte of an answer
+const DNS_FLAG_TC: u8 = 0x02;
+const RCODE_SERVFAIL: u8 = 2;
+
+/// Answer telling the client the query failed, so it does not wait for a
+/// timeout before trying its next server.
+fn servfail(query: &[u8]) -> Option<Vec<u8>> {
+    let (_, end) = dns_question(query)?;
+    let mut res = Vec::with_capacity(end);
+    res.extend_from_slice(&query[..2]);
+    // QR, the opcode and RD of the query, RA
+    res.push(0x80 | (query[2] & 0x79));
+    res.push(0x80 | RCODE_SERVFAIL);
+    res.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    res.extend_from_slice(&query[12..end]);
+    Some(res)
+}
+
+// DNS over TCP: every message goes with a 2 byte length
+fn tcp_codec() -> FrameCodec {
+    FrameCodec::new(LengthPrefix::U16, MAX_DNS_MESSAGE)
+}
+
+/// Sends one query to the resolver configured for its name, as DNS over TCP
+/// over the channel the pac rules pick for that resolver.
+/// Asks the resolver with a plain datagram when the channel carries them,
+/// `None` leaves the query to a stream: no datagram flow, no answer in time,
+/// or an answer truncated to fit a datagram.
+async fn forward_datagram(channel: &str, query: &[u8], target: &str) -> Option<Vec<u8>> {
+    let mut flow = open_datagram_flow(channel, target)?;
+    flow.send(query).ok()?;
+    let answer = tokio::time::timeout(QUERY_TIMEOUT, flow.recv())
+        .await
+        .ok()??;
+    if answer.len() > 2 && answer[2] & DNS_FLAG_TC != 0 {
+        return None;
+    }
+    Some(answer)
+}
+
+async fn forward_query(
+    tunnel_id: u32,
+    query: &[u8],
+    cfg: &TunnelConfig,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let name = match dns_question(query) {
+        Some((name, _)) => name.to_lowercase(),
+        None => return Err(make_error("invalid dns query")),
+    };
+    let resolver = cfg
+        .resolvers
+        .as_ref()
+        .and_then(|rs| rs.iter().find(|r| r.is_match(name.as_str())));
+    let target = match resolver {
+        Some(r) => r.server_addr(),
+        None => return Err(make_error("no resolver for query")),
+    };
+    let rule = match select_rule(cfg, target.as_str()) {
+        Some(r) => r,
+        None => return Err(make_error("no valid channel found.")),
+    };
+    let channel = rule_channel(rule);
+    info!(
+        "[{}]Forward dns query {} to {} via {}",
+        tunnel_id, name, target, channel
+    );
+    let _metrics = register_stream_metrics(channel.as_str(), target.as_str());
+    if let Some(answer) = forward_datagram(channel.as_str(), query, target.as_str()).await {
+        return Ok(answer);
+    }
+    let mut remote = match open_rule_stream(rule, target).await {
+        Ok(s) => s,
+        Err(e) => return Err(make_error(&e.to_string())),
+    };
+    let exchange = async {
+        let (ro, wo) = remote.split();
+        FrameWriter::new(wo, tcp_codec()).write_frame(query).await?;
+        FrameReader::new(ro, tcp_codec()).read_frame().await
+    };
+    let r = tokio::time::timeout(QUERY_TIMEOUT, exchange).await;
+    let _ = remote.close();
+    match r {
+        Ok(Ok(Some(answer))) => Ok(answer.to_vec()),
+        Ok(Ok(None)) => Err(make_error("resolver closed without answer")),
+        Ok(Err(e)) => Err(Box::new(e)),
+        Err(_) => Err(make_error("timeout waiting for dns answer")),
+    }
+}
+
+pub(super) async fn answer_query(
+    tunnel_id: u32,
+    query: &[u8],
+    cfg: &TunnelConfig,
+) -> Option<Vec<u8>> {
+    match forward_query(tunnel_id, query, cfg).await {
+        Ok(answer) => Some(answer),
+        Err(e) => {
+            error!("[{}]Failed to forward dns query; error={}", tunnel_id, e);
+            servfail(query)
+        }
+    }
+}
+
+async fn handle_tcp_client(
+    tunnel_id: u32,
+    mut inbound: TcpStream,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let (ri, wi) = inbound.split();
+    let mut reader = FrameReader::new(ri, tcp_codec());
+    let mut writer = FrameWriter::new(wi, tcp_codec());
+    while let Some(query) = reader.read_frame().await? {
+        if let Some(answer) = answer_query(tunnel_id, &query, &cfg).await {
+            writer.write_frame(&answer).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn serve_tcp(
+    addr: SocketAddr,
+    cfg: TunnelConfig,
+    ids: Arc<AtomicU32>,
+) -> Result<(), std::io::Error> {
+    let mut listener = TcpListener::bind(addr).await?;
+    while let Ok((inbound, _)) = listener.accept().await {
+        let tunnel_id = ids.fetch_add(1, Ordering::SeqCst);
+        let handle = handle_tcp_client(tunnel_id, inbound, cfg.clone()).map(move |r| {
+            if let Err(e) = r {
+                error!("[{}]Failed to handle; error={}", tunnel_id, e);
+            }
+        });
+        tokio::spawn(handle);
+    }
+    Ok(())
+}
+
+async fn serve_udp(
+    addr: SocketAddr,
+    cfg: TunnelConfig,
+    ids: Arc<AtomicU32>,
+) -> Result<(), std::io::Error> {
+    let socket = UdpSocket::bind(addr).await?;
+    let (mut recv_half, mut send_half) = socket.split();
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel::<(Vec<u8>, SocketAddr)>();
+    tokio::spawn(async move {
+        while let Some((data, peer)) = reply_rx.recv().await {
+            if let Err(e) = send_half.send_to(&data[..], &peer).await {
+                error!("dns forward udp send error:{}", e);
+            }
+        }
+    });
+    let mut buf = vec![0u8; MAX_DNS_MESSAGE];
+    loop {
+        let (n, peer) = recv_half.recv_from(&mut buf).await?;
+        let query = buf[..n].to_vec();
+        let tunnel_id = ids.fetch_add(1, Ordering::SeqCst);
+        let cfg = cfg.clone();
+        let reply_tx = reply_tx.clone();
+        tokio::spawn(async move {
+            if let Some(answer) = answer_query(tunnel_id, &query, &cfg).await {
+                let _ = reply_tx.send((answer, peer));
+            }
+        });
+    }
+}
+
+/// A `dnsfwd://host:port` listener: a plain DNS server on UDP and TCP which
+/// resolves every query through the tunnel, so lookups of clients that cannot
+/// do DNS over SOCKS do not leak to the local network.
+pub async fn start_dns_forward_server(addr: &str, cfg: TunnelConfig) -> Result<(), Box<dyn Error>> {
+    let addr: SocketAddr = match addr.parse() {
+        Ok(a) => a,
+        Err(_) => return Err(make_error("dnsfwd listener needs an ip address")),
+    };
+    if cfg.resolvers.as_ref().map_or(true, |r| r.is_empty()) {
+        return Err(make_error("dnsfwd listener requires 'resolvers'"));
+    }
+    let ids = Arc::new(AtomicU32::new(0));
+    let (tcp, udp) = join(
+        serve_tcp(addr, cfg.clone(), ids.clone()),
+        serve_udp(addr, cfg, ids),
+    )
+    .await;
+    tcp?;
+    udp?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_servfail() {
+        // id 0xabcd, RD, one question "a.cn" IN A
+        let query = [
+            0xab, 0xcd, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 1, b'a', 2, b'c', b'n', 0, 0, 1, 0, 1,
+        ];
+        let res = servfail(&query).unwrap();
+        assert_eq!(&res[..4], &[0xab, 0xcd, 0x81, 0x82]);
+        assert_eq!(&res[4..12], &[0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&res[12..], &query[12..]);
+        assert!(servfail(&query[..15]).is_none());
+    }
+}
@@ -0,0 +1,119 @@
+use super::dns_forward::answer_query;
+use crate::config::TunnelConfig;
+use crate::utils::make_error;
+
+use bytes::Bytes;
+use std::error::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const DNS_MESSAGE_TYPE: &str = "application/dns-message";
+const MAX_DNS_MESSAGE: usize = 65535;
+
+struct DohRequest {
+    method: String,
+    path: String,
+    content_length: usize,
+}
+
+fn parse_request(head: &[u8]) -> Option<DohRequest> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+    req.parse(head).ok()?;
+    let content_length = req
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("Content-Length"))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(0);
+    Some(DohRequest {
+        method: String::from(req.method?),
+        path: String::from(req.path?),
+        content_length,
+    })
+}
+
+/// The base64url `dns` parameter of a GET query string.
+fn dns_param(path: &str) -> Option<Vec<u8>> {
+    let query = path.splitn(2, '?').nth(1)?;
+    let value = query.split('&').find(|kv| kv.starts_with("dns="))?;
+    let value = &value["dns=".len()..];
+    base64::decode_config(value.trim_end_matches('='), base64::URL_SAFE_NO_PAD).ok()
+}
+
+/// A GET or POST of the listener's DoH path, answered only when the
+/// listener has resolvers.
+pub fn is_doh_request(head: &[u8], cfg: &TunnelConfig) -> bool {
+    if cfg.resolvers.as_ref().map_or(true, |r| r.is_empty()) {
+        return false;
+    }
+    match parse_request(head) {
+        Some(req) => {
+            let path = req.path.split('?').next().unwrap_or("");
+            (req.method == "GET" || req.method == "POST") && path == cfg.doh_path()
+        }
+        None => false,
+    }
+}
+
+/// Answers one DNS-over-HTTPS (RFC 8484) query of a browser pointed at the
+/// local listener, resolved through the tunnel like `dnsfwd://` queries.
+pub async fn serve_doh(
+    tunnel_id: u32,
+    mut inbound: TcpStream,
+    head: &[u8],
+    body: Bytes,
+    cfg: &TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let req = match parse_request(head) {
+        Some(r) => r,
+        None => return Err(make_error("invalid doh request")),
+    };
+    let query = if req.method == "POST" {
+        if req.content_length > MAX_DNS_MESSAGE || body.len() > req.content_length {
+            return Err(make_error("invalid doh request body"));
+        }
+        let mut query = body.to_vec();
+        query.resize(req.content_length, 0);
+        inbound.read_exact(&mut query[body.len()..]).await?;
+        Some(query)
+    } else {
+        dns_param(req.path.as_str())
+    };
+    let answer = match query {
+        Some(q) => answer_query(tunnel_id, &q, cfg).await,
+        None => None,
+    };
+    let answer = match answer {
+        Some(a) => a,
+        None => {
+            let res = "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+            inbound.write_all(res.as_bytes()).await?;
+            return Ok(());
+        }
+    };
+    let res = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Cache-Control: max-age=0\r\nConnection: close\r\n\r\n",
+        DNS_MESSAGE_TYPE,
+        answer.len()
+    );
+    inbound.write_all(res.as_bytes()).await?;
+    inbound.write_all(&answer[..]).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dns_param() {
+        let path = "/dns-query?ct=x&dns=q80BAAABAAAAAAAAAWECY24AAAEAAQ";
+        let query = dns_param(path).unwrap();
+        assert_eq!(&query[..4], &[0xab, 0xcd, 0x01, 0x00]);
+        assert_eq!(query.len(), 22);
+        assert!(dns_param("/dns-query").is_none());
+    }
+}
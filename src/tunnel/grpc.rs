@@ -0,0 +1,109 @@
+use super::rmux::serve_rmux_session;
+use crate::config::TunnelConfig;
+use crate::transport::{
+    grpc_path, listener_server_config, tls_accept, GrpcReader, GrpcWriter, H2Reader, H2Writer,
+    GRPC_CONTENT_TYPE,
+};
+use async_tls::TlsAcceptor;
+use bytes::Bytes;
+use futures::FutureExt;
+use h2::server::SendResponse;
+use h2::RecvStream;
+use http::{Request, Response, StatusCode};
+use std::error::Error;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+
+fn is_grpc_request(req: &Request<RecvStream>, path: &str) -> bool {
+    if req.uri().path() != path {
+        return false;
+    }
+    match req
+        .headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(ct) => ct.starts_with(GRPC_CONTENT_TYPE),
+        None => false,
+    }
+}
+
+async fn handle_grpc_call(
+    tunnel_id: u32,
+    req: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    path: String,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    if !is_grpc_request(&req, path.as_str()) {
+        let res = Response::builder().status(StatusCode::NOT_FOUND).body(())?;
+        respond.send_response(res, true)?;
+        return Ok(());
+    }
+    let res = Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", GRPC_CONTENT_TYPE)
+        .body(())?;
+    let send = respond.send_response(res, false)?;
+    let reader = GrpcReader::new(H2Reader::new(req.into_body()));
+    let mut writer = GrpcWriter::new(H2Writer::new(send), true);
+    let rc = serve_rmux_session(tunnel_id, reader, &mut writer, &cfg).await;
+    let _ = writer.shutdown().await;
+    rc?;
+    Ok(())
+}
+
+async fn handle_grpc_conn(
+    tunnel_id: u32,
+    inbound: TcpStream,
+    acceptor: TlsAcceptor,
+    path: String,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let tls = tls_accept(inbound, &acceptor).await?;
+    let mut conn = h2::server::handshake(tls).await?;
+    while let Some(r) = conn.accept().await {
+        let (req, respond) = r?;
+        let handle =
+            handle_grpc_call(tunnel_id, req, respond, path.clone(), cfg.clone()).map(move |r| {
+                if let Err(e) = r {
+                    error!("[{}]Failed to handle grpc call; error={}", tunnel_id, e);
+                }
+            });
+        tokio::spawn(handle);
+    }
+    Ok(())
+}
+
+/// Serves rmux sessions as calls of a single bidi streaming gRPC method,
+/// the service name is taken from the listen url path.
+pub async fn start_grpc_server(
+    addr: &str,
+    service: &str,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let server_config = listener_server_config(&cfg, "grpc", &["h2"], None).await?;
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+    let path = grpc_path(service);
+    let mut listener = TcpListener::bind(addr).await?;
+    let tunnel_id_seed = AtomicU32::new(0);
+    while let Ok((inbound, _)) = listener.accept().await {
+        let tunnel_id = tunnel_id_seed.fetch_add(1, Ordering::SeqCst);
+        let handle = handle_grpc_conn(
+            tunnel_id,
+            inbound,
+            acceptor.clone(),
+            path.clone(),
+            cfg.clone(),
+        )
+        .map(move |r| {
+            if let Err(e) = r {
+                error!("[{}]Failed to handle; error={}", tunnel_id, e);
+            }
+        });
+        tokio::spawn(handle);
+    }
+    Ok(())
+}
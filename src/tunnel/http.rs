@@ -1,13 +1,27 @@
-use super::relay::{relay_connection, relay_stream};
-use crate::utils::{make_error, read_until_separator};
+use super::doh::{is_doh_request, serve_doh};
+use super::mitm::mitm_acceptor;
+use super::pac::{is_pac_request, serve_pac};
+use super::proxy_auth::{auth_challenge, authorize};
+use super::relay::{
+    is_blocked, open_rule_stream, relay, relay_connection, relay_stream, rule_channel, select_rule,
+    select_rule_in,
+};
+use crate::config::MitmConfig;
+use crate::transport::{new_client_config, tls_accept, tls_connect_io};
+use crate::utils::{
+    make_error, read_until_separator_timeout, register_stream_metrics, DEFAULT_HANDSHAKE_TIMEOUT,
+    DEFAULT_MAX_HEAD_SIZE,
+};
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use httparse::Status;
 use std::collections::vec_deque::VecDeque;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Write as fmt_write;
-use std::net::Shutdown;
+use std::net::{IpAddr, Shutdown};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use tokio::io::AsyncRead;
 use tokio::io::AsyncWriteExt;
@@ -17,6 +31,10 @@ use unicase::Ascii;
 use crate::config::TunnelConfig;
 use crate::utils::fill_read_buf;
 
+const HTTP_HEAD_SEPARATORS: &[&str] = &["\r\n\r\n", "\n\n"];
+const BLOCKED_RESPONSE: &str =
+    "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
 #[derive(Clone, PartialEq, Debug, Default)]
 pub struct Header {
     /// The name portion of a header.
@@ -110,6 +128,8 @@ pub struct HttpReader<'a, T: ?Sized> {
     body_tail: VecDeque<u8>,
     //remote_host: String,
     counter: u32,
+    /// headers to set on every request, an empty value removes the header
+    header_rules: Option<&'a HashMap<String, String>>,
 }
 
 fn newHttpReader<'a, T>(sock: &'a mut T) -> HttpReader<'a, T>
@@ -125,10 +145,11 @@ where
         body_tail: VecDeque::new(),
         //remote_host: String::new(),
         counter: 0,
+        header_rules: None,
     }
 }
 
-impl<T: AsyncRead + Unpin + ?Sized> HttpReader<'_, T> {
+impl<'a, T: AsyncRead + Unpin + ?Sized> HttpReader<'a, T> {
     // pub fn get_remote_addr(&self) -> &str {
     //     self.remote_host.as_str()
     // }
@@ -136,8 +157,21 @@ impl<T: AsyncRead + Unpin + ?Sized> HttpReader<'_, T> {
         self.recv_buf.reserve(b.len());
         self.recv_buf.put_slice(b);
     }
+    pub fn set_header_rules(&mut self, rules: Option<&'a HashMap<String, String>>) {
+        self.header_rules = rules;
+    }
     fn parse_request(&mut self) -> Result<(bool, String, i64), std::io::Error> {
-        parse_request(&mut self.recv_buf, Some(&mut self.http_buf))
+        let r = parse_request(
+            &mut self.recv_buf,
+            Some(&mut self.http_buf),
+            self.header_rules,
+        )?;
+        // a body that came along with the head is relayed before the next head
+        if r.0 && r.2 != 0 {
+            self.state = HttpDecodeState::DecodingBody;
+            self.body_length = r.2;
+        }
+        Ok(r)
     }
 }
 
@@ -185,6 +219,7 @@ fn check_body_complete_and_fill_read_buf(
 fn parse_request(
     recv_buf: &mut BytesMut,
     http_buf: Option<&mut BytesMut>,
+    header_rules: Option<&HashMap<String, String>>,
 ) -> Result<(bool, String, i64), std::io::Error> {
     let mut success = false;
     let mut body_length: i64 = 0;
@@ -250,6 +285,15 @@ fn parse_request(
         }
         hreq.headers.push(header);
     }
+    for (name, value) in header_rules.into_iter().flatten() {
+        hreq.remove_header(name.as_str());
+        if !value.is_empty() {
+            hreq.headers.push(Header {
+                name: Ascii::new(name.clone()),
+                value: Bytes::copy_from_slice(value.as_bytes()),
+            });
+        }
+    }
     if let Some(v) = req.path {
         if v.starts_with("http://") {
             let vv = &v[7..];
@@ -298,6 +342,7 @@ impl<T: AsyncRead + ?Sized + Unpin> AsyncRead for HttpReader<'_, T> {
             body_tail,
             //remote_host,
             counter,
+            header_rules,
         } = &mut *self;
         let pr = Pin::new(reader);
         let n = fill_read_buf(http_buf, buf);
@@ -341,7 +386,7 @@ impl<T: AsyncRead + ?Sized + Unpin> AsyncRead for HttpReader<'_, T> {
             }
         }
         if *state == HttpDecodeState::DecodingHeader {
-            match parse_request(recv_buf, Some(http_buf)) {
+            match parse_request(recv_buf, Some(http_buf), *header_rules) {
                 Ok((success, _target, blen)) => {
                     if success {
                         if 0 == blen {
@@ -376,7 +421,24 @@ pub async fn handle_http(
     mut inbound: TcpStream,
     cfg: &TunnelConfig,
 ) -> Result<(), Box<dyn Error>> {
-    let (head, body) = read_until_separator(&mut inbound, "\r\n\r\n").await?;
+    let (_, head, body) = read_until_separator_timeout(
+        &mut inbound,
+        HTTP_HEAD_SEPARATORS,
+        DEFAULT_MAX_HEAD_SIZE,
+        DEFAULT_HANDSHAKE_TIMEOUT,
+    )
+    .await?;
+    // browsers fetch the PAC file before they know about any proxy login
+    if is_pac_request(&head, cfg) {
+        return serve_pac(tunnel_id, inbound, &head, cfg).await;
+    }
+    if is_doh_request(&head, cfg) {
+        return serve_doh(tunnel_id, inbound, &head, body, cfg).await;
+    }
+    if !authorize(&head, cfg) {
+        inbound.write_all(auth_challenge().as_bytes()).await?;
+        return Ok(());
+    }
 
     let (mut ri, mut wi) = inbound.split();
     let mut hreader = newHttpReader(&mut ri);
@@ -397,6 +459,11 @@ pub async fn handle_http(
     if target.find(':').is_none() {
         target.push_str(":80");
     }
+    if is_blocked(cfg, target.as_str()) {
+        info!("[{}]Blocked HTTP proxy to {}", tunnel_id, target);
+        wi.write_all(BLOCKED_RESPONSE.as_bytes()).await?;
+        return Ok(());
+    }
     info!("[{}]Handle HTTP proxy to {} ", tunnel_id, target);
     relay_stream(tunnel_id, &mut hreader, &mut wi, target, cfg, Vec::new()).await?;
     let _ = inbound.shutdown(Shutdown::Both);
@@ -408,9 +475,19 @@ pub async fn handle_https(
     mut inbound: TcpStream,
     cfg: &TunnelConfig,
 ) -> Result<(), Box<dyn Error>> {
-    let (head, _) = read_until_separator(&mut inbound, "\r\n\r\n").await?;
+    let (_, head, _) = read_until_separator_timeout(
+        &mut inbound,
+        HTTP_HEAD_SEPARATORS,
+        DEFAULT_MAX_HEAD_SIZE,
+        DEFAULT_HANDSHAKE_TIMEOUT,
+    )
+    .await?;
+    if !authorize(&head, cfg) {
+        inbound.write_all(auth_challenge().as_bytes()).await?;
+        return Ok(());
+    }
     let mut hbuf = BytesMut::from(&head[..]);
-    let target = match parse_request(&mut hbuf, None) {
+    let target = match parse_request(&mut hbuf, None, None) {
         Err(_e) => {
             return Err(make_error("failed to parse http header"));
         }
@@ -421,11 +498,106 @@ pub async fn handle_https(
             remote
         }
     };
+    if is_blocked(cfg, target.as_str()) {
+        info!("[{}]Blocked HTTPS proxy to {}", tunnel_id, target);
+        inbound.write_all(BLOCKED_RESPONSE.as_bytes()).await?;
+        return Ok(());
+    }
 
     let conn_res = "HTTP/1.0 200 Connection established\r\n\r\n";
     inbound.write_all(conn_res.as_bytes()).await?;
 
+    if let Some(mitm) = cfg.mitm.as_ref() {
+        if let Some(host) = mitm_host(mitm, target.as_str()) {
+            let host = String::from(host);
+            return handle_mitm(tunnel_id, inbound, mitm, host, target, cfg).await;
+        }
+    }
     info!("[{}]Handle HTTPS proxy to {} ", tunnel_id, target);
     relay_connection(tunnel_id, inbound, cfg, target, Vec::new()).await?;
     Ok(())
 }
+
+fn mitm_host<'a>(mitm: &MitmConfig, target: &'a str) -> Option<&'a str> {
+    let host = match target.rfind(':') {
+        Some(i) => &target[..i],
+        None => target,
+    };
+    // leaf certs are only issued for dns names
+    if host.starts_with('[') || host.parse::<IpAddr>().is_ok() || !mitm.is_match(host) {
+        return None;
+    }
+    Some(host)
+}
+
+fn request_path(head: &[u8]) -> String {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(head) {
+        Ok(_) => String::from(req.path.unwrap_or("/")),
+        Err(_) => String::from("/"),
+    }
+}
+
+/// Terminates the client's TLS with a leaf cert from the local CA, then
+/// relays the decrypted requests with the header rules applied over a new
+/// TLS session to the real target. The channel is picked by `host/path` of
+/// the first request.
+async fn handle_mitm(
+    tunnel_id: u32,
+    inbound: TcpStream,
+    mitm: &MitmConfig,
+    host: String,
+    target: String,
+    cfg: &TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let acceptor = mitm_acceptor(mitm, host.as_str())?;
+    let mut local = tls_accept(inbound, &acceptor).await?;
+    let (_, head, body) = read_until_separator_timeout(
+        &mut local,
+        HTTP_HEAD_SEPARATORS,
+        DEFAULT_MAX_HEAD_SIZE,
+        DEFAULT_HANDSHAKE_TIMEOUT,
+    )
+    .await?;
+    let route = format!("{}{}", host, request_path(&head));
+    let rule = match mitm.pac.as_ref() {
+        Some(rules) => select_rule_in(rules, route.as_str()),
+        None => None,
+    };
+    let rule = match rule.or_else(|| select_rule(cfg, target.as_str())) {
+        Some(r) => r,
+        None => return Err(make_error("no valid channel found.")),
+    };
+    let channel = rule_channel(rule);
+    info!("[{}]Intercept HTTPS {} via {}", tunnel_id, route, channel);
+    let _metrics = register_stream_metrics(channel.as_str(), target.as_str());
+    let mut remote = match open_rule_stream(rule, target).await {
+        Ok(s) => s,
+        Err(e) => return Err(make_error(&e.to_string())),
+    };
+    let r = async {
+        let (ro, wo) = remote.split();
+        let tls_cfg = Arc::new(new_client_config(&["http/1.1"]));
+        let upstream = tls_connect_io(ro, wo, host.as_str(), tls_cfg).await?;
+        let (mut ru, mut wu) = tokio::io::split(upstream);
+        let (mut rl, mut wl) = tokio::io::split(local);
+        let mut hreader = newHttpReader(&mut rl);
+        hreader.set_header_rules(mitm.headers.as_ref());
+        hreader.add_recv_content(&head);
+        hreader.add_recv_content(&body);
+        hreader.parse_request()?;
+        relay(
+            tunnel_id,
+            &mut hreader,
+            &mut wl,
+            &mut ru,
+            &mut wu,
+            cfg.relay_buf_size(),
+        )
+        .await
+    }
+    .await;
+    let _ = remote.close();
+    r
+}
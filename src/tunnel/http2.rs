@@ -0,0 +1,197 @@
+use super::proxy_auth::{auth_challenge_values, authorize_request};
+use super::relay::relay_stream;
+use crate::config::TunnelConfig;
+use crate::rmux::DEFAULT_REPLAY_WINDOW_SECS;
+use crate::transport::{
+    check_h2_auth_token, listener_server_config, tls_accept, H2Reader, H2Writer, H2_AUTH_HEADER,
+    H2_TARGET_HEADER,
+};
+use crate::utils::make_error;
+use async_tls::TlsAcceptor;
+use bytes::Bytes;
+use futures::FutureExt;
+use h2::server::SendResponse;
+use h2::RecvStream;
+use http::{Method, Request, Response, StatusCode};
+use std::error::Error;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+
+fn header_str<'a>(req: &'a Request<RecvStream>, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+async fn handle_h2_request(
+    tunnel_id: u32,
+    req: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    path: String,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let key = match cfg.cipher.as_ref() {
+        Some(c) => c.key.as_str(),
+        None => "",
+    };
+    let window = cfg.replay_window_secs.unwrap_or(DEFAULT_REPLAY_WINDOW_SECS);
+    let target = match header_str(&req, H2_TARGET_HEADER) {
+        Some(t) if req.uri().path() == path.as_str() => match header_str(&req, H2_AUTH_HEADER) {
+            Some(token) if check_h2_auth_token(key, t, token, window) => Some(String::from(t)),
+            _ => None,
+        },
+        _ => None,
+    };
+    let target = match target {
+        Some(t) => t,
+        None => {
+            // look like any other https site to unauthenticated requests
+            let res = Response::builder().status(StatusCode::NOT_FOUND).body(())?;
+            respond.send_response(res, true)?;
+            return Ok(());
+        }
+    };
+    let res = Response::builder().status(StatusCode::OK).body(())?;
+    let send = respond.send_response(res, false)?;
+    info!("[{}]Handle h2 stream to {}", tunnel_id, target);
+    let mut reader = H2Reader::new(req.into_body());
+    let mut writer = H2Writer::new(send);
+    relay_stream(
+        tunnel_id,
+        &mut reader,
+        &mut writer,
+        target,
+        &cfg,
+        Vec::new(),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn handle_h2_conn(
+    tunnel_id: u32,
+    inbound: TcpStream,
+    acceptor: TlsAcceptor,
+    path: String,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let tls = tls_accept(inbound, &acceptor).await?;
+    let mut conn = h2::server::handshake(tls).await?;
+    // accept() also drives the connection for the spawned streams
+    while let Some(r) = conn.accept().await {
+        let (req, respond) = r?;
+        let handle =
+            handle_h2_request(tunnel_id, req, respond, path.clone(), cfg.clone()).map(move |r| {
+                if let Err(e) = r {
+                    error!("[{}]Failed to handle h2 stream; error={}", tunnel_id, e);
+                }
+            });
+        tokio::spawn(handle);
+    }
+    Ok(())
+}
+
+/// One CONNECT stream of an HTTP/2 client of the local proxy port.
+async fn handle_h2_connect(
+    tunnel_id: u32,
+    req: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    if req.method() != Method::CONNECT {
+        let res = Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(())?;
+        respond.send_response(res, true)?;
+        return Ok(());
+    }
+    let target = match req.uri().authority() {
+        Some(a) if a.port().is_some() => a.to_string(),
+        _ => {
+            let res = Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(())?;
+            respond.send_response(res, true)?;
+            return Ok(());
+        }
+    };
+    if !authorize_request(
+        &cfg,
+        "CONNECT",
+        target.as_str(),
+        header_str(&req, "proxy-authorization"),
+    ) {
+        let mut res = Response::builder().status(StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+        for value in auth_challenge_values() {
+            res = res.header("proxy-authenticate", value);
+        }
+        respond.send_response(res.body(())?, true)?;
+        return Ok(());
+    }
+    let res = Response::builder().status(StatusCode::OK).body(())?;
+    let send = respond.send_response(res, false)?;
+    info!("[{}]Handle HTTP/2 CONNECT to {}", tunnel_id, target);
+    let mut reader = H2Reader::new(req.into_body());
+    let mut writer = H2Writer::new(send);
+    relay_stream(
+        tunnel_id,
+        &mut reader,
+        &mut writer,
+        target,
+        &cfg,
+        Vec::new(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Serves a cleartext HTTP/2 client of the local proxy port, every stream is
+/// a CONNECT of its own so one connection carries many proxied ones.
+pub async fn handle_h2_proxy(
+    tunnel_id: u32,
+    inbound: TcpStream,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let mut conn = h2::server::handshake(inbound).await?;
+    while let Some(r) = conn.accept().await {
+        let (req, respond) = r?;
+        let handle = handle_h2_connect(tunnel_id, req, respond, cfg.clone()).map(move |r| {
+            if let Err(e) = r {
+                error!("[{}]Failed to handle h2 stream; error={}", tunnel_id, e);
+            }
+        });
+        tokio::spawn(handle);
+    }
+    Ok(())
+}
+
+pub async fn start_h2_server(
+    addr: &str,
+    path: &str,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    // the cipher key is all that authenticates a stream
+    if cfg.cipher.is_none() {
+        return Err(make_error("h2 listener requires 'cipher'"));
+    }
+    let server_config = listener_server_config(&cfg, "h2", &["h2"], None).await?;
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+    let mut listener = TcpListener::bind(addr).await?;
+    let tunnel_id_seed = AtomicU32::new(0);
+    while let Ok((inbound, _)) = listener.accept().await {
+        let tunnel_id = tunnel_id_seed.fetch_add(1, Ordering::SeqCst);
+        let handle = handle_h2_conn(
+            tunnel_id,
+            inbound,
+            acceptor.clone(),
+            String::from(path),
+            cfg.clone(),
+        )
+        .map(move |r| {
+            if let Err(e) = r {
+                error!("[{}]Failed to handle; error={}", tunnel_id, e);
+            }
+        });
+        tokio::spawn(handle);
+    }
+    Ok(())
+}
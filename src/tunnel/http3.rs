@@ -0,0 +1,136 @@
+use super::relay::relay_stream;
+use crate::config::TunnelConfig;
+use crate::rmux::DEFAULT_REPLAY_WINDOW_SECS;
+use crate::transport::{
+    check_h2_auth_token, drain_uni_streams, h3_listen, header_value, open_control_stream,
+    read_headers, write_headers, H3Reader, H3Writer, H2_AUTH_HEADER, H2_TARGET_HEADER, H3_PROTOCOL,
+};
+use crate::utils::make_error;
+use futures::{FutureExt, StreamExt};
+use quinn::{Connecting, NewConnection, RecvStream, SendStream};
+use std::error::Error;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Accepts both a plain CONNECT and an extended CONNECT on `path`, as long
+/// as it carries a valid auth token for its target.
+fn request_target(
+    fields: &[(String, String)],
+    path: &str,
+    key: &str,
+    window: u64,
+) -> Option<String> {
+    if header_value(fields, ":method") != Some("CONNECT") {
+        return None;
+    }
+    if let Some(protocol) = header_value(fields, ":protocol") {
+        if protocol != H3_PROTOCOL || header_value(fields, ":path") != Some(path) {
+            return None;
+        }
+    }
+    let target = header_value(fields, H2_TARGET_HEADER)?;
+    match header_value(fields, H2_AUTH_HEADER) {
+        Some(token) if check_h2_auth_token(key, target, token, window) => {
+            Some(String::from(target))
+        }
+        _ => None,
+    }
+}
+
+async fn handle_h3_request(
+    tunnel_id: u32,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    path: String,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let key = match cfg.cipher.as_ref() {
+        Some(c) => c.key.as_str(),
+        None => "",
+    };
+    let window = cfg.replay_window_secs.unwrap_or(DEFAULT_REPLAY_WINDOW_SECS);
+    let fields = read_headers(&mut recv).await?;
+    let target = match request_target(&fields, path.as_str(), key, window) {
+        Some(t) => t,
+        None => {
+            // look like any other https site to unauthenticated requests
+            write_headers(&mut send, &[(":status", "404")]).await?;
+            let _ = send.finish().await;
+            return Ok(());
+        }
+    };
+    write_headers(&mut send, &[(":status", "200")]).await?;
+    info!("[{}]Handle h3 stream to {}", tunnel_id, target);
+    let mut reader = H3Reader::new(recv);
+    let mut writer = H3Writer::new(send);
+    relay_stream(
+        tunnel_id,
+        &mut reader,
+        &mut writer,
+        target,
+        &cfg,
+        Vec::new(),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn handle_h3_conn(
+    tunnel_id: u32,
+    connecting: Connecting,
+    path: String,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let NewConnection {
+        connection,
+        uni_streams,
+        mut bi_streams,
+        ..
+    } = connecting.await?;
+    let _control = open_control_stream(&connection).await?;
+    tokio::spawn(drain_uni_streams(uni_streams));
+    while let Some(stream) = bi_streams.next().await {
+        let (send, recv) = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                info!("[{}]h3 connection closed:{}", tunnel_id, e);
+                break;
+            }
+        };
+        let handle =
+            handle_h3_request(tunnel_id, send, recv, path.clone(), cfg.clone()).map(move |r| {
+                if let Err(e) = r {
+                    error!("[{}]Failed to handle h3 stream; error={}", tunnel_id, e);
+                }
+            });
+        tokio::spawn(handle);
+    }
+    Ok(())
+}
+
+pub async fn start_h3_server(
+    addr: &str,
+    path: &str,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let (cert, key) = match (cfg.cert.as_ref(), cfg.key.as_ref()) {
+        (Some(c), Some(k)) => (c.clone(), k.clone()),
+        _ => return Err(make_error("h3 listener requires 'cert' and 'key'")),
+    };
+    // the cipher key is all that authenticates a stream
+    if cfg.cipher.is_none() {
+        return Err(make_error("h3 listener requires 'cipher'"));
+    }
+    let (_endpoint, mut incoming) = h3_listen(addr, cert.as_str(), key.as_str()).await?;
+    let tunnel_id_seed = AtomicU32::new(0);
+    while let Some(connecting) = incoming.next().await {
+        let tunnel_id = tunnel_id_seed.fetch_add(1, Ordering::SeqCst);
+        let handle =
+            handle_h3_conn(tunnel_id, connecting, String::from(path), cfg.clone()).map(move |r| {
+                if let Err(e) = r {
+                    error!("[{}]Failed to handle; error={}", tunnel_id, e);
+                }
+            });
+        tokio::spawn(handle);
+    }
+    Ok(())
+}
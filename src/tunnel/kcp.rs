@@ -0,0 +1,27 @@
+use super::rmux::serve_rmux_session;
+use crate::config::TunnelConfig;
+use crate::transport::kcp_listen;
+use futures::FutureExt;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+pub async fn start_kcp_server(addr: &str, cfg: TunnelConfig) -> Result<(), std::io::Error> {
+    let kcp_cfg = cfg.kcp.clone().unwrap_or_default();
+    let mut listener = kcp_listen(addr, kcp_cfg).await?;
+    let tunnel_id_seed = AtomicU32::new(0);
+    while let Some((stream, peer)) = listener.accept().await {
+        let tunnel_id = tunnel_id_seed.fetch_add(1, Ordering::SeqCst);
+        info!("[{}]Accept kcp session from {}", tunnel_id, peer);
+        let cfg = cfg.clone();
+        let handle = async move {
+            let (read, write) = tokio::io::split(stream);
+            serve_rmux_session(tunnel_id, read, write, &cfg).await
+        }
+        .map(move |r| {
+            if let Err(e) = r {
+                error!("[{}]Failed to handle; error={}", tunnel_id, e);
+            }
+        });
+        tokio::spawn(handle);
+    }
+    Ok(())
+}
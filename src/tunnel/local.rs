@@ -1,22 +1,44 @@
+use super::detect::{detect_inbound, Inbound, DETECT_PREFIX_LEN};
+use super::dns::start_dns_server;
+use super::dns_forward::start_dns_forward_server;
+use super::grpc::start_grpc_server;
 use super::http::handle_http;
 use super::http::handle_https;
+use super::http2::{handle_h2_proxy, start_h2_server};
+use super::http3::start_h3_server;
+use super::kcp::start_kcp_server;
+use super::quic::start_quic_server;
 use super::relay::relay_connection;
-use super::rmux::handle_rmux;
-use super::socks5::handle_socks5;
+use super::rmux::{handle_rmux, handle_rmux_client_auth, handle_rmux_noise, handle_rmux_tls};
+use super::shadowsocks::{handle_shadowsocks, shadowsocks_cipher};
+use super::sniff::relay_sniffed;
+use super::socks4::handle_socks4;
+use super::socks5::{handle_socks5, handle_socks5_tls};
 use super::tls::handle_tls;
-use super::tls::valid_tls_version;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use super::tproxy::start_tproxy_server;
+use super::trojan::{handle_trojan, trojan_hashes};
+use super::tun::start_tun_server;
+use super::udp_forward::start_udp_forward_server;
+#[cfg(unix)]
+use super::unix::start_unix_server;
+use super::vhost::handle_vhost;
 use super::ws::handle_websocket;
-use crate::utils::{get_origin_dst, make_error};
+use crate::rmux::allow_reverse_ports;
+use crate::transport::{listener_server_config, plugin_listen, ClientAuthAcceptor, NoiseKeys};
+use crate::utils::{enable_tfo_listener, get_origin_dst, make_error, DEFAULT_HANDSHAKE_TIMEOUT};
 
+use async_tls::TlsAcceptor;
 use futures::FutureExt;
 use std::env;
 use std::error::Error;
 use tokio::net::{TcpListener, TcpStream};
 
 use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
 use url::Url;
 
-use crate::config::TunnelConfig;
+use crate::config::{PACConfig, TunnelConfig};
 
 async fn handle_inbound(
     tunnel_id: u32,
@@ -32,54 +54,37 @@ async fn handle_inbound(
         return Ok(());
     }
 
-    let mut peek_buf = [0u8; 3];
-    inbound.peek(&mut peek_buf).await?;
-    match peek_buf[0] {
-        5 => {
-            //socks5
-            info!("[{}]Accept client as SOCKS5 proxy.", tunnel_id);
-            handle_socks5(tunnel_id, inbound, &cfg).await?;
-            return Ok(());
-        }
-        4 => {
-            //socks4
-            error!("socks4 not supported!");
-            return Err(make_error("socks4 unimplemented"));
+    let mut prefix = [0u8; DETECT_PREFIX_LEN];
+    let n = match tokio::time::timeout(DEFAULT_HANDSHAKE_TIMEOUT, inbound.peek(&mut prefix)).await {
+        Ok(r) => r?,
+        Err(_) => return Err(make_error("timeout waiting for client data")),
+    };
+    if let Some(detector) = detect_inbound(&prefix[..n]) {
+        info!("[{}]Accept client as {}.", tunnel_id, detector.name);
+        // a TLS hello carries no credentials, its SNI would open the proxy to anyone
+        if detector.inbound == Inbound::Tls && cfg.requires_auth() {
+            return Err(make_error(
+                "tls clients are refused by a listener with users",
+            ));
         }
-        _ => {
-            //info!("Not socks protocol:{}", _data[0]);
+        match detector.inbound {
+            Inbound::Socks5 => handle_socks5(tunnel_id, inbound, &cfg).await?,
+            Inbound::Socks4 => handle_socks4(tunnel_id, inbound, &cfg).await?,
+            Inbound::Tls => handle_tls(tunnel_id, inbound, &cfg).await?,
+            Inbound::Http2 => handle_h2_proxy(tunnel_id, inbound, cfg).await?,
+            Inbound::HttpConnect => handle_https(tunnel_id, inbound, &cfg).await?,
+            Inbound::Http => handle_http(tunnel_id, inbound, &cfg).await?,
         }
-    }
-    if valid_tls_version(&peek_buf[..]) {
-        info!("[{}]Accept client as SNI proxy.", tunnel_id);
-        handle_tls(tunnel_id, inbound, &cfg).await?;
         return Ok(());
     }
-    if let Ok(prefix_str) = std::str::from_utf8(&peek_buf) {
-        let prefix_str = prefix_str.to_uppercase();
-        match prefix_str.as_str() {
-            "GET" | "PUT" | "POS" | "DEL" | "OPT" | "TRA" | "PAT" | "HEA" | "CON" | "UPG" => {
-                info!(
-                    "[{}]Accept client as HTTP proxy with method:{}",
-                    tunnel_id, prefix_str
-                );
-                //http proxy
-                if prefix_str.as_str() == "CON" {
-                    handle_https(tunnel_id, inbound, &cfg).await?;
-                } else {
-                    handle_http(tunnel_id, inbound, &cfg).await?;
-                }
-                return Ok(());
-            }
-            _ => {
-                //nothing
-            }
-        };
-    }
     if let Some(dst) = get_origin_dst(&inbound) {
-        let target = format!("{}:{}", dst.ip().to_string(), dst.port());
+        if cfg.requires_auth() {
+            return Err(make_error(
+                "transparent clients are refused by a listener with users",
+            ));
+        }
         let relay = async move {
-            let _ = relay_connection(tunnel_id, inbound, &cfg, target, Vec::new()).await;
+            let _ = relay_sniffed(tunnel_id, inbound, &cfg, dst).await;
         };
         tokio::spawn(relay);
         return Ok(());
@@ -87,20 +92,98 @@ async fn handle_inbound(
     Ok(())
 }
 
+/// Connections sent to a `redirect://` listener by iptables REDIRECT or pf
+/// `rdr`: the target is the original destination of the socket, nothing is
+/// read from the client first.
+async fn handle_redirect(
+    tunnel_id: u32,
+    inbound: TcpStream,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let dst = match get_origin_dst(&inbound) {
+        Some(d) => d,
+        None => return Err(make_error("no original destination")),
+    };
+    // dialed directly, relaying would connect back to ourselves
+    if inbound.local_addr().ok() == Some(dst) {
+        return Err(make_error("connection was not redirected"));
+    }
+    info!("[{}]Handle redirected connection to {}", tunnel_id, dst);
+    relay_sniffed(tunnel_id, inbound, &cfg, dst).await
+}
+
+/// Connections of a `forward://` listener all go to the one configured target.
+async fn handle_forward(
+    tunnel_id: u32,
+    inbound: TcpStream,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let target = match cfg.forward.as_ref() {
+        Some(f) => f.target.clone(),
+        None => return Err(make_error("no forward target")),
+    };
+    info!("[{}]Handle forward connection to {}", tunnel_id, target);
+    relay_connection(tunnel_id, inbound, &cfg, target, Vec::new()).await
+}
+
 pub async fn start_tunnel_server(mut cfg: TunnelConfig) -> Result<(), Box<dyn Error>> {
     let mut listen_str = String::from(cfg.listen.as_str());
     if cfg.listen.find("://").is_none() {
         listen_str.insert_str(0, "local://");
     }
-    if listen_str.rfind(':') == listen_str.find(':') {
+    if listen_str.rfind(':') == listen_str.find(':') && !listen_str.starts_with("unix://") {
         let port = env::var("PORT").unwrap_or_else(|_| "3000".to_string());
         listen_str.push(':');
         listen_str.push_str(port.as_str());
     }
 
+    if listen_str.starts_with("forward://") || listen_str.starts_with("udpfwd://") {
+        let forward = match cfg.forward.as_ref() {
+            Some(f) => f.clone(),
+            None => return Err(make_error("forward listener requires 'forward'")),
+        };
+        // a fixed channel replaces the rules
+        if let Some(channel) = forward.channel {
+            cfg.pac = vec![PACConfig {
+                host: String::from(".*"),
+                domains: None,
+                cidr: None,
+                ports: None,
+                list: None,
+                channel,
+                bind_address: None,
+                bind_interface: None,
+                priority: None,
+                re: None,
+                domain_set: Default::default(),
+                nets: Default::default(),
+                port_ranges: Vec::new(),
+            }];
+        }
+    }
     for pac in cfg.pac.iter_mut() {
         pac.init();
     }
+    if let Some(resolvers) = cfg.resolvers.as_mut() {
+        for resolver in resolvers.iter_mut() {
+            resolver.init();
+        }
+    }
+    if let Some(limit) = cfg.rate_limit.as_mut() {
+        limit.init();
+    }
+    if let Some(shaping) = cfg.shaping.as_mut() {
+        shaping.init();
+    }
+    if let Some(mitm) = cfg.mitm.as_mut() {
+        mitm.init();
+    }
+    for key in cfg.keys.iter_mut().flatten() {
+        key.init();
+    }
+    if let Some(ports) = &cfg.reverse_ports {
+        allow_reverse_ports(ports);
+    }
 
     let listen_url = match Url::parse(listen_str.as_str()) {
         Err(e) => {
@@ -109,13 +192,128 @@ pub async fn start_tunnel_server(mut cfg: TunnelConfig) -> Result<(), Box<dyn Er
         }
         Ok(u) => u,
     };
+    #[cfg(unix)]
+    {
+        if listen_url.scheme() == "unix" {
+            let path = String::from(listen_url.path());
+            start_unix_server(path.as_str(), cfg).await?;
+            return Ok(());
+        }
+    }
     let addr = format!(
         "{}:{}",
         listen_url.host().unwrap(),
         listen_url.port().unwrap()
     );
+    // these listeners serve their own sockets, not the accept loop below
+    let unpluggable = [
+        "h2", "h3", "grpc", "kcp", "dns", "dnsfwd", "udpfwd", "quic", "tun", "tproxy",
+    ];
+    if cfg.plugin.is_some() && unpluggable.contains(&listen_url.scheme()) {
+        return Err(make_error("plugins only take plain tcp listeners"));
+    }
+
+    if listen_url.scheme() == "h2" {
+        let path = String::from(listen_url.path());
+        start_h2_server(addr.as_str(), path.as_str(), cfg).await?;
+        return Ok(());
+    }
+    if listen_url.scheme() == "h3" {
+        let path = String::from(listen_url.path());
+        start_h3_server(addr.as_str(), path.as_str(), cfg).await?;
+        return Ok(());
+    }
+    if listen_url.scheme() == "grpc" {
+        let service = String::from(listen_url.path());
+        start_grpc_server(addr.as_str(), service.as_str(), cfg).await?;
+        return Ok(());
+    }
+    if listen_url.scheme() == "kcp" {
+        start_kcp_server(addr.as_str(), cfg).await?;
+        return Ok(());
+    }
+    if listen_url.scheme() == "dns" {
+        let domain = String::from(listen_url.path().trim_matches('/'));
+        start_dns_server(addr.as_str(), domain.as_str(), cfg).await?;
+        return Ok(());
+    }
+    if listen_url.scheme() == "dnsfwd" {
+        start_dns_forward_server(addr.as_str(), cfg).await?;
+        return Ok(());
+    }
+    if listen_url.scheme() == "udpfwd" {
+        start_udp_forward_server(addr.as_str(), cfg).await?;
+        return Ok(());
+    }
+    if listen_url.scheme() == "quic" {
+        start_quic_server(addr.as_str(), cfg).await?;
+        return Ok(());
+    }
+    if listen_url.scheme() == "tun" {
+        let prefix = match listen_url.path().trim_matches('/') {
+            "" => "24",
+            p => p,
+        };
+        let prefix = String::from(prefix);
+        start_tun_server(addr.as_str(), prefix.as_str(), cfg).await?;
+        return Ok(());
+    }
+    if listen_url.scheme() == "tproxy" {
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        {
+            start_tproxy_server(addr.as_str(), cfg).await?;
+            return Ok(());
+        }
+        #[cfg(not(any(target_os = "android", target_os = "linux")))]
+        return Err(make_error("tproxy listener requires linux"));
+    }
+
+    let mut client_auth = None;
+    let tls_acceptor = if ["tls", "trojan", "socks5s"].contains(&listen_url.scheme()) {
+        let alpn: Vec<String> = match cfg.tls.as_ref().and_then(|t| t.alpn.clone()) {
+            Some(alpn) => alpn,
+            None => vec![String::from("http/1.1")],
+        };
+        let alpn: Vec<&str> = alpn.iter().map(|s| s.as_str()).collect();
+        let client_ca = cfg.tls.as_ref().and_then(|t| t.client_ca.clone());
+        let server_config =
+            listener_server_config(&cfg, listen_url.scheme(), &alpn, client_ca.as_deref()).await?;
+        if let (Some(ca), "tls") = (client_ca.as_ref(), listen_url.scheme()) {
+            client_auth = Some(ClientAuthAcceptor::new(server_config.clone(), ca.as_str())?);
+        }
+        Some(TlsAcceptor::from(Arc::new(server_config)))
+    } else {
+        None
+    };
+
+    let ss_cipher = if listen_url.scheme() == "ss" {
+        Some(shadowsocks_cipher(&cfg)?)
+    } else {
+        None
+    };
+    let trojan_auth = if listen_url.scheme() == "trojan" {
+        Some(trojan_hashes(&cfg)?)
+    } else {
+        None
+    };
+    let noise_keys = if listen_url.scheme() == "noise" {
+        match cfg.noise.as_ref() {
+            Some(noise) => Some(NoiseKeys::new(noise, true)?),
+            None => return Err(make_error("noise listener requires 'noise' config")),
+        }
+    } else {
+        None
+    };
 
-    let mut listener = TcpListener::bind(addr).await?;
+    let mut listener = match cfg.plugin.as_ref() {
+        Some(plugin) => plugin_listen(plugin, addr.as_str()).await?,
+        None => TcpListener::bind(addr).await?,
+    };
+    if let Some(qlen) = cfg.tcp_fast_open.filter(|v| *v > 0) {
+        if let Err(e) = enable_tfo_listener(&listener, qlen) {
+            error!("Failed to enable TCP fast open on {}: {}", listen_str, e);
+        }
+    }
     let tunnel_id_seed = AtomicU32::new(0);
     while let Ok((inbound, _)) = listener.accept().await {
         let tunnel_id = tunnel_id_seed.fetch_add(1, Ordering::SeqCst);
@@ -126,6 +324,27 @@ pub async fn start_tunnel_server(mut cfg: TunnelConfig) -> Result<(), Box<dyn Er
                 }
             });
             tokio::spawn(handle);
+        } else if listen_url.scheme() == "redirect" {
+            let handle = handle_redirect(tunnel_id, inbound, cfg.clone()).map(move |r| {
+                if let Err(e) = r {
+                    error!("[{}]Failed to handle; error={}", tunnel_id, e);
+                }
+            });
+            tokio::spawn(handle);
+        } else if listen_url.scheme() == "forward" {
+            let handle = handle_forward(tunnel_id, inbound, cfg.clone()).map(move |r| {
+                if let Err(e) = r {
+                    error!("[{}]Failed to handle; error={}", tunnel_id, e);
+                }
+            });
+            tokio::spawn(handle);
+        } else if listen_url.scheme() == "vhost" {
+            let handle = handle_vhost(tunnel_id, inbound, cfg.clone()).map(move |r| {
+                if let Err(e) = r {
+                    error!("[{}]Failed to handle; error={}", tunnel_id, e);
+                }
+            });
+            tokio::spawn(handle);
         } else if listen_url.scheme() == "rmux" {
             let handle = handle_rmux(tunnel_id, inbound, cfg.clone()).map(move |r| {
                 if let Err(e) = r {
@@ -133,6 +352,62 @@ pub async fn start_tunnel_server(mut cfg: TunnelConfig) -> Result<(), Box<dyn Er
                 }
             });
             tokio::spawn(handle);
+        } else if let Some(keys) = noise_keys.as_ref() {
+            let handle =
+                handle_rmux_noise(tunnel_id, inbound, keys.clone(), cfg.clone()).map(move |r| {
+                    if let Err(e) = r {
+                        error!("[{}]Failed to handle; error={}", tunnel_id, e);
+                    }
+                });
+            tokio::spawn(handle);
+        } else if let Some(cipher) = ss_cipher.as_ref() {
+            let handle =
+                handle_shadowsocks(tunnel_id, inbound, cipher.clone(), cfg.clone()).map(move |r| {
+                    if let Err(e) = r {
+                        error!("[{}]Failed to handle; error={}", tunnel_id, e);
+                    }
+                });
+            tokio::spawn(handle);
+        } else if let (Some(acceptor), Some(hashes)) = (tls_acceptor.as_ref(), trojan_auth.as_ref())
+        {
+            let handle = handle_trojan(
+                tunnel_id,
+                inbound,
+                acceptor.clone(),
+                hashes.clone(),
+                cfg.clone(),
+            )
+            .map(move |r| {
+                if let Err(e) = r {
+                    error!("[{}]Failed to handle; error={}", tunnel_id, e);
+                }
+            });
+            tokio::spawn(handle);
+        } else if let (Some(acceptor), "socks5s") = (tls_acceptor.as_ref(), listen_url.scheme()) {
+            let handle = handle_socks5_tls(tunnel_id, inbound, acceptor.clone(), cfg.clone()).map(
+                move |r| {
+                    if let Err(e) = r {
+                        error!("[{}]Failed to handle; error={}", tunnel_id, e);
+                    }
+                },
+            );
+            tokio::spawn(handle);
+        } else if let Some(acceptor) = client_auth.as_ref() {
+            let handle = handle_rmux_client_auth(tunnel_id, inbound, acceptor.clone(), cfg.clone())
+                .map(move |r| {
+                    if let Err(e) = r {
+                        error!("[{}]Failed to handle; error={}", tunnel_id, e);
+                    }
+                });
+            tokio::spawn(handle);
+        } else if let Some(acceptor) = tls_acceptor.as_ref() {
+            let handle =
+                handle_rmux_tls(tunnel_id, inbound, acceptor.clone(), cfg.clone()).map(move |r| {
+                    if let Err(e) = r {
+                        error!("[{}]Failed to handle; error={}", tunnel_id, e);
+                    }
+                });
+            tokio::spawn(handle);
         } else if listen_url.scheme() == "ws" {
             let handle = handle_websocket(tunnel_id, inbound, cfg.clone()).map(move |r| {
                 if let Err(e) = r {
@@ -0,0 +1,75 @@
+use crate::config::MitmConfig;
+use crate::utils::make_io_error;
+use async_tls::TlsAcceptor;
+use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType, KeyPair};
+use rustls::internal::pemfile::certs;
+use rustls::{NoClientAuth, ServerConfig};
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::sync::{Arc, Mutex};
+
+// clients refuse leaf certs valid for much longer than a year
+const LEAF_VALIDITY_DAYS: i64 = 365;
+const MAX_CACHED_LEAVES: usize = 1024;
+
+lazy_static! {
+    static ref LEAF_CONFIGS: Mutex<HashMap<String, Arc<ServerConfig>>> = Mutex::new(HashMap::new());
+}
+
+fn to_io_error(e: rcgen::RcgenError) -> std::io::Error {
+    make_io_error(&e.to_string())
+}
+
+fn load_ca(cfg: &MitmConfig) -> Result<(Certificate, rustls::Certificate), std::io::Error> {
+    let cert_pem = std::fs::read_to_string(cfg.ca_cert.as_str())?;
+    let key_pem = std::fs::read_to_string(cfg.ca_key.as_str())?;
+    let key = KeyPair::from_pem(key_pem.as_str()).map_err(to_io_error)?;
+    let params =
+        CertificateParams::from_ca_cert_pem(cert_pem.as_str(), key).map_err(to_io_error)?;
+    let ca = Certificate::from_params(params).map_err(to_io_error)?;
+    // the chain carries the CA cert as configured, not as rcgen would rebuild it
+    let mut reader = BufReader::new(cert_pem.as_bytes());
+    match certs(&mut reader) {
+        Ok(mut c) if !c.is_empty() => Ok((ca, c.remove(0))),
+        _ => Err(make_io_error("invalid mitm ca_cert")),
+    }
+}
+
+fn new_leaf_config(
+    host: &str,
+    ca: &Certificate,
+    ca_der: rustls::Certificate,
+) -> Result<ServerConfig, std::io::Error> {
+    let mut params = CertificateParams::new(vec![String::from(host)]);
+    let mut dn = DistinguishedName::new();
+    dn.push(DnType::CommonName, host);
+    params.distinguished_name = dn;
+    let now = chrono::Utc::now();
+    params.not_before = now - chrono::Duration::days(1);
+    params.not_after = now + chrono::Duration::days(LEAF_VALIDITY_DAYS);
+    let leaf = Certificate::from_params(params).map_err(to_io_error)?;
+    let der = leaf.serialize_der_with_signer(ca).map_err(to_io_error)?;
+    let key = rustls::PrivateKey(leaf.serialize_private_key_der());
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    if let Err(e) = config.set_single_cert(vec![rustls::Certificate(der), ca_der], key) {
+        return Err(make_io_error(&e.to_string()));
+    }
+    config.set_protocols(&[b"http/1.1".to_vec()]);
+    Ok(config)
+}
+
+/// Acceptor presenting a cert for `host` signed by the configured CA, leaf
+/// certs are generated once per host.
+pub fn mitm_acceptor(cfg: &MitmConfig, host: &str) -> Result<TlsAcceptor, std::io::Error> {
+    if let Some(config) = LEAF_CONFIGS.lock().unwrap().get(host) {
+        return Ok(TlsAcceptor::from(config.clone()));
+    }
+    let (ca, ca_der) = load_ca(cfg)?;
+    let config = Arc::new(new_leaf_config(host, &ca, ca_der)?);
+    let mut leaves = LEAF_CONFIGS.lock().unwrap();
+    if leaves.len() >= MAX_CACHED_LEAVES {
+        leaves.clear();
+    }
+    leaves.insert(String::from(host), config.clone());
+    Ok(TlsAcceptor::from(config))
+}
@@ -1,10 +1,37 @@
+mod detect;
+mod dns;
+mod dns_forward;
+mod doh;
+mod grpc;
 mod http;
+mod http2;
+mod http3;
+mod kcp;
 mod local;
+mod mitm;
+mod pac;
+mod proxy_auth;
+mod quic;
 mod relay;
+mod reverse;
 mod rmux;
+mod rule_list;
+mod shadowsocks;
+mod sniff;
+mod socks4;
 mod socks5;
 mod tls;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod tproxy;
+mod trojan;
+mod tun;
+mod udp_forward;
+#[cfg(unix)]
+mod unix;
+mod vhost;
 mod ws;
 
 pub use self::local::start_tunnel_server;
 pub use self::relay::relay;
+pub use self::reverse::start_reverse_listener;
+pub use self::rule_list::start_rule_lists;
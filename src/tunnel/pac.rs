@@ -0,0 +1,199 @@
+use crate::config::{PACConfig, TunnelConfig};
+
+use std::error::Error;
+use std::fmt::Write as fmt_write;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+const PAC_CONTENT_TYPE: &str = "application/x-ns-proxy-autoconfig";
+
+// FindProxyForURL only gets the url and host, rules match "host:port"
+const PAC_FIND_PROXY: &str = r#"
+function targetPort(url) {
+    var m = url.match(/^[a-z0-9+.-]+:\/\/(?:[^\/@]*@)?(?:\[[^\]]*\]|[^\/:]*)(?::(\d+))?/i);
+    if (m && m[1]) {
+        return m[1];
+    }
+    var scheme = url.substring(0, url.indexOf(":")).toLowerCase();
+    return (scheme == "https" || scheme == "wss") ? "443" : "80";
+}
+
+function FindProxyForURL(url, host) {
+    var target = host + ":" + targetPort(url);
+    for (var i = 0; i < rules.length; i++) {
+        if (new RegExp(rules[i][0]).test(target)) {
+            return rules[i][1];
+        }
+    }
+    return "DIRECT";
+}
+"#;
+
+fn request_line(head: &[u8]) -> Option<(String, String, Option<String>)> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+    if req.parse(head).is_err() {
+        return None;
+    }
+    let method = String::from(req.method?);
+    let path = String::from(req.path?);
+    let host = req
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("Host"))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+        .map(|h| String::from(h.trim()));
+    Some((method, path, host))
+}
+
+/// A GET or HEAD of the listener's own PAC path, as browsers send when
+/// pointed at `http://<listen>/proxy.pac`.
+pub fn is_pac_request(head: &[u8], cfg: &TunnelConfig) -> bool {
+    match request_line(head) {
+        Some((method, path, _)) => {
+            let path = path.split('?').next().unwrap_or("");
+            (method == "GET" || method == "HEAD") && path == cfg.pac_path()
+        }
+        None => false,
+    }
+}
+
+fn js_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Translates the pac rules into a PAC script sending every host the tunnel
+/// would relay over a channel to `proxy`. Plain `direct` rules become
+/// DIRECT, direct rules with bind options still go through the proxy so
+/// the binding applies, as do rules on domain matchers, ip ranges, ports or
+/// rule lists, which the script cannot check. The host patterns are evaluated as JS regexps, which
+/// accept the common subset of the regex syntax.
+pub fn generate_pac(rules: &[PACConfig], proxy: &str) -> String {
+    let mut pac = String::from("var rules = [\n");
+    for rule in rules.iter() {
+        let plain = rule.domains.is_none()
+            && rule.cidr.is_none()
+            && rule.ports.is_none()
+            && rule.list.is_none()
+            && rule.dial_options().is_empty();
+        let action = if rule.channel == "direct" && plain {
+            String::from("DIRECT")
+        } else {
+            format!("PROXY {}", proxy)
+        };
+        let _ = writeln!(
+            pac,
+            "    [{}, {}],",
+            js_string(rule.host.as_str()),
+            js_string(action.as_str())
+        );
+    }
+    pac.push_str("];\n");
+    pac.push_str(PAC_FIND_PROXY);
+    pac
+}
+
+/// Answers a PAC request on the local listener. The script is generated
+/// from the rules on every request, so it never gets stale.
+pub async fn serve_pac(
+    tunnel_id: u32,
+    mut inbound: TcpStream,
+    head: &[u8],
+    cfg: &TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let (method, host) = match request_line(head) {
+        Some((method, _, host)) => (method, host),
+        None => (String::from("GET"), None),
+    };
+    // the address the client reached us with is the one to hand back
+    let proxy = match host {
+        Some(h) if !h.is_empty() => h,
+        _ => inbound.local_addr()?.to_string(),
+    };
+    info!("[{}]Serve PAC file with proxy {}", tunnel_id, proxy);
+    let body = generate_pac(&cfg.pac, proxy.as_str());
+    let res = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        PAC_CONTENT_TYPE,
+        body.len()
+    );
+    inbound.write_all(res.as_bytes()).await?;
+    if method != "HEAD" {
+        inbound.write_all(body.as_bytes()).await?;
+    }
+    let _ = inbound.shutdown(std::net::Shutdown::Both);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(pac: &str) -> TunnelConfig {
+        let s = format!("listen = \"127.0.0.1:48100\"\npac = {}", pac);
+        let mut cfg: TunnelConfig = toml::from_str(s.as_str()).unwrap();
+        for rule in cfg.pac.iter_mut() {
+            rule.init();
+        }
+        cfg
+    }
+
+    #[test]
+    fn test_is_pac_request() {
+        let cfg = config("[]");
+        let head = b"GET /proxy.pac?t=1 HTTP/1.1\r\nHost: 127.0.0.1:48100\r\n\r\n";
+        assert!(is_pac_request(head, &cfg));
+        let head = b"GET http://example.com/proxy.pac HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert!(!is_pac_request(head, &cfg));
+        let head = b"POST /proxy.pac HTTP/1.1\r\n\r\n";
+        assert!(!is_pac_request(head, &cfg));
+    }
+
+    #[test]
+    fn test_rule_conditions() {
+        let cfg = config(
+            r#"[{cidr = ["10.0.0.0/8", "fd00::/8"], ports = ["22", "8000-9000"], channel = "block"},
+                {host = "example\\.com:", ports = ["443"], channel = "direct"}]"#,
+        );
+        let (block, direct) = (&cfg.pac[0], &cfg.pac[1]);
+        assert!(block.is_block() && block.is_match("10.1.2.3:22"));
+        assert!(block.is_match("[fd00::1]:8080"));
+        assert!(block.is_match("udp://10.0.0.1:8443"));
+        assert!(!block.is_match("10.1.2.3:443"));
+        assert!(!block.is_match("intranet.example:22"));
+        assert!(direct.is_match("www.example.com:443"));
+        assert!(!direct.is_match("www.example.com:80"));
+    }
+
+    #[test]
+    fn test_generate_pac() {
+        let cfg = config(
+            r#"[{host = "\\.cn:\\d+$", channel = "direct"},
+                {host = "^10\\.", channel = "direct", bind_interface = "eth1"},
+                {cidr = ["192.168.0.0/16"], channel = "direct"},
+                {host = ".*", channel = "rmux"}]"#,
+        );
+        let pac = generate_pac(&cfg.pac, "127.0.0.1:48100");
+        assert!(pac.contains(r#"["\\.cn:\\d+$", "DIRECT"],"#));
+        assert!(pac.contains(r#"["^10\\.", "PROXY 127.0.0.1:48100"],"#));
+        assert!(pac.contains(r#"["", "PROXY 127.0.0.1:48100"],"#));
+        assert!(pac.contains(r#"[".*", "PROXY 127.0.0.1:48100"],"#));
+        assert!(pac.contains("function FindProxyForURL(url, host)"));
+    }
+}
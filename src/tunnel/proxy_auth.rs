@@ -0,0 +1,291 @@
+use crate::config::TunnelConfig;
+use ring::constant_time::verify_slices_are_equal;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const REALM: &str = "rsnova";
+// digest nonces older than this are refused and the client challenged again
+const NONCE_LIFETIME_SECS: u64 = 300;
+
+lazy_static! {
+    static ref NONCE_SECRET: [u8; 32] = rand::random::<[u8; 32]>();
+}
+
+fn to_hex(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len() * 2);
+    for b in data {
+        s.push_str(format!("{:02x}", b).as_str());
+    }
+    s
+}
+
+fn now_secs() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+fn nonce_mac(ts: &str) -> String {
+    let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+    ctx.update(&NONCE_SECRET[..]);
+    ctx.update(ts.as_bytes());
+    to_hex(&ctx.finish().as_ref()[..16])
+}
+
+// stateless nonce: issue time and a mac over it
+fn new_nonce(now: u64) -> String {
+    let ts = format!("{:x}", now);
+    let mac = nonce_mac(ts.as_str());
+    format!("{}.{}", ts, mac)
+}
+
+fn valid_nonce(nonce: &str, now: u64) -> bool {
+    let mut parts = nonce.splitn(2, '.');
+    let (ts, mac) = match (parts.next(), parts.next()) {
+        (Some(t), Some(m)) => (t, m),
+        _ => return false,
+    };
+    let issued = match u64::from_str_radix(ts, 16) {
+        Ok(v) => v,
+        Err(_) => return false,
+    };
+    if issued > now || now - issued > NONCE_LIFETIME_SECS {
+        return false;
+    }
+    verify_slices_are_equal(nonce_mac(ts).as_bytes(), mac.as_bytes()).is_ok()
+}
+
+/// Splits the `key=value` list of a Digest credential, values may be quoted.
+fn digest_params(s: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut rest = s.trim();
+    while !rest.is_empty() {
+        let eq = match rest.find('=') {
+            Some(i) => i,
+            None => break,
+        };
+        let key = rest[..eq].trim().to_ascii_lowercase();
+        rest = rest[eq + 1..].trim_start();
+        let value = if rest.starts_with('"') {
+            let mut value = String::new();
+            let mut end = rest.len();
+            let mut escaped = false;
+            for (i, c) in rest.char_indices().skip(1) {
+                if escaped {
+                    value.push(c);
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    end = i + 1;
+                    break;
+                } else {
+                    value.push(c);
+                }
+            }
+            rest = &rest[end..];
+            value
+        } else {
+            let end = rest.find(',').unwrap_or_else(|| rest.len());
+            let value = String::from(rest[..end].trim());
+            rest = &rest[end..];
+            value
+        };
+        params.push((key, value));
+        rest = rest.trim_start().trim_start_matches(',').trim_start();
+    }
+    params
+}
+
+fn md5_hex(s: &str) -> String {
+    format!("{:x}", md5::compute(s.as_bytes()))
+}
+
+fn check_digest(cfg: &TunnelConfig, method: &str, path: &str, credential: &str, now: u64) -> bool {
+    let params = digest_params(credential);
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(k, _)| k.as_str() == name)
+            .map(|(_, v)| v.as_str())
+    };
+    let (username, nonce, uri, response) = match (
+        param("username"),
+        param("nonce"),
+        param("uri"),
+        param("response"),
+    ) {
+        (Some(user), Some(nonce), Some(uri), Some(response)) => (user, nonce, uri, response),
+        _ => return false,
+    };
+    if param("realm") != Some(REALM) || uri != path || !valid_nonce(nonce, now) {
+        return false;
+    }
+    let user = match cfg
+        .users
+        .iter()
+        .flatten()
+        .find(|u| u.username.as_str() == username)
+    {
+        Some(u) => u,
+        None => return false,
+    };
+    let ha1 = md5_hex(format!("{}:{}:{}", username, REALM, user.password).as_str());
+    let ha2 = md5_hex(format!("{}:{}", method, uri).as_str());
+    let expected = match param("qop") {
+        Some("auth") => {
+            let (nc, cnonce) = match (param("nc"), param("cnonce")) {
+                (Some(nc), Some(c)) => (nc, c),
+                _ => return false,
+            };
+            md5_hex(format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2).as_str())
+        }
+        Some(_) => return false,
+        None => md5_hex(format!("{}:{}:{}", ha1, nonce, ha2).as_str()),
+    };
+    verify_slices_are_equal(
+        expected.as_bytes(),
+        response.to_ascii_lowercase().as_bytes(),
+    )
+    .is_ok()
+}
+
+fn check_credential(cfg: &TunnelConfig, method: &str, path: &str, value: &str) -> bool {
+    let mut parts = value.trim().splitn(2, ' ');
+    let scheme = parts.next().unwrap_or("").to_ascii_lowercase();
+    let credential = parts.next().unwrap_or("").trim();
+    match scheme.as_str() {
+        "basic" => {
+            let decoded = match base64::decode(credential) {
+                Ok(d) => d,
+                Err(_) => return false,
+            };
+            let colon = match decoded.iter().position(|b| *b == b':') {
+                Some(i) => i,
+                None => return false,
+            };
+            cfg.check_user(&decoded[..colon], &decoded[colon + 1..])
+        }
+        "digest" => check_digest(cfg, method, path, credential, now_secs()),
+        _ => false,
+    }
+}
+
+/// Checks the `Proxy-Authorization` of a request head against the users of
+/// the listener; always passes when none are configured.
+pub fn authorize(head: &[u8], cfg: &TunnelConfig) -> bool {
+    if !cfg.requires_auth() {
+        return true;
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+    if req.parse(head).is_err() {
+        return false;
+    }
+    let method = req.method.unwrap_or("");
+    let path = req.path.unwrap_or("");
+    let value = req
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("proxy-authorization"))
+        .and_then(|h| std::str::from_utf8(h.value).ok());
+    authorize_request(cfg, method, path, value)
+}
+
+/// Like `authorize` for a request parsed already, e.g. a stream of an
+/// HTTP/2 connection.
+pub fn authorize_request(
+    cfg: &TunnelConfig,
+    method: &str,
+    path: &str,
+    value: Option<&str>,
+) -> bool {
+    if !cfg.requires_auth() {
+        return true;
+    }
+    match value {
+        Some(v) => check_credential(cfg, method, path, v),
+        None => false,
+    }
+}
+
+/// The `Proxy-Authenticate` values offering both Basic and Digest.
+pub fn auth_challenge_values() -> Vec<String> {
+    vec![
+        format!("Basic realm=\"{}\"", REALM),
+        format!(
+            "Digest realm=\"{}\", qop=\"auth\", algorithm=MD5, nonce=\"{}\"",
+            REALM,
+            new_nonce(now_secs())
+        ),
+    ]
+}
+
+/// The 407 answer offering both Basic and Digest.
+pub fn auth_challenge() -> String {
+    let mut res = String::from("HTTP/1.1 407 Proxy Authentication Required\r\n");
+    for value in auth_challenge_values() {
+        res.push_str(format!("Proxy-Authenticate: {}\r\n", value).as_str());
+    }
+    res.push_str("Content-Length: 0\r\nConnection: close\r\n\r\n");
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UserConfig;
+
+    fn config() -> TunnelConfig {
+        let mut cfg: TunnelConfig =
+            toml::from_str("listen = \"127.0.0.1:48100\"\npac = []").unwrap();
+        cfg.users = Some(vec![UserConfig {
+            username: String::from("Mufasa"),
+            password: String::from("Circle Of Life"),
+            password_file: None,
+        }]);
+        cfg
+    }
+
+    #[test]
+    fn test_basic() {
+        let cfg = config();
+        let value = format!("Basic {}", base64::encode("Mufasa:Circle Of Life"));
+        assert!(check_credential(&cfg, "GET", "/", value.as_str()));
+        let value = format!("Basic {}", base64::encode("Mufasa:wrong"));
+        assert!(!check_credential(&cfg, "GET", "/", value.as_str()));
+    }
+
+    #[test]
+    fn test_digest() {
+        let cfg = config();
+        let now = now_secs();
+        let nonce = new_nonce(now);
+        let ha1 = md5_hex("Mufasa:rsnova:Circle Of Life");
+        let ha2 = md5_hex("CONNECT:example.com:443");
+        let response =
+            md5_hex(format!("{}:{}:00000001:0a4f113b:auth:{}", ha1, nonce, ha2).as_str());
+        let credential = format!(
+            "username=\"Mufasa\", realm=\"rsnova\", nonce=\"{}\", uri=\"example.com:443\", \
+             qop=auth, nc=00000001, cnonce=\"0a4f113b\", response=\"{}\"",
+            nonce, response
+        );
+        let path = "example.com:443";
+        assert!(check_digest(
+            &cfg,
+            "CONNECT",
+            path,
+            credential.as_str(),
+            now
+        ));
+        assert!(!check_digest(&cfg, "GET", path, credential.as_str(), now));
+        assert!(!check_digest(
+            &cfg,
+            "CONNECT",
+            path,
+            credential.as_str(),
+            now + 3600
+        ));
+        assert!(!valid_nonce("5f.00", now));
+    }
+}
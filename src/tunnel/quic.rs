@@ -0,0 +1,60 @@
+use super::rmux::serve_rmux_session;
+use crate::config::TunnelConfig;
+use crate::transport::quic_listen;
+use crate::utils::make_io_error;
+use futures::{FutureExt, StreamExt};
+use quinn::{Connecting, NewConnection};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+async fn handle_quic_conn(
+    tunnel_id: u32,
+    connecting: Connecting,
+    cfg: TunnelConfig,
+) -> Result<(), std::io::Error> {
+    let NewConnection { mut bi_streams, .. } = match connecting.await {
+        Ok(c) => c,
+        Err(e) => return Err(make_io_error(&e.to_string())),
+    };
+    // every bidirectional stream carries an independent rmux session
+    while let Some(stream) = bi_streams.next().await {
+        let (send, recv) = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                info!("[{}]QUIC connection closed:{}", tunnel_id, e);
+                break;
+            }
+        };
+        let cfg = cfg.clone();
+        let handle =
+            async move { serve_rmux_session(tunnel_id, recv, send, &cfg).await }.map(move |r| {
+                if let Err(e) = r {
+                    error!("[{}]Failed to handle QUIC stream; error={}", tunnel_id, e);
+                }
+            });
+        tokio::spawn(handle);
+    }
+    Ok(())
+}
+
+pub async fn start_quic_server(addr: &str, cfg: TunnelConfig) -> Result<(), std::io::Error> {
+    let cert = match cfg.cert.as_ref() {
+        Some(c) => c.clone(),
+        None => return Err(make_io_error("QUIC listener requires 'cert'")),
+    };
+    let key = match cfg.key.as_ref() {
+        Some(k) => k.clone(),
+        None => return Err(make_io_error("QUIC listener requires 'key'")),
+    };
+    let (_endpoint, mut incoming) = quic_listen(addr, cert.as_str(), key.as_str()).await?;
+    let tunnel_id_seed = AtomicU32::new(0);
+    while let Some(connecting) = incoming.next().await {
+        let tunnel_id = tunnel_id_seed.fetch_add(1, Ordering::SeqCst);
+        let handle = handle_quic_conn(tunnel_id, connecting, cfg.clone()).map(move |r| {
+            if let Err(e) = r {
+                error!("[{}]Failed to handle; error={}", tunnel_id, e);
+            }
+        });
+        tokio::spawn(handle);
+    }
+    Ok(())
+}
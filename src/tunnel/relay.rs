@@ -1,21 +1,44 @@
-use crate::channel::get_channel_stream;
-use crate::config::TunnelConfig;
-use crate::rmux::get_channel_session_size;
-use crate::utils::{make_error, relay_buf_copy, RelayState};
+use crate::channel::{
+    get_channel_stream_with_data, get_channel_stream_with_priority, get_direct_stream_with,
+    get_session_size, tagged_channel, ChannelStream, StreamPriority,
+};
+use crate::config::{PACConfig, TunnelConfig};
+use crate::rmux::{
+    is_early_data_channel, open_datagram_flow, open_udp_association, DatagramFlow, UdpAssociation,
+};
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::utils::splice_copy;
+use crate::utils::{
+    bi_copy, make_error, register_stream_metrics, rejoin, split_owned, DatagramReader,
+    DatagramWriter, MeteredStream, RateLimitedReader, RateLimitedWriter, RelayState, ShapedWriter,
+    TimedStream, UDP_FLOW_IDLE, UDP_TARGET_PREFIX,
+};
 
-use futures::future::join3;
+use futures::future::join;
+use std::collections::HashMap;
 use std::error::Error;
+use std::io::ErrorKind;
 use std::net::Shutdown;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
 use tokio::time;
-use tokio::time::delay_for;
 
 // static RELAYS: AtomicU32 = AtomicU32::new(0);
 
+/// Times a stream its session lost before it relayed anything is opened
+/// again, and how long each reopen waits for a new session.
+const MAX_STREAM_MIGRATIONS: u32 = 2;
+const MIGRATE_WAIT: Duration = Duration::from_secs(5);
+const MIGRATE_RETRY: Duration = Duration::from_millis(200);
+/// How long a stream whose channel takes early data waits for the first
+/// bytes of the local side to send them in its SYN, like the ClientHello
+/// following a CONNECT.
+const EARLY_DATA_WAIT: Duration = Duration::from_millis(20);
+
 pub async fn relay_connection(
     tunnel_id: u32,
     mut inbound: TcpStream,
@@ -23,6 +46,26 @@ pub async fn relay_connection(
     target: String,
     relay_buf: Vec<u8>,
 ) -> Result<(), Box<dyn Error>> {
+    if let Some(secs) = cfg.idle_timeout_secs {
+        let timed = TimedStream::new(inbound, Duration::from_secs(secs));
+        let (mut ri, mut wi) = split_owned(timed);
+        let _ = relay_stream(tunnel_id, &mut ri, &mut wi, target, cfg, relay_buf).await;
+        if let Ok(timed) = rejoin(ri, wi) {
+            let _ = timed.get_ref().shutdown(Shutdown::Both);
+        }
+        return Ok(());
+    }
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    {
+        if cfg.rate_limit.is_none()
+            && cfg.shaping.is_none()
+            && select_channel(cfg, target.as_str()).as_deref() == Some("direct")
+        {
+            let _ = splice_relay(tunnel_id, &mut inbound, target, cfg, relay_buf).await;
+            let _ = inbound.shutdown(Shutdown::Both);
+            return Ok(());
+        }
+    }
     let (mut ri, mut wi) = inbound.split();
     //let mut ri = tokio::io::BufReader::new(ri);
     //let mut wi = tokio::io::BufWriter::new(wi);
@@ -31,6 +74,152 @@ pub async fn relay_connection(
     Ok(())
 }
 
+pub(super) fn select_rule<'a>(cfg: &'a TunnelConfig, target: &str) -> Option<&'a PACConfig> {
+    select_rule_in(&cfg.pac, target)
+}
+
+/// The channel the streams of `rule` go over, for a "proxy:" rule the
+/// first channel of its tag with a session, or the tag itself if none has.
+pub(super) fn rule_channel(rule: &PACConfig) -> String {
+    match rule.proxy_tag().and_then(tagged_channel) {
+        Some(channel) => channel,
+        None => String::from(rule.channel.as_str()),
+    }
+}
+
+/// First rule matching `target` whose channel currently has a session.
+pub(super) fn select_rule_in<'a>(rules: &'a [PACConfig], target: &str) -> Option<&'a PACConfig> {
+    for pac in rules.iter() {
+        if pac.is_match(target) {
+            if pac.channel.as_str() != "direct"
+                && !pac.is_block()
+                && get_session_size(rule_channel(pac).as_str()) == 0
+            {
+                continue;
+            }
+            return Some(pac);
+        }
+    }
+    None
+}
+
+/// Whether the rule routing `target` refuses it, so a proxy handshake can
+/// say so instead of succeeding.
+pub(super) fn is_blocked(cfg: &TunnelConfig, target: &str) -> bool {
+    select_rule(cfg, target).is_some_and(|rule| rule.is_block())
+}
+
+fn select_channel(cfg: &TunnelConfig, target: &str) -> Option<String> {
+    select_rule(cfg, target).map(rule_channel)
+}
+
+fn rule_priority(rule: &PACConfig) -> StreamPriority {
+    rule.priority
+        .as_ref()
+        .and_then(|p| StreamPriority::from_name(p.as_str()))
+        .unwrap_or_default()
+}
+
+/// Opens the stream of the matched rule, direct ones honor the rule's bind options.
+pub(super) async fn open_rule_stream(
+    rule: &PACConfig,
+    target: String,
+) -> Result<Box<dyn ChannelStream + Send>, std::io::Error> {
+    if rule.is_block() {
+        info!("Blocked {} by pac rule", target);
+        return Err(std::io::Error::new(
+            ErrorKind::PermissionDenied,
+            "blocked by rule",
+        ));
+    }
+    let opts = rule.dial_options();
+    if rule.channel == "direct" && !opts.is_empty() {
+        return get_direct_stream_with(target, &opts).await;
+    }
+    let priority = rule_priority(rule);
+    get_channel_stream_with_priority(rule_channel(rule), target, priority).await
+}
+
+/// Like `open_rule_stream`, with `early` in the SYN of an rmux stream if
+/// its session takes it, as the returned flag tells.
+async fn open_rule_stream_with_data(
+    rule: &PACConfig,
+    target: String,
+    early: &[u8],
+) -> Result<(Box<dyn ChannelStream + Send>, bool), std::io::Error> {
+    if rule.channel == "direct" || rule.is_block() || early.is_empty() {
+        return open_rule_stream(rule, target).await.map(|s| (s, false));
+    }
+    let priority = rule_priority(rule);
+    get_channel_stream_with_data(rule_channel(rule), target, priority, early).await
+}
+
+// Both ends are plain sockets, so let the kernel move the payload.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+async fn splice_relay(
+    tunnel_id: u32,
+    inbound: &mut TcpStream,
+    target: String,
+    cfg: &TunnelConfig,
+    relay_buf: Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    use nix::sys::socket::{shutdown, Shutdown as SockShutdown};
+    use std::os::unix::io::AsRawFd;
+
+    // the handle keeps the stream listed until the relay is done
+    let handle = register_stream_metrics("direct", target.as_str());
+    let metrics = handle.metrics();
+    let rule = match select_rule(cfg, target.as_str()) {
+        Some(r) => r,
+        None => return Err(make_error("no valid channel found.")),
+    };
+    let mut remote = match open_rule_stream(rule, target).await {
+        Ok(s) => s,
+        Err(e) => {
+            return Err(make_error(&e.to_string()));
+        }
+    };
+    {
+        let conn = match remote.tcp_stream() {
+            Some(c) => c,
+            None => {
+                let _ = remote.close();
+                return Err(make_error("direct channel is not a tcp stream"));
+            }
+        };
+        if !relay_buf.is_empty() {
+            conn.write_all(&relay_buf[..]).await?;
+        }
+        let ifd = inbound.as_raw_fd();
+        let ofd = conn.as_raw_fd();
+        let (mut ri, mut wi) = inbound.split();
+        let (mut ro, mut wo) = conn.split();
+        let up = async {
+            let r = splice_copy(&mut ri, &mut wo, ifd, ofd, |n| metrics.on_upload(n)).await;
+            if r.is_err() {
+                let _ = shutdown(ifd, SockShutdown::Both);
+                let _ = shutdown(ofd, SockShutdown::Both);
+            }
+            r
+        };
+        let down = async {
+            let r = splice_copy(&mut ro, &mut wi, ofd, ifd, |n| metrics.on_download(n)).await;
+            if r.is_err() {
+                let _ = shutdown(ifd, SockShutdown::Both);
+                let _ = shutdown(ofd, SockShutdown::Both);
+            }
+            r
+        };
+        let (up, down) = join(up, down).await;
+        info!(
+            "[{}]Spliced stream close with up:{:?} down:{:?}",
+            tunnel_id, up, down
+        );
+    }
+    let _ = remote.close();
+    Ok(())
+}
+
 pub async fn relay_stream<'a, A, B>(
     tunnel_id: u32,
     local_reader: &'a mut A,
@@ -43,19 +232,14 @@ where
     A: AsyncRead + Unpin + ?Sized,
     B: AsyncWrite + Unpin + ?Sized,
 {
-    let mut channel = String::new();
-    for pac in cfg.pac.iter() {
-        if pac.is_match(target.as_str()) {
-            channel = String::from(pac.channel.as_str());
-            if channel.as_str() != "direct" && get_channel_session_size(channel.as_str()) == 0 {
-                continue;
-            }
-            break;
+    let rule = match select_rule(cfg, target.as_str()) {
+        Some(r) => r,
+        None => {
+            return Err(make_error("no valid channel found."));
         }
-    }
-    if channel.is_empty() {
-        return Err(make_error("no valid channel found."));
-    }
+    };
+    let channel = rule_channel(rule);
+    let channel = channel.as_str();
 
     //let remote_target = String::from(target.as_str());
     // RELAYS.fetch_add(1, Ordering::SeqCst);
@@ -65,29 +249,77 @@ where
     //     remote_target,
     //     RELAYS.load(Ordering::SeqCst)
     // );
-    let mut remote = match get_channel_stream(channel, target).await {
-        Ok(s) => s,
-        Err(e) => {
-            //RELAYS.fetch_sub(1, Ordering::SeqCst);
-            return Err(make_error(&e.to_string()));
-        }
-    };
-    {
-        let (mut ro, mut wo) = remote.split();
-        let no_relay = !relay_buf.is_empty() && wo.write_all(&relay_buf[..]).await.is_err();
-        if !no_relay {
-            let _ = relay(
-                tunnel_id,
-                local_reader,
-                local_writer,
-                &mut ro,
-                &mut wo,
-                cfg.relay_buf_size(),
-            )
-            .await;
+    let metrics = register_stream_metrics(channel, target.as_str());
+    let local_reader = MeteredStream::new(local_reader, metrics.metrics());
+    let local_writer = MeteredStream::new(local_writer, metrics.metrics());
+    let mut local_reader = RateLimitedReader::new(local_reader, cfg.upload_buckets());
+    let local_writer = RateLimitedWriter::new(local_writer, cfg.download_buckets());
+    let mut local_writer = ShapedWriter::new(local_writer, cfg.shaper());
+    // bytes the listener took off the client already, like a sniffed head
+    metrics.metrics().on_upload(relay_buf.len());
+    let mut head = relay_buf;
+    if head.is_empty() && is_early_data_channel(channel) {
+        head = read_early_data(&mut local_reader, cfg.relay_buf_size()).await;
+    }
+    // the head went out in the SYN of the stream already
+    let (mut remote, head_sent) =
+        match open_rule_stream_with_data(rule, target.clone(), &head[..]).await {
+            Ok(s) => s,
+            Err(e) => {
+                //RELAYS.fetch_sub(1, Ordering::SeqCst);
+                return Err(make_error(&e.to_string()));
+            }
+        };
+    let mut migrations = 0;
+    loop {
+        let lost = {
+            let (mut ro, mut wo) = remote.split();
+            let first = if head.is_empty() && migrations < MAX_STREAM_MIGRATIONS {
+                first_activity(&mut local_reader, &mut ro, cfg.relay_buf_size()).await
+            } else {
+                FirstActivity::Skipped
+            };
+            let down = match first {
+                FirstActivity::Skipped => Some(Vec::new()),
+                FirstActivity::Local(data) => {
+                    head = data;
+                    Some(Vec::new())
+                }
+                FirstActivity::Remote(data) => Some(data),
+                FirstActivity::Lost => None,
+            };
+            if let Some(down) = &down {
+                let no_relay = (!down.is_empty()
+                    && local_writer.write_all(&down[..]).await.is_err())
+                    || (!head.is_empty() && !head_sent && wo.write_all(&head[..]).await.is_err());
+                if !no_relay {
+                    let _ = relay(
+                        tunnel_id,
+                        &mut local_reader,
+                        &mut local_writer,
+                        &mut ro,
+                        &mut wo,
+                        cfg.relay_buf_size(),
+                    )
+                    .await;
+                }
+            }
+            down.is_none()
+        };
+        let _ = remote.close();
+        if !lost {
+            break;
         }
+        migrations += 1;
+        remote = match reopen_rule_stream(rule, target.as_str()).await {
+            Ok(s) => s,
+            Err(e) => return Err(make_error(&e.to_string())),
+        };
+        info!(
+            "[{}]Reopened stream to {} lost with its session, {} time(s)",
+            tunnel_id, target, migrations
+        );
     }
-    let _ = remote.close();
     // RELAYS.fetch_sub(1, Ordering::SeqCst);
     // info!(
     //     "[{}][{}]Stream close with curent relay:{}",
@@ -98,6 +330,229 @@ where
     Ok(())
 }
 
+/// The first bytes of the local side if they come within `EARLY_DATA_WAIT`,
+/// none on EOF, errors or servers which speak first.
+async fn read_early_data<A>(local_reader: &mut A, buf_size: usize) -> Vec<u8>
+where
+    A: AsyncRead + Unpin + ?Sized,
+{
+    let mut buf = vec![0u8; buf_size];
+    match time::timeout(EARLY_DATA_WAIT, local_reader.read(&mut buf)).await {
+        Ok(Ok(n)) => buf.truncate(n),
+        _ => buf.clear(),
+    }
+    buf
+}
+
+/// What happened first on a stream which relayed nothing yet.
+enum FirstActivity {
+    /// the stream carries a request already, it is no longer safe to reopen
+    Skipped,
+    /// bytes from the local side, or its EOF or error as none
+    Local(Vec<u8>),
+    /// bytes from the remote, or its EOF or error as none
+    Remote(Vec<u8>),
+    /// the session carrying the stream ended before either side spoke
+    Lost,
+}
+
+/// Waits for either side of a fresh stream to speak. Until then nothing
+/// reached the target, so a stream its session lost may be opened anew.
+async fn first_activity<A, R>(
+    local_reader: &mut A,
+    remote_reader: &mut R,
+    buf_size: usize,
+) -> FirstActivity
+where
+    A: AsyncRead + Unpin + ?Sized,
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut up = vec![0u8; buf_size];
+    let mut down = vec![0u8; buf_size];
+    tokio::select! {
+        r = local_reader.read(&mut up) => {
+            up.truncate(*r.as_ref().unwrap_or(&0));
+            FirstActivity::Local(up)
+        }
+        r = remote_reader.read(&mut down) => match r {
+            Err(e) if e.kind() == ErrorKind::ConnectionAborted => FirstActivity::Lost,
+            r => {
+                down.truncate(r.unwrap_or(0));
+                FirstActivity::Remote(down)
+            }
+        },
+    }
+}
+
+/// Opens the rule's stream again, waiting a moment for the channel to
+/// dial a session in place of the lost one.
+async fn reopen_rule_stream(
+    rule: &PACConfig,
+    target: &str,
+) -> Result<Box<dyn ChannelStream + Send>, std::io::Error> {
+    let deadline = Instant::now() + MIGRATE_WAIT;
+    loop {
+        match open_rule_stream(rule, String::from(target)).await {
+            Ok(s) => return Ok(s),
+            Err(e) if Instant::now() >= deadline => return Err(e),
+            Err(_) => time::delay_for(MIGRATE_RETRY).await,
+        }
+    }
+}
+
+/// One UDP flow of a transparent listener, relayed as a `udp://` target.
+pub(super) struct UdpFlow {
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    done: Arc<AtomicBool>,
+}
+
+impl UdpFlow {
+    /// Queues a datagram, it is handed back once the flow has ended.
+    pub fn send(&self, data: Vec<u8>) -> Result<(), Vec<u8>> {
+        if self.is_done() {
+            return Err(data);
+        }
+        self.tx.send(data).map_err(|e| e.0)
+    }
+    pub fn is_done(&self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+}
+
+/// Relays the datagrams sent to the flow to `dst`, a `host:port`, the
+/// replies come out of `reply_tx`.
+pub(super) fn start_udp_flow(
+    tunnel_id: u32,
+    dst: String,
+    cfg: TunnelConfig,
+    reply_tx: mpsc::UnboundedSender<Vec<u8>>,
+) -> UdpFlow {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let done = Arc::new(AtomicBool::new(false));
+    let flow_done = done.clone();
+    tokio::spawn(async move {
+        let target = format!("{}{}", UDP_TARGET_PREFIX, dst);
+        let datagram_flow = select_rule(&cfg, target.as_str())
+            .and_then(|rule| open_datagram_flow(rule_channel(rule).as_str(), dst.as_str()));
+        match datagram_flow {
+            Some(flow) => relay_datagram_flow(flow, rx, reply_tx).await,
+            None => {
+                let mut reader = DatagramReader::new(rx);
+                let mut writer = DatagramWriter::new(reply_tx);
+                let _ = relay_stream(
+                    tunnel_id,
+                    &mut reader,
+                    &mut writer,
+                    target,
+                    &cfg,
+                    Vec::new(),
+                )
+                .await;
+            }
+        }
+        flow_done.store(true, Ordering::SeqCst);
+    });
+    UdpFlow { tx, done }
+}
+
+/// The datagrams of one local socket to any number of targets, like a
+/// SOCKS5 UDP association or a source port of the tun device. Targets whose
+/// rule's channel relays UDP associations share one of that channel, the
+/// others get a `UdpFlow` each; replies come out of `reply_tx` along with
+/// the address they came from.
+pub(super) struct UdpRouter {
+    tunnel_id: u32,
+    cfg: TunnelConfig,
+    reply_tx: mpsc::Sender<(String, Vec<u8>)>,
+    associations: HashMap<String, UdpAssociation>,
+    flows: HashMap<String, UdpFlow>,
+}
+
+impl UdpRouter {
+    pub fn new(
+        tunnel_id: u32,
+        cfg: TunnelConfig,
+        reply_tx: mpsc::Sender<(String, Vec<u8>)>,
+    ) -> Self {
+        Self {
+            tunnel_id,
+            cfg,
+            reply_tx,
+            associations: HashMap::new(),
+            flows: HashMap::new(),
+        }
+    }
+
+    /// Relays `data` to `dst`, a `host:port`.
+    pub fn send_to(&mut self, dst: &str, data: Vec<u8>) {
+        let target = format!("{}{}", UDP_TARGET_PREFIX, dst);
+        if let Some(rule) = select_rule(&self.cfg, target.as_str()) {
+            let channel = rule_channel(rule);
+            let channel = channel.as_str();
+            self.associations.retain(|_, a| !a.is_expired());
+            if !self.associations.contains_key(channel) {
+                if let Some(a) = open_udp_association(channel, self.reply_tx.clone()) {
+                    self.associations.insert(String::from(channel), a);
+                }
+            }
+            if let Some(a) = self.associations.get_mut(channel) {
+                if a.send_to(dst, &data[..]).is_ok() {
+                    return;
+                }
+            }
+        }
+        let data = match self.flows.get(dst) {
+            Some(f) => match f.send(data) {
+                Ok(()) => return,
+                Err(d) => d,
+            },
+            None => data,
+        };
+        self.flows.retain(|_, f| !f.is_done());
+        let (flow_tx, mut flow_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let mut reply_tx = self.reply_tx.clone();
+        let from = String::from(dst);
+        tokio::spawn(async move {
+            while let Some(data) = flow_rx.recv().await {
+                if reply_tx.send((from.clone(), data)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let flow = start_udp_flow(self.tunnel_id, String::from(dst), self.cfg.clone(), flow_tx);
+        let _ = flow.send(data);
+        self.flows.insert(String::from(dst), flow);
+    }
+}
+
+/// Relays a UDP flow as datagrams of an rmux session rather than over a
+/// stream, until either side stayed quiet for `UDP_FLOW_IDLE`.
+async fn relay_datagram_flow(
+    mut flow: DatagramFlow,
+    mut rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    reply_tx: mpsc::UnboundedSender<Vec<u8>>,
+) {
+    loop {
+        let (up, down) = tokio::select! {
+            data = time::timeout(UDP_FLOW_IDLE, rx.recv()) => (Some(data), None),
+            data = time::timeout(UDP_FLOW_IDLE, flow.recv()) => (None, Some(data)),
+        };
+        match (up, down) {
+            (Some(Ok(Some(data))), _) => {
+                if flow.send(&data[..]).is_err() {
+                    return;
+                }
+            }
+            (_, Some(Ok(Some(data)))) => {
+                if reply_tx.send(data).is_err() {
+                    return;
+                }
+            }
+            _ => return,
+        }
+    }
+}
+
 pub async fn relay<'a, R, W, A, B>(
     tunnel_id: u32,
     local_reader: &'a mut A,
@@ -112,68 +567,47 @@ where
     A: AsyncRead + Unpin + ?Sized,
     B: AsyncWrite + Unpin + ?Sized,
 {
-    let c2s_state = Arc::new(Mutex::new(RelayState::new()));
-    let s2c_state = Arc::new(Mutex::new(RelayState::new()));
+    let state = Arc::new(Mutex::new(RelayState::new()));
 
-    let c2s_state_c2s = c2s_state.clone();
-    let s2c_state_c2s = s2c_state.clone();
-    let client_to_server = async {
-        //let _ = buf_copy(local_reader, remote_writer, Box::new([0; RELAY_BUF_SIZE])).await;
-        {
-            let _ = relay_buf_copy(
-                local_reader,
-                remote_writer,
-                vec![0; relay_buf_size],
-                c2s_state_c2s.clone(),
-            )
-            .await;
-            info!("[{}]Stream close client_to_server", tunnel_id);
-        }
-        c2s_state_c2s.lock().unwrap().close();
-        let _ = remote_writer.shutdown().await;
-        if !s2c_state_c2s.lock().unwrap().is_closed() {
-            delay_for(Duration::from_secs(5)).await;
-            s2c_state_c2s.lock().unwrap().close();
-        }
-    };
-
-    let c2s_state_s2c = c2s_state.clone();
-    let s2c_state_s2c = s2c_state.clone();
-    let server_to_client = async {
-        //let _ = buf_copy(remote_reader, local_writer, Box::new([0; RELAY_BUF_SIZE])).await;
-        {
-            let _ = relay_buf_copy(
-                remote_reader,
-                local_writer,
-                vec![0; relay_buf_size],
-                s2c_state_s2c.clone(),
-            )
-            .await;
-            info!("[{}]Stream close server_to_client", tunnel_id);
-        }
-        s2c_state_s2c.lock().unwrap().close();
-        let _ = local_writer.shutdown().await;
-        if !c2s_state_s2c.lock().unwrap().is_closed() {
-            delay_for(Duration::from_secs(5)).await;
-            c2s_state_s2c.lock().unwrap().close();
-        }
+    let copy_state = state.clone();
+    let copy = async {
+        let r = bi_copy(
+            local_reader,
+            local_writer,
+            remote_reader,
+            remote_writer,
+            relay_buf_size,
+            copy_state.clone(),
+        )
+        .await;
+        copy_state.lock().unwrap().close();
+        r
     };
 
     let check_timeout = async {
         let mut interval = time::interval(Duration::from_secs(1));
         let max_wait_secs = 30;
-        while !c2s_state.lock().unwrap().is_closed() || !s2c_state.lock().unwrap().is_closed() {
+        while !state.lock().unwrap().is_closed() {
             interval.tick().await;
-            if c2s_state.lock().unwrap().pending_elapsed().as_secs() > max_wait_secs
-                && s2c_state.lock().unwrap().pending_elapsed().as_secs() > max_wait_secs
-            {
-                c2s_state.lock().unwrap().close();
-                s2c_state.lock().unwrap().close();
+            if state.lock().unwrap().pending_elapsed().as_secs() > max_wait_secs {
+                state.lock().unwrap().close();
                 return;
             }
         }
     };
 
-    join3(client_to_server, server_to_client, check_timeout).await;
-    Ok(())
+    let (r, _) = join(copy, check_timeout).await;
+    match r {
+        Ok((up, down, elapsed)) => {
+            info!(
+                "[{}]Stream close with up:{} down:{} bytes in {:?}",
+                tunnel_id, up, down, elapsed
+            );
+            Ok(())
+        }
+        Err(e) => {
+            info!("[{}]Stream close with error:{}", tunnel_id, e);
+            Ok(())
+        }
+    }
 }
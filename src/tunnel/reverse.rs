@@ -0,0 +1,78 @@
+use super::relay::relay;
+use crate::channel::ChannelStream;
+use crate::rmux::{create_session_stream, forget_bound_service, get_bound_session, PROTO_PORT};
+
+use std::error::Error;
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicU32, Ordering};
+use tokio::net::{TcpListener, TcpStream};
+
+static REVERSE_TUNNEL_ID_SEED: AtomicU32 = AtomicU32::new(0);
+
+async fn handle_reverse_conn(
+    tunnel_id: u32,
+    mut inbound: TcpStream,
+    addr: &str,
+    relay_buf_size: usize,
+) -> Result<(), Box<dyn Error>> {
+    let session_id = match get_bound_session(PROTO_PORT, addr) {
+        Some(id) => id,
+        None => {
+            // the clients of the port are gone, keep listening until one is back
+            let _ = inbound.shutdown(Shutdown::Both);
+            return Ok(());
+        }
+    };
+    info!(
+        "[{}]Handle reverse port {} via session {}",
+        tunnel_id, addr, session_id
+    );
+    let mut stream =
+        create_session_stream("", session_id, PROTO_PORT, addr, relay_buf_size).await?;
+    let r = {
+        let (mut ri, mut wi) = inbound.split();
+        let (mut ro, mut wo) = stream.split();
+        relay(
+            tunnel_id,
+            &mut ri,
+            &mut wi,
+            &mut ro,
+            &mut wo,
+            relay_buf_size,
+        )
+        .await
+    };
+    let _ = stream.close();
+    let _ = inbound.shutdown(Shutdown::Both);
+    r
+}
+
+/// Listens on `addr` of the remote for the clients which bound it with their
+/// `reverse` mappings, every connection is relayed back through the session
+/// which bound the port last.
+pub async fn start_reverse_listener(
+    addr: String,
+    relay_buf_size: usize,
+) -> Result<(), Box<dyn Error>> {
+    let mut listener = match TcpListener::bind(addr.as_str()).await {
+        Ok(l) => l,
+        Err(e) => {
+            // so the next bind tries again
+            forget_bound_service(PROTO_PORT, addr.as_str());
+            return Err(Box::new(e));
+        }
+    };
+    info!("Listen on reverse port {}", addr);
+    while let Ok((inbound, _)) = listener.accept().await {
+        let tunnel_id = REVERSE_TUNNEL_ID_SEED.fetch_add(1, Ordering::SeqCst);
+        let addr = addr.clone();
+        tokio::spawn(async move {
+            if let Err(e) =
+                handle_reverse_conn(tunnel_id, inbound, addr.as_str(), relay_buf_size).await
+            {
+                error!("[{}]Failed to handle; error={}", tunnel_id, e);
+            }
+        });
+    }
+    Ok(())
+}
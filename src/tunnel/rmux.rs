@@ -1,24 +1,207 @@
-use crate::config::TunnelConfig;
+use super::relay::relay_stream;
+use crate::config::{KeyConfig, TunnelConfig};
+use crate::mux::{run_mux_server, MuxProtocol, MuxStream};
+use crate::obfs::{obfs_accept, pad_records};
 use crate::rmux::{
-    handle_rmux_session, new_auth_event, read_rmux_event, AuthRequest, AuthResponse, CryptoContext,
+    accept_key_share, check_auth_stamp, cipher_suite_method, expand_cipher_suites,
+    handle_rmux_session, is_supported_method, negotiate_version, new_auth_event,
+    preferred_cipher_suites, process_rmux_session, read_rmux_event, session_key, user_session_key,
+    AuthRequest, AuthResponse, AuthTrailer, Compression, CryptoContext, MuxContext, PaddingPolicy,
+    RekeyPolicy, SessionLimits, StreamWindows, DEFAULT_RECV_BUF_SIZE, DEFAULT_REPLAY_WINDOW_SECS,
+    FEATURE_EARLY_DATA, FEATURE_HYBRID_KEX, FEATURE_PADDING, SUPPORTED_FEATURES,
 };
-use crate::utils::make_io_error;
+use crate::transport::{noise_accept, parse_ss_addr, tls_accept, ClientAuthAcceptor, NoiseKeys};
+use crate::utils::{make_error, make_io_error, zeroize, ShapedWriter, DEFAULT_HANDSHAKE_TIMEOUT};
+use async_tls::TlsAcceptor;
 use bytes::BytesMut;
-use tokio::io::AsyncWriteExt;
+use futures::FutureExt;
+use std::error::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 
+// an ATYP, a length byte and a domain name of up to 255 bytes, a port
+const MAX_TARGET_LEN: usize = 1 + 1 + 255 + 2;
+
 //use rand::Rng;
 // use std::sync::atomic::{AtomicU32, Ordering};
 
+/// Whether the auth frame is fresh. A replayed or stale one gets no answer,
+/// as if it failed to decrypt, so a probe learns nothing from replaying a
+/// captured handshake.
+fn is_fresh_auth(cfg: &TunnelConfig, trailer: &AuthTrailer) -> bool {
+    let window = cfg.replay_window_secs.unwrap_or(DEFAULT_REPLAY_WINDOW_SECS);
+    if window == 0 {
+        return true;
+    }
+    match trailer.stamp {
+        Some((secs, nonce)) => check_auth_stamp(window, secs, &nonce),
+        None => cfg.unstamped_auth.unwrap_or(false),
+    }
+}
+
+/// Stream windows of a session: what the client advertised after its auth
+/// request, and what the listener buffers.
+fn session_windows(cfg: &TunnelConfig, trailer: &AuthTrailer) -> StreamWindows {
+    let default_window = StreamWindows::default_window(cfg.relay_buf_size());
+    StreamWindows {
+        send: trailer.window.unwrap_or(default_window),
+        recv: cfg.stream_window.unwrap_or(default_window),
+    }
+}
+
+/// Compression of a session: what the client asked for, unless the
+/// listener turned compression off.
+fn session_compression(cfg: &TunnelConfig, trailer: &AuthTrailer) -> Compression {
+    if !cfg.compression.unwrap_or(true) {
+        return Compression::None;
+    }
+    Compression::accept(trailer.compression)
+}
+
+/// Cipher of a session: the first of the suites the client offered which
+/// the listener takes, or the method of a client predating suites.
+fn session_method(
+    cfg: &TunnelConfig,
+    auth_req: &AuthRequest,
+    trailer: &AuthTrailer,
+) -> Result<String, String> {
+    let offered = &trailer.cipher_suites;
+    if offered.is_empty() {
+        if is_supported_method(auth_req.method.as_str()) {
+            return Ok(auth_req.method.clone());
+        }
+        return Err(format!("unsupported cipher method:{}", auth_req.method));
+    }
+    let taken = match cfg.cipher.as_ref().and_then(|c| c.suites.as_ref()) {
+        Some(names) => expand_cipher_suites(&names[..]),
+        None => preferred_cipher_suites(),
+    };
+    offered
+        .iter()
+        .filter_map(|id| cipher_suite_method(*id))
+        .find(|m| taken.contains(m))
+        .map(String::from)
+        .ok_or_else(|| String::from("no cipher suite in common"))
+}
+
+/// User of a session: the one whose key id the client presented, none on
+/// listeners without users, which refuse key ids.
+fn session_user(
+    cfg: &TunnelConfig,
+    trailer: &AuthTrailer,
+    cert_names: Option<&[String]>,
+) -> Result<Option<KeyConfig>, String> {
+    let id = &trailer.key_id;
+    if cfg.keys.as_ref().map_or(true, |k| k.is_empty()) {
+        if id.is_empty() {
+            return Ok(None);
+        }
+        return Err(String::from("listener has no key ids"));
+    }
+    if let Some(names) = cert_names {
+        let user = match cfg.find_cert_user(names) {
+            Some(u) => u,
+            None => return Err(format!("no user for client certificate {:?}", names)),
+        };
+        if !id.is_empty() && *id != user.id {
+            return Err(format!(
+                "key id {} does not match the client certificate",
+                id
+            ));
+        }
+        return Ok(Some(user.clone()));
+    }
+    if id.is_empty() {
+        return Err(String::from("key id required"));
+    }
+    match cfg.find_key(id.as_str()) {
+        Some(k) => Ok(Some(k.clone())),
+        None => Err(format!("unknown key id:{}", id)),
+    }
+}
+
+/// Features of a session: those the client asked for which the listener
+/// takes.
+fn session_features(cfg: &TunnelConfig, trailer: &AuthTrailer) -> u8 {
+    let mut features = SUPPORTED_FEATURES;
+    if !cfg.early_data.unwrap_or(true) {
+        features &= !FEATURE_EARLY_DATA;
+    }
+    if !cfg.hybrid_kex.unwrap_or(true) {
+        features &= !FEATURE_HYBRID_KEX;
+    }
+    features & trailer.features
+}
+
+/// The features of a session and `key` mixed with the secrets of the
+/// client's hybrid key share, with the answer to it; an invalid share
+/// drops the feature and leaves the key as it is.
+fn session_key_share(
+    trailer: &AuthTrailer,
+    features: u8,
+    mut key: Vec<u8>,
+) -> (u8, Vec<u8>, Option<Vec<u8>>) {
+    if features & FEATURE_HYBRID_KEX == 0 {
+        return (features, key, None);
+    }
+    let share = trailer.key_share.as_deref().unwrap_or(&[]);
+    match accept_key_share(&key[..], share) {
+        Ok((answer, mixed)) => {
+            zeroize(&mut key[..]);
+            (features, mixed, Some(answer))
+        }
+        Err(e) => {
+            warn!("Hybrid key exchange refused: {}", e);
+            (features & !FEATURE_HYBRID_KEX, key, None)
+        }
+    }
+}
+
+/// Padding of a session, only the auth frame's for clients which do not
+/// know padding frames.
+fn session_padding(cfg: &TunnelConfig, features: u8) -> PaddingPolicy {
+    let padding = cfg
+        .padding
+        .as_ref()
+        .map(PaddingPolicy::from)
+        .unwrap_or_default();
+    if features & FEATURE_PADDING == 0 {
+        return padding.without_frames();
+    }
+    padding
+}
+
+/// The encrypted response refusing a client for `err`, which it logs.
+fn refuse_auth(wctx: &mut CryptoContext, method: String, err: &str) -> BytesMut {
+    let auth_res = AuthResponse {
+        success: false,
+        err: String::from(err),
+        rand: 0,
+        method,
+    };
+    let mut res = new_auth_event(0, &auth_res, &AuthTrailer::default());
+    let mut buf = BytesMut::new();
+    wctx.encrypt(&mut res, &mut buf);
+    buf
+}
+
 pub async fn handle_rmux(
     tunnel_id: u32,
     mut inbound: TcpStream,
     cfg: TunnelConfig,
 ) -> Result<(), std::io::Error> {
-    let key = String::from(cfg.cipher.as_ref().unwrap().key.as_str());
+    if cfg.obfs.is_some() {
+        let (read, write) = inbound.split();
+        return serve_rmux_session(tunnel_id, read, write, &cfg).await;
+    }
+    if let Some(protocol) = MuxProtocol::from_name(cfg.mux()) {
+        let (read, write) = inbound.split();
+        return serve_mux_session(tunnel_id, protocol, read, write, &cfg).await;
+    }
+    let key = session_key(cfg.cipher.as_ref().unwrap()).await;
     let method = String::from(cfg.cipher.as_ref().unwrap().method.as_str());
-    let mut rctx = CryptoContext::new(method.as_str(), key.as_str(), 0);
-    let mut wctx = CryptoContext::new(method.as_str(), key.as_str(), 0);
+    let mut rctx = CryptoContext::with_key(method.as_str(), &key[..], 0);
+    let mut wctx = CryptoContext::with_key(method.as_str(), &key[..], 0);
     //1. auth connection
     let recv_ev = match read_rmux_event(&mut rctx, &mut inbound).await {
         Err(_) => return Err(make_io_error("can NOT read first auth envent.")),
@@ -36,20 +219,361 @@ pub async fn handle_rmux(
             return Err(make_io_error("Failed to parse AuthRequest"));
         }
     };
+    let trailer = AuthTrailer::decode(&auth_req, &recv_ev.body[..]);
+    if !is_fresh_auth(&cfg, &trailer) {
+        return Err(make_io_error("replayed or stale auth event."));
+    }
+    let version = match negotiate_version(trailer.version) {
+        Ok(v) => v,
+        Err(e) => {
+            let buf = refuse_auth(&mut wctx, auth_req.method, e.as_str());
+            inbound.write_all(&buf[..]).await?;
+            return Err(make_io_error(e.as_str()));
+        }
+    };
+    let cipher = match session_method(&cfg, &auth_req, &trailer) {
+        Ok(m) => m,
+        Err(e) => {
+            let buf = refuse_auth(&mut wctx, auth_req.method, e.as_str());
+            inbound.write_all(&buf[..]).await?;
+            return Err(make_io_error(e.as_str()));
+        }
+    };
+    let user = match session_user(&cfg, &trailer, None) {
+        Ok(u) => u,
+        Err(e) => {
+            let buf = refuse_auth(&mut wctx, auth_req.method, e.as_str());
+            inbound.write_all(&buf[..]).await?;
+            return Err(make_io_error(e.as_str()));
+        }
+    };
+    let windows = session_windows(&cfg, &trailer);
+    let compression = session_compression(&cfg, &trailer);
+    let features = session_features(&cfg, &trailer);
+    // a user's session is sealed with the user's key after the handshake
+    let key = match user.as_ref() {
+        Some(u) => user_session_key(cfg.cipher.as_ref().unwrap(), u.key.as_str()).await,
+        None => key,
+    };
+    let (features, mut key, answer) = session_key_share(&trailer, features, key);
+    let padding = session_padding(&cfg, features);
     //let mut rng = rand::thread_rng();
     let auth_res = AuthResponse {
         success: true,
         err: String::new(),
         rand: rand::random::<u64>(),
         //rand: 1,
-        method: auth_req.method,
+        method: cipher,
+    };
+    let reply = AuthTrailer {
+        window: Some(windows.recv),
+        compression: compression.mask(),
+        padding: padding.handshake_padding(),
+        features,
+        key_share: answer,
+        ..Default::default()
     };
-    let mut res = new_auth_event(0, &auth_res);
+    let mut res = new_auth_event(0, &auth_res, &reply);
     let mut buf = BytesMut::new();
     wctx.encrypt(&mut res, &mut buf);
     inbound.write_all(&buf[..]).await?;
-    let rctx = CryptoContext::new(auth_res.method.as_str(), key.as_str(), auth_res.rand);
-    let wctx = CryptoContext::new(auth_res.method.as_str(), key.as_str(), auth_res.rand);
-    handle_rmux_session("", tunnel_id, inbound, rctx, wctx, 0, cfg.relay_buf_size()).await?;
+    let rctx = CryptoContext::with_key(auth_res.method.as_str(), &key[..], auth_res.rand);
+    let wctx = CryptoContext::with_key(auth_res.method.as_str(), &key[..], auth_res.rand);
+    zeroize(&mut key[..]);
+    let mut ctx = MuxContext::new("", tunnel_id, rctx, wctx, 0);
+    if let Some(u) = user {
+        info!("[{}]Session of user {}", tunnel_id, u.id);
+        ctx.set_user(u);
+    }
+    ctx.set_windows(windows);
+    ctx.set_compression(compression);
+    ctx.set_padding(padding);
+    ctx.set_rekey(RekeyPolicy::new(
+        cfg.rekey_interval_mins,
+        cfg.rekey_after_mb,
+    ));
+    ctx.set_limits(SessionLimits::new(
+        cfg.max_session_age_mins,
+        cfg.reconnect_interval_mins,
+        cfg.max_mb_per_key,
+    ));
+    ctx.set_protocol(version, features);
+    handle_rmux_session(ctx, inbound, cfg.relay_buf_size(), cfg.shaper()).await?;
     Ok(())
 }
+
+/// Reads the SOCKS5 style target address a mux stream starts with.
+async fn read_mux_target(stream: &mut MuxStream) -> Result<(String, Vec<u8>), Box<dyn Error>> {
+    let (reader, _) = stream.split_mut();
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; MAX_TARGET_LEN];
+    loop {
+        if let Some((target, n)) = parse_ss_addr(&buf[..])? {
+            return Ok((target, buf[n..].to_vec()));
+        }
+        if buf.len() >= MAX_TARGET_LEN {
+            return Err(make_error("invalid mux stream target"));
+        }
+        let n = reader
+            .read(&mut chunk[..MAX_TARGET_LEN - buf.len()])
+            .await?;
+        if n == 0 {
+            return Err(make_error("mux stream closed before its target"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+async fn handle_mux_stream(
+    tunnel_id: u32,
+    mut stream: MuxStream,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    // kcptun style listeners relay every stream to their fixed target
+    let (target, head) = match cfg.forward.as_ref() {
+        Some(f) => (f.target.clone(), Vec::new()),
+        None => {
+            match tokio::time::timeout(DEFAULT_HANDSHAKE_TIMEOUT, read_mux_target(&mut stream))
+                .await
+            {
+                Ok(r) => r?,
+                Err(_) => return Err(make_error("timeout reading mux stream target")),
+            }
+        }
+    };
+    let (r, w) = stream.split_mut();
+    relay_stream(tunnel_id, r, w, target, &cfg, head).await
+}
+
+/// Serves a session of a listener whose `mux` is not rmux, every stream is
+/// relayed by the listener's pac rules.
+async fn serve_mux_session<R, W>(
+    tunnel_id: u32,
+    protocol: MuxProtocol,
+    mut ri: R,
+    mut wi: W,
+    cfg: &TunnelConfig,
+) -> Result<(), std::io::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    info!("[{}]Serve {} session", tunnel_id, cfg.mux());
+    run_mux_server(tunnel_id, protocol, &mut ri, &mut wi, |stream| {
+        let f = handle_mux_stream(tunnel_id, stream, cfg.clone()).map(move |r| {
+            if let Err(e) = r {
+                error!("[{}]Failed to handle mux stream; error={}", tunnel_id, e);
+            }
+        });
+        tokio::spawn(f);
+    })
+    .await
+}
+
+/// Authenticates and serves an rmux session over an arbitrary carrier,
+/// like a websocket or a QUIC stream.
+pub async fn serve_rmux_session<R, W>(
+    tunnel_id: u32,
+    ri: R,
+    wi: W,
+    cfg: &TunnelConfig,
+) -> Result<(), std::io::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    serve_rmux_session_for(tunnel_id, ri, wi, cfg, None).await
+}
+
+/// `serve_rmux_session` of a client whose certificate has `cert_names`,
+/// which pick its user rather than a key id.
+async fn serve_rmux_session_for<R, W>(
+    tunnel_id: u32,
+    ri: R,
+    wi: W,
+    cfg: &TunnelConfig,
+    cert_names: Option<&[String]>,
+) -> Result<(), std::io::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match cfg.obfs.as_ref() {
+        Some(obfs) => {
+            let (reader, writer) = obfs_accept(obfs, ri, wi).await?;
+            serve_session(tunnel_id, reader, writer, cfg, cert_names).await
+        }
+        None => serve_session(tunnel_id, ri, wi, cfg, cert_names).await,
+    }
+}
+
+/// Serves a session on a carrier past its obfuscation, if any.
+async fn serve_session<R, W>(
+    tunnel_id: u32,
+    ri: R,
+    wi: W,
+    cfg: &TunnelConfig,
+    cert_names: Option<&[String]>,
+) -> Result<(), std::io::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if let Some(protocol) = MuxProtocol::from_name(cfg.mux()) {
+        return serve_mux_session(tunnel_id, protocol, ri, wi, cfg).await;
+    }
+    let mut writer = ShapedWriter::new(wi, cfg.shaper());
+    let mut buf_reader = tokio::io::BufReader::with_capacity(DEFAULT_RECV_BUF_SIZE, ri);
+    let key = session_key(cfg.cipher.as_ref().unwrap()).await;
+    let method = String::from(cfg.cipher.as_ref().unwrap().method.as_str());
+    let mut rctx = CryptoContext::with_key(method.as_str(), &key[..], 0);
+    let mut wctx = CryptoContext::with_key(method.as_str(), &key[..], 0);
+    //1. auth connection
+    let recv_ev = match read_rmux_event(&mut rctx, &mut buf_reader).await {
+        Err(e) => return Err(make_io_error(&e.to_string())),
+        Ok(ev) => ev,
+    };
+    let auth_req: AuthRequest = match bincode::deserialize(&recv_ev.body[..]) {
+        Ok(m) => m,
+        Err(err) => {
+            error!(
+                "Failed to parse AuthRequest with error:{} while data len:{} {}",
+                err,
+                recv_ev.body.len(),
+                recv_ev.header.len(),
+            );
+            return Err(make_io_error("Failed to parse AuthRequest"));
+        }
+    };
+    let trailer = AuthTrailer::decode(&auth_req, &recv_ev.body[..]);
+    if !is_fresh_auth(cfg, &trailer) {
+        return Err(make_io_error("replayed or stale auth event."));
+    }
+    let version = match negotiate_version(trailer.version) {
+        Ok(v) => v,
+        Err(e) => {
+            let buf = refuse_auth(&mut wctx, auth_req.method, e.as_str());
+            writer.write_all(&buf[..]).await?;
+            return Err(make_io_error(e.as_str()));
+        }
+    };
+    let cipher = match session_method(cfg, &auth_req, &trailer) {
+        Ok(m) => m,
+        Err(e) => {
+            let buf = refuse_auth(&mut wctx, auth_req.method, e.as_str());
+            writer.write_all(&buf[..]).await?;
+            return Err(make_io_error(e.as_str()));
+        }
+    };
+    let user = match session_user(cfg, &trailer, cert_names) {
+        Ok(u) => u,
+        Err(e) => {
+            let buf = refuse_auth(&mut wctx, auth_req.method, e.as_str());
+            writer.write_all(&buf[..]).await?;
+            return Err(make_io_error(e.as_str()));
+        }
+    };
+    let windows = session_windows(cfg, &trailer);
+    let compression = session_compression(cfg, &trailer);
+    let features = session_features(cfg, &trailer);
+    // a user's session is sealed with the user's key after the handshake,
+    // unless the client logged in by certificate only
+    let key = match user.as_ref() {
+        Some(u) if !trailer.key_id.is_empty() => {
+            user_session_key(cfg.cipher.as_ref().unwrap(), u.key.as_str()).await
+        }
+        _ => key,
+    };
+    let (features, mut key, answer) = session_key_share(&trailer, features, key);
+    let padding = session_padding(cfg, features);
+    let auth_res = AuthResponse {
+        success: true,
+        err: String::new(),
+        rand: rand::random::<u64>(),
+        method: cipher,
+    };
+    let reply = AuthTrailer {
+        window: Some(windows.recv),
+        compression: compression.mask(),
+        padding: padding.handshake_padding(),
+        features,
+        key_share: answer,
+        ..Default::default()
+    };
+    let mut res = new_auth_event(0, &auth_res, &reply);
+    let mut buf = BytesMut::new();
+    wctx.encrypt(&mut res, &mut buf);
+    writer.write_all(&buf[..]).await?;
+    let rctx = CryptoContext::with_key(auth_res.method.as_str(), &key[..], auth_res.rand);
+    let wctx = CryptoContext::with_key(auth_res.method.as_str(), &key[..], auth_res.rand);
+    zeroize(&mut key[..]);
+    let mut ctx = MuxContext::new("", tunnel_id, rctx, wctx, 0);
+    if let Some(u) = user {
+        info!("[{}]Session of user {}", tunnel_id, u.id);
+        ctx.set_user(u);
+    }
+    ctx.set_windows(windows);
+    ctx.set_compression(compression);
+    ctx.set_padding(padding);
+    ctx.set_rekey(RekeyPolicy::new(
+        cfg.rekey_interval_mins,
+        cfg.rekey_after_mb,
+    ));
+    ctx.set_limits(SessionLimits::new(
+        cfg.max_session_age_mins,
+        cfg.reconnect_interval_mins,
+        cfg.max_mb_per_key,
+    ));
+    ctx.set_protocol(version, features);
+    process_rmux_session(ctx, &mut buf_reader, &mut writer, cfg.relay_buf_size()).await?;
+    Ok(())
+}
+
+pub async fn handle_rmux_tls(
+    tunnel_id: u32,
+    inbound: TcpStream,
+    acceptor: TlsAcceptor,
+    cfg: TunnelConfig,
+) -> Result<(), std::io::Error> {
+    let tls = tls_accept(inbound, &acceptor).await?;
+    let (read, write) = tokio::io::split(tls);
+    match cfg.record_bucket() {
+        Some(bucket) => {
+            let (read, write) = pad_records(bucket, read, write);
+            serve_rmux_session(tunnel_id, read, write, &cfg).await
+        }
+        None => serve_rmux_session(tunnel_id, read, write, &cfg).await,
+    }
+}
+
+/// `handle_rmux_tls` for "noise://" listeners, which take the clients whose
+/// keys `keys` lists.
+pub async fn handle_rmux_noise(
+    tunnel_id: u32,
+    inbound: TcpStream,
+    keys: NoiseKeys,
+    cfg: TunnelConfig,
+) -> Result<(), std::io::Error> {
+    let (read, write) = tokio::io::split(inbound);
+    let (read, write) = noise_accept(&keys, read, write).await?;
+    serve_rmux_session(tunnel_id, read, write, &cfg).await
+}
+
+/// `handle_rmux_tls` for listeners with `tls.client_ca`, the client's
+/// certificate picks the session's user.
+pub async fn handle_rmux_client_auth(
+    tunnel_id: u32,
+    inbound: TcpStream,
+    acceptor: ClientAuthAcceptor,
+    cfg: TunnelConfig,
+) -> Result<(), std::io::Error> {
+    let (tls, names) = acceptor.accept(inbound).await?;
+    let (read, write) = tokio::io::split(tls);
+    match cfg.record_bucket() {
+        Some(bucket) => {
+            let (read, write) = pad_records(bucket, read, write);
+            serve_rmux_session_for(tunnel_id, read, write, &cfg, Some(&names[..])).await
+        }
+        None => serve_rmux_session_for(tunnel_id, read, write, &cfg, Some(&names[..])).await,
+    }
+}
@@ -0,0 +1,127 @@
+//! Fetching of the rule lists pac rules refer to. A list is read from its
+//! cache file at start, then fetched again over its channel whenever its
+//! update interval passed, and written back to the cache.
+use crate::channel::get_channel_stream;
+use crate::config::{set_rule_list, RuleList, RuleListConfig};
+use crate::transport::{new_client_config, read_response, tls_connect_io};
+use crate::utils::make_io_error;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::time::{delay_for, timeout};
+use url::Url;
+
+const DEFAULT_UPDATE_HOURS: u64 = 24;
+/// wait before the first fetch, for the channels to connect
+const FIRST_FETCH_DELAY: Duration = Duration::from_secs(5);
+const RETRY_DELAY: Duration = Duration::from_secs(300);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn cache_path(cfg: &RuleListConfig) -> PathBuf {
+    match cfg.cache.as_ref() {
+        Some(p) => PathBuf::from(p),
+        None => std::env::temp_dir().join(format!("rsnova-{}.rules", cfg.name)),
+    }
+}
+
+/// Loads the cached lists and starts keeping them up to date.
+pub fn start_rule_lists(lists: &[RuleListConfig]) {
+    for cfg in lists {
+        let cache = cache_path(cfg);
+        let mut age = None;
+        if let Ok(text) = std::fs::read_to_string(&cache) {
+            let list = RuleList::parse(text.as_str());
+            info!(
+                "Rule list {} loaded {} rules from {:?}",
+                cfg.name,
+                list.len(),
+                cache
+            );
+            set_rule_list(cfg.name.as_str(), list);
+            age = std::fs::metadata(&cache)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| SystemTime::now().duration_since(t).ok());
+        }
+        tokio::spawn(update_rule_list(cfg.clone(), cache, age));
+    }
+}
+
+async fn update_rule_list(cfg: RuleListConfig, cache: PathBuf, age: Option<Duration>) {
+    let interval =
+        Duration::from_secs(cfg.update_hours.unwrap_or(DEFAULT_UPDATE_HOURS).max(1) * 3600);
+    let mut wait = match age {
+        Some(age) if age < interval => interval - age,
+        _ => FIRST_FETCH_DELAY,
+    };
+    loop {
+        delay_for(wait).await;
+        let channel = cfg.channel.as_deref().unwrap_or("direct");
+        match timeout(FETCH_TIMEOUT, fetch_text(channel, cfg.url.as_str())).await {
+            Ok(Ok(text)) => {
+                let list = RuleList::parse(text.as_str());
+                info!("Rule list {} fetched with {} rules", cfg.name, list.len());
+                set_rule_list(cfg.name.as_str(), list);
+                if let Err(e) = std::fs::write(&cache, text.as_bytes()) {
+                    warn!("Failed to write rule list cache {:?}:{}", cache, e);
+                }
+                wait = interval;
+            }
+            Ok(Err(e)) => {
+                warn!("Failed to fetch rule list {}:{}", cfg.name, e);
+                wait = RETRY_DELAY;
+            }
+            Err(_) => {
+                warn!("Fetching rule list {} timed out", cfg.name);
+                wait = RETRY_DELAY;
+            }
+        }
+    }
+}
+
+/// Gets `url` over `channel`, the body of a 200 response.
+async fn fetch_text(channel: &str, url: &str) -> Result<String, std::io::Error> {
+    let url = match Url::parse(url) {
+        Ok(u) => u,
+        Err(e) => return Err(make_io_error(&e.to_string())),
+    };
+    let host = match url.host_str() {
+        Some(h) => String::from(h),
+        None => return Err(make_io_error("rule list url without host")),
+    };
+    let port = url.port_or_known_default().unwrap_or(80);
+    let path = match url.query() {
+        Some(q) => format!("{}?{}", url.path(), q),
+        None => String::from(url.path()),
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: rsnova\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    let mut stream =
+        get_channel_stream(String::from(channel), format!("{}:{}", host, port)).await?;
+    let (reader, writer) = stream.split();
+    let body = if url.scheme() == "https" {
+        let tls_cfg = Arc::new(new_client_config(&["http/1.1"]));
+        let tls = tls_connect_io(reader, writer, host.as_str(), tls_cfg).await?;
+        let (reader, writer) = tokio::io::split(tls);
+        get(reader, writer, request.as_bytes()).await?
+    } else {
+        get(reader, writer, request.as_bytes()).await?
+    };
+    String::from_utf8(body).map_err(|_| make_io_error("rule list is not utf-8"))
+}
+
+async fn get<R, W>(mut reader: R, mut writer: W, request: &[u8]) -> Result<Vec<u8>, std::io::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(request).await?;
+    let resp = read_response(&mut reader, false).await?;
+    if resp.status != 200 {
+        return Err(make_io_error(&format!("http status {}", resp.status)));
+    }
+    Ok(resp.body)
+}
@@ -0,0 +1,71 @@
+use super::relay::relay_stream;
+use crate::config::TunnelConfig;
+use crate::transport::{parse_ss_addr, ShadowsocksCipher, ShadowsocksReader, ShadowsocksWriter};
+use crate::utils::{make_error, DEFAULT_HANDSHAKE_TIMEOUT};
+
+use std::error::Error;
+use std::net::Shutdown;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+
+// a domain target is at most 1 + 1 + 255 + 2 bytes
+const MAX_SS_ADDR_LEN: usize = 259;
+
+pub fn shadowsocks_cipher(cfg: &TunnelConfig) -> Result<ShadowsocksCipher, Box<dyn Error>> {
+    let cipher = match cfg.cipher.as_ref() {
+        Some(c) => c,
+        None => return Err(make_error("ss listener requires 'cipher'")),
+    };
+    Ok(ShadowsocksCipher::new(
+        cipher.method.as_str(),
+        cipher.key.as_str(),
+    )?)
+}
+
+/// Reads the target address leading the stream, returning it with the
+/// payload already read behind it.
+async fn read_target<R>(reader: &mut R) -> Result<(String, Vec<u8>), Box<dyn Error>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = vec![0u8; 1024];
+    let mut len = 0;
+    loop {
+        if let Some((target, n)) = parse_ss_addr(&buf[..len])? {
+            return Ok((target, buf[n..len].to_vec()));
+        }
+        if len >= MAX_SS_ADDR_LEN {
+            return Err(make_error("invalid shadowsocks target address"));
+        }
+        let n = reader.read(&mut buf[len..]).await?;
+        if n == 0 {
+            return Err(make_error(
+                "shadowsocks stream closed before target address",
+            ));
+        }
+        len += n;
+    }
+}
+
+/// Serves a shadowsocks AEAD client on an `ss://` listener.
+pub async fn handle_shadowsocks(
+    tunnel_id: u32,
+    mut inbound: TcpStream,
+    cipher: ShadowsocksCipher,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    {
+        let (ri, wi) = inbound.split();
+        let mut reader = ShadowsocksReader::new(ri, &cipher);
+        let mut writer = ShadowsocksWriter::new(wi, &cipher)?;
+        let (target, payload) =
+            match tokio::time::timeout(DEFAULT_HANDSHAKE_TIMEOUT, read_target(&mut reader)).await {
+                Ok(r) => r?,
+                Err(_) => return Err(make_error("timeout waiting for shadowsocks target")),
+            };
+        info!("[{}]Handle shadowsocks proxy to {}", tunnel_id, target);
+        let _ = relay_stream(tunnel_id, &mut reader, &mut writer, target, &cfg, payload).await;
+    }
+    let _ = inbound.shutdown(Shutdown::Both);
+    Ok(())
+}
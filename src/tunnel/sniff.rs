@@ -0,0 +1,146 @@
+use super::relay::relay_connection;
+use super::tls::{parse_sni, valid_tls_version};
+use crate::config::TunnelConfig;
+use crate::utils::PeekableReader;
+
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::AsyncRead;
+use tokio::net::TcpStream;
+
+// clients of server-speaks-first protocols send nothing, don't hold them long
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(300);
+const SNIFF_HEAD_TIMEOUT: Duration = Duration::from_secs(2);
+// the largest TLS record
+const MAX_SNIFF_LEN: usize = 5 + 16 * 1024;
+
+fn sniffed_domain(host: &str) -> Option<String> {
+    let host = host.trim().trim_end_matches('.').to_lowercase();
+    if host.is_empty() || host.starts_with('[') || host.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    Some(host)
+}
+
+fn http_host(head: &[u8]) -> Option<String> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+    req.parse(head).ok()?;
+    let host = req
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("Host"))?;
+    let host = std::str::from_utf8(host.value).ok()?;
+    let host = match host.rfind(':') {
+        Some(i) if !host.ends_with(']') => &host[..i],
+        _ => host,
+    };
+    sniffed_domain(host)
+}
+
+/// Domain of the ClientHello SNI or the HTTP Host header the client starts
+/// with, everything read stays in the reader.
+async fn sniff_domain<R>(reader: &mut PeekableReader<R>) -> Option<String>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut prefix = [0u8; 8];
+    let n = match tokio::time::timeout(SNIFF_TIMEOUT, reader.peek_some(&mut prefix)).await {
+        Ok(Ok(n)) => n,
+        _ => return None,
+    };
+    let prefix = &prefix[..n];
+    if valid_tls_version(prefix) {
+        let mut header = [0u8; 5];
+        if reader
+            .peek_exact_timeout(&mut header, SNIFF_HEAD_TIMEOUT)
+            .await
+            .ok()?
+            < header.len()
+        {
+            return None;
+        }
+        let len = 5 + u16::from_be_bytes([header[3], header[4]]) as usize;
+        let mut record = vec![0u8; len];
+        if reader
+            .peek_exact_timeout(&mut record, SNIFF_HEAD_TIMEOUT)
+            .await
+            .ok()?
+            < len
+        {
+            return None;
+        }
+        return parse_sni(&record[..])
+            .ok()
+            .and_then(|sni| sniffed_domain(sni.as_str()));
+    }
+    // request methods are upper case tokens
+    if !prefix.is_empty() && prefix.iter().all(|c| c.is_ascii_uppercase() || *c == b' ') {
+        let head = reader
+            .peek_until_timeout(b"\r\n\r\n", MAX_SNIFF_LEN, SNIFF_HEAD_TIMEOUT)
+            .await
+            .ok()?;
+        return http_host(&head[..]);
+    }
+    None
+}
+
+/// Relays a connection of a transparent listener to its original destination
+/// `dst`. With `sniff` on, the domain in the client's first bytes replaces
+/// the ip, so domain rules apply and the remote resolves the name itself.
+pub(super) async fn relay_sniffed(
+    tunnel_id: u32,
+    mut inbound: TcpStream,
+    cfg: &TunnelConfig,
+    dst: SocketAddr,
+) -> Result<(), Box<dyn Error>> {
+    let mut target = dst.to_string();
+    let peeked = if cfg.sniff() {
+        let mut reader = PeekableReader::with_watermarks(&mut inbound, 0, MAX_SNIFF_LEN);
+        if let Some(domain) = sniff_domain(&mut reader).await {
+            info!("[{}]Sniffed {} for {}", tunnel_id, domain, target);
+            target = format!("{}:{}", domain, dst.port());
+        }
+        let (_, peeked) = reader.into_inner();
+        peeked.to_vec()
+    } else {
+        Vec::new()
+    };
+    relay_connection(tunnel_id, inbound, cfg, target, peeked).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_host() {
+        let head = b"GET / HTTP/1.1\r\nHost: Example.COM:8080\r\n\r\n";
+        assert_eq!(http_host(head), Some(String::from("example.com")));
+        let head = b"GET / HTTP/1.1\r\nhost: example.com.\r\n\r\n";
+        assert_eq!(http_host(head), Some(String::from("example.com")));
+        let head = b"GET / HTTP/1.1\r\nHost: 10.0.0.1:80\r\n\r\n";
+        assert_eq!(http_host(head), None);
+        let head = b"GET / HTTP/1.1\r\nHost: [::1]\r\n\r\n";
+        assert_eq!(http_host(head), None);
+    }
+
+    #[tokio::test]
+    async fn test_sniff_tls() {
+        // ClientHello with only a server_name extension for "a.io"
+        let mut hello = vec![0x01, 0, 0, 0, 0x03, 0x03];
+        hello.extend_from_slice(&[0u8; 32]);
+        hello.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        let ext = [0u8, 0, 0, 9, 0, 7, 0, 0, 4, b'a', b'.', b'i', b'o'];
+        hello.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&ext);
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        record.extend_from_slice(&hello);
+
+        let mut reader = PeekableReader::new(&record[..]);
+        assert_eq!(sniff_domain(&mut reader).await, Some(String::from("a.io")));
+        assert_eq!(reader.peeked().len(), record.len());
+    }
+}
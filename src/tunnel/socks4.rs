@@ -0,0 +1,108 @@
+use super::relay::relay_connection;
+use crate::config::TunnelConfig;
+use crate::utils::{make_error, DEFAULT_HANDSHAKE_TIMEOUT};
+use std::error::Error;
+use std::net::Ipv4Addr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+mod v4 {
+    pub const VERSION: u8 = 4;
+    pub const CMD_CONNECT: u8 = 1;
+    pub const REPLY_VERSION: u8 = 0;
+    pub const REQUEST_GRANTED: u8 = 0x5A;
+    pub const REQUEST_REJECTED: u8 = 0x5B;
+    // longest USERID or SOCKS4a host name accepted
+    pub const MAX_FIELD_LEN: usize = 255;
+}
+
+async fn read_cstring<S>(inbound: &mut S) -> Result<Vec<u8>, Box<dyn Error>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut data = Vec::new();
+    loop {
+        let b = inbound.read_u8().await?;
+        if b == 0 {
+            return Ok(data);
+        }
+        if data.len() >= v4::MAX_FIELD_LEN {
+            return Err(make_error("socks4 field too long"));
+        }
+        data.push(b);
+    }
+}
+
+async fn reply<S>(inbound: &mut S, code: u8) -> Result<(), Box<dyn Error>>
+where
+    S: AsyncWrite + Unpin,
+{
+    // port and address are ignored by clients for CONNECT
+    let resp = [v4::REPLY_VERSION, code, 0, 0, 0, 0, 0, 0];
+    inbound.write_all(&resp).await?;
+    Ok(())
+}
+
+/// Reads a SOCKS4 or SOCKS4a CONNECT request, returning its target.
+async fn socks4_handshake<S>(inbound: &mut S, cfg: &TunnelConfig) -> Result<String, Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = [0u8; 8];
+    inbound.read_exact(&mut head).await?;
+    if head[0] != v4::VERSION {
+        return Err(make_error("didn't confirm with v4 version"));
+    }
+    let _user_id = read_cstring(inbound).await?;
+    let port = ((head[2] as u16) << 8) | (head[3] as u16);
+    let ip = Ipv4Addr::new(head[4], head[5], head[6], head[7]);
+    // SOCKS4a: 0.0.0.x with x != 0 means a host name follows the USERID
+    let target = if head[4..7] == [0, 0, 0] && head[7] != 0 {
+        let host = read_cstring(inbound).await?;
+        match String::from_utf8(host) {
+            Ok(h) if !h.is_empty() => format!("{}:{}", h, port),
+            _ => {
+                reply(inbound, v4::REQUEST_REJECTED).await?;
+                return Err(make_error("invalid socks4a host"));
+            }
+        }
+    } else {
+        format!("{}:{}", ip, port)
+    };
+    if head[1] != v4::CMD_CONNECT {
+        reply(inbound, v4::REQUEST_REJECTED).await?;
+        return Err(make_error("unsupported command"));
+    }
+    if cfg.requires_auth() {
+        // SOCKS4 carries no password, so it can not log in
+        reply(inbound, v4::REQUEST_REJECTED).await?;
+        return Err(make_error("socks4 rejected on a listener requiring auth"));
+    }
+    reply(inbound, v4::REQUEST_GRANTED).await?;
+    Ok(target)
+}
+
+pub async fn handle_socks4(
+    tunnel_id: u32,
+    mut inbound: TcpStream,
+    cfg: &TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let target_addr = match tokio::time::timeout(
+        DEFAULT_HANDSHAKE_TIMEOUT,
+        socks4_handshake(&mut inbound, cfg),
+    )
+    .await
+    {
+        Ok(r) => r?,
+        Err(_) => return Err(make_error("timeout during socks4 handshake")),
+    };
+    info!(
+        "[{}]Handle SOCKS4 proxy to {} with local:{} remote:{}",
+        tunnel_id,
+        target_addr,
+        inbound.local_addr().unwrap(),
+        inbound.peer_addr().unwrap()
+    );
+    relay_connection(tunnel_id, inbound, cfg, target_addr, Vec::new()).await?;
+    Ok(())
+}
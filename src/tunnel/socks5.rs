@@ -1,12 +1,19 @@
-use super::relay::relay_connection;
-use crate::utils::make_error;
+use super::relay::{is_blocked, relay_connection, relay_stream, UdpRouter};
+use crate::transport::tls_accept;
+use crate::utils::{make_error, DEFAULT_HANDSHAKE_TIMEOUT};
 
 use crate::config::TunnelConfig;
+use crate::rmux::UDP_RELAY_QUEUE;
+use async_tls::TlsAcceptor;
 use std::error::Error;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+
+const MAX_DATAGRAM: usize = 65535;
 
 mod v5 {
     pub const VERSION: u8 = 5;
@@ -14,6 +21,12 @@ mod v5 {
     pub const METH_NO_AUTH: u8 = 0;
     pub const METH_GSSAPI: u8 = 1;
     pub const METH_USER_PASS: u8 = 2;
+    pub const METH_NO_ACCEPTABLE: u8 = 0xFF;
+
+    // RFC 1929 username/password sub-negotiation
+    pub const USER_PASS_VERSION: u8 = 1;
+    pub const USER_PASS_SUCCESS: u8 = 0;
+    pub const USER_PASS_FAILURE: u8 = 1;
 
     pub const CMD_CONNECT: u8 = 1;
     pub const CMD_BIND: u8 = 2;
@@ -24,6 +37,7 @@ mod v5 {
     pub const ATYP_DOMAIN: u8 = 3;
 
     pub const SOCKS_RESP_SUUCESS: u8 = 0;
+    pub const SOCKS_RESP_NOT_ALLOWED: u8 = 2;
 }
 
 // Extracts the name and port from addr_buf and returns them, converting
@@ -46,26 +60,135 @@ fn name_port(addr_buf: &[u8]) -> Option<String> {
     Some(format!("{}:{}", hostname, port))
 }
 
-pub async fn handle_socks5(
-    tunnel_id: u32,
-    mut inbound: TcpStream,
+/// The target and the data of a datagram a client sent to its UDP
+/// association, fragments are not supported.
+fn parse_udp_request(buf: &[u8]) -> Option<(String, &[u8])> {
+    if buf.len() < 4 || buf[2] != 0 {
+        return None;
+    }
+    let (addr, n) = match buf[3] {
+        v5::ATYP_IPV4 => {
+            let a = buf.get(4..10)?;
+            let ip = Ipv4Addr::new(a[0], a[1], a[2], a[3]);
+            let port = u16::from_be_bytes([a[4], a[5]]);
+            (SocketAddr::new(IpAddr::V4(ip), port).to_string(), 10)
+        }
+        v5::ATYP_IPV6 => {
+            let a = buf.get(4..22)?;
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&a[..16]);
+            let port = u16::from_be_bytes([a[16], a[17]]);
+            (
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port).to_string(),
+                22,
+            )
+        }
+        v5::ATYP_DOMAIN => {
+            let len = *buf.get(4)? as usize;
+            let addr_buf = buf.get(5..5 + len + 2)?;
+            (name_port(addr_buf)?, 5 + len + 2)
+        }
+        _ => return None,
+    };
+    Some((addr, &buf[n..]))
+}
+
+/// Appends `addr` in the form of a SOCKS5 reply, a domain unless it is an
+/// ip and port.
+fn put_socks_addr(out: &mut Vec<u8>, addr: &str) {
+    match addr.parse::<SocketAddr>() {
+        Ok(SocketAddr::V4(a)) => {
+            out.push(v5::ATYP_IPV4);
+            out.extend_from_slice(&a.ip().octets());
+            out.extend_from_slice(&a.port().to_be_bytes());
+        }
+        Ok(SocketAddr::V6(a)) => {
+            out.push(v5::ATYP_IPV6);
+            out.extend_from_slice(&a.ip().octets());
+            out.extend_from_slice(&a.port().to_be_bytes());
+        }
+        Err(_) => {
+            let (host, port) = match addr.rfind(':') {
+                Some(i) => (&addr[..i], addr[i + 1..].parse::<u16>().unwrap_or(0)),
+                None => (addr, 0),
+            };
+            let host = &host.as_bytes()[..std::cmp::min(host.len(), 255)];
+            out.push(v5::ATYP_DOMAIN);
+            out.push(host.len() as u8);
+            out.extend_from_slice(host);
+            out.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+}
+
+async fn socks5_auth<S>(inbound: &mut S, cfg: &TunnelConfig) -> Result<(), Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = [0u8; 2];
+    inbound.read_exact(&mut head).await?;
+    if head[0] != v5::USER_PASS_VERSION {
+        return Err(make_error("invalid username/password auth version"));
+    }
+    let mut username = vec![0u8; head[1] as usize];
+    inbound.read_exact(&mut username).await?;
+    let mut plen = [0u8; 1];
+    inbound.read_exact(&mut plen).await?;
+    let mut password = vec![0u8; plen[0] as usize];
+    inbound.read_exact(&mut password).await?;
+    if !cfg.check_user(&username, &password) {
+        inbound
+            .write_all(&[v5::USER_PASS_VERSION, v5::USER_PASS_FAILURE])
+            .await?;
+        return Err(make_error(
+            format!(
+                "socks5 auth failed for user:{}",
+                String::from_utf8_lossy(&username)
+            )
+            .as_str(),
+        ));
+    }
+    inbound
+        .write_all(&[v5::USER_PASS_VERSION, v5::USER_PASS_SUCCESS])
+        .await?;
+    Ok(())
+}
+
+/// The command and the target of a client's request, the success reply
+/// of a CONNECT is sent already.
+async fn socks5_handshake<S>(
+    inbound: &mut S,
     cfg: &TunnelConfig,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(u8, String), Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     //let mut peek_buf = Vec::new();
     let mut num_methods_buf = [0u8; 2];
     inbound.read_exact(&mut num_methods_buf).await?;
     let mut vdata = vec![0; num_methods_buf[1] as usize];
     inbound.read_exact(&mut vdata).await?;
-    if !vdata.contains(&v5::METH_NO_AUTH) {
+    let method = if cfg.requires_auth() {
+        v5::METH_USER_PASS
+    } else {
+        v5::METH_NO_AUTH
+    };
+    if !vdata.contains(&method) {
+        inbound
+            .write_all(&[v5::VERSION, v5::METH_NO_ACCEPTABLE])
+            .await?;
         return Err(make_error("no supported method given"));
     }
-    inbound.write_all(&[v5::VERSION, v5::METH_NO_AUTH]).await?;
+    inbound.write_all(&[v5::VERSION, method]).await?;
+    if method == v5::METH_USER_PASS {
+        socks5_auth(inbound, cfg).await?;
+    }
     let mut head = [0u8; 4];
     inbound.read_exact(&mut head).await?;
     if head[0] != v5::VERSION {
         return Err(make_error("didn't confirm with v5 version"));
     }
-    if head[1] != v5::CMD_CONNECT {
+    if head[1] != v5::CMD_CONNECT && head[1] != v5::CMD_UDP_ASSOCIATE {
         return Err(make_error("unsupported command"));
     }
     let target_addr = match head[3] {
@@ -109,6 +232,9 @@ pub async fn handle_socks5(
             return Err(make_error(msg.as_str()));
         }
     };
+    if head[1] == v5::CMD_UDP_ASSOCIATE {
+        return Ok((head[1], target_addr));
+    }
     let mut resp = [0u8; 10];
     // VER - protocol version
     resp[0] = 5;
@@ -117,12 +243,100 @@ pub async fn handle_socks5(
     // In theory this should reply back with a bunch more kinds of
     // errors if possible, but for now we just recognize a few concrete
     // errors.
-    resp[1] = v5::SOCKS_RESP_SUUCESS;
+    let blocked = is_blocked(cfg, target_addr.as_str());
+    resp[1] = if blocked {
+        v5::SOCKS_RESP_NOT_ALLOWED
+    } else {
+        v5::SOCKS_RESP_SUUCESS
+    };
 
     // RSV - reserved
     resp[2] = 0;
     resp[3] = 1; // socksAtypeV4         = 0x01
     inbound.write_all(&resp).await?;
+    if blocked {
+        return Err(make_error("target blocked by pac rule"));
+    }
+    Ok((head[1], target_addr))
+}
+
+/// Serves a UDP ASSOCIATE: a socket on the address the client reached the
+/// listener at relays the datagrams of the client's host until the control
+/// connection closes.
+async fn handle_udp_associate(
+    tunnel_id: u32,
+    mut inbound: TcpStream,
+    cfg: &TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let peer_ip = inbound.peer_addr()?.ip();
+    let socket = UdpSocket::bind(SocketAddr::new(inbound.local_addr()?.ip(), 0)).await?;
+    let bound = socket.local_addr()?;
+    let mut resp = vec![v5::VERSION, v5::SOCKS_RESP_SUUCESS, 0];
+    put_socks_addr(&mut resp, bound.to_string().as_str());
+    inbound.write_all(&resp[..]).await?;
+    info!(
+        "[{}]Handle SOCKS5 udp associate on {} for {}",
+        tunnel_id, bound, peer_ip
+    );
+
+    let (mut recv_half, mut send_half) = socket.split();
+    let (reply_tx, mut reply_rx) = mpsc::channel::<(String, Vec<u8>)>(UDP_RELAY_QUEUE);
+    let mut router = UdpRouter::new(tunnel_id, cfg.clone(), reply_tx);
+    // the client's socket, known from its first datagram
+    let mut client: Option<SocketAddr> = None;
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    let mut ctrl = [0u8; 64];
+    loop {
+        tokio::select! {
+            r = inbound.read(&mut ctrl) => match r {
+                Ok(0) | Err(_) => return Ok(()),
+                Ok(_) => {}
+            },
+            r = recv_half.recv_from(&mut buf) => {
+                let (n, from) = r?;
+                if from.ip() != peer_ip {
+                    continue;
+                }
+                client = Some(from);
+                if let Some((dst, data)) = parse_udp_request(&buf[..n]) {
+                    router.send_to(dst.as_str(), data.to_vec());
+                }
+            }
+            reply = reply_rx.recv() => {
+                let (from, data) = match reply {
+                    Some(r) => r,
+                    None => return Ok(()),
+                };
+                let client = match client {
+                    Some(c) => c,
+                    None => continue,
+                };
+                let mut packet = vec![0u8, 0, 0];
+                put_socks_addr(&mut packet, from.as_str());
+                packet.extend_from_slice(&data[..]);
+                send_half.send_to(&packet[..], &client).await?;
+            }
+        }
+    }
+}
+
+pub async fn handle_socks5(
+    tunnel_id: u32,
+    mut inbound: TcpStream,
+    cfg: &TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let (cmd, target_addr) = match tokio::time::timeout(
+        DEFAULT_HANDSHAKE_TIMEOUT,
+        socks5_handshake(&mut inbound, cfg),
+    )
+    .await
+    {
+        Ok(r) => r?,
+        Err(_) => return Err(make_error("timeout during socks5 handshake")),
+    };
+    if cmd == v5::CMD_UDP_ASSOCIATE {
+        return handle_udp_associate(tunnel_id, inbound, cfg).await;
+    }
 
     info!(
         "[{}]Handle SOCKS5 proxy to {} with local:{} remote:{}",
@@ -134,3 +348,77 @@ pub async fn handle_socks5(
     relay_connection(tunnel_id, inbound, cfg, target_addr, Vec::new()).await?;
     Ok(())
 }
+
+/// SOCKS5 over a non TCP carrier, like a unix socket or TLS.
+pub async fn handle_socks5_stream<S>(
+    tunnel_id: u32,
+    mut inbound: S,
+    cfg: &TunnelConfig,
+) -> Result<(), Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (cmd, target_addr) = match tokio::time::timeout(
+        DEFAULT_HANDSHAKE_TIMEOUT,
+        socks5_handshake(&mut inbound, cfg),
+    )
+    .await
+    {
+        Ok(r) => r?,
+        Err(_) => return Err(make_error("timeout during socks5 handshake")),
+    };
+    if cmd == v5::CMD_UDP_ASSOCIATE {
+        // the client's datagrams could not reach a socket behind this carrier
+        return Err(make_error("udp associate needs a tcp connection"));
+    }
+    info!("[{}]Handle SOCKS5 proxy to {}", tunnel_id, target_addr);
+    let (mut ri, mut wi) = tokio::io::split(inbound);
+    relay_stream(tunnel_id, &mut ri, &mut wi, target_addr, cfg, Vec::new()).await
+}
+
+/// Serves a `socks5s://` listener, SOCKS5 inside TLS so the proxy can be
+/// exposed on untrusted networks. With `tls.client_ca` set only clients with
+/// a certificate of that CA get through the handshake.
+pub async fn handle_socks5_tls(
+    tunnel_id: u32,
+    inbound: TcpStream,
+    acceptor: TlsAcceptor,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let tls = match tokio::time::timeout(DEFAULT_HANDSHAKE_TIMEOUT, tls_accept(inbound, &acceptor))
+        .await
+    {
+        Ok(r) => r?,
+        Err(_) => return Err(make_error("timeout during tls handshake")),
+    };
+    handle_socks5_stream(tunnel_id, tls, &cfg).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_udp_request() {
+        let mut packet = vec![0u8, 0, 0];
+        put_socks_addr(&mut packet, "8.8.8.8:53");
+        packet.extend_from_slice(b"query");
+        let (dst, data) = parse_udp_request(&packet[..]).unwrap();
+        assert_eq!(dst, "8.8.8.8:53");
+        assert_eq!(data, b"query");
+        let mut packet = vec![0u8, 0, 0];
+        put_socks_addr(&mut packet, "example.com:443");
+        assert_eq!(packet[3], v5::ATYP_DOMAIN);
+        assert_eq!(parse_udp_request(&packet[..]).unwrap().0, "example.com:443");
+        let mut packet = vec![0u8, 0, 0];
+        put_socks_addr(&mut packet, "[2001:db8::1]:53");
+        assert_eq!(
+            parse_udp_request(&packet[..]).unwrap().0,
+            "[2001:db8::1]:53"
+        );
+        // fragments and truncated addresses are dropped
+        packet[2] = 1;
+        assert!(parse_udp_request(&packet[..]).is_none());
+        assert!(parse_udp_request(&[0, 0, 0, v5::ATYP_IPV4, 1, 2]).is_none());
+    }
+}
@@ -1,5 +1,5 @@
 use super::relay::relay_connection;
-use crate::utils::make_error;
+use crate::utils::{make_error, DEFAULT_HANDSHAKE_TIMEOUT};
 
 use std::error::Error;
 
@@ -42,11 +42,24 @@ pub async fn peek_sni(inbound: &mut TcpStream) -> Result<(String, Vec<u8>), Box<
     let mut vdata = vec![0; n as usize];
     inbound.read_exact(&mut vdata).await?;
     peek_buf.extend_from_slice(&vdata[..]);
+    let server_name = parse_sni(&peek_buf[..])?;
+    Ok((server_name, peek_buf))
+}
+
+/// Server name of the ClientHello in a complete TLS record.
+pub fn parse_sni(record: &[u8]) -> Result<String, Box<dyn Error>> {
+    if record.len() < 5 + 42 {
+        return Err(make_error("no sufficient space for sni"));
+    }
+    let vdata = &record[5..];
     if vdata[0] != 0x01 {
         return Err(make_error("not clienthello handshake"));
     }
     let rest_buf = &vdata[38..];
     let sid_len = rest_buf[0] as usize;
+    if rest_buf.len() < 1 + sid_len {
+        return Err(make_error("invalid sid_len"));
+    }
     let rest_buf = &rest_buf[(1 + sid_len)..];
     if rest_buf.len() < 2 {
         return Err(make_error("no sufficient space for sni0"));
@@ -108,7 +121,7 @@ pub async fn peek_sni(inbound: &mut TcpStream) -> Result<(String, Vec<u8>), Box<
                 if name_type == 0 {
                     let server_name = String::from_utf8_lossy(&data[0..name_len]);
                     debug!("####Peek SNI:{}", server_name);
-                    return Ok((String::from(server_name), peek_buf));
+                    return Ok(String::from(server_name));
                 }
                 data = &data[name_len..];
             }
@@ -122,7 +135,11 @@ pub async fn handle_tls(
     mut inbound: TcpStream,
     cfg: &TunnelConfig,
 ) -> Result<(), Box<dyn Error>> {
-    let (sni, peek_buf) = peek_sni(&mut inbound).await?;
+    let (sni, peek_buf) =
+        match tokio::time::timeout(DEFAULT_HANDSHAKE_TIMEOUT, peek_sni(&mut inbound)).await {
+            Ok(r) => r?,
+            Err(_) => return Err(make_error("timeout reading tls client hello")),
+        };
     let mut target = sni;
     target.push_str(":443");
 
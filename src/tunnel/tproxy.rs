@@ -0,0 +1,276 @@
+use super::relay::{start_udp_flow, UdpFlow};
+use super::sniff::relay_sniffed;
+use crate::config::TunnelConfig;
+use crate::utils::{make_error, make_io_error};
+use futures::future::join;
+use nix::libc;
+use nix::sys::socket::{
+    bind, listen, socket, AddressFamily, InetAddr, SockAddr, SockFlag, SockType,
+};
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::mpsc;
+
+// linux/in.h and linux/in6.h
+const IP_TRANSPARENT: libc::c_int = 19;
+const IP_RECVORIGDSTADDR: libc::c_int = 20;
+const IPV6_TRANSPARENT: libc::c_int = 75;
+const IPV6_RECVORIGDSTADDR: libc::c_int = 74;
+
+const MAX_DATAGRAM: usize = 65535;
+
+type Datagram = (SocketAddr, SocketAddr, Vec<u8>);
+
+fn set_opt(fd: RawFd, level: libc::c_int, opt: libc::c_int) -> std::io::Result<()> {
+    let val: libc::c_int = 1;
+    let rc = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            opt,
+            &val as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A socket bound to `addr` that may accept or send for foreign addresses,
+/// needs CAP_NET_ADMIN.
+fn transparent_socket(addr: &SocketAddr, ty: SockType) -> std::io::Result<RawFd> {
+    let family = if addr.is_ipv4() {
+        AddressFamily::Inet
+    } else {
+        AddressFamily::Inet6
+    };
+    let fd = match socket(family, ty, SockFlag::SOCK_CLOEXEC, None) {
+        Ok(fd) => fd,
+        Err(e) => return Err(make_io_error(&e.to_string())),
+    };
+    let setup = || -> std::io::Result<()> {
+        set_opt(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR)?;
+        if addr.is_ipv4() {
+            set_opt(fd, libc::SOL_IP, IP_TRANSPARENT)?;
+            if ty == SockType::Datagram {
+                set_opt(fd, libc::SOL_IP, IP_RECVORIGDSTADDR)?;
+            }
+        } else {
+            set_opt(fd, libc::SOL_IPV6, IPV6_TRANSPARENT)?;
+            if ty == SockType::Datagram {
+                set_opt(fd, libc::SOL_IPV6, IPV6_RECVORIGDSTADDR)?;
+            }
+        }
+        match bind(fd, &SockAddr::new_inet(InetAddr::from_std(addr))) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(make_io_error(&e.to_string())),
+        }
+    };
+    if let Err(e) = setup() {
+        let _ = nix::unistd::close(fd);
+        return Err(e);
+    }
+    Ok(fd)
+}
+
+unsafe fn to_socket_addr(sa: *const libc::sockaddr) -> Option<SocketAddr> {
+    match (*sa).sa_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = &*(sa as *const libc::sockaddr_in);
+            let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                ip,
+                u16::from_be(sin.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let sin6 = &*(sa as *const libc::sockaddr_in6);
+            let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                ip,
+                u16::from_be(sin6.sin6_port),
+                0,
+                0,
+            )))
+        }
+        _ => None,
+    }
+}
+
+/// Receives one datagram along with its source and original destination.
+fn recv_orig_dst(
+    fd: RawFd,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    unsafe {
+        let mut src: libc::sockaddr_storage = std::mem::zeroed();
+        let mut control = [0u64; 16];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_name = &mut src as *mut libc::sockaddr_storage as *mut libc::c_void;
+        msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+        let n = libc::recvmsg(fd, &mut msg, 0);
+        if n < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let src =
+            match to_socket_addr(&src as *const libc::sockaddr_storage as *const libc::sockaddr) {
+                Some(a) => a,
+                None => return Err(make_io_error("unknown source address family")),
+            };
+        let mut dst = None;
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let c = &*cmsg;
+            // IP_ORIGDSTADDR and IPV6_ORIGDSTADDR share the values of the RECV options
+            if (c.cmsg_level == libc::SOL_IP && c.cmsg_type == IP_RECVORIGDSTADDR)
+                || (c.cmsg_level == libc::SOL_IPV6 && c.cmsg_type == IPV6_RECVORIGDSTADDR)
+            {
+                dst = to_socket_addr(libc::CMSG_DATA(cmsg) as *const libc::sockaddr);
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        Ok((n as usize, src, dst))
+    }
+}
+
+async fn serve_tcp(
+    addr: SocketAddr,
+    cfg: TunnelConfig,
+    ids: Arc<AtomicU32>,
+) -> std::io::Result<()> {
+    let fd = transparent_socket(&addr, SockType::Stream)?;
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    if let Err(e) = listen(fd, 1024) {
+        return Err(make_io_error(&e.to_string()));
+    }
+    listener.set_nonblocking(true)?;
+    let mut listener = TcpListener::from_std(listener)?;
+    while let Ok((inbound, _)) = listener.accept().await {
+        let tunnel_id = ids.fetch_add(1, Ordering::SeqCst);
+        // the socket is bound to the original destination
+        let dst = match inbound.local_addr() {
+            Ok(a) => a,
+            Err(_) => continue,
+        };
+        info!("[{}]Handle tproxy connection to {}", tunnel_id, dst);
+        let cfg = cfg.clone();
+        tokio::spawn(async move {
+            let _ = relay_sniffed(tunnel_id, inbound, &cfg, dst).await;
+        });
+    }
+    Ok(())
+}
+
+/// Relays one (source, original destination) pair, answers are sent from a
+/// socket bound to the original destination so the client sees it reply.
+fn new_udp_flow(
+    tunnel_id: u32,
+    src: SocketAddr,
+    dst: SocketAddr,
+    cfg: TunnelConfig,
+) -> std::io::Result<UdpFlow> {
+    let fd = transparent_socket(&dst, SockType::Datagram)?;
+    let reply = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+    reply.set_nonblocking(true)?;
+    let mut reply = UdpSocket::from_std(reply)?;
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    tokio::spawn(async move {
+        while let Some(data) = reply_rx.recv().await {
+            if reply.send_to(&data[..], &src).await.is_err() {
+                break;
+            }
+        }
+    });
+    info!("[{}]Handle tproxy udp {} -> {}", tunnel_id, src, dst);
+    Ok(start_udp_flow(tunnel_id, dst.to_string(), cfg, reply_tx))
+}
+
+async fn serve_udp(
+    addr: SocketAddr,
+    cfg: TunnelConfig,
+    ids: Arc<AtomicU32>,
+) -> std::io::Result<()> {
+    let fd = transparent_socket(&addr, SockType::Datagram)?;
+    let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+    let (tx, mut rx) = mpsc::unbounded_channel::<Datagram>();
+    // recvmsg with control messages has no tokio api, a thread blocks on it
+    std::thread::spawn(move || {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let (n, src, dst) = match recv_orig_dst(fd, &mut buf) {
+                Ok(r) => r,
+                Err(e) => {
+                    error!("tproxy udp recv error:{}", e);
+                    break;
+                }
+            };
+            let dst = match dst {
+                Some(d) => d,
+                None => continue,
+            };
+            if tx.send((src, dst, buf[..n].to_vec())).is_err() {
+                break;
+            }
+        }
+        drop(socket);
+    });
+    let mut flows: HashMap<(SocketAddr, SocketAddr), UdpFlow> = HashMap::new();
+    while let Some((src, dst, data)) = rx.recv().await {
+        let key = (src, dst);
+        let data = match flows.get(&key) {
+            Some(flow) => match flow.send(data) {
+                Ok(()) => continue,
+                Err(d) => d,
+            },
+            None => data,
+        };
+        flows.retain(|_, f| !f.is_done());
+        let tunnel_id = ids.fetch_add(1, Ordering::SeqCst);
+        let flow = match new_udp_flow(tunnel_id, src, dst, cfg.clone()) {
+            Ok(f) => f,
+            Err(e) => {
+                error!(
+                    "[{}]Failed to open tproxy udp flow to {}: {}",
+                    tunnel_id, dst, e
+                );
+                continue;
+            }
+        };
+        let _ = flow.send(data);
+        flows.insert(key, flow);
+    }
+    Ok(())
+}
+
+/// TCP and UDP on the same port of a `tproxy://` listener, fed by iptables
+/// TPROXY rules with the original addresses intact.
+pub async fn start_tproxy_server(addr: &str, cfg: TunnelConfig) -> Result<(), Box<dyn Error>> {
+    let addr: SocketAddr = match addr.parse() {
+        Ok(a) => a,
+        Err(_) => return Err(make_error("tproxy listener needs an ip address")),
+    };
+    let ids = Arc::new(AtomicU32::new(0));
+    let (tcp, udp) = join(
+        serve_tcp(addr, cfg.clone(), ids.clone()),
+        serve_udp(addr, cfg, ids),
+    )
+    .await;
+    tcp?;
+    udp?;
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use super::relay::{relay, relay_stream};
+use crate::config::TunnelConfig;
+use crate::transport::{parse_trojan_request, tls_accept, trojan_hash, TROJAN_CMD_CONNECT};
+use crate::utils::{make_error, DEFAULT_HANDSHAKE_TIMEOUT};
+
+use async_tls::TlsAcceptor;
+use ring::constant_time::verify_slices_are_equal;
+use std::error::Error;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// hash, cmd, the longest address and both CRLFs
+const MAX_TROJAN_HEAD_LEN: usize = 56 + 2 + 1 + 259 + 2;
+
+/// Password hashes a `trojan://` listener accepts.
+pub fn trojan_hashes(cfg: &TunnelConfig) -> Result<Arc<Vec<String>>, Box<dyn Error>> {
+    let passwords = match cfg.trojan.as_ref() {
+        Some(t) if !t.passwords.is_empty() => &t.passwords,
+        _ => return Err(make_error("trojan listener requires 'trojan.passwords'")),
+    };
+    Ok(Arc::new(
+        passwords.iter().map(|p| trojan_hash(p.as_str())).collect(),
+    ))
+}
+
+/// Reads into `head` until it holds a trojan request, returning its target
+/// and where the payload after it starts, none for anything else. `head`
+/// keeps every byte read, so what a timeout cuts short still goes to the
+/// fallback.
+async fn read_head<R>(
+    reader: &mut R,
+    hashes: &[String],
+    head: &mut Vec<u8>,
+) -> Result<Option<(String, usize)>, Box<dyn Error>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = [0u8; 1024];
+    loop {
+        match parse_trojan_request(&head[..]) {
+            Ok(Some(req)) => {
+                let known = hashes.iter().fold(false, |found, h| {
+                    found | verify_slices_are_equal(h.as_bytes(), req.hash.as_bytes()).is_ok()
+                });
+                if !known || req.cmd != TROJAN_CMD_CONNECT {
+                    return Ok(None);
+                }
+                return Ok(Some((req.target, req.len)));
+            }
+            Ok(None) if head.len() < MAX_TROJAN_HEAD_LEN => {}
+            _ => return Ok(None),
+        }
+        // a read cut by the timeout consumed nothing
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Err(make_error("trojan stream closed before request"));
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+}
+
+/// Serves a trojan client over TLS. Only CONNECT is relayed, anything else
+/// goes to the fallback site as if it was a plain HTTPS request.
+pub async fn handle_trojan(
+    tunnel_id: u32,
+    inbound: TcpStream,
+    acceptor: TlsAcceptor,
+    hashes: Arc<Vec<String>>,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let tls = tls_accept(inbound, &acceptor).await?;
+    let (mut ri, mut wi) = tokio::io::split(tls);
+    let mut head = Vec::new();
+    let request = match tokio::time::timeout(
+        DEFAULT_HANDSHAKE_TIMEOUT,
+        read_head(&mut ri, &hashes[..], &mut head),
+    )
+    .await
+    {
+        Ok(r) => r?,
+        Err(_) => None,
+    };
+    match request {
+        Some((target, start)) => {
+            info!("[{}]Handle trojan proxy to {}", tunnel_id, target);
+            let payload = head.split_off(start);
+            relay_stream(tunnel_id, &mut ri, &mut wi, target, &cfg, payload).await
+        }
+        None => {
+            let fallback = match cfg.trojan.as_ref().and_then(|t| t.fallback.as_ref()) {
+                Some(f) => f.clone(),
+                None => return Err(make_error("invalid trojan request")),
+            };
+            info!(
+                "[{}]Hand invalid trojan request to fallback {}",
+                tunnel_id, fallback
+            );
+            let mut remote = TcpStream::connect(fallback.as_str()).await?;
+            let (mut ro, mut wo) = remote.split();
+            wo.write_all(&head[..]).await?;
+            relay(
+                tunnel_id,
+                &mut ri,
+                &mut wi,
+                &mut ro,
+                &mut wo,
+                cfg.relay_buf_size(),
+            )
+            .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::encode_trojan_request;
+    use bytes::BytesMut;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    /// Sends its bytes, then nothing ever again.
+    struct Stall(Vec<u8>);
+
+    impl AsyncRead for Stall {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            if self.0.is_empty() {
+                return Poll::Pending;
+            }
+            let n = std::cmp::min(buf.len(), self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0.drain(..n);
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_head_timeout() {
+        let hashes = vec![trojan_hash("secret")];
+        // a client stopping halfway through the hash
+        let mut reader = Stall(hashes[0].as_bytes()[..20].to_vec());
+        let mut head = Vec::new();
+        let r = tokio::time::timeout(
+            Duration::from_millis(20),
+            read_head(&mut reader, &hashes[..], &mut head),
+        )
+        .await;
+        assert!(r.is_err());
+        // what the fallback gets
+        assert_eq!(&head[..], &hashes[0].as_bytes()[..20]);
+
+        let mut request = BytesMut::new();
+        encode_trojan_request(&hashes[0], TROJAN_CMD_CONNECT, "abc.com:443", &mut request).unwrap();
+        request.extend_from_slice(b"hello");
+        let mut reader = Stall(request.to_vec());
+        let mut head = Vec::new();
+        let (target, start) = read_head(&mut reader, &hashes[..], &mut head)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(target, "abc.com:443");
+        assert_eq!(&head[start..], b"hello");
+    }
+}
@@ -0,0 +1,415 @@
+use super::relay::UdpRouter;
+use super::sniff::relay_sniffed;
+use crate::config::TunnelConfig;
+use crate::rmux::UDP_RELAY_QUEUE;
+use crate::utils::{make_error, TunDevice, UDP_FLOW_IDLE};
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+// ports standing in for the rewritten tcp flows
+const NAT_PORT_BASE: u16 = 10000;
+const NAT_PORT_COUNT: u16 = 50000;
+const NAT_IDLE: Duration = Duration::from_secs(600);
+const MAX_PACKET: usize = 65535;
+
+fn sum16(data: &[u8], mut sum: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for c in &mut chunks {
+        sum += u32::from(u16::from_be_bytes([c[0], c[1]]));
+    }
+    if let [b] = chunks.remainder() {
+        sum += u32::from(*b) << 8;
+    }
+    sum
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn l4_checksum(src: Ipv4Addr, dst: Ipv4Addr, proto: u8, segment: &[u8]) -> u16 {
+    let mut sum = sum16(&src.octets(), 0);
+    sum = sum16(&dst.octets(), sum);
+    sum += u32::from(proto) + segment.len() as u32;
+    fold(sum16(segment, sum))
+}
+
+#[derive(Debug, PartialEq)]
+struct Flow {
+    proto: u8,
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    header_len: usize,
+}
+
+/// Addresses of an unfragmented IPv4 TCP or UDP packet, anything else is
+/// not proxied. IPv6 is out of scope: it would need an IPv6 gateway on the
+/// interface with its own listener and a second NAT.
+fn parse_packet(pkt: &[u8]) -> Option<Flow> {
+    if pkt.len() < 20 || pkt[0] >> 4 != 4 {
+        return None;
+    }
+    let header_len = usize::from(pkt[0] & 0x0f) * 4;
+    let total = usize::from(u16::from_be_bytes([pkt[2], pkt[3]]));
+    if header_len < 20 || total < header_len || total > pkt.len() {
+        return None;
+    }
+    // more fragments flag or a fragment offset
+    if u16::from_be_bytes([pkt[6], pkt[7]]) & 0x3fff != 0 {
+        return None;
+    }
+    let proto = pkt[9];
+    let min_len = match proto {
+        PROTO_TCP => 20,
+        PROTO_UDP => 8,
+        _ => return None,
+    };
+    if total < header_len + min_len {
+        return None;
+    }
+    let l4 = &pkt[header_len..];
+    let src = Ipv4Addr::new(pkt[12], pkt[13], pkt[14], pkt[15]);
+    let dst = Ipv4Addr::new(pkt[16], pkt[17], pkt[18], pkt[19]);
+    Some(Flow {
+        proto,
+        src: SocketAddrV4::new(src, u16::from_be_bytes([l4[0], l4[1]])),
+        dst: SocketAddrV4::new(dst, u16::from_be_bytes([l4[2], l4[3]])),
+        header_len,
+    })
+}
+
+/// Sets the addresses of a packet `parse_packet` accepted and fixes up both
+/// checksums.
+fn rewrite_packet(pkt: &mut [u8], header_len: usize, src: SocketAddrV4, dst: SocketAddrV4) {
+    let total = usize::from(u16::from_be_bytes([pkt[2], pkt[3]]));
+    let proto = pkt[9];
+    pkt[12..16].copy_from_slice(&src.ip().octets());
+    pkt[16..20].copy_from_slice(&dst.ip().octets());
+    pkt[10..12].copy_from_slice(&[0, 0]);
+    let sum = fold(sum16(&pkt[..header_len], 0));
+    pkt[10..12].copy_from_slice(&sum.to_be_bytes());
+    let segment = &mut pkt[header_len..total];
+    segment[0..2].copy_from_slice(&src.port().to_be_bytes());
+    segment[2..4].copy_from_slice(&dst.port().to_be_bytes());
+    let offset = if proto == PROTO_TCP { 16 } else { 6 };
+    segment[offset..offset + 2].copy_from_slice(&[0, 0]);
+    let mut sum = l4_checksum(*src.ip(), *dst.ip(), proto, segment);
+    if proto == PROTO_UDP && sum == 0 {
+        sum = 0xffff;
+    }
+    segment[offset..offset + 2].copy_from_slice(&sum.to_be_bytes());
+}
+
+fn udp_packet(src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+    let total = 28 + payload.len();
+    let mut pkt = vec![0u8; total];
+    pkt[0] = 0x45;
+    pkt[2..4].copy_from_slice(&(total as u16).to_be_bytes());
+    // don't fragment
+    pkt[6] = 0x40;
+    pkt[8] = 64;
+    pkt[9] = PROTO_UDP;
+    pkt[24..26].copy_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+    pkt[28..].copy_from_slice(payload);
+    rewrite_packet(&mut pkt, 20, src, dst);
+    pkt
+}
+
+struct NatEntry {
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    last: Instant,
+}
+
+/// TCP flows are handed to the kernel's own stack: a flow `src -> dst` is
+/// rewritten to `dst-ip:port -> gateway`, where the local listener accepts
+/// it and finds the original destination again by `port`.
+#[derive(Default)]
+struct TcpNat {
+    ports: HashMap<(SocketAddrV4, SocketAddrV4), u16>,
+    entries: HashMap<u16, NatEntry>,
+    cursor: u16,
+}
+
+impl TcpNat {
+    fn port_for(&mut self, src: SocketAddrV4, dst: SocketAddrV4) -> Option<u16> {
+        if let Some(port) = self.ports.get(&(src, dst)) {
+            if let Some(e) = self.entries.get_mut(port) {
+                e.last = Instant::now();
+            }
+            return Some(*port);
+        }
+        for _ in 0..NAT_PORT_COUNT {
+            let port = NAT_PORT_BASE + self.cursor;
+            self.cursor = (self.cursor + 1) % NAT_PORT_COUNT;
+            let free = match self.entries.get(&port) {
+                Some(e) => e.last.elapsed() > NAT_IDLE,
+                None => true,
+            };
+            if !free {
+                continue;
+            }
+            if let Some(old) = self.entries.remove(&port) {
+                self.ports.remove(&(old.src, old.dst));
+            }
+            let last = Instant::now();
+            self.entries.insert(port, NatEntry { src, dst, last });
+            self.ports.insert((src, dst), port);
+            return Some(port);
+        }
+        None
+    }
+    fn lookup(&mut self, port: u16) -> Option<(SocketAddrV4, SocketAddrV4)> {
+        let e = self.entries.get_mut(&port)?;
+        e.last = Instant::now();
+        Some((e.src, e.dst))
+    }
+}
+
+/// Rewrites a TCP packet read from the device, false drops it.
+fn nat_tcp(pkt: &mut [u8], flow: &Flow, gateway: SocketAddrV4, nat: &Mutex<TcpNat>) -> bool {
+    let mut nat = nat.lock().unwrap();
+    if flow.src == gateway {
+        // the listener answering, back to the addresses of the original flow
+        return match nat.lookup(flow.dst.port()) {
+            Some((src, dst)) => {
+                rewrite_packet(pkt, flow.header_len, dst, src);
+                true
+            }
+            None => false,
+        };
+    }
+    match nat.port_for(flow.src, flow.dst) {
+        Some(port) => {
+            let src = SocketAddrV4::new(*flow.dst.ip(), port);
+            rewrite_packet(pkt, flow.header_len, src, gateway);
+            true
+        }
+        None => false,
+    }
+}
+
+async fn serve_tcp(
+    mut listener: TcpListener,
+    nat: Arc<Mutex<TcpNat>>,
+    cfg: TunnelConfig,
+    ids: Arc<AtomicU32>,
+) {
+    while let Ok((inbound, peer)) = listener.accept().await {
+        let tunnel_id = ids.fetch_add(1, Ordering::SeqCst);
+        let port = peer.port();
+        let dst = match nat.lock().unwrap().lookup(port) {
+            Some((_, dst)) => SocketAddr::V4(dst),
+            None => continue,
+        };
+        info!("[{}]Handle tun connection to {}", tunnel_id, dst);
+        let cfg = cfg.clone();
+        tokio::spawn(async move {
+            let _ = relay_sniffed(tunnel_id, inbound, &cfg, dst).await;
+        });
+    }
+}
+
+/// Relays the datagrams of one source socket to whichever targets it
+/// sends to, replies are written back to the device as packets from the
+/// address they came from.
+fn new_udp_router(
+    tunnel_id: u32,
+    src: SocketAddrV4,
+    cfg: TunnelConfig,
+    packets: std::sync::mpsc::Sender<Vec<u8>>,
+) -> UdpRouter {
+    let (reply_tx, mut reply_rx) = mpsc::channel::<(String, Vec<u8>)>(UDP_RELAY_QUEUE);
+    tokio::spawn(async move {
+        while let Some((from, data)) = reply_rx.recv().await {
+            let from: SocketAddrV4 = match from.parse() {
+                Ok(a) => a,
+                Err(_) => continue,
+            };
+            if data.len() > MAX_PACKET - 28 {
+                continue;
+            }
+            if packets.send(udp_packet(from, src, &data[..])).is_err() {
+                break;
+            }
+        }
+    });
+    info!("[{}]Handle tun udp from {}", tunnel_id, src);
+    UdpRouter::new(tunnel_id, cfg, reply_tx)
+}
+
+/// A `tun://gateway:port/prefix` listener: creates a tun interface with
+/// the gateway address, TCP flows routed into it are terminated by the
+/// kernel on `port` and UDP flows are relayed per datagram. IPv4 only,
+/// IPv6 packets routed into the interface are dropped with a warning.
+/// Routes into the interface are up to the user.
+pub async fn start_tun_server(
+    gateway: &str,
+    prefix: &str,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let gateway: SocketAddrV4 = match gateway.parse() {
+        Ok(a) => a,
+        Err(_) => return Err(make_error("tun listener needs an ipv4 gateway address")),
+    };
+    let prefix: u8 = match prefix.parse() {
+        Ok(p) if p <= 32 => p,
+        _ => return Err(make_error("invalid tun prefix length")),
+    };
+    let tun_cfg = cfg.tun.clone().unwrap_or_default();
+    let mut device = TunDevice::open(
+        tun_cfg.name.as_deref(),
+        *gateway.ip(),
+        prefix,
+        tun_cfg.mtu(),
+    )?;
+    info!(
+        "tun device {} up with {}/{}",
+        device.name,
+        gateway.ip(),
+        prefix
+    );
+    let name = device.name.clone();
+    let listener = TcpListener::bind(SocketAddr::V4(gateway)).await?;
+    let nat = Arc::new(Mutex::new(TcpNat::default()));
+    let ids = Arc::new(AtomicU32::new(0));
+    tokio::spawn(serve_tcp(listener, nat.clone(), cfg.clone(), ids.clone()));
+
+    // device reads and writes block, each side gets a thread
+    let (packet_tx, packet_rx) = std::sync::mpsc::channel::<Vec<u8>>();
+    let mut writer = device.writer()?;
+    std::thread::spawn(move || {
+        while let Ok(pkt) = packet_rx.recv() {
+            if let Err(e) = writer.write_packet(&pkt[..]) {
+                error!("tun write error:{}", e);
+            }
+        }
+    });
+    let (read_tx, mut read_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    std::thread::spawn(move || {
+        let mut buf = vec![0u8; MAX_PACKET];
+        loop {
+            match device.read_packet(&mut buf) {
+                Ok(n) => {
+                    if read_tx.send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("tun read error:{}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    // the udp sources by their socket, with the last time they sent
+    let mut sources: HashMap<SocketAddrV4, (UdpRouter, Instant)> = HashMap::new();
+    let mut warned_v6 = false;
+    while let Some(mut pkt) = read_rx.recv().await {
+        let flow = match parse_packet(&pkt[..]) {
+            Some(f) => f,
+            None => {
+                if !warned_v6 && pkt.first().map(|b| b >> 4) == Some(6) {
+                    warn!(
+                        "tun device {} drops ipv6 packets, only ipv4 is proxied",
+                        name
+                    );
+                    warned_v6 = true;
+                }
+                continue;
+            }
+        };
+        if flow.proto == PROTO_TCP {
+            if nat_tcp(&mut pkt[..], &flow, gateway, &nat) {
+                let _ = packet_tx.send(pkt);
+            }
+            continue;
+        }
+        let total = usize::from(u16::from_be_bytes([pkt[2], pkt[3]]));
+        let payload = pkt[flow.header_len + 8..total].to_vec();
+        let now = Instant::now();
+        if !sources.contains_key(&flow.src) {
+            sources.retain(|_, (_, active)| now.duration_since(*active) < UDP_FLOW_IDLE);
+            let tunnel_id = ids.fetch_add(1, Ordering::SeqCst);
+            let router = new_udp_router(tunnel_id, flow.src, cfg.clone(), packet_tx.clone());
+            sources.insert(flow.src, (router, now));
+        }
+        if let Some((router, active)) = sources.get_mut(&flow.src) {
+            *active = now;
+            router.send_to(flow.dst.to_string().as_str(), payload);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_udp_packet_checksums() {
+        let src: SocketAddrV4 = "10.255.0.1:5353".parse().unwrap();
+        let dst: SocketAddrV4 = "8.8.8.8:53".parse().unwrap();
+        let pkt = udp_packet(src, dst, b"hello");
+        // a correct checksum sums up to zero
+        assert_eq!(fold(sum16(&pkt[..20], 0)), 0);
+        assert_eq!(l4_checksum(*src.ip(), *dst.ip(), PROTO_UDP, &pkt[20..]), 0);
+        let flow = parse_packet(&pkt[..]).unwrap();
+        assert_eq!(
+            flow,
+            Flow {
+                proto: PROTO_UDP,
+                src,
+                dst,
+                header_len: 20,
+            }
+        );
+        // ipv6 is not proxied
+        let mut v6 = vec![0u8; 48];
+        v6[0] = 0x60;
+        v6[6] = PROTO_UDP;
+        assert_eq!(parse_packet(&v6[..]), None);
+    }
+
+    #[test]
+    fn test_tcp_nat() {
+        let gateway: SocketAddrV4 = "10.255.0.1:48106".parse().unwrap();
+        let client: SocketAddrV4 = "10.255.0.1:40000".parse().unwrap();
+        let server: SocketAddrV4 = "93.184.216.34:443".parse().unwrap();
+        let nat = Mutex::new(TcpNat::default());
+        let mut pkt = vec![0u8; 40];
+        pkt[0] = 0x45;
+        pkt[2..4].copy_from_slice(&40u16.to_be_bytes());
+        pkt[9] = PROTO_TCP;
+        rewrite_packet(&mut pkt, 20, client, server);
+
+        let flow = parse_packet(&pkt[..]).unwrap();
+        assert!(nat_tcp(&mut pkt, &flow, gateway, &nat));
+        let to_listener = parse_packet(&pkt[..]).unwrap();
+        assert_eq!(to_listener.dst, gateway);
+        assert_eq!(to_listener.src.ip(), server.ip());
+
+        // the listener's answer goes back as server -> client
+        rewrite_packet(&mut pkt, 20, gateway, to_listener.src);
+        let answer = parse_packet(&pkt[..]).unwrap();
+        assert!(nat_tcp(&mut pkt, &answer, gateway, &nat));
+        let back = parse_packet(&pkt[..]).unwrap();
+        assert_eq!((back.src, back.dst), (server, client));
+        assert_eq!(
+            l4_checksum(*server.ip(), *client.ip(), PROTO_TCP, &pkt[20..]),
+            0
+        );
+    }
+}
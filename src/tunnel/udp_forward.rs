@@ -0,0 +1,81 @@
+use super::relay::{start_udp_flow, UdpFlow};
+use crate::config::TunnelConfig;
+use crate::utils::make_error;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+const MAX_DATAGRAM: usize = 65535;
+
+struct ForwardFlow {
+    flow: UdpFlow,
+    last_active: Instant,
+}
+
+/// A `udpfwd://host:port` listener: the datagrams of every client address
+/// become one UDP flow to the fixed `forward.target`, which ends once it
+/// stayed idle for `forward.timeout_secs`.
+pub async fn start_udp_forward_server(addr: &str, cfg: TunnelConfig) -> Result<(), Box<dyn Error>> {
+    let forward = match cfg.forward.clone() {
+        Some(f) => f,
+        None => return Err(make_error("udpfwd listener requires 'forward'")),
+    };
+    let timeout = forward.timeout();
+    let socket = UdpSocket::bind(addr).await?;
+    let (mut recv_half, mut send_half) = socket.split();
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel::<(Vec<u8>, SocketAddr)>();
+    let mut flows: HashMap<SocketAddr, ForwardFlow> = HashMap::new();
+    let mut sweep = tokio::time::interval(Duration::from_secs(1));
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    let mut tunnel_id: u32 = 0;
+    loop {
+        tokio::select! {
+            r = recv_half.recv_from(&mut buf) => {
+                let (n, peer) = r?;
+                let data = buf[..n].to_vec();
+                let data = match flows.get_mut(&peer) {
+                    Some(f) => match f.flow.send(data) {
+                        Ok(()) => {
+                            f.last_active = Instant::now();
+                            continue;
+                        }
+                        Err(d) => d,
+                    },
+                    None => data,
+                };
+                tunnel_id += 1;
+                info!("[{}]Handle udp forward {} -> {}", tunnel_id, peer, forward.target);
+                let (flow_tx, mut flow_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+                let replies = reply_tx.clone();
+                tokio::spawn(async move {
+                    while let Some(data) = flow_rx.recv().await {
+                        if replies.send((data, peer)).is_err() {
+                            break;
+                        }
+                    }
+                });
+                let flow = start_udp_flow(tunnel_id, forward.target.clone(), cfg.clone(), flow_tx);
+                let _ = flow.send(data);
+                flows.insert(peer, ForwardFlow { flow, last_active: Instant::now() });
+            }
+            reply = reply_rx.recv() => {
+                if let Some((data, peer)) = reply {
+                    if let Some(f) = flows.get_mut(&peer) {
+                        f.last_active = Instant::now();
+                    }
+                    if let Err(e) = send_half.send_to(&data[..], &peer).await {
+                        error!("udp forward send error:{}", e);
+                    }
+                }
+            }
+            _ = sweep.tick() => {
+                // dropping a flow ends its stream
+                flows.retain(|_, f| !f.flow.is_done() && f.last_active.elapsed() < timeout);
+            }
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use super::relay::relay_stream;
+use super::rmux::serve_rmux_session;
+use super::socks5::handle_socks5_stream;
+use crate::config::TunnelConfig;
+use futures::FutureExt;
+use std::error::Error;
+use std::sync::atomic::{AtomicU32, Ordering};
+use tokio::net::{UnixListener, UnixStream};
+
+async fn handle_unix_inbound(
+    tunnel_id: u32,
+    inbound: UnixStream,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    // a cipher means this is a hop of the tunnel, otherwise a local proxy
+    if cfg.cipher.is_some() {
+        let (read, write) = tokio::io::split(inbound);
+        serve_rmux_session(tunnel_id, read, write, &cfg).await?;
+        return Ok(());
+    }
+    if let Some(target) = cfg.tunnel_server.clone() {
+        let (mut read, mut write) = tokio::io::split(inbound);
+        return relay_stream(tunnel_id, &mut read, &mut write, target, &cfg, Vec::new()).await;
+    }
+    handle_socks5_stream(tunnel_id, inbound, &cfg).await
+}
+
+/// Serves `unix:///path.sock`, removing a stale socket file left at `path`.
+pub async fn start_unix_server(path: &str, cfg: TunnelConfig) -> Result<(), Box<dyn Error>> {
+    let _ = std::fs::remove_file(path);
+    let mut listener = UnixListener::bind(path)?;
+    let tunnel_id_seed = AtomicU32::new(0);
+    while let Ok((inbound, _)) = listener.accept().await {
+        let tunnel_id = tunnel_id_seed.fetch_add(1, Ordering::SeqCst);
+        let handle = handle_unix_inbound(tunnel_id, inbound, cfg.clone()).map(move |r| {
+            if let Err(e) = r {
+                error!("[{}]Failed to handle; error={}", tunnel_id, e);
+            }
+        });
+        tokio::spawn(handle);
+    }
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use super::relay::relay;
+use super::tls::{peek_sni, valid_tls_version};
+use crate::channel::ChannelStream;
+use crate::config::TunnelConfig;
+use crate::rmux::{create_session_stream, get_bound_session, PROTO_VHOST};
+use crate::utils::{
+    make_error, read_until_separator_timeout, DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_MAX_HEAD_SIZE,
+};
+
+use std::error::Error;
+use std::net::Shutdown;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+const NO_VHOST_RESPONSE: &str =
+    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+fn http_host(head: &[u8]) -> Option<String> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+    req.parse(head).ok()?;
+    let host = req
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("Host"))?;
+    let host = std::str::from_utf8(host.value).ok()?.trim();
+    match host.rfind(':') {
+        Some(i) if !host.ends_with(']') => Some(String::from(&host[..i])),
+        _ => Some(String::from(host)),
+    }
+}
+
+/// Reads the virtual host the client asks for, the TLS SNI or the HTTP Host
+/// header, returning it with everything read so far.
+async fn read_vhost(inbound: &mut TcpStream) -> Result<(String, Vec<u8>, bool), Box<dyn Error>> {
+    let mut peek_buf = [0u8; 3];
+    inbound.peek(&mut peek_buf).await?;
+    if valid_tls_version(&peek_buf[..]) {
+        let (sni, head) = peek_sni(inbound).await?;
+        return Ok((sni, head, true));
+    }
+    let (_, head, body) = read_until_separator_timeout(
+        inbound,
+        &["\r\n\r\n", "\n\n"],
+        DEFAULT_MAX_HEAD_SIZE,
+        DEFAULT_HANDSHAKE_TIMEOUT,
+    )
+    .await?;
+    let host = match http_host(&head[..]) {
+        Some(h) => h,
+        None => return Err(make_error("no host in http request")),
+    };
+    let mut data = head.to_vec();
+    data.extend_from_slice(&body[..]);
+    Ok((host, data, false))
+}
+
+/// Serves a `vhost://` listener on the remote: HTTP requests and TLS
+/// connections are relayed by Host or SNI to the client session which bound
+/// that virtual host, TLS passes through untouched.
+pub async fn handle_vhost(
+    tunnel_id: u32,
+    mut inbound: TcpStream,
+    cfg: TunnelConfig,
+) -> Result<(), Box<dyn Error>> {
+    let (host, head, is_tls) =
+        match tokio::time::timeout(DEFAULT_HANDSHAKE_TIMEOUT, read_vhost(&mut inbound)).await {
+            Ok(r) => r?,
+            Err(_) => return Err(make_error("timeout reading virtual host")),
+        };
+    let session_id = match get_bound_session(PROTO_VHOST, host.as_str()) {
+        Some(id) => id,
+        None => {
+            if !is_tls {
+                inbound.write_all(NO_VHOST_RESPONSE.as_bytes()).await?;
+            }
+            let _ = inbound.shutdown(Shutdown::Both);
+            return Err(make_error("no session bound to virtual host"));
+        }
+    };
+    info!(
+        "[{}]Handle vhost {} via session {}",
+        tunnel_id, host, session_id
+    );
+    let mut stream = create_session_stream(
+        "",
+        session_id,
+        PROTO_VHOST,
+        host.as_str(),
+        cfg.relay_buf_size(),
+    )
+    .await?;
+    let r = async {
+        let (mut ri, mut wi) = inbound.split();
+        let (mut ro, mut wo) = stream.split();
+        wo.write_all(&head[..]).await?;
+        relay(
+            tunnel_id,
+            &mut ri,
+            &mut wi,
+            &mut ro,
+            &mut wo,
+            cfg.relay_buf_size(),
+        )
+        .await
+    }
+    .await;
+    let _ = stream.close();
+    let _ = inbound.shutdown(Shutdown::Both);
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_host() {
+        let head = b"GET / HTTP/1.1\r\nHost: app.example.com:8080\r\n\r\n";
+        assert_eq!(http_host(head), Some(String::from("app.example.com")));
+        let head = b"GET / HTTP/1.1\r\nHost: [::1]\r\n\r\n";
+        assert_eq!(http_host(head), Some(String::from("[::1]")));
+        assert_eq!(http_host(b"GET / HTTP/1.1\r\n\r\n"), None);
+    }
+}
@@ -86,3 +86,75 @@ impl Buf for VBuf {
         len
     }
 }
+
+/// A `Buf` view over a borrowed list of `IoSlice`s, so vectored writes can be
+/// handed to writers that only expose `poll_write_buf`.
+pub struct IoSliceBuf<'a, 'b> {
+    slices: &'a [IoSlice<'b>],
+    idx: usize,
+    pos: usize,
+}
+
+impl<'a, 'b> IoSliceBuf<'a, 'b> {
+    pub fn new(slices: &'a [IoSlice<'b>]) -> Self {
+        let mut b = Self {
+            slices,
+            idx: 0,
+            pos: 0,
+        };
+        b.skip_empty();
+        b
+    }
+    fn skip_empty(&mut self) {
+        while self.idx < self.slices.len() && self.pos == self.slices[self.idx].len() {
+            self.idx += 1;
+            self.pos = 0;
+        }
+    }
+}
+
+impl Buf for IoSliceBuf<'_, '_> {
+    fn remaining(&self) -> usize {
+        if self.idx >= self.slices.len() {
+            return 0;
+        }
+        let sum = self.slices[self.idx..]
+            .iter()
+            .map(|s| s.len())
+            .sum::<usize>();
+        sum - self.pos
+    }
+
+    fn bytes(&self) -> &[u8] {
+        if self.idx >= self.slices.len() {
+            return &[];
+        }
+        &self.slices[self.idx][self.pos..]
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        while cnt > 0 && self.idx < self.slices.len() {
+            let left = self.slices[self.idx].len() - self.pos;
+            if cnt < left {
+                self.pos += cnt;
+                return;
+            }
+            cnt -= left;
+            self.idx += 1;
+            self.pos = 0;
+        }
+        self.skip_empty();
+    }
+
+    fn bytes_vectored<'c>(&'c self, dst: &mut [IoSlice<'c>]) -> usize {
+        if self.idx >= self.slices.len() || dst.is_empty() {
+            return 0;
+        }
+        let len = cmp::min(self.slices.len() - self.idx, dst.len());
+        dst[0] = IoSlice::new(self.bytes());
+        for i in 1..len {
+            dst[i] = IoSlice::new(&self.slices[self.idx + i]);
+        }
+        len
+    }
+}
@@ -0,0 +1,199 @@
+use super::io::make_io_error;
+use futures::stream::{FuturesUnordered, StreamExt};
+use nix::sys::socket::{InetAddr, SockAddr};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// Stagger between two connection attempts, RFC 8305 recommends 250ms.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Pins outbound sockets to a source address and/or a network device.
+#[derive(Debug, Clone, Default)]
+pub struct DialOptions {
+    pub bind_address: Option<IpAddr>,
+    pub bind_interface: Option<String>,
+}
+
+impl DialOptions {
+    pub fn is_empty(&self) -> bool {
+        self.bind_address.is_none() && self.bind_interface.is_none()
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn bind_to_device(fd: RawFd, iface: &str) -> std::io::Result<()> {
+    use nix::libc;
+    let rc = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            iface.as_ptr() as *const libc::c_void,
+            iface.len() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn bind_to_device(_fd: RawFd, _iface: &str) -> std::io::Result<()> {
+    Err(make_io_error("bind_interface is only supported on linux"))
+}
+
+/// Applies `opts` to a socket which is about to connect to `target`.
+pub fn bind_socket(fd: RawFd, target: &SocketAddr, opts: &DialOptions) -> std::io::Result<()> {
+    if let Some(iface) = &opts.bind_interface {
+        bind_to_device(fd, iface.as_str())?;
+    }
+    if let Some(ip) = opts.bind_address {
+        let local = SockAddr::new_inet(InetAddr::from_std(&SocketAddr::new(ip, 0)));
+        if let Err(e) = nix::sys::socket::bind(fd, &local) {
+            return Err(make_io_error(&format!(
+                "bind {} for {} failed:{}",
+                ip, target, e
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn connect_bound_blocking(
+    target: SocketAddr,
+    opts: DialOptions,
+) -> std::io::Result<std::net::TcpStream> {
+    use nix::sys::socket::{connect, socket, AddressFamily, SockFlag, SockType};
+    use std::os::unix::io::FromRawFd;
+    let family = if target.is_ipv4() {
+        AddressFamily::Inet
+    } else {
+        AddressFamily::Inet6
+    };
+    let fd = match socket(family, SockType::Stream, SockFlag::SOCK_CLOEXEC, None) {
+        Ok(fd) => fd,
+        Err(e) => return Err(make_io_error(&e.to_string())),
+    };
+    let stream = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+    bind_socket(fd, &target, &opts)?;
+    let remote = SockAddr::new_inet(InetAddr::from_std(&target));
+    if let Err(e) = connect(fd, &remote) {
+        return Err(make_io_error(&e.to_string()));
+    }
+    stream.set_nonblocking(true)?;
+    Ok(stream)
+}
+
+async fn connect_one(target: SocketAddr, opts: &DialOptions) -> Result<TcpStream, std::io::Error> {
+    if opts.is_empty() {
+        return TcpStream::connect(&target).await;
+    }
+    // tokio has no way to set up a socket before connecting it
+    let opts = opts.clone();
+    let stream =
+        tokio::task::spawn_blocking(move || connect_bound_blocking(target, opts)).await??;
+    TcpStream::from_std(stream)
+}
+
+/// Orders resolved addresses for dialing: IPv6 first, then alternating
+/// between the two families.
+fn sort_addrs(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.into_iter().partition(|a| a.is_ipv6());
+    let mut sorted = Vec::with_capacity(v6.len() + v4.len());
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => {
+                sorted.extend(a);
+                sorted.extend(b);
+            }
+        }
+    }
+    sorted
+}
+
+/// Connects to `addr` ("host:port") racing all resolved addresses, started
+/// `CONNECTION_ATTEMPT_DELAY` apart, and keeps the first one established.
+pub async fn happy_connect(addr: &str, opts: &DialOptions) -> Result<TcpStream, std::io::Error> {
+    let mut addrs: Vec<SocketAddr> = tokio::net::lookup_host(addr).await?.collect();
+    if let Some(ip) = opts.bind_address {
+        addrs.retain(|a| a.is_ipv4() == ip.is_ipv4());
+    }
+    let addrs = sort_addrs(addrs);
+    if addrs.is_empty() {
+        return Err(make_io_error("no address resolved"));
+    }
+    if addrs.len() == 1 {
+        return connect_one(addrs[0], opts).await;
+    }
+    let mut pending = FuturesUnordered::new();
+    let mut next = 0;
+    let mut last_err = None;
+    loop {
+        if pending.is_empty() {
+            if next >= addrs.len() {
+                break;
+            }
+            pending.push(connect_one(addrs[next], opts));
+            next += 1;
+        }
+        let attempt = if next < addrs.len() {
+            let delay = tokio::time::delay_for(CONNECTION_ATTEMPT_DELAY);
+            tokio::select! {
+                r = pending.next() => r,
+                _ = delay => {
+                    pending.push(connect_one(addrs[next], opts));
+                    next += 1;
+                    continue;
+                }
+            }
+        } else {
+            pending.next().await
+        };
+        match attempt {
+            Some(Ok(conn)) => return Ok(conn),
+            Some(Err(e)) => {
+                // a failed attempt starts the next one right away
+                if next < addrs.len() {
+                    pending.push(connect_one(addrs[next], opts));
+                    next += 1;
+                }
+                last_err = Some(e);
+            }
+            None => {}
+        }
+    }
+    Err(last_err.unwrap_or_else(|| make_io_error("all connection attempts failed")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sort_addrs() {
+        let addrs: Vec<SocketAddr> = vec![
+            "1.1.1.1:80".parse().unwrap(),
+            "2.2.2.2:80".parse().unwrap(),
+            "[::1]:80".parse().unwrap(),
+            "3.3.3.3:80".parse().unwrap(),
+            "[::2]:80".parse().unwrap(),
+        ];
+        let sorted: Vec<String> = sort_addrs(addrs).iter().map(|a| a.to_string()).collect();
+        assert_eq!(
+            sorted,
+            vec![
+                "[::1]:80",
+                "1.1.1.1:80",
+                "[::2]:80",
+                "2.2.2.2:80",
+                "3.3.3.3:80"
+            ]
+        );
+    }
+}
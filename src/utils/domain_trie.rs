@@ -0,0 +1,123 @@
+//! A set of domains matching hosts by suffix, for rule lists of 100k+
+//! domains. Domains are stored as paths of labels from the top level
+//! down, so "com" and "google" are kept once however many domains end in
+//! them, and a lookup costs a hash probe per label of the host.
+use std::collections::HashMap;
+
+const ROOT: u32 = 0;
+
+#[derive(Debug, Clone)]
+pub struct DomainTrie {
+    /// ids of the distinct labels
+    labels: HashMap<Box<str>, u32>,
+    /// child of a node by the label id of the edge
+    edges: HashMap<(u32, u32), u32>,
+    /// whether the path to a node is a domain of the set
+    terminal: Vec<bool>,
+    len: usize,
+}
+
+impl Default for DomainTrie {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn domain_labels(domain: &str) -> impl Iterator<Item = &str> {
+    domain.rsplit('.').filter(|l| !l.is_empty())
+}
+
+impl DomainTrie {
+    pub fn new() -> Self {
+        Self {
+            labels: HashMap::new(),
+            edges: HashMap::new(),
+            terminal: vec![false],
+            len: 0,
+        }
+    }
+
+    /// Adds `domain`, false if it or a parent domain is in the set already.
+    pub fn insert(&mut self, domain: &str) -> bool {
+        let mut node = ROOT;
+        for label in domain_labels(domain) {
+            if self.terminal[node as usize] {
+                return false;
+            }
+            let next_label = self.labels.len() as u32;
+            let label = *self.labels.entry(Box::from(label)).or_insert(next_label);
+            let next_node = self.terminal.len() as u32;
+            node = *self.edges.entry((node, label)).or_insert(next_node);
+            if node == next_node {
+                self.terminal.push(false);
+            }
+        }
+        if node == ROOT || self.terminal[node as usize] {
+            return false;
+        }
+        self.terminal[node as usize] = true;
+        self.len += 1;
+        true
+    }
+
+    /// Whether `host` or one of its parent domains is in the set. `host`
+    /// has to be lowercase like the domains inserted.
+    pub fn contains(&self, host: &str) -> bool {
+        let mut node = ROOT;
+        for label in domain_labels(host) {
+            let label = match self.labels.get(label) {
+                Some(l) => *l,
+                None => return false,
+            };
+            node = match self.edges.get(&(node, label)) {
+                Some(n) => *n,
+                None => return false,
+            };
+            if self.terminal[node as usize] {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Domains in the set.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Nodes and distinct labels of the trie, what its memory grows with.
+    pub fn size(&self) -> (usize, usize) {
+        (self.terminal.len(), self.labels.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_domain_trie() {
+        let mut trie = DomainTrie::new();
+        assert!(trie.insert("google.com"));
+        assert!(trie.insert("mail.example.com"));
+        assert!(trie.insert("co.uk."));
+        assert!(!trie.insert("google.com"));
+        assert!(!trie.insert("www.google.com"));
+        assert!(!trie.insert(""));
+        assert_eq!(trie.len(), 3);
+        assert_eq!(trie.size(), (7, 6));
+
+        assert!(trie.contains("google.com"));
+        assert!(trie.contains("www.google.com."));
+        assert!(trie.contains("a.b.co.uk"));
+        assert!(trie.contains("x.mail.example.com"));
+        assert!(!trie.contains("notgoogle.com"));
+        assert!(!trie.contains("example.com"));
+        assert!(!trie.contains("com"));
+        assert!(!trie.contains(""));
+    }
+}
@@ -0,0 +1,317 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::io::make_invalid_data_error;
+
+const MAX_VARINT_LEN: usize = 10;
+const CHECKSUM_LEN: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LengthPrefix {
+    /// LEB128 encoded length, as protobuf length delimited fields.
+    Varint,
+    /// Big endian u16 length, as DNS over TCP and Noise messages.
+    U16,
+    /// Big endian u32 length.
+    U32,
+}
+
+/// Describes a length prefixed frame: `[flags |] prefix | payload [| crc32]`.
+/// The prefix counts payload bytes only.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCodec {
+    pub prefix: LengthPrefix,
+    pub max_frame_size: usize,
+    /// a flags byte before the prefix, which has to be zero, like the
+    /// compressed flag of gRPC messages
+    pub flags: bool,
+    pub checksum: bool,
+}
+
+/// Decodes a LEB128 varint, returning the value and its encoded length.
+pub fn decode_varint(buf: &[u8]) -> io::Result<Option<(u64, usize)>> {
+    let mut v: u64 = 0;
+    for (i, b) in buf.iter().enumerate() {
+        if i >= MAX_VARINT_LEN {
+            return Err(make_invalid_data_error("varint too long"));
+        }
+        v |= u64::from(b & 0x7F) << (7 * i);
+        if b & 0x80 == 0 {
+            return Ok(Some((v, i + 1)));
+        }
+    }
+    if buf.len() >= MAX_VARINT_LEN {
+        return Err(make_invalid_data_error("varint too long"));
+    }
+    Ok(None)
+}
+
+pub fn encode_varint(mut v: u64, out: &mut BytesMut) {
+    out.reserve(MAX_VARINT_LEN);
+    while v >= 0x80 {
+        out.put_u8((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.put_u8(v as u8);
+}
+
+impl FrameCodec {
+    pub fn new(prefix: LengthPrefix, max_frame_size: usize) -> Self {
+        Self {
+            prefix,
+            max_frame_size,
+            flags: false,
+            checksum: false,
+        }
+    }
+
+    pub fn encode(&self, data: &[u8], out: &mut BytesMut) -> io::Result<()> {
+        let limit = match self.prefix {
+            LengthPrefix::U16 => std::cmp::min(self.max_frame_size, usize::from(u16::MAX)),
+            _ => self.max_frame_size,
+        };
+        if data.len() > limit {
+            return Err(make_invalid_data_error("frame exceeds max size"));
+        }
+        out.reserve(1 + 4 + data.len() + CHECKSUM_LEN);
+        if self.flags {
+            out.put_u8(0);
+        }
+        match self.prefix {
+            LengthPrefix::Varint => encode_varint(data.len() as u64, out),
+            LengthPrefix::U16 => out.put_u16(data.len() as u16),
+            LengthPrefix::U32 => out.put_u32(data.len() as u32),
+        }
+        out.put_slice(data);
+        if self.checksum {
+            out.put_u32(crc::crc32::checksum_ieee(data));
+        }
+        Ok(())
+    }
+
+    /// The length of the frame `buf` starts with and the bytes before its
+    /// payload, once they are there.
+    fn decode_len(&self, buf: &[u8]) -> io::Result<Option<(usize, usize)>> {
+        let flags_len = if self.flags { 1 } else { 0 };
+        if buf.len() < flags_len {
+            return Ok(None);
+        }
+        if self.flags && buf[0] != 0 {
+            return Err(make_invalid_data_error("frame flags not supported"));
+        }
+        let rest = &buf[flags_len..];
+        let (len, prefix_len) = match self.prefix {
+            LengthPrefix::Varint => match decode_varint(rest)? {
+                Some(v) => v,
+                None => return Ok(None),
+            },
+            LengthPrefix::U16 if rest.len() >= 2 => {
+                (u64::from(u16::from_be_bytes([rest[0], rest[1]])), 2)
+            }
+            LengthPrefix::U32 if rest.len() >= 4 => {
+                let mut xbuf: [u8; 4] = Default::default();
+                xbuf.copy_from_slice(&rest[0..4]);
+                (u64::from(u32::from_be_bytes(xbuf)), 4)
+            }
+            _ => return Ok(None),
+        };
+        if len > self.max_frame_size as u64 {
+            return Err(make_invalid_data_error("frame exceeds max size"));
+        }
+        Ok(Some((len as usize, flags_len + prefix_len)))
+    }
+
+    /// Splits one complete frame off the front of `buf`, or returns `None`
+    /// if more bytes are needed.
+    pub fn decode(&self, buf: &mut BytesMut) -> io::Result<Option<Bytes>> {
+        let (len, header_len) = match self.decode_len(&buf[..])? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let tail_len = if self.checksum { CHECKSUM_LEN } else { 0 };
+        if buf.len() < header_len + len + tail_len {
+            return Ok(None);
+        }
+        buf.advance(header_len);
+        let payload = buf.split_to(len).freeze();
+        if self.checksum {
+            let mut xbuf: [u8; 4] = Default::default();
+            xbuf.copy_from_slice(&buf[0..CHECKSUM_LEN]);
+            buf.advance(CHECKSUM_LEN);
+            if u32::from_be_bytes(xbuf) != crc::crc32::checksum_ieee(&payload[..]) {
+                return Err(make_invalid_data_error("frame checksum mismatch"));
+            }
+        }
+        Ok(Some(payload))
+    }
+}
+
+/// Reads the frames of `inner`. A `PeekableReader` can be passed as is, it
+/// replays what was peeked first, so the frames of a sniffed stream lose
+/// nothing.
+pub struct FrameReader<T> {
+    inner: T,
+    codec: FrameCodec,
+    buf: BytesMut,
+}
+
+impl<T> FrameReader<T>
+where
+    T: AsyncRead + Unpin,
+{
+    pub fn new(inner: T, codec: FrameCodec) -> Self {
+        Self {
+            inner,
+            codec,
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// Returns the next frame, or `None` on EOF at a frame boundary.
+    pub async fn read_frame(&mut self) -> io::Result<Option<Bytes>> {
+        loop {
+            if let Some(frame) = self.codec.decode(&mut self.buf)? {
+                return Ok(Some(frame));
+            }
+            self.buf.reserve(4096);
+            let n = self.inner.read_buf(&mut self.buf).await?;
+            if n == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+        }
+    }
+
+    /// The inner stream and the bytes read past the last frame.
+    pub fn into_inner(self) -> (T, BytesMut) {
+        (self.inner, self.buf)
+    }
+}
+
+pub struct FrameWriter<T> {
+    inner: T,
+    codec: FrameCodec,
+    buf: BytesMut,
+}
+
+impl<T> FrameWriter<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    pub fn new(inner: T, codec: FrameCodec) -> Self {
+        Self {
+            inner,
+            codec,
+            buf: BytesMut::new(),
+        }
+    }
+
+    pub async fn write_frame(&mut self, data: &[u8]) -> io::Result<()> {
+        self.buf.clear();
+        self.codec.encode(data, &mut self.buf)?;
+        self.inner.write_all(&self.buf[..]).await?;
+        self.inner.flush().await
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::PeekableReader;
+
+    #[test]
+    fn test_varint() {
+        let mut buf = BytesMut::new();
+        encode_varint(300, &mut buf);
+        assert_eq!(&buf[..], &[0xAC, 0x02]);
+        assert_eq!(decode_varint(&buf[..]).unwrap(), Some((300, 2)));
+        assert_eq!(decode_varint(&buf[..1]).unwrap(), None);
+        assert!(decode_varint(&[0xFF; 10]).is_err());
+    }
+
+    #[test]
+    fn test_u32_roundtrip() {
+        let codec = FrameCodec::new(LengthPrefix::U32, 1024);
+        let mut buf = BytesMut::new();
+        codec.encode(b"hello", &mut buf).unwrap();
+        codec.encode(b"world", &mut buf).unwrap();
+        assert_eq!(&buf[..9], &[0, 0, 0, 5, b'h', b'e', b'l', b'l', b'o']);
+        assert_eq!(&codec.decode(&mut buf).unwrap().unwrap()[..], b"hello");
+        assert_eq!(&codec.decode(&mut buf).unwrap().unwrap()[..], b"world");
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_varint_partial() {
+        let codec = FrameCodec {
+            checksum: true,
+            ..FrameCodec::new(LengthPrefix::Varint, 1024)
+        };
+        let data = vec![7u8; 300];
+        let mut full = BytesMut::new();
+        codec.encode(&data, &mut full).unwrap();
+        // 2 byte varint + payload + crc
+        assert_eq!(full.len(), 2 + 300 + 4);
+        let mut buf = BytesMut::from(&full[..100]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&full[100..]);
+        assert_eq!(&codec.decode(&mut buf).unwrap().unwrap()[..], &data[..]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let codec = FrameCodec {
+            checksum: true,
+            ..FrameCodec::new(LengthPrefix::U16, 1024)
+        };
+        let mut buf = BytesMut::new();
+        codec.encode(b"hello", &mut buf).unwrap();
+        buf[3] ^= 0xFF;
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_flags_and_max_size() {
+        let codec = FrameCodec {
+            flags: true,
+            ..FrameCodec::new(LengthPrefix::U32, 4)
+        };
+        let mut buf = BytesMut::new();
+        assert!(codec.encode(b"hello", &mut buf).is_err());
+        codec.encode(b"hi", &mut buf).unwrap();
+        assert_eq!(&buf[..], &[0, 0, 0, 0, 2, b'h', b'i']);
+        assert_eq!(&codec.decode(&mut buf).unwrap().unwrap()[..], b"hi");
+        let mut buf = BytesMut::from(&[1u8, 0, 0, 0, 2, b'h', b'i'][..]);
+        assert!(codec.decode(&mut buf).is_err());
+        let mut buf = BytesMut::from(&[0u8, 0, 0, 0, 5][..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_frame_reader_peeked() {
+        let codec = FrameCodec::new(LengthPrefix::U16, 1024);
+        let mut wire = BytesMut::new();
+        codec.encode(b"first", &mut wire).unwrap();
+        codec.encode(b"second", &mut wire).unwrap();
+        let mut peekable = PeekableReader::new(&wire[..]);
+        let mut prefix = [0u8; 4];
+        peekable.peek_exact(&mut prefix).await.unwrap();
+        let mut reader = FrameReader::new(peekable, codec);
+        assert_eq!(&reader.read_frame().await.unwrap().unwrap()[..], b"first");
+        assert_eq!(&reader.read_frame().await.unwrap().unwrap()[..], b"second");
+        assert!(reader.read_frame().await.unwrap().is_none());
+
+        let mut out = Vec::new();
+        let mut writer = FrameWriter::new(&mut out, codec);
+        writer.write_frame(b"first").await.unwrap();
+        assert_eq!(&out[..], &wire[..7]);
+    }
+}
@@ -0,0 +1,413 @@
+//! Country lookups in a MaxMind DB (`.mmdb`) file like GeoLite2-Country,
+//! for the `geoip:` conditions of pac rules. The file is read on the first
+//! lookup and again once it changed, checked at most every reload interval.
+use super::make_io_error;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+const DATA_SECTION_SEPARATOR: usize = 16;
+const DEFAULT_RELOAD: Duration = Duration::from_secs(24 * 3600);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Str(String),
+    Uint(u64),
+    Int(i32),
+    Double(f64),
+    Bool(bool),
+    Bytes(Vec<u8>),
+    Map(Vec<(String, Value)>),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+    fn as_uint(&self) -> Option<u64> {
+        match self {
+            Value::Uint(v) => Some(*v),
+            _ => None,
+        }
+    }
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+}
+
+/// A decoder of the data section starting at `base` of `buf`, pointers
+/// being offsets from `base`.
+struct Decoder<'a> {
+    buf: &'a [u8],
+    base: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn bytes(&self, pos: usize, n: usize) -> Result<&'a [u8], std::io::Error> {
+        self.buf
+            .get(pos..pos + n)
+            .ok_or_else(|| make_io_error("mmdb data out of bounds"))
+    }
+
+    fn uint(&self, pos: usize, n: usize) -> Result<u64, std::io::Error> {
+        if n > 8 {
+            return Err(make_io_error("mmdb integer too long"));
+        }
+        Ok(self
+            .bytes(pos, n)?
+            .iter()
+            .fold(0u64, |v, b| (v << 8) | u64::from(*b)))
+    }
+
+    /// The value at `pos`, and the position after it.
+    fn decode(&self, pos: usize, depth: u32) -> Result<(Value, usize), std::io::Error> {
+        if depth > 32 {
+            return Err(make_io_error("mmdb data nested too deep"));
+        }
+        let ctrl = self.bytes(pos, 1)?[0];
+        let mut pos = pos + 1;
+        let mut kind = ctrl >> 5;
+        if kind == 1 {
+            let vvv = u64::from(ctrl & 0x7);
+            let (ptr, n) = match (ctrl >> 3) & 0x3 {
+                0 => ((vvv << 8) | self.uint(pos, 1)?, 1),
+                1 => (((vvv << 16) | self.uint(pos, 2)?) + 2048, 2),
+                2 => (((vvv << 24) | self.uint(pos, 3)?) + 526_336, 3),
+                _ => (self.uint(pos, 4)?, 4),
+            };
+            let (value, _) = self.decode(self.base + ptr as usize, depth + 1)?;
+            return Ok((value, pos + n));
+        }
+        if kind == 0 {
+            kind = 7 + self.bytes(pos, 1)?[0];
+            pos += 1;
+        }
+        let mut size = usize::from(ctrl & 0x1f);
+        if size >= 29 {
+            let n = size - 28;
+            size = match n {
+                1 => 29 + self.uint(pos, 1)? as usize,
+                2 => 285 + self.uint(pos, 2)? as usize,
+                _ => 65_821 + self.uint(pos, 3)? as usize,
+            };
+            pos += n;
+        }
+        match kind {
+            2 => {
+                let s = std::str::from_utf8(self.bytes(pos, size)?)
+                    .map_err(|_| make_io_error("mmdb string is not UTF-8"))?;
+                Ok((Value::Str(String::from(s)), pos + size))
+            }
+            3 => {
+                let v = self.uint(pos, 8)?;
+                Ok((Value::Double(f64::from_bits(v)), pos + 8))
+            }
+            4 => Ok((Value::Bytes(self.bytes(pos, size)?.to_vec()), pos + size)),
+            5 | 6 | 9 => Ok((Value::Uint(self.uint(pos, size)?), pos + size)),
+            // uint128 values do not fit, they only appear in odd databases
+            10 => Ok((Value::Bytes(self.bytes(pos, size)?.to_vec()), pos + size)),
+            7 => {
+                let mut entries = Vec::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (key, next) = self.decode(pos, depth + 1)?;
+                    let (value, next) = self.decode(next, depth + 1)?;
+                    let key = match key {
+                        Value::Str(k) => k,
+                        _ => return Err(make_io_error("mmdb map key is not a string")),
+                    };
+                    entries.push((key, value));
+                    pos = next;
+                }
+                Ok((Value::Map(entries), pos))
+            }
+            8 => Ok((Value::Int(self.uint(pos, size)? as u32 as i32), pos + size)),
+            11 => {
+                let mut items = Vec::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (item, next) = self.decode(pos, depth + 1)?;
+                    items.push(item);
+                    pos = next;
+                }
+                Ok((Value::Array(items), pos))
+            }
+            14 => Ok((Value::Bool(size != 0), pos)),
+            15 => {
+                let v = self.uint(pos, 4)? as u32;
+                Ok((Value::Double(f64::from(f32::from_bits(v))), pos + 4))
+            }
+            _ => Err(make_io_error("unknown mmdb data type")),
+        }
+    }
+}
+
+/// A MaxMind DB file held in memory.
+pub struct Mmdb {
+    buf: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    /// node IPv4 lookups start from in an IPv6 tree
+    ipv4_start: usize,
+}
+
+impl Mmdb {
+    pub fn from_bytes(buf: Vec<u8>) -> Result<Self, std::io::Error> {
+        let marker = buf
+            .windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or_else(|| make_io_error("not a MaxMind DB file"))?;
+        let start = marker + METADATA_MARKER.len();
+        let meta = {
+            let decoder = Decoder {
+                buf: &buf[..],
+                base: start,
+            };
+            decoder.decode(start, 0)?.0
+        };
+        let field = |name: &str| {
+            meta.get(name)
+                .and_then(Value::as_uint)
+                .ok_or_else(|| make_io_error(&format!("mmdb metadata without {}", name)))
+        };
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(make_io_error("unsupported mmdb record size"));
+        }
+        if record_size * 2 / 8 * node_count + DATA_SECTION_SEPARATOR > marker {
+            return Err(make_io_error("mmdb search tree out of bounds"));
+        }
+        let mut db = Mmdb {
+            buf,
+            node_count,
+            record_size,
+            ip_version,
+            ipv4_start: 0,
+        };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = db.record(node, 0);
+            }
+            db.ipv4_start = node;
+        }
+        Ok(db)
+    }
+
+    fn record(&self, node: usize, bit: u8) -> usize {
+        let size = self.record_size * 2 / 8;
+        let b = &self.buf[node * size..(node + 1) * size];
+        let be = |s: &[u8]| s.iter().fold(0usize, |v, x| (v << 8) | usize::from(*x));
+        match (self.record_size, bit) {
+            (24, 0) => be(&b[0..3]),
+            (24, _) => be(&b[3..6]),
+            (28, 0) => (usize::from(b[3] & 0xf0) << 20) | be(&b[0..3]),
+            (28, _) => (usize::from(b[3] & 0x0f) << 24) | be(&b[4..7]),
+            (_, 0) => be(&b[0..4]),
+            (_, _) => be(&b[4..8]),
+        }
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Result<Option<Value>, std::io::Error> {
+        let (bits, mut node) = match ip {
+            IpAddr::V4(v4) if self.ip_version == 6 => (v4.octets().to_vec(), self.ipv4_start),
+            IpAddr::V4(v4) => (v4.octets().to_vec(), 0),
+            IpAddr::V6(v6) if self.ip_version == 6 => (v6.octets().to_vec(), 0),
+            IpAddr::V6(v6) => match v6.to_ipv4() {
+                Some(v4) => (v4.octets().to_vec(), 0),
+                None => return Ok(None),
+            },
+        };
+        for i in 0..bits.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = (bits[i / 8] >> (7 - i % 8)) & 1;
+            node = self.record(node, bit);
+        }
+        if node <= self.node_count {
+            return Ok(None);
+        }
+        let tree_size = self.record_size * 2 / 8 * self.node_count;
+        let base = tree_size + DATA_SECTION_SEPARATOR;
+        // a record between the tree and the data section is corrupt
+        let offset = (node - self.node_count)
+            .checked_sub(DATA_SECTION_SEPARATOR)
+            .ok_or_else(|| make_io_error("mmdb record points into the separator"))?;
+        let decoder = Decoder {
+            buf: &self.buf[..],
+            base,
+        };
+        decoder.decode(base + offset, 0).map(|(v, _)| Some(v))
+    }
+
+    /// ISO code of the country of `ip`, or of the country it is registered
+    /// in, upper case.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let record = match self.lookup(ip) {
+            Ok(Some(r)) => r,
+            Ok(None) => return None,
+            Err(e) => {
+                debug!("GeoIP lookup of {} failed:{}", ip, e);
+                return None;
+            }
+        };
+        ["country", "registered_country"]
+            .iter()
+            .filter_map(|k| record.get(k))
+            .filter_map(|c| c.get("iso_code").and_then(Value::as_str))
+            .next()
+            .map(|code| code.to_uppercase())
+    }
+}
+
+struct GeoIpState {
+    path: Option<String>,
+    reload: Duration,
+    db: Option<Arc<Mmdb>>,
+    checked_at: Option<Instant>,
+    modified: Option<SystemTime>,
+}
+
+lazy_static! {
+    static ref GEOIP: Mutex<GeoIpState> = Mutex::new(GeoIpState {
+        path: None,
+        reload: DEFAULT_RELOAD,
+        db: None,
+        checked_at: None,
+        modified: None,
+    });
+}
+
+/// Sets the database of the `geoip:` conditions; it is read on the first
+/// lookup, and again if it changed once `reload` passed.
+pub fn init_geoip(path: &str, reload: Option<Duration>) {
+    let mut state = GEOIP.lock().unwrap();
+    state.path = Some(String::from(path));
+    state.reload = reload.unwrap_or(DEFAULT_RELOAD);
+    state.db = None;
+    state.checked_at = None;
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn load(path: &str) -> Result<Mmdb, std::io::Error> {
+    Mmdb::from_bytes(std::fs::read(path)?)
+}
+
+/// ISO code of the country of `ip` in the configured database, none
+/// without one.
+pub fn geoip_country(ip: IpAddr) -> Option<String> {
+    let db = {
+        let mut state = GEOIP.lock().unwrap();
+        let path = state.path.clone()?;
+        match state.checked_at {
+            None => {
+                // the first lookup waits for the file, later ones never do
+                state.checked_at = Some(Instant::now());
+                state.modified = modified(path.as_str());
+                match load(path.as_str()) {
+                    Ok(db) => {
+                        info!("Loaded GeoIP database {}", path);
+                        state.db = Some(Arc::new(db));
+                    }
+                    Err(e) => error!("Failed to load GeoIP database {}:{}", path, e),
+                }
+            }
+            Some(at) if at.elapsed() > state.reload => {
+                state.checked_at = Some(Instant::now());
+                let now = modified(path.as_str());
+                if now.is_some() && now != state.modified {
+                    state.modified = now;
+                    std::thread::spawn(move || reload(path));
+                }
+            }
+            _ => {}
+        }
+        state.db.clone()?
+    };
+    db.country(ip)
+}
+
+fn reload(path: String) {
+    match load(path.as_str()) {
+        Ok(db) => {
+            let mut state = GEOIP.lock().unwrap();
+            if state.path.as_deref() == Some(path.as_str()) {
+                info!("Reloaded GeoIP database {}", path);
+                state.db = Some(Arc::new(db));
+            }
+        }
+        Err(e) => error!("Failed to reload GeoIP database {}:{}", path, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut v = vec![(2 << 5) | s.len() as u8];
+        v.extend_from_slice(s.as_bytes());
+        v
+    }
+
+    /// A database of one node: 0.0.0.0/1 is in `CN`, 128.0.0.0/1 nowhere.
+    fn test_db() -> Vec<u8> {
+        let mut db = Vec::new();
+        // node 0: left record points at data offset 0, right one is empty
+        let left = 1 + DATA_SECTION_SEPARATOR as u32;
+        db.extend_from_slice(&left.to_be_bytes()[1..]);
+        db.extend_from_slice(&1u32.to_be_bytes()[1..]);
+        db.extend_from_slice(&[0u8; DATA_SECTION_SEPARATOR]);
+        db.push((7 << 5) | 1);
+        db.extend(string("country"));
+        db.push((7 << 5) | 1);
+        db.extend(string("iso_code"));
+        db.extend(string("cn"));
+        db.extend_from_slice(METADATA_MARKER);
+        db.push((7 << 5) | 3);
+        db.extend(string("node_count"));
+        db.extend_from_slice(&[(6 << 5) | 1, 1]);
+        db.extend(string("record_size"));
+        db.extend_from_slice(&[(5 << 5) | 1, 24]);
+        db.extend(string("ip_version"));
+        db.extend_from_slice(&[(5 << 5) | 1, 4]);
+        db
+    }
+
+    #[test]
+    fn test_mmdb_country() {
+        let db = Mmdb::from_bytes(test_db()).unwrap();
+        assert_eq!(
+            db.country("1.2.3.4".parse().unwrap()),
+            Some(String::from("CN"))
+        );
+        assert_eq!(db.country("200.1.1.1".parse().unwrap()), None);
+        assert_eq!(
+            db.country("::ffff:1.2.3.4".parse().unwrap()),
+            Some(String::from("CN"))
+        );
+        assert!(Mmdb::from_bytes(vec![0u8; 64]).is_err());
+
+        let mut corrupt = test_db();
+        corrupt[2] = 5;
+        let db = Mmdb::from_bytes(corrupt).unwrap();
+        assert!(db.lookup("1.2.3.4".parse().unwrap()).is_err());
+        assert_eq!(db.country("1.2.3.4".parse().unwrap()), None);
+    }
+}
@@ -1,14 +1,133 @@
+use bytes::BufMut;
 use bytes::Bytes;
 use bytes::BytesMut;
 use std::error::Error;
 use std::future::Future;
 use std::io;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, Waker};
 use std::time::{Duration, SystemTime};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 use tokio::net::TcpStream;
+use tokio::time::{delay_for, Delay, Instant};
+
+pub const DEFAULT_POOL_CHUNK_SIZE: usize = 8 * 1024;
+pub const DEFAULT_POOL_MAX_CHUNKS: usize = 1024;
+/// Upper bound for a client to complete its protocol greeting.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Shards of a pool's free list. Each thread sticks to one shard, so
+/// threads rarely wait on each other's lock.
+const POOL_SHARDS: usize = 16;
+
+lazy_static! {
+    static ref BUFFER_POOL: BufferPool =
+        BufferPool::new(DEFAULT_POOL_CHUNK_SIZE, DEFAULT_POOL_MAX_CHUNKS);
+}
+
+static NEXT_POOL_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static POOL_SHARD: usize = NEXT_POOL_SHARD.fetch_add(1, Ordering::Relaxed) % POOL_SHARDS;
+}
+
+/// A shared free list of `BytesMut` chunks.
+///
+/// Only chunks of exactly `chunk_size` capacity are pooled, grown or split
+/// buffers are dropped. At most about `max_chunks` idle chunks are retained
+/// over all shards; anything beyond that is dropped.
+pub struct BufferPool {
+    chunk_size: AtomicUsize,
+    max_chunks: AtomicUsize,
+    shards: Vec<Mutex<Vec<BytesMut>>>,
+}
+
+impl BufferPool {
+    pub fn new(chunk_size: usize, max_chunks: usize) -> Self {
+        Self {
+            chunk_size: AtomicUsize::new(chunk_size),
+            max_chunks: AtomicUsize::new(max_chunks),
+            shards: (0..POOL_SHARDS).map(|_| Mutex::new(Vec::new())).collect(),
+        }
+    }
+
+    pub fn configure(&self, chunk_size: usize, max_chunks: usize) {
+        self.chunk_size.store(chunk_size, Ordering::SeqCst);
+        self.max_chunks.store(max_chunks, Ordering::SeqCst);
+        let shard_max = self.shard_max();
+        for shard in self.shards.iter() {
+            let mut free = shard.lock().unwrap();
+            free.retain(|b| b.capacity() == chunk_size);
+            free.truncate(shard_max);
+        }
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size.load(Ordering::SeqCst)
+    }
+
+    pub fn idle_chunks(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+
+    fn shard_max(&self) -> usize {
+        let max_chunks = self.max_chunks.load(Ordering::SeqCst);
+        max_chunks.div_ceil(POOL_SHARDS)
+    }
+
+    fn shard(&self) -> &Mutex<Vec<BytesMut>> {
+        &self.shards[POOL_SHARD.with(|s| *s)]
+    }
+
+    /// Returns an empty buffer with at least `chunk_size` capacity.
+    pub fn acquire(&self) -> BytesMut {
+        if let Some(buf) = self.shard().lock().unwrap().pop() {
+            return buf;
+        }
+        BytesMut::with_capacity(self.chunk_size())
+    }
+
+    /// Returns a zero filled buffer of exactly `size` bytes.
+    pub fn acquire_sized(&self, size: usize) -> BytesMut {
+        let mut buf = if size <= self.chunk_size() {
+            self.acquire()
+        } else {
+            BytesMut::with_capacity(size)
+        };
+        buf.resize(size, 0);
+        buf
+    }
+
+    pub fn release(&self, mut buf: BytesMut) {
+        if buf.capacity() != self.chunk_size() {
+            return;
+        }
+        buf.clear();
+        let shard_max = self.shard_max();
+        let mut free = self.shard().lock().unwrap();
+        if free.len() < shard_max {
+            free.push(buf);
+        }
+    }
+}
+
+pub fn init_buffer_pool(chunk_size: usize, max_chunks: usize) {
+    BUFFER_POOL.configure(chunk_size, max_chunks);
+}
+
+pub fn acquire_buffer() -> BytesMut {
+    BUFFER_POOL.acquire()
+}
+
+pub fn acquire_sized_buffer(size: usize) -> BytesMut {
+    BUFFER_POOL.acquire_sized(size)
+}
+
+pub fn release_buffer(buf: BytesMut) {
+    BUFFER_POOL.release(buf)
+}
 
 pub fn make_error(desc: &str) -> Box<dyn Error> {
     Box::new(std::io::Error::new(std::io::ErrorKind::Other, desc))
@@ -18,6 +137,10 @@ pub fn make_io_error(desc: &str) -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::Other, desc)
 }
 
+pub fn make_invalid_data_error(desc: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, desc)
+}
+
 pub struct RelayState {
     shutdown: bool,
     waker: Option<Waker>,
@@ -63,69 +186,45 @@ impl RelayState {
     }
 }
 
-pub struct RelayBufCopy<'a, R: ?Sized, W: ?Sized> {
-    reader: &'a mut R,
-    read_done: bool,
-    writer: &'a mut W,
+struct CopyHalf {
+    buf: BytesMut,
     pos: usize,
     cap: usize,
     amt: u64,
-    buf: Vec<u8>,
-    state: Arc<Mutex<RelayState>>,
+    read_done: bool,
+    done: bool,
 }
 
-pub fn relay_buf_copy<'a, R, W>(
-    reader: &'a mut R,
-    writer: &'a mut W,
-    buf: Vec<u8>,
-    state: Arc<Mutex<RelayState>>,
-) -> RelayBufCopy<'a, R, W>
-where
-    R: AsyncRead + Unpin + ?Sized,
-    W: AsyncWrite + Unpin + ?Sized,
-{
-    RelayBufCopy {
-        reader,
-        read_done: false,
-        writer,
-        amt: 0,
-        pos: 0,
-        cap: 0,
-        buf,
-        state,
+impl CopyHalf {
+    fn new(buf_size: usize) -> Self {
+        Self {
+            buf: acquire_sized_buffer(buf_size),
+            pos: 0,
+            cap: 0,
+            amt: 0,
+            read_done: false,
+            done: false,
+        }
     }
-}
 
-impl<R, W> Future for RelayBufCopy<'_, R, W>
-where
-    R: AsyncRead + Unpin + ?Sized,
-    W: AsyncWrite + Unpin + ?Sized,
-{
-    type Output = io::Result<u64>;
-
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+    // Copies until the reader hits EOF, then flushes and shuts down the
+    // writer so the FIN is forwarded to the peer.
+    fn poll_copy<R, W>(
+        &mut self,
+        cx: &mut Context<'_>,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> Poll<io::Result<()>>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        if self.done {
+            return Poll::Ready(Ok(()));
+        }
         loop {
-            self.state.lock().unwrap().clear_waker();
-            if self.state.lock().unwrap().shutdown {
-                return Poll::Ready(Ok(self.amt));
-            }
-            // If our buffer is empty, then we need to read some data to
-            // continue.
             if self.pos == self.cap && !self.read_done {
-                let me = &mut *self;
-                let pin_reader = Pin::new(&mut *me.reader);
-                let n = match pin_reader.poll_read(cx, &mut me.buf) {
-                    Poll::Pending => {
-                        let mut shared_state = self.state.lock().unwrap();
-                        shared_state.set_waker(cx.waker().clone());
-                        return Poll::Pending;
-                    }
-                    Poll::Ready(Err(e)) => {
-                        return Poll::Ready(Err(e));
-                    }
-                    Poll::Ready(Ok(n)) => n,
-                };
-                //let n = ready!(Pin::new(&mut *me.reader).poll_read(cx, &mut me.buf))?;
+                let n = ready!(Pin::new(&mut *reader).poll_read(cx, &mut self.buf))?;
                 if n == 0 {
                     self.read_done = true;
                 } else {
@@ -133,78 +232,416 @@ where
                     self.cap = n;
                 }
             }
-
-            // If our buffer has some data, let's write it out!
             while self.pos < self.cap {
-                let me = &mut *self;
-                let pin_writer = Pin::new(&mut *me.writer);
-                let i = match pin_writer.poll_write(cx, &me.buf[me.pos..me.cap]) {
-                    Poll::Pending => {
-                        let mut shared_state = self.state.lock().unwrap();
-                        shared_state.set_waker(cx.waker().clone());
-                        return Poll::Pending;
-                    }
-                    Poll::Ready(Err(e)) => {
-                        return Poll::Ready(Err(e));
-                    }
-                    Poll::Ready(Ok(n)) => n,
-                };
-                //let i = ready!(Pin::new(&mut *me.writer).poll_write(cx, &me.buf[me.pos..me.cap]))?;
+                let i =
+                    ready!(Pin::new(&mut *writer).poll_write(cx, &self.buf[self.pos..self.cap]))?;
                 if i == 0 {
                     return Poll::Ready(Err(io::Error::new(
                         io::ErrorKind::WriteZero,
                         "write zero byte into writer",
                     )));
-                } else {
-                    self.pos += i;
-                    self.amt += i as u64;
                 }
+                self.pos += i;
+                self.amt += i as u64;
             }
-
-            // If we've written all the data and we've seen EOF, flush out the
-            // data and finish the transfer.
             if self.pos == self.cap && self.read_done {
-                let me = &mut *self;
-                let pin_writer = Pin::new(&mut *me.writer);
-                match pin_writer.poll_flush(cx) {
-                    Poll::Pending => {
-                        let mut shared_state = self.state.lock().unwrap();
-                        shared_state.set_waker(cx.waker().clone());
-                        return Poll::Pending;
-                    }
-                    Poll::Ready(Err(e)) => {
-                        return Poll::Ready(Err(e));
-                    }
-                    Poll::Ready(_) => {}
+                ready!(Pin::new(&mut *writer).poll_flush(cx))?;
+                ready!(Pin::new(&mut *writer).poll_shutdown(cx))?;
+                self.done = true;
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl Drop for CopyHalf {
+    fn drop(&mut self) {
+        release_buffer(std::mem::take(&mut self.buf));
+    }
+}
+
+/// Copies local->remote ("up") and remote->local ("down") concurrently.
+///
+/// Each EOF is forwarded as a shutdown of the opposite writer while the other
+/// direction keeps flowing. Resolves with `(bytes_up, bytes_down, duration)`
+/// once both directions reached EOF, once the optional linger time after the
+/// first EOF expired, or once the shared `RelayState` is closed by someone else.
+pub struct BiCopy<'a, A: ?Sized, B: ?Sized, C: ?Sized, D: ?Sized> {
+    local_reader: &'a mut A,
+    local_writer: &'a mut B,
+    remote_reader: &'a mut C,
+    remote_writer: &'a mut D,
+    up: CopyHalf,
+    down: CopyHalf,
+    start: Instant,
+    linger: Option<Duration>,
+    linger_delay: Option<Delay>,
+    state: Arc<Mutex<RelayState>>,
+}
+
+pub fn bi_copy<'a, A, B, C, D>(
+    local_reader: &'a mut A,
+    local_writer: &'a mut B,
+    remote_reader: &'a mut C,
+    remote_writer: &'a mut D,
+    buf_size: usize,
+    state: Arc<Mutex<RelayState>>,
+) -> BiCopy<'a, A, B, C, D>
+where
+    A: AsyncRead + Unpin + ?Sized,
+    B: AsyncWrite + Unpin + ?Sized,
+    C: AsyncRead + Unpin + ?Sized,
+    D: AsyncWrite + Unpin + ?Sized,
+{
+    BiCopy {
+        local_reader,
+        local_writer,
+        remote_reader,
+        remote_writer,
+        up: CopyHalf::new(buf_size),
+        down: CopyHalf::new(buf_size),
+        start: Instant::now(),
+        linger: None,
+        linger_delay: None,
+        state,
+    }
+}
+
+impl<A: ?Sized, B: ?Sized, C: ?Sized, D: ?Sized> BiCopy<'_, A, B, C, D> {
+    /// Stop waiting for the other direction `linger` after the first EOF.
+    pub fn linger(mut self, linger: Duration) -> Self {
+        self.linger = Some(linger);
+        self
+    }
+    fn result(&self) -> (u64, u64, Duration) {
+        (self.up.amt, self.down.amt, self.start.elapsed())
+    }
+}
+
+impl<A, B, C, D> Future for BiCopy<'_, A, B, C, D>
+where
+    A: AsyncRead + Unpin + ?Sized,
+    B: AsyncWrite + Unpin + ?Sized,
+    C: AsyncRead + Unpin + ?Sized,
+    D: AsyncWrite + Unpin + ?Sized,
+{
+    type Output = io::Result<(u64, u64, Duration)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let me = &mut *self;
+        me.state.lock().unwrap().clear_waker();
+        if me.state.lock().unwrap().is_closed() {
+            return Poll::Ready(Ok(me.result()));
+        }
+        if let Poll::Ready(Err(e)) =
+            me.up
+                .poll_copy(cx, &mut *me.local_reader, &mut *me.remote_writer)
+        {
+            return Poll::Ready(Err(e));
+        }
+        if let Poll::Ready(Err(e)) =
+            me.down
+                .poll_copy(cx, &mut *me.remote_reader, &mut *me.local_writer)
+        {
+            return Poll::Ready(Err(e));
+        }
+        if me.up.done && me.down.done {
+            return Poll::Ready(Ok(me.result()));
+        }
+        if me.up.done || me.down.done {
+            if let Some(linger) = me.linger {
+                let delay = me.linger_delay.get_or_insert_with(|| delay_for(linger));
+                if Pin::new(delay).poll(cx).is_ready() {
+                    return Poll::Ready(Ok(me.result()));
                 }
-                //ready!(Pin::new(&mut *me.writer).poll_flush(cx))?;
-                return Poll::Ready(Ok(self.amt));
+            }
+        }
+        me.state.lock().unwrap().set_waker(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// Wraps a stream and fails any pending read/write once no bytes have moved
+/// in either direction for `idle` time.
+pub struct TimedStream<T> {
+    inner: T,
+    idle: Duration,
+    delay: Delay,
+    kind: io::ErrorKind,
+}
+
+impl<T> TimedStream<T> {
+    pub fn new(inner: T, idle: Duration) -> Self {
+        Self {
+            inner,
+            idle,
+            delay: delay_for(idle),
+            kind: io::ErrorKind::TimedOut,
+        }
+    }
+    pub fn set_error_kind(&mut self, kind: io::ErrorKind) {
+        self.kind = kind;
+    }
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+    fn touch(&mut self) {
+        self.delay.reset(Instant::now() + self.idle);
+    }
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        match Pin::new(&mut self.delay).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(io::Error::new(self.kind, "idle timeout")),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<T> AsyncRead for TimedStream<T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(r) => {
+                self.touch();
+                Poll::Ready(r)
+            }
+            Poll::Pending => match self.poll_idle(cx) {
+                Poll::Ready(e) => Poll::Ready(Err(e)),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+impl<T> AsyncWrite for TimedStream<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.inner).poll_write(cx, buf) {
+            Poll::Ready(r) => {
+                self.touch();
+                Poll::Ready(r)
+            }
+            Poll::Pending => match self.poll_idle(cx) {
+                Poll::Ready(e) => Poll::Ready(Err(e)),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match Pin::new(&mut self.inner).poll_flush(cx) {
+            Poll::Ready(r) => Poll::Ready(r),
+            Poll::Pending => match self.poll_idle(cx) {
+                Poll::Ready(e) => Poll::Ready(Err(e)),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+pub const DEFAULT_MAX_HEAD_SIZE: usize = 64 * 1024;
+
+fn find_first_separator(buf: &[u8], separators: &[&str]) -> Option<(usize, usize)> {
+    let mut found: Option<(usize, usize)> = None;
+    for (idx, sep) in separators.iter().enumerate() {
+        if let Some(pos) = twoway::find_bytes(buf, sep.as_bytes()) {
+            match found {
+                Some((_, p)) if p <= pos => {}
+                _ => found = Some((idx, pos)),
             }
         }
     }
+    found
 }
 
-pub async fn read_until_separator(
-    stream: &mut TcpStream,
-    separator: &str,
-) -> Result<(Bytes, Bytes), std::io::Error> {
-    let mut buf = BytesMut::with_capacity(1024);
-    let mut b = [0u8; 1024];
+/// Same as `read_until_separator`, but fails with `TimedOut` if no complete
+/// head arrives before `timeout`.
+pub async fn read_until_separator_timeout<T>(
+    stream: &mut T,
+    separators: &[&str],
+    max_size: usize,
+    timeout: Duration,
+) -> Result<(usize, Bytes, Bytes), std::io::Error>
+where
+    T: AsyncRead + Unpin,
+{
+    match tokio::time::timeout(timeout, read_until_separator(stream, separators, max_size)).await {
+        Ok(r) => r,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "timeout reading until separator",
+        )),
+    }
+}
+
+/// Reads until any of `separators` shows up, returning the index of the
+/// separator that matched first, the head including the separator, and any
+/// bytes read past it. Fails once `max_size` bytes were read without a match.
+/// If the stream ends first, the index is `separators.len()`, matching none
+/// of them, and the head is whatever was read.
+pub async fn read_until_separator<T>(
+    stream: &mut T,
+    separators: &[&str],
+    max_size: usize,
+) -> Result<(usize, Bytes, Bytes), std::io::Error>
+where
+    T: AsyncRead + Unpin,
+{
+    let max_sep_len = separators.iter().map(|s| s.len()).max().unwrap_or(0);
+    let mut buf = acquire_buffer();
+    let mut searched: usize = 0;
     loop {
-        let n = stream.read(&mut b).await?;
-        if n > 0 {
-            buf.extend_from_slice(&b[0..n]);
+        if !buf.has_remaining_mut() {
+            buf.reserve(1024);
+        }
+        let n = match stream.read_buf(&mut buf).await {
+            Ok(n) => n,
+            Err(e) => {
+                release_buffer(buf);
+                return Err(e);
+            }
+        };
+        if n == 0 {
+            if buf.is_empty() {
+                release_buffer(buf);
+                return Ok((separators.len(), Bytes::default(), Bytes::default()));
+            }
+            return Ok((separators.len(), buf.freeze(), Bytes::default()));
+        }
+        let offset = if searched >= max_sep_len {
+            searched - max_sep_len + 1
         } else {
-            return Ok((buf.freeze(), Bytes::default()));
+            0
+        };
+        if let Some((idx, pos)) = find_first_separator(&buf[offset..], separators) {
+            let end = offset + pos + separators[idx].len();
+            // handed out without a copy, so the buffer is not pooled again
+            let body = buf.split_off(end);
+            return Ok((idx, buf.freeze(), body.freeze()));
         }
-        if let Some(pos) = twoway::find_bytes(&buf[..], separator.as_bytes()) {
-            let wsize = separator.len();
-            let body = buf.split_off(pos + wsize);
-            return Ok((buf.freeze(), body.freeze()));
+        searched = buf.len();
+        if buf.len() >= max_size {
+            release_buffer(buf);
+            return Err(make_invalid_data_error(
+                "no separator found within size limit",
+            ));
         }
     }
 }
 
+#[cfg(any(target_os = "android", target_os = "linux"))]
+const SPLICE_CHUNK_SIZE: usize = 64 * 1024;
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+struct SplicePipe {
+    rfd: std::os::unix::io::RawFd,
+    wfd: std::os::unix::io::RawFd,
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+impl Drop for SplicePipe {
+    fn drop(&mut self) {
+        let _ = nix::unistd::close(self.rfd);
+        let _ = nix::unistd::close(self.wfd);
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn nix_io_error(e: nix::Error) -> io::Error {
+    match e {
+        nix::Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+        _ => make_io_error(&e.to_string()),
+    }
+}
+
+/// Moves bytes from `reader` to `writer` through a kernel pipe with
+/// `splice(2)`, so payload never enters user space. `rfd`/`wfd` must be the
+/// raw sockets behind the two halves. When the destination socket is not
+/// writable the pending pipe content is drained through a normal write.
+/// `on_moved` is told of the bytes as they reach `writer`.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub async fn splice_copy<W, F>(
+    reader: &mut tokio::net::tcp::ReadHalf<'_>,
+    writer: &mut W,
+    rfd: std::os::unix::io::RawFd,
+    wfd: std::os::unix::io::RawFd,
+    mut on_moved: F,
+) -> io::Result<u64>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+    F: FnMut(usize),
+{
+    use nix::errno::Errno;
+    use nix::fcntl::{splice, OFlag, SpliceFFlags};
+    use tokio::io::AsyncWriteExt;
+
+    let (prfd, pwfd) =
+        nix::unistd::pipe2(OFlag::O_NONBLOCK | OFlag::O_CLOEXEC).map_err(nix_io_error)?;
+    let pipe = SplicePipe {
+        rfd: prfd,
+        wfd: pwfd,
+    };
+    let flags = SpliceFFlags::SPLICE_F_MOVE | SpliceFFlags::SPLICE_F_NONBLOCK;
+    let mut amt: u64 = 0;
+    let mut probe = [0u8; 1];
+    loop {
+        // wait until the source is readable, 0 means EOF
+        if reader.peek(&mut probe).await? == 0 {
+            break;
+        }
+        let moved = match splice(rfd, None, pipe.wfd, None, SPLICE_CHUNK_SIZE, flags) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(nix::Error::Sys(Errno::EAGAIN)) => continue,
+            Err(e) => return Err(nix_io_error(e)),
+        };
+        let mut left = moved;
+        while left > 0 {
+            match splice(pipe.rfd, None, wfd, None, left, flags) {
+                Ok(n) => {
+                    left -= n;
+                    amt += n as u64;
+                    on_moved(n);
+                }
+                Err(nix::Error::Sys(Errno::EAGAIN)) => {
+                    let mut buf = vec![0u8; left];
+                    let mut pos = 0;
+                    while pos < left {
+                        pos +=
+                            nix::unistd::read(pipe.rfd, &mut buf[pos..]).map_err(nix_io_error)?;
+                    }
+                    writer.write_all(&buf[..]).await?;
+                    amt += left as u64;
+                    on_moved(left);
+                    left = 0;
+                }
+                Err(e) => return Err(nix_io_error(e)),
+            }
+        }
+    }
+    writer.shutdown().await?;
+    Ok(amt)
+}
+
 pub fn clear_channel<T>(channel: &mut tokio::sync::mpsc::Receiver<T>) {
     channel.close();
     while true {
@@ -232,3 +669,59 @@ pub fn clear_unbounded_channel<T>(channel: &mut tokio::sync::mpsc::UnboundedRece
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_pool() {
+        // two idle chunks a shard
+        let pool = BufferPool::new(64, 2 * POOL_SHARDS);
+        let a = pool.acquire();
+        assert_eq!(a.capacity(), 64);
+        let ptr = a.as_ptr();
+        pool.release(a);
+        assert_eq!(pool.idle_chunks(), 1);
+        let mut b = pool.acquire();
+        assert_eq!(b.as_ptr(), ptr);
+        assert_eq!(pool.idle_chunks(), 0);
+
+        // grown, split and smaller buffers are not pooled
+        b.reserve(1024);
+        pool.release(b);
+        let mut c = pool.acquire();
+        let _ = c.split_off(32);
+        pool.release(c);
+        pool.release(BytesMut::with_capacity(16));
+        pool.release(pool.acquire_sized(4096));
+        assert_eq!(pool.idle_chunks(), 0);
+
+        let chunks: Vec<BytesMut> = (0..3).map(|_| pool.acquire_sized(10)).collect();
+        for chunk in chunks {
+            pool.release(chunk);
+        }
+        assert_eq!(pool.idle_chunks(), 2);
+        assert!(pool.acquire().is_empty());
+
+        pool.configure(128, 2 * POOL_SHARDS);
+        assert_eq!(pool.idle_chunks(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_read_until_separator() {
+        let mut input = &b"GET / HTTP/1.1\r\nHost: a\r\n\r\nbody"[..];
+        let (idx, head, body) = read_until_separator(&mut input, &["\n\n", "\r\n\r\n"], 1024)
+            .await
+            .unwrap();
+        assert_eq!(idx, 1);
+        assert_eq!(&head[..], &b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"[..]);
+        assert_eq!(&body[..], b"body");
+
+        let mut input = &b"partial"[..];
+        let (idx, head, body) = read_until_separator(&mut input, &["\r\n"], 1024)
+            .await
+            .unwrap();
+        assert_eq!((idx, &head[..], body.len()), (1, &b"partial"[..], 0));
+    }
+}
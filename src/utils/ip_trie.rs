@@ -0,0 +1,176 @@
+//! Longest prefix matching of ips against a set of ranges, for rules with
+//! thousands of cidr entries like country ip lists. The ranges are kept
+//! in a binary trie per address family, a lookup walks at most 32 or 128
+//! nodes whatever the number of ranges.
+use std::net::IpAddr;
+
+/// no child, the root is never a child
+const NONE: u32 = 0;
+
+#[derive(Debug, Clone)]
+struct Node<T> {
+    children: [u32; 2],
+    value: Option<T>,
+}
+
+#[derive(Debug, Clone)]
+struct Tree<T> {
+    nodes: Vec<Node<T>>,
+}
+
+impl<T> Tree<T> {
+    fn new() -> Self {
+        Self {
+            nodes: vec![Node {
+                children: [NONE; 2],
+                value: None,
+            }],
+        }
+    }
+
+    /// Bit `i` of `key` from the top.
+    fn bit(key: u128, i: u8) -> usize {
+        (key >> (127 - i) & 1) as usize
+    }
+
+    fn insert(&mut self, key: u128, prefix: u8, value: T) -> Option<T> {
+        let mut node = 0;
+        for i in 0..prefix {
+            let b = Self::bit(key, i);
+            let mut next = self.nodes[node].children[b];
+            if next == NONE {
+                next = self.nodes.len() as u32;
+                self.nodes.push(Node {
+                    children: [NONE; 2],
+                    value: None,
+                });
+                self.nodes[node].children[b] = next;
+            }
+            node = next as usize;
+        }
+        self.nodes[node].value.replace(value)
+    }
+
+    fn longest_match(&self, key: u128, width: u8) -> Option<(u8, &T)> {
+        let mut node = 0;
+        let mut found = self.nodes[0].value.as_ref().map(|v| (0, v));
+        for i in 0..width {
+            let next = self.nodes[node].children[Self::bit(key, i)];
+            if next == NONE {
+                break;
+            }
+            node = next as usize;
+            if let Some(v) = self.nodes[node].value.as_ref() {
+                found = Some((i + 1, v));
+            }
+        }
+        found
+    }
+}
+
+/// Ranges of ips of both families, each with a value.
+#[derive(Debug, Clone)]
+pub struct IpTrie<T> {
+    v4: Tree<T>,
+    v6: Tree<T>,
+    len: usize,
+}
+
+impl<T> Default for IpTrie<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The bits of `ip` from the top of a u128 and how many there are.
+fn key(ip: &IpAddr) -> (u128, u8) {
+    match ip {
+        IpAddr::V4(ip) => (u128::from(u32::from(*ip)) << 96, 32),
+        IpAddr::V6(ip) => (u128::from(*ip), 128),
+    }
+}
+
+impl<T> IpTrie<T> {
+    pub fn new() -> Self {
+        Self {
+            v4: Tree::new(),
+            v6: Tree::new(),
+            len: 0,
+        }
+    }
+
+    /// Sets the value of the range of `addr` and `prefix`, returning the
+    /// value it replaced. The bits of `addr` after the prefix are ignored.
+    pub fn insert(&mut self, addr: IpAddr, prefix: u8, value: T) -> Option<T> {
+        let (key, width) = key(&addr);
+        let tree = if addr.is_ipv4() {
+            &mut self.v4
+        } else {
+            &mut self.v6
+        };
+        let old = tree.insert(key, prefix.min(width), value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    /// The value of the longest range containing `ip`, and its prefix
+    /// length.
+    pub fn longest_match(&self, ip: &IpAddr) -> Option<(u8, &T)> {
+        let (key, width) = key(ip);
+        if ip.is_ipv4() {
+            self.v4.longest_match(key, width)
+        } else {
+            self.v6.longest_match(key, width)
+        }
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.longest_match(ip).is_some()
+    }
+
+    /// Ranges in the trie.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ip_trie() {
+        let mut trie = IpTrie::new();
+        assert!(trie.insert(ip("10.0.0.0"), 8, "ten").is_none());
+        assert!(trie.insert(ip("10.1.0.0"), 16, "ten-one").is_none());
+        assert!(trie.insert(ip("192.168.1.7"), 32, "host").is_none());
+        assert!(trie.insert(ip("2001:db8::"), 32, "doc").is_none());
+        assert_eq!(trie.insert(ip("10.255.0.0"), 8, "10/8"), Some("ten"));
+        assert_eq!(trie.len(), 4);
+
+        assert_eq!(trie.longest_match(&ip("10.1.2.3")), Some((16, &"ten-one")));
+        assert_eq!(trie.longest_match(&ip("10.2.0.1")), Some((8, &"10/8")));
+        assert_eq!(trie.longest_match(&ip("192.168.1.7")), Some((32, &"host")));
+        assert!(!trie.contains(&ip("192.168.1.8")));
+        assert!(!trie.contains(&ip("11.0.0.1")));
+        assert!(trie.contains(&ip("2001:db8:1::1")));
+        assert!(!trie.contains(&ip("2001:db9::1")));
+        // v4 ranges never match v6 ips of the same bits
+        assert!(!trie.contains(&ip("a00::1")));
+
+        let mut any = IpTrie::new();
+        any.insert(ip("0.0.0.0"), 0, ());
+        assert!(any.contains(&ip("8.8.8.8")));
+        assert!(!any.contains(&ip("::1")));
+    }
+}
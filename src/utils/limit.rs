@@ -0,0 +1,303 @@
+use bytes::Buf;
+use std::cmp;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{delay_for, Delay, Instant};
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: i64,
+    last: Instant,
+}
+
+/// A byte token bucket refilled at `rate` bytes/sec up to `burst` bytes.
+///
+/// Consumers may overdraw the bucket by the size of their last read/write;
+/// the debt is paid back before anyone is granted more quota.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u64,
+    burst: u64,
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, burst: u64) -> Arc<Self> {
+        let burst = cmp::max(burst, rate);
+        Arc::new(Self {
+            rate,
+            burst,
+            state: Mutex::new(BucketState {
+                tokens: burst as i64,
+                last: Instant::now(),
+            }),
+        })
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Returns the bytes currently allowed, or how long to wait for the
+    /// bucket to become positive again.
+    pub fn available(&self) -> Result<usize, Duration> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last);
+        let refill = (elapsed.as_micros() as u64).saturating_mul(self.rate) / 1_000_000;
+        if refill > 0 {
+            state.tokens = cmp::min(state.tokens + refill as i64, self.burst as i64);
+            state.last = now;
+        }
+        if state.tokens > 0 {
+            return Ok(state.tokens as usize);
+        }
+        let missing = (1 - state.tokens) as u64;
+        let micros = cmp::max(missing.saturating_mul(1_000_000) / self.rate, 1000);
+        Err(Duration::from_micros(micros))
+    }
+
+    pub fn consume(&self, n: usize) {
+        self.state.lock().unwrap().tokens -= n as i64;
+    }
+}
+
+fn poll_quota(
+    buckets: &[Arc<TokenBucket>],
+    delay: &mut Option<Delay>,
+    cx: &mut Context<'_>,
+) -> Poll<usize> {
+    loop {
+        if let Some(d) = delay {
+            ready!(Pin::new(d).poll(cx));
+            *delay = None;
+        }
+        let mut quota = std::usize::MAX;
+        let mut wait: Option<Duration> = None;
+        for b in buckets {
+            match b.available() {
+                Ok(n) => quota = cmp::min(quota, n),
+                Err(w) => wait = Some(wait.map_or(w, |v| cmp::max(v, w))),
+            }
+        }
+        match wait {
+            None => return Poll::Ready(quota),
+            Some(w) => *delay = Some(delay_for(w)),
+        }
+    }
+}
+
+fn consume_quota(buckets: &[Arc<TokenBucket>], n: usize) {
+    for b in buckets {
+        b.consume(n);
+    }
+}
+
+/// Throttles reads against every bucket given; with no buckets the reader
+/// is a plain pass through.
+pub struct RateLimitedReader<R> {
+    inner: R,
+    buckets: Vec<Arc<TokenBucket>>,
+    delay: Option<Delay>,
+}
+
+impl<R> RateLimitedReader<R> {
+    pub fn new(inner: R, buckets: Vec<Arc<TokenBucket>>) -> Self {
+        Self {
+            inner,
+            buckets,
+            delay: None,
+        }
+    }
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R> AsyncRead for RateLimitedReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let Self {
+            inner,
+            buckets,
+            delay,
+        } = &mut *self;
+        if buckets.is_empty() {
+            return Pin::new(inner).poll_read(cx, buf);
+        }
+        let quota = ready!(poll_quota(buckets, delay, cx));
+        let len = cmp::min(quota, buf.len());
+        let n = ready!(Pin::new(inner).poll_read(cx, &mut buf[..len]))?;
+        consume_quota(buckets, n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// Throttles writes against every bucket given; with no buckets the writer
+/// is a plain pass through.
+pub struct RateLimitedWriter<W> {
+    inner: W,
+    buckets: Vec<Arc<TokenBucket>>,
+    delay: Option<Delay>,
+}
+
+impl<W> RateLimitedWriter<W> {
+    pub fn new(inner: W, buckets: Vec<Arc<TokenBucket>>) -> Self {
+        Self {
+            inner,
+            buckets,
+            delay: None,
+        }
+    }
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W> AsyncWrite for RateLimitedWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let Self {
+            inner,
+            buckets,
+            delay,
+        } = &mut *self;
+        if buckets.is_empty() {
+            return Pin::new(inner).poll_write(cx, buf);
+        }
+        let quota = ready!(poll_quota(buckets, delay, cx));
+        let len = cmp::min(quota, buf.len());
+        let n = ready!(Pin::new(inner).poll_write(cx, &buf[..len]))?;
+        consume_quota(buckets, n);
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// A sustained rate with a burst allowance shared by every writer of a
+/// listener. Whenever a write has to wait for tokens, a random extra delay of
+/// up to `jitter` is added so throttled traffic has no fixed cadence.
+#[derive(Debug)]
+pub struct TrafficShaper {
+    bucket: Arc<TokenBucket>,
+    jitter: Duration,
+}
+
+impl TrafficShaper {
+    pub fn new(rate: u64, burst: u64, jitter: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            bucket: TokenBucket::new(rate, burst),
+            jitter,
+        })
+    }
+    fn jittered(&self, wait: Duration) -> Duration {
+        let jitter_micros = self.jitter.as_micros() as u64;
+        if jitter_micros == 0 {
+            return wait;
+        }
+        wait + Duration::from_micros(rand::random::<u64>() % jitter_micros)
+    }
+}
+
+pub struct ShapedWriter<W> {
+    inner: W,
+    shaper: Option<Arc<TrafficShaper>>,
+    delay: Option<Delay>,
+}
+
+impl<W> ShapedWriter<W> {
+    pub fn new(inner: W, shaper: Option<Arc<TrafficShaper>>) -> Self {
+        Self {
+            inner,
+            shaper,
+            delay: None,
+        }
+    }
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W> AsyncWrite for ShapedWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let Self {
+            inner,
+            shaper,
+            delay,
+        } = &mut *self;
+        let shaper = match shaper {
+            Some(s) => s,
+            None => return Pin::new(inner).poll_write(cx, buf),
+        };
+        let quota = loop {
+            if let Some(d) = delay {
+                ready!(Pin::new(d).poll(cx));
+                *delay = None;
+            }
+            match shaper.bucket.available() {
+                Ok(n) => break n,
+                Err(w) => *delay = Some(delay_for(shaper.jittered(w))),
+            }
+        };
+        let len = cmp::min(quota, buf.len());
+        let n = ready!(Pin::new(inner).poll_write(cx, &buf[..len]))?;
+        shaper.bucket.consume(n);
+        Poll::Ready(Ok(n))
+    }
+    fn poll_write_buf<B: Buf>(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut B,
+    ) -> Poll<io::Result<usize>>
+    where
+        Self: Sized,
+    {
+        if self.shaper.is_none() {
+            return Pin::new(&mut self.inner).poll_write_buf(cx, buf);
+        }
+        if !buf.has_remaining() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = ready!(self.as_mut().poll_write(cx, buf.bytes()))?;
+        buf.advance(n);
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,318 @@
+use bytes::Buf;
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+lazy_static! {
+    static ref METRICS_REGISTRY: Mutex<MetricsRegistry> = Mutex::new(MetricsRegistry::new());
+}
+
+/// Counters of a relayed stream, always from the client's side whichever
+/// end they are taken at: `read_bytes` were sent by the client, the upload,
+/// and `write_bytes` were sent to it, the download, whose first byte gives
+/// the latency of the target.
+pub struct StreamMetrics {
+    read_bytes: AtomicU64,
+    write_bytes: AtomicU64,
+    // micros since `born_time` until the first byte was downloaded, 0 if none yet
+    first_byte_micros: AtomicU64,
+    born_time: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MetricsSnapshot {
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    pub first_byte_latency: Option<Duration>,
+    pub age: Duration,
+}
+
+impl StreamMetrics {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            read_bytes: AtomicU64::new(0),
+            write_bytes: AtomicU64::new(0),
+            first_byte_micros: AtomicU64::new(0),
+            born_time: Instant::now(),
+        })
+    }
+    /// Counts bytes from the client to the target.
+    pub fn on_upload(&self, n: usize) {
+        self.read_bytes.fetch_add(n as u64, Ordering::SeqCst);
+    }
+    /// Counts bytes from the target to the client.
+    pub fn on_download(&self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        if self.write_bytes.fetch_add(n as u64, Ordering::SeqCst) == 0 {
+            let micros = std::cmp::max(self.born_time.elapsed().as_micros() as u64, 1);
+            self.first_byte_micros.store(micros, Ordering::SeqCst);
+        }
+    }
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let micros = self.first_byte_micros.load(Ordering::SeqCst);
+        MetricsSnapshot {
+            read_bytes: self.read_bytes.load(Ordering::SeqCst),
+            write_bytes: self.write_bytes.load(Ordering::SeqCst),
+            first_byte_latency: if micros > 0 {
+                Some(Duration::from_micros(micros))
+            } else {
+                None
+            },
+            age: self.born_time.elapsed(),
+        }
+    }
+}
+
+/// Counts the bytes moved through `inner`. Read and write halves of one
+/// connection can share the same `StreamMetrics`.
+pub struct MeteredStream<T> {
+    inner: T,
+    metrics: Arc<StreamMetrics>,
+    // wraps the target's end, reads are downloads
+    at_target: bool,
+}
+
+impl<T> MeteredStream<T> {
+    /// Meters the client's end of a relay.
+    pub fn new(inner: T, metrics: Arc<StreamMetrics>) -> Self {
+        Self {
+            inner,
+            metrics,
+            at_target: false,
+        }
+    }
+    /// Meters the target's end of a relay.
+    pub fn target(inner: T, metrics: Arc<StreamMetrics>) -> Self {
+        Self {
+            inner,
+            metrics,
+            at_target: true,
+        }
+    }
+    fn on_read(&self, n: usize) {
+        if self.at_target {
+            self.metrics.on_download(n);
+        } else {
+            self.metrics.on_upload(n);
+        }
+    }
+    fn on_write(&self, n: usize) {
+        if self.at_target {
+            self.metrics.on_upload(n);
+        } else {
+            self.metrics.on_download(n);
+        }
+    }
+    pub fn metrics(&self) -> &Arc<StreamMetrics> {
+        &self.metrics
+    }
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> AsyncRead for MeteredStream<T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.on_read(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<T> AsyncWrite for MeteredStream<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.on_write(n);
+        Poll::Ready(Ok(n))
+    }
+    fn poll_write_buf<B: Buf>(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut B,
+    ) -> Poll<io::Result<usize>>
+    where
+        Self: Sized,
+    {
+        let n = ready!(Pin::new(&mut self.inner).poll_write_buf(cx, buf))?;
+        self.on_write(n);
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+struct ActiveStream {
+    group: String,
+    target: String,
+    metrics: Arc<StreamMetrics>,
+}
+
+#[derive(Default)]
+struct GroupTotals {
+    streams: u64,
+    read_bytes: u64,
+    write_bytes: u64,
+}
+
+struct MetricsRegistry {
+    id_seed: u64,
+    active: HashMap<u64, ActiveStream>,
+    totals: HashMap<String, GroupTotals>,
+}
+
+impl MetricsRegistry {
+    fn new() -> Self {
+        Self {
+            id_seed: 0,
+            active: HashMap::new(),
+            totals: HashMap::new(),
+        }
+    }
+}
+
+/// Keeps a stream listed in the registry; its final counters are folded into
+/// the group totals on drop.
+pub struct MetricsHandle {
+    id: u64,
+    metrics: Arc<StreamMetrics>,
+}
+
+impl MetricsHandle {
+    pub fn metrics(&self) -> Arc<StreamMetrics> {
+        self.metrics.clone()
+    }
+}
+
+impl Drop for MetricsHandle {
+    fn drop(&mut self) {
+        let mut registry = METRICS_REGISTRY.lock().unwrap();
+        if let Some(s) = registry.active.remove(&self.id) {
+            let snapshot = s.metrics.snapshot();
+            let totals = registry.totals.entry(s.group).or_default();
+            totals.streams += 1;
+            totals.read_bytes += snapshot.read_bytes;
+            totals.write_bytes += snapshot.write_bytes;
+        }
+    }
+}
+
+/// Registers a new stream to `target` under `group` (usually the channel
+/// or remote name).
+pub fn register_stream_metrics(group: &str, target: &str) -> MetricsHandle {
+    let metrics = StreamMetrics::new();
+    let mut registry = METRICS_REGISTRY.lock().unwrap();
+    registry.id_seed += 1;
+    let id = registry.id_seed;
+    registry.active.insert(
+        id,
+        ActiveStream {
+            group: String::from(group),
+            target: String::from(target),
+            metrics: metrics.clone(),
+        },
+    );
+    MetricsHandle { id, metrics }
+}
+
+pub fn dump_stream_metrics() -> String {
+    let registry = METRICS_REGISTRY.lock().unwrap();
+    let mut info = String::from("========================Streams====================\n");
+    let mut active_groups: HashMap<&str, (u64, u64, u64)> = HashMap::new();
+    for (id, s) in registry.active.iter() {
+        let snapshot = s.metrics.snapshot();
+        let age_secs = std::cmp::max(snapshot.age.as_secs(), 1);
+        info.push_str(
+            format!(
+                "{}:[{}]target:{}, age:{:?}, read_bytes:{}, write_bytes:{}, read_rate:{}/s, write_rate:{}/s, first_byte:{:?}\n",
+                id,
+                s.group,
+                s.target,
+                snapshot.age,
+                snapshot.read_bytes,
+                snapshot.write_bytes,
+                snapshot.read_bytes / age_secs,
+                snapshot.write_bytes / age_secs,
+                snapshot.first_byte_latency,
+            )
+            .as_str(),
+        );
+        let g = active_groups.entry(s.group.as_str()).or_insert((0, 0, 0));
+        g.0 += 1;
+        g.1 += snapshot.read_bytes;
+        g.2 += snapshot.write_bytes;
+    }
+    info.push_str("\nActive By Group:\n");
+    for (group, (count, read_bytes, write_bytes)) in active_groups.iter() {
+        info.push_str(
+            format!(
+                "[{}]streams:{}, read_bytes:{}, write_bytes:{}\n",
+                group, count, read_bytes, write_bytes
+            )
+            .as_str(),
+        );
+    }
+    info.push_str("\nClosed By Group:\n");
+    for (group, totals) in registry.totals.iter() {
+        info.push_str(
+            format!(
+                "[{}]streams:{}, read_bytes:{}, write_bytes:{}\n",
+                group, totals.streams, totals.read_bytes, totals.write_bytes
+            )
+            .as_str(),
+        );
+    }
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_metered_sides() {
+        let metrics = StreamMetrics::new();
+        let mut buf = [0u8; 8];
+        // the client sends 3 bytes and gets 5 back, counted at either end
+        let mut client = MeteredStream::new(&b"abc"[..], metrics.clone());
+        assert_eq!(client.read(&mut buf).await.unwrap(), 3);
+        let mut target = MeteredStream::target(&b"hello"[..], metrics.clone());
+        assert_eq!(target.read(&mut buf).await.unwrap(), 5);
+        let s = metrics.snapshot();
+        assert_eq!((s.read_bytes, s.write_bytes), (3, 5));
+        assert!(s.first_byte_latency.is_some());
+
+        let metrics = StreamMetrics::new();
+        let mut client = MeteredStream::new(Vec::new(), metrics.clone());
+        client.write_all(b"hello").await.unwrap();
+        let mut target = MeteredStream::target(Vec::new(), metrics.clone());
+        target.write_all(b"abc").await.unwrap();
+        let s = metrics.snapshot();
+        assert_eq!((s.read_bytes, s.write_bytes), (3, 5));
+    }
+}
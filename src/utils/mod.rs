@@ -1,15 +1,52 @@
 mod buf;
+mod dial;
+mod domain_trie;
+mod frame;
+mod geoip;
 mod io;
+mod ip_trie;
+mod limit;
+mod metrics;
 mod net;
 mod net2;
+mod peek;
+#[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+mod pf;
+mod split;
+mod tun;
+mod udp;
 mod ws;
+mod zeroize;
 
 pub use self::buf::{fill_read_buf, VBuf};
+pub use self::dial::{happy_connect, DialOptions};
+pub use self::domain_trie::DomainTrie;
+pub use self::frame::{
+    decode_varint, encode_varint, FrameCodec, FrameReader, FrameWriter, LengthPrefix,
+};
+pub use self::geoip::{geoip_country, init_geoip};
 pub use self::io::make_error;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use self::io::splice_copy;
 pub use self::io::{
-    clear_channel, clear_unbounded_channel, make_io_error, read_until_separator, relay_buf_copy,
-    RelayState,
+    bi_copy, clear_channel, clear_unbounded_channel, init_buffer_pool, make_invalid_data_error,
+    make_io_error, read_until_separator, read_until_separator_timeout, RelayState, TimedStream,
+    DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_MAX_HEAD_SIZE,
+};
+pub use self::ip_trie::IpTrie;
+pub use self::limit::{
+    RateLimitedReader, RateLimitedWriter, ShapedWriter, TokenBucket, TrafficShaper,
+};
+pub use self::metrics::{dump_stream_metrics, register_stream_metrics, MeteredStream};
+pub use self::net::{
+    enable_tfo_listener, get_origin_dst, proxy_connect, tfo_connect, AsyncTcpStream,
+};
+pub use self::net2::{AsyncFuturesIO, AsyncTokioIO};
+pub use self::peek::PeekableReader;
+pub use self::split::{rejoin, split_owned, OwnedReadHalf, OwnedWriteHalf};
+pub use self::tun::TunDevice;
+pub use self::udp::{
+    udp_connect, DatagramReader, DatagramWriter, UDP_FLOW_IDLE, UDP_TARGET_PREFIX,
 };
-pub use self::net::{get_origin_dst, http_proxy_connect, AsyncTcpStream};
-pub use self::net2::AsyncTokioIO;
 pub use self::ws::{WebsocketReader, WebsocketWriter};
+pub use self::zeroize::zeroize;
@@ -1,25 +1,48 @@
-use super::io::read_until_separator;
+use super::buf::IoSliceBuf;
+use super::dial::{happy_connect, DialOptions};
+use super::io::{make_io_error, read_until_separator, DEFAULT_MAX_HEAD_SIZE};
 
 use httparse::Status;
+use std::io::IoSlice;
 use std::net::SocketAddr;
 
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use url::Url;
 
-#[cfg(not(any(target_os = "android", target_os = "linux")))]
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "openbsd"
+)))]
 pub fn get_origin_dst(_socket: &TcpStream) -> Option<SocketAddr> {
     None
 }
 
+/// Destination of a connection before pf `rdr` sent it to us.
+#[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+pub fn get_origin_dst(socket: &TcpStream) -> Option<SocketAddr> {
+    let peer = socket.peer_addr().ok()?;
+    let local = socket.local_addr().ok()?;
+    super::pf::pf_origin_dst(peer, local)
+}
+
+/// Destination of a connection before iptables REDIRECT/DNAT sent it to us.
 #[cfg(any(target_os = "android", target_os = "linux"))]
 pub fn get_origin_dst(socket: &TcpStream) -> Option<SocketAddr> {
     use nix::sys::socket::{getsockopt, sockopt, InetAddr};
-    use std::net::ToSocketAddrs;
     use std::os::unix::io::AsRawFd;
     let fd = socket.as_raw_fd();
+    if let Ok(SocketAddr::V6(_)) = socket.local_addr() {
+        // ip6tables, or an ipv4 client on a dual stack socket
+        if let Some(dst) = get_origin_dst6(fd) {
+            return Some(dst);
+        }
+    }
     let opt = sockopt::OriginalDst {};
     match getsockopt(fd, opt) {
         Ok(addr) => Some(InetAddr::V4(addr).to_std()),
@@ -27,6 +50,34 @@ pub fn get_origin_dst(socket: &TcpStream) -> Option<SocketAddr> {
     }
 }
 
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn get_origin_dst6(fd: std::os::unix::io::RawFd) -> Option<SocketAddr> {
+    use nix::libc;
+    use std::net::{Ipv6Addr, SocketAddrV6};
+    // linux/netfilter_ipv6/ip6_tables.h
+    const IP6T_SO_ORIGINAL_DST: libc::c_int = 80;
+    let mut addr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_IPV6,
+            IP6T_SO_ORIGINAL_DST,
+            &mut addr as *mut libc::sockaddr_in6 as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc != 0 {
+        return None;
+    }
+    Some(SocketAddr::V6(SocketAddrV6::new(
+        Ipv6Addr::from(addr.sin6_addr.s6_addr),
+        u16::from_be(addr.sin6_port),
+        0,
+        0,
+    )))
+}
+
 pub fn is_ok_response(buf: &[u8]) -> bool {
     let mut headers = [httparse::EMPTY_HEADER; 32];
     let mut res = httparse::Response::new(&mut headers);
@@ -39,42 +90,158 @@ pub fn is_ok_response(buf: &[u8]) -> bool {
     }
 }
 
-pub async fn http_proxy_connect(proxy: &Url, remote: &str) -> Result<TcpStream, std::io::Error> {
-    let connect_str = format!(
-        "CONNECT {} HTTP/1.1\r\nHost: {}\r\nConnection: keep-alive\r\nProxy-Connection: keep-alive\r\n\r\n",
-        remote, remote
-    );
-    let raddr: Vec<SocketAddr> = match proxy.socket_addrs(|| None) {
-        Ok(m) => m,
-        Err(err) => {
-            error!(
-                "Failed to parse addr with error:{} from connect request:{}",
-                err, proxy
-            );
-            return Err(std::io::Error::from(std::io::ErrorKind::ConnectionAborted));
-        }
+async fn connect_proxy_server(
+    proxy: &Url,
+    opts: &DialOptions,
+) -> Result<TcpStream, std::io::Error> {
+    let addr = match proxy.host_str() {
+        Some(h) => format!("{}:{}", h, proxy.port_or_known_default().unwrap_or(1080)),
+        None => return Err(make_io_error("invalid proxy url")),
     };
-    let connect_bytes = connect_str.into_bytes();
-
-    let conn = TcpStream::connect(&raddr[0]);
     let dur = std::time::Duration::from_secs(3);
-    let s = tokio::time::timeout(dur, conn).await?;
-
-    let mut socket = match s {
-        Ok(s) => s,
+    match tokio::time::timeout(dur, happy_connect(addr.as_str(), opts)).await? {
+        Ok(s) => Ok(s),
         Err(err) => {
-            error!("Failed to connect proxy:{} with err:{}", raddr[0], err);
-            return Err(err);
+            error!("Failed to connect proxy:{} with err:{}", addr, err);
+            Err(err)
         }
-    };
+    }
+}
+
+/// Dials `remote` through an upstream proxy given as
+/// `http://[user:pass@]host:port` or `socks5://[user:pass@]host:port`.
+pub async fn proxy_connect(
+    proxy: &Url,
+    remote: &str,
+    opts: &DialOptions,
+) -> Result<TcpStream, std::io::Error> {
+    match proxy.scheme() {
+        "http" => http_proxy_connect(proxy, remote, opts).await,
+        "socks5" | "socks5h" => socks5_proxy_connect(proxy, remote, opts).await,
+        s => Err(make_io_error(&format!("unsupported proxy scheme:{}", s))),
+    }
+}
+
+pub async fn http_proxy_connect(
+    proxy: &Url,
+    remote: &str,
+    opts: &DialOptions,
+) -> Result<TcpStream, std::io::Error> {
+    let mut connect_str = format!(
+        "CONNECT {} HTTP/1.1\r\nHost: {}\r\nConnection: keep-alive\r\nProxy-Connection: keep-alive\r\n",
+        remote, remote
+    );
+    if !proxy.username().is_empty() {
+        let credential = format!("{}:{}", proxy.username(), proxy.password().unwrap_or(""));
+        connect_str.push_str(
+            format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64::encode(credential.as_bytes())
+            )
+            .as_str(),
+        );
+    }
+    connect_str.push_str("\r\n");
+    let connect_bytes = connect_str.into_bytes();
+    let mut socket = connect_proxy_server(proxy, opts).await?;
     socket.write_all(&connect_bytes[..]).await?;
-    let (head, _) = read_until_separator(&mut socket, "\r\n\r\n").await?;
+    let (_, head, _) =
+        read_until_separator(&mut socket, &["\r\n\r\n", "\n\n"], DEFAULT_MAX_HEAD_SIZE).await?;
     if is_ok_response(&head[..]) {
         return Ok(socket);
     }
     Err(std::io::Error::from(std::io::ErrorKind::ConnectionAborted))
 }
 
+/// SOCKS5 CONNECT request for `remote`, which is `host:port` or `[v6]:port`.
+fn socks5_connect_request(remote: &str) -> Result<Vec<u8>, std::io::Error> {
+    let pos = match remote.rfind(':') {
+        Some(p) => p,
+        None => return Err(make_io_error("missing port in socks5 target")),
+    };
+    let port: u16 = match remote[pos + 1..].parse() {
+        Ok(p) => p,
+        Err(_) => return Err(make_io_error("invalid port in socks5 target")),
+    };
+    let host = remote[..pos].trim_start_matches('[').trim_end_matches(']');
+    let mut req = vec![5u8, 1, 0];
+    if let Ok(ip) = host.parse::<std::net::Ipv4Addr>() {
+        req.push(1);
+        req.extend_from_slice(&ip.octets());
+    } else if let Ok(ip) = host.parse::<std::net::Ipv6Addr>() {
+        req.push(4);
+        req.extend_from_slice(&ip.octets());
+    } else {
+        if host.len() > 255 {
+            return Err(make_io_error("socks5 target host too long"));
+        }
+        req.push(3);
+        req.push(host.len() as u8);
+        req.extend_from_slice(host.as_bytes());
+    }
+    req.extend_from_slice(&port.to_be_bytes());
+    Ok(req)
+}
+
+pub async fn socks5_proxy_connect(
+    proxy: &Url,
+    remote: &str,
+    opts: &DialOptions,
+) -> Result<TcpStream, std::io::Error> {
+    let req = socks5_connect_request(remote)?;
+    let mut socket = connect_proxy_server(proxy, opts).await?;
+    let user = proxy.username();
+    let pass = proxy.password().unwrap_or("");
+    if user.is_empty() {
+        socket.write_all(&[5, 1, 0]).await?;
+    } else {
+        socket.write_all(&[5, 2, 0, 2]).await?;
+    }
+    let mut res = [0u8; 2];
+    socket.read_exact(&mut res).await?;
+    match res[1] {
+        0 => {}
+        2 if !user.is_empty() => {
+            if user.len() > 255 || pass.len() > 255 {
+                return Err(make_io_error("socks5 credential too long"));
+            }
+            let mut auth = vec![1u8, user.len() as u8];
+            auth.extend_from_slice(user.as_bytes());
+            auth.push(pass.len() as u8);
+            auth.extend_from_slice(pass.as_bytes());
+            socket.write_all(&auth[..]).await?;
+            socket.read_exact(&mut res).await?;
+            if res[1] != 0 {
+                return Err(make_io_error("socks5 proxy auth failed"));
+            }
+        }
+        _ => return Err(make_io_error("no acceptable socks5 auth method")),
+    }
+    socket.write_all(&req[..]).await?;
+    let mut head = [0u8; 4];
+    socket.read_exact(&mut head).await?;
+    if head[1] != 0 {
+        return Err(make_io_error(&format!(
+            "socks5 proxy connect failed with code:{}",
+            head[1]
+        )));
+    }
+    // skip the bound address
+    let skip = match head[3] {
+        1 => 4 + 2,
+        4 => 16 + 2,
+        3 => {
+            let mut len = [0u8; 1];
+            socket.read_exact(&mut len).await?;
+            len[0] as usize + 2
+        }
+        _ => return Err(make_io_error("invalid socks5 reply")),
+    };
+    let mut bound = vec![0u8; skip];
+    socket.read_exact(&mut bound).await?;
+    Ok(socket)
+}
+
 pub struct AsyncTcpStream {
     s: TcpStream,
 }
@@ -107,6 +274,16 @@ impl futures::AsyncWrite for AsyncTcpStream {
         pin_mut!(s);
         s.poll_write(cx, buf)
     }
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<futures::io::Result<usize>> {
+        let Self { s } = &mut *self;
+        pin_mut!(s);
+        let mut b = IoSliceBuf::new(bufs);
+        s.poll_write_buf(cx, &mut b)
+    }
     fn poll_flush(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -124,3 +301,128 @@ impl futures::AsyncWrite for AsyncTcpStream {
         s.poll_shutdown(cx)
     }
 }
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn set_tcp_opt(fd: std::os::unix::io::RawFd, opt: i32, val: i32) -> std::io::Result<()> {
+    use nix::libc;
+    let rc = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            opt,
+            &val as *const i32 as *const libc::c_void,
+            std::mem::size_of::<i32>() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Enables TCP Fast Open on a listening socket with the given pending queue length.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn enable_tfo_listener(listener: &tokio::net::TcpListener, qlen: i32) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    set_tcp_opt(listener.as_raw_fd(), nix::libc::TCP_FASTOPEN, qlen)
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+pub fn enable_tfo_listener(_listener: &tokio::net::TcpListener, _qlen: i32) -> std::io::Result<()> {
+    Err(std::io::Error::from(std::io::ErrorKind::Other))
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn tfo_connect_blocking(
+    addr: SocketAddr,
+    data: &[u8],
+    opts: &DialOptions,
+) -> std::io::Result<std::net::TcpStream> {
+    use nix::libc;
+    use nix::sys::socket::{socket, AddressFamily, InetAddr, SockAddr, SockFlag, SockType};
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+    let family = if addr.is_ipv4() {
+        AddressFamily::Inet
+    } else {
+        AddressFamily::Inet6
+    };
+    let fd = match socket(family, SockType::Stream, SockFlag::SOCK_CLOEXEC, None) {
+        Ok(fd) => fd,
+        Err(e) => return Err(make_io_error(&e.to_string())),
+    };
+    // owns the fd from here on, it gets closed on every error path
+    let mut stream = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+    super::dial::bind_socket(fd, &addr, opts)?;
+    let sa = SockAddr::new_inet(InetAddr::from_std(&addr));
+    // SAFETY: `sa` outlives the pointer, which is only read by the sendto
+    // below with the length it came with.
+    let (sa_ptr, sa_len) = unsafe { sa.as_ffi_pair() };
+    let n = unsafe {
+        libc::sendto(
+            fd,
+            data.as_ptr() as *const libc::c_void,
+            data.len(),
+            libc::MSG_FASTOPEN,
+            sa_ptr as *const libc::sockaddr,
+            sa_len,
+        )
+    };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let n = n as usize;
+    if n < data.len() {
+        stream.write_all(&data[n..])?;
+    }
+    stream.set_nonblocking(true)?;
+    Ok(stream)
+}
+
+/// Connects to `addr` with `data` carried in the SYN when the kernel has a
+/// fast open cookie for it, and falls back to a normal connect + write elsewhere.
+pub async fn tfo_connect(
+    addr: SocketAddr,
+    data: Vec<u8>,
+    opts: &DialOptions,
+) -> Result<TcpStream, std::io::Error> {
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    {
+        let opts = opts.clone();
+        let connect =
+            tokio::task::spawn_blocking(move || tfo_connect_blocking(addr, &data[..], &opts));
+        let dur = std::time::Duration::from_secs(5);
+        let stream = tokio::time::timeout(dur, connect).await??;
+        TcpStream::from_std(stream?)
+    }
+    #[cfg(not(any(target_os = "android", target_os = "linux")))]
+    {
+        let dur = std::time::Duration::from_secs(5);
+        let target = addr.to_string();
+        let connect = happy_connect(target.as_str(), opts);
+        let mut stream = tokio::time::timeout(dur, connect).await??;
+        stream.write_all(&data[..]).await?;
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_socks5_connect_request() {
+        assert_eq!(
+            socks5_connect_request("1.2.3.4:80").unwrap(),
+            vec![5, 1, 0, 1, 1, 2, 3, 4, 0, 80]
+        );
+        let v6 = socks5_connect_request("[::1]:443").unwrap();
+        assert_eq!(v6[3], 4);
+        assert_eq!(v6.len(), 4 + 16 + 2);
+        assert_eq!(
+            socks5_connect_request("a.io:8080").unwrap(),
+            vec![5, 1, 0, 3, 4, b'a', b'.', b'i', b'o', 0x1f, 0x90]
+        );
+        assert!(socks5_connect_request("a.io").is_err());
+    }
+}
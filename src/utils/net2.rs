@@ -1,7 +1,11 @@
+use bytes::Buf;
 use futures::{AsyncRead, AsyncWrite};
+use std::io::IoSlice;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
+const MAX_WRITE_SLICES: usize = 64;
+
 pub struct AsyncTokioIO<S> {
     s: S,
 }
@@ -43,6 +47,27 @@ where
         pin_mut!(s);
         s.poll_write(cx, buf)
     }
+    fn poll_write_buf<B: Buf>(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut B,
+    ) -> Poll<futures::io::Result<usize>>
+    where
+        Self: Sized,
+    {
+        if !buf.has_remaining() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = {
+            let mut slices = [IoSlice::new(&[]); MAX_WRITE_SLICES];
+            let cnt = buf.bytes_vectored(&mut slices);
+            let Self { s } = &mut *self;
+            pin_mut!(s);
+            ready!(s.poll_write_vectored(cx, &slices[..cnt]))?
+        };
+        buf.advance(n);
+        Poll::Ready(Ok(n))
+    }
     fn poll_flush(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -60,3 +85,134 @@ where
         s.poll_close(cx)
     }
 }
+
+/// Joins a tokio reader and writer into one futures-io stream, e.g. the
+/// halves of a channel stream for async-tls.
+pub struct AsyncFuturesIO<R, W> {
+    r: R,
+    w: W,
+}
+
+impl<R, W> AsyncFuturesIO<R, W> {
+    pub fn new(r: R, w: W) -> Self
+    where
+        R: tokio::io::AsyncRead + Unpin,
+        W: tokio::io::AsyncWrite + Unpin,
+    {
+        Self { r, w }
+    }
+}
+
+impl<R, W> AsyncRead for AsyncFuturesIO<R, W>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<futures::io::Result<usize>> {
+        Pin::new(&mut self.r).poll_read(cx, buf)
+    }
+}
+
+impl<R, W> AsyncWrite for AsyncFuturesIO<R, W>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<futures::io::Result<usize>> {
+        Pin::new(&mut self.w).poll_write(cx, buf)
+    }
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.w).poll_flush(cx)
+    }
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.w).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::VBuf;
+    use tokio::io::AsyncWriteExt;
+
+    /// Takes at most `limit` bytes a write, recording the slices of each.
+    struct Recorder {
+        data: Vec<u8>,
+        slices: Vec<usize>,
+        limit: usize,
+    }
+
+    impl AsyncRead for Recorder {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Ok(0))
+        }
+    }
+
+    impl AsyncWrite for Recorder {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+        }
+        fn poll_write_vectored(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> Poll<std::io::Result<usize>> {
+            self.slices.push(bufs.len());
+            let mut n = 0;
+            for b in bufs {
+                let take = std::cmp::min(b.len(), self.limit - n);
+                self.data.extend_from_slice(&b[..take]);
+                n += take;
+            }
+            Poll::Ready(Ok(n))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_buf_vectored() {
+        let mut io = AsyncTokioIO::new(Recorder {
+            data: Vec::new(),
+            slices: Vec::new(),
+            limit: 4,
+        });
+        let mut vbuf = VBuf::new();
+        vbuf.push(b"ab".to_vec());
+        vbuf.push(b"cde".to_vec());
+        vbuf.push(b"f".to_vec());
+        // one write of all three slices, cut short by the writer
+        assert_eq!(io.write_buf(&mut vbuf).await.unwrap(), 4);
+        assert_eq!(vbuf.remaining(), 2);
+        assert_eq!(io.write_buf(&mut vbuf).await.unwrap(), 2);
+        assert_eq!(io.write_buf(&mut vbuf).await.unwrap(), 0);
+        assert_eq!(io.s.slices, vec![3, 2]);
+        assert_eq!(&io.s.data[..], b"abcdef");
+    }
+}
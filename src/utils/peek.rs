@@ -0,0 +1,269 @@
+use bytes::{Buf, Bytes, BytesMut};
+use futures::future::poll_fn;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::buf::fill_read_buf;
+use super::io::make_invalid_data_error;
+
+const PEEK_READ_SIZE: usize = 1024;
+
+fn peek_timeout_error() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "timeout peeking stream")
+}
+
+/// A stream wrapper that allows inspecting a prefix of the inbound data
+/// without consuming it. Peeked bytes are replayed by `poll_read` first,
+/// writes are passed straight through.
+///
+/// With watermarks set, peeks stop reading from the inner stream once `high`
+/// bytes are buffered and resume after the reader drains the buffer down to
+/// `low`. Meanwhile a peek wanting more gets what is buffered, as if the
+/// stream ended there, instead of waiting on a read it is in the way of.
+pub struct PeekableReader<T> {
+    inner: T,
+    peek_buf: BytesMut,
+    eof: bool,
+    watermarks: Option<(usize, usize)>,
+    paused: bool,
+}
+
+impl<T> PeekableReader<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            peek_buf: BytesMut::new(),
+            eof: false,
+            watermarks: None,
+            paused: false,
+        }
+    }
+    pub fn with_watermarks(inner: T, low: usize, high: usize) -> Self {
+        let high = std::cmp::max(high, 1);
+        let mut r = Self::new(inner);
+        r.watermarks = Some((std::cmp::min(low, high), high));
+        r
+    }
+    fn on_drained(&mut self) {
+        if let Some((low, _)) = self.watermarks {
+            if self.paused && self.peek_buf.len() <= low {
+                self.paused = false;
+            }
+        }
+    }
+    /// How many bytes peeks may buffer now.
+    fn peek_limit(&self) -> usize {
+        match self.watermarks {
+            None => usize::MAX,
+            Some(_) if self.paused => self.peek_buf.len(),
+            Some((_, high)) => high,
+        }
+    }
+    pub fn peeked(&self) -> &[u8] {
+        &self.peek_buf[..]
+    }
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+    /// Returns the inner stream together with any peeked but unread bytes.
+    pub fn into_inner(self) -> (T, BytesMut) {
+        (self.inner, self.peek_buf)
+    }
+}
+
+impl<T> PeekableReader<T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        if self.eof {
+            return Poll::Ready(Ok(0));
+        }
+        let room = self.peek_limit().saturating_sub(self.peek_buf.len());
+        if room == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        let mut chunk = [0u8; PEEK_READ_SIZE];
+        let chunk_len = std::cmp::min(room, PEEK_READ_SIZE);
+        let n = ready!(Pin::new(&mut self.inner).poll_read(cx, &mut chunk[..chunk_len]))?;
+        self.peek_buf.extend_from_slice(&chunk[..n]);
+        if n == 0 {
+            self.eof = true;
+        }
+        if let Some((_, high)) = self.watermarks {
+            if self.peek_buf.len() >= high {
+                self.paused = true;
+            }
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    /// Fills `buf` completely unless EOF or the high watermark is hit first.
+    pub fn poll_peek(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        while self.peek_buf.len() < std::cmp::min(buf.len(), self.peek_limit()) {
+            if ready!(self.poll_fill(cx))? == 0 {
+                break;
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.peek_buf.len());
+        buf[..n].copy_from_slice(&self.peek_buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    /// Copies whatever is already buffered, reading at most once more if
+    /// nothing is.
+    pub fn poll_peek_some(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.peek_buf.is_empty() {
+            ready!(self.poll_fill(cx))?;
+        }
+        let n = std::cmp::min(buf.len(), self.peek_buf.len());
+        buf[..n].copy_from_slice(&self.peek_buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_peek_until(
+        &mut self,
+        cx: &mut Context<'_>,
+        separator: &[u8],
+        max: usize,
+    ) -> Poll<io::Result<Bytes>> {
+        loop {
+            if let Some(pos) = twoway::find_bytes(&self.peek_buf[..], separator) {
+                let end = pos + separator.len();
+                return Poll::Ready(Ok(Bytes::copy_from_slice(&self.peek_buf[..end])));
+            }
+            if self.peek_buf.len() >= std::cmp::min(max, self.peek_limit()) {
+                return Poll::Ready(Err(make_invalid_data_error(
+                    "no separator found within peek limit",
+                )));
+            }
+            if ready!(self.poll_fill(cx))? == 0 {
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::UnexpectedEof)));
+            }
+        }
+    }
+
+    pub async fn peek_exact(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_peek(cx, buf)).await
+    }
+
+    pub async fn peek_some(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_peek_some(cx, buf)).await
+    }
+
+    /// Peeks until `separator` shows up, returning the prefix including it.
+    pub async fn peek_until(&mut self, separator: &[u8], max: usize) -> io::Result<Bytes> {
+        poll_fn(|cx| self.poll_peek_until(cx, separator, max)).await
+    }
+
+    pub async fn peek_exact_timeout(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<usize> {
+        match tokio::time::timeout(timeout, self.peek_exact(buf)).await {
+            Ok(r) => r,
+            Err(_) => Err(peek_timeout_error()),
+        }
+    }
+
+    pub async fn peek_until_timeout(
+        &mut self,
+        separator: &[u8],
+        max: usize,
+        timeout: Duration,
+    ) -> io::Result<Bytes> {
+        match tokio::time::timeout(timeout, self.peek_until(separator, max)).await {
+            Ok(r) => r,
+            Err(_) => Err(peek_timeout_error()),
+        }
+    }
+}
+
+impl<T> AsyncRead for PeekableReader<T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if !self.peek_buf.is_empty() {
+            let n = fill_read_buf(&mut self.peek_buf, buf);
+            self.on_drained();
+            return Poll::Ready(Ok(n));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T> AsyncWrite for PeekableReader<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+    fn poll_write_buf<B: Buf>(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut B,
+    ) -> Poll<io::Result<usize>>
+    where
+        Self: Sized,
+    {
+        Pin::new(&mut self.inner).poll_write_buf(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_peek_watermarks() {
+        let data: Vec<u8> = (0..32).collect();
+        let mut reader = PeekableReader::with_watermarks(&data[..], 4, 8);
+        // a peek beyond the high watermark gets what fits instead of stalling
+        let mut buf = [0u8; 16];
+        assert_eq!(reader.peek_exact(&mut buf).await.unwrap(), 8);
+        assert_eq!(&buf[..8], &data[..8]);
+        assert_eq!(reader.peeked().len(), 8);
+        assert!(reader.peek_until(&[31], 64).await.is_err());
+
+        // still paused above the low watermark
+        let mut head = [0u8; 3];
+        reader.read_exact(&mut head).await.unwrap();
+        assert_eq!(reader.peek_exact(&mut buf).await.unwrap(), 5);
+        reader.read_exact(&mut head[..2]).await.unwrap();
+        assert_eq!(reader.peeked().len(), 3);
+        assert_eq!(reader.peek_exact(&mut buf).await.unwrap(), 8);
+        assert_eq!(&buf[..8], &data[5..13]);
+
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(&rest[..], &data[5..]);
+    }
+}
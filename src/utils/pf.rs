@@ -0,0 +1,128 @@
+use nix::libc;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::AsRawFd;
+
+const PF_OUT: u8 = 2;
+
+/// `struct pf_addr`, a union of in_addr and in6_addr.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct PfAddr {
+    addr: [u32; 4],
+}
+
+impl PfAddr {
+    fn new(ip: IpAddr) -> Self {
+        let mut addr = [0u32; 4];
+        match ip {
+            IpAddr::V4(v4) => addr[0] = u32::from_ne_bytes(v4.octets()),
+            IpAddr::V6(v6) => {
+                let octets = v6.octets();
+                for (i, chunk) in octets.chunks(4).enumerate() {
+                    addr[i] = u32::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                }
+            }
+        }
+        Self { addr }
+    }
+    fn octets(&self) -> [u8; 16] {
+        let mut octets = [0u8; 16];
+        for (i, v) in self.addr.iter().enumerate() {
+            octets[i * 4..i * 4 + 4].copy_from_slice(&v.to_ne_bytes());
+        }
+        octets
+    }
+}
+
+// macOS keeps the ports in a `union pf_state_xport`, 4 bytes each
+#[cfg(target_os = "macos")]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct PfPort {
+    port: u16,
+    _pad: u16,
+}
+
+#[cfg(not(target_os = "macos"))]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct PfPort {
+    port: u16,
+}
+
+#[cfg(target_os = "macos")]
+#[repr(C)]
+#[derive(Default)]
+struct PfiocNatlook {
+    saddr: PfAddr,
+    daddr: PfAddr,
+    rsaddr: PfAddr,
+    rdaddr: PfAddr,
+    sport: PfPort,
+    dport: PfPort,
+    rsport: PfPort,
+    rdport: PfPort,
+    af: u8,
+    proto: u8,
+    proto_variant: u8,
+    direction: u8,
+}
+
+#[cfg(not(target_os = "macos"))]
+#[repr(C)]
+#[derive(Default)]
+struct PfiocNatlook {
+    saddr: PfAddr,
+    daddr: PfAddr,
+    rsaddr: PfAddr,
+    rdaddr: PfAddr,
+    sport: PfPort,
+    dport: PfPort,
+    rsport: PfPort,
+    rdport: PfPort,
+    af: u8,
+    proto: u8,
+    direction: u8,
+}
+
+nix::ioctl_readwrite!(diocnatlook, b'D', 23, PfiocNatlook);
+
+/// Asks pf for the destination a `rdr` rule rewrote into `local`, needs read
+/// access to /dev/pf.
+pub fn pf_origin_dst(peer: SocketAddr, local: SocketAddr) -> Option<SocketAddr> {
+    let dev = match std::fs::File::open("/dev/pf") {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Failed to open /dev/pf: {}", e);
+            return None;
+        }
+    };
+    let mut nl = PfiocNatlook::default();
+    nl.saddr = PfAddr::new(peer.ip());
+    nl.sport.port = peer.port().to_be();
+    nl.daddr = PfAddr::new(local.ip());
+    nl.dport.port = local.port().to_be();
+    nl.af = match peer {
+        SocketAddr::V4(_) => libc::AF_INET as u8,
+        SocketAddr::V6(_) => libc::AF_INET6 as u8,
+    };
+    nl.proto = libc::IPPROTO_TCP as u8;
+    nl.direction = PF_OUT;
+    if unsafe { diocnatlook(dev.as_raw_fd(), &mut nl) }.is_err() {
+        return None;
+    }
+    let port = u16::from_be(nl.rdport.port);
+    let octets = nl.rdaddr.octets();
+    match peer {
+        SocketAddr::V4(_) => {
+            let ip = Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]);
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+        }
+        SocketAddr::V6(_) => Some(SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::from(octets),
+            port,
+            0,
+            0,
+        ))),
+    }
+}
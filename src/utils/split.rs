@@ -0,0 +1,98 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Read half of a stream split by `split_owned`. The halves are `'static`
+/// and can be kept apart, e.g. in the reader and writer of a channel stream.
+/// The halves of a `TimedStream` have to be polled by one task, its single
+/// idle timer only wakes the task that polled it last.
+pub struct OwnedReadHalf<T> {
+    inner: Arc<Mutex<T>>,
+}
+
+pub struct OwnedWriteHalf<T> {
+    inner: Arc<Mutex<T>>,
+}
+
+pub fn split_owned<T>(stream: T) -> (OwnedReadHalf<T>, OwnedWriteHalf<T>)
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let inner = Arc::new(Mutex::new(stream));
+    (
+        OwnedReadHalf {
+            inner: inner.clone(),
+        },
+        OwnedWriteHalf { inner },
+    )
+}
+
+/// Joins two halves returned by `split_owned` back into the original stream.
+/// Fails if the halves belong to different streams.
+pub fn rejoin<T>(
+    read: OwnedReadHalf<T>,
+    write: OwnedWriteHalf<T>,
+) -> Result<T, (OwnedReadHalf<T>, OwnedWriteHalf<T>)> {
+    if !Arc::ptr_eq(&read.inner, &write.inner) {
+        return Err((read, write));
+    }
+    drop(write);
+    match Arc::try_unwrap(read.inner) {
+        Ok(m) => Ok(match m.into_inner() {
+            Ok(s) => s,
+            Err(poisoned) => poisoned.into_inner(),
+        }),
+        Err(inner) => Err((
+            OwnedReadHalf {
+                inner: inner.clone(),
+            },
+            OwnedWriteHalf { inner },
+        )),
+    }
+}
+
+fn lock<T>(inner: &Arc<Mutex<T>>) -> io::Result<MutexGuard<'_, T>> {
+    inner
+        .lock()
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "split stream poisoned"))
+}
+
+impl<T> AsyncRead for OwnedReadHalf<T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut s = lock(&self.inner)?;
+        Pin::new(&mut *s).poll_read(cx, buf)
+    }
+}
+
+impl<T> AsyncWrite for OwnedWriteHalf<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut s = lock(&self.inner)?;
+        Pin::new(&mut *s).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut s = lock(&self.inner)?;
+        Pin::new(&mut *s).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut s = lock(&self.inner)?;
+        Pin::new(&mut *s).poll_shutdown(cx)
+    }
+}
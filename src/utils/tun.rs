@@ -0,0 +1,311 @@
+use super::io::make_io_error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::Ipv4Addr;
+
+/// A layer 3 tun interface, one IP packet per read or write.
+pub struct TunDevice {
+    pub name: String,
+    file: File,
+}
+
+/// Write side of a `TunDevice`, it can live on another thread.
+pub struct TunWriter {
+    file: File,
+}
+
+#[cfg(any(target_os = "android", target_os = "linux", target_os = "macos"))]
+fn run(cmd: &str, args: &[&str]) -> std::io::Result<()> {
+    let status = std::process::Command::new(cmd).args(args).status()?;
+    if !status.success() {
+        return Err(make_io_error(&format!(
+            "'{} {}' failed with {}",
+            cmd,
+            args.join(" "),
+            status
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn netmask(prefix: u8) -> Ipv4Addr {
+    let bits = if prefix == 0 {
+        0
+    } else {
+        u32::max_value() << (32 - u32::from(prefix.min(32)))
+    };
+    Ipv4Addr::from(bits)
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod platform {
+    use nix::libc;
+    use std::fs::{File, OpenOptions};
+    use std::os::unix::io::AsRawFd;
+
+    const IFF_TUN: libc::c_short = 0x0001;
+    const IFF_NO_PI: libc::c_short = 0x1000;
+
+    #[repr(C)]
+    pub struct IfReq {
+        name: [u8; 16],
+        flags: libc::c_short,
+        _pad: [u8; 22],
+    }
+
+    nix::ioctl_write_ptr_bad!(
+        tunsetiff,
+        nix::request_code_write!(b'T', 202, std::mem::size_of::<libc::c_int>()),
+        IfReq
+    );
+
+    pub const PACKET_HEADER: usize = 0;
+
+    pub fn open(name: Option<&str>) -> std::io::Result<(String, File)> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")?;
+        let mut req = IfReq {
+            name: [0u8; 16],
+            flags: IFF_TUN | IFF_NO_PI,
+            _pad: [0u8; 22],
+        };
+        if let Some(name) = name {
+            let n = std::cmp::min(name.len(), 15);
+            req.name[..n].copy_from_slice(&name.as_bytes()[..n]);
+        }
+        if let Err(e) = unsafe { tunsetiff(file.as_raw_fd(), &req) } {
+            return Err(super::make_io_error(&e.to_string()));
+        }
+        let len = req.name.iter().position(|b| *b == 0).unwrap_or(16);
+        let name = String::from_utf8_lossy(&req.name[..len]).to_string();
+        Ok((name, file))
+    }
+
+    pub fn configure(
+        name: &str,
+        ip: std::net::Ipv4Addr,
+        prefix: u8,
+        mtu: u32,
+    ) -> std::io::Result<()> {
+        super::run(
+            "ip",
+            &[
+                "addr",
+                "add",
+                format!("{}/{}", ip, prefix).as_str(),
+                "dev",
+                name,
+            ],
+        )?;
+        super::run(
+            "ip",
+            &[
+                "link",
+                "set",
+                "dev",
+                name,
+                "mtu",
+                mtu.to_string().as_str(),
+                "up",
+            ],
+        )
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use nix::libc;
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+
+    const AF_SYSTEM: libc::c_int = 32;
+    const AF_SYS_CONTROL: u16 = 2;
+    const SYSPROTO_CONTROL: libc::c_int = 2;
+    const UTUN_OPT_IFNAME: libc::c_int = 2;
+    const UTUN_CONTROL_NAME: &[u8] = b"com.apple.net.utun_control";
+
+    #[repr(C)]
+    pub struct CtlInfo {
+        ctl_id: u32,
+        ctl_name: [u8; 96],
+    }
+
+    #[repr(C)]
+    struct SockaddrCtl {
+        sc_len: u8,
+        sc_family: u8,
+        ss_sysaddr: u16,
+        sc_id: u32,
+        sc_unit: u32,
+        sc_reserved: [u32; 5],
+    }
+
+    nix::ioctl_readwrite!(ctliocginfo, b'N', 3, CtlInfo);
+
+    // utun prefixes every packet with its address family
+    pub const PACKET_HEADER: usize = 4;
+
+    pub fn open(name: Option<&str>) -> std::io::Result<(String, File)> {
+        let unit = match name {
+            Some(n) => match n.trim_start_matches("utun").parse::<u32>() {
+                Ok(u) => u + 1,
+                Err(_) => return Err(super::make_io_error("tun name must be utunN on macos")),
+            },
+            None => 0,
+        };
+        let fd = unsafe { libc::socket(AF_SYSTEM, libc::SOCK_DGRAM, SYSPROTO_CONTROL) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // owns the fd from here on
+        let file = unsafe { File::from_raw_fd(fd) };
+        let mut info = CtlInfo {
+            ctl_id: 0,
+            ctl_name: [0u8; 96],
+        };
+        info.ctl_name[..UTUN_CONTROL_NAME.len()].copy_from_slice(UTUN_CONTROL_NAME);
+        if let Err(e) = unsafe { ctliocginfo(fd, &mut info) } {
+            return Err(super::make_io_error(&e.to_string()));
+        }
+        let addr = SockaddrCtl {
+            sc_len: std::mem::size_of::<SockaddrCtl>() as u8,
+            sc_family: AF_SYSTEM as u8,
+            ss_sysaddr: AF_SYS_CONTROL,
+            sc_id: info.ctl_id,
+            sc_unit: unit,
+            sc_reserved: [0u32; 5],
+        };
+        let rc = unsafe {
+            libc::connect(
+                fd,
+                &addr as *const SockaddrCtl as *const libc::sockaddr,
+                std::mem::size_of::<SockaddrCtl>() as libc::socklen_t,
+            )
+        };
+        if rc != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut ifname = [0u8; 16];
+        let mut len = ifname.len() as libc::socklen_t;
+        let rc = unsafe {
+            libc::getsockopt(
+                fd,
+                SYSPROTO_CONTROL,
+                UTUN_OPT_IFNAME,
+                ifname.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if rc != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let n = ifname.iter().position(|b| *b == 0).unwrap_or(len as usize);
+        Ok((String::from_utf8_lossy(&ifname[..n]).to_string(), file))
+    }
+
+    pub fn configure(
+        name: &str,
+        ip: std::net::Ipv4Addr,
+        prefix: u8,
+        mtu: u32,
+    ) -> std::io::Result<()> {
+        let ip = ip.to_string();
+        let mask = super::netmask(prefix).to_string();
+        super::run(
+            "ifconfig",
+            &[
+                name,
+                "inet",
+                ip.as_str(),
+                ip.as_str(),
+                "netmask",
+                mask.as_str(),
+                "mtu",
+                mtu.to_string().as_str(),
+                "up",
+            ],
+        )?;
+        let net = format!("{}/{}", ip, prefix);
+        super::run(
+            "route",
+            &["-n", "add", "-net", net.as_str(), "-interface", name],
+        )
+    }
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux", target_os = "macos")))]
+mod platform {
+    use std::fs::File;
+
+    pub const PACKET_HEADER: usize = 0;
+
+    // windows needs the wintun driver dll and its api, which isn't supported
+    pub fn open(_name: Option<&str>) -> std::io::Result<(String, File)> {
+        Err(super::make_io_error(
+            "tun devices are only supported on linux, android and macos",
+        ))
+    }
+
+    pub fn configure(
+        _name: &str,
+        _ip: std::net::Ipv4Addr,
+        _prefix: u8,
+        _mtu: u32,
+    ) -> std::io::Result<()> {
+        Err(super::make_io_error(
+            "tun devices are only supported on linux, android and macos",
+        ))
+    }
+}
+
+impl TunDevice {
+    /// Creates the interface, `name` picks a fixed one, and brings it up with
+    /// `ip/prefix` assigned.
+    pub fn open(name: Option<&str>, ip: Ipv4Addr, prefix: u8, mtu: u32) -> std::io::Result<Self> {
+        let (name, file) = platform::open(name)?;
+        platform::configure(name.as_str(), ip, prefix, mtu)?;
+        Ok(Self { name, file })
+    }
+
+    pub fn writer(&self) -> std::io::Result<TunWriter> {
+        Ok(TunWriter {
+            file: self.file.try_clone()?,
+        })
+    }
+
+    /// Blocks for the next packet and returns its length, the packet starts
+    /// at `buf[0]`.
+    pub fn read_packet(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let n = self.file.read(buf)?;
+            if n > platform::PACKET_HEADER {
+                buf.copy_within(platform::PACKET_HEADER..n, 0);
+                return Ok(n - platform::PACKET_HEADER);
+            }
+            if n == 0 {
+                return Err(make_io_error("tun device closed"));
+            }
+        }
+    }
+}
+
+impl TunWriter {
+    pub fn write_packet(&mut self, packet: &[u8]) -> std::io::Result<()> {
+        if platform::PACKET_HEADER == 0 {
+            self.file.write_all(packet)?;
+            return Ok(());
+        }
+        let family: u8 = if packet.first().map(|b| b >> 4) == Some(6) {
+            30
+        } else {
+            2
+        };
+        let mut data = Vec::with_capacity(packet.len() + platform::PACKET_HEADER);
+        data.extend_from_slice(&[0, 0, 0, family]);
+        data.extend_from_slice(packet);
+        self.file.write_all(&data[..])
+    }
+}
@@ -0,0 +1,133 @@
+use super::io::make_io_error;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+/// Targets with this prefix are UDP flows, their streams carry datagrams
+/// each prefixed with a 2 byte big endian length.
+pub const UDP_TARGET_PREFIX: &str = "udp://";
+/// A flow ends once no datagram came back for this long.
+pub const UDP_FLOW_IDLE: Duration = Duration::from_secs(60);
+const MAX_DATAGRAM: usize = 65535;
+
+/// Reads the datagrams received on `rx` as length prefixed frames.
+pub struct DatagramReader {
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    pending: Bytes,
+}
+
+impl DatagramReader {
+    pub fn new(rx: mpsc::UnboundedReceiver<Vec<u8>>) -> Self {
+        Self {
+            rx,
+            pending: Bytes::new(),
+        }
+    }
+}
+
+impl AsyncRead for DatagramReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.pending.is_empty() {
+            let data = match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(d)) => d,
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            };
+            let mut frame = BytesMut::with_capacity(2 + data.len());
+            frame.put_u16(data.len() as u16);
+            frame.put_slice(&data[..]);
+            self.pending = frame.freeze();
+        }
+        let n = std::cmp::min(buf.len(), self.pending.len());
+        let data = self.pending.split_to(n);
+        buf[..n].copy_from_slice(&data[..]);
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// Splits the length prefixed frames written to it into datagrams for `tx`.
+pub struct DatagramWriter {
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    buf: BytesMut,
+}
+
+impl DatagramWriter {
+    pub fn new(tx: mpsc::UnboundedSender<Vec<u8>>) -> Self {
+        Self {
+            tx,
+            buf: BytesMut::new(),
+        }
+    }
+}
+
+impl AsyncWrite for DatagramWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.buf.put_slice(data);
+        while self.buf.len() >= 2 {
+            let len = ((self.buf[0] as usize) << 8) | self.buf[1] as usize;
+            if self.buf.len() < 2 + len {
+                break;
+            }
+            self.buf.advance(2);
+            let datagram = self.buf.split_to(len).to_vec();
+            if self.tx.send(datagram).is_err() {
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+            }
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Opens a UDP flow to `addr` and bridges it to a reader/writer pair of
+/// frames, the flow ends when the peer stayed quiet for `UDP_FLOW_IDLE`.
+pub async fn udp_connect(addr: &str) -> Result<(DatagramReader, DatagramWriter), io::Error> {
+    let remote = match tokio::net::lookup_host(addr).await?.next() {
+        Some(a) => a,
+        None => return Err(make_io_error("no address resolved")),
+    };
+    let local = if remote.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(remote).await?;
+    let (mut recv_half, mut send_half) = socket.split();
+    let (up_tx, mut up_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    let (down_tx, down_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    tokio::spawn(async move {
+        while let Some(data) = up_rx.recv().await {
+            if send_half.send(&data[..]).await.is_err() {
+                break;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        while let Ok(Ok(n)) = tokio::time::timeout(UDP_FLOW_IDLE, recv_half.recv(&mut buf)).await {
+            if down_tx.send(buf[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    Ok((DatagramReader::new(down_rx), DatagramWriter::new(up_tx)))
+}
@@ -1,8 +1,9 @@
 use crate::utils::{fill_read_buf, make_io_error};
-use bytes::BytesMut;
+use bytes::{Buf, BytesMut};
 use futures::stream::{SplitSink, SplitStream};
 use futures::{Future, SinkExt, Stream};
 use std::error::Error;
+use std::io::IoSlice;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncWrite};
@@ -103,6 +104,40 @@ where
             Poll::Ready(Ok(())) => Poll::Ready(Ok(blen)),
         }
     }
+    fn poll_write_buf<B: Buf>(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut B,
+    ) -> Poll<Result<usize, std::io::Error>>
+    where
+        Self: Sized,
+    {
+        // coalesce all pending slices into one binary frame
+        if !buf.has_remaining() {
+            return Poll::Ready(Ok(0));
+        }
+        let mut data = Vec::with_capacity(buf.remaining());
+        {
+            let mut slices = [IoSlice::new(&[]); 64];
+            let cnt = buf.bytes_vectored(&mut slices);
+            for s in &slices[..cnt] {
+                data.extend_from_slice(s);
+            }
+        }
+        let blen = data.len();
+        let Self { sink } = &mut *self;
+        let msg = Message::binary(data);
+        let future = sink.send(msg);
+        pin_mut!(future);
+        match future.as_mut().poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Err(e)) => Poll::Ready(Err(make_io_error(&e.to_string()))),
+            Poll::Ready(Ok(())) => {
+                buf.advance(blen);
+                Poll::Ready(Ok(blen))
+            }
+        }
+    }
     fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
         Poll::Ready(Ok(()))
     }
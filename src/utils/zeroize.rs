@@ -0,0 +1,25 @@
+use std::sync::atomic::{compiler_fence, Ordering};
+
+/// Overwrites `buf` with zeros in a way the optimizer keeps, for the
+/// ephemeral secrets of a handshake and the session keys mixed from them.
+/// The configured keys are not worth it, every connection holds a clone of
+/// its config.
+pub fn zeroize(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        // a volatile write is never elided as a dead store
+        unsafe { std::ptr::write_volatile(b, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zeroize() {
+        let mut key = [7u8; 32];
+        zeroize(&mut key[..]);
+        assert!(key.iter().all(|b| *b == 0));
+    }
+}